@@ -28,11 +28,14 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-extern crate failure;
+#[macro_use] extern crate failure;
+#[macro_use] extern crate lazy_static;
 extern crate libc;
 #[macro_use] extern crate log;
 extern crate parking_lot;
 extern crate time;
 
 pub mod clock;
+pub mod sched;
 pub mod strutil;
+pub mod trace;
@@ -0,0 +1,191 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Scheduling priority and CPU affinity for classes of threads.
+//!
+//! On a saturated small board, heavy viewing (building `.mp4` files, serving them, running
+//! ffmpeg for thumbnails) can starve the threads which read from cameras and write sample files,
+//! causing dropped frames. `configure` sets a `Policy` for each `Class`, and threads call `enter`
+//! as they start to apply their class's policy. Threads spawned afterward inherit it.
+//!
+//! This is only implemented on Linux, where niceness and affinity are per-thread.
+
+use failure::Error;
+use parking_lot::Mutex;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Class {
+    /// Threads which read from cameras and write recordings: the streamers and syncers.
+    Recorder,
+
+    /// HTTP serving and background jobs such as exports, clips, thumbnails, and mosaics.
+    Web,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Policy {
+    /// The niceness, from -20 (highest priority) to 19 (lowest), or `None` to leave unchanged.
+    /// Negative values require `CAP_SYS_NICE` (or a sufficient `RLIMIT_NICE`).
+    pub nice: Option<i32>,
+
+    /// The CPUs to run on, or `None` to leave unchanged.
+    pub cpus: Option<Vec<usize>>,
+}
+
+lazy_static! {
+    static ref POLICIES: Mutex<[Policy; 2]> = Mutex::new(Default::default());
+}
+
+impl Policy {
+    /// Parses the command-line forms of a policy: an optional niceness and an optional CPU list
+    /// such as `0-1,3`.
+    pub fn parse(nice: Option<i32>, cpus: Option<&str>) -> Result<Self, Error> {
+        if let Some(n) = nice {
+            if n < -20 || n > 19 {
+                bail!("niceness {} is out of range [-20, 19]", n);
+            }
+        }
+        Ok(Policy {
+            nice,
+            cpus: match cpus {
+                None => None,
+                Some(c) => Some(parse_cpus(c)?),
+            },
+        })
+    }
+
+    fn is_empty(&self) -> bool { self.nice.is_none() && self.cpus.is_none() }
+}
+
+/// Parses a CPU list in the format of `taskset --cpu-list`, such as `0-1,3`.
+pub fn parse_cpus(s: &str) -> Result<Vec<usize>, Error> {
+    let mut cpus = Vec::new();
+    for part in s.split(',') {
+        let part = part.trim();
+        let (start, end) = match part.find('-') {
+            None => {
+                let c = part.parse()
+                            .map_err(|_| format_err!("bad CPU {:?} in {:?}", part, s))?;
+                (c, c)
+            },
+            Some(i) => {
+                let start = part[..i].parse();
+                let end = part[i+1..].parse();
+                match (start, end) {
+                    (Ok(start), Ok(end)) if start <= end => (start, end),
+                    _ => bail!("bad CPU range {:?} in {:?}", part, s),
+                }
+            },
+        };
+        for c in start ..= end {
+            if c >= MAX_CPUS {
+                bail!("CPU {} is beyond the maximum of {}", c, MAX_CPUS - 1);
+            }
+            if !cpus.contains(&c) {
+                cpus.push(c);
+            }
+        }
+    }
+    cpus.sort();
+    Ok(cpus)
+}
+
+const MAX_CPUS: usize = 1024;
+
+/// Sets the policy for threads of each class. This should be called once at startup, before
+/// any threads call `enter`.
+pub fn configure(recorder: Policy, web: Policy) -> Result<(), Error> {
+    if cfg!(not(target_os = "linux")) && (!recorder.is_empty() || !web.is_empty()) {
+        bail!("thread priority and CPU affinity are only supported on Linux");
+    }
+    *POLICIES.lock() = [recorder, web];
+    Ok(())
+}
+
+/// Applies the policy of the given class to the calling thread. Failures are logged rather than
+/// returned; they shouldn't stop recording.
+pub fn enter(class: Class) {
+    let p = POLICIES.lock()[class as usize].clone();
+    if let Err(e) = apply(&p) {
+        warn!("Unable to apply {:?} scheduling policy {:?}: {}", class, p, e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn apply(p: &Policy) -> Result<(), Error> {
+    use libc;
+    use std::io;
+    use std::mem;
+    if let Some(n) = p.nice {
+        // On Linux, setpriority with a thread id affects only that thread.
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, n) } < 0 {
+            let e = io::Error::last_os_error();
+            if n < 0 && e.raw_os_error() == Some(libc::EACCES) {
+                bail!("{}; raising priority requires CAP_SYS_NICE or a higher RLIMIT_NICE", e);
+            }
+            return Err(e.into());
+        }
+    }
+    if let Some(ref cpus) = p.cpus {
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for &c in cpus {
+            unsafe { libc::CPU_SET(c, &mut set) };
+        }
+        if unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn apply(_p: &Policy) -> Result<(), Error> { Ok(()) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpus() {
+        assert_eq!(parse_cpus("0").unwrap(), vec![0]);
+        assert_eq!(parse_cpus("3,0-1").unwrap(), vec![0, 1, 3]);
+        assert_eq!(parse_cpus("1-2, 2-3").unwrap(), vec![1, 2, 3]);
+        parse_cpus("").unwrap_err();
+        parse_cpus("2-1").unwrap_err();
+        parse_cpus("a").unwrap_err();
+        parse_cpus("1024").unwrap_err();
+        Policy::parse(Some(-21), None).unwrap_err();
+        assert_eq!(Policy::parse(Some(-5), Some("2-3")).unwrap(), Policy {
+            nice: Some(-5),
+            cpus: Some(vec![2, 3]),
+        });
+    }
+}
@@ -0,0 +1,184 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Lightweight spans for profiling, optionally exported as a trace file.
+//!
+//! Spans are cheap (an atomic load) unless tracing has been started with `start`. Then each
+//! completed span is appended to the trace file in the [Chrome trace event
+//! format](https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU/),
+//! which can be opened in `chrome://tracing` or [Perfetto](https://ui.perfetto.dev/).
+
+use failure::Error;
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::thread;
+use time;
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+static NEXT_TID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref OUT: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+}
+
+thread_local! {
+    /// This thread's id within the trace, or 0 if it's not yet been assigned.
+    static TID: Cell<usize> = Cell::new(0);
+}
+
+/// Starts writing completed spans to the given file, which is truncated.
+pub fn start(path: &Path) -> Result<(), Error> {
+    let mut f = BufWriter::new(File::create(path)?);
+
+    // The closing `]` is optional, so the file is valid even if the process doesn't exit cleanly.
+    f.write_all(b"[\n")?;
+    *OUT.lock() = Some(f);
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Stops tracing, flushing the trace file.
+pub fn stop() -> Result<(), Error> {
+    ENABLED.store(false, Ordering::SeqCst);
+    if let Some(mut f) = OUT.lock().take() {
+        f.write_all(b"{}]\n")?;
+        f.flush()?;
+    }
+    Ok(())
+}
+
+/// A span which is recorded when dropped.
+#[must_use]
+pub struct Span {
+    name: &'static str,
+    detail: Option<String>,
+    start_ns: u64,
+}
+
+/// Starts a span with the given name.
+pub fn span(name: &'static str) -> Option<Span> {
+    span_with(name, || None)
+}
+
+/// Starts a span with the given name and detail (such as a stream id), which is only computed
+/// if tracing is enabled.
+pub fn span_with<F: FnOnce() -> Option<String>>(name: &'static str, detail_f: F) -> Option<Span> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some(Span {
+        name,
+        detail: detail_f(),
+        start_ns: time::precise_time_ns(),
+    })
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let dur_ns = time::precise_time_ns() - self.start_ns;
+        let (tid, new_thread) = TID.with(|t| match t.get() {
+            0 => {
+                let tid = NEXT_TID.fetch_add(1, Ordering::Relaxed) + 1;
+                t.set(tid);
+                (tid, true)
+            },
+            tid => (tid, false),
+        });
+        let mut buf = String::new();
+        if new_thread {
+            buf.push_str(r#"{"name":"thread_name","ph":"M","pid":1,"tid":"#);
+            write!(&mut buf, r#"{},"args":{{"name":"#, tid).unwrap();
+            push_json_str(&mut buf, thread::current().name().unwrap_or("unnamed"));
+            buf.push_str("}},\n");
+        }
+        event(&mut buf, self.name, self.detail.as_ref().map(String::as_str), tid,
+              self.start_ns / 1000, dur_ns / 1000);
+        let mut l = OUT.lock();
+        if let Some(ref mut f) = *l {
+            if let Err(e) = f.write_all(buf.as_bytes()) {
+                warn!("Unable to write trace; stopping: {}", e);
+                ENABLED.store(false, Ordering::SeqCst);
+                *l = None;
+            }
+        }
+    }
+}
+
+/// Appends a complete (`X`) event to `buf`.
+fn event(buf: &mut String, name: &str, detail: Option<&str>, tid: usize, ts_us: u64,
+         dur_us: u64) {
+    buf.push_str(r#"{"name":"#);
+    push_json_str(buf, name);
+    write!(buf, r#","ph":"X","pid":1,"tid":{},"ts":{},"dur":{}"#, tid, ts_us, dur_us).unwrap();
+    if let Some(d) = detail {
+        buf.push_str(r#","args":{"detail":"#);
+        push_json_str(buf, d);
+        buf.push('}');
+    }
+    buf.push_str("},\n");
+}
+
+/// Appends `s` as a quoted JSON string.
+fn push_json_str(buf: &mut String, s: &str) {
+    buf.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(buf, "\\u{:04x}", c as u32).unwrap(),
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled() {
+        assert!(span("foo").is_none());
+        assert!(span_with("foo", || panic!("detail computed while disabled")).is_none());
+    }
+
+    #[test]
+    fn format() {
+        let mut buf = String::new();
+        event(&mut buf, "flush", Some("reason \"x\"\n"), 2, 1000, 50);
+        assert_eq!(buf, "{\"name\":\"flush\",\"ph\":\"X\",\"pid\":1,\"tid\":2,\"ts\":1000,\
+                         \"dur\":50,\"args\":{\"detail\":\"reason \\\"x\\\"\\u000a\"}},\n");
+    }
+}
@@ -0,0 +1,130 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tamper-evident hash chains over each stream's recordings.
+//!
+//! Each committed recording's `recording_integrity.chain_sha1` is a SHA-1 hash of the previous
+//! recording's chain hash and the recording's own metadata, including its `sample_file_sha1` and
+//! a hash of its video index. Modifying or removing a recording in the middle of a stream thus
+//! breaks the following link. Retention only deletes a stream's oldest recordings, so the rest
+//! of the chain stays verifiable.
+//!
+//! Removing the newest recordings or rewriting the whole chain can't be detected from the chain
+//! alone. For this, the stream's chain head is periodically *anchored*: recorded in the
+//! `chain_anchor` table and published (as `Change::ChainAnchored`) so that an external witness
+//! can keep its own copy. `check::run` verifies both the links and the anchors.
+
+use db::{CompositeId, RecordingFlags, RecordingToInsert};
+use failure::Error;
+use openssl::hash;
+
+/// The minimum time between anchors of a stream's chain.
+pub const ANCHOR_INTERVAL_SEC: i64 = 3600;
+
+/// The chain hash preceding a stream's first recording.
+pub const GENESIS: [u8; 20] = [0u8; 20];
+
+/// A stream's chain head as of a given recording, as in the `chain_anchor` table.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Anchor {
+    /// The most recent recording covered by the anchor.
+    pub id: CompositeId,
+    pub chain_sha1: [u8; 20],
+
+    /// The wall time at which the anchor was made, in seconds since epoch.
+    pub time_sec: i64,
+}
+
+/// Returns the chain hash of recording `id` given the chain hash of its predecessor.
+///
+/// The `Archived` flag is excluded: it's set after the recording is linked (see
+/// `LockedDatabase::archive_recordings`), so including it would break the chain.
+pub fn link(prev: &[u8; 20], id: CompositeId, r: &RecordingToInsert) -> Result<[u8; 20], Error> {
+    let mut h = hash::Hasher::new(hash::MessageDigest::sha1())?;
+    h.update(&prev[..])?;
+    h.update(&be(id.0 as u64, 8))?;
+    h.update(&be(r.start.0 as u64, 8))?;
+    let flags = r.flags & !(RecordingFlags::Archived as i32);
+    for &v in &[r.duration_90k, r.run_offset, flags, r.sample_file_bytes, r.video_samples,
+                r.video_sync_samples, r.video_sample_entry_id] {
+        h.update(&be(v as u32 as u64, 4))?;
+    }
+    h.update(&r.sample_file_sha1[..])?;
+    h.update(&hash::hash(hash::MessageDigest::sha1(), &r.video_index)?)?;
+    let mut out = [0u8; 20];
+    out.copy_from_slice(&h.finish()?);
+    Ok(out)
+}
+
+/// Returns the low `len` bytes of `v` in big-endian order.
+fn be(v: u64, len: usize) -> Vec<u8> {
+    (0..len).rev().map(|i| (v >> (8 * i)) as u8).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use db::{CompositeId, RecordingFlags, RecordingToInsert};
+    use super::*;
+
+    #[test]
+    fn link_covers_predecessor_and_metadata() {
+        let id = CompositeId::new(1, 1);
+        let r = RecordingToInsert {
+            sample_file_bytes: 42,
+            duration_90k: 90000,
+            video_index: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let a = link(&GENESIS, id, &r).unwrap();
+        assert_eq!(a, link(&GENESIS, id, &r).unwrap());
+        assert!(a != link(&[1u8; 20], id, &r).unwrap());
+        assert!(a != link(&GENESIS, CompositeId::new(1, 2), &r).unwrap());
+        let mut r2 = r.clone();
+        r2.video_index[0] = 4;
+        assert!(a != link(&GENESIS, id, &r2).unwrap());
+        let mut r3 = r.clone();
+        r3.sample_file_sha1[19] = 1;
+        assert!(a != link(&GENESIS, id, &r3).unwrap());
+    }
+
+    #[test]
+    fn link_ignores_archived_flag() {
+        let id = CompositeId::new(1, 1);
+        let r = RecordingToInsert {
+            flags: RecordingFlags::TrailingZero as i32,
+            sample_file_bytes: 42,
+            duration_90k: 90000,
+            ..Default::default()
+        };
+        let mut archived = r.clone();
+        archived.flags |= RecordingFlags::Archived as i32;
+        assert_eq!(link(&GENESIS, id, &r).unwrap(), link(&GENESIS, id, &archived).unwrap());
+    }
+}
@@ -30,15 +30,16 @@
 
 //! Subcommand to check the database and sample file dir for errors.
 
+use chain;
 use db::{self, CompositeId, FromSqlUuid};
 use dir;
 use failure::Error;
 use fnv::FnvHashMap;
+use platform;
 use raw;
 use recording;
 use rusqlite::{self, types::ToSql};
 use schema;
-use std::os::unix::ffi::OsStrExt;
 use std::fs;
 
 pub struct Options {
@@ -52,7 +53,8 @@ pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
     let mut streams_by_dir: FnvHashMap<i32, Dir> = FnvHashMap::default();
     {
         let mut dir_stmt = conn.prepare(r#"
-            select d.id, d.path, d.uuid, d.last_complete_open_id, o.uuid
+            select d.id, d.path, d.uuid, d.last_complete_open_id, o.uuid, d.network_fs,
+                   d.wrapped_key is not null
             from sample_file_dir d left join open o on (d.last_complete_open_id = o.id)
         "#)?;
         let mut garbage_stmt = conn.prepare_cached(
@@ -66,6 +68,8 @@ pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
             let dir_uuid: FromSqlUuid = row.get_checked(2)?;
             let open_id = row.get_checked(3)?;
             let open_uuid: FromSqlUuid = row.get_checked(4)?;
+            let network_fs: bool = row.get_checked(5)?;
+            let encrypted: bool = row.get_checked(6)?;
             meta.db_uuid.extend_from_slice(&db_uuid.as_bytes()[..]);
             meta.dir_uuid.extend_from_slice(&dir_uuid.0.as_bytes()[..]);
             {
@@ -75,8 +79,8 @@ pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
             }
 
             // Open the directory (checking its metadata) and hold it open (for the lock).
-            let _dir = dir::SampleFileDir::open(&dir_path, &meta)?;
-            let mut streams = read_dir(&dir_path, opts)?;
+            let _dir = dir::SampleFileDir::open(&dir_path, &meta, network_fs, None)?;
+            let mut streams = read_dir(&dir_path, encrypted, opts)?;
             let mut rows = garbage_stmt.query(&[&dir_id])?;
             while let Some(row) = rows.next() {
                 let row = row?;
@@ -91,18 +95,26 @@ pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
     // Scan known streams.
     {
         let mut stmt = conn.prepare(r#"
-            select id, sample_file_dir_id from stream where sample_file_dir_id is not null
+            select id, sample_file_dir_id, mirror_sample_file_dir_id from stream
+            where sample_file_dir_id is not null
         "#)?;
         let mut rows = stmt.query(&[] as &[&ToSql])?;
         while let Some(row) = rows.next() {
             let row = row?;
             let stream_id = row.get_checked(0)?;
             let dir_id = row.get_checked(1)?;
+            let mirror_dir_id: Option<i32> = row.get_checked(2)?;
+
+            // Mirrored copies aren't tracked in the database; see mirror.rs.
+            if let Some(d) = mirror_dir_id.and_then(|m| streams_by_dir.get_mut(&m)) {
+                d.remove(&stream_id);
+            }
             let stream = match streams_by_dir.get_mut(&dir_id) {
                 None => Stream::default(),
                 Some(d) => d.remove(&stream_id).unwrap_or_else(Stream::default),
             };
             compare_stream(conn, stream_id, opts, stream)?;
+            verify_chain(conn, stream_id)?;
         }
     }
 
@@ -122,6 +134,99 @@ pub fn run(conn: &rusqlite::Connection, opts: &Options) -> Result<(), Error> {
     Ok(())
 }
 
+/// Verifies the stream's hash chain (see `chain`): that each recording links to its
+/// predecessor, that the stream's chain head matches its newest recording, and that each
+/// anchor matches the chain or refers to a recording since deleted by retention.
+fn verify_chain(conn: &rusqlite::Connection, stream_id: i32) -> Result<(), Error> {
+    let head: Option<Vec<u8>> = conn.query_row_and_then(
+        "select chain_sha1 from stream where id = ?", &[&stream_id], |row| row.get_checked(0))?;
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          r.composite_id,
+          r.run_offset,
+          r.flags,
+          r.sample_file_bytes,
+          r.start_time_90k,
+          r.duration_90k,
+          r.video_samples,
+          r.video_sync_samples,
+          r.video_sample_entry_id,
+          i.sample_file_sha1,
+          i.chain_sha1,
+          p.video_index
+        from
+          recording r
+          join recording_integrity i on (r.composite_id = i.composite_id)
+          join recording_playback p on (r.composite_id = p.composite_id)
+        where
+          r.stream_id = ?
+        order by
+          r.composite_id
+    "#)?;
+    let mut rows = stmt.query(&[&stream_id])?;
+    let mut links = FnvHashMap::default();
+    let mut prev: Option<(CompositeId, [u8; 20])> = None;
+    let mut oldest = None;
+    let mut newest = None;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let id = CompositeId(row.get_checked(0)?);
+        if oldest.is_none() {
+            oldest = Some(id);
+        }
+        newest = Some(id);
+        let chain_sha1 = match row.get_checked::<_, Option<Vec<u8>>>(10)? {
+            None => {  // made before the chain was started.
+                prev = None;
+                continue;
+            },
+            Some(c) => raw::sha1_from_blob(c)?,
+        };
+        if let Some((prev_id, prev_sha1)) = prev {
+            let sample_file_sha1: Vec<u8> = row.get_checked(9)?;
+            let mut r = db::RecordingToInsert {
+                run_offset: row.get_checked(1)?,
+                flags: row.get_checked(2)?,
+                sample_file_bytes: row.get_checked(3)?,
+                start: recording::Time(row.get_checked(4)?),
+                duration_90k: row.get_checked(5)?,
+                video_samples: row.get_checked(6)?,
+                video_sync_samples: row.get_checked(7)?,
+                video_sample_entry_id: row.get_checked(8)?,
+                video_index: row.get_checked(11)?,
+                ..Default::default()
+            };
+            r.sample_file_sha1 = raw::sha1_from_blob(sample_file_sha1)?;
+            if prev_id.recording() + 1 != id.recording() {
+                error!("Stream {} hash chain broken: recordings after {} and before {} are \
+                        missing", stream_id, prev_id, id);
+            } else if chain::link(&prev_sha1, id, &r)? != chain_sha1 {
+                error!("Recording {} doesn't match stream {}'s hash chain", id, stream_id);
+            }
+        }
+        links.insert(id, chain_sha1);
+        prev = Some((id, chain_sha1));
+    }
+    if let (Some(h), Some((id, c))) = (head, prev) {
+        if h[..] != c[..] {
+            error!("Stream {} hash chain head doesn't match newest recording {}", stream_id, id);
+        }
+    }
+    for a in raw::list_chain_anchors(conn, stream_id)? {
+        if let Some(c) = links.get(&a.id) {
+            if *c != a.chain_sha1 {
+                error!("Recording {} doesn't match anchor made at {}", a.id, a.time_sec);
+            }
+        } else if newest.map(|n| a.id.0 > n.0).unwrap_or(false) {
+            error!("Stream {} anchor made at {} covers recording {}, after its newest recording; \
+                    recordings have been removed", stream_id, a.time_sec, a.id);
+        } else if oldest.map(|o| a.id.0 > o.0).unwrap_or(false) {
+            error!("Anchored recording {} is missing", a.id);
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Eq, PartialEq)]
 struct RecordingSummary {
     bytes: u64,
@@ -148,6 +253,9 @@ struct Recording {
 
     /// True iff a `garbage` row is present.
     garbage_row: bool,
+
+    /// True iff the `recording` row has the archived flag, so no file is expected.
+    archived: bool,
 }
 
 type Stream = FnvHashMap<i32, Recording>;
@@ -176,15 +284,18 @@ fn summarize_index(video_index: &[u8]) -> Result<RecordingSummary, Error> {
 
 /// Reads through the given sample file directory.
 /// Logs unexpected files and creates a hash map of the files found there.
-/// If `opts.compare_lens` is set, the values are lengths; otherwise they're insignificant.
-fn read_dir(path: &str, opts: &Options) -> Result<Dir, Error> {
+/// If `opts.compare_lens` is set, the values are lengths (of the plaintext, if `encrypted`);
+/// otherwise they're insignificant.
+fn read_dir(path: &str, encrypted: bool, opts: &Options) -> Result<Dir, Error> {
     let mut dir = Dir::default();
     for e in fs::read_dir(path)? {
         let e = e?;
         let f = e.file_name();
-        let f = f.as_bytes();
+        let f = platform::name_bytes(&f);
         match f {
             b"meta" | b"meta-tmp" => continue,
+            b"lease" | b"lease.tmp" => continue,
+            b"lock" => continue,
             _ => {},
         };
         let id = match dir::parse_id(f) {
@@ -194,7 +305,11 @@ fn read_dir(path: &str, opts: &Options) -> Result<Dir, Error> {
                 continue;
             }
         };
-        let len = if opts.compare_lens { e.metadata()?.len() } else { 0 };
+        let len = match (opts.compare_lens, encrypted) {
+            (false, _) => 0,
+            (true, false) => e.metadata()?.len(),
+            (true, true) => dir::Cipher::plaintext_len(e.metadata()?.len()),
+        };
         let stream = dir.entry(id.stream()).or_insert_with(Stream::default);
         stream.entry(id.recording()).or_insert_with(Recording::default).file = Some(len);
     }
@@ -226,16 +341,18 @@ fn compare_stream(conn: &rusqlite::Connection, stream_id: i32, opts: &Options,
         while let Some(row) = rows.next() {
             let row = row?;
             let id = CompositeId(row.get_checked(0)?);
+            let flags: i32 = row.get_checked(1)?;
+            let archived = db::RecordingFlags::Archived as i32;
             let s = RecordingSummary {
-                flags: row.get_checked(1)?,
+                flags: flags & !archived,
                 bytes: row.get_checked::<_, i64>(2)? as u64,
                 duration: row.get_checked(3)?,
                 video_samples: row.get_checked(4)?,
                 video_sync_samples: row.get_checked(5)?,
             };
-            stream.entry(id.recording())
-                  .or_insert_with(Recording::default)
-                  .recording_row = Some(s);
+            let r = stream.entry(id.recording()).or_insert_with(Recording::default);
+            r.recording_row = Some(s);
+            r.archived = (flags & archived) != 0;
         }
     }
 
@@ -309,9 +426,13 @@ fn compare_stream(conn: &rusqlite::Connection, stream_id: i32, opts: &Options,
             None => error!("Recording {} missing playback row: {:#?}", id, recording),
         }
         match recording.file {
+            Some(_) if recording.archived => {
+                error!("Archived recording {} still has a file: {:#?}", id, recording);
+            },
             Some(len) => if opts.compare_lens && r.bytes != len {
                 error!("Recording {} length mismatch: {:#?}", id, recording);
             },
+            None if recording.archived => {},
             None => error!("Recording {} missing file: {:#?}", id, recording),
         }
     }
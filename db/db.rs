@@ -53,6 +53,8 @@
 //!     cycles.
 
 use base::clock::{self, Clocks};
+use base::trace;
+use chain;
 use dir;
 use failure::Error;
 use fnv::{self, FnvHashMap, FnvHashSet};
@@ -77,7 +79,7 @@ use time;
 use uuid::Uuid;
 
 /// Expected schema version. See `guide/schema.md` for more information.
-pub const EXPECTED_VERSION: i32 = 3;
+pub const EXPECTED_VERSION: i32 = 4;
 
 const GET_RECORDING_PLAYBACK_SQL: &'static str = r#"
     select
@@ -93,8 +95,10 @@ const INSERT_VIDEO_SAMPLE_ENTRY_SQL: &'static str = r#"
                             values (:sha1, :width, :height, :rfc6381_codec, :data)
 "#;
 
-const UPDATE_NEXT_RECORDING_ID_SQL: &'static str =
-    "update stream set next_recording_id = :next_recording_id where id = :stream_id";
+const UPDATE_NEXT_RECORDING_ID_SQL: &'static str = r#"
+    update stream set next_recording_id = :next_recording_id, chain_sha1 = :chain_sha1
+    where id = :stream_id
+"#;
 
 pub struct FromSqlUuid(pub Uuid);
 
@@ -147,6 +151,10 @@ pub struct ListRecordingsRow {
     pub run_offset: i32,
     pub open_id: u32,
     pub flags: i32,
+
+    /// The minimum and maximum frame intervals; see `RecordingToInsert`.
+    pub min_frame_interval_90k: i32,
+    pub max_frame_interval_90k: i32,
 }
 
 /// A row used in `list_aggregated_recordings`.
@@ -163,6 +171,46 @@ pub struct ListAggregatedRecordingsRow {
     pub open_id: u32,
     pub first_uncommitted: Option<i32>,
     pub growing: bool,
+    pub degraded: bool,
+
+    /// True iff the aggregated recordings' sample files have been archived; see
+    /// `LockedDatabase::archive_recordings`. A row never mixes archived and unarchived recordings.
+    pub archived: bool,
+
+    /// The minimum and maximum frame intervals of any of the aggregated recordings.
+    pub min_frame_interval_90k: i32,
+    pub max_frame_interval_90k: i32,
+}
+
+/// When `list_aggregated_recordings` splits a run of recordings into multiple rows: a row ends
+/// before the recording which would make it reach `duration` or exceed `bytes` or `recordings`.
+/// A single recording is never split, so a row may exceed these if it has only one recording.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AggregationSplit {
+    pub duration: recording::Duration,
+    pub bytes: i64,
+    pub recordings: i32,
+}
+
+impl Default for AggregationSplit {
+    /// Splits only where the run is interrupted.
+    fn default() -> Self {
+        AggregationSplit {
+            duration: recording::Duration(i64::max_value()),
+            bytes: i64::max_value(),
+            recordings: i32::max_value(),
+        }
+    }
+}
+
+impl AggregationSplit {
+    /// Returns true if `a` should end rather than be extended by `row`.
+    fn should_split(&self, a: &ListAggregatedRecordingsRow, row: &ListRecordingsRow) -> bool {
+        let new_dur = a.time.end - a.time.start + recording::Duration(row.duration_90k as i64);
+        new_dur >= self.duration ||
+        a.sample_file_bytes + row.sample_file_bytes as i64 > self.bytes ||
+        a.ids.end - a.ids.start >= self.recordings
+    }
 }
 
 /// Select fields from the `recordings_playback` table. Retrieve with `with_recording_playback`.
@@ -174,6 +222,8 @@ pub struct RecordingPlayback<'a> {
 /// Bitmask in the `flags` field in the `recordings` table; see `schema.sql`.
 pub enum RecordingFlags {
     TrailingZero = 1,
+    Degraded = 2,
+    Archived = 4,
 
     // These values (starting from high bit on down) are never written to the database.
     Growing = 1 << 30,
@@ -194,6 +244,270 @@ pub struct RecordingToInsert {
     pub video_sample_entry_id: i32,
     pub video_index: Vec<u8>,
     pub sample_file_sha1: [u8; 20],
+
+    /// The shortest and longest durations of any frame, excluding a trailing frame of zero
+    /// duration, as maintained by `recording::SampleIndexEncoder`. Both are 0 if there are no
+    /// such frames.
+    pub min_frame_interval_90k: i32,
+    pub max_frame_interval_90k: i32,
+
+    /// Packets from the stream's metadata track, if any, as encoded by
+    /// `recording::append_metadata`.
+    pub metadata: Vec<u8>,
+}
+
+/// An object detected within an event; see `LockedDatabase::add_event_detections`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Detection {
+    /// What was detected, such as `person`.
+    pub label: String,
+
+    /// The detector's confidence, from 0 to 1, if known.
+    pub score: Option<f64>,
+
+    /// The bounding box, as fractions of the frame's width and height.
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// An event to pass to `add_event`.
+#[derive(Clone, Debug)]
+pub struct EventToInsert {
+    pub camera_id: i32,
+
+    /// The kind of event, such as `sound_level`.
+    pub type_: String,
+    pub time: Range<recording::Time>,
+    pub description: Option<String>,
+    pub score: Option<f64>,
+}
+
+/// A Web Push subscription, as supplied by a browser's `PushManager.subscribe()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PushSubscription {
+    pub endpoint: String,
+
+    /// The subscription's public key and authentication secret, base64url-encoded.
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// The state of a background `Job`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum JobState {
+    Pending = 0,
+    Running = 1,
+    Done = 2,
+    Failed = 3,
+    Cancelled = 4,
+}
+
+impl JobState {
+    pub fn parse(state: i32) -> Option<Self> {
+        match state {
+            0 => Some(JobState::Pending),
+            1 => Some(JobState::Running),
+            2 => Some(JobState::Done),
+            3 => Some(JobState::Failed),
+            4 => Some(JobState::Cancelled),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn is_finished(self) -> bool {
+        match self {
+            JobState::Pending | JobState::Running => false,
+            _ => true,
+        }
+    }
+}
+
+/// A background job, as stored in the `job` table. The database only persists jobs; running them
+/// is the server's responsibility.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Job {
+    pub id: i32,
+    pub uuid: Uuid,
+
+    /// The kind of job, such as `export`.
+    pub type_: String,
+
+    /// Kind-specific parameters, as a JSON object.
+    pub params: String,
+    pub state: JobState,
+    pub created_sec: i64,
+    pub finished_sec: Option<i64>,
+
+    /// A kind-specific JSON result (if done) or an error message (if failed).
+    pub result: Option<String>,
+}
+
+/// A change of interest to live observers such as the web UI; see `LockedDatabase::watch`.
+#[derive(Clone, Debug)]
+pub enum Change {
+    /// Recordings were committed to the given stream by a flush.
+    RecordingsAdded { stream_id: i32, count: usize },
+
+    /// The state of the given stream's health changed; see `StreamHealth::state`.
+    StreamHealth { stream_id: i32 },
+
+    /// An event was added via `add_event`.
+    EventAdded { id: i64, event: EventToInsert },
+
+    /// An existing event was extended by merging a new one into it; see `EventMerge`. `event`
+    /// has the full, updated time range.
+    EventUpdated { id: i64, event: EventToInsert },
+
+    /// The given stream's hash chain was anchored by a flush. See `chain`.
+    ChainAnchored { stream_id: i32, anchor: chain::Anchor },
+
+    /// Whether the given camera is reachable on the network changed; see `CameraReachability`.
+    CameraReachability { camera_id: i32 },
+
+    /// The given stream exceeded its `retain_bytes`, starting a grace period before its old
+    /// recordings are deleted; see `LockedDatabase::set_retention_grace`.
+    RetentionExceeded { stream_id: i32 },
+}
+
+/// How `add_event` merges bursts of events, so that (say) a tree waving in the wind produces one
+/// long event rather than hundreds of short ones. Events are merged only with the most recent
+/// event of the same camera, type, and description.
+#[derive(Clone, Debug)]
+pub struct EventMerge {
+    /// An event starting less than this long after the end of the previous one is merged into it.
+    /// Zero disables merging.
+    pub min_gap: recording::Duration,
+
+    /// Events aren't merged if the result would be longer than this.
+    pub max_duration: recording::Duration,
+}
+
+impl Default for EventMerge {
+    fn default() -> Self {
+        EventMerge {
+            min_gap: recording::Duration(30 * TIME_UNITS_PER_SEC),
+            max_duration: recording::Duration(10 * 60 * TIME_UNITS_PER_SEC),
+        }
+    }
+}
+
+impl EventMerge {
+    /// Returns the merger of `next` into `prev`, if they should be merged.
+    fn merge(&self, prev: &EventToInsert, next: &EventToInsert) -> Option<EventToInsert> {
+        if self.min_gap.0 == 0 || next.time.start < prev.time.start ||
+           next.time.start - prev.time.end >= self.min_gap {
+            return None;
+        }
+        let end = cmp::max(prev.time.end, next.time.end);
+        if end - prev.time.start > self.max_duration {
+            return None;
+        }
+        let score = match (prev.score, next.score) {
+            (Some(p), Some(n)) => Some(p.max(n)),
+            (p, n) => p.or(n),
+        };
+        Some(EventToInsert {
+            time: prev.time.start .. end,
+            score,
+            ..prev.clone()
+        })
+    }
+}
+
+/// SQLite's `synchronous` setting; see <https://www.sqlite.org/pragma.html#pragma_synchronous>.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    /// Syncs on every commit. A flush is durable once it returns.
+    Full,
+
+    /// In WAL mode, syncs only on checkpoints. The database can't be corrupted, but the most
+    /// recent flushes may be lost on power failure. Those might include deletions of recordings
+    /// whose sample files have already been unlinked; `moonfire-nvr check` reports these.
+    Normal,
+}
+
+impl Synchronous {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "full" => Synchronous::Full,
+            "normal" => Synchronous::Normal,
+            _ => bail!("unknown synchronous mode {:?}; expected full or normal", s),
+        })
+    }
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Synchronous::Full => "full",
+            Synchronous::Normal => "normal",
+        }
+    }
+}
+
+/// Tuning of the SQLite connection, applied with `Pragmas::apply` before `Database::new`.
+#[derive(Clone, Debug)]
+pub struct Pragmas {
+    /// The size of the page cache, in KiB.
+    pub cache_kib: u64,
+
+    /// The maximum bytes of the database file to access via `mmap` rather than `read`. Zero
+    /// disables memory-mapped I/O.
+    pub mmap_bytes: u64,
+
+    pub synchronous: Synchronous,
+}
+
+impl Default for Pragmas {
+    /// Returns SQLite's defaults on 32-bit platforms, where address space is scarce, and more
+    /// generous settings elsewhere.
+    fn default() -> Self {
+        if cfg!(target_pointer_width = "64") {
+            Pragmas {
+                cache_kib: 16 << 10,
+                mmap_bytes: 256 << 20,
+                synchronous: Synchronous::Full,
+            }
+        } else {
+            Pragmas {
+                cache_kib: 2000,
+                mmap_bytes: 0,
+                synchronous: Synchronous::Full,
+            }
+        }
+    }
+}
+
+impl Pragmas {
+    pub fn apply(&self, conn: &rusqlite::Connection) -> Result<(), Error> {
+        // A negative cache_size is in KiB rather than pages.
+        conn.execute_batch(&format!("pragma cache_size = -{}; pragma mmap_size = {}; \
+                                     pragma synchronous = {};",
+                                    self.cache_kib, self.mmap_bytes,
+                                    self.synchronous.as_str()))?;
+        Ok(())
+    }
+}
+
+/// A row used in `list_events`.
+#[derive(Clone, Debug)]
+pub struct ListEventsRow {
+    pub id: i64,
+    pub camera_id: i32,
+    pub type_: String,
+    pub time: Range<recording::Time>,
+    pub description: Option<String>,
+    pub score: Option<f64>,
 }
 
 impl RecordingToInsert {
@@ -209,6 +523,8 @@ impl RecordingToInsert {
             run_offset: self.run_offset,
             open_id,
             flags: self.flags | RecordingFlags::Uncommitted as i32,
+            min_frame_interval_90k: self.min_frame_interval_90k,
+            max_frame_interval_90k: self.max_frame_interval_90k,
         }
     }
 }
@@ -270,6 +586,15 @@ pub struct SampleFileDir {
     pub id: i32,
     pub path: String,
     pub uuid: Uuid,
+
+    /// True iff the directory is on a network filesystem; see `dir::SampleFileDir::open`.
+    pub network_fs: bool,
+
+    /// The number of bytes to keep free on the directory's filesystem; see `schema.sql`.
+    pub reserved_bytes: i64,
+
+    /// If the directory is encrypted, its key wrapped with the master key; see `dir::Cipher`.
+    wrapped_key: Option<Vec<u8>>,
     dir: Option<Arc<dir::SampleFileDir>>,
     last_complete_open: Option<Open>,
 
@@ -284,6 +609,9 @@ pub struct SampleFileDir {
 }
 
 impl SampleFileDir {
+    /// Returns true iff the given sample file is garbage which hasn't yet been unlinked.
+    pub fn needs_unlink(&self, id: CompositeId) -> bool { self.garbage_needs_unlink.contains(&id) }
+
     /// Returns a cloned copy of the directory, or Err if closed.
     ///
     /// Use `LockedDatabase::open_sample_file_dirs` prior to calling this method.
@@ -318,10 +646,376 @@ pub struct Camera {
     pub host: String,
     pub username: String,
     pub password: String,
+
+    /// The `(username, password)` in use before the most recent `rotate_camera_credentials`, if
+    /// any, for `rollback_camera_credentials`.
+    pub previous_credentials: Option<(String, String)>,
     pub streams: [Option<i32>; 2],
+
+    /// User-defined key/value labels, such as `location` => `garage`.
+    pub labels: BTreeMap<String, String>,
+
+    /// The tenant owning this camera, if any.
+    pub tenant_id: Option<i32>,
+
+    /// The camera's own event feed to subscribe to, if any.
+    pub event_source: Option<EventSource>,
+
+    /// How often to store snapshots of the camera, if at all.
+    pub snapshot_schedule: Option<SnapshotSchedule>,
+
+    /// The result of the most recent network reachability check. Not persisted.
+    pub reachability: CameraReachability,
+
+    /// The camera's clock, as of the most recent check. Not persisted.
+    pub clock: CameraClock,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// A schedule of snapshots of a camera, stored independently of recordings; see
+/// `add_scheduled_snapshot`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SnapshotSchedule {
+    /// How often to take a snapshot, in seconds. Snapshots are taken at multiples of this in local
+    /// time, so an interval which divides a day gives the same times each day.
+    pub interval_sec: i64,
+
+    /// How long to keep snapshots, in days.
+    pub retain_days: i64,
+}
+
+impl SnapshotSchedule {
+    fn check(&self) -> Result<(), Error> {
+        if self.interval_sec <= 0 {
+            bail!("snapshot interval {} sec must be positive", self.interval_sec);
+        }
+        if self.retain_days <= 0 {
+            bail!("snapshot retention {} days must be positive", self.retain_days);
+        }
+        Ok(())
+    }
+}
+
+/// Video encoder settings to push to a camera via ONVIF; see `onvif::set_encoder`. (For
+/// `StreamSource::V4l2`, they're instead applied when encoding locally.) `None` leaves the
+/// camera's own setting alone.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct EncoderSettings {
+    /// The key frame interval ("GOP length"), in frames.
+    pub gov_length: Option<i32>,
+    pub bitrate_kbps: Option<i32>,
+    pub frame_rate: Option<i32>,
+
+    /// The resolution, as `(width, height)`.
+    pub resolution: Option<(i32, i32)>,
+}
+
+impl EncoderSettings {
+    /// Returns true if there's nothing to push.
+    pub fn is_empty(&self) -> bool { *self == EncoderSettings::default() }
+
+    fn check(&self) -> Result<(), Error> {
+        for &(name, v) in &[("GOP length", self.gov_length),
+                            ("bitrate", self.bitrate_kbps),
+                            ("frame rate", self.frame_rate),
+                            ("width", self.resolution.map(|r| r.0)),
+                            ("height", self.resolution.map(|r| r.1))] {
+            if let Some(v) = v {
+                if v <= 0 {
+                    bail!("encoder {} {} must be positive", name, v);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What was found and cleaned up from the previous run when the database was opened, so that
+/// problems during an unattended restart (such as a crash or power loss overnight) are noticed
+/// rather than discovered as missing footage weeks later. See `LockedDatabase::startup_report`.
+#[derive(Clone, Debug, Default)]
+pub struct StartupReport {
+    /// When the previous run last flushed or closed the database, if there was one.
+    pub previous_run_end: Option<recording::Time>,
+
+    /// Keyed by stream id. Streams with nothing to report are omitted.
+    pub streams: BTreeMap<i32, StreamStartupReport>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StreamStartupReport {
+    /// Recordings written by the previous run but never committed to the database, such as due to
+    /// a crash or power loss before the next flush. Their sample files are deleted when the
+    /// stream's syncer starts, rolling them back.
+    pub abandoned_recordings: usize,
+
+    /// Recordings deleted by the previous run whose sample files hadn't yet been unlinked.
+    pub garbage_collected: usize,
+
+    /// For a stream configured to record, the time from the end of its newest recording to
+    /// startup: footage which is missing because nothing was recording.
+    pub gap: Option<recording::Duration>,
+}
+
+/// Whether a camera answers on the network, independent of its streams. See
+/// `LockedDatabase::update_camera_reachability`.
+#[derive(Clone, Debug, Default)]
+pub struct CameraReachability {
+    /// `None` if the camera hasn't been checked yet.
+    pub reachable: Option<bool>,
+
+    /// The error message of the most recent check, if it failed.
+    pub last_error: Option<String>,
+}
+
+/// How far a camera's clock is from the server's. See `LockedDatabase::update_camera_clock`.
+#[derive(Clone, Debug, Default)]
+pub struct CameraClock {
+    /// The camera's time minus the server's, in seconds, as of the most recent successful check.
+    pub skew_sec: Option<i64>,
+
+    /// True iff the camera sets its clock via NTP, as of the most recent successful check.
+    pub ntp: Option<bool>,
+
+    /// The error message of the most recent check, if it failed.
+    pub last_error: Option<String>,
+}
+
+/// What `LockedDatabase::teardown_camera` removed.
+#[derive(Clone, Debug, Default)]
+pub struct CameraTeardown {
+    pub streams: usize,
+
+    /// The number of recordings deleted, including archived ones.
+    pub recordings: usize,
+    pub events: usize,
+
+    /// The `(sample_file_dir_id, id)` of each sample file moved to the garbage table.
+    pub sample_files: Vec<(i32, CompositeId)>,
+}
+
+/// A group of cameras (such as an apartment or business unit) sharing a storage quota.
+#[derive(Clone, Debug)]
+pub struct Tenant {
+    pub id: i32,
+    pub uuid: Uuid,
+    pub short_name: String,
+
+    /// The maximum total bytes of completed recordings to retain across all of the tenant's
+    /// streams, or `None` for no tenant-wide limit. This is enforced by the syncer in addition to
+    /// each stream's own `retain_bytes`.
+    pub retain_bytes: Option<i64>,
+
+    /// If exports by the tenant's users must be watermarked with the exporting user and time.
+    pub watermark_exports: bool,
+}
+
+/// A user, as looked up by name when authenticating a request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+
+    /// The tenant this user belongs to, if any. Such a user can see only that tenant's cameras.
+    pub tenant_id: Option<i32>,
+}
+
+/// The container format of an export.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExportContainer {
+    Mp4,
+
+    /// Matroska, remuxed from `.mp4` by an external ffmpeg.
+    Matroska,
+}
+
+impl ExportContainer {
+    /// Returns the value of the `export_preset.container` column, which is also the file
+    /// extension.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExportContainer::Mp4 => "mp4",
+            ExportContainer::Matroska => "mkv",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "mp4" => Some(ExportContainer::Mp4),
+            "mkv" => Some(ExportContainer::Matroska),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ExportContainer {
+    fn default() -> Self { ExportContainer::Mp4 }
+}
+
+/// A named set of export options, so that organizational policy (such as "always watermark,
+/// never more than 720p for external sharing") is applied consistently.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExportPreset {
+    pub id: i32,
+    pub name: String,
+
+    /// The maximum height of the exported video, in pixels; taller video is scaled down.
+    pub max_height: Option<i32>,
+
+    /// If exports must be watermarked with the requesting user.
+    pub watermark: bool,
+
+    /// If exports include the timestamp subtitle track.
+    pub timestamps: bool,
+    pub container: ExportContainer,
+}
+
+/// The settable fields of an `ExportPreset`, for `add_export_preset` and `update_export_preset`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExportPresetChange {
+    pub name: String,
+    pub max_height: Option<i32>,
+    pub watermark: bool,
+    pub timestamps: bool,
+    pub container: ExportContainer,
+}
+
+impl ExportPresetChange {
+    fn check(&self) -> Result<(), Error> {
+        if self.name.is_empty() {
+            bail!("export preset name must be non-empty");
+        }
+        if let Some(h) = self.max_height {
+            if h <= 0 || h % 2 != 0 {
+                bail!("export preset {}: max_height {} must be a positive even number",
+                      self.name, h);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A litigation hold, preserving a camera's recordings within a time range. Recordings
+/// overlapping a hold are not deleted, either manually or by retention, until it's released or
+/// expires.
+#[derive(Clone, Debug)]
+pub struct Hold {
+    pub id: i32,
+    pub uuid: Uuid,
+    pub camera_id: i32,
+    pub time: Range<recording::Time>,
+    pub reason: String,
+    pub created_sec: i64,
+
+    /// When the hold expires, in seconds since epoch. The first flush after this releases it.
+    pub expires_sec: Option<i64>,
+}
+
+/// An incident: a named collection of related material gathered for an investigation.
+#[derive(Clone, Debug)]
+pub struct Incident {
+    pub id: i32,
+    pub uuid: Uuid,
+    pub title: String,
+    pub description: String,
+    pub created_sec: i64,
+}
+
+/// Something referenced by an `Incident`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IncidentItem {
+    /// An event, by id.
+    Event(i64),
+
+    /// A time range of a camera, across all its streams.
+    Range { camera_id: i32, time: Range<recording::Time> },
+
+    /// A note, by id.
+    Note(i64),
+
+    /// An export job, by uuid. This isn't checked against the job table; the export may be
+    /// deleted independently of the incident.
+    Export(Uuid),
+}
+
+/// Bytes served by the web server to a user, for a camera, within an hour.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BandwidthUsage {
+    /// The start of the hour, in seconds since epoch. Must be a multiple of 3600.
+    pub start_sec: i64,
+
+    /// The user's name, or empty if unknown.
+    pub user_name: String,
+
+    /// The camera whose data was served, or `None` for other requests.
+    pub camera_uuid: Option<Uuid>,
+
+    pub requests: i64,
+    pub bytes: i64,
+}
+
+/// A per-user web UI setting, such as a default camera layout.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserPreference {
+    pub key: String,
+
+    /// An arbitrary JSON value. The database doesn't interpret it.
+    pub value: String,
+
+    /// Starts at 1 and increments on each change.
+    pub version: i64,
+}
+
+/// A saved view: a named grid of cameras.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    pub id: i32,
+    pub uuid: Uuid,
+    pub name: String,
+    pub owner_user_id: i32,
+
+    /// If true, the layout is visible to all users, not just its owner.
+    pub shared: bool,
+
+    pub rows: u8,
+    pub columns: u8,
+    pub created_sec: i64,
+
+    /// The non-empty cells, ordered by position.
+    pub cells: Vec<LayoutCell>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayoutCell {
+    /// The index within the grid, in row-major order.
+    pub position: u16,
+
+    /// The camera to show. This may refer to a camera which has since been removed.
+    pub camera_uuid: Uuid,
+    pub stream_type: StreamType,
+}
+
+/// The maximum number of rows or columns of a `Layout`.
+pub const MAX_LAYOUT_DIMENSION: u8 = 16;
+
+/// A user-supplied note on a time range of a stream, such as "package stolen here".
+#[derive(Clone, Debug)]
+pub struct Note {
+    pub id: i64,
+    pub stream_id: i32,
+    pub time: Range<recording::Time>,
+    pub text: String,
+    pub created_sec: i64,
+}
+
+/// A stored thumbnail of a stream's key frame, as returned by `list_thumbnails`. Also used for
+/// scheduled snapshots, as returned by `list_scheduled_snapshots`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ListThumbnailsRow {
+    pub time: recording::Time,
+    pub bytes: i64,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum StreamType { MAIN, SUB }
 
 impl StreamType {
@@ -364,17 +1058,177 @@ impl ::std::fmt::Display for StreamType {
 
 pub const ALL_STREAM_TYPES: [StreamType; 2] = [StreamType::MAIN, StreamType::SUB];
 
-#[derive(Clone, Debug)]
-pub struct Stream {
-    pub id: i32,
-    pub camera_id: i32,
-    pub sample_file_dir_id: Option<i32>,
-    pub type_: StreamType,
-    pub rtsp_path: String,
-    pub retain_bytes: i64,
-    pub flush_if_sec: i64,
+/// A camera-side feed of analytics events (motion, line crossing, tamper, etc.).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EventSource {
+    /// ONVIF `PullPointSubscription`s, via the camera's event service.
+    Onvif,
 
-    /// The time range of recorded data associated with this stream (minimum start time and maximum
+    /// Hikvision's ISAPI `alertStream`.
+    Hikvision,
+
+    /// Dahua's `eventManager.cgi?action=attach`.
+    Dahua,
+}
+
+impl EventSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventSource::Onvif => "onvif",
+            EventSource::Hikvision => "hikvision",
+            EventSource::Dahua => "dahua",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "onvif" => Some(EventSource::Onvif),
+            "hikvision" => Some(EventSource::Hikvision),
+            "dahua" => Some(EventSource::Dahua),
+            _ => None,
+        }
+    }
+}
+
+/// Where a stream's video comes from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StreamSource {
+    /// RTSP, from the camera's `host` at the stream's `rtsp_path`.
+    Rtsp,
+
+    /// An HTTP Live Streaming playlist of H.264 video at the given `http://` or `https://` URL.
+    Hls(String),
+
+    /// Motion JPEG at the given `http://` or `https://` URL, which must be transcoded to H.264
+    /// before recording.
+    Mjpeg(String),
+
+    /// A local Video4Linux2 device at the given path, such as `/dev/video0`, which must be
+    /// encoded to H.264 before recording. The stream's `encoder` settings apply to this encoding
+    /// rather than being pushed via ONVIF.
+    V4l2(String),
+
+    /// Video which the device pushes to the given `rtmp://` or `srt://` listen URL, such as
+    /// `rtmp://0.0.0.0:1935/live/<key>`. The server listens for a single publisher while the
+    /// stream is recording.
+    Push(String),
+}
+
+impl Default for StreamSource {
+    fn default() -> Self { StreamSource::Rtsp }
+}
+
+impl StreamSource {
+    /// Returns the value of the `source` column.
+    pub fn type_str(&self) -> &'static str {
+        match *self {
+            StreamSource::Rtsp => "rtsp",
+            StreamSource::Hls(_) => "hls",
+            StreamSource::Mjpeg(_) => "mjpeg",
+            StreamSource::V4l2(_) => "v4l2",
+            StreamSource::Push(_) => "push",
+        }
+    }
+
+    /// Returns the value of the `source_url` column: an HTTP URL, a device path, or a listen URL.
+    pub fn url(&self) -> Option<&str> {
+        match *self {
+            StreamSource::Rtsp => None,
+            StreamSource::Hls(ref u) | StreamSource::Mjpeg(ref u) |
+            StreamSource::V4l2(ref u) | StreamSource::Push(ref u) => Some(u),
+        }
+    }
+
+    /// Checks that the URL or device path is plausible before saving.
+    fn check(&self) -> Result<(), Error> {
+        match *self {
+            StreamSource::Rtsp => Ok(()),
+            StreamSource::Hls(ref u) | StreamSource::Mjpeg(ref u) => {
+                check_http_url("source", Some(u))
+            },
+            StreamSource::V4l2(ref d) => {
+                if !d.starts_with("/dev/") || d.contains(char::is_whitespace) {
+                    bail!("V4L2 device {:?} must be a path within /dev", d);
+                }
+                Ok(())
+            },
+            StreamSource::Push(ref u) => {
+                if !(u.starts_with("rtmp://") || u.starts_with("srt://")) ||
+                   u.contains(char::is_whitespace) {
+                    bail!("push URL {:?} must be an rtmp:// or srt:// URL", u);
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Parses the `source` and `source_url` columns.
+    pub fn parse(type_: &str, url: Option<String>) -> Result<Self, Error> {
+        Ok(match (type_, url) {
+            ("rtsp", None) => StreamSource::Rtsp,
+            ("hls", Some(u)) => StreamSource::Hls(u),
+            ("mjpeg", Some(u)) => StreamSource::Mjpeg(u),
+            ("v4l2", Some(d)) => StreamSource::V4l2(d),
+            ("push", Some(u)) => StreamSource::Push(u),
+            (t, u) => bail!("invalid stream source {:?} with url {:?}", t, u),
+        })
+    }
+}
+
+pub const ALL_EVENT_SOURCES: [EventSource; 3] =
+    [EventSource::Onvif, EventSource::Hikvision, EventSource::Dahua];
+
+#[derive(Clone, Debug)]
+pub struct Stream {
+    pub id: i32,
+    pub camera_id: i32,
+    pub sample_file_dir_id: Option<i32>,
+
+    /// A second directory to which committed recordings are copied. See `mirror`.
+    pub mirror_sample_file_dir_id: Option<i32>,
+    pub type_: StreamType,
+    pub rtsp_path: String,
+
+    /// Where the stream's video comes from. `rtsp_path` is only used for `StreamSource::Rtsp`.
+    pub source: StreamSource,
+    pub retain_bytes: i64,
+
+    /// The relative priority of this stream's recordings when sharing space with other streams,
+    /// as in a tenant's quota or a directory's `reserved_bytes`. Eviction keeps the ages of the
+    /// streams' oldest recordings roughly proportional to their weights.
+    pub retain_weight: i32,
+    pub flush_if_sec: i64,
+
+    /// The desired duration of each recording, in seconds. Recordings are rotated at the first
+    /// key frame after each multiple of this.
+    pub recording_duration_sec: i64,
+
+    /// If set, the UUID of the `user_data_unregistered` SEI messages in which the camera reports
+    /// its own motion detection; see `h264::sei_messages`.
+    pub sei_motion_uuid: Option<Uuid>,
+
+    /// If set, an `http://` or `https://` URL from which the camera serves a JPEG of this stream's
+    /// current frame, used for snapshots instead of decoding the stream. The camera's credentials
+    /// are supplied by HTTP basic or digest authentication.
+    pub snapshot_url: Option<String>,
+
+    /// If ONVIF analytics in the stream's metadata track should be turned into events. (The
+    /// metadata track is recorded regardless.)
+    pub metadata_events: bool,
+
+    /// If nonzero, a small JPEG of a key frame is stored roughly this often while recording, for
+    /// fast thumbnails and scrubbing without reading sample files; see `list_thumbnails`.
+    pub thumbnail_interval_sec: i64,
+
+    /// Video encoder settings to push to the camera when the stream starts.
+    pub encoder: EncoderSettings,
+
+    /// If set, extra ffmpeg input options to apply when opening the source, as whitespace-
+    /// separated `key=value` pairs (such as `analyzeduration=10000000 stimeout=20000000`), for
+    /// cameras which need them. See `parse_input_options`.
+    pub input_options: Option<String>,
+
+    /// The time range of recorded data associated with this stream (minimum start time and maximum
     /// end time). `None` iff there are no recordings for this camera.
     pub range: Option<Range<recording::Time>>,
     pub sample_file_bytes: i64,
@@ -400,9 +1254,19 @@ pub struct Stream {
     pub days: BTreeMap<StreamDayKey, StreamDayValue>,
     pub record: bool,
 
+    /// If recording is temporarily suspended; see `LockedDatabase::set_stream_paused`.
+    pub paused: bool,
+
     /// The `next_recording_id` currently committed to the database.
     pub(crate) next_recording_id: i32,
 
+    /// The chain hash of the most recent committed recording (see `chain`), or
+    /// `chain::GENESIS` if there has been none since the chain was started.
+    pub chain_head: [u8; 20],
+
+    /// The most recent anchor of the stream's hash chain, if any.
+    pub last_anchor: Option<chain::Anchor>,
+
     /// The recordings which have been added via `LockedDatabase::add_recording` but have yet to
     /// committed to the database.
     ///
@@ -414,14 +1278,84 @@ pub struct Stream {
 
     /// The number of recordings in `uncommitted` which are synced and ready to commit.
     synced_recordings: usize,
+
+    /// The health of the stream as last reported by its streamer via `update_stream_health`.
+    /// This isn't persisted.
+    pub health: StreamHealth,
+
+    /// When the stream's usage last went over `retain_bytes`, if it's over now and there's a
+    /// retention grace period. See `LockedDatabase::set_retention_grace`. This isn't persisted;
+    /// the grace period starts again after a restart.
+    pub over_limit_since: Option<recording::Time>,
+}
+
+/// The health of a stream. See `Stream::health`.
+#[derive(Clone, Debug, Default)]
+pub struct StreamHealth {
+    /// The number of consecutive attempts to receive from the stream which have failed.
+    pub consecutive_failures: u32,
+
+    /// The error message of the most recent failure, if any.
+    pub last_error: Option<String>,
+
+    /// True iff the stream is currently being recorded from a fallback source, as described in
+    /// `RecordingFlags::Degraded`.
+    pub degraded: bool,
+
+    /// True iff frames are being held in memory because the stream's sample file directory is
+    /// unavailable. See `writer::Writer::set_max_spool_bytes`.
+    pub spooling: bool,
+}
+
+impl StreamHealth {
+    /// Returns a summary of the stream's health: `ok`, `spooling`, `degraded`, or `failing`.
+    pub fn state(&self) -> &'static str {
+        if self.spooling {
+            "spooling"
+        } else if self.degraded {
+            "degraded"
+        } else if self.consecutive_failures > 0 {
+            "failing"
+        } else {
+            "ok"
+        }
+    }
+
+    /// Returns why a `failing` stream is failing: `unreachable` if its camera doesn't answer on
+    /// the network, `authentication` if the camera rejected its credentials, or `stream`
+    /// otherwise. Returns `None` for other states.
+    pub fn cause(&self, reachability: &CameraReachability) -> Option<&'static str> {
+        if self.state() != "failing" {
+            return None;
+        }
+        if reachability.reachable == Some(false) {
+            return Some("unreachable");
+        }
+        match self.last_error {
+            // ffmpeg reports RTSP status 401 as "Server returned 401 Unauthorized".
+            Some(ref e) if e.contains("401") || e.contains("Unauthorized") => {
+                Some("authentication")
+            },
+            _ => Some("stream"),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct StreamChange {
     pub sample_file_dir_id: Option<i32>,
+    pub mirror_sample_file_dir_id: Option<i32>,
     pub rtsp_path: String,
+    pub source: StreamSource,
     pub record: bool,
     pub flush_if_sec: i64,
+    pub recording_duration_sec: i64,
+    pub sei_motion_uuid: Option<Uuid>,
+    pub snapshot_url: Option<String>,
+    pub metadata_events: bool,
+    pub thumbnail_interval_sec: i64,
+    pub encoder: EncoderSettings,
+    pub input_options: Option<String>,
 }
 
 /// Information about a camera, used by `add_camera` and `update_camera`.
@@ -437,6 +1371,13 @@ pub struct CameraChange {
     /// correspond to no stream in the database, provided there are no existing recordings for that
     /// stream.
     pub streams: [StreamChange; 2],
+
+    /// The complete set of labels; any existing labels not present here will be removed.
+    pub labels: BTreeMap<String, String>,
+
+    pub tenant_id: Option<i32>,
+    pub event_source: Option<EventSource>,
+    pub snapshot_schedule: Option<SnapshotSchedule>,
 }
 
 /// Adds non-zero `delta` to the day represented by `day` in the map `m`.
@@ -527,6 +1468,43 @@ impl Stream {
         self.sample_file_bytes += sample_file_bytes as i64;
         adjust_days(r, 1, &mut self.days);
     }
+
+    /// Returns the end time of the stream's newest recording, including one not yet committed
+    /// (such as the one currently being written).
+    pub fn newest_end(&self) -> Option<recording::Time> {
+        let committed = self.range.as_ref().map(|r| r.end);
+        let u = match self.uncommitted.back() {
+            None => return committed,
+            Some(u) => u.lock(),
+        };
+        let end = u.start + recording::Duration(u.duration_90k as i64);
+        Some(committed.map(|c| cmp::max(c, end)).unwrap_or(end))
+    }
+
+    /// Returns true if the stream has no uncommitted recordings and no recordings queued for
+    /// deletion, as `LockedDatabase::teardown_camera` requires.
+    pub fn is_settled(&self) -> bool { self.uncommitted.is_empty() && self.to_delete.is_empty() }
+
+    /// Returns true if all of the stream's uncommitted recordings are synced, so that the next
+    /// flush will commit them.
+    pub fn is_synced(&self) -> bool { self.synced_recordings == self.uncommitted.len() }
+
+    /// Returns the bytes counted against `retain_bytes` (and any tenant quota) when deciding what
+    /// to delete: committed recordings, plus synced ones awaiting the next flush, minus those
+    /// already queued for deletion.
+    pub fn retention_usage(&self) -> i64 {
+        self.sample_file_bytes + self.bytes_to_add - self.bytes_to_delete
+    }
+
+    /// Returns the bytes of recordings not yet committed to the database, including the one
+    /// currently being written.
+    pub fn uncommitted_bytes(&self) -> i64 {
+        self.uncommitted.iter().map(|u| u.lock().sample_file_bytes as i64).sum()
+    }
+
+    /// Returns the id of the next recording to be committed. Uncommitted recordings have this id
+    /// and above; committed ones are below it.
+    pub fn next_recording_id(&self) -> i32 { self.next_recording_id }
 }
 
 /// Initializes the recordings associated with the given camera.
@@ -538,7 +1516,8 @@ fn init_recordings(conn: &mut rusqlite::Connection, stream_id: i32, camera: &Cam
         select
           recording.start_time_90k,
           recording.duration_90k,
-          recording.sample_file_bytes
+          recording.sample_file_bytes,
+          recording.flags
         from
           recording
         where
@@ -550,7 +1529,11 @@ fn init_recordings(conn: &mut rusqlite::Connection, stream_id: i32, camera: &Cam
         let row = row?;
         let start = recording::Time(row.get_checked(0)?);
         let duration = recording::Duration(row.get_checked(1)?);
-        let bytes = row.get_checked(2)?;
+        let mut bytes = row.get_checked(2)?;
+        let flags: i32 = row.get_checked(3)?;
+        if (flags & RecordingFlags::Archived as i32) != 0 {
+            bytes = 0;  // archived recordings take no space in the sample file directory.
+        }
         stream.add_recording(start .. start + duration, bytes);
         i += 1;
     }
@@ -570,12 +1553,39 @@ pub struct LockedDatabase {
     open_monotonic: recording::Time,
 
     sample_file_dirs_by_id: BTreeMap<i32, SampleFileDir>,
+    tenants_by_id: BTreeMap<i32, Tenant>,
+    holds_by_id: BTreeMap<i32, Hold>,
+
+    /// Holds which have kept retention from deleting a recording, so that each is logged once.
+    holds_blocking_retention: FnvHashSet<i32>,
+
+    export_presets_by_id: BTreeMap<i32, ExportPreset>,
     cameras_by_id: BTreeMap<i32, Camera>,
     streams_by_id: BTreeMap<i32, Stream>,
     cameras_by_uuid: BTreeMap<Uuid, i32>,  // values are ids.
     video_sample_entries_by_id: BTreeMap<i32, Arc<VideoSampleEntry>>,
     video_index_cache: RefCell<LruCache<i64, Box<[u8]>, fnv::FnvBuildHasher>>,
+
+    /// Incremented whenever streams are added, removed, or reassigned; see `streams_generation`.
+    streams_generation: u64,
+
+    /// The key with which encrypted directories' keys are wrapped; see `set_master_key`.
+    master_key: Option<dir::MasterKey>,
+
     on_flush: Vec<Box<Fn() + Send>>,
+    watchers: Vec<Box<Fn(&LockedDatabase, &Change) + Send>>,
+
+    event_merge: EventMerge,
+
+    /// See `set_retention_grace`.
+    retention_grace: recording::Duration,
+
+    /// See `startup_report`.
+    startup_report: StartupReport,
+
+    /// The id and contents of the most recent event for each camera, type, and description, for
+    /// `event_merge`.
+    last_events: FnvHashMap<(i32, String, Option<String>), (i64, EventToInsert)>,
 }
 
 /// Represents a row of the `open` database table.
@@ -610,6 +1620,50 @@ struct StreamStateChanger {
     streams: Vec<(i32, Option<Stream>)>,
 }
 
+fn check_recording_duration(sec: i64) -> Result<(), Error> {
+    if sec <= 0 || sec > recording::MAX_DESIRED_RECORDING_DURATION_SEC {
+        bail!("recording duration {} sec must be in (0, {}]",
+              sec, recording::MAX_DESIRED_RECORDING_DURATION_SEC);
+    }
+    Ok(())
+}
+
+fn check_thumbnail_interval(sec: i64) -> Result<(), Error> {
+    if sec < 0 {
+        bail!("thumbnail interval {} sec must be non-negative", sec);
+    }
+    Ok(())
+}
+
+/// Parses a stream's `input_options` into `(key, value)` pairs. Keys are limited to the
+/// characters of ffmpeg option names, starting with a letter; values may contain anything but
+/// whitespace.
+pub fn parse_input_options(options: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut out = Vec::new();
+    for o in options.split_whitespace() {
+        let eq = o.find('=').ok_or_else(|| format_err!("input option {:?} lacks =value", o))?;
+        let (k, v) = (&o[..eq], &o[eq+1..]);
+        let valid = k.bytes().next().map(|b| b.is_ascii_alphabetic()).unwrap_or(false) &&
+                    k.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+        if !valid {
+            bail!("invalid input option name {:?}", k);
+        }
+        out.push((k.to_owned(), v.to_owned()));
+    }
+    Ok(out)
+}
+
+/// Checks a URL to be fetched with the camera's credentials, described by `what` in errors.
+fn check_http_url(what: &str, url: Option<&str>) -> Result<(), Error> {
+    if let Some(u) = url {
+        if !(u.starts_with("http://") || u.starts_with("https://")) ||
+           u.contains(char::is_whitespace) {
+            bail!("{} URL {:?} must be an http:// or https:// URL", what, u);
+        }
+    }
+    Ok(())
+}
+
 impl StreamStateChanger {
     /// Performs the database updates (guarded by the given transaction) and returns the state
     /// change to be applied on successful commit.
@@ -620,6 +1674,11 @@ impl StreamStateChanger {
         let mut streams = Vec::with_capacity(2);
         let existing_streams = existing.map(|e| e.streams).unwrap_or_default();
         for (i, ref mut sc) in change.streams.iter_mut().enumerate() {
+            if let Some(m) = sc.mirror_sample_file_dir_id {
+                if sc.sample_file_dir_id.is_none() || sc.sample_file_dir_id == Some(m) {
+                    bail!("mirror sample file dir {} must differ from the stream's own dir", m);
+                }
+            }
             let mut have_data = false;
             if let Some(sid) = existing_streams[i] {
                 let s = streams_by_id.get(&sid).unwrap();
@@ -631,9 +1690,10 @@ impl StreamStateChanger {
                               d, sc.sample_file_dir_id, sid);
                     }
                 }
-                if !have_data && sc.rtsp_path.is_empty() && sc.sample_file_dir_id.is_none() &&
-                   !sc.record {
+                if !have_data && sc.rtsp_path.is_empty() && sc.source == StreamSource::Rtsp &&
+                   sc.sample_file_dir_id.is_none() && !sc.record {
                     // Delete stream.
+                    raw::delete_thumbnails(tx, sid, None)?;
                     let mut stmt = tx.prepare_cached(r#"
                         delete from stream where id = ?
                     "#)?;
@@ -643,20 +1703,57 @@ impl StreamStateChanger {
                     streams.push((sid, None));
                 } else {
                     // Update stream.
+                    check_recording_duration(sc.recording_duration_sec)?;
+                    check_http_url("snapshot", sc.snapshot_url.as_ref().map(String::as_str))?;
+                    sc.source.check()?;
+                    if let Some(ref o) = sc.input_options {
+                        parse_input_options(o)?;
+                    }
+                    check_thumbnail_interval(sc.thumbnail_interval_sec)?;
+                    sc.encoder.check()?;
+                    let sei_motion_uuid = sc.sei_motion_uuid.as_ref().map(|u| &u.as_bytes()[..]);
                     let mut stmt = tx.prepare_cached(r#"
                         update stream set
                             rtsp_path = :rtsp_path,
+                            source = :source,
+                            source_url = :source_url,
                             record = :record,
                             flush_if_sec = :flush_if_sec,
-                            sample_file_dir_id = :sample_file_dir_id
+                            recording_duration_sec = :recording_duration_sec,
+                            sei_motion_uuid = :sei_motion_uuid,
+                            snapshot_url = :snapshot_url,
+                            metadata_events = :metadata_events,
+                            thumbnail_interval_sec = :thumbnail_interval_sec,
+                            encoder_gov_length = :encoder_gov_length,
+                            encoder_bitrate_kbps = :encoder_bitrate_kbps,
+                            encoder_frame_rate = :encoder_frame_rate,
+                            encoder_width = :encoder_width,
+                            encoder_height = :encoder_height,
+                            input_options = :input_options,
+                            sample_file_dir_id = :sample_file_dir_id,
+                            mirror_sample_file_dir_id = :mirror_sample_file_dir_id
                         where
                             id = :id
                     "#)?;
                     let rows = stmt.execute_named(&[
                         (":rtsp_path", &sc.rtsp_path),
+                        (":source", &sc.source.type_str()),
+                        (":source_url", &sc.source.url()),
                         (":record", &sc.record),
                         (":flush_if_sec", &sc.flush_if_sec),
+                        (":recording_duration_sec", &sc.recording_duration_sec),
+                        (":sei_motion_uuid", &sei_motion_uuid),
+                        (":snapshot_url", &sc.snapshot_url),
+                        (":metadata_events", &sc.metadata_events),
+                        (":thumbnail_interval_sec", &sc.thumbnail_interval_sec),
+                        (":encoder_gov_length", &sc.encoder.gov_length),
+                        (":encoder_bitrate_kbps", &sc.encoder.bitrate_kbps),
+                        (":encoder_frame_rate", &sc.encoder.frame_rate),
+                        (":encoder_width", &sc.encoder.resolution.map(|r| r.0)),
+                        (":encoder_height", &sc.encoder.resolution.map(|r| r.1)),
+                        (":input_options", &sc.input_options),
                         (":sample_file_dir_id", &sc.sample_file_dir_id),
+                        (":mirror_sample_file_dir_id", &sc.mirror_sample_file_dir_id),
                         (":id", &sid),
                     ])?;
                     if rows != 1 {
@@ -666,23 +1763,54 @@ impl StreamStateChanger {
                     let s = (*s).clone();
                     streams.push((sid, Some(Stream {
                         sample_file_dir_id: sc.sample_file_dir_id,
+                        mirror_sample_file_dir_id: sc.mirror_sample_file_dir_id,
                         rtsp_path: mem::replace(&mut sc.rtsp_path, String::new()),
+                        source: mem::replace(&mut sc.source, StreamSource::Rtsp),
                         record: sc.record,
                         flush_if_sec: sc.flush_if_sec,
+                        recording_duration_sec: sc.recording_duration_sec,
+                        sei_motion_uuid: sc.sei_motion_uuid,
+                        snapshot_url: sc.snapshot_url.take(),
+                        metadata_events: sc.metadata_events,
+                        thumbnail_interval_sec: sc.thumbnail_interval_sec,
+                        encoder: sc.encoder,
+                        input_options: sc.input_options.take(),
                         ..s
                     })));
                 }
             } else {
-                if sc.rtsp_path.is_empty() && sc.sample_file_dir_id.is_none() && !sc.record {
+                if sc.rtsp_path.is_empty() && sc.source == StreamSource::Rtsp &&
+                   sc.sample_file_dir_id.is_none() && !sc.record {
                     // Do nothing; there is no record and we want to keep it that way.
                     continue;
                 }
                 // Insert stream.
+                check_recording_duration(sc.recording_duration_sec)?;
+                check_http_url("snapshot", sc.snapshot_url.as_ref().map(String::as_str))?;
+                sc.source.check()?;
+                if let Some(ref o) = sc.input_options {
+                    parse_input_options(o)?;
+                }
+                check_thumbnail_interval(sc.thumbnail_interval_sec)?;
+                sc.encoder.check()?;
+                let sei_motion_uuid = sc.sei_motion_uuid.as_ref().map(|u| &u.as_bytes()[..]);
                 let mut stmt = tx.prepare_cached(r#"
                     insert into stream (camera_id,  sample_file_dir_id,  type,  rtsp_path,  record,
-                                        retain_bytes, flush_if_sec,  next_recording_id)
+                                        retain_bytes, flush_if_sec,  next_recording_id,
+                                        mirror_sample_file_dir_id,  recording_duration_sec,
+                                        sei_motion_uuid,  snapshot_url,  metadata_events,
+                                        thumbnail_interval_sec,  encoder_gov_length,
+                                        encoder_bitrate_kbps,  encoder_frame_rate,
+                                        encoder_width,  encoder_height,  source,  source_url,
+                                        input_options)
                                 values (:camera_id, :sample_file_dir_id, :type, :rtsp_path, :record,
-                                        0,            :flush_if_sec, 1)
+                                        0,            :flush_if_sec, 1,
+                                        :mirror_sample_file_dir_id, :recording_duration_sec,
+                                        :sei_motion_uuid, :snapshot_url, :metadata_events,
+                                        :thumbnail_interval_sec, :encoder_gov_length,
+                                        :encoder_bitrate_kbps, :encoder_frame_rate,
+                                        :encoder_width, :encoder_height, :source, :source_url,
+                                        :input_options)
                 "#)?;
                 let type_ = StreamType::from_index(i).unwrap();
                 stmt.execute_named(&[
@@ -692,6 +1820,20 @@ impl StreamStateChanger {
                     (":rtsp_path", &sc.rtsp_path),
                     (":record", &sc.record),
                     (":flush_if_sec", &sc.flush_if_sec),
+                    (":mirror_sample_file_dir_id", &sc.mirror_sample_file_dir_id),
+                    (":recording_duration_sec", &sc.recording_duration_sec),
+                    (":sei_motion_uuid", &sei_motion_uuid),
+                    (":snapshot_url", &sc.snapshot_url),
+                    (":metadata_events", &sc.metadata_events),
+                    (":thumbnail_interval_sec", &sc.thumbnail_interval_sec),
+                    (":encoder_gov_length", &sc.encoder.gov_length),
+                    (":encoder_bitrate_kbps", &sc.encoder.bitrate_kbps),
+                    (":encoder_frame_rate", &sc.encoder.frame_rate),
+                    (":encoder_width", &sc.encoder.resolution.map(|r| r.0)),
+                    (":encoder_height", &sc.encoder.resolution.map(|r| r.1)),
+                    (":source", &sc.source.type_str()),
+                    (":source_url", &sc.source.url()),
+                    (":input_options", &sc.input_options),
                 ])?;
                 let id = tx.last_insert_rowid() as i32;
                 sids[i] = Some(id);
@@ -700,9 +1842,19 @@ impl StreamStateChanger {
                     type_,
                     camera_id,
                     sample_file_dir_id: sc.sample_file_dir_id,
+                    mirror_sample_file_dir_id: sc.mirror_sample_file_dir_id,
                     rtsp_path: mem::replace(&mut sc.rtsp_path, String::new()),
+                    source: mem::replace(&mut sc.source, StreamSource::Rtsp),
                     retain_bytes: 0,
+                    retain_weight: 1,
                     flush_if_sec: sc.flush_if_sec,
+                    recording_duration_sec: sc.recording_duration_sec,
+                    sei_motion_uuid: sc.sei_motion_uuid,
+                    snapshot_url: sc.snapshot_url.take(),
+                    metadata_events: sc.metadata_events,
+                    thumbnail_interval_sec: sc.thumbnail_interval_sec,
+                    encoder: sc.encoder,
+                    input_options: sc.input_options.take(),
                     range: None,
                     sample_file_bytes: 0,
                     to_delete: Vec::new(),
@@ -711,9 +1863,14 @@ impl StreamStateChanger {
                     duration: recording::Duration(0),
                     days: BTreeMap::new(),
                     record: sc.record,
+                    paused: false,
                     next_recording_id: 1,
+                    chain_head: chain::GENESIS,
+                    last_anchor: None,
                     uncommitted: VecDeque::new(),
                     synced_recordings: 0,
+                    health: StreamHealth::default(),
+                    over_limit_since: None,
                 })));
             }
         }
@@ -744,11 +1901,25 @@ pub struct RetentionChange {
     pub stream_id: i32,
     pub new_record: bool,
     pub new_limit: i64,
+    pub new_weight: i32,
 }
 
 impl LockedDatabase {
+    /// Returns the database's UUID (from the `meta` table), which identifies this NVR.
+    pub fn uuid(&self) -> Uuid { self.uuid }
+
     /// Returns an immutable view of the cameras by id.
     pub fn cameras_by_id(&self) -> &BTreeMap<i32, Camera> { &self.cameras_by_id }
+    pub fn tenants_by_id(&self) -> &BTreeMap<i32, Tenant> { &self.tenants_by_id }
+    pub fn holds_by_id(&self) -> &BTreeMap<i32, Hold> { &self.holds_by_id }
+    pub fn export_presets_by_id(&self) -> &BTreeMap<i32, ExportPreset> {
+        &self.export_presets_by_id
+    }
+
+    /// Returns the export preset with the given name, if any.
+    pub fn get_export_preset(&self, name: &str) -> Option<&ExportPreset> {
+        self.export_presets_by_id.values().find(|p| p.name == name)
+    }
     pub fn sample_file_dirs_by_id(&self) -> &BTreeMap<i32, SampleFileDir> {
         &self.sample_file_dirs_by_id
     }
@@ -792,6 +1963,16 @@ impl LockedDatabase {
         Ok(())
     }
 
+    /// Adds a recording which is already synced, to be written on the next `flush`. This is
+    /// `add_recording` followed by `mark_synced`, for callers (such as `moonfire-nvr bench`)
+    /// which have no sample file to write.
+    pub fn add_synced_recording(&mut self, stream_id: i32, r: RecordingToInsert)
+                                -> Result<CompositeId, Error> {
+        let (id, _) = self.add_recording(stream_id, r)?;
+        self.mark_synced(id)?;
+        Ok(id)
+    }
+
     pub(crate) fn delete_garbage(&mut self, dir_id: i32, ids: &mut Vec<CompositeId>)
                                  -> Result<(), Error> {
         let dir = match self.sample_file_dirs_by_id.get_mut(&dir_id) {
@@ -816,6 +1997,7 @@ impl LockedDatabase {
     ///
     /// The public API is in `DatabaseGuard::flush()`; it supplies the `Clocks` to this function.
     fn flush<C: Clocks>(&mut self, clocks: &C, reason: &str) -> Result<(), Error> {
+        let _span = trace::span_with("db flush", || Some(reason.to_owned()));
         let o = match self.open.as_ref() {
             None => bail!("database is read-only"),
             Some(o) => o,
@@ -823,21 +2005,39 @@ impl LockedDatabase {
         let tx = self.conn.transaction()?;
         let mut new_ranges = FnvHashMap::with_capacity_and_hasher(self.streams_by_id.len(),
                                                                   Default::default());
+        let mut new_heads = FnvHashMap::default();
+        let mut new_anchors = Vec::new();
+        let now_sec = clocks.realtime().sec;
         {
             let mut stmt = tx.prepare_cached(UPDATE_NEXT_RECORDING_ID_SQL)?;
             for (&stream_id, s) in &self.streams_by_id {
                 // Process additions.
+                let mut head = s.chain_head;
+                let mut last_id = None;
                 for i in 0..s.synced_recordings {
                     let l = s.uncommitted[i].lock();
-                    raw::insert_recording(
-                        &tx, o, CompositeId::new(stream_id, s.next_recording_id + i as i32), &l)?;
+                    let id = CompositeId::new(stream_id, s.next_recording_id + i as i32);
+                    head = chain::link(&head, id, &l)?;
+                    raw::insert_recording(&tx, o, id, &l, &head)?;
+                    last_id = Some(id);
                 }
-                if s.synced_recordings > 0 {
+                if let Some(id) = last_id {
                     new_ranges.entry(stream_id).or_insert(None);
                     stmt.execute_named(&[
                         (":stream_id", &stream_id),
                         (":next_recording_id", &(s.next_recording_id + s.synced_recordings as i32)),
+                        (":chain_sha1", &&head[..]),
                     ])?;
+                    new_heads.insert(stream_id, head);
+                    let due = match s.last_anchor {
+                        None => true,
+                        Some(ref a) => now_sec - a.time_sec >= chain::ANCHOR_INTERVAL_SEC,
+                    };
+                    if due {
+                        let a = chain::Anchor { id, chain_sha1: head, time_sec: now_sec };
+                        raw::insert_chain_anchor(&tx, &a)?;
+                        new_anchors.push((stream_id, a));
+                    }
                 }
 
                 // Process deletions.
@@ -849,15 +2049,44 @@ impl LockedDatabase {
                     };
 
                     // raw::delete_recordings does a bulk transfer of a range from recording to
-                    // garbage, rather than operating on each element of to_delete. This is
-                    // guaranteed to give the same result because to_delete is guaranteed to be the
-                    // oldest recordings for the stream.
-                    let start = CompositeId::new(stream_id, 0);
-                    let end = CompositeId(l.id.0 + 1);
-                    let n = raw::delete_recordings(&tx, dir, start .. end)? as usize;
+                    // garbage, rather than operating on each element of to_delete. to_delete is
+                    // in id order, but held recordings are skipped, so transfer each run of
+                    // consecutive ids separately. raw::delete_recordings skips archived ones.
+                    let mut n = 0;
+                    let mut i = 0;
+                    while i < s.to_delete.len() {
+                        let mut j = i + 1;
+                        while j < s.to_delete.len() &&
+                              s.to_delete[j].id.0 == s.to_delete[j - 1].id.0 + 1 {
+                            j += 1;
+                        }
+                        let ids = s.to_delete[i].id .. CompositeId(s.to_delete[j - 1].id.0 + 1);
+                        n += raw::delete_recordings(&tx, dir, ids)?;
+                        i = j;
+                    }
                     if n != s.to_delete.len() {
-                        bail!("Found {} rows in {} .. {}, expected {}: {:?}",
-                              n, start, end, s.to_delete.len(), &s.to_delete);
+                        bail!("Found {} rows, expected {}: {:?}", n, s.to_delete.len(),
+                              &s.to_delete);
+                    }
+
+                    // Delete thumbnails through the end of the last deleted recording, except
+                    // those of held recordings.
+                    let l_end = l.start + recording::Duration(l.duration as i64);
+                    let mut held: Vec<Range<recording::Time>> =
+                        self.holds_by_id.values()
+                            .filter(|h| h.camera_id == s.camera_id && h.time.start < l_end)
+                            .map(|h| h.time.clone())
+                            .collect();
+                    held.sort_by_key(|t| t.start);
+                    let mut start = recording::Time(i64::min_value());
+                    for h in held {
+                        if start < h.start {
+                            raw::delete_thumbnails_in(&tx, stream_id, start .. h.start)?;
+                        }
+                        start = cmp::max(start, h.end);
+                    }
+                    if start < l_end {
+                        raw::delete_thumbnails_in(&tx, stream_id, start .. l_end)?;
                     }
                 }
             }
@@ -865,6 +2094,14 @@ impl LockedDatabase {
         for dir in self.sample_file_dirs_by_id.values() {
             raw::mark_sample_files_deleted(&tx, &dir.garbage_unlinked)?;
         }
+        let expired_holds: Vec<i32> =
+            self.holds_by_id.values()
+                .filter(|h| h.expires_sec.map(|e| e <= now_sec).unwrap_or(false))
+                .map(|h| h.id)
+                .collect();
+        for &id in &expired_holds {
+            tx.execute("delete from hold where id = ?", &[&id])?;
+        }
         for (&stream_id, mut r) in &mut new_ranges {
             *r = raw::get_range(&tx, stream_id)?;
         }
@@ -882,6 +2119,12 @@ impl LockedDatabase {
         }
         tx.commit()?;
 
+        for id in expired_holds {
+            let h = self.holds_by_id.remove(&id).unwrap();
+            self.holds_blocking_retention.remove(&id);
+            info!(target: "audit", "hold {} ({}) expired", h.uuid, h.reason);
+        }
+
         // Process delete_garbage.
         let mut gced = 0;
         for dir in self.sample_file_dirs_by_id.values_mut() {
@@ -891,6 +2134,7 @@ impl LockedDatabase {
 
         let mut added = 0;
         let mut deleted = 0;
+        let mut changes = Vec::new();
         for (stream_id, new_range) in new_ranges.drain() {
             let s = self.streams_by_id.get_mut(&stream_id).unwrap();
             let d = self.sample_file_dirs_by_id.get_mut(&s.sample_file_dir_id.unwrap()).unwrap();
@@ -907,6 +2151,9 @@ impl LockedDatabase {
             }
 
             // Process add_recordings.
+            if let Some(h) = new_heads.get(&stream_id) {
+                s.chain_head = *h;
+            }
             s.next_recording_id += s.synced_recordings as i32;
             added += s.synced_recordings;
             s.bytes_to_add = 0;
@@ -916,19 +2163,123 @@ impl LockedDatabase {
                 let end = l.start + recording::Duration(l.duration_90k as i64);
                 s.add_recording(l.start .. end, l.sample_file_bytes);
             }
+            if s.synced_recordings > 0 {
+                changes.push(Change::RecordingsAdded { stream_id, count: s.synced_recordings });
+            }
             s.synced_recordings = 0;
 
             // Fix the range.
             s.range = new_range;
         }
+        for (stream_id, anchor) in new_anchors.drain(..) {
+            self.streams_by_id.get_mut(&stream_id).unwrap().last_anchor = Some(anchor.clone());
+            changes.push(Change::ChainAnchored { stream_id, anchor });
+        }
         info!("Flush (why: {}): added {} recordings, deleted {}, marked {} files GCed.",
               reason, added, deleted, gced);
         for cb in &self.on_flush {
             cb();
         }
+        for c in &changes {
+            self.notify(c);
+        }
+        Ok(())
+    }
+
+    /// Sets the master key. Directories created afterward are encrypted, and existing encrypted
+    /// directories can be opened. Must be called before `open_sample_file_dirs`.
+    pub fn set_master_key(&mut self, key: dir::MasterKey) {
+        self.master_key = Some(key);
+    }
+
+    /// Sets how `add_event` merges bursts of events.
+    pub fn set_event_merge(&mut self, m: EventMerge) {
+        self.event_merge = m;
+    }
+
+    /// Sets how long a stream may exceed its `retain_bytes` before its old recordings are
+    /// deleted, so that a temporary bitrate spike doesn't immediately eat into history. Zero (the
+    /// default) deletes immediately. This doesn't apply to tenant quotas or directories'
+    /// `reserved_bytes`, which protect against filling the disk.
+    pub fn set_retention_grace(&mut self, grace: recording::Duration) {
+        self.retention_grace = grace;
+    }
+
+    pub fn retention_grace(&self) -> recording::Duration { self.retention_grace }
+
+    /// Notes whether the given stream is over its `retain_bytes` as of `now`, returning when it
+    /// went over if so. Watchers are notified with `Change::RetentionExceeded` when it first
+    /// goes over.
+    pub(crate) fn note_over_limit(&mut self, stream_id: i32, over: bool, now: recording::Time)
+                                  -> Result<Option<recording::Time>, Error> {
+        let (since, started) = {
+            let s = self.streams_by_id.get_mut(&stream_id)
+                        .ok_or_else(|| format_err!("no such stream {}", stream_id))?;
+            let started = over && s.over_limit_since.is_none();
+            s.over_limit_since = if over { Some(s.over_limit_since.unwrap_or(now)) } else { None };
+            (s.over_limit_since, started)
+        };
+        if started {
+            self.notify(&Change::RetentionExceeded { stream_id });
+        }
+        Ok(since)
+    }
+
+    /// Adds a watcher which will receive each subsequent `Change`.
+    /// The lock will be held while this is run, so it should not do any I/O.
+    pub fn watch(&mut self, w: Box<Fn(&LockedDatabase, &Change) + Send>) {
+        self.watchers.push(w);
+    }
+
+    /// Returns a counter which changes whenever streams are added, removed, or changed by
+    /// `add_camera`, `update_camera`, or `delete_camera`. Callers which cache information derived
+    /// from `streams_by_id` (such as the stream-to-sample-file-directory mapping) can compare it
+    /// against the value they last saw to decide when to recompute.
+    pub fn streams_generation(&self) -> u64 { self.streams_generation }
+
+    /// Returns what was cleaned up from the previous run at startup. Abandoned recordings are
+    /// filled in as each sample file directory's syncer starts.
+    pub fn startup_report(&self) -> &StartupReport { &self.startup_report }
+
+    /// Fills in the parts of `startup_report` known from the database contents alone.
+    fn init_startup_report(&mut self, now: recording::Time) -> Result<(), Error> {
+        let current_open = self.open.map(|o| o.id as i64).unwrap_or(-1);
+        let end: Option<i64> = self.conn.query_row(
+            "select max(end_time_90k) from open where id != ?", &[&current_open as &ToSql],
+            |r| r.get_checked(0))??;
+        let r = &mut self.startup_report;
+        r.previous_run_end = end.map(recording::Time);
+        for d in self.sample_file_dirs_by_id.values() {
+            for id in &d.garbage_needs_unlink {
+                r.streams.entry(id.stream()).or_insert_with(Default::default)
+                 .garbage_collected += 1;
+            }
+        }
+        for s in self.streams_by_id.values() {
+            if let (true, Some(range)) = (s.record, s.range.as_ref()) {
+                if now > range.end {
+                    r.streams.entry(s.id).or_insert_with(Default::default).gap =
+                        Some(now - range.end);
+                }
+            }
+        }
         Ok(())
     }
 
+    /// Adds recordings rolled back by a syncer's startup to `startup_report`.
+    pub(crate) fn note_abandoned_recordings(&mut self, ids: &[CompositeId]) {
+        for id in ids {
+            self.startup_report.streams.entry(id.stream()).or_insert_with(Default::default)
+                .abandoned_recordings += 1;
+        }
+    }
+
+    fn notify(&self, c: &Change) {
+        for w in &self.watchers {
+            w(self, c);
+        }
+    }
+
     /// Sets a watcher which will receive an (empty) event on successful flush.
     /// The lock will be held while this is run, so it should not do any I/O.
     pub(crate) fn on_flush(&mut self, run: Box<Fn() + Send>) {
@@ -972,7 +2323,15 @@ impl LockedDatabase {
                 open.id = o.id;
                 open.uuid.extend_from_slice(&o.uuid.as_bytes()[..]);
             }
-            let d = dir::SampleFileDir::open(&dir.path, &meta)?;
+            let cipher = match dir.wrapped_key {
+                None => None,
+                Some(ref w) => {
+                    let m = self.master_key.as_ref().ok_or_else(|| format_err!(
+                        "dir {} is encrypted; a master key is required", dir.path))?;
+                    Some(dir::Cipher::unwrap(w, m)?)
+                },
+            };
+            let d = dir::SampleFileDir::open(&dir.path, &meta, dir.network_fs, cipher)?;
             if self.open.is_none() {  // read-only mode; it's already fully opened.
                 dir.dir = Some(d);
             } else {  // read-write mode; there are more steps to do.
@@ -1016,6 +2375,11 @@ impl LockedDatabase {
         &self.video_sample_entries_by_id
     }
 
+    /// Gets a given tenant by uuid.
+    pub fn get_tenant(&self, uuid: Uuid) -> Option<&Tenant> {
+        self.tenants_by_id.values().find(|t| t.uuid == uuid)
+    }
+
     /// Gets a given camera by uuid.
     pub fn get_camera(&self, uuid: Uuid) -> Option<&Camera> {
         match self.cameras_by_uuid.get(&uuid) {
@@ -1024,6 +2388,11 @@ impl LockedDatabase {
         }
     }
 
+    /// Gets a given camera by its (unique) short name.
+    pub fn get_camera_by_short_name(&self, short_name: &str) -> Option<&Camera> {
+        self.cameras_by_id.values().find(|c| c.short_name == short_name)
+    }
+
     /// Lists the specified recordings, passing them to a supplied function. Given that the
     /// function is called with the database lock held, it should be quick.
     ///
@@ -1056,52 +2425,625 @@ impl LockedDatabase {
         Ok(())
     }
 
-    /// Lists the specified recordings in ascending order by id.
-    pub fn list_recordings_by_id(
-        &self, stream_id: i32, desired_ids: Range<i32>,
-        f: &mut FnMut(ListRecordingsRow) -> Result<(), Error>) -> Result<(), Error> {
-        let s = match self.streams_by_id.get(&stream_id) {
-            None => bail!("no such stream {}", stream_id),
-            Some(s) => s,
+    /// Lists events for the given camera which overlap the given time range, in ascending order
+    /// by start time.
+    pub fn list_events(&self, camera_id: i32, desired_time: Range<recording::Time>,
+                       f: &mut FnMut(ListEventsRow) -> Result<(), Error>) -> Result<(), Error> {
+        if !self.cameras_by_id.contains_key(&camera_id) {
+            bail!("no such camera {}", camera_id);
+        }
+        raw::list_events(&self.conn, camera_id, desired_time, f)
+    }
+
+    /// Lists notes on the given stream which overlap the given time range and (if `query` is
+    /// supplied) contain the given text.
+    pub fn list_notes(&self, stream_id: i32, desired_time: Range<recording::Time>,
+                      query: Option<&str>, f: &mut FnMut(Note) -> Result<(), Error>)
+                      -> Result<(), Error> {
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!("no such stream {}", stream_id);
+        }
+        raw::list_notes(&self.conn, stream_id, desired_time, query, f)
+    }
+
+    /// Adds a note on the given stream's time range, returning its id. Like events, notes are
+    /// written immediately.
+    pub fn add_note(&mut self, stream_id: i32, time: Range<recording::Time>, text: &str,
+                    now_sec: i64) -> Result<i64, Error> {
+        if self.open.is_none() {
+            bail!("database is read-only");
+        }
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!("no such stream {}", stream_id);
+        }
+        if time.end < time.start {
+            bail!("note has negative duration: {:?}", time);
+        }
+        if text.is_empty() {
+            bail!("note must have text");
+        }
+        raw::insert_note(&self.conn, stream_id, &time, text, now_sec)
+    }
+
+    /// Stores a thumbnail of the given stream's key frame at the given time. Like events,
+    /// thumbnails are written immediately. They're deleted along with the recordings they cover.
+    pub fn add_thumbnail(&mut self, stream_id: i32, time: recording::Time, jpeg: &[u8])
+                         -> Result<(), Error> {
+        if self.open.is_none() {
+            bail!("database is read-only");
+        }
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!("no such stream {}", stream_id);
+        }
+        if jpeg.is_empty() {
+            bail!("empty thumbnail for stream {}", stream_id);
+        }
+        raw::insert_thumbnail(&self.conn, stream_id, time, jpeg)
+    }
+
+    /// Lists thumbnails of the given stream within the given time range, in ascending order.
+    pub fn list_thumbnails(&self, stream_id: i32, desired_time: Range<recording::Time>)
+                           -> Result<Vec<ListThumbnailsRow>, Error> {
+        if !self.streams_by_id.contains_key(&stream_id) {
+            bail!("no such stream {}", stream_id);
+        }
+        raw::list_thumbnails(&self.conn, stream_id, desired_time)
+    }
+
+    /// Gets the latest thumbnail of the given stream at or before the given time, returning its
+    /// time and JPEG.
+    pub fn get_thumbnail(&self, stream_id: i32, time: recording::Time)
+                         -> Result<Option<(recording::Time, Vec<u8>)>, Error> {
+        raw::get_thumbnail(&self.conn, stream_id, time)
+    }
+
+    /// Stores a scheduled snapshot of the given camera, taken at the given time. See
+    /// `SnapshotSchedule`.
+    pub fn add_scheduled_snapshot(&mut self, camera_id: i32, time: recording::Time, jpeg: &[u8])
+                                  -> Result<(), Error> {
+        if self.open.is_none() {
+            bail!("database is read-only");
+        }
+        if !self.cameras_by_id.contains_key(&camera_id) {
+            bail!("no such camera {}", camera_id);
+        }
+        if jpeg.is_empty() {
+            bail!("empty snapshot for camera {}", camera_id);
+        }
+        raw::insert_scheduled_snapshot(&self.conn, camera_id, time, jpeg)
+    }
+
+    /// Lists scheduled snapshots of the given camera within the given time range, in ascending
+    /// order.
+    pub fn list_scheduled_snapshots(&self, camera_id: i32, desired_time: Range<recording::Time>)
+                                    -> Result<Vec<ListThumbnailsRow>, Error> {
+        if !self.cameras_by_id.contains_key(&camera_id) {
+            bail!("no such camera {}", camera_id);
+        }
+        raw::list_scheduled_snapshots(&self.conn, camera_id, desired_time)
+    }
+
+    /// Gets the latest scheduled snapshot of the given camera at or before the given time,
+    /// returning its time and JPEG.
+    pub fn get_scheduled_snapshot(&self, camera_id: i32, time: recording::Time)
+                                  -> Result<Option<(recording::Time, Vec<u8>)>, Error> {
+        raw::get_scheduled_snapshot(&self.conn, camera_id, time)
+    }
+
+    /// Deletes the given camera's scheduled snapshots from before the given time, returning how
+    /// many were deleted.
+    pub fn delete_scheduled_snapshots(&mut self, camera_id: i32, before: recording::Time)
+                                      -> Result<usize, Error> {
+        raw::delete_scheduled_snapshots(&self.conn, camera_id, Some(before))
+    }
+
+    /// Adds an incident with no items, returning it.
+    pub fn add_incident(&mut self, title: String, description: String, now_sec: i64)
+                        -> Result<Incident, Error> {
+        if title.is_empty() {
+            bail!("incident must have a title");
+        }
+        let mut i = Incident {
+            id: 0,
+            uuid: Uuid::new_v4(),
+            title,
+            description,
+            created_sec: now_sec,
         };
-        if desired_ids.start < s.next_recording_id {
-            raw::list_recordings_by_id(&self.conn, stream_id, desired_ids.clone(), f)?;
+        i.id = raw::insert_incident(&self.conn, &i)? as i32;
+        info!(target: "audit", "added incident {}: {}", i.uuid, i.title);
+        Ok(i)
+    }
+
+    /// Lists all incidents, in the order they were added.
+    pub fn list_incidents(&self) -> Result<Vec<Incident>, Error> {
+        raw::list_incidents(&self.conn)
+    }
+
+    /// Deletes the given incident and its items (but not the events, notes, and exports they
+    /// reference), returning true iff it existed.
+    pub fn delete_incident(&mut self, id: i32) -> Result<bool, Error> {
+        let tx = self.conn.transaction()?;
+        let existed = raw::delete_incident(&tx, id)?;
+        tx.commit()?;
+        if existed {
+            info!(target: "audit", "deleted incident {}", id);
         }
-        if desired_ids.end > s.next_recording_id {
-            let start = cmp::max(0, desired_ids.start - s.next_recording_id) as usize;
-            let end = cmp::min((desired_ids.end - s.next_recording_id) as usize,
-                               s.uncommitted.len());
-            for i in start .. end {
-                let row = {
-                    let l = s.uncommitted[i].lock();
-                    if l.video_samples > 0 {
-                        l.to_list_row(CompositeId::new(stream_id, s.next_recording_id + i as i32),
-                                      self.open.unwrap().id)
-                    } else {
-                        continue;
-                    }
-                };
-                f(row)?;
-            }
+        Ok(existed)
+    }
+
+    /// Adds an item to the given incident.
+    pub fn add_incident_item(&mut self, incident_id: i32, item: &IncidentItem)
+                             -> Result<(), Error> {
+        match *item {
+            IncidentItem::Event(id) => {
+                if self.get_event(id)?.is_none() {
+                    bail!("no such event {}", id);
+                }
+            },
+            IncidentItem::Range { camera_id, ref time } => {
+                if !self.cameras_by_id.contains_key(&camera_id) {
+                    bail!("no such camera {}", camera_id);
+                }
+                if time.end < time.start {
+                    bail!("incident range has negative duration: {:?}", time);
+                }
+            },
+            IncidentItem::Note(_) | IncidentItem::Export(_) => {},
         }
-        Ok(())
+        raw::insert_incident_item(&self.conn, incident_id, item)
     }
 
-    /// Calls `list_recordings_by_time` and aggregates consecutive recordings.
-    /// Rows are given to the callback in arbitrary order. Callers which care about ordering
+    /// Lists the items of the given incident, in the order they were added.
+    pub fn list_incident_items(&self, incident_id: i32) -> Result<Vec<IncidentItem>, Error> {
+        raw::list_incident_items(&self.conn, incident_id)
+    }
+
+    /// Gets a given user by name.
+    pub fn get_user(&self, username: &str) -> Result<Option<User>, Error> {
+        raw::get_user(&self.conn, username)
+    }
+
+    /// Lists the names of the users belonging to the given tenant.
+    pub fn list_tenant_users(&self, tenant_id: i32) -> Result<Vec<String>, Error> {
+        raw::list_tenant_users(&self.conn, tenant_id)
+    }
+
+    /// Assigns the named user to the given tenant, or to none.
+    pub fn set_user_tenant(&mut self, username: &str, tenant_id: Option<i32>)
+                           -> Result<(), Error> {
+        self.check_tenant(tenant_id)?;
+        let mut stmt = self.conn.prepare_cached(
+            "update user set tenant_id = :tenant_id where username = :username")?;
+        if stmt.execute_named(&[(":tenant_id", &tenant_id), (":username", &username)])? != 1 {
+            bail!("no such user {}", username);
+        }
+        info!(target: "audit", "assigned user {} to tenant {:?}", username,
+              tenant_id.map(|id| &self.tenants_by_id[&id].short_name));
+        Ok(())
+    }
+
+    /// Lists the preferences of the given user, ordered by key, or `None` if there's no such
+    /// user.
+    pub fn list_user_preferences(&self, user_id: i32)
+                                 -> Result<Option<Vec<UserPreference>>, Error> {
+        if !raw::user_exists(&self.conn, user_id)? {
+            return Ok(None);
+        }
+        raw::list_user_preferences(&self.conn, user_id).map(Some)
+    }
+
+    /// Sets a preference of the given user, returning its new version.
+    /// If `expected_version` is supplied, the preference is only changed if its current version
+    /// matches (with 0 meaning the preference doesn't exist yet); otherwise this returns `None`.
+    pub fn set_user_preference(&mut self, user_id: i32, key: &str, value: &str,
+                               expected_version: Option<i64>) -> Result<Option<i64>, Error> {
+        if key.is_empty() {
+            bail!("preference must have a key");
+        }
+        let tx = self.conn.transaction()?;
+        if !raw::user_exists(&tx, user_id)? {
+            bail!("no such user {}", user_id);
+        }
+        let version = raw::get_user_preference_version(&tx, user_id, key)?.unwrap_or(0);
+        if let Some(e) = expected_version {
+            if e != version {
+                return Ok(None);
+            }
+        }
+        raw::set_user_preference(&tx, user_id, key, value, version + 1)?;
+        tx.commit()?;
+        Ok(Some(version + 1))
+    }
+
+    /// Adds a layout, returning it. `l.id` and `l.uuid` are ignored.
+    pub fn add_layout(&mut self, mut l: Layout) -> Result<Layout, Error> {
+        if l.name.is_empty() {
+            bail!("layout must have a name");
+        }
+        if l.rows < 1 || l.rows > MAX_LAYOUT_DIMENSION ||
+           l.columns < 1 || l.columns > MAX_LAYOUT_DIMENSION {
+            bail!("layout must have 1 to {} rows and columns", MAX_LAYOUT_DIMENSION);
+        }
+        l.cells.sort_by_key(|c| c.position);
+        let n = u16::from(l.rows) * u16::from(l.columns);
+        for (i, c) in l.cells.iter().enumerate() {
+            if c.position >= n {
+                bail!("cell position {} is outside {}x{} layout", c.position, l.rows, l.columns);
+            }
+            if i > 0 && l.cells[i - 1].position == c.position {
+                bail!("duplicate cell position {}", c.position);
+            }
+        }
+        let tx = self.conn.transaction()?;
+        if !raw::user_exists(&tx, l.owner_user_id)? {
+            bail!("no such user {}", l.owner_user_id);
+        }
+        l.uuid = Uuid::new_v4();
+        l.id = raw::insert_layout(&tx, &l)? as i32;
+        tx.commit()?;
+        info!(target: "audit", "added layout {}: {}", l.uuid, l.name);
+        Ok(l)
+    }
+
+    /// Lists layouts visible to the given user (those it owns and those shared), or only shared
+    /// layouts if `user_id` is `None`. Layouts are in the order they were added.
+    pub fn list_layouts(&self, user_id: Option<i32>) -> Result<Vec<Layout>, Error> {
+        let mut layouts = raw::list_layouts(&self.conn)?;
+        layouts.retain(|l| l.shared || Some(l.owner_user_id) == user_id);
+        Ok(layouts)
+    }
+
+    /// Gets the given layout, regardless of its owner.
+    pub fn get_layout(&self, uuid: Uuid) -> Result<Option<Layout>, Error> {
+        Ok(raw::list_layouts(&self.conn)?.into_iter().find(|l| l.uuid == uuid))
+    }
+
+    /// Shares or unshares the given layout, returning true iff it exists.
+    pub fn set_layout_shared(&mut self, id: i32, shared: bool) -> Result<bool, Error> {
+        let existed = raw::set_layout_shared(&self.conn, id, shared)?;
+        if existed {
+            info!(target: "audit", "{} layout {}", if shared { "shared" } else { "unshared" }, id);
+        }
+        Ok(existed)
+    }
+
+    /// Deletes the given layout, returning true iff it existed.
+    pub fn delete_layout(&mut self, id: i32) -> Result<bool, Error> {
+        let tx = self.conn.transaction()?;
+        let existed = raw::delete_layout(&tx, id)?;
+        tx.commit()?;
+        if existed {
+            info!(target: "audit", "deleted layout {}", id);
+        }
+        Ok(existed)
+    }
+
+    pub fn get_event(&self, id: i64) -> Result<Option<ListEventsRow>, Error> {
+        raw::get_event(&self.conn, id)
+    }
+
+    /// Adds the given usage to the stored hourly totals.
+    pub fn add_bandwidth_usage(&mut self, usage: &[BandwidthUsage]) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+        for u in usage {
+            if u.start_sec % 3600 != 0 {
+                bail!("bandwidth usage start {} isn't on an hour boundary", u.start_sec);
+            }
+            raw::add_bandwidth_usage(&tx, u)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Lists hourly bandwidth totals starting within the given range, ordered by start.
+    pub fn list_bandwidth_usage(&self, start_sec: Range<i64>)
+                                -> Result<Vec<BandwidthUsage>, Error> {
+        raw::list_bandwidth_usage(&self.conn, start_sec)
+    }
+
+    /// Deletes hourly bandwidth totals starting before the given time, returning how many.
+    pub fn delete_bandwidth_usage_before(&mut self, sec: i64) -> Result<usize, Error> {
+        raw::delete_bandwidth_usage_before(&self.conn, sec)
+    }
+
+    /// Adds an event, returning its id. Unlike recordings, events are written immediately rather
+    /// than at the next flush; they're small and infrequent.
+    ///
+    /// If the event should be merged with the previous one (see `EventMerge`), that event is
+    /// extended instead, and its id is returned.
+    pub fn add_event(&mut self, e: &EventToInsert) -> Result<i64, Error> {
+        if self.open.is_none() {
+            bail!("database is read-only");
+        }
+        if !self.cameras_by_id.contains_key(&e.camera_id) {
+            bail!("no such camera {}", e.camera_id);
+        }
+        if e.time.end < e.time.start {
+            bail!("event has negative duration: {:?}", e);
+        }
+        let key = (e.camera_id, e.type_.clone(), e.description.clone());
+        let merged = match self.last_events.get(&key) {
+            Some(&(id, ref prev)) => self.event_merge.merge(prev, e).map(|m| (id, m)),
+            None => None,
+        };
+        if let Some((id, m)) = merged {
+            raw::update_event(&self.conn, id, &m)?;
+            self.last_events.insert(key, (id, m.clone()));
+            self.notify(&Change::EventUpdated { id, event: m });
+            return Ok(id);
+        }
+        let id = raw::insert_event(&self.conn, e)?;
+        self.last_events.insert(key, (id, e.clone()));
+        self.notify(&Change::EventAdded { id, event: e.clone() });
+        Ok(id)
+    }
+
+    /// Stores a JPEG snapshot of the given event, replacing any existing one.
+    pub fn add_event_snapshot(&mut self, event_id: i64, jpeg: &[u8]) -> Result<(), Error> {
+        if self.open.is_none() {
+            bail!("database is read-only");
+        }
+        if jpeg.is_empty() {
+            bail!("empty snapshot for event {}", event_id);
+        }
+        raw::insert_event_snapshot(&self.conn, event_id, jpeg)
+    }
+
+    pub fn get_event_snapshot(&self, event_id: i64) -> Result<Option<Vec<u8>>, Error> {
+        raw::get_event_snapshot(&self.conn, event_id)
+    }
+
+    /// Adds objects detected within the given event.
+    pub fn add_event_detections(&mut self, event_id: i64, detections: &[Detection])
+                                -> Result<(), Error> {
+        for d in detections {
+            if d.label.is_empty() {
+                bail!("detection must have a label");
+            }
+            if d.score.map(|s| !(s >= 0. && s <= 1.)).unwrap_or(false) {
+                bail!("detection score {:?} is outside [0, 1]", d.score);
+            }
+            if !(d.x >= 0. && d.y >= 0. && d.width > 0. && d.height > 0. &&
+                 d.x + d.width <= 1. && d.y + d.height <= 1.) {
+                bail!("detection box {:?} is outside the frame", d);
+            }
+        }
+        let tx = self.conn.transaction()?;
+        if raw::get_event(&tx, event_id)?.is_none() {
+            bail!("no such event {}", event_id);
+        }
+        for d in detections {
+            raw::insert_event_detection(&tx, event_id, d)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Lists the objects detected within the given event, in the order they were added.
+    pub fn list_event_detections(&self, event_id: i64) -> Result<Vec<Detection>, Error> {
+        raw::list_event_detections(&self.conn, event_id)
+    }
+
+    /// Adds a push subscription, replacing any existing one with the same endpoint.
+    pub fn add_push_subscription(&mut self, s: &PushSubscription) -> Result<(), Error> {
+        if s.endpoint.is_empty() {
+            bail!("push subscription must have an endpoint");
+        }
+        raw::insert_push_subscription(&self.conn, s)
+    }
+
+    /// Deletes the push subscription with the given endpoint, returning true iff it existed.
+    pub fn delete_push_subscription(&mut self, endpoint: &str) -> Result<bool, Error> {
+        raw::delete_push_subscription(&self.conn, endpoint)
+    }
+
+    pub fn list_push_subscriptions(&self) -> Result<Vec<PushSubscription>, Error> {
+        raw::list_push_subscriptions(&self.conn)
+    }
+
+    /// Adds a pending job of the given type, returning it.
+    pub fn add_job(&mut self, type_: String, params: String, now_sec: i64) -> Result<Job, Error> {
+        let mut j = Job {
+            id: 0,
+            uuid: Uuid::new_v4(),
+            type_,
+            params,
+            state: JobState::Pending,
+            created_sec: now_sec,
+            finished_sec: None,
+            result: None,
+        };
+        j.id = raw::insert_job(&self.conn, &j)? as i32;
+        Ok(j)
+    }
+
+    /// Updates the state, finish time, and result of the given job.
+    pub fn update_job(&mut self, j: &Job) -> Result<(), Error> {
+        raw::update_job(&self.conn, j)
+    }
+
+    /// Deletes the given job, returning true iff it existed.
+    pub fn delete_job(&mut self, id: i32) -> Result<bool, Error> {
+        raw::delete_job(&self.conn, id)
+    }
+
+    /// Lists all jobs, in the order they were added.
+    pub fn list_jobs(&self) -> Result<Vec<Job>, Error> {
+        raw::list_jobs(&self.conn)
+    }
+
+    /// Updates the in-memory health of the given stream.
+    pub fn update_stream_health(&mut self, stream_id: i32, health: StreamHealth)
+                                -> Result<(), Error> {
+        let changed = match self.streams_by_id.get_mut(&stream_id) {
+            None => bail!("no such stream {}", stream_id),
+            Some(s) => {
+                let changed = s.health.state() != health.state();
+                s.health = health;
+                changed
+            },
+        };
+        if changed {
+            self.notify(&Change::StreamHealth { stream_id });
+        }
+        Ok(())
+    }
+
+    /// Pauses or resumes recording of the given stream. This is persisted immediately, so that it
+    /// survives a restart; the stream's streamer notices within a key frame interval. Returns
+    /// true iff the state changed.
+    pub fn set_stream_paused(&mut self, stream_id: i32, paused: bool) -> Result<bool, Error> {
+        match self.streams_by_id.get(&stream_id) {
+            None => bail!("no such stream {}", stream_id),
+            Some(s) if s.paused == paused => return Ok(false),
+            Some(_) => {},
+        }
+        self.conn.execute_named("update stream set paused = :paused where id = :id",
+                                &[(":paused", &paused), (":id", &stream_id)])?;
+        self.streams_by_id.get_mut(&stream_id).unwrap().paused = paused;
+        Ok(true)
+    }
+
+    /// Changes the credentials used to access the given camera, keeping the current ones for
+    /// `rollback_camera_credentials`. Streamers notice the change at their next key frame and
+    /// reconnect with the new credentials.
+    pub fn rotate_camera_credentials(&mut self, camera_id: i32, username: String,
+                                     password: String) -> Result<(), Error> {
+        let previous = match self.cameras_by_id.get(&camera_id) {
+            None => bail!("no such camera {}", camera_id),
+            Some(c) => (c.username.clone(), c.password.clone()),
+        };
+        self.set_camera_credentials(camera_id, (username, password), Some(previous))
+    }
+
+    /// Swaps the given camera's credentials with those in use before the most recent rotation, so
+    /// rolling back twice returns to the rotated credentials.
+    pub fn rollback_camera_credentials(&mut self, camera_id: i32) -> Result<(), Error> {
+        let (current, previous) = match self.cameras_by_id.get(&camera_id) {
+            None => bail!("no such camera {}", camera_id),
+            Some(c) => match c.previous_credentials {
+                None => bail!("camera {} has no previous credentials", c.short_name),
+                Some(ref p) => (p.clone(), (c.username.clone(), c.password.clone())),
+            },
+        };
+        self.set_camera_credentials(camera_id, current, Some(previous))
+    }
+
+    fn set_camera_credentials(&mut self, camera_id: i32, current: (String, String),
+                              previous: Option<(String, String)>) -> Result<(), Error> {
+        let rows = self.conn.execute_named(r#"
+            update camera set
+                username = :username,
+                password = :password,
+                previous_username = :previous_username,
+                previous_password = :previous_password
+            where
+                id = :id
+        "#, &[
+            (":username", &current.0),
+            (":password", &current.1),
+            (":previous_username", &previous.as_ref().map(|p| &p.0)),
+            (":previous_password", &previous.as_ref().map(|p| &p.1)),
+            (":id", &camera_id),
+        ])?;
+        if rows != 1 {
+            bail!("Camera {} missing from database", camera_id);
+        }
+        let c = self.cameras_by_id.get_mut(&camera_id).unwrap();
+        c.username = current.0;
+        c.password = current.1;
+        c.previous_credentials = previous;
+        Ok(())
+    }
+
+    /// Records the result of a reachability check of the given camera: `None` if it answered,
+    /// or the error if not. Returns true iff its reachability changed, in which case watchers are
+    /// notified.
+    pub fn update_camera_reachability(&mut self, camera_id: i32, error: Option<String>)
+                                      -> Result<bool, Error> {
+        let reachable = Some(error.is_none());
+        let changed = match self.cameras_by_id.get_mut(&camera_id) {
+            None => bail!("no such camera {}", camera_id),
+            Some(c) => {
+                let changed = c.reachability.reachable != reachable;
+                c.reachability = CameraReachability { reachable, last_error: error };
+                changed
+            },
+        };
+        if changed {
+            self.notify(&Change::CameraReachability { camera_id });
+        }
+        Ok(changed)
+    }
+
+    /// Records the result of a clock check of the given camera: its skew and whether it uses NTP,
+    /// or the error. A failed check keeps the skew from the last successful one.
+    pub fn update_camera_clock(&mut self, camera_id: i32, result: Result<(i64, bool), String>)
+                               -> Result<(), Error> {
+        let c = match self.cameras_by_id.get_mut(&camera_id) {
+            None => bail!("no such camera {}", camera_id),
+            Some(c) => c,
+        };
+        match result {
+            Ok((skew_sec, ntp)) => {
+                c.clock = CameraClock {
+                    skew_sec: Some(skew_sec),
+                    ntp: Some(ntp),
+                    last_error: None,
+                };
+            },
+            Err(e) => c.clock.last_error = Some(e),
+        }
+        Ok(())
+    }
+
+    /// Lists the specified recordings in ascending order by id.
+    pub fn list_recordings_by_id(
+        &self, stream_id: i32, desired_ids: Range<i32>,
+        f: &mut FnMut(ListRecordingsRow) -> Result<(), Error>) -> Result<(), Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail!("no such stream {}", stream_id),
+            Some(s) => s,
+        };
+        if desired_ids.start < s.next_recording_id {
+            raw::list_recordings_by_id(&self.conn, stream_id, desired_ids.clone(), f)?;
+        }
+        if desired_ids.end > s.next_recording_id {
+            let start = cmp::max(0, desired_ids.start - s.next_recording_id) as usize;
+            let end = cmp::min((desired_ids.end - s.next_recording_id) as usize,
+                               s.uncommitted.len());
+            for i in start .. end {
+                let row = {
+                    let l = s.uncommitted[i].lock();
+                    if l.video_samples > 0 {
+                        l.to_list_row(CompositeId::new(stream_id, s.next_recording_id + i as i32),
+                                      self.open.unwrap().id)
+                    } else {
+                        continue;
+                    }
+                };
+                f(row)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `list_recordings_by_time` and aggregates consecutive recordings.
+    /// Rows are given to the callback in arbitrary order. Callers which care about ordering
     /// should do their own sorting.
     pub fn list_aggregated_recordings(
-        &self, stream_id: i32, desired_time: Range<recording::Time>,
-        forced_split: recording::Duration,
+        &self, stream_id: i32, desired_time: Range<recording::Time>, split: AggregationSplit,
         f: &mut FnMut(&ListAggregatedRecordingsRow) -> Result<(), Error>)
         -> Result<(), Error> {
         // Iterate, maintaining a map from a recording_id to the aggregated row for the latest
         // batch of recordings from the run starting at that id. Runs can be split into multiple
         // batches for a few reasons:
         //
-        // * forced split (when exceeding a duration limit)
+        // * forced split (when exceeding a duration, byte, or recording count limit)
         // * a missing id (one that was deleted out of order)
         // * video_sample_entry mismatch (if the parameters changed during a RTSP session)
+        // * archived mismatch (if only some of the run's sample files have been archived)
         //
         // This iteration works because in a run, the start_time+duration of recording id r
         // is equal to the start_time of recording id r+1. Thus ascending times guarantees
@@ -1113,11 +3055,10 @@ impl LockedDatabase {
         self.list_recordings_by_time(stream_id, desired_time, &mut |row| {
             let recording_id = row.id.recording();
             let run_start_id = recording_id - row.run_offset;
+            let archived = (row.flags & RecordingFlags::Archived as i32) != 0;
             let needs_flush = if let Some(a) = aggs.get(&run_start_id) {
-                let new_dur = a.time.end - a.time.start +
-                              recording::Duration(row.duration_90k as i64);
                 a.ids.end != recording_id || row.video_sample_entry_id != a.video_sample_entry_id ||
-                   new_dur >= forced_split
+                   a.archived != archived || split.should_split(a, &row)
             } else {
                 false
             };
@@ -1127,6 +3068,7 @@ impl LockedDatabase {
             }
             let uncommitted = (row.flags & RecordingFlags::Uncommitted as i32) != 0;
             let growing = (row.flags & RecordingFlags::Growing as i32) != 0;
+            let degraded = (row.flags & RecordingFlags::Degraded as i32) != 0;
             use std::collections::btree_map::Entry;
             match aggs.entry(run_start_id) {
                 Entry::Occupied(mut e) => {
@@ -1148,6 +3090,14 @@ impl LockedDatabase {
                         a.first_uncommitted = a.first_uncommitted.or(Some(recording_id));
                     }
                     a.growing = growing;
+                    a.degraded |= degraded;
+                    if row.min_frame_interval_90k > 0 &&
+                       (a.min_frame_interval_90k == 0 ||
+                        row.min_frame_interval_90k < a.min_frame_interval_90k) {
+                        a.min_frame_interval_90k = row.min_frame_interval_90k;
+                    }
+                    a.max_frame_interval_90k = cmp::max(a.max_frame_interval_90k,
+                                                        row.max_frame_interval_90k);
                 },
                 Entry::Vacant(e) => {
                     e.insert(ListAggregatedRecordingsRow {
@@ -1162,6 +3112,10 @@ impl LockedDatabase {
                         open_id: row.open_id,
                         first_uncommitted: if uncommitted { Some(recording_id) } else { None },
                         growing,
+                        degraded,
+                        archived,
+                        min_frame_interval_90k: row.min_frame_interval_90k,
+                        max_frame_interval_90k: row.max_frame_interval_90k,
                     });
                 },
             };
@@ -1212,8 +3166,50 @@ impl LockedDatabase {
         Err(format_err!("no such recording {}", id))
     }
 
-    /// Deletes the oldest recordings that aren't already queued for deletion.
-    /// `f` should return true for each row that should be deleted.
+    /// Calls `f` with the given recording's metadata sidecar (empty if it has none); see
+    /// `recording::parse_metadata`. Note the lock is held for the duration of `f`.
+    pub fn with_recording_metadata<R>(&self, id: CompositeId,
+                                      f: &mut FnMut(&[u8]) -> Result<R, Error>)
+                                      -> Result<R, Error> {
+        let s = self.streams_by_id
+                    .get(&id.stream())
+                    .ok_or_else(|| format_err!("no stream for {}", id))?;
+        if s.next_recording_id <= id.recording() {
+            let i = id.recording() - s.next_recording_id;
+            if i as usize >= s.uncommitted.len() {
+                bail!("no such recording {}", id);
+            }
+            let l = s.uncommitted[i as usize].lock();
+            return f(&l.metadata);
+        }
+        f(&raw::get_recording_metadata(&self.conn, id)?)
+    }
+
+    /// Returns the ids of the stream's committed recordings, from the oldest (archived or not)
+    /// through the newest. The range is empty if there are none.
+    pub fn committed_recording_ids(&self, stream_id: i32) -> Result<Range<i32>, Error> {
+        let s = match self.streams_by_id.get(&stream_id) {
+            None => bail!("no such stream {}", stream_id),
+            Some(s) => s,
+        };
+        let start = raw::get_first_recording_id(&self.conn, stream_id)?
+                        .unwrap_or(s.next_recording_id);
+        Ok(start .. s.next_recording_id)
+    }
+
+    /// Returns the id of the stream's oldest committed recording which isn't archived, if any.
+    pub(crate) fn oldest_recording_id(&self, stream_id: i32) -> Result<Option<CompositeId>, Error> {
+        let mut id = None;
+        raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, 0), &mut |r| {
+            id = Some(r.id);
+            false
+        })?;
+        Ok(id)
+    }
+
+    /// Deletes the oldest recordings that aren't already queued for deletion or archived.
+    /// `f` should return true for each row that should be deleted. Recordings covered by a `Hold`
+    /// are skipped, so a hold doesn't keep retention from deleting the recordings after it.
     pub(crate) fn delete_oldest_recordings(
         &mut self, stream_id: i32, f: &mut FnMut(&ListOldestRecordingsRow) -> bool)
         -> Result<(), Error> {
@@ -1225,7 +3221,19 @@ impl LockedDatabase {
             None => 0,
             Some(row) => row.id.recording() + 1,
         };
+        let holds: Vec<&Hold> =
+            self.holds_by_id.values().filter(|h| h.camera_id == s.camera_id).collect();
+        let logged = &mut self.holds_blocking_retention;
         raw::list_oldest_recordings(&self.conn, CompositeId::new(stream_id, end), &mut |r| {
+            let time = r.start .. r.start + recording::Duration(r.duration as i64);
+            if let Some(h) = holds.iter().find(|h| h.time.start < time.end &&
+                                                   time.start < h.time.end) {
+                if logged.insert(h.id) {
+                    warn!(target: "audit", "hold {} ({}) prevents deletion of recording {} and \
+                          any others it covers", h.uuid, h.reason, r.id);
+                }
+                return true;
+            }
             if f(&r) {
                 s.to_delete.push(r);
                 s.bytes_to_delete += r.sample_file_bytes as i64;
@@ -1235,6 +3243,180 @@ impl LockedDatabase {
         })
     }
 
+    /// Returns true if any of the stream's committed recordings with the given ids is covered by
+    /// a `Hold` on its camera.
+    pub fn recordings_held(&self, stream_id: i32, ids: Range<i32>) -> Result<bool, Error> {
+        let camera_id = match self.streams_by_id.get(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s.camera_id,
+        };
+        let holds: Vec<&Hold> =
+            self.holds_by_id.values().filter(|h| h.camera_id == camera_id).collect();
+        if holds.is_empty() {
+            return Ok(false);
+        }
+        let mut held = false;
+        raw::list_recordings_by_id(&self.conn, stream_id, ids, &mut |r| {
+            let end = r.start + recording::Duration(r.duration_90k as i64);
+            held = held || holds.iter().any(|h| h.time.start < end && r.start < h.time.end);
+            Ok(())
+        })?;
+        Ok(held)
+    }
+
+    /// Marks the stream's recordings with the given ids as archived to `location`, so that they
+    /// remain listed after their sample files are moved elsewhere. Only committed recordings not
+    /// queued for deletion can be archived; ones already archived are skipped. Fails if any is
+    /// covered by a `Hold`, as held recordings must stay in place. Returns the ids of
+    /// the newly archived recordings, whose sample files the caller should then unlink with
+    /// `SampleFileDir::unlink_unreferenced`.
+    pub fn archive_recordings(&mut self, stream_id: i32, ids: Range<i32>, location: &str)
+                              -> Result<Vec<CompositeId>, Error> {
+        if location.is_empty() {
+            bail!("archive location must be non-empty");
+        }
+        if self.recordings_held(stream_id, ids.clone())? {
+            bail!("stream {} recordings {:?} are covered by a hold", stream_id, ids);
+        }
+        let s = match self.streams_by_id.get_mut(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => s,
+        };
+        if ids.end > s.next_recording_id {
+            bail!("stream {} recordings {:?} aren't all committed", stream_id, ids);
+        }
+        if let Some(l) = s.to_delete.last() {
+            if l.id.recording() >= ids.start {
+                bail!("stream {} recordings through {} are being deleted", stream_id, l.id);
+            }
+        }
+        let mut archived = Vec::new();
+        let mut bytes = 0;
+        let tx = self.conn.transaction()?;
+        {
+            let mut list_stmt = tx.prepare_cached(r#"
+                select
+                  composite_id,
+                  sample_file_bytes
+                from
+                  recording
+                where
+                  :start <= composite_id and
+                  composite_id < :end and
+                  (flags & :archived) = 0
+            "#)?;
+            let mut rows = list_stmt.query_named(&[
+                (":start", &CompositeId::new(stream_id, ids.start).0),
+                (":end", &CompositeId::new(stream_id, ids.end).0),
+                (":archived", &(RecordingFlags::Archived as i32)),
+            ])?;
+            while let Some(row) = rows.next() {
+                let row = row?;
+                archived.push(CompositeId(row.get_checked(0)?));
+                bytes += row.get_checked::<_, i32>(1)? as i64;
+            }
+            let mut flag_stmt = tx.prepare_cached(
+                "update recording set flags = flags | ? where composite_id = ?")?;
+            let mut location_stmt = tx.prepare_cached(
+                "insert into recording_archive (composite_id, location) values (?, ?)")?;
+            for id in &archived {
+                flag_stmt.execute(&[&(RecordingFlags::Archived as i32) as &ToSql, &id.0])?;
+                location_stmt.execute(&[&id.0 as &ToSql, &location])?;
+            }
+        }
+        tx.commit()?;
+        s.sample_file_bytes -= bytes;
+        Ok(archived)
+    }
+
+    /// Returns where the given recording's sample file was archived, or `None` if it isn't.
+    pub fn get_archive_location(&self, id: CompositeId) -> Result<Option<String>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            "select location from recording_archive where composite_id = ?")?;
+        let mut rows = stmt.query(&[&id.0])?;
+        let location = match rows.next() {
+            None => None,
+            Some(row) => Some(row?.get_checked(0)?),
+        };
+        Ok(location)
+    }
+
+    /// Returns the SHA-1 hash of the given committed recording's sample file, or `None` if it's
+    /// unknown (the `recording_integrity` row is optional).
+    pub fn get_sample_file_sha1(&self, id: CompositeId) -> Result<Option<[u8; 20]>, Error> {
+        let mut stmt = self.conn.prepare_cached(
+            "select sample_file_sha1 from recording_integrity where composite_id = ?")?;
+        let mut rows = stmt.query(&[&id.0])?;
+        let sha1: Option<Vec<u8>> = match rows.next() {
+            None => return Ok(None),
+            Some(row) => row?.get_checked(0)?,
+        };
+        match sha1 {
+            None => Ok(None),
+            Some(b) => Ok(Some(raw::sha1_from_blob(b)?)),
+        }
+    }
+
+    /// Moves the given stream's recordings to another sample file directory, once their files
+    /// have been copied there. The stream's `sample_file_dir_id` is changed, and the files in the
+    /// old directory are moved to the garbage table to be unlinked, as if deleted. The stream must
+    /// be settled (see `Stream::is_settled`) with no recordings outside `copied`. Returns the ids
+    /// of the files moved to the garbage table; a copied recording not among them has since been
+    /// deleted, so its copy should be unlinked.
+    pub fn move_stream(&mut self, stream_id: i32, dir_id: i32, copied: Range<i32>)
+                       -> Result<Vec<CompositeId>, Error> {
+        let old_dir_id = {
+            let s = match self.streams_by_id.get(&stream_id) {
+                None => bail!("no such stream {}", stream_id),
+                Some(s) => s,
+            };
+            let old = match s.sample_file_dir_id {
+                None => bail!("stream {} has no sample file dir", stream_id),
+                Some(d) if d == dir_id => bail!("stream {} is already in dir {}", stream_id, d),
+                Some(d) => d,
+            };
+            if s.mirror_sample_file_dir_id == Some(dir_id) {
+                bail!("dir {} is stream {}'s mirror", dir_id, stream_id);
+            }
+            if !self.sample_file_dirs_by_id.contains_key(&dir_id) {
+                bail!("no such dir {}", dir_id);
+            }
+            if !s.is_settled() {
+                bail!("stream {} has recordings in progress", stream_id);
+            }
+            if s.next_recording_id > copied.end {
+                bail!("stream {} recordings {}..{} weren't copied",
+                      stream_id, copied.end, s.next_recording_id);
+            }
+            old
+        };
+        let mut moved = Vec::new();
+        let tx = self.conn.transaction()?;
+        {
+            raw::list_oldest_recordings(&tx, CompositeId::new(stream_id, 0), &mut |r| {
+                moved.push(r.id);
+                true
+            })?;
+            if let Some(id) = moved.iter().find(|id| id.recording() < copied.start) {
+                bail!("recording {} wasn't copied", id);
+            }
+            let mut garbage_stmt = tx.prepare_cached(r#"
+                insert into garbage (sample_file_dir_id, composite_id) values (?, ?)
+            "#)?;
+            for id in &moved {
+                garbage_stmt.execute(&[&old_dir_id as &ToSql, &id.0])?;
+            }
+            tx.execute("update stream set sample_file_dir_id = ? where id = ?",
+                       &[&dir_id as &ToSql, &stream_id])?;
+        }
+        tx.commit()?;
+        self.sample_file_dirs_by_id.get_mut(&old_dir_id).unwrap()
+            .garbage_needs_unlink.extend(moved.iter().cloned());
+        self.streams_by_id.get_mut(&stream_id).unwrap().sample_file_dir_id = Some(dir_id);
+        self.streams_generation += 1;
+        Ok(moved)
+    }
+
     /// Initializes the video_sample_entries. To be called during construction.
     fn init_video_sample_entries(&mut self) -> Result<(), Error> {
         info!("Loading video sample entries");
@@ -1285,7 +3467,10 @@ impl LockedDatabase {
               d.path,
               d.uuid,
               d.last_complete_open_id,
-              o.uuid
+              o.uuid,
+              d.network_fs,
+              d.reserved_bytes,
+              d.wrapped_key
             from
               sample_file_dir d left join open o on (d.last_complete_open_id = o.id);
         "#)?;
@@ -1305,6 +3490,9 @@ impl LockedDatabase {
                 id,
                 uuid: dir_uuid.0,
                 path: row.get_checked(1)?,
+                network_fs: row.get_checked(5)?,
+                reserved_bytes: row.get_checked(6)?,
+                wrapped_key: row.get_checked(7)?,
                 dir: None,
                 last_complete_open,
                 garbage_needs_unlink: raw::list_garbage(&self.conn, id)?,
@@ -1327,7 +3515,13 @@ impl LockedDatabase {
               description,
               host,
               username,
-              password
+              password,
+              tenant_id,
+              event_source,
+              snapshot_interval_sec,
+              snapshot_retain_days,
+              previous_username,
+              previous_password
             from
               camera;
         "#)?;
@@ -1336,6 +3530,22 @@ impl LockedDatabase {
             let row = row?;
             let id = row.get_checked(0)?;
             let uuid: FromSqlUuid = row.get_checked(1)?;
+            let event_source = match row.get_checked::<_, Option<String>>(8)? {
+                None => None,
+                Some(s) => Some(EventSource::parse(&s).ok_or_else(
+                    || format_err!("camera {} has unknown event source {:?}", id, s))?),
+            };
+            let snapshot_schedule = match row.get_checked::<_, Option<i64>>(9)? {
+                None => None,
+                Some(interval_sec) => Some(SnapshotSchedule {
+                    interval_sec,
+                    retain_days: row.get_checked(10)?,
+                }),
+            };
+            let previous_credentials = match row.get_checked::<_, Option<String>>(11)? {
+                None => None,
+                Some(u) => Some((u, row.get_checked(12)?)),
+            };
             self.cameras_by_id.insert(id, Camera {
                 id: id,
                 uuid: uuid.0,
@@ -1344,15 +3554,129 @@ impl LockedDatabase {
                 host: row.get_checked(4)?,
                 username: row.get_checked(5)?,
                 password: row.get_checked(6)?,
+                previous_credentials,
                 streams: Default::default(),
+                labels: BTreeMap::new(),
+                tenant_id: row.get_checked(7)?,
+                event_source,
+                snapshot_schedule,
+                reachability: CameraReachability::default(),
+                clock: CameraClock::default(),
             });
             self.cameras_by_uuid.insert(uuid.0, id);
         }
+        let mut stmt = self.conn.prepare("select camera_id, key, value from camera_label")?;
+        let mut rows = stmt.query(&[] as &[&ToSql])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let camera_id: i32 = row.get_checked(0)?;
+            let c = self.cameras_by_id.get_mut(&camera_id)
+                        .ok_or_else(|| format_err!("label for missing camera {}", camera_id))?;
+            c.labels.insert(row.get_checked(1)?, row.get_checked(2)?);
+        }
         info!("Loaded {} cameras", self.cameras_by_id.len());
         Ok(())
     }
 
-    /// Initializes the streams, but not their matching recordings.
+    /// Initializes the holds. To be called during construction.
+    fn init_holds(&mut self) -> Result<(), Error> {
+        info!("Loading holds");
+        let mut stmt = self.conn.prepare(r#"
+            select
+              id,
+              uuid,
+              camera_id,
+              start_time_90k,
+              end_time_90k,
+              reason,
+              created_sec,
+              expires_sec
+            from
+              hold;
+        "#)?;
+        let mut rows = stmt.query(&[] as &[&ToSql])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let id = row.get_checked(0)?;
+            let uuid: FromSqlUuid = row.get_checked(1)?;
+            self.holds_by_id.insert(id, Hold {
+                id,
+                uuid: uuid.0,
+                camera_id: row.get_checked(2)?,
+                time: recording::Time(row.get_checked(3)?) .. recording::Time(row.get_checked(4)?),
+                reason: row.get_checked(5)?,
+                created_sec: row.get_checked(6)?,
+                expires_sec: row.get_checked(7)?,
+            });
+        }
+        info!("Loaded {} holds", self.holds_by_id.len());
+        Ok(())
+    }
+
+    /// Initializes the export presets. To be called during construction.
+    fn init_export_presets(&mut self) -> Result<(), Error> {
+        info!("Loading export presets");
+        let mut stmt = self.conn.prepare(r#"
+            select
+              id,
+              name,
+              max_height,
+              watermark,
+              timestamps,
+              container
+            from
+              export_preset;
+        "#)?;
+        let mut rows = stmt.query(&[] as &[&ToSql])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let id = row.get_checked(0)?;
+            let container: String = row.get_checked(5)?;
+            self.export_presets_by_id.insert(id, ExportPreset {
+                id,
+                name: row.get_checked(1)?,
+                max_height: row.get_checked(2)?,
+                watermark: row.get_checked(3)?,
+                timestamps: row.get_checked(4)?,
+                container: ExportContainer::parse(&container).ok_or_else(
+                    || format_err!("export preset {} has unknown container {:?}", id, container))?,
+            });
+        }
+        info!("Loaded {} export presets", self.export_presets_by_id.len());
+        Ok(())
+    }
+
+    /// Initializes the tenants. To be called during construction.
+    fn init_tenants(&mut self) -> Result<(), Error> {
+        info!("Loading tenants");
+        let mut stmt = self.conn.prepare(r#"
+            select
+              id,
+              uuid,
+              short_name,
+              retain_bytes,
+              watermark_exports
+            from
+              tenant;
+        "#)?;
+        let mut rows = stmt.query(&[] as &[&ToSql])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let id = row.get_checked(0)?;
+            let uuid: FromSqlUuid = row.get_checked(1)?;
+            self.tenants_by_id.insert(id, Tenant {
+                id,
+                uuid: uuid.0,
+                short_name: row.get_checked(2)?,
+                retain_bytes: row.get_checked(3)?,
+                watermark_exports: row.get_checked(4)?,
+            });
+        }
+        info!("Loaded {} tenants", self.tenants_by_id.len());
+        Ok(())
+    }
+
+    /// Initializes the streams, but not their matching recordings.
     /// To be called during construction.
     fn init_streams(&mut self) -> Result<(), Error> {
         info!("Loading streams");
@@ -1366,7 +3690,24 @@ impl LockedDatabase {
               retain_bytes,
               flush_if_sec,
               next_recording_id,
-              record
+              record,
+              retain_weight,
+              chain_sha1,
+              mirror_sample_file_dir_id,
+              recording_duration_sec,
+              sei_motion_uuid,
+              snapshot_url,
+              metadata_events,
+              thumbnail_interval_sec,
+              paused,
+              encoder_gov_length,
+              encoder_bitrate_kbps,
+              encoder_frame_rate,
+              encoder_width,
+              encoder_height,
+              source,
+              source_url,
+              input_options
             from
               stream;
         "#)?;
@@ -1384,14 +3725,37 @@ impl LockedDatabase {
                         .ok_or_else(|| format_err!("missing camera {} for stream {}",
                                                    camera_id, id))?;
             let flush_if_sec = row.get_checked(6)?;
+            let chain_head = match row.get_checked::<_, Option<Vec<u8>>>(10)? {
+                None => chain::GENESIS,
+                Some(h) => raw::sha1_from_blob(h)?,
+            };
             self.streams_by_id.insert(id, Stream {
                 id,
                 type_,
                 camera_id,
                 sample_file_dir_id: row.get_checked(3)?,
+                mirror_sample_file_dir_id: row.get_checked(11)?,
                 rtsp_path: row.get_checked(4)?,
+                source: StreamSource::parse(&row.get_checked::<_, String>(23)?,
+                                            row.get_checked(24)?)?,
                 retain_bytes: row.get_checked(5)?,
+                retain_weight: row.get_checked(9)?,
                 flush_if_sec,
+                recording_duration_sec: row.get_checked(12)?,
+                sei_motion_uuid: row.get_checked::<_, Option<FromSqlUuid>>(13)?.map(|u| u.0),
+                snapshot_url: row.get_checked(14)?,
+                metadata_events: row.get_checked(15)?,
+                thumbnail_interval_sec: row.get_checked(16)?,
+                encoder: EncoderSettings {
+                    gov_length: row.get_checked(18)?,
+                    bitrate_kbps: row.get_checked(19)?,
+                    frame_rate: row.get_checked(20)?,
+                    resolution: match (row.get_checked(21)?, row.get_checked(22)?) {
+                        (Some(w), Some(h)) => Some((w, h)),
+                        _ => None,
+                    },
+                },
+                input_options: row.get_checked(25)?,
                 range: None,
                 sample_file_bytes: 0,
                 to_delete: Vec::new(),
@@ -1400,12 +3764,22 @@ impl LockedDatabase {
                 duration: recording::Duration(0),
                 days: BTreeMap::new(),
                 next_recording_id: row.get_checked(7)?,
+                chain_head,
+                last_anchor: None,
                 record: row.get_checked(8)?,
+                paused: row.get_checked(17)?,
                 uncommitted: VecDeque::new(),
                 synced_recordings: 0,
+                health: StreamHealth::default(),
+                over_limit_since: None,
             });
             c.streams[type_.index()] = Some(id);
         }
+        for (stream_id, a) in raw::list_last_chain_anchors(&self.conn)? {
+            if let Some(s) = self.streams_by_id.get_mut(&stream_id) {
+                s.last_anchor = Some(a);
+            }
+        }
         info!("Loaded {} streams", self.streams_by_id.len());
         Ok(())
     }
@@ -1454,7 +3828,7 @@ impl LockedDatabase {
         Ok(id)
     }
 
-    pub fn add_sample_file_dir(&mut self, path: String) -> Result<i32, Error> {
+    pub fn add_sample_file_dir(&mut self, path: String, network_fs: bool) -> Result<i32, Error> {
         let mut meta = schema::DirMeta::default();
         let uuid = Uuid::new_v4();
         let uuid_bytes = &uuid.as_bytes()[..];
@@ -1471,11 +3845,22 @@ impl LockedDatabase {
             open.uuid.extend_from_slice(&o.uuid.as_bytes()[..]);
         }
 
-        let dir = dir::SampleFileDir::create(&path, &meta)?;
+        // New directories are encrypted iff there's a master key.
+        let (cipher, wrapped_key) = match self.master_key {
+            None => (None, None),
+            Some(ref m) => {
+                let c = dir::Cipher::generate()?;
+                let w = c.wrap(m)?;
+                (Some(c), Some(w))
+            },
+        };
+        let dir = dir::SampleFileDir::create(&path, &meta, network_fs, cipher)?;
         self.conn.execute(r#"
-            insert into sample_file_dir (path, uuid, last_complete_open_id)
-                                 values (?,    ?,    ?)
-        "#, &[&path as &ToSql, &uuid_bytes, &o.id])?;
+            insert into sample_file_dir (path, uuid, last_complete_open_id, network_fs,
+                                         wrapped_key)
+                                 values (?,    ?,    ?,                     ?,
+                                         ?)
+        "#, &[&path as &ToSql, &uuid_bytes, &o.id, &network_fs, &wrapped_key])?;
         let id = self.conn.last_insert_rowid() as i32;
         use ::std::collections::btree_map::Entry;
         let e = self.sample_file_dirs_by_id.entry(id);
@@ -1484,6 +3869,9 @@ impl LockedDatabase {
                 id,
                 path,
                 uuid,
+                network_fs,
+                reserved_bytes: 0,
+                wrapped_key,
                 dir: Some(dir),
                 last_complete_open: None,
                 garbage_needs_unlink: FnvHashSet::default(),
@@ -1499,7 +3887,8 @@ impl LockedDatabase {
 
     pub fn delete_sample_file_dir(&mut self, dir_id: i32) -> Result<(), Error> {
         for (&id, s) in self.streams_by_id.iter() {
-            if s.sample_file_dir_id == Some(dir_id) {
+            if s.sample_file_dir_id == Some(dir_id) ||
+               s.mirror_sample_file_dir_id == Some(dir_id) {
                 bail!("can't delete dir referenced by stream {}", id);
             }
         }
@@ -1511,7 +3900,9 @@ impl LockedDatabase {
             bail!("must collect garbage before deleting directory {}", d.get().path);
         }
         let dir = match d.get_mut().dir.take() {
-            None => dir::SampleFileDir::open(&d.get().path, &d.get().meta(&self.uuid))?,
+            // Only the directory's emptiness is checked, so its key isn't needed.
+            None => dir::SampleFileDir::open(&d.get().path, &d.get().meta(&self.uuid),
+                                             d.get().network_fs, None)?,
             Some(arc) => match Arc::strong_count(&arc) {
                 1 => {
                     d.get_mut().dir = Some(arc);  // put it back.
@@ -1536,6 +3927,11 @@ impl LockedDatabase {
 
     /// Adds a camera.
     pub fn add_camera(&mut self, mut camera: CameraChange) -> Result<i32, Error> {
+        self.check_tenant(camera.tenant_id)?;
+        if let Some(ref s) = camera.snapshot_schedule {
+            s.check()?;
+        }
+        self.check_short_name(None, &camera.short_name)?;
         let uuid = Uuid::new_v4();
         let uuid_bytes = &uuid.as_bytes()[..];
         let tx = self.conn.transaction()?;
@@ -1543,8 +3939,12 @@ impl LockedDatabase {
         let camera_id;
         {
             let mut stmt = tx.prepare_cached(r#"
-                insert into camera (uuid,  short_name,  description,  host,  username,  password)
-                            values (:uuid, :short_name, :description, :host, :username, :password)
+                insert into camera (uuid,  short_name,  description,  host,  username,  password,
+                                    tenant_id,  event_source,  snapshot_interval_sec,
+                                    snapshot_retain_days)
+                            values (:uuid, :short_name, :description, :host, :username, :password,
+                                    :tenant_id, :event_source, :snapshot_interval_sec,
+                                    :snapshot_retain_days)
             "#)?;
             stmt.execute_named(&[
                 (":uuid", &uuid_bytes),
@@ -1553,10 +3953,16 @@ impl LockedDatabase {
                 (":host", &camera.host),
                 (":username", &camera.username),
                 (":password", &camera.password),
+                (":tenant_id", &camera.tenant_id),
+                (":event_source", &camera.event_source.map(EventSource::as_str)),
+                (":snapshot_interval_sec", &camera.snapshot_schedule.map(|s| s.interval_sec)),
+                (":snapshot_retain_days",
+                 &camera.snapshot_schedule.map(|s| s.retain_days).unwrap_or(30)),
             ])?;
             camera_id = tx.last_insert_rowid() as i32;
             streams = StreamStateChanger::new(&tx, camera_id, None, &self.streams_by_id,
                                          &mut camera)?;
+            set_camera_labels(&tx, camera_id, &camera.labels)?;
         }
         tx.commit()?;
         let streams = streams.apply(&mut self.streams_by_id);
@@ -1568,14 +3974,27 @@ impl LockedDatabase {
             host: camera.host,
             username: camera.username,
             password: camera.password,
+            previous_credentials: None,
             streams,
+            labels: camera.labels,
+            tenant_id: camera.tenant_id,
+            event_source: camera.event_source,
+            snapshot_schedule: camera.snapshot_schedule,
+            reachability: CameraReachability::default(),
+            clock: CameraClock::default(),
         });
         self.cameras_by_uuid.insert(uuid, camera_id);
+        self.streams_generation += 1;
         Ok(camera_id)
     }
 
     /// Updates a camera.
     pub fn update_camera(&mut self, camera_id: i32, mut camera: CameraChange) -> Result<(), Error> {
+        self.check_tenant(camera.tenant_id)?;
+        if let Some(ref s) = camera.snapshot_schedule {
+            s.check()?;
+        }
+        self.check_short_name(Some(camera_id), &camera.short_name)?;
         let tx = self.conn.transaction()?;
         let streams;
         let c = self
@@ -1591,7 +4010,11 @@ impl LockedDatabase {
                     description = :description,
                     host = :host,
                     username = :username,
-                    password = :password
+                    password = :password,
+                    tenant_id = :tenant_id,
+                    event_source = :event_source,
+                    snapshot_interval_sec = :snapshot_interval_sec,
+                    snapshot_retain_days = coalesce(:snapshot_retain_days, snapshot_retain_days)
                 where
                     id = :id
             "#)?;
@@ -1602,41 +4025,377 @@ impl LockedDatabase {
                 (":host", &camera.host),
                 (":username", &camera.username),
                 (":password", &camera.password),
+                (":tenant_id", &camera.tenant_id),
+                (":event_source", &camera.event_source.map(EventSource::as_str)),
+                (":snapshot_interval_sec", &camera.snapshot_schedule.map(|s| s.interval_sec)),
+                (":snapshot_retain_days", &camera.snapshot_schedule.map(|s| s.retain_days)),
             ])?;
             if rows != 1 {
                 bail!("Camera {} missing from database", camera_id);
             }
+            set_camera_labels(&tx, camera_id, &camera.labels)?;
         }
         tx.commit()?;
         c.short_name = camera.short_name;
         c.description = camera.description;
+        if c.host != camera.host {
+            c.reachability = CameraReachability::default();
+            c.clock = CameraClock::default();
+        }
         c.host = camera.host;
         c.username = camera.username;
         c.password = camera.password;
         c.streams = streams.apply(&mut self.streams_by_id);
+        c.labels = camera.labels;
+        c.tenant_id = camera.tenant_id;
+        c.event_source = camera.event_source;
+        c.snapshot_schedule = camera.snapshot_schedule;
+        self.streams_generation += 1;
+        Ok(())
+    }
+
+    /// Checks that `short_name` isn't used by any camera other than `camera_id`.
+    fn check_short_name(&self, camera_id: Option<i32>, short_name: &str) -> Result<(), Error> {
+        if short_name.is_empty() {
+            bail!("camera short name must be non-empty");
+        }
+        if let Some(c) = self.get_camera_by_short_name(short_name) {
+            if Some(c.id) != camera_id {
+                bail!("camera short name {:?} is already used by camera {}", short_name, c.uuid);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_tenant(&self, tenant_id: Option<i32>) -> Result<(), Error> {
+        if let Some(id) = tenant_id {
+            if !self.tenants_by_id.contains_key(&id) {
+                bail!("no such tenant {}", id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a tenant with the given tenant-wide retention limit and export policy.
+    pub fn add_tenant(&mut self, short_name: String, retain_bytes: Option<i64>,
+                      watermark_exports: bool) -> Result<i32, Error> {
+        if let Some(b) = retain_bytes {
+            if b < 0 {
+                bail!("can't set limit for tenant {} to {}; must be >= 0", short_name, b);
+            }
+        }
+        let uuid = Uuid::new_v4();
+        let uuid_bytes = &uuid.as_bytes()[..];
+        let mut stmt = self.conn.prepare_cached(r#"
+            insert into tenant (uuid,  short_name,  retain_bytes,  watermark_exports)
+                        values (:uuid, :short_name, :retain_bytes, :watermark_exports)
+        "#)?;
+        stmt.execute_named(&[
+            (":uuid", &uuid_bytes),
+            (":short_name", &short_name),
+            (":retain_bytes", &retain_bytes),
+            (":watermark_exports", &watermark_exports),
+        ])?;
+        let id = self.conn.last_insert_rowid() as i32;
+        self.tenants_by_id.insert(id, Tenant {
+            id,
+            uuid,
+            short_name,
+            retain_bytes,
+            watermark_exports,
+        });
+        Ok(id)
+    }
+
+    /// Updates a tenant's name, retention limit, and export policy. Note this doesn't delete any
+    /// recordings; the syncer will do so as new recordings are saved.
+    pub fn update_tenant(&mut self, id: i32, short_name: String, retain_bytes: Option<i64>,
+                         watermark_exports: bool) -> Result<(), Error> {
+        if let Some(b) = retain_bytes {
+            if b < 0 {
+                bail!("can't set limit for tenant {} to {}; must be >= 0", id, b);
+            }
+        }
+        let t = self.tenants_by_id.get_mut(&id).ok_or_else(|| format_err!("no such tenant {}", id))?;
+        let mut stmt = self.conn.prepare_cached(r#"
+            update tenant set
+                short_name = :short_name,
+                retain_bytes = :retain_bytes,
+                watermark_exports = :watermark_exports
+            where
+                id = :id
+        "#)?;
+        let rows = stmt.execute_named(&[
+            (":id", &id),
+            (":short_name", &short_name),
+            (":retain_bytes", &retain_bytes),
+            (":watermark_exports", &watermark_exports),
+        ])?;
+        if rows != 1 {
+            bail!("Tenant {} missing from database", id);
+        }
+        t.short_name = short_name;
+        t.retain_bytes = retain_bytes;
+        t.watermark_exports = watermark_exports;
+        Ok(())
+    }
+
+    /// Adds an export preset, returning its id.
+    pub fn add_export_preset(&mut self, change: ExportPresetChange) -> Result<i32, Error> {
+        change.check()?;
+        if self.get_export_preset(&change.name).is_some() {
+            bail!("export preset {} already exists", change.name);
+        }
+        let mut stmt = self.conn.prepare_cached(r#"
+            insert into export_preset (name,  max_height,  watermark,  timestamps,  container)
+                               values (:name, :max_height, :watermark, :timestamps, :container)
+        "#)?;
+        stmt.execute_named(&[
+            (":name", &change.name),
+            (":max_height", &change.max_height),
+            (":watermark", &change.watermark),
+            (":timestamps", &change.timestamps),
+            (":container", &change.container.as_str()),
+        ])?;
+        let id = self.conn.last_insert_rowid() as i32;
+        info!(target: "audit", "added export preset {}: {:?}", change.name, change);
+        self.export_presets_by_id.insert(id, ExportPreset {
+            id,
+            name: change.name,
+            max_height: change.max_height,
+            watermark: change.watermark,
+            timestamps: change.timestamps,
+            container: change.container,
+        });
+        Ok(id)
+    }
+
+    /// Updates an export preset. Exports already requested are unaffected.
+    pub fn update_export_preset(&mut self, id: i32, change: ExportPresetChange)
+                                -> Result<(), Error> {
+        change.check()?;
+        if self.export_presets_by_id.values().any(|p| p.id != id && p.name == change.name) {
+            bail!("export preset {} already exists", change.name);
+        }
+        let p = self.export_presets_by_id.get_mut(&id)
+                    .ok_or_else(|| format_err!("no such export preset {}", id))?;
+        let mut stmt = self.conn.prepare_cached(r#"
+            update export_preset set
+                name = :name,
+                max_height = :max_height,
+                watermark = :watermark,
+                timestamps = :timestamps,
+                container = :container
+            where
+                id = :id
+        "#)?;
+        let rows = stmt.execute_named(&[
+            (":id", &id),
+            (":name", &change.name),
+            (":max_height", &change.max_height),
+            (":watermark", &change.watermark),
+            (":timestamps", &change.timestamps),
+            (":container", &change.container.as_str()),
+        ])?;
+        if rows != 1 {
+            bail!("Export preset {} missing from database", id);
+        }
+        info!(target: "audit", "updated export preset {}: {:?}", p.name, change);
+        p.name = change.name;
+        p.max_height = change.max_height;
+        p.watermark = change.watermark;
+        p.timestamps = change.timestamps;
+        p.container = change.container;
+        Ok(())
+    }
+
+    /// Deletes an export preset. Exports already requested are unaffected.
+    pub fn delete_export_preset(&mut self, id: i32) -> Result<(), Error> {
+        if !self.export_presets_by_id.contains_key(&id) {
+            bail!("No such export preset {} to remove", id);
+        }
+        if self.conn.execute("delete from export_preset where id = ?", &[&id])? != 1 {
+            bail!("Export preset {} missing from database", id);
+        }
+        let p = self.export_presets_by_id.remove(&id).unwrap();
+        info!(target: "audit", "deleted export preset {}", p.name);
+        Ok(())
+    }
+
+    /// Adds a hold on the given camera's recordings within the given time range, lasting until
+    /// released or (if given) `expires_sec`.
+    pub fn add_hold(&mut self, camera_id: i32, time: Range<recording::Time>, reason: String,
+                    now_sec: i64, expires_sec: Option<i64>) -> Result<i32, Error> {
+        let camera_uuid = self.cameras_by_id.get(&camera_id)
+                              .map(|c| c.uuid)
+                              .ok_or_else(|| format_err!("no such camera {}", camera_id))?;
+        if time.start >= time.end {
+            bail!("hold must have a non-empty time range; got {}-{}", time.start, time.end);
+        }
+        if let Some(e) = expires_sec {
+            if e <= now_sec {
+                bail!("hold must expire in the future; got {}", e);
+            }
+        }
+        let uuid = Uuid::new_v4();
+        let uuid_bytes = &uuid.as_bytes()[..];
+        let mut stmt = self.conn.prepare_cached(r#"
+            insert into hold (uuid,  camera_id,  start_time_90k,  end_time_90k,  reason,
+                              created_sec,  expires_sec)
+                      values (:uuid, :camera_id, :start_time_90k, :end_time_90k, :reason,
+                              :created_sec, :expires_sec)
+        "#)?;
+        stmt.execute_named(&[
+            (":uuid", &uuid_bytes),
+            (":camera_id", &camera_id),
+            (":start_time_90k", &time.start.0),
+            (":end_time_90k", &time.end.0),
+            (":reason", &reason),
+            (":created_sec", &now_sec),
+            (":expires_sec", &expires_sec),
+        ])?;
+        let id = self.conn.last_insert_rowid() as i32;
+        info!(target: "audit", "added hold {} on camera {} from {} to {} (expires {:?}): {}",
+              uuid, camera_uuid, time.start, time.end, expires_sec, reason);
+        self.holds_by_id.insert(id, Hold {
+            id,
+            uuid,
+            camera_id,
+            time,
+            reason,
+            created_sec: now_sec,
+            expires_sec,
+        });
+        Ok(id)
+    }
+
+    /// Changes when a hold expires, as when a claim is settled early or drags on.
+    pub fn update_hold_expiry(&mut self, id: i32, expires_sec: Option<i64>) -> Result<(), Error> {
+        let h = match self.holds_by_id.get_mut(&id) {
+            None => bail!("No such hold {}", id),
+            Some(h) => h,
+        };
+        if let Some(e) = expires_sec {
+            if e <= h.created_sec {
+                bail!("hold {} must expire after its creation; got {}", h.uuid, e);
+            }
+        }
+        if self.conn.execute("update hold set expires_sec = ? where id = ?",
+                             &[&expires_sec as &ToSql, &id])? != 1 {
+            bail!("Hold {} missing from database", id);
+        }
+        info!(target: "audit", "hold {} ({}) now expires {:?}", h.uuid, h.reason, expires_sec);
+        h.expires_sec = expires_sec;
+        Ok(())
+    }
+
+    /// Releases (deletes) a hold, allowing its recordings to be deleted.
+    pub fn release_hold(&mut self, id: i32) -> Result<(), Error> {
+        if !self.holds_by_id.contains_key(&id) {
+            bail!("No such hold {} to release", id);
+        }
+        if self.conn.execute("delete from hold where id = ?", &[&id])? != 1 {
+            bail!("Hold {} missing from database", id);
+        }
+        let h = self.holds_by_id.remove(&id).unwrap();
+        self.holds_blocking_retention.remove(&id);
+        info!(target: "audit", "released hold {} ({})", h.uuid, h.reason);
+        Ok(())
+    }
+
+    /// Deletes a tenant. The tenant must have no cameras or users.
+    pub fn delete_tenant(&mut self, id: i32) -> Result<(), Error> {
+        if !self.tenants_by_id.contains_key(&id) {
+            bail!("No such tenant {} to remove", id);
+        }
+        if self.cameras_by_id.values().any(|c| c.tenant_id == Some(id)) {
+            bail!("Can't remove tenant {}; has cameras.", id);
+        }
+        if !raw::list_tenant_users(&self.conn, id)?.is_empty() {
+            bail!("Can't remove tenant {}; has users.", id);
+        }
+        if self.conn.execute("delete from tenant where id = ?", &[&id])? != 1 {
+            bail!("Tenant {} missing from database", id);
+        }
+        self.tenants_by_id.remove(&id);
         Ok(())
     }
 
     /// Deletes a camera and its streams. The camera must have no recordings.
     pub fn delete_camera(&mut self, id: i32) -> Result<(), Error> {
+        self.remove_camera(id, false).map(|_| ())
+    }
+
+    /// Deletes a camera along with everything referring to it, in a single transaction: its
+    /// streams' recordings (including archived ones), notes, and thumbnails, and its events and
+    /// incident items. The camera must have no holds, and its streams must have no uncommitted
+    /// recordings (so recording should be paused first) or recordings queued for deletion.
+    ///
+    /// The sample files are moved to the garbage table rather than unlinked; they're unlinked by
+    /// their directory's syncer after the next flush, or by `writer::collect_garbage`.
+    pub fn teardown_camera(&mut self, id: i32) -> Result<CameraTeardown, Error> {
+        self.remove_camera(id, true)
+    }
+
+    fn remove_camera(&mut self, id: i32, teardown: bool) -> Result<CameraTeardown, Error> {
         let uuid = self.cameras_by_id.get(&id)
                        .map(|c| c.uuid)
                        .ok_or_else(|| format_err!("No such camera {} to remove", id))?;
         let mut streams_to_delete = Vec::new();
+        let mut removed = CameraTeardown::default();
         let tx = self.conn.transaction()?;
         {
             let mut stream_stmt = tx.prepare_cached(r"delete from stream where id = :id")?;
-            for (stream_id, stream) in &self.streams_by_id {
+            let mut note_stmt = tx.prepare_cached(r"delete from note where stream_id = :id")?;
+            let mut note_item_stmt = tx.prepare_cached(r#"
+                delete from incident_item
+                where note_id in (select id from note where stream_id = :id)
+            "#)?;
+            let mut anchor_stmt =
+                tx.prepare_cached(r"delete from chain_anchor where stream_id = :id")?;
+            let mut thumbnail_stmt =
+                tx.prepare_cached(r"delete from thumbnail where stream_id = :id")?;
+            if self.holds_by_id.values().any(|h| h.camera_id == id) {
+                bail!("Can't remove camera {}; has holds.", id);
+            }
+            for (&stream_id, stream) in &self.streams_by_id {
                 if stream.camera_id != id { continue };
-                if stream.range.is_some() {
+                if !teardown && stream.range.is_some() {
                     bail!("Can't remove camera {}; has recordings.", id);
                 }
-                let rows = stream_stmt.execute_named(&[(":id", stream_id)])?;
+                if !stream.uncommitted.is_empty() {
+                    bail!("Can't remove camera {}; stream {} is still recording.", id, stream_id);
+                }
+                if !stream.to_delete.is_empty() {
+                    bail!("Can't remove camera {}; stream {} has recordings being deleted.",
+                          id, stream_id);
+                }
+                if teardown {
+                    let ids = CompositeId::new(stream_id, 0) .. CompositeId::new(stream_id + 1, 0);
+                    if let Some(dir_id) = stream.sample_file_dir_id {
+                        raw::list_oldest_recordings(&tx, ids.start, &mut |r| {
+                            removed.sample_files.push((dir_id, r.id));
+                            true
+                        })?;
+                        removed.recordings += raw::delete_recordings(&tx, dir_id, ids.clone())?;
+                    }
+                    removed.recordings += raw::delete_archived_recordings(&tx, ids)?;
+                    note_item_stmt.execute_named(&[(":id", &stream_id)])?;
+                }
+                note_stmt.execute_named(&[(":id", &stream_id)])?;
+                anchor_stmt.execute_named(&[(":id", &stream_id)])?;
+                thumbnail_stmt.execute_named(&[(":id", &stream_id)])?;
+                let rows = stream_stmt.execute_named(&[(":id", &stream_id)])?;
                 if rows != 1 {
                     bail!("Stream {} missing from database", id);
                 }
-                streams_to_delete.push(*stream_id);
+                streams_to_delete.push(stream_id);
             }
+            if teardown {
+                removed.events = raw::delete_camera_events(&tx, id)?;
+            }
+            set_camera_labels(&tx, id, &BTreeMap::new())?;
+            raw::delete_scheduled_snapshots(&tx, id, None)?;
             let mut cam_stmt = tx.prepare_cached(r"delete from camera where id = :id")?;
             let rows = cam_stmt.execute_named(&[(":id", &id)])?;
             if rows != 1 {
@@ -1644,12 +4403,41 @@ impl LockedDatabase {
             }
         }
         tx.commit()?;
+        for &(dir_id, id) in &removed.sample_files {
+            if let Some(d) = self.sample_file_dirs_by_id.get_mut(&dir_id) {
+                d.garbage_needs_unlink.insert(id);
+            }
+        }
+        removed.streams = streams_to_delete.len();
         for id in streams_to_delete {
             self.streams_by_id.remove(&id);
         }
         self.cameras_by_id.remove(&id);
         self.cameras_by_uuid.remove(&uuid);
-        return Ok(())
+        self.last_events.retain(|k, _| k.0 != id);
+        self.streams_generation += 1;
+        Ok(removed)
+    }
+
+    /// Sets the number of bytes to keep free on the given sample file directory's filesystem.
+    /// Doesn't delete any recordings; that happens on the directory's next rotation.
+    pub fn update_reserved_bytes(&mut self, dir_id: i32, reserved_bytes: i64)
+                                 -> Result<(), Error> {
+        if reserved_bytes < 0 {
+            bail!("can't set reserved bytes for dir {} to {}; must be >= 0",
+                  dir_id, reserved_bytes);
+        }
+        let d = self.sample_file_dirs_by_id.get_mut(&dir_id)
+                    .ok_or_else(|| format_err!("no such dir {}", dir_id))?;
+        let mut stmt = self.conn.prepare_cached(r#"
+            update sample_file_dir set reserved_bytes = :reserved_bytes where id = :id
+        "#)?;
+        stmt.execute_named(&[
+            (":reserved_bytes", &reserved_bytes),
+            (":id", &dir_id),
+        ])?;
+        d.reserved_bytes = reserved_bytes;
+        Ok(())
     }
 
     pub fn update_retention(&mut self, changes: &[RetentionChange]) -> Result<(), Error> {
@@ -1659,7 +4447,8 @@ impl LockedDatabase {
                 update stream
                 set
                   record = :record,
-                  retain_bytes = :retain
+                  retain_bytes = :retain,
+                  retain_weight = :weight
                 where
                   id = :id
             "#)?;
@@ -1668,9 +4457,14 @@ impl LockedDatabase {
                     bail!("can't set limit for stream {} to {}; must be >= 0",
                           c.stream_id, c.new_limit);
                 }
+                if c.new_weight <= 0 {
+                    bail!("can't set weight for stream {} to {}; must be > 0",
+                          c.stream_id, c.new_weight);
+                }
                 let rows = stmt.execute_named(&[
                     (":record", &c.new_record),
                     (":retain", &c.new_limit),
+                    (":weight", &c.new_weight),
                     (":id", &c.stream_id),
                 ])?;
                 if rows != 1 {
@@ -1683,11 +4477,30 @@ impl LockedDatabase {
             let s = self.streams_by_id.get_mut(&c.stream_id).expect("stream in db but not state");
             s.record = c.new_record;
             s.retain_bytes = c.new_limit;
+            s.retain_weight = c.new_weight;
         }
         Ok(())
     }
 }
 
+/// Replaces the labels of the given camera within a transaction.
+fn set_camera_labels(tx: &rusqlite::Transaction, camera_id: i32,
+                     labels: &BTreeMap<String, String>) -> Result<(), Error> {
+    let mut del = tx.prepare_cached("delete from camera_label where camera_id = :camera_id")?;
+    del.execute_named(&[(":camera_id", &camera_id)])?;
+    let mut ins = tx.prepare_cached(r#"
+        insert into camera_label (camera_id,  key,  value)
+                          values (:camera_id, :key, :value)
+    "#)?;
+    for (k, v) in labels {
+        if k.is_empty() {
+            bail!("label keys must be non-empty");
+        }
+        ins.execute_named(&[(":camera_id", &camera_id), (":key", k), (":value", v)])?;
+    }
+    Ok(())
+}
+
 /// Initializes a database.
 /// Note this doesn't set journal options, so that it can be used on in-memory databases for
 /// test code.
@@ -1795,12 +4608,23 @@ impl<C: Clocks + Clone> Database<C> {
                 open,
                 open_monotonic,
                 sample_file_dirs_by_id: BTreeMap::new(),
+                tenants_by_id: BTreeMap::new(),
+                holds_by_id: BTreeMap::new(),
+                holds_blocking_retention: FnvHashSet::default(),
+                export_presets_by_id: BTreeMap::new(),
                 cameras_by_id: BTreeMap::new(),
                 cameras_by_uuid: BTreeMap::new(),
                 streams_by_id: BTreeMap::new(),
                 video_sample_entries_by_id: BTreeMap::new(),
                 video_index_cache: RefCell::new(LruCache::with_hasher(1024, Default::default())),
+                streams_generation: 0,
+                master_key: None,
                 on_flush: Vec::new(),
+                watchers: Vec::new(),
+                event_merge: EventMerge::default(),
+                retention_grace: recording::Duration(0),
+                startup_report: StartupReport::default(),
+                last_events: FnvHashMap::default(),
             })),
             clocks,
         };
@@ -1808,13 +4632,17 @@ impl<C: Clocks + Clone> Database<C> {
             let l = &mut *db.lock();
             l.init_video_sample_entries()?;
             l.init_sample_file_dirs()?;
+            l.init_tenants()?;
             l.init_cameras()?;
+            l.init_holds()?;
+            l.init_export_presets()?;
             l.init_streams()?;
             for (&stream_id, ref mut stream) in &mut l.streams_by_id {
                 // TODO: we could use one thread per stream if we had multiple db conns.
                 let camera = l.cameras_by_id.get(&stream.camera_id).unwrap();
                 init_recordings(&mut l.conn, stream_id, camera, stream)?;
             }
+            l.init_startup_report(recording::Time::new(db.clocks.realtime()))?;
         }
         Ok(db)
     }
@@ -1861,7 +4689,7 @@ impl<'db, C: Clocks + Clone> DatabaseGuard<'db, C> {
     ///
     /// On success, for each affected sample file directory with a flush watcher set, sends a
     /// `Flush` event.
-    pub(crate) fn flush(&mut self, reason: &str) -> Result<(), Error> {
+    pub fn flush(&mut self, reason: &str) -> Result<(), Error> {
         self.db.flush(self.clocks, reason)
     }
 }
@@ -1955,6 +4783,8 @@ mod tests {
                 assert_eq!(r.video_samples, row.video_samples);
                 assert_eq!(r.video_sync_samples, row.video_sync_samples);
                 assert_eq!(r.sample_file_bytes, row.sample_file_bytes);
+                assert_eq!(r.min_frame_interval_90k, row.min_frame_interval_90k);
+                assert_eq!(r.max_frame_interval_90k, row.max_frame_interval_90k);
                 let vse = db.video_sample_entries_by_id().get(&row.video_sample_entry_id).unwrap();
                 assert_eq!(vse.rfc6381_codec, "avc1.4d0029");
                 Ok(())
@@ -1977,6 +4807,259 @@ mod tests {
         // TODO: with_recording_playback.
     }
 
+    #[test]
+    fn test_aggregation_split() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        let vse_id = l.insert_video_sample_entry(
+            1920, 1080, include_bytes!("testdata/avc1").to_vec(),
+            "avc1.4d0029".to_owned()).unwrap();
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        for i in 0 .. 5 {
+            l.add_recording(testutil::TEST_STREAM_ID, RecordingToInsert {
+                run_offset: i,
+                sample_file_bytes: 100,
+                start: start + recording::Duration(i as i64 * TIME_UNITS_PER_SEC),
+                duration_90k: TIME_UNITS_PER_SEC as i32,
+                video_samples: 1,
+                video_sync_samples: 1,
+                video_sample_entry_id: vse_id,
+                ..Default::default()
+            }).unwrap();
+        }
+        let all_time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+        let list = |split| {
+            let mut ids = Vec::new();
+            l.list_aggregated_recordings(testutil::TEST_STREAM_ID, all_time.clone(), split,
+                                         &mut |r| { ids.push(r.ids.clone()); Ok(()) }).unwrap();
+            ids.sort_by_key(|r| r.start);
+            ids
+        };
+        assert_eq!(list(AggregationSplit::default()), vec![1 .. 6]);
+        assert_eq!(list(AggregationSplit { bytes: 250, ..Default::default() }),
+                   vec![1 .. 3, 3 .. 5, 5 .. 6]);
+        assert_eq!(list(AggregationSplit { recordings: 3, ..Default::default() }),
+                   vec![1 .. 4, 4 .. 6]);
+        assert_eq!(list(AggregationSplit {
+            duration: recording::Duration(3 * TIME_UNITS_PER_SEC),
+            bytes: 150,
+            ..Default::default()
+        }), vec![1 .. 2, 2 .. 3, 3 .. 4, 4 .. 5, 5 .. 6]);
+    }
+
+    #[test]
+    fn test_archive_recordings() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        let vse_id = l.insert_video_sample_entry(
+            1920, 1080, include_bytes!("testdata/avc1").to_vec(),
+            "avc1.4d0029".to_owned()).unwrap();
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        for i in 0 .. 3 {
+            let (id, _) = l.add_recording(testutil::TEST_STREAM_ID, RecordingToInsert {
+                run_offset: i,
+                sample_file_bytes: 100,
+                start: start + recording::Duration(i as i64 * TIME_UNITS_PER_SEC),
+                duration_90k: TIME_UNITS_PER_SEC as i32,
+                video_samples: 1,
+                video_sync_samples: 1,
+                video_sample_entry_id: vse_id,
+                video_index: [0u8; 100].to_vec(),
+                ..Default::default()
+            }).unwrap();
+            l.mark_synced(id).unwrap();
+        }
+        l.flush("add test").unwrap();
+        assert_eq!(l.committed_recording_ids(testutil::TEST_STREAM_ID).unwrap(), 1 .. 4);
+
+        // A hold covering the middle recording prevents archiving any range that includes it.
+        let hold_start = start + recording::Duration(TIME_UNITS_PER_SEC + 1);
+        let hold_id = l.add_hold(testutil::TEST_CAMERA_ID,
+                                 hold_start .. hold_start + recording::Duration(1),
+                                 "claim 123".to_owned(), 42, None).unwrap();
+        l.archive_recordings(testutil::TEST_STREAM_ID, 1 .. 4, "s3://bucket/x").unwrap_err();
+        l.archive_recordings(testutil::TEST_STREAM_ID, 2 .. 3, "s3://bucket/x").unwrap_err();
+        assert!(l.recordings_held(testutil::TEST_STREAM_ID, 2 .. 3).unwrap());
+        assert!(!l.recordings_held(testutil::TEST_STREAM_ID, 3 .. 4).unwrap());
+        assert_eq!(l.get_archive_location(CompositeId::new(testutil::TEST_STREAM_ID, 1)).unwrap(),
+                   None);
+        assert_eq!(l.streams_by_id()[&testutil::TEST_STREAM_ID].sample_file_bytes, 300);
+        l.release_hold(hold_id).unwrap();
+
+        // Archive the middle recording.
+        let archived = CompositeId::new(testutil::TEST_STREAM_ID, 2);
+        l.archive_recordings(testutil::TEST_STREAM_ID, 2 .. 10, "s3://bucket/x").unwrap_err();
+        l.archive_recordings(testutil::TEST_STREAM_ID, 2 .. 3, "").unwrap_err();
+        assert_eq!(l.archive_recordings(testutil::TEST_STREAM_ID, 2 .. 3, "s3://bucket/x").unwrap(),
+                   vec![archived]);
+        assert!(l.archive_recordings(testutil::TEST_STREAM_ID, 2 .. 3, "elsewhere").unwrap()
+                 .is_empty());
+        assert_eq!(l.streams_by_id()[&testutil::TEST_STREAM_ID].sample_file_bytes, 200);
+        assert_eq!(l.get_archive_location(archived).unwrap().as_ref().map(String::as_str),
+                   Some("s3://bucket/x"));
+        assert_eq!(l.get_archive_location(CompositeId::new(testutil::TEST_STREAM_ID, 1)).unwrap(),
+                   None);
+
+        // It's listed separately from its unarchived neighbors.
+        let all_time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+        let mut rows = Vec::new();
+        l.list_aggregated_recordings(testutil::TEST_STREAM_ID, all_time.clone(),
+                                     AggregationSplit::default(),
+                                     &mut |r| { rows.push((r.ids.clone(), r.archived)); Ok(()) })
+         .unwrap();
+        rows.sort_by_key(|r| (r.0).start);
+        assert_eq!(rows, vec![(1 .. 2, false), (2 .. 3, true), (3 .. 4, false)]);
+
+        // Retention passes over it.
+        let mut n = 0;
+        l.delete_oldest_recordings(testutil::TEST_STREAM_ID, &mut |_| { n += 1; true }).unwrap();
+        assert_eq!(n, 2);
+        l.flush("delete test").unwrap();
+        rows.clear();
+        l.list_aggregated_recordings(testutil::TEST_STREAM_ID, all_time,
+                                     AggregationSplit::default(),
+                                     &mut |r| { rows.push((r.ids.clone(), r.archived)); Ok(()) })
+         .unwrap();
+        assert_eq!(rows, vec![(2 .. 3, true)]);
+        assert_eq!(l.streams_by_id()[&testutil::TEST_STREAM_ID].sample_file_bytes, 0);
+        assert_eq!(l.committed_recording_ids(testutil::TEST_STREAM_ID).unwrap(), 2 .. 4);
+    }
+
+    #[test]
+    fn test_teardown_camera() {
+        testutil::init();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        let vse_id = l.insert_video_sample_entry(
+            1920, 1080, include_bytes!("testdata/avc1").to_vec(),
+            "avc1.4d0029".to_owned()).unwrap();
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let add = |l: &mut LockedDatabase, i: i32| {
+            l.add_recording(testutil::TEST_STREAM_ID, RecordingToInsert {
+                run_offset: i,
+                sample_file_bytes: 100,
+                start: start + recording::Duration(i as i64 * TIME_UNITS_PER_SEC),
+                duration_90k: TIME_UNITS_PER_SEC as i32,
+                video_samples: 1,
+                video_sync_samples: 1,
+                video_sample_entry_id: vse_id,
+                video_index: [0u8; 100].to_vec(),
+                ..Default::default()
+            }).unwrap().0
+        };
+        for i in 0 .. 3 {
+            let id = add(&mut l, i);
+            l.mark_synced(id).unwrap();
+        }
+        l.flush("add test").unwrap();
+        l.archive_recordings(testutil::TEST_STREAM_ID, 2 .. 3, "s3://bucket/x").unwrap();
+        l.add_event(&EventToInsert {
+            camera_id: testutil::TEST_CAMERA_ID,
+            type_: "motion".to_owned(),
+            time: start .. start + recording::Duration(TIME_UNITS_PER_SEC),
+            description: None,
+            score: None,
+        }).unwrap();
+        let streams = l.streams_by_id().values()
+                       .filter(|s| s.camera_id == testutil::TEST_CAMERA_ID)
+                       .count();
+
+        // A recording in progress or a hold prevents teardown.
+        let id = add(&mut l, 3);
+        l.teardown_camera(testutil::TEST_CAMERA_ID).unwrap_err();
+        assert!(!l.streams_by_id()[&testutil::TEST_STREAM_ID].is_settled());
+        l.mark_synced(id).unwrap();
+        assert!(l.streams_by_id()[&testutil::TEST_STREAM_ID].is_synced());
+        l.flush("add test").unwrap();
+        assert!(l.streams_by_id()[&testutil::TEST_STREAM_ID].is_settled());
+        let hold_id = l.add_hold(testutil::TEST_CAMERA_ID,
+                                 start .. start + recording::Duration(1), "claim".to_owned(), 42,
+                                 None).unwrap();
+        l.teardown_camera(testutil::TEST_CAMERA_ID).unwrap_err();
+        l.release_hold(hold_id).unwrap();
+
+        let removed = l.teardown_camera(testutil::TEST_CAMERA_ID).unwrap();
+        assert_eq!(removed.streams, streams);
+        assert_eq!(removed.recordings, 4);
+        assert_eq!(removed.events, 1);
+        assert!(!l.streams_by_id().contains_key(&testutil::TEST_STREAM_ID));
+        let garbage: Vec<_> = removed.sample_files.iter().map(|&(_, id)| id.recording()).collect();
+        assert_eq!(garbage, vec![1, 3, 4]);
+        for &(d, id) in &removed.sample_files {
+            assert!(l.sample_file_dirs_by_id()[&d].needs_unlink(id));
+        }
+        assert!(l.cameras_by_id().is_empty());
+        let n: i64 = l.conn.query_row("select count(*) from event", &[] as &[&ToSql],
+                                      |r| r.get(0)).unwrap();
+        assert_eq!(n, 0);
+        let n: i64 = l.conn.query_row("select count(*) from recording", &[] as &[&ToSql],
+                                      |r| r.get(0)).unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn test_move_stream() {
+        testutil::init();
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().to_str().unwrap().to_owned();
+        let tdb = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        let old_dir_id = l.streams_by_id()[&testutil::TEST_STREAM_ID].sample_file_dir_id.unwrap();
+        let new_dir_id = l.add_sample_file_dir(path, false).unwrap();
+        let vse_id = l.insert_video_sample_entry(
+            1920, 1080, include_bytes!("testdata/avc1").to_vec(),
+            "avc1.4d0029".to_owned()).unwrap();
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let add = |l: &mut LockedDatabase, i: i32| {
+            l.add_recording(testutil::TEST_STREAM_ID, RecordingToInsert {
+                run_offset: i,
+                sample_file_bytes: 100,
+                start: start + recording::Duration(i as i64 * TIME_UNITS_PER_SEC),
+                duration_90k: TIME_UNITS_PER_SEC as i32,
+                video_samples: 1,
+                video_sync_samples: 1,
+                video_sample_entry_id: vse_id,
+                video_index: [0u8; 100].to_vec(),
+                sample_file_sha1: [1u8; 20],
+                ..Default::default()
+            }).unwrap().0
+        };
+        for i in 0 .. 3 {
+            let id = add(&mut l, i);
+            l.mark_synced(id).unwrap();
+        }
+        l.flush("add test").unwrap();
+        l.archive_recordings(testutil::TEST_STREAM_ID, 2 .. 3, "s3://bucket/x").unwrap();
+        assert_eq!(l.get_sample_file_sha1(CompositeId::new(testutil::TEST_STREAM_ID, 1)).unwrap(),
+                   Some([1u8; 20]));
+
+        // The stream must be settled, and all its recordings must have been copied.
+        let id = add(&mut l, 3);
+        l.move_stream(testutil::TEST_STREAM_ID, new_dir_id, 0 .. 5).unwrap_err();
+        l.mark_synced(id).unwrap();
+        l.flush("add test").unwrap();
+        l.move_stream(testutil::TEST_STREAM_ID, new_dir_id, 0 .. 3).unwrap_err();
+        l.move_stream(testutil::TEST_STREAM_ID, old_dir_id, 0 .. 5).unwrap_err();
+        l.move_stream(testutil::TEST_STREAM_ID, new_dir_id + 1, 0 .. 5).unwrap_err();
+
+        let generation = l.streams_generation();
+        let moved = l.move_stream(testutil::TEST_STREAM_ID, new_dir_id, 0 .. 5).unwrap();
+        let moved: Vec<i32> = moved.iter().map(|id| id.recording()).collect();
+        assert_eq!(moved, vec![1, 3, 4]);  // not the archived recording.
+        assert_eq!(l.streams_by_id()[&testutil::TEST_STREAM_ID].sample_file_dir_id,
+                   Some(new_dir_id));
+        assert_ne!(l.streams_generation(), generation);
+        let old = &l.sample_file_dirs_by_id()[&old_dir_id];
+        assert!(old.needs_unlink(CompositeId::new(testutil::TEST_STREAM_ID, 1)));
+        assert!(!old.needs_unlink(CompositeId::new(testutil::TEST_STREAM_ID, 2)));
+        let dir_id: i32 = l.conn.query_row("select sample_file_dir_id from stream where id = ?",
+                                           &[&testutil::TEST_STREAM_ID as &ToSql],
+                                           |r| r.get(0)).unwrap();
+        assert_eq!(dir_id, new_dir_id);
+    }
+
     #[test]
     fn test_adjust_days() {
         testutil::init();
@@ -2054,20 +5137,20 @@ mod tests {
     fn test_version_too_old() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (2, 0, '');").unwrap();
+        c.execute_batch("delete from version; insert into version values (3, 0, '');").unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(e.to_string().starts_with(
-                "Database schema version 2 is too old (expected 3)"), "got: {:?}", e);
+                "Database schema version 3 is too old (expected 4)"), "got: {:?}", e);
     }
 
     #[test]
     fn test_version_too_new() {
         testutil::init();
         let c = setup_conn();
-        c.execute_batch("delete from version; insert into version values (4, 0, '');").unwrap();
+        c.execute_batch("delete from version; insert into version values (5, 0, '');").unwrap();
         let e = Database::new(clock::RealClocks {}, c, false).err().unwrap();
         assert!(e.to_string().starts_with(
-                "Database schema version 4 is too new (expected 3)"), "got: {:?}", e);
+                "Database schema version 5 is too new (expected 4)"), "got: {:?}", e);
     }
 
     /// Basic test of running some queries on a fresh database.
@@ -2080,6 +5163,914 @@ mod tests {
         assert_eq!(0, db.cameras_by_id().values().count());
     }
 
+    #[test]
+    fn test_pragmas() {
+        testutil::init();
+        let conn = setup_conn();
+        Pragmas {
+            cache_kib: 4096,
+            mmap_bytes: 1 << 20,
+            synchronous: Synchronous::Normal,
+        }.apply(&conn).unwrap();
+        let get = |p: &str| -> i64 {
+            conn.query_row(&format!("pragma {}", p), &[] as &[&ToSql], |r| r.get(0)).unwrap()
+        };
+        assert_eq!(get("cache_size"), -4096);
+        assert_eq!(get("synchronous"), 1);  // NORMAL
+        assert_eq!(Synchronous::parse("full").unwrap(), Synchronous::Full);
+        Synchronous::parse("off").unwrap_err();
+    }
+
+    #[test]
+    fn test_events() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        let camera_id = db.add_camera(CameraChange {
+            short_name: "testcam".to_owned(),
+            description: "".to_owned(),
+            host: "test-camera".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: Default::default(),
+            labels: BTreeMap::new(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
+        }).unwrap();
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let e = EventToInsert {
+            camera_id,
+            type_: "sound_level".to_owned(),
+            time: start .. start + recording::Duration(5 * TIME_UNITS_PER_SEC),
+            description: Some("loud noise".to_owned()),
+            score: Some(-3.5),
+        };
+        let added = Arc::new(Mutex::new(Vec::new()));
+        db.watch({
+            let added = added.clone();
+            Box::new(move |_, c| if let &Change::EventAdded { id, .. } = c {
+                added.lock().push(id);
+            })
+        });
+        let id = db.add_event(&e).unwrap();
+        assert_eq!(&*added.lock(), &[id]);
+        let mut rows = Vec::new();
+        db.list_events(camera_id, recording::Time(0) .. recording::Time(i64::max_value()),
+                       &mut |r| { rows.push(r); Ok(()) }).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].id, id);
+        assert_eq!(rows[0].time, e.time);
+        assert_eq!(rows[0].description, e.description);
+        assert_eq!(rows[0].score, e.score);
+        let row = db.get_event(id).unwrap().unwrap();
+        assert_eq!(row.camera_id, camera_id);
+        assert_eq!(row.time, e.time);
+        assert!(db.get_event(id + 1).unwrap().is_none());
+        assert!(db.get_event_snapshot(id).unwrap().is_none());
+        db.add_event_snapshot(id, b"\xff\xd8\xff\xd9").unwrap();
+        assert_eq!(db.get_event_snapshot(id).unwrap().unwrap(), b"\xff\xd8\xff\xd9");
+
+        let person = Detection {
+            label: "person".to_owned(),
+            score: Some(0.9),
+            x: 0.25,
+            y: 0.5,
+            width: 0.5,
+            height: 0.5,
+        };
+        let mut outside = person.clone();
+        outside.x = 0.75;
+        db.add_event_detections(id, &[person.clone(), outside]).unwrap_err();
+        db.add_event_detections(id + 100, &[person.clone()]).unwrap_err();
+        assert!(db.list_event_detections(id).unwrap().is_empty());
+        db.add_event_detections(id, &[person.clone()]).unwrap();
+        assert_eq!(db.list_event_detections(id).unwrap(), vec![person]);
+
+        // An event shortly after is merged into the first, with the higher score.
+        let sec = |s: i64| recording::Duration(s * TIME_UNITS_PER_SEC);
+        let e2 = EventToInsert {
+            time: e.time.end + sec(10) .. e.time.end + sec(15),
+            score: Some(-1.),
+            ..e.clone()
+        };
+        assert_eq!(db.add_event(&e2).unwrap(), id);
+        let row = db.get_event(id).unwrap().unwrap();
+        assert_eq!(row.time, e.time.start .. e2.time.end);
+        assert_eq!(row.score, Some(-1.));
+
+        // One with a different description, long after, or making too long an event is not.
+        let e3 = EventToInsert { description: None, ..e2.clone() };
+        let id3 = db.add_event(&e3).unwrap();
+        assert!(id3 != id);
+        let e4 = EventToInsert {
+            time: e2.time.end + sec(60) .. e2.time.end + sec(61),
+            ..e.clone()
+        };
+        let id4 = db.add_event(&e4).unwrap();
+        assert!(id4 != id && id4 != id3);
+        let e5 = EventToInsert { time: e4.time.end .. e4.time.end + sec(600), ..e.clone() };
+        assert!(db.add_event(&e5).unwrap() != id4);
+        assert_eq!(&*added.lock(), &[id, id3, id4, id4 + 1]);
+
+        // A range which ends where the event starts shouldn't match.
+        rows.clear();
+        db.list_events(camera_id, recording::Time(0) .. start,
+                       &mut |r| { rows.push(r); Ok(()) }).unwrap();
+        assert!(rows.is_empty());
+    }
+
+    #[test]
+    fn test_tenants() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let (tenant_id, camera_id);
+        {
+            let mut l = db.lock();
+            tenant_id = l.add_tenant("apt1".to_owned(), Some(1 << 30), false).unwrap();
+            let mut c = CameraChange {
+                short_name: "testcam".to_owned(),
+                description: "".to_owned(),
+                host: "test-camera".to_owned(),
+                username: "".to_owned(),
+                password: "".to_owned(),
+                streams: Default::default(),
+                labels: BTreeMap::new(),
+                tenant_id: Some(tenant_id + 1),
+                event_source: None,
+                snapshot_schedule: None,
+            };
+            l.add_camera(c.clone()).unwrap_err();  // no such tenant.
+            c.tenant_id = Some(tenant_id);
+            camera_id = l.add_camera(c).unwrap();
+            l.delete_tenant(tenant_id).unwrap_err();  // has cameras.
+            l.conn.execute_batch(
+                "insert into user (id, username, flags) values (1, 'alice', 0)").unwrap();
+            l.set_user_tenant("bob", Some(tenant_id)).unwrap_err();  // no such user.
+            l.set_user_tenant("alice", Some(tenant_id + 1)).unwrap_err();  // no such tenant.
+            l.set_user_tenant("alice", Some(tenant_id)).unwrap();
+            l.update_tenant(tenant_id, "apt2".to_owned(), None, true).unwrap();
+        }
+
+        // Closing and reopening the database should present the same contents.
+        let conn = db.close();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut l = db.lock();
+        {
+            let t = l.tenants_by_id().get(&tenant_id).unwrap();
+            assert_eq!(t.short_name, "apt2");
+            assert_eq!(t.retain_bytes, None);
+            assert!(t.watermark_exports);
+        }
+        assert_eq!(l.cameras_by_id().get(&camera_id).unwrap().tenant_id, Some(tenant_id));
+        assert_eq!(l.get_user("alice").unwrap(), Some(User {
+            id: 1,
+            username: "alice".to_owned(),
+            tenant_id: Some(tenant_id),
+        }));
+        assert_eq!(l.get_user("bob").unwrap(), None);
+        assert_eq!(l.list_tenant_users(tenant_id).unwrap(), vec!["alice".to_owned()]);
+        l.delete_camera(camera_id).unwrap();
+        l.delete_tenant(tenant_id).unwrap_err();  // has users.
+        l.set_user_tenant("alice", None).unwrap();
+        l.delete_tenant(tenant_id).unwrap();
+        assert!(l.tenants_by_id().is_empty());
+    }
+
+    #[test]
+    fn test_export_presets() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let id;
+        {
+            let mut l = db.lock();
+            let mut c = ExportPresetChange {
+                name: "external".to_owned(),
+                max_height: Some(721),
+                watermark: true,
+                timestamps: true,
+                container: ExportContainer::Matroska,
+            };
+            l.add_export_preset(c.clone()).unwrap_err();  // odd height.
+            c.max_height = Some(720);
+            id = l.add_export_preset(c.clone()).unwrap();
+            l.add_export_preset(c.clone()).unwrap_err();  // duplicate name.
+            c.container = ExportContainer::Mp4;
+            l.update_export_preset(id, c).unwrap();
+        }
+
+        // Closing and reopening the database should present the same contents.
+        let conn = db.close();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut l = db.lock();
+        {
+            let p = l.get_export_preset("external").unwrap();
+            assert_eq!(p.id, id);
+            assert_eq!(p.max_height, Some(720));
+            assert!(p.watermark);
+            assert_eq!(p.container, ExportContainer::Mp4);
+        }
+        l.delete_export_preset(id).unwrap();
+        assert!(l.export_presets_by_id().is_empty());
+    }
+
+    #[test]
+    fn test_push_subscriptions() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        let mut s = PushSubscription {
+            endpoint: "https://push.example.com/a".to_owned(),
+            p256dh: "key1".to_owned(),
+            auth: "auth1".to_owned(),
+        };
+        db.add_push_subscription(&s).unwrap();
+        s.p256dh = "key2".to_owned();
+        db.add_push_subscription(&s).unwrap();  // replaces.
+        assert_eq!(db.list_push_subscriptions().unwrap(), vec![s.clone()]);
+        assert!(db.delete_push_subscription(&s.endpoint).unwrap());
+        assert!(!db.delete_push_subscription(&s.endpoint).unwrap());
+        assert!(db.list_push_subscriptions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_jobs() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        let mut j = db.add_job("export".to_owned(), "{}".to_owned(), 42).unwrap();
+        assert_eq!(db.list_jobs().unwrap(), vec![j.clone()]);
+        j.state = JobState::Done;
+        j.finished_sec = Some(43);
+        j.result = Some("{\"bytes\":1}".to_owned());
+        db.update_job(&j).unwrap();
+        assert_eq!(db.list_jobs().unwrap(), vec![j.clone()]);
+        assert!(db.delete_job(j.id).unwrap());
+        assert!(!db.delete_job(j.id).unwrap());
+        assert!(db.update_job(&j).is_err());
+        assert!(db.list_jobs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_incidents() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        let camera_id = db.add_camera(CameraChange {
+            short_name: "testcam".to_owned(),
+            description: "".to_owned(),
+            host: "test-camera".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: Default::default(),
+            labels: BTreeMap::new(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
+        }).unwrap();
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let time = start .. start + recording::Duration(5 * TIME_UNITS_PER_SEC);
+        let event_id = db.add_event(&EventToInsert {
+            camera_id,
+            type_: "sound_level".to_owned(),
+            time: time.clone(),
+            description: None,
+            score: None,
+        }).unwrap();
+        db.add_incident("".to_owned(), "".to_owned(), 42).unwrap_err();
+        let i = db.add_incident("break-in".to_owned(), "".to_owned(), 42).unwrap();
+        let export = Uuid::new_v4();
+        let items = vec![
+            IncidentItem::Event(event_id),
+            IncidentItem::Range { camera_id, time },
+            IncidentItem::Export(export),
+        ];
+        for item in &items {
+            db.add_incident_item(i.id, item).unwrap();
+        }
+        db.add_incident_item(i.id, &IncidentItem::Event(event_id + 1)).unwrap_err();
+        let incidents = db.list_incidents().unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].uuid, i.uuid);
+        assert_eq!(incidents[0].title, "break-in");
+        assert_eq!(db.list_incident_items(i.id).unwrap(), items);
+        assert!(db.delete_incident(i.id).unwrap());
+        assert!(db.list_incidents().unwrap().is_empty());
+        assert!(!db.delete_incident(i.id).unwrap());
+    }
+
+    #[test]
+    fn test_user_preferences() {
+        testutil::init();
+        let conn = setup_conn();
+        conn.execute_batch("insert into user (id, username, flags) values (1, 'slamb', 0)")
+            .unwrap();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        assert_eq!(db.list_user_preferences(2).unwrap(), None);
+        db.set_user_preference(2, "theme", "\"dark\"", None).unwrap_err();
+        assert_eq!(db.list_user_preferences(1).unwrap(), Some(vec![]));
+
+        // Unconditional and conditional sets.
+        assert_eq!(db.set_user_preference(1, "theme", "\"dark\"", None).unwrap(), Some(1));
+        assert_eq!(db.set_user_preference(1, "speed", "2", Some(0)).unwrap(), Some(1));
+        assert_eq!(db.set_user_preference(1, "theme", "\"light\"", Some(1)).unwrap(), Some(2));
+
+        // A stale version is rejected without changing anything.
+        assert_eq!(db.set_user_preference(1, "theme", "\"blue\"", Some(1)).unwrap(), None);
+        assert_eq!(db.set_user_preference(1, "layout", "{}", Some(1)).unwrap(), None);
+        assert_eq!(db.list_user_preferences(1).unwrap(), Some(vec![
+            UserPreference { key: "speed".to_owned(), value: "2".to_owned(), version: 1 },
+            UserPreference { key: "theme".to_owned(), value: "\"light\"".to_owned(), version: 2 },
+        ]));
+    }
+
+    #[test]
+    fn test_bandwidth_usage() {
+        testutil::init();
+        let db = Database::new(clock::RealClocks {}, setup_conn(), true).unwrap();
+        let mut db = db.lock();
+        let cam = Uuid::parse_str("fd20f7a2-9d69-4cb3-94ed-d51a20c3edfe").unwrap();
+        let u = |start_sec, camera_uuid, requests, bytes| BandwidthUsage {
+            start_sec,
+            user_name: "slamb".to_owned(),
+            camera_uuid,
+            requests,
+            bytes,
+        };
+        db.add_bandwidth_usage(&[u(3600, Some(cam), 1, 100), u(3600, None, 2, 10)]).unwrap();
+        db.add_bandwidth_usage(&[u(3600, Some(cam), 1, 50), u(3600, None, 1, 1),
+                                 u(7200, None, 1, 5)]).unwrap();
+        db.add_bandwidth_usage(&[u(3601, None, 1, 1)]).unwrap_err();
+        let mut l = db.list_bandwidth_usage(0 .. 7200).unwrap();
+        l.sort_by_key(|u| u.camera_uuid);
+        assert_eq!(l, vec![u(3600, None, 3, 11), u(3600, Some(cam), 2, 150)]);
+        assert_eq!(db.delete_bandwidth_usage_before(7200).unwrap(), 2);
+        assert_eq!(db.list_bandwidth_usage(0 .. 10800).unwrap(), vec![u(7200, None, 1, 5)]);
+    }
+
+    #[test]
+    fn test_layouts() {
+        testutil::init();
+        let conn = setup_conn();
+        conn.execute_batch(r#"
+            insert into user (id, username, flags) values (1, 'alice', 0);
+            insert into user (id, username, flags) values (2, 'bob', 0);
+        "#).unwrap();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        let camera_uuid = Uuid::new_v4();
+        let cell = |position| LayoutCell {
+            position,
+            camera_uuid,
+            stream_type: StreamType::SUB,
+        };
+        let layout = |name: &str, owner_user_id, cells| Layout {
+            id: 0,
+            uuid: Uuid::nil(),
+            name: name.to_owned(),
+            owner_user_id,
+            shared: false,
+            rows: 2,
+            columns: 2,
+            created_sec: 42,
+            cells,
+        };
+        db.add_layout(layout("", 1, vec![])).unwrap_err();
+        db.add_layout(layout("wall", 3, vec![])).unwrap_err();
+        db.add_layout(layout("wall", 1, vec![cell(4)])).unwrap_err();
+        db.add_layout(layout("wall", 1, vec![cell(1), cell(1)])).unwrap_err();
+        let a = db.add_layout(layout("wall", 1, vec![cell(3), cell(0)])).unwrap();
+        assert_eq!(a.cells, vec![cell(0), cell(3)]);
+        let b = db.add_layout(layout("desk", 2, vec![])).unwrap();
+        assert_eq!(db.list_layouts(Some(1)).unwrap(), vec![a.clone()]);
+        assert!(db.list_layouts(None).unwrap().is_empty());
+
+        // Sharing makes a layout visible to everyone.
+        assert!(db.set_layout_shared(a.id, true).unwrap());
+        let mut shared = a.clone();
+        shared.shared = true;
+        assert_eq!(db.list_layouts(Some(2)).unwrap(), vec![shared.clone(), b.clone()]);
+        assert_eq!(db.list_layouts(None).unwrap(), vec![shared.clone()]);
+        assert_eq!(db.get_layout(b.uuid).unwrap(), Some(b.clone()));
+
+        assert!(db.delete_layout(a.id).unwrap());
+        assert!(!db.delete_layout(a.id).unwrap());
+        assert!(!db.set_layout_shared(a.id, false).unwrap());
+        assert_eq!(db.get_layout(a.uuid).unwrap(), None);
+    }
+
+    #[test]
+    fn test_notes() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        let camera_id = db.add_camera(CameraChange {
+            short_name: "testcam".to_owned(),
+            description: "".to_owned(),
+            host: "test-camera".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: [
+                StreamChange {
+                    sample_file_dir_id: None,
+                    mirror_sample_file_dir_id: None,
+                    rtsp_path: "/main".to_owned(),
+                    source: StreamSource::Rtsp,
+                    record: false,
+                    flush_if_sec: 1,
+                    recording_duration_sec: 60,
+                    sei_motion_uuid: None,
+                    snapshot_url: None,
+                    metadata_events: false,
+                    thumbnail_interval_sec: 0,
+                    encoder: Default::default(),
+                    input_options: None,
+                },
+                Default::default(),
+            ],
+            labels: BTreeMap::new(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
+        }).unwrap();
+        let stream_id = db.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let all = recording::Time(0) .. recording::Time(i64::max_value());
+        let one_sec = recording::Duration(TIME_UNITS_PER_SEC);
+        db.add_note(stream_id, start .. start, "", 0).unwrap_err();
+        let id = db.add_note(stream_id, start .. start + one_sec, "Package stolen here", 42)
+                   .unwrap();
+        db.add_note(stream_id, start + one_sec .. start + one_sec, "mail carrier", 43).unwrap();
+
+        let mut notes = Vec::new();
+        db.list_notes(stream_id, all.clone(), None, &mut |n| { notes.push(n); Ok(()) }).unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].id, id);
+        assert_eq!(notes[0].time, start .. start + one_sec);
+        assert_eq!(notes[0].text, "Package stolen here");
+        assert_eq!(notes[0].created_sec, 42);
+
+        // Searches are case-insensitive.
+        notes.clear();
+        db.list_notes(stream_id, all.clone(), Some("package"),
+                      &mut |n| { notes.push(n); Ok(()) }).unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].id, id);
+
+        // A range which ends where the note starts shouldn't match.
+        notes.clear();
+        db.list_notes(stream_id, recording::Time(0) .. start, None,
+                      &mut |n| { notes.push(n); Ok(()) }).unwrap();
+        assert!(notes.is_empty());
+
+        // Deleting the camera should delete its notes.
+        db.delete_camera(camera_id).unwrap();
+    }
+
+    #[test]
+    fn test_camera_reachability() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        let camera_id = db.add_camera(CameraChange {
+            short_name: "testcam".to_owned(),
+            description: "".to_owned(),
+            host: "test-camera".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: Default::default(),
+            labels: BTreeMap::new(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
+        }).unwrap();
+        let changes = Arc::new(Mutex::new(0));
+        db.watch({
+            let changes = changes.clone();
+            Box::new(move |_, c| if let &Change::CameraReachability { .. } = c {
+                *changes.lock() += 1;
+            })
+        });
+        assert_eq!(db.cameras_by_id()[&camera_id].reachability.reachable, None);
+        assert!(db.update_camera_reachability(camera_id, None).unwrap());
+        assert!(!db.update_camera_reachability(camera_id, None).unwrap());
+        assert!(db.update_camera_reachability(camera_id, Some("timed out".to_owned())).unwrap());
+        assert_eq!(*changes.lock(), 2);
+        let r = db.cameras_by_id()[&camera_id].reachability.clone();
+        assert_eq!(r.reachable, Some(false));
+
+        // A failing stream's cause depends on the camera's reachability and the error.
+        let mut h = StreamHealth {
+            consecutive_failures: 1,
+            last_error: Some("Server returned 401 Unauthorized (authorization failed)".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(h.cause(&r), Some("unreachable"));
+        assert_eq!(h.cause(&CameraReachability::default()), Some("authentication"));
+        h.last_error = Some("Connection timed out".to_owned());
+        assert_eq!(h.cause(&CameraReachability::default()), Some("stream"));
+        h.consecutive_failures = 0;
+        assert_eq!(h.cause(&r), None);
+
+        // A failed clock check keeps the last measured skew.
+        db.update_camera_clock(camera_id, Ok((-7, false))).unwrap();
+        db.update_camera_clock(camera_id, Err("timed out".to_owned())).unwrap();
+        let clock = db.cameras_by_id()[&camera_id].clock.clone();
+        assert_eq!(clock.skew_sec, Some(-7));
+        assert_eq!(clock.ntp, Some(false));
+        assert_eq!(clock.last_error.as_ref().map(String::as_str), Some("timed out"));
+    }
+
+    #[test]
+    fn test_scheduled_snapshots() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        let mut change = CameraChange {
+            short_name: "garden".to_owned(),
+            description: "".to_owned(),
+            host: "test-camera".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: Default::default(),
+            labels: BTreeMap::new(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: Some(SnapshotSchedule { interval_sec: 0, retain_days: 30 }),
+        };
+        db.add_camera(change.clone()).unwrap_err();
+        change.snapshot_schedule = Some(SnapshotSchedule { interval_sec: 300, retain_days: 30 });
+        let camera_id = db.add_camera(change.clone()).unwrap();
+        assert_eq!(db.cameras_by_id()[&camera_id].snapshot_schedule, change.snapshot_schedule);
+        change.snapshot_schedule = None;
+        db.update_camera(camera_id, change).unwrap();
+        assert_eq!(db.cameras_by_id()[&camera_id].snapshot_schedule, None);
+
+        let t = recording::Time(1_500_000_000 * TIME_UNITS_PER_SEC);
+        let five_min = recording::Duration(300 * TIME_UNITS_PER_SEC);
+        db.add_scheduled_snapshot(camera_id, t, b"").unwrap_err();
+        db.add_scheduled_snapshot(camera_id + 1, t, b"\xff\xd8\xff\xd9").unwrap_err();
+        db.add_scheduled_snapshot(camera_id, t, b"\xff\xd8\xff\xd9").unwrap();
+        db.add_scheduled_snapshot(camera_id, t + five_min, b"\xff\xd8\x00\xff\xd9").unwrap();
+        let all = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+        assert_eq!(db.list_scheduled_snapshots(camera_id, all.clone()).unwrap(), vec![
+            ListThumbnailsRow { time: t, bytes: 4 },
+            ListThumbnailsRow { time: t + five_min, bytes: 5 },
+        ]);
+        assert_eq!(db.get_scheduled_snapshot(camera_id, t + five_min - recording::Duration(1))
+                     .unwrap().unwrap().0, t);
+        assert!(db.get_scheduled_snapshot(camera_id, t - recording::Duration(1)).unwrap()
+                  .is_none());
+        assert_eq!(db.delete_scheduled_snapshots(camera_id, t + five_min).unwrap(), 1);
+        assert_eq!(db.list_scheduled_snapshots(camera_id, all).unwrap().len(), 1);
+        db.delete_camera(camera_id).unwrap();
+    }
+
+    #[test]
+    fn test_stream_paused() {
+        testutil::init();
+        let db = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = db.db.lock();
+        let id = testutil::TEST_STREAM_ID;
+        assert!(!l.streams_by_id()[&id].paused);
+        assert!(l.set_stream_paused(id, true).unwrap());
+        assert!(!l.set_stream_paused(id, true).unwrap());
+        assert!(l.streams_by_id()[&id].paused);
+        let paused: bool = l.conn.query_row("select paused from stream where id = ?",
+                                            &[&id as &ToSql], |r| r.get(0)).unwrap();
+        assert!(paused);
+        l.set_stream_paused(id + 100, false).unwrap_err();
+    }
+
+    #[test]
+    fn test_camera_credentials() {
+        testutil::init();
+        let db = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = db.db.lock();
+        let id = testutil::TEST_CAMERA_ID;
+        l.rollback_camera_credentials(id).unwrap_err();
+        l.rotate_camera_credentials(id, "foo".to_owned(), "p@ss:w/rd".to_owned()).unwrap();
+        {
+            let c = &l.cameras_by_id()[&id];
+            assert_eq!(c.password, "p@ss:w/rd");
+            assert_eq!(c.previous_credentials, Some(("foo".to_owned(), "bar".to_owned())));
+        }
+        l.rollback_camera_credentials(id).unwrap();
+        let (password, previous): (String, String) = l.conn.query_row(
+            "select password, previous_password from camera where id = ?", &[&id as &ToSql],
+            |r| (r.get(0), r.get(1))).unwrap();
+        assert_eq!(password, "bar");
+        assert_eq!(previous, "p@ss:w/rd");
+        l.rotate_camera_credentials(id + 100, "".to_owned(), "".to_owned()).unwrap_err();
+    }
+
+    #[test]
+    fn test_note_over_limit() {
+        testutil::init();
+        let db = testutil::TestDb::new(clock::RealClocks {});
+        let mut l = db.db.lock();
+        let id = testutil::TEST_STREAM_ID;
+        let exceeded = Arc::new(Mutex::new(0));
+        l.watch({
+            let exceeded = exceeded.clone();
+            Box::new(move |_, c| if let &Change::RetentionExceeded { .. } = c {
+                *exceeded.lock() += 1;
+            })
+        });
+        let t = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let later = t + recording::Duration(TIME_UNITS_PER_SEC);
+        assert_eq!(l.note_over_limit(id, false, t).unwrap(), None);
+        assert_eq!(l.note_over_limit(id, true, t).unwrap(), Some(t));
+        assert_eq!(l.note_over_limit(id, true, later).unwrap(), Some(t));
+        assert_eq!(*exceeded.lock(), 1);
+        assert_eq!(l.note_over_limit(id, false, later).unwrap(), None);
+        assert_eq!(l.streams_by_id()[&id].over_limit_since, None);
+        assert_eq!(l.note_over_limit(id, true, later).unwrap(), Some(later));
+        assert_eq!(*exceeded.lock(), 2);
+    }
+
+    #[test]
+    fn test_encoder_settings() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut l = db.lock();
+        let encoder = EncoderSettings {
+            gov_length: Some(15),
+            resolution: Some((1280, 720)),
+            ..Default::default()
+        };
+        let mut change = CameraChange {
+            short_name: "testcam".to_owned(),
+            description: "".to_owned(),
+            host: "test-camera".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: [
+                StreamChange {
+                    rtsp_path: "/main".to_owned(),
+                    flush_if_sec: 1,
+                    recording_duration_sec: 60,
+                    encoder: EncoderSettings { bitrate_kbps: Some(0), ..encoder },
+                    ..Default::default()
+                },
+                Default::default(),
+            ],
+            labels: BTreeMap::new(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
+        };
+        l.add_camera(change.clone()).unwrap_err();
+        change.streams[0].encoder = encoder;
+        let camera_id = l.add_camera(change).unwrap();
+        let id = l.cameras_by_id()[&camera_id].streams[0].unwrap();
+        assert_eq!(l.streams_by_id()[&id].encoder, encoder);
+        let row: (Option<i32>, Option<i32>, Option<i32>) = l.conn.query_row(
+            "select encoder_gov_length, encoder_bitrate_kbps, encoder_height from stream \
+             where id = ?", &[&id as &ToSql], |r| (r.get(0), r.get(1), r.get(2))).unwrap();
+        assert_eq!(row, (Some(15), None, Some(720)));
+    }
+
+    #[test]
+    fn test_parse_input_options() {
+        assert_eq!(parse_input_options(" analyzeduration=10000000  rtsp_flags=prefer_tcp ")
+                       .unwrap(),
+                   vec![("analyzeduration".to_owned(), "10000000".to_owned()),
+                        ("rtsp_flags".to_owned(), "prefer_tcp".to_owned())]);
+        assert_eq!(parse_input_options("").unwrap(), vec![]);
+        parse_input_options("stimeout").unwrap_err();
+        parse_input_options("-i=foo").unwrap_err();
+    }
+
+    #[test]
+    fn test_stream_source() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut l = db.lock();
+        let mut change = CameraChange {
+            short_name: "phone".to_owned(),
+            description: "".to_owned(),
+            host: "".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: [
+                StreamChange {
+                    source: StreamSource::Mjpeg("rtsp://phone/video".to_owned()),
+                    flush_if_sec: 1,
+                    recording_duration_sec: 60,
+                    ..Default::default()
+                },
+                Default::default(),
+            ],
+            labels: BTreeMap::new(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
+        };
+        l.add_camera(change.clone()).unwrap_err();
+        let source = StreamSource::Mjpeg("http://phone:8080/video".to_owned());
+        change.streams[0].source = source.clone();
+        let camera_id = l.add_camera(change).unwrap();
+        let id = l.cameras_by_id()[&camera_id].streams[0].unwrap();
+        assert_eq!(l.streams_by_id()[&id].source, source);
+        let row: (String, Option<String>) = l.conn.query_row(
+            "select source, source_url from stream where id = ?", &[&id as &ToSql],
+            |r| (r.get(0), r.get(1))).unwrap();
+        assert_eq!(StreamSource::parse(&row.0, row.1).unwrap(), source);
+        StreamSource::parse("hls", None).unwrap_err();
+        StreamSource::V4l2("video0".to_owned()).check().unwrap_err();
+        StreamSource::V4l2("/dev/video0".to_owned()).check().unwrap();
+        StreamSource::Push("http://0.0.0.0:1935/live/key".to_owned()).check().unwrap_err();
+        StreamSource::Push("rtmp://0.0.0.0:1935/live/key".to_owned()).check().unwrap();
+        StreamSource::Push("srt://0.0.0.0:9000".to_owned()).check().unwrap();
+    }
+
+    #[test]
+    fn test_thumbnails() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut db = db.lock();
+        let camera_id = db.add_camera(CameraChange {
+            short_name: "testcam".to_owned(),
+            description: "".to_owned(),
+            host: "test-camera".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: [
+                StreamChange {
+                    rtsp_path: "/main".to_owned(),
+                    flush_if_sec: 1,
+                    recording_duration_sec: 60,
+                    thumbnail_interval_sec: 10,
+                    ..Default::default()
+                },
+                Default::default(),
+            ],
+            labels: BTreeMap::new(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
+        }).unwrap();
+        let stream_id = db.cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        assert_eq!(db.streams_by_id()[&stream_id].thumbnail_interval_sec, 10);
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let ten_sec = recording::Duration(10 * TIME_UNITS_PER_SEC);
+        db.add_thumbnail(stream_id, start, b"").unwrap_err();
+        db.add_thumbnail(stream_id, start, b"\xff\xd8\xff\xd9").unwrap();
+        db.add_thumbnail(stream_id, start + ten_sec, b"\xff\xd8\x00\xff\xd9").unwrap();
+        assert_eq!(db.list_thumbnails(stream_id, start .. start + ten_sec).unwrap(),
+                   vec![ListThumbnailsRow { time: start, bytes: 4 }]);
+        assert_eq!(db.list_thumbnails(stream_id, start .. start + ten_sec + ten_sec)
+                     .unwrap().len(), 2);
+
+        // Lookups return the latest thumbnail at or before the given time.
+        assert!(db.get_thumbnail(stream_id, start - ten_sec).unwrap().is_none());
+        let (t, jpeg) = db.get_thumbnail(stream_id, start + recording::Duration(1))
+                          .unwrap().unwrap();
+        assert_eq!(t, start);
+        assert_eq!(jpeg, b"\xff\xd8\xff\xd9");
+        let (t, _) = db.get_thumbnail(stream_id, start + ten_sec + ten_sec).unwrap().unwrap();
+        assert_eq!(t, start + ten_sec);
+
+        // Deleting the camera should delete its thumbnails.
+        db.delete_camera(camera_id).unwrap();
+    }
+
+    #[test]
+    fn test_holds() {
+        testutil::init();
+        let conn = setup_conn();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().to_str().unwrap().to_owned();
+        let sample_file_dir_id = { db.lock() }.add_sample_file_dir(path, false).unwrap();
+        let camera_id = db.lock().add_camera(CameraChange {
+            short_name: "testcam".to_owned(),
+            description: "".to_owned(),
+            host: "test-camera".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: [
+                StreamChange {
+                    sample_file_dir_id: Some(sample_file_dir_id),
+                    mirror_sample_file_dir_id: None,
+                    rtsp_path: "/main".to_owned(),
+                    source: StreamSource::Rtsp,
+                    record: true,
+                    flush_if_sec: 1,
+                    recording_duration_sec: 60,
+                    sei_motion_uuid: None,
+                    snapshot_url: None,
+                    metadata_events: false,
+                    thumbnail_interval_sec: 0,
+                    encoder: Default::default(),
+                    input_options: None,
+                },
+                Default::default(),
+            ],
+            labels: BTreeMap::new(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
+        }).unwrap();
+        let stream_id = db.lock().cameras_by_id().get(&camera_id).unwrap().streams[0].unwrap();
+        let vse_id = db.lock().insert_video_sample_entry(
+            1920, 1080, include_bytes!("testdata/avc1").to_vec(),
+            "avc1.4d0029".to_owned()).unwrap();
+
+        // Add three one-second recordings; hold the second.
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        {
+            let mut l = db.lock();
+            for i in 0..3 {
+                let (id, _) = l.add_recording(stream_id, RecordingToInsert {
+                    sample_file_bytes: 42,
+                    run_offset: i,
+                    flags: 0,
+                    start: start + recording::Duration(i as i64 * TIME_UNITS_PER_SEC),
+                    duration_90k: TIME_UNITS_PER_SEC as i32,
+                    local_time_delta: recording::Duration(0),
+                    video_samples: 1,
+                    video_sync_samples: 1,
+                    video_sample_entry_id: vse_id,
+                    video_index: [0u8; 100].to_vec(),
+                    sample_file_sha1: [0u8; 20],
+                    min_frame_interval_90k: TIME_UNITS_PER_SEC as i32,
+                    max_frame_interval_90k: TIME_UNITS_PER_SEC as i32,
+                    metadata: Vec::new(),
+                }).unwrap();
+                l.mark_synced(id).unwrap();
+            }
+            l.flush("add test").unwrap();
+            let hold_start = start + recording::Duration(TIME_UNITS_PER_SEC + 1);
+            l.add_hold(camera_id, hold_start .. hold_start, "empty".to_owned(), 42, None)
+             .unwrap_err();
+            l.add_hold(camera_id + 1, hold_start .. hold_start + recording::Duration(1),
+                       "no such camera".to_owned(), 42, None).unwrap_err();
+            l.add_hold(camera_id, hold_start .. hold_start + recording::Duration(1),
+                       "expired".to_owned(), 42, Some(42)).unwrap_err();
+            l.add_hold(camera_id, hold_start .. hold_start + recording::Duration(1),
+                       "claim 123".to_owned(), 42, Some(i64::max_value())).unwrap();
+        }
+
+        // Closing and reopening the database should present the same hold.
+        let conn = db.close();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let mut l = db.lock();
+        let hold_id = {
+            assert_eq!(l.holds_by_id().len(), 1);
+            let h = l.holds_by_id().values().next().unwrap();
+            assert_eq!(h.camera_id, camera_id);
+            assert_eq!(h.reason, "claim 123");
+            assert_eq!(h.created_sec, 42);
+            assert_eq!(h.expires_sec, Some(i64::max_value()));
+            h.id
+        };
+
+        // Retention should skip the held recording but delete the ones on either side of it.
+        let mut ids = Vec::new();
+        l.delete_oldest_recordings(stream_id, &mut |r| { ids.push(r.id.recording()); true })
+         .unwrap();
+        assert_eq!(ids, vec![0, 2]);
+        assert_eq!(l.holds_blocking_retention.len(), 1);
+        l.flush("delete test").unwrap();
+        let mut n = 0;
+        l.delete_oldest_recordings(stream_id, &mut |_| { n += 1; true }).unwrap();
+        assert_eq!(n, 0);
+        l.delete_camera(camera_id).unwrap_err();  // has holds.
+
+        // Once expired, the hold is released by the next flush and the held recording can be
+        // deleted.
+        l.update_hold_expiry(hold_id, Some(41)).unwrap_err();  // before creation.
+        l.update_hold_expiry(hold_id, Some(43)).unwrap();
+        l.flush("expire test").unwrap();
+        assert!(l.holds_by_id().is_empty());
+        assert!(l.holds_blocking_retention.is_empty());
+        l.release_hold(hold_id).unwrap_err();
+        let hold_start = start + recording::Duration(TIME_UNITS_PER_SEC + 1);
+        let hold_id = l.add_hold(camera_id, hold_start .. hold_start + recording::Duration(1),
+                                 "claim 456".to_owned(), 42, None).unwrap();
+        l.release_hold(hold_id).unwrap();
+        l.release_hold(hold_id).unwrap_err();
+        ids.clear();
+        l.delete_oldest_recordings(stream_id, &mut |r| { ids.push(r.id.recording()); true })
+         .unwrap();
+        assert_eq!(ids, vec![1]);
+    }
+
     /// Basic test of the full lifecycle of recording. Does not exercise error cases.
     #[test]
     fn test_full_lifecycle() {
@@ -2088,7 +6079,7 @@ mod tests {
         let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
         let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
         let path = tmpdir.path().to_str().unwrap().to_owned();
-        let sample_file_dir_id = { db.lock() }.add_sample_file_dir(path).unwrap();
+        let sample_file_dir_id = { db.lock() }.add_sample_file_dir(path, false).unwrap();
         let mut c = CameraChange {
             short_name: "testcam".to_owned(),
             description: "".to_owned(),
@@ -2098,22 +6089,46 @@ mod tests {
             streams: [
                 StreamChange {
                     sample_file_dir_id: Some(sample_file_dir_id),
+                    mirror_sample_file_dir_id: None,
                     rtsp_path: "/main".to_owned(),
+                    source: StreamSource::Rtsp,
                     record: false,
                     flush_if_sec: 1,
+                    recording_duration_sec: 60,
+                    sei_motion_uuid: None,
+                    snapshot_url: None,
+                    metadata_events: false,
+                    thumbnail_interval_sec: 0,
+                    encoder: Default::default(),
+                    input_options: None,
                 },
                 StreamChange {
                     sample_file_dir_id: Some(sample_file_dir_id),
+                    mirror_sample_file_dir_id: None,
                     rtsp_path: "/sub".to_owned(),
+                    source: StreamSource::Rtsp,
                     record: true,
                     flush_if_sec: 1,
+                    recording_duration_sec: 60,
+                    sei_motion_uuid: None,
+                    snapshot_url: None,
+                    metadata_events: false,
+                    thumbnail_interval_sec: 0,
+                    encoder: Default::default(),
+                    input_options: None,
                 },
             ],
+            labels: [("location".to_owned(), "garage".to_owned())].iter().cloned().collect(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
         };
         let camera_id = db.lock().add_camera(c.clone()).unwrap();
         let (main_stream_id, sub_stream_id);
         {
             let mut l = db.lock();
+            l.add_camera(c.clone()).unwrap_err();  // duplicate short name.
+            assert_eq!(l.get_camera_by_short_name(&c.short_name).unwrap().id, camera_id);
             {
                 let c = l.cameras_by_id().get(&camera_id).unwrap();
                 main_stream_id = c.streams[0].unwrap();
@@ -2123,16 +6138,19 @@ mod tests {
                 stream_id: main_stream_id,
                 new_record: true,
                 new_limit: 42,
+                new_weight: 2,
             }]).unwrap();
             {
                 let main = l.streams_by_id().get(&main_stream_id).unwrap();
                 assert!(main.record);
                 assert_eq!(main.retain_bytes, 42);
+                assert_eq!(main.retain_weight, 2);
                 assert_eq!(main.flush_if_sec, 1);
             }
 
             assert_eq!(l.streams_by_id().get(&sub_stream_id).unwrap().flush_if_sec, 1);
             c.streams[1].flush_if_sec = 2;
+            c.labels.insert("direction".to_owned(), "north".to_owned());
             l.update_camera(camera_id, c).unwrap();
             assert_eq!(l.streams_by_id().get(&sub_stream_id).unwrap().flush_if_sec, 2);
         }
@@ -2143,6 +6161,13 @@ mod tests {
         let conn = db.close();
         let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
         assert_eq!(db.lock().streams_by_id().get(&sub_stream_id).unwrap().flush_if_sec, 2);
+        {
+            let l = db.lock();
+            let labels = &l.cameras_by_id().get(&camera_id).unwrap().labels;
+            assert_eq!(labels.len(), 2);
+            assert_eq!(labels.get("location").map(String::as_str), Some("garage"));
+            assert_eq!(labels.get("direction").map(String::as_str), Some("north"));
+        }
         assert_no_recordings(&db, camera_uuid);
 
         // TODO: assert_eq!(db.lock().list_garbage(sample_file_dir_id).unwrap(), &[]);
@@ -2166,6 +6191,9 @@ mod tests {
             video_sample_entry_id: vse_id,
             video_index: [0u8; 100].to_vec(),
             sample_file_sha1: [0u8; 20],
+            min_frame_interval_90k: TIME_UNITS_PER_SEC as i32,
+            max_frame_interval_90k: TIME_UNITS_PER_SEC as i32,
+            metadata: b"\x00\x04<a/>".to_vec(),
         };
         let id = {
             let mut db = db.lock();
@@ -2175,15 +6203,32 @@ mod tests {
             id
         };
         assert_eq!(db.lock().streams_by_id().get(&main_stream_id).unwrap().next_recording_id, 2);
+        let head = ::chain::link(&::chain::GENESIS, id, &recording).unwrap();
+        {
+            let l = db.lock();
+            let s = l.streams_by_id().get(&main_stream_id).unwrap();
+            assert_eq!(s.chain_head, head);
+            assert_eq!(s.last_anchor.as_ref().unwrap().id, id);
+        }
 
         // Queries should return the correct result (with caches update on insert).
         assert_single_recording(&db, main_stream_id, &recording);
+        db.lock().with_recording_metadata(id, &mut |m| {
+            assert_eq!(m, &recording.metadata[..]);
+            Ok(())
+        }).unwrap();
 
         // Queries on a fresh database should return the correct result (with caches populated from
         // existing database contents rather than built on insert).
         let conn = db.close();
         let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
         assert_single_recording(&db, main_stream_id, &recording);
+        {
+            let l = db.lock();
+            let s = l.streams_by_id().get(&main_stream_id).unwrap();
+            assert_eq!(s.chain_head, head);
+            assert_eq!(s.last_anchor.as_ref().unwrap().chain_sha1, head);
+        }
 
         // Deleting a recording should succeed, update the min/max times, and mark it as garbage.
         {
@@ -2195,6 +6240,8 @@ mod tests {
                 let s = db.streams_by_id().get(&main_stream_id).unwrap();
                 assert_eq!(s.sample_file_bytes, 42);
                 assert_eq!(s.bytes_to_delete, 42);
+                assert_eq!(s.retention_usage(), 0);
+                assert_eq!(s.uncommitted_bytes(), 0);
             }
             n = 0;
 
@@ -2226,5 +6273,14 @@ mod tests {
                           .map(|&id| id)
                           .collect();
         assert_eq!(&g, &[]);
+
+        // On reopening, the garbage left by the previous run should be reported.
+        let conn = db.close();
+        let db = Database::new(clock::RealClocks {}, conn, true).unwrap();
+        let l = db.lock();
+        let r = l.startup_report();
+        assert!(r.previous_run_end.is_some());
+        assert_eq!(r.streams[&main_stream_id].garbage_collected, 1);
+        assert_eq!(r.streams[&main_stream_id].abandoned_recordings, 0);
     }
 }
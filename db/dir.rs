@@ -34,16 +34,35 @@
 
 use db::CompositeId;
 use failure::{Error, Fail};
-use libc::{self, c_char};
+use libc;
+use openssl::aes::{self, AesKey};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::{rand, symm};
 use protobuf::{self, Message};
 use schema;
-use std::ffi;
+use platform;
+use std::cmp;
+use std::fmt;
 use std::fs;
-use std::io::{self, Read, Write};
-use std::mem;
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::FromRawFd;
-use std::sync::Arc;
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration as StdDuration;
+use time;
+use uuid::Uuid;
+use zfs;
+
+pub use platform::{Fd, FileMode, FsStats, LockMode};
+
+/// How long a network filesystem lease (see `SampleFileDir::open`) is valid without refresh.
+const LEASE_SEC: i64 = 120;
+
+/// How often a network filesystem lease is refreshed.
+const LEASE_REFRESH_SEC: u64 = 30;
 
 /// A sample file directory. Typically one per physical disk drive.
 ///
@@ -56,91 +75,371 @@ pub struct SampleFileDir {
     /// The open file descriptor for the directory. The worker uses it to create files and sync the
     /// directory. Other threads use it to open sample files for reading during video serving.
     pub(crate) fd: Fd,
-}
 
-/// A file descriptor associated with a directory (not necessarily the sample file dir).
-#[derive(Debug)]
-pub struct Fd(libc::c_int);
+    /// True iff the directory is on a network filesystem (such as NFS or SMB), which may not have
+    /// reliable `flock`, `O_EXCL`, or directory `fsync` semantics. See `SampleFileDir::open`.
+    network_fs: bool,
+
+    /// True iff this directory's lease has been taken by another instance. No further files will
+    /// be created. Only set on a network filesystem.
+    lease_lost: AtomicBool,
+
+    /// If the directory is encrypted, its key. See `Cipher`.
+    cipher: Option<Cipher>,
+
+    /// The id of the database open for which this directory was opened read/write, or `None` if
+    /// it's read-only. Part of the nonce of each encrypted file written.
+    open_id: Option<u32>,
 
-impl Drop for Fd {
-    fn drop(&mut self) {
-        if unsafe { libc::close(self.0) } < 0 {
-            let e = io::Error::last_os_error();
-            warn!("Unable to close sample file dir: {}", e);
+    /// The properties of the ZFS dataset holding the directory, if any. See `zfs`.
+    zfs: Option<zfs::Properties>,
+}
+
+/// A key used to wrap each encrypted directory's `Cipher` key for storage in the database.
+/// It's never itself stored in the database, so a copy of the database and sample file
+/// directories (such as a stolen disk) isn't sufficient to read encrypted footage.
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    /// Loads a master key from a file holding 64 hexadecimal digits (optionally followed by
+    /// whitespace). One can be created with `openssl rand -hex 32`.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let mut s = String::new();
+        fs::File::open(path)
+            .map_err(|e| format_err!("unable to open master key {}: {}", path, e))?
+            .read_to_string(&mut s)?;
+        let s = s.trim().as_bytes();
+        let mut k = [0u8; 32];
+        if s.len() != 2 * k.len() {
+            bail!("master key {} should be {} hex digits; is {} bytes", path, 2 * k.len(),
+                  s.len());
         }
+        for (i, b) in k.iter_mut().enumerate() {
+            let hi = dehex_digit(s[2*i]).ok_or_else(|| format_err!("bad master key {}", path))?;
+            let lo = dehex_digit(s[2*i+1]).ok_or_else(|| format_err!("bad master key {}", path))?;
+            *b = (hi << 4) | lo;
+        }
+        Ok(MasterKey(k))
     }
 }
 
-impl Fd {
-    /// Opens the given path as a directory.
-    pub fn open(path: &str, mkdir: bool) -> Result<Fd, io::Error> {
-        let cstring = ffi::CString::new(path)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-        if mkdir && unsafe { libc::mkdir(cstring.as_ptr(), 0o700) } != 0 {
-            let e = io::Error::last_os_error();
-            if e.kind() != io::ErrorKind::AlreadyExists {
-                return Err(e.into());
-            }
-        }
-        let fd = unsafe { libc::open(cstring.as_ptr(), libc::O_DIRECTORY | libc::O_RDONLY, 0) };
-        if fd < 0 {
-            return Err(io::Error::last_os_error().into());
+fn dehex_digit(b: u8) -> Option<u8> {
+    match b {
+        b'0'...b'9' => Some(b - b'0'),
+        b'a'...b'f' => Some(b - b'a' + 10),
+        b'A'...b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// An encrypted directory's key. Sample files are encrypted with AES-256-GCM in chunks of
+/// `CHUNK_LEN` bytes, each followed on disk by its `TAG_LEN`-byte authentication tag, so that
+/// they can be written incrementally and any byte range can be read (for HTTP range requests) by
+/// decrypting only the chunks it spans. Reads fail if a chunk or its tag has been altered. Each
+/// file is encrypted with its own key, derived from this one, the recording's composite id, and
+/// the database open id it was written under, so a nonce (the chunk's index) is never reused
+/// with the same key, even if an uncommitted recording's id is reused after a crash.
+#[derive(Clone)]
+pub struct Cipher {
+    key: [u8; 32],
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Cipher { .. }") }
+}
+
+/// The length of a wrapped `Cipher` key, as in the `sample_file_dir.wrapped_key` column.
+pub const WRAPPED_KEY_LEN: usize = 40;
+
+/// The plaintext length of each encrypted chunk of a sample file, other than the last.
+const CHUNK_LEN: u64 = 1 << 16;
+
+/// The length of the authentication tag following each encrypted chunk.
+const TAG_LEN: u64 = 16;
+
+/// Returns the AES-256-GCM nonce of the given chunk within a file.
+fn chunk_nonce(chunk: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    for i in 0..8 {
+        nonce[4 + i] = (chunk >> (56 - 8 * i)) as u8;
+    }
+    nonce
+}
+
+impl Cipher {
+    /// Generates a new random key.
+    pub fn generate() -> Result<Self, Error> {
+        let mut key = [0u8; 32];
+        rand::rand_bytes(&mut key)?;
+        Ok(Cipher { key })
+    }
+
+    /// Wraps the key with the given master key (RFC 3394), returning `WRAPPED_KEY_LEN` bytes.
+    pub fn wrap(&self, master: &MasterKey) -> Result<Vec<u8>, Error> {
+        let m = AesKey::new_encrypt(&master.0).map_err(|_| format_err!("invalid master key"))?;
+        let mut out = vec![0u8; WRAPPED_KEY_LEN];
+        aes::wrap_key(&m, None, &mut out, &self.key)
+            .map_err(|_| format_err!("unable to wrap key"))?;
+        Ok(out)
+    }
+
+    /// Unwraps a key produced by `wrap`. This fails if `master` isn't the key it was wrapped
+    /// with.
+    pub fn unwrap(wrapped: &[u8], master: &MasterKey) -> Result<Self, Error> {
+        if wrapped.len() != WRAPPED_KEY_LEN {
+            bail!("wrapped key has length {}; expected {}", wrapped.len(), WRAPPED_KEY_LEN);
         }
-        Ok(Fd(fd))
+        let m = AesKey::new_decrypt(&master.0).map_err(|_| format_err!("invalid master key"))?;
+        let mut key = [0u8; 32];
+        aes::unwrap_key(&m, None, &mut key, wrapped)
+            .map_err(|_| format_err!("unable to unwrap key; is this the right master key?"))?;
+        Ok(Cipher { key })
+    }
+
+    /// Returns the plaintext length of an encrypted sample file of `len` bytes.
+    pub fn plaintext_len(len: u64) -> u64 {
+        let chunks = (len + CHUNK_LEN + TAG_LEN - 1) / (CHUNK_LEN + TAG_LEN);
+        len.saturating_sub(chunks * TAG_LEN)
     }
 
-    pub(crate) fn sync(&self) -> Result<(), io::Error> {
-        let res = unsafe { libc::fsync(self.0) };
-        if res < 0 {
-            return Err(io::Error::last_os_error())
+    /// Derives the key of the given sample file (HMAC-SHA256 of its id and open id).
+    fn file_key(&self, id: CompositeId, open_id: u32) -> Result<[u8; 32], Error> {
+        let mut msg = [0u8; 12];
+        for i in 0..8 {
+            msg[i] = (id.0 >> (56 - 8 * i)) as u8;
         }
-        Ok(())
+        for i in 0..4 {
+            msg[8 + i] = (open_id >> (24 - 8 * i)) as u8;
+        }
+        let pkey = PKey::hmac(&self.key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+        signer.update(&msg)?;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&signer.sign_to_vec()?);
+        Ok(key)
+    }
+
+    /// Returns an `Encrypter` for writing the given sample file from the beginning.
+    pub fn encrypter(&self, id: CompositeId, open_id: u32) -> Result<Encrypter, Error> {
+        Ok(Encrypter {
+            key: self.file_key(id, open_id)?,
+            chunk: 0,
+            chunk_pos: 0,
+            crypter: None,
+        })
     }
 
-    /// Opens a sample file within this directory with the given flags and (if creating) mode.
-    unsafe fn openat(&self, p: *const c_char, flags: libc::c_int, mode: libc::c_int)
-                     -> Result<fs::File, io::Error> {
-        let fd = libc::openat(self.0, p, flags, mode);
-        if fd < 0 {
-            return Err(io::Error::last_os_error())
+    /// Reads and decrypts the plaintext byte range `r` of the given sample file, failing if any
+    /// chunk it spans doesn't authenticate.
+    ///
+    /// If `growing`, the recording is still being written, so its last chunk on disk may not
+    /// have its tag yet. A short last chunk is then decrypted without authentication; it's
+    /// authenticated by any read once the recording is complete.
+    pub fn read(&self, f: &fs::File, id: CompositeId, open_id: u32, r: Range<u64>,
+                growing: bool) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::with_capacity((r.end - r.start) as usize);
+        if r.start >= r.end {
+            return Ok(out);
+        }
+        let key = self.file_key(id, open_id)?;
+        let mut f = f;
+        let mut buf = vec![0u8; (CHUNK_LEN + TAG_LEN) as usize];
+        for chunk in r.start / CHUNK_LEN .. (r.end - 1) / CHUNK_LEN + 1 {
+            f.seek(io::SeekFrom::Start(chunk * (CHUNK_LEN + TAG_LEN)))?;
+            let mut len = 0;
+            while len < buf.len() {
+                let n = f.read(&mut buf[len..])?;
+                if n == 0 {
+                    break;
+                }
+                len += n;
+            }
+            let nonce = chunk_nonce(chunk);
+            let plain = if growing && len < buf.len() {
+                // GCM's keystream for a 96-bit nonce starts at counter 2.
+                let mut iv = [0u8; 16];
+                iv[..12].copy_from_slice(&nonce);
+                iv[15] = 2;
+                symm::decrypt(symm::Cipher::aes_256_ctr(), &key, Some(&iv), &buf[..len])?
+            } else {
+                if len <= TAG_LEN as usize {
+                    bail!("{}: chunk {} is truncated", id, chunk);
+                }
+                let (data, tag) = buf[..len].split_at(len - TAG_LEN as usize);
+                symm::decrypt_aead(symm::Cipher::aes_256_gcm(), &key, Some(&nonce), &[], data,
+                                   tag)
+                    .map_err(|_| format_err!("{}: chunk {} failed authentication", id, chunk))?
+            };
+            let chunk_start = chunk * CHUNK_LEN;
+            let from = (cmp::max(r.start, chunk_start) - chunk_start) as usize;
+            let to = (cmp::min(r.end, chunk_start + CHUNK_LEN) - chunk_start) as usize;
+            if plain.len() < to {
+                bail!("{}: chunk {} is truncated", id, chunk);
+            }
+            out.extend_from_slice(&plain[from .. to]);
         }
-        Ok(fs::File::from_raw_fd(fd))
+        Ok(out)
     }
+}
+
+/// Incrementally encrypts a sample file; see `Cipher`.
+pub struct Encrypter {
+    key: [u8; 32],
+
+    /// The index of the chunk being encrypted.
+    chunk: u64,
 
-    /// Locks the directory with the specified `flock` operation.
-    pub fn lock(&self, operation: libc::c_int) -> Result<(), io::Error> {
-        let ret = unsafe { libc::flock(self.0, operation) };
-        if ret < 0 {
-            return Err(io::Error::last_os_error().into());
+    /// The number of plaintext bytes of `chunk` encrypted so far.
+    chunk_pos: u64,
+
+    /// The state of `chunk`, if any of it has been encrypted.
+    crypter: Option<symm::Crypter>,
+}
+
+impl Encrypter {
+    /// Encrypts `data`, appending the result to `out`, including the tag of each chunk it
+    /// completes.
+    pub fn update(&mut self, mut data: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        while !data.is_empty() {
+            if self.crypter.is_none() {
+                self.crypter = Some(symm::Crypter::new(
+                    symm::Cipher::aes_256_gcm(), symm::Mode::Encrypt, &self.key,
+                    Some(&chunk_nonce(self.chunk)))?);
+            }
+            let n = cmp::min(data.len() as u64, CHUNK_LEN - self.chunk_pos) as usize;
+            let at = out.len();
+            out.resize(at + n + TAG_LEN as usize, 0);
+            let written = self.crypter.as_mut().unwrap().update(&data[..n], &mut out[at..])?;
+            out.truncate(at + written);
+            self.chunk_pos += n as u64;
+            data = &data[n..];
+            if self.chunk_pos == CHUNK_LEN {
+                self.seal(out)?;
+            }
         }
         Ok(())
     }
 
-    pub fn statfs(&self) -> Result<libc::statvfs, io::Error> {
-        unsafe {
-            let mut stat: libc::statvfs = mem::zeroed();
-            if libc::fstatvfs(self.0, &mut stat) < 0 {
-                return Err(io::Error::last_os_error())
+    /// Finishes the current chunk, if any, appending its tag to `out`. This must be called
+    /// after the last `update`.
+    pub fn seal(&mut self, out: &mut Vec<u8>) -> Result<(), Error> {
+        let mut c = match self.crypter.take() {
+            None => return Ok(()),
+            Some(c) => c,
+        };
+        let mut rest = [0u8; TAG_LEN as usize];
+        let n = c.finalize(&mut rest)?;
+        out.extend_from_slice(&rest[..n]);
+        let mut tag = [0u8; TAG_LEN as usize];
+        c.get_tag(&mut tag)?;
+        out.extend_from_slice(&tag);
+        self.chunk += 1;
+        self.chunk_pos = 0;
+        Ok(())
+    }
+}
+
+/// A sample file being written, encrypting its contents if the directory is encrypted.
+pub struct SampleFileWriter {
+    f: fs::File,
+
+    /// The encryption state, if encrypted.
+    encrypter: Option<Encrypter>,
+
+    /// True once the last chunk has been sealed by `sync_all`; no more data can be written then.
+    sealed: bool,
+
+    /// If set, data is buffered and written in pieces of exactly this size (other than the last).
+    /// Note buffered data isn't visible to readers of the file until it's written.
+    write_size: Option<usize>,
+
+    /// Data (already encrypted, if applicable) not yet written. Used with `write_size`, and
+    /// when encrypted, as the encryption state has already advanced past any data not yet written.
+    buf: Vec<u8>,
+
+    /// False iff the filesystem makes every write synchronous, so `sync_all` needn't `fsync`.
+    sync_needed: bool,
+}
+
+impl SampleFileWriter {
+    /// Writes out any buffered data and syncs the file. If encrypted, this seals the last chunk,
+    /// so it must be called only once all data has been written.
+    pub fn sync_all(&mut self) -> Result<(), io::Error> {
+        if let Some(ref mut e) = self.encrypter {
+            if !self.sealed {
+                e.seal(&mut self.buf)
+                 .map_err(|e| io::Error::new(io::ErrorKind::Other, e.compat()))?;
+                self.sealed = true;
             }
-            Ok(stat)
         }
+        let len = self.buf.len();
+        self.flush_buf(len)?;
+        if self.sync_needed {
+            self.f.sync_all()?;
+        }
+        Ok(())
     }
-}
 
-pub(crate) unsafe fn renameat(from_fd: &Fd, from_path: *const c_char,
-                   to_fd: &Fd, to_path: *const c_char) -> Result<(), io::Error> {
-    let result = libc::renameat(from_fd.0, from_path, to_fd.0, to_path);
-    if result < 0 {
-        return Err(io::Error::last_os_error())
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if self.sealed {
+            return Err(io::Error::new(io::ErrorKind::Other, "sample file is already sealed"));
+        }
+        let write_size = match (self.write_size, self.encrypter.is_some()) {
+            (None, false) => {
+                return self.f.write(buf);
+            },
+            (None, true) => {
+                // Write out data from before, so that on error the caller can retry without any
+                // data having been accepted. Then accept all of buf and try to write it out,
+                // leaving it buffered on error. The error will recur on the next call.
+                let len = self.buf.len();
+                self.flush_buf(len)?;
+                self.accept(buf)?;
+                let len = self.buf.len();
+                let _ = self.flush_buf(len);
+                return Ok(buf.len());
+            },
+            (Some(s), _) => s,
+        };
+
+        // Write out a full buffer before accepting more, so that on error the caller can retry
+        // without any data having been accepted. When encrypted, a chunk's tag may have taken
+        // the buffer past write_size; the excess starts the next piece.
+        if self.buf.len() >= write_size {
+            self.flush_buf(write_size)?;
+        }
+        let n = cmp::min(buf.len(), write_size - self.buf.len());
+        self.accept(&buf[..n])?;
+        Ok(n)
+    }
+
+    /// Appends all of `data` to `buf`, encrypting it if applicable.
+    fn accept(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        match self.encrypter {
+            None => self.buf.extend_from_slice(data),
+            Some(ref mut e) => {
+                e.update(data, &mut self.buf)
+                 .map_err(|e| io::Error::new(io::ErrorKind::Other, e.compat()))?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Writes the first `len` bytes of `buf`. On error, the unwritten portion remains buffered.
+    fn flush_buf(&mut self, mut len: usize) -> Result<(), io::Error> {
+        while len > 0 {
+            let n = self.f.write(&self.buf[..len])?;
+            self.buf.drain(..n);
+            len -= n;
+        }
+        Ok(())
     }
-    Ok(())
 }
 
 /// Reads `dir`'s metadata. If none is found, returns an empty proto.
 pub(crate) fn read_meta(dir: &Fd) -> Result<schema::DirMeta, Error> {
     let mut meta = schema::DirMeta::default();
-    let p = unsafe { ffi::CStr::from_ptr("meta\0".as_ptr() as *const c_char) };
-    let mut f = match unsafe { dir.openat(p.as_ptr(), libc::O_RDONLY, 0) } {
+    let mut f = match dir.open_file("meta", FileMode::Read) {
         Err(e) => {
             if e.kind() == ::std::io::ErrorKind::NotFound {
                 return Ok(meta);
@@ -158,29 +457,59 @@ pub(crate) fn read_meta(dir: &Fd) -> Result<schema::DirMeta, Error> {
 
 /// Write `dir`'s metadata, clobbering existing data.
 pub(crate) fn write_meta(dir: &Fd, meta: &schema::DirMeta) -> Result<(), Error> {
-    let (tmp_path, final_path) = unsafe {
-        (ffi::CStr::from_ptr("meta.tmp\0".as_ptr() as *const c_char),
-         ffi::CStr::from_ptr("meta\0".as_ptr() as *const c_char))
-    };
-    let mut f = unsafe { dir.openat(tmp_path.as_ptr(),
-                                    libc::O_CREAT | libc::O_TRUNC | libc::O_WRONLY, 0o600)? };
+    let mut f = dir.open_file("meta.tmp", FileMode::CreateTruncate)?;
     meta.write_to_writer(&mut f)?;
     f.sync_all()?;
-    unsafe { renameat(&dir, tmp_path.as_ptr(), &dir, final_path.as_ptr())? };
+    dir.rename("meta.tmp", "meta")?;
     dir.sync()?;
     Ok(())
 }
 
+/// A lease on a sample file directory on a network filesystem, stored in its `lease` file.
+#[derive(Debug, PartialEq)]
+struct Lease {
+    /// The uuid of the open (see `schema::DirMeta`) which holds this lease.
+    open_uuid: Uuid,
+
+    /// When the lease expires unless refreshed, in seconds since epoch.
+    expires_sec: i64,
+}
+
+impl Lease {
+    fn parse(data: &str) -> Result<Self, Error> {
+        let mut parts = data.split_whitespace();
+        let (u, e) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(u), Some(e), None) => (u, e),
+            _ => bail!("malformed lease {:?}", data),
+        };
+        Ok(Lease {
+            open_uuid: Uuid::parse_str(u)?,
+            expires_sec: e.parse()?,
+        })
+    }
+}
+
 impl SampleFileDir {
     /// Opens the directory using the given metadata.
     ///
     /// `db_meta.in_progress_open` should be filled if the directory should be opened in read/write
     /// mode; absent in read-only mode.
-    pub fn open(path: &str, db_meta: &schema::DirMeta)
+    ///
+    /// Ordinarily a read/write open takes an exclusive `flock` on the directory. If `network_fs`,
+    /// it instead takes a lease: a `lease` file naming the open's uuid, refreshed periodically by
+    /// a background thread until the directory is dropped. Another instance can't open the
+    /// directory for writing until the lease expires.
+    ///
+    /// `cipher` should be supplied iff the directory is encrypted.
+    pub fn open(path: &str, db_meta: &schema::DirMeta, network_fs: bool, cipher: Option<Cipher>)
                 -> Result<Arc<SampleFileDir>, Error> {
         let read_write = db_meta.in_progress_open.is_some();
-        let s = SampleFileDir::open_self(path, false)?;
-        s.fd.lock(if read_write { libc::LOCK_EX } else { libc::LOCK_SH } | libc::LOCK_NB)?;
+        let s = SampleFileDir::open_self(path, false, network_fs, cipher, db_meta)?;
+        if !network_fs {
+            s.fd.lock(if read_write { LockMode::Exclusive } else { LockMode::Shared })?;
+        } else if read_write {
+            SampleFileDir::take_lease(&s, path, &db_meta.get_in_progress_open().uuid)?;
+        }
         let dir_meta = read_meta(&s.fd)?;
         if !SampleFileDir::consistent(db_meta, &dir_meta) {
             bail!("metadata mismatch.\ndb: {:#?}\ndir: {:#?}", db_meta, &dir_meta);
@@ -210,10 +539,14 @@ impl SampleFileDir {
         true
     }
 
-    pub(crate) fn create(path: &str, db_meta: &schema::DirMeta)
-                         -> Result<Arc<SampleFileDir>, Error> {
-        let s = SampleFileDir::open_self(path, true)?;
-        s.fd.lock(libc::LOCK_EX | libc::LOCK_NB)?;
+    pub(crate) fn create(path: &str, db_meta: &schema::DirMeta, network_fs: bool,
+                         cipher: Option<Cipher>) -> Result<Arc<SampleFileDir>, Error> {
+        let s = SampleFileDir::open_self(path, true, network_fs, cipher, db_meta)?;
+        if network_fs {
+            SampleFileDir::take_lease(&s, path, &db_meta.get_in_progress_open().uuid)?;
+        } else {
+            s.fd.lock(LockMode::Exclusive)?;
+        }
         let old_meta = read_meta(&s.fd)?;
 
         // Verify metadata. We only care that it hasn't been completely opened.
@@ -232,62 +565,209 @@ impl SampleFileDir {
     pub(crate) fn is_empty(path: &str) -> Result<bool, Error> {
         for e in fs::read_dir(path)? {
             let e = e?;
-            match e.file_name().as_bytes() {
+            match platform::name_bytes(&e.file_name()) {
                 b"." | b".." => continue,
                 b"meta" | b"meta-tmp" => continue,  // existing metadata is fine.
+                b"lease" | b"lease.tmp" => continue,
+                b"lock" => continue,  // see platform::windows::Fd::lock.
                 _ => return Ok(false),
             }
         }
         Ok(true)
     }
 
-    fn open_self(path: &str, create: bool) -> Result<Arc<SampleFileDir>, Error> {
+    fn open_self(path: &str, create: bool, network_fs: bool, cipher: Option<Cipher>,
+                 db_meta: &schema::DirMeta) -> Result<Arc<SampleFileDir>, Error> {
         let fd = Fd::open(path, create)
             .map_err(|e| format_err!("unable to open sample file dir {}: {}", path, e))?;
+        let zfs = if fd.is_zfs()? {
+            match zfs::Properties::get(path) {
+                Ok(p) => {
+                    info!("dir {}: on ZFS dataset {} with sync={}, recordsize={}", path,
+                          p.dataset, if p.sync_always { "always" } else { "standard" },
+                          p.recordsize);
+                    Some(p)
+                },
+                Err(e) => {
+                    warn!("dir {}: on ZFS but unable to get dataset properties: {}", path, e);
+                    None
+                },
+            }
+        } else {
+            None
+        };
         Ok(Arc::new(SampleFileDir {
             fd,
+            network_fs,
+            lease_lost: AtomicBool::new(false),
+            cipher,
+            open_id: db_meta.in_progress_open.as_ref().map(|o| o.id),
+            zfs,
         }))
     }
 
+    /// Returns the directory's cipher, if it's encrypted. Data read from `open_file` must then be
+    /// decrypted with it.
+    pub fn cipher(&self) -> Option<&Cipher> { self.cipher.as_ref() }
+
+    /// Reads the `lease` file, if any.
+    fn read_lease(&self) -> Result<Option<Lease>, Error> {
+        let mut f = match self.fd.open_file("lease", FileMode::Read) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+            Ok(f) => f,
+        };
+        let mut data = String::new();
+        f.read_to_string(&mut data)?;
+        Ok(Some(Lease::parse(&data)?))
+    }
+
+    /// Writes the `lease` file via an atomic rename, as `O_EXCL` may not be reliable.
+    fn write_lease(&self, lease: &Lease) -> Result<(), Error> {
+        let mut f = self.fd.open_file("lease.tmp", FileMode::CreateTruncate)?;
+        write!(f, "{} {}\n", lease.open_uuid, lease.expires_sec)?;
+        f.sync_all()?;
+        self.fd.rename("lease.tmp", "lease")?;
+        self.sync()?;
+        Ok(())
+    }
+
+    /// Takes the lease for the given open, failing if another instance holds it.
+    /// On success, starts a thread to refresh it.
+    fn take_lease(s: &Arc<SampleFileDir>, path: &str, open_uuid: &[u8]) -> Result<(), Error> {
+        let open_uuid = Uuid::from_slice(open_uuid)?;
+        let now = time::get_time().sec;
+        if let Some(l) = s.read_lease()? {
+            if l.open_uuid != open_uuid && l.expires_sec > now {
+                bail!("sample file dir {} is leased by open {} for another {} sec",
+                      path, l.open_uuid, l.expires_sec - now);
+            }
+        }
+        let lease = Lease { open_uuid, expires_sec: now + LEASE_SEC };
+        s.write_lease(&lease)?;
+
+        // Two instances may have raced; the later rename wins. Wait for any concurrent write to
+        // land, then make sure it's still ours.
+        thread::sleep(StdDuration::from_secs(1));
+        if s.read_lease()?.as_ref() != Some(&lease) {
+            bail!("lost race for lease on sample file dir {}", path);
+        }
+        let weak = Arc::downgrade(s);
+        thread::Builder::new()
+            .name(format!("lease-{}", path))
+            .spawn(move || SampleFileDir::refresh_lease(weak, open_uuid))?;
+        Ok(())
+    }
+
+    /// Refreshes the lease until the directory is dropped or the lease is lost.
+    fn refresh_lease(weak: Weak<SampleFileDir>, open_uuid: Uuid) {
+        loop {
+            thread::sleep(StdDuration::from_secs(LEASE_REFRESH_SEC));
+            let s = match weak.upgrade() {
+                None => return,
+                Some(s) => s,
+            };
+            let r = s.read_lease().and_then(|l| match l {
+                Some(ref l) if l.open_uuid != open_uuid => {
+                    s.lease_lost.store(true, Ordering::SeqCst);
+                    bail!("lease was taken by open {}; no longer writing", l.open_uuid);
+                },
+                _ => s.write_lease(&Lease {
+                    open_uuid,
+                    expires_sec: time::get_time().sec + LEASE_SEC,
+                }),
+            });
+            if let Err(e) = r {
+                error!("dir: unable to refresh lease: {}", e);
+                if s.lease_lost.load(Ordering::SeqCst) {
+                    return;
+                }
+            }
+        }
+    }
+
     /// Opens the given sample file for reading.
     pub fn open_file(&self, composite_id: CompositeId) -> Result<fs::File, io::Error> {
-        let p = SampleFileDir::get_rel_pathname(composite_id);
-        unsafe { self.fd.openat(p.as_ptr(), libc::O_RDONLY, 0) }
+        self.fd.open_file(&SampleFileDir::get_rel_pathname(composite_id), FileMode::Read)
     }
 
-    pub fn create_file(&self, composite_id: CompositeId) -> Result<fs::File, io::Error> {
+    /// Creates the given sample file for writing.
+    ///
+    /// On a network filesystem, this doesn't use `O_EXCL`, which may spuriously fail when a
+    /// retransmitted create request finds the file created by the original. The lease ensures
+    /// there's no other writer, and ids are never reused.
+    pub fn create_file(&self, composite_id: CompositeId)
+                       -> Result<SampleFileWriter, io::Error> {
+        if self.lease_lost.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Other, "sample file dir lease lost"));
+        }
+        let encrypter = match (self.cipher.as_ref(), self.open_id) {
+            (None, _) => None,
+            (Some(c), Some(o)) => {
+                Some(c.encrypter(composite_id, o)
+                      .map_err(|e| io::Error::new(io::ErrorKind::Other, e.compat()))?)
+            },
+            (Some(_), None) => return Err(io::Error::new(io::ErrorKind::Other,
+                                                         "sample file dir is read-only")),
+        };
         let p = SampleFileDir::get_rel_pathname(composite_id);
-        unsafe { self.fd.openat(p.as_ptr(), libc::O_WRONLY | libc::O_EXCL | libc::O_CREAT, 0o600) }
+        let mode = if self.network_fs {
+            FileMode::CreateTruncate
+        } else {
+            FileMode::CreateExclusive
+        };
+        let f = self.fd.open_file(&p, mode)?;
+        let write_size = self.zfs.as_ref().map(|z| z.write_size());
+        Ok(SampleFileWriter {
+            f,
+            encrypter,
+            sealed: false,
+            write_size,
+            buf: Vec::with_capacity(write_size.unwrap_or(0)),
+            sync_needed: !self.zfs.as_ref().map(|z| z.sync_always).unwrap_or(false),
+        })
     }
 
     pub(crate) fn write_meta(&self, meta: &schema::DirMeta) -> Result<(), Error> {
         write_meta(&self.fd, meta)
     }
 
-    pub fn statfs(&self) -> Result<libc::statvfs, io::Error> { self.fd.statfs() }
+    pub fn fs_stats(&self) -> Result<FsStats, io::Error> { self.fd.fs_stats() }
 
     /// Gets a pathname for a sample file suitable for passing to open or unlink.
-    fn get_rel_pathname(id: CompositeId) -> [libc::c_char; 17] {
-        let mut buf = [0u8; 17];
-        write!(&mut buf[..16], "{:016x}", id.0).expect("can't format id to pathname buf");
-
-        // libc::c_char seems to be i8 on some platforms (Linux/arm) and u8 on others (Linux/amd64).
-        unsafe { mem::transmute::<[u8; 17], [libc::c_char; 17]>(buf) }
-    }
+    fn get_rel_pathname(id: CompositeId) -> String { format!("{:016x}", id.0) }
 
     /// Unlinks the given sample file within this directory.
     pub(crate) fn unlink_file(&self, id: CompositeId) -> Result<(), io::Error> {
-        let p = SampleFileDir::get_rel_pathname(id);
-        let res = unsafe { libc::unlinkat(self.fd.0, p.as_ptr(), 0) };
-        if res < 0 {
-            return Err(io::Error::last_os_error())
+        self.fd.unlink(&SampleFileDir::get_rel_pathname(id))
+    }
+
+    /// Unlinks sample files which the database doesn't reference, such as those of recordings
+    /// which `LockedDatabase::archive_recordings` has marked as archived, then syncs the directory.
+    /// Files which are already gone are ignored.
+    pub fn unlink_unreferenced(&self, ids: &[CompositeId]) -> Result<(), io::Error> {
+        for &id in ids {
+            match self.unlink_file(id) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                r => r?,
+            }
         }
-        Ok(())
+        self.sync()
     }
 
     /// Syncs the directory itself.
-    pub(crate) fn sync(&self) -> Result<(), io::Error> {
-        self.fd.sync()
+    ///
+    /// Some network filesystems reject `fsync` on a directory with `EINVAL`; this is tolerated,
+    /// as their directory operations are committed by the server before returning. On a ZFS
+    /// dataset with `sync=always`, directory operations are likewise already durable.
+    pub fn sync(&self) -> Result<(), io::Error> {
+        if self.zfs.as_ref().map(|z| z.sync_always).unwrap_or(false) {
+            return Ok(());
+        }
+        match self.fd.sync() {
+            Err(ref e) if self.network_fs && e.raw_os_error() == Some(libc::EINVAL) => Ok(()),
+            r => r,
+        }
     }
 }
 
@@ -321,4 +801,108 @@ mod tests {
         parse_id(b"0").unwrap_err();
         parse_id(b"000000010000000x").unwrap_err();
     }
+
+    #[test]
+    fn cipher() {
+        use db::CompositeId;
+        use std::fs;
+        use super::{CHUNK_LEN, Cipher, MasterKey, TAG_LEN};
+        use tempdir::TempDir;
+        let master = MasterKey([7u8; 32]);
+        let c = Cipher::generate().unwrap();
+        let wrapped = c.wrap(&master).unwrap();
+        let c = Cipher::unwrap(&wrapped, &master).unwrap();
+        Cipher::unwrap(&wrapped, &MasterKey([8u8; 32])).unwrap_err();
+
+        // Encrypting in pieces should match encrypting all at once, with a tag after each chunk.
+        let id = CompositeId::new(1, 2);
+        let len = 2 * CHUNK_LEN as usize + 100;
+        let plain: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let mut whole = Vec::new();
+        let mut e = c.encrypter(id, 3).unwrap();
+        e.update(&plain, &mut whole).unwrap();
+        e.seal(&mut whole).unwrap();
+        assert_eq!(whole.len(), len + 3 * TAG_LEN as usize);
+        assert_eq!(Cipher::plaintext_len(whole.len() as u64), len as u64);
+        let mut pieces = Vec::new();
+        let mut e = c.encrypter(id, 3).unwrap();
+        e.update(&plain[..7], &mut pieces).unwrap();
+        e.update(&plain[7..CHUNK_LEN as usize + 33], &mut pieces).unwrap();
+        e.update(&plain[CHUNK_LEN as usize + 33..], &mut pieces).unwrap();
+        e.seal(&mut pieces).unwrap();
+        assert_eq!(whole, pieces);
+
+        // Any range should decrypt to the plaintext.
+        let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().join("f");
+        fs::write(&path, &whole).unwrap();
+        let f = fs::File::open(&path).unwrap();
+        let chunk = CHUNK_LEN as usize;
+        for r in &[0 .. len, 21 .. 50, chunk - 10 .. chunk + 10, 2 * chunk .. len, 5 .. 5] {
+            assert_eq!(c.read(&f, id, 3, r.start as u64 .. r.end as u64, false).unwrap(),
+                       &plain[r.clone()]);
+        }
+
+        // The wrong open id, or an altered chunk, fails authentication; other chunks still read.
+        c.read(&f, id, 4, 0 .. 10, false).unwrap_err();
+        let mut altered = whole.clone();
+        altered[chunk + TAG_LEN as usize + 3] ^= 1;
+        fs::write(&path, &altered).unwrap();
+        let f = fs::File::open(&path).unwrap();
+        c.read(&f, id, 3, chunk as u64 .. chunk as u64 + 1, false).unwrap_err();
+        assert_eq!(c.read(&f, id, 3, 0 .. 10, false).unwrap(), &plain[0 .. 10]);
+
+        // A growing file's unsealed last chunk can be read only as such.
+        fs::write(&path, &whole[.. whole.len() - TAG_LEN as usize]).unwrap();
+        let f = fs::File::open(&path).unwrap();
+        let r = 2 * CHUNK_LEN .. len as u64;
+        c.read(&f, id, 3, r.clone(), false).unwrap_err();
+        assert_eq!(c.read(&f, id, 3, r, true).unwrap(), &plain[2 * chunk ..]);
+    }
+
+    #[test]
+    fn buffered_write() {
+        use db::CompositeId;
+        use std::fs;
+        use super::{Cipher, SampleFileWriter, TAG_LEN};
+        use tempdir::TempDir;
+        let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
+        let c = Cipher::generate().unwrap();
+        let id = CompositeId::new(1, 2);
+        let path = tmpdir.path().join("f");
+        let mut w = SampleFileWriter {
+            f: fs::File::create(&path).unwrap(),
+            encrypter: Some(c.encrypter(id, 3).unwrap()),
+            sealed: false,
+            write_size: Some(4),
+            buf: Vec::new(),
+            sync_needed: true,
+        };
+        let plain: Vec<u8> = (0..10u8).collect();
+        assert_eq!(w.write(&plain[..3]).unwrap(), 3);
+        assert_eq!(w.write(&plain[3..]).unwrap(), 1);  // fills the first piece.
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        assert_eq!(w.write(&plain[4..]).unwrap(), 4);  // writes the first piece.
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4);
+        assert_eq!(w.write(&plain[8..]).unwrap(), 2);
+        w.sync_all().unwrap();  // seals the chunk.
+        assert_eq!(fs::metadata(&path).unwrap().len(), 10 + TAG_LEN);
+        w.write(&plain[..1]).unwrap_err();
+        let f = fs::File::open(&path).unwrap();
+        assert_eq!(c.read(&f, id, 3, 0 .. 10, false).unwrap(), plain);
+    }
+
+    #[test]
+    fn parse_lease() {
+        use super::Lease;
+        use uuid::Uuid;
+        let l = Lease::parse("c9e0a3d5-3f3c-4a6c-8c5b-2b7a1e0c9d1f 1530000000\n").unwrap();
+        assert_eq!(l, Lease {
+            open_uuid: Uuid::parse_str("c9e0a3d5-3f3c-4a6c-8c5b-2b7a1e0c9d1f").unwrap(),
+            expires_sec: 1530000000,
+        });
+        Lease::parse("").unwrap_err();
+        Lease::parse("c9e0a3d5-3f3c-4a6c-8c5b-2b7a1e0c9d1f").unwrap_err();
+        Lease::parse("c9e0a3d5-3f3c-4a6c-8c5b-2b7a1e0c9d1f 1 2").unwrap_err();
+    }
 }
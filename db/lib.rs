@@ -32,6 +32,7 @@
 
 #[macro_use] extern crate failure;
 extern crate fnv;
+#[cfg(windows)] extern crate fs2;
 #[macro_use] extern crate lazy_static;
 extern crate libc;
 #[macro_use] extern crate log;
@@ -47,15 +48,19 @@ extern crate tempdir;
 extern crate time;
 extern crate uuid;
 
+pub mod chain;
 pub mod check;
 mod coding;
 pub mod db;
 pub mod dir;
+pub mod mirror;
+mod platform;
 mod raw;
 pub mod recording;
 mod schema;
 pub mod upgrade;
 pub mod writer;
+mod zfs;
 
 // This is only for #[cfg(test)], but it's also used by the dependent crate, and it appears that
 // #[cfg(test)] is not passed on to dependencies.
@@ -0,0 +1,201 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Mirroring of streams' recordings to a second sample file directory.
+//!
+//! A stream with a `mirror_sample_file_dir_id` has each recording copied there by a background
+//! thread once it's committed, so that losing one disk doesn't lose the stream's footage. The
+//! copies are made through a bounded queue rather than by the writer itself, so a slow or failing
+//! mirror never delays recording to the primary directory; if the queue fills, recordings are
+//! skipped (with a warning) rather than mirrored late. Mirror files are deleted as retention
+//! deletes the primary's.
+//!
+//! A mirror directory holds files under the same names as the primary, decrypted and re-encrypted
+//! with the mirror's key if either is encrypted. If the primary disk fails, the stream can be
+//! pointed at the mirror (after clearing its recordings' missing files, as `moonfire-nvr check`
+//! reports) to recover.
+
+use db::{self, CompositeId};
+use dir;
+use failure::Error;
+use fnv::FnvHashMap;
+use platform;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::sync::{Arc, mpsc};
+use std::thread;
+
+/// The maximum number of flushes' worth of recordings to queue for mirroring.
+const QUEUE_LEN: usize = 64;
+
+struct Message {
+    stream_id: i32,
+    ids: Range<i32>,
+}
+
+struct Mirror {
+    db: Arc<db::Database>,
+
+    /// For each stream, the recording id before which mirror files are known to be deleted.
+    trimmed_to: FnvHashMap<i32, i32>,
+}
+
+/// Starts mirroring streams' recordings as they're committed. The database must be read-write,
+/// with directories opened (as by `LockedDatabase::open_sample_file_dirs`) for all streams which
+/// have mirrors, including the mirror directories.
+pub fn start(db: Arc<db::Database>) -> Result<(), Error> {
+    let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+    db.lock().watch(Box::new(move |db, c| {
+        if let db::Change::RecordingsAdded { stream_id, count } = *c {
+            let s = match db.streams_by_id().get(&stream_id) {
+                Some(s) if s.mirror_sample_file_dir_id.is_some() => s,
+                _ => return,
+            };
+            let ids = s.next_recording_id - count as i32 .. s.next_recording_id;
+            if let Err(mpsc::TrySendError::Full(m)) = tx.try_send(Message { stream_id, ids }) {
+                warn!("mirror: queue is full; not mirroring stream {} recordings {:?}",
+                      m.stream_id, m.ids);
+            }
+        }
+    }));
+    let mut m = Mirror {
+        db,
+        trimmed_to: FnvHashMap::default(),
+    };
+    thread::Builder::new()
+        .name("mirror".to_owned())
+        .spawn(move || {
+            while let Ok(msg) = rx.recv() {
+                if let Err(e) = m.copy(msg.stream_id, msg.ids.clone()) {
+                    warn!("mirror: unable to mirror stream {} recordings {:?}: {}",
+                          msg.stream_id, msg.ids, e);
+                }
+                if let Err(e) = m.trim(msg.stream_id) {
+                    warn!("mirror: unable to trim stream {}: {}", msg.stream_id, e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+impl Mirror {
+    /// Returns the stream's primary and mirror directories, or `None` if it's no longer mirrored.
+    fn dirs(&self, stream_id: i32)
+            -> Result<Option<(Arc<dir::SampleFileDir>, Arc<dir::SampleFileDir>, String)>, Error> {
+        let l = self.db.lock();
+        let s = l.streams_by_id().get(&stream_id)
+                 .ok_or_else(|| format_err!("no stream {}", stream_id))?;
+        let (p, m) = match (s.sample_file_dir_id, s.mirror_sample_file_dir_id) {
+            (Some(p), Some(m)) => (p, m),
+            _ => return Ok(None),
+        };
+        let dirs = l.sample_file_dirs_by_id();
+        let m = &dirs[&m];
+        Ok(Some((dirs[&p].get()?, m.get()?, m.path.clone())))
+    }
+
+    /// Copies the given recordings from the primary directory to the mirror.
+    fn copy(&mut self, stream_id: i32, ids: Range<i32>) -> Result<(), Error> {
+        let (src, dst, _) = match self.dirs(stream_id)? {
+            None => return Ok(()),
+            Some(d) => d,
+        };
+        let mut rows = Vec::new();
+        self.db.lock().list_recordings_by_id(stream_id, ids, &mut |r| {
+            rows.push((r.id, r.open_id, r.sample_file_bytes));
+            Ok(())
+        })?;
+        for (id, open_id, bytes) in rows {
+            let f = src.open_file(id)?;
+            let buf = match src.cipher() {
+                None => {
+                    let mut buf = Vec::with_capacity(bytes as usize);
+                    (&f).read_to_end(&mut buf)?;
+                    buf
+                },
+                Some(c) => c.read(&f, id, open_id, 0 .. bytes as u64, false)?,
+            };
+            let mut f = match dst.create_file(id) {
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                r => r?,
+            };
+            let mut remaining = &buf[..];
+            while !remaining.is_empty() {
+                let n = f.write(remaining)?;
+                remaining = &remaining[n..];
+            }
+            f.sync_all()?;
+        }
+        dst.sync()?;
+        Ok(())
+    }
+
+    /// Deletes mirror files of recordings which retention has deleted from the primary.
+    fn trim(&mut self, stream_id: i32) -> Result<(), Error> {
+        let (_, dst, path) = match self.dirs(stream_id)? {
+            None => return Ok(()),
+            Some(d) => d,
+        };
+        let oldest = {
+            let l = self.db.lock();
+            match l.oldest_recording_id(stream_id)? {
+                Some(id) => id.recording(),
+                None => l.streams_by_id()[&stream_id].next_recording_id,
+            }
+        };
+        let to_unlink: Vec<CompositeId> = match self.trimmed_to.get(&stream_id) {
+            Some(&t) => (t .. oldest).map(|r| CompositeId::new(stream_id, r)).collect(),
+            None => {
+                // The first time, scan the directory for stale files.
+                let mut v = Vec::new();
+                for e in ::std::fs::read_dir(&path)? {
+                    let e = e?;
+                    if let Ok(id) = dir::parse_id(platform::name_bytes(&e.file_name())) {
+                        if id.stream() == stream_id && id.recording() < oldest {
+                            v.push(id);
+                        }
+                    }
+                }
+                v
+            },
+        };
+        for &id in &to_unlink {
+            match dst.unlink_file(id) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                r => r?,
+            }
+        }
+        if !to_unlink.is_empty() {
+            dst.sync()?;
+        }
+        self.trimmed_to.insert(stream_id, oldest);
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Platform-specific filesystem operations.
+//!
+//! Sample file directories and the database directory are accessed through an `Fd`, a handle to
+//! the directory which can lock it and open, rename, and unlink the files within it. Unix systems
+//! (including macOS) implement it with a directory file descriptor and the `*at` system calls.
+//! Windows has no equivalent, so there it holds the directory's path and joins it with each file
+//! name.
+
+#[cfg(unix)] mod unix;
+#[cfg(windows)] mod windows;
+
+#[cfg(unix)] pub use self::unix::{Fd, name_bytes};
+#[cfg(windows)] pub use self::windows::{Fd, name_bytes};
+
+/// How `Fd::open_file` should open a file.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FileMode {
+    /// Opens an existing file for reading.
+    Read,
+
+    /// Creates a new file for writing, failing if it already exists.
+    CreateExclusive,
+
+    /// Creates a file for writing, truncating it if it already exists.
+    CreateTruncate,
+}
+
+/// The kind of lock taken by `Fd::lock`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// Statistics about the filesystem holding a directory, as returned by `Fd::fs_stats`.
+#[derive(Copy, Clone, Debug)]
+pub struct FsStats {
+    /// The bytes available to unprivileged users.
+    pub available_bytes: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use super::{Fd, FileMode, LockMode};
+    use tempdir::TempDir;
+
+    #[test]
+    fn file_ops() {
+        let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().join("dir");
+        let fd = Fd::open(path.to_str().unwrap(), true).unwrap();
+        fd.lock(LockMode::Exclusive).unwrap();
+        fd.open_file("a", FileMode::CreateExclusive).unwrap().write_all(b"foo").unwrap();
+        fd.open_file("a", FileMode::CreateExclusive).unwrap_err();
+        fd.rename("a", "b").unwrap();
+        fd.sync().unwrap();
+        let mut data = String::new();
+        fd.open_file("b", FileMode::Read).unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "foo");
+        fd.open_file("b", FileMode::CreateTruncate).unwrap();
+        assert_eq!(fd.open_file("b", FileMode::Read).unwrap().metadata().unwrap().len(), 0);
+        fd.unlink("b").unwrap();
+        fd.open_file("b", FileMode::Read).unwrap_err();
+        assert!(fd.fs_stats().unwrap().available_bytes > 0);
+    }
+}
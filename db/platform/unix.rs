@@ -0,0 +1,184 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Unix (including macOS) implementation of `Fd`.
+
+use libc;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use super::{FileMode, FsStats, LockMode};
+
+/// A file descriptor associated with a directory (not necessarily the sample file dir).
+#[derive(Debug)]
+pub struct Fd(libc::c_int);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        if unsafe { libc::close(self.0) } < 0 {
+            let e = io::Error::last_os_error();
+            warn!("Unable to close sample file dir: {}", e);
+        }
+    }
+}
+
+fn cstring(p: &str) -> Result<CString, io::Error> {
+    CString::new(p).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+impl Fd {
+    /// Opens the given path as a directory.
+    pub fn open(path: &str, mkdir: bool) -> Result<Fd, io::Error> {
+        let cstring = cstring(path)?;
+        if mkdir && unsafe { libc::mkdir(cstring.as_ptr(), 0o700) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e.into());
+            }
+        }
+        let fd = unsafe { libc::open(cstring.as_ptr(), libc::O_DIRECTORY | libc::O_RDONLY, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Fd(fd))
+    }
+
+    /// Syncs the directory itself. On macOS, `fsync` doesn't flush the drive's write cache, so
+    /// this uses `F_FULLFSYNC` instead, as `std::fs::File::sync_all` does.
+    pub fn sync(&self) -> Result<(), io::Error> {
+        #[cfg(target_os = "macos")]
+        let res = unsafe { libc::fcntl(self.0, libc::F_FULLFSYNC) };
+        #[cfg(not(target_os = "macos"))]
+        let res = unsafe { libc::fsync(self.0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    /// Opens a file within this directory, creating it with mode `0600` if requested.
+    pub fn open_file(&self, name: &str, mode: FileMode) -> Result<fs::File, io::Error> {
+        let flags = match mode {
+            FileMode::Read => libc::O_RDONLY,
+            FileMode::CreateExclusive => libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+            FileMode::CreateTruncate => libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+        };
+        let name = cstring(name)?;
+        let fd = unsafe { libc::openat(self.0, name.as_ptr(), flags, 0o600) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+
+    /// Locks the directory with a non-blocking `flock`.
+    pub fn lock(&self, mode: LockMode) -> Result<(), io::Error> {
+        let operation = match mode {
+            LockMode::Shared => libc::LOCK_SH,
+            LockMode::Exclusive => libc::LOCK_EX,
+        };
+        let ret = unsafe { libc::flock(self.0, operation | libc::LOCK_NB) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Atomically renames `from` to `to` within this directory, replacing any existing `to`.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        let (from, to) = (cstring(from)?, cstring(to)?);
+        if unsafe { libc::renameat(self.0, from.as_ptr(), self.0, to.as_ptr()) } < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    /// Unlinks the given file within this directory.
+    pub fn unlink(&self, name: &str) -> Result<(), io::Error> {
+        let name = cstring(name)?;
+        if unsafe { libc::unlinkat(self.0, name.as_ptr(), 0) } < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    /// Returns true iff the directory is on a ZFS filesystem.
+    #[cfg(target_os = "linux")]
+    pub fn is_zfs(&self) -> Result<bool, io::Error> {
+        const ZFS_SUPER_MAGIC: i64 = 0x2fc12fc2;
+        let stat = unsafe {
+            let mut stat: libc::statfs = mem::zeroed();
+            if libc::fstatfs(self.0, &mut stat) < 0 {
+                return Err(io::Error::last_os_error())
+            }
+            stat
+        };
+        Ok(stat.f_type as i64 == ZFS_SUPER_MAGIC)
+    }
+
+    /// Returns true iff the directory is on a ZFS filesystem.
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    pub fn is_zfs(&self) -> Result<bool, io::Error> {
+        let stat = unsafe {
+            let mut stat: libc::statfs = mem::zeroed();
+            if libc::fstatfs(self.0, &mut stat) < 0 {
+                return Err(io::Error::last_os_error())
+            }
+            stat
+        };
+        let name: Vec<u8> = stat.f_fstypename.iter().take_while(|&&c| c != 0).map(|&c| c as u8)
+                                .collect();
+        Ok(name == b"zfs")
+    }
+
+    /// Returns true iff the directory is on a ZFS filesystem.
+    #[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "macos")))]
+    pub fn is_zfs(&self) -> Result<bool, io::Error> { Ok(false) }
+
+    pub fn fs_stats(&self) -> Result<FsStats, io::Error> {
+        let stat = unsafe {
+            let mut stat: libc::statvfs = mem::zeroed();
+            if libc::fstatvfs(self.0, &mut stat) < 0 {
+                return Err(io::Error::last_os_error())
+            }
+            stat
+        };
+
+        // `f_bavail` is in units of `f_frsize`. On Linux this is usually the same as `f_bsize`,
+        // but on macOS `f_bsize` is the (much larger) preferred I/O size.
+        Ok(FsStats { available_bytes: stat.f_frsize as i64 * stat.f_bavail as i64 })
+    }
+}
+
+/// Returns the bytes of a file name, for matching against known names.
+pub fn name_bytes(name: &OsStr) -> &[u8] { name.as_bytes() }
@@ -0,0 +1,126 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Windows implementation of `Fd`.
+
+use fs2::{self, FileExt};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::windows::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use super::{FileMode, FsStats, LockMode};
+
+/// `FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE`. Opening files with this sharing mode
+/// matches Unix semantics; in particular, the syncer can unlink a sample file while it's being
+/// served.
+const SHARE_ALL: u32 = 0x1 | 0x2 | 0x4;
+
+/// A directory (not necessarily the sample file dir). Windows can't open files relative to a
+/// directory handle, so this holds the path. Locks are taken on a `lock` file within it.
+#[derive(Debug)]
+pub struct Fd {
+    path: PathBuf,
+
+    /// The locked `lock` file, if any. The lock is released when it's closed.
+    lock_file: Mutex<Option<fs::File>>,
+}
+
+impl Fd {
+    /// Opens the given path as a directory.
+    pub fn open(path: &str, mkdir: bool) -> Result<Fd, io::Error> {
+        if mkdir {
+            match fs::create_dir(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+                r => r?,
+            }
+        }
+        if !fs::metadata(path)?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("{} is not a directory", path)));
+        }
+        Ok(Fd {
+            path: PathBuf::from(path),
+            lock_file: Mutex::new(None),
+        })
+    }
+
+    /// Windows has no way to sync a directory; NTFS journals directory operations itself.
+    pub fn sync(&self) -> Result<(), io::Error> { Ok(()) }
+
+    /// Opens a file within this directory.
+    pub fn open_file(&self, name: &str, mode: FileMode) -> Result<fs::File, io::Error> {
+        let mut o = fs::OpenOptions::new();
+        o.share_mode(SHARE_ALL);
+        match mode {
+            FileMode::Read => o.read(true),
+            FileMode::CreateExclusive => o.write(true).create_new(true),
+            FileMode::CreateTruncate => o.write(true).create(true).truncate(true),
+        };
+        o.open(self.path.join(name))
+    }
+
+    /// Locks the directory by taking a non-blocking `LockFileEx` lock on its `lock` file.
+    pub fn lock(&self, mode: LockMode) -> Result<(), io::Error> {
+        let f = fs::OpenOptions::new().read(true).write(true).create(true).share_mode(SHARE_ALL)
+                                      .open(self.path.join("lock"))?;
+        match mode {
+            LockMode::Shared => f.try_lock_shared()?,
+            LockMode::Exclusive => f.try_lock_exclusive()?,
+        }
+        *self.lock_file.lock().unwrap() = Some(f);
+        Ok(())
+    }
+
+    /// Renames `from` to `to` within this directory, replacing any existing `to`.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        fs::rename(self.path.join(from), self.path.join(to))
+    }
+
+    /// Deletes the given file within this directory. If it's open elsewhere, its name remains
+    /// until it's closed; that's fine as sample file names are never reused.
+    pub fn unlink(&self, name: &str) -> Result<(), io::Error> {
+        fs::remove_file(self.path.join(name))
+    }
+
+    /// Returns true iff the directory is on a ZFS filesystem, which is never the case on Windows.
+    pub fn is_zfs(&self) -> Result<bool, io::Error> { Ok(false) }
+
+    pub fn fs_stats(&self) -> Result<FsStats, io::Error> {
+        Ok(FsStats { available_bytes: fs2::available_space(&self.path)? as i64 })
+    }
+}
+
+/// Returns the bytes of a file name, for matching against known names. Names which aren't valid
+/// Unicode (and thus can't be any of Moonfire NVR's own files) are returned as empty.
+pub fn name_bytes(name: &OsStr) -> &[u8] {
+    name.to_str().map(str::as_bytes).unwrap_or(b"")
+}
@@ -30,9 +30,10 @@
 
 //! Raw database access: SQLite statements which do not touch any cached state.
 
+use chain;
 use db::{self, CompositeId, FromSqlUuid};
 use failure::{Error, ResultExt};
-use fnv::FnvHashSet;
+use fnv::{FnvHashMap, FnvHashSet};
 use recording;
 use rusqlite::{self, types::ToSql};
 use std::ops::Range;
@@ -50,7 +51,9 @@ const LIST_RECORDINGS_BY_TIME_SQL: &'static str = r#"
         recording.video_samples,
         recording.video_sync_samples,
         recording.video_sample_entry_id,
-        recording.open_id
+        recording.open_id,
+        recording.min_frame_interval_90k,
+        recording.max_frame_interval_90k
     from
         recording
     where
@@ -73,7 +76,9 @@ const LIST_RECORDINGS_BY_ID_SQL: &'static str = r#"
         recording.video_samples,
         recording.video_sync_samples,
         recording.video_sample_entry_id,
-        recording.open_id
+        recording.open_id,
+        recording.min_frame_interval_90k,
+        recording.max_frame_interval_90k
     from
         recording
     where
@@ -114,7 +119,8 @@ const LIST_OLDEST_RECORDINGS_SQL: &'static str = r#"
       recording
     where
       :start <= composite_id and
-      composite_id < :end
+      composite_id < :end and
+      (flags & :archived) = 0  -- archived recordings aren't subject to retention.
     order by
       composite_id
 "#;
@@ -160,6 +166,8 @@ fn list_recordings_inner(mut rows: rusqlite::Rows,
             video_sync_samples: row.get_checked(7)?,
             video_sample_entry_id: row.get_checked(8)?,
             open_id: row.get_checked(9)?,
+            min_frame_interval_90k: row.get_checked(10)?,
+            max_frame_interval_90k: row.get_checked(11)?,
         })?;
     }
     Ok(())
@@ -174,15 +182,17 @@ pub(crate) fn get_db_uuid(conn: &rusqlite::Connection) -> Result<Uuid, Error> {
 
 /// Inserts the specified recording (for from `try_flush` only).
 pub(crate) fn insert_recording(tx: &rusqlite::Transaction, o: &db::Open, id: CompositeId,
-                    r: &db::RecordingToInsert) -> Result<(), Error> {
+                    r: &db::RecordingToInsert, chain_sha1: &[u8; 20]) -> Result<(), Error> {
     let mut stmt = tx.prepare_cached(r#"
         insert into recording (composite_id, stream_id, open_id, run_offset, flags,
                                sample_file_bytes, start_time_90k, duration_90k,
-                               video_samples, video_sync_samples, video_sample_entry_id)
+                               video_samples, video_sync_samples, video_sample_entry_id,
+                               min_frame_interval_90k, max_frame_interval_90k)
                        values (:composite_id, :stream_id, :open_id, :run_offset, :flags,
                                :sample_file_bytes, :start_time_90k, :duration_90k,
                                :video_samples, :video_sync_samples,
-                               :video_sample_entry_id)
+                               :video_sample_entry_id, :min_frame_interval_90k,
+                               :max_frame_interval_90k)
     "#).with_context(|e| format!("can't prepare recording insert: {}", e))?;
     stmt.execute_named(&[
         (":composite_id", &id.0),
@@ -196,11 +206,15 @@ pub(crate) fn insert_recording(tx: &rusqlite::Transaction, o: &db::Open, id: Com
         (":video_samples", &r.video_samples),
         (":video_sync_samples", &r.video_sync_samples),
         (":video_sample_entry_id", &r.video_sample_entry_id),
+        (":min_frame_interval_90k", &r.min_frame_interval_90k),
+        (":max_frame_interval_90k", &r.max_frame_interval_90k),
     ]).with_context(|e| format!("unable to insert recording for {:#?}: {}", r, e))?;
 
     let mut stmt = tx.prepare_cached(r#"
-        insert into recording_integrity (composite_id,  local_time_delta_90k,  sample_file_sha1)
-                                 values (:composite_id, :local_time_delta_90k, :sample_file_sha1)
+        insert into recording_integrity (composite_id,  local_time_delta_90k,  sample_file_sha1,
+                                         chain_sha1)
+                                 values (:composite_id, :local_time_delta_90k, :sample_file_sha1,
+                                         :chain_sha1)
     "#).with_context(|e| format!("can't prepare recording_integrity insert: {}", e))?;
     let sha1 = &r.sample_file_sha1[..];
     let delta = match r.run_offset {
@@ -211,6 +225,7 @@ pub(crate) fn insert_recording(tx: &rusqlite::Transaction, o: &db::Open, id: Com
         (":composite_id", &id.0),
         (":local_time_delta_90k", &delta),
         (":sample_file_sha1", &sha1),
+        (":chain_sha1", &&chain_sha1[..]),
     ]).with_context(|e| format!("unable to insert recording_integrity for {:#?}: {}", r, e))?;
 
     let mut stmt = tx.prepare_cached(r#"
@@ -222,11 +237,106 @@ pub(crate) fn insert_recording(tx: &rusqlite::Transaction, o: &db::Open, id: Com
         (":video_index", &r.video_index),
     ]).with_context(|e| format!("unable to insert recording_playback for {:#?}: {}", r, e))?;
 
+    if !r.metadata.is_empty() {
+        let mut stmt = tx.prepare_cached(r#"
+            insert into recording_metadata (composite_id,  metadata)
+                                    values (:composite_id, :metadata)
+        "#).with_context(|e| format!("can't prepare recording_metadata insert: {}", e))?;
+        stmt.execute_named(&[
+            (":composite_id", &id.0),
+            (":metadata", &r.metadata),
+        ]).with_context(|e| format!("unable to insert recording_metadata for {}: {}", id, e))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the given recording's metadata sidecar, or an empty vector if it has none.
+pub(crate) fn get_recording_metadata(conn: &rusqlite::Connection, id: CompositeId)
+                                     -> Result<Vec<u8>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select metadata from recording_metadata where composite_id = :composite_id
+    "#)?;
+    let mut rows = stmt.query_named(&[(":composite_id", &id.0)])?;
+    if let Some(row) = rows.next() {
+        return Ok(row?.get_checked(0)?);
+    }
+    Ok(Vec::new())
+}
+
+/// Inserts an anchor of a stream's hash chain.
+pub(crate) fn insert_chain_anchor(tx: &rusqlite::Transaction, a: &chain::Anchor)
+                                  -> Result<(), Error> {
+    let mut stmt = tx.prepare_cached(r#"
+        insert into chain_anchor (stream_id,  composite_id,  chain_sha1,  time_sec)
+                          values (:stream_id, :composite_id, :chain_sha1, :time_sec)
+    "#)?;
+    stmt.execute_named(&[
+        (":stream_id", &a.id.stream()),
+        (":composite_id", &a.id.0),
+        (":chain_sha1", &&a.chain_sha1[..]),
+        (":time_sec", &a.time_sec),
+    ])?;
     Ok(())
 }
 
+/// Lists the anchors of the given stream's hash chain, in ascending order.
+pub(crate) fn list_chain_anchors(conn: &rusqlite::Connection, stream_id: i32)
+                                 -> Result<Vec<chain::Anchor>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select composite_id, chain_sha1, time_sec from chain_anchor
+        where stream_id = :stream_id order by composite_id
+    "#)?;
+    let mut rows = stmt.query_named(&[(":stream_id", &stream_id)])?;
+    let mut anchors = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        anchors.push(chain::Anchor {
+            id: CompositeId(row.get_checked(0)?),
+            chain_sha1: sha1_from_blob(row.get_checked(1)?)?,
+            time_sec: row.get_checked(2)?,
+        });
+    }
+    Ok(anchors)
+}
+
+/// Lists each stream's most recent hash chain anchor.
+pub(crate) fn list_last_chain_anchors(conn: &rusqlite::Connection)
+                                      -> Result<Vec<(i32, chain::Anchor)>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          a.stream_id, a.composite_id, a.chain_sha1, a.time_sec
+        from
+          chain_anchor a
+        where
+          a.composite_id = (select max(composite_id) from chain_anchor b
+                            where b.stream_id = a.stream_id)
+    "#)?;
+    let mut rows = stmt.query(&[] as &[&ToSql])?;
+    let mut anchors = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        anchors.push((row.get_checked(0)?, chain::Anchor {
+            id: CompositeId(row.get_checked(1)?),
+            chain_sha1: sha1_from_blob(row.get_checked(2)?)?,
+            time_sec: row.get_checked(3)?,
+        }));
+    }
+    Ok(anchors)
+}
+
+pub(crate) fn sha1_from_blob(b: Vec<u8>) -> Result<[u8; 20], Error> {
+    if b.len() != 20 {
+        bail!("expected 20-byte SHA-1, got {} bytes", b.len());
+    }
+    let mut sha1 = [0u8; 20];
+    sha1.copy_from_slice(&b);
+    Ok(sha1)
+}
+
 /// Tranfers the given recording range from the `recording` and `recording_playback` tables to the
-/// `garbage` table. `sample_file_dir_id` is assumed to be correct.
+/// `garbage` table, deleting any metadata sidecars. `sample_file_dir_id` is assumed to be correct.
+/// Archived recordings within the range are skipped; their sample files are already gone.
 ///
 /// Returns the number of recordings which were deleted.
 pub(crate) fn delete_recordings(tx: &rusqlite::Transaction, sample_file_dir_id: i32,
@@ -241,34 +351,48 @@ pub(crate) fn delete_recordings(tx: &rusqlite::Transaction, sample_file_dir_id:
           recording
         where
           :start <= composite_id and
-          composite_id < :end
+          composite_id < :end and
+          (flags & :archived) = 0
     "#)?;
     let mut del1 = tx.prepare_cached(r#"
         delete from recording_playback
         where
-          :start <= composite_id and
-          composite_id < :end
+          composite_id in (select composite_id from recording
+                           where :start <= composite_id and composite_id < :end and
+                                 (flags & :archived) = 0)
     "#)?;
     let mut del2 = tx.prepare_cached(r#"
         delete from recording_integrity
         where
-          :start <= composite_id and
-          composite_id < :end
+          composite_id in (select composite_id from recording
+                           where :start <= composite_id and composite_id < :end and
+                                 (flags & :archived) = 0)
+    "#)?;
+    let mut del_metadata = tx.prepare_cached(r#"
+        delete from recording_metadata
+        where
+          composite_id in (select composite_id from recording
+                           where :start <= composite_id and composite_id < :end and
+                                 (flags & :archived) = 0)
     "#)?;
     let mut del3 = tx.prepare_cached(r#"
         delete from recording
         where
           :start <= composite_id and
-          composite_id < :end
+          composite_id < :end and
+          (flags & :archived) = 0
     "#)?;
+    let archived = db::RecordingFlags::Archived as i32;
     let n = insert.execute_named(&[
         (":sample_file_dir_id", &sample_file_dir_id),
         (":start", &ids.start.0),
         (":end", &ids.end.0),
+        (":archived", &archived),
     ])?;
     let p: &[(&str, &rusqlite::types::ToSql)] = &[
         (":start", &ids.start.0),
         (":end", &ids.end.0),
+        (":archived", &archived),
     ];
     let n1 = del1.execute_named(p)?;
     if n1 != n {
@@ -278,6 +402,7 @@ pub(crate) fn delete_recordings(tx: &rusqlite::Transaction, sample_file_dir_id:
     if n2 > n {  // fewer is okay; recording_integrity is optional.
         bail!("inserted {} garbage rows but deleted {} recording_integrity rows!", n, n2);
     }
+    del_metadata.execute_named(p)?;
     let n3 = del3.execute_named(p)?;
     if n3 != n {
         bail!("deleted {} recording rows but {} recording_playback rows!", n3, n);
@@ -285,6 +410,55 @@ pub(crate) fn delete_recordings(tx: &rusqlite::Transaction, sample_file_dir_id:
     Ok(n)
 }
 
+/// Deletes the archived recordings in the given range, which have no sample files to collect as
+/// garbage. Returns the number deleted.
+pub(crate) fn delete_archived_recordings(tx: &rusqlite::Transaction, ids: Range<CompositeId>)
+                                         -> Result<usize, Error> {
+    let archived = db::RecordingFlags::Archived as i32;
+    let p: &[(&str, &rusqlite::types::ToSql)] = &[
+        (":start", &ids.start.0),
+        (":end", &ids.end.0),
+        (":archived", &archived),
+    ];
+    for table in &["recording_archive", "recording_playback", "recording_integrity",
+                   "recording_metadata"] {
+        tx.execute_named(&format!(r#"
+            delete from {}
+            where
+              composite_id in (select composite_id from recording
+                               where :start <= composite_id and composite_id < :end and
+                                     (flags & :archived) != 0)
+        "#, table), p)?;
+    }
+    Ok(tx.execute_named(r#"
+        delete from recording
+        where
+          :start <= composite_id and
+          composite_id < :end and
+          (flags & :archived) != 0
+    "#, p)?)
+}
+
+/// Deletes the given camera's events, along with their snapshots, detections, and incident items
+/// referring to them or to the camera itself. Returns the number of events deleted.
+pub(crate) fn delete_camera_events(tx: &rusqlite::Transaction, camera_id: i32)
+                                   -> Result<usize, Error> {
+    let p: &[(&str, &rusqlite::types::ToSql)] = &[(":camera_id", &camera_id)];
+    tx.execute_named(r#"
+        delete from incident_item
+        where
+          camera_id = :camera_id or
+          event_id in (select id from event where camera_id = :camera_id)
+    "#, p)?;
+    for table in &["event_snapshot", "event_detection"] {
+        tx.execute_named(&format!(r#"
+            delete from {}
+            where event_id in (select id from event where camera_id = :camera_id)
+        "#, table), p)?;
+    }
+    Ok(tx.execute_named("delete from event where camera_id = :camera_id", p)?)
+}
+
 /// Marks the given sample files as deleted. This shouldn't be called until the files have
 /// been `unlink()`ed and the parent directory `fsync()`ed.
 pub(crate) fn mark_sample_files_deleted(tx: &rusqlite::Transaction, ids: &[CompositeId])
@@ -368,6 +542,7 @@ pub(crate) fn list_oldest_recordings(conn: &rusqlite::Connection, start: Composi
     let mut rows = stmt.query_named(&[
         (":start", &start.0),
         (":end", &CompositeId::new(start.stream() + 1, 0).0),
+        (":archived", &(db::RecordingFlags::Archived as i32)),
     ])?;
     while let Some(row) = rows.next() {
         let row = row?;
@@ -383,3 +558,1023 @@ pub(crate) fn list_oldest_recordings(conn: &rusqlite::Connection, start: Composi
     }
     Ok(())
 }
+
+/// Inserts the specified event, returning its id.
+pub(crate) fn insert_event(conn: &rusqlite::Connection, e: &db::EventToInsert)
+                           -> Result<i64, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert into event (camera_id,  type,  start_time_90k,  end_time_90k,  description,  score)
+                   values (:camera_id, :type, :start_time_90k, :end_time_90k, :description, :score)
+    "#).with_context(|e| format!("can't prepare event insert: {}", e))?;
+    stmt.execute_named(&[
+        (":camera_id", &e.camera_id),
+        (":type", &e.type_),
+        (":start_time_90k", &e.time.start.0),
+        (":end_time_90k", &e.time.end.0),
+        (":description", &e.description),
+        (":score", &e.score),
+    ]).with_context(|err| format!("unable to insert event {:#?}: {}", e, err))?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Updates the time range and score of the given event.
+pub(crate) fn update_event(conn: &rusqlite::Connection, id: i64, e: &db::EventToInsert)
+                           -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        update event set
+            start_time_90k = :start_time_90k,
+            end_time_90k = :end_time_90k,
+            score = :score
+        where
+            id = :id
+    "#)?;
+    let rows = stmt.execute_named(&[
+        (":start_time_90k", &e.time.start.0),
+        (":end_time_90k", &e.time.end.0),
+        (":score", &e.score),
+        (":id", &id),
+    ])?;
+    if rows != 1 {
+        bail!("no such event {}", id);
+    }
+    Ok(())
+}
+
+/// Inserts a snapshot of the given event, replacing any existing one.
+pub(crate) fn insert_event_snapshot(conn: &rusqlite::Connection, event_id: i64, jpeg: &[u8])
+                                    -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert or replace into event_snapshot (event_id,  jpeg)
+                                       values (:event_id, :jpeg)
+    "#)?;
+    stmt.execute_named(&[
+        (":event_id", &event_id),
+        (":jpeg", &jpeg),
+    ])?;
+    Ok(())
+}
+
+/// Inserts an object detected within the given event.
+pub(crate) fn insert_event_detection(conn: &rusqlite::Connection, event_id: i64,
+                                     d: &db::Detection) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert into event_detection (event_id,  label,  score,  x,  y,  width,  height)
+                             values (:event_id, :label, :score, :x, :y, :width, :height)
+    "#)?;
+    stmt.execute_named(&[
+        (":event_id", &event_id),
+        (":label", &d.label),
+        (":score", &d.score),
+        (":x", &d.x),
+        (":y", &d.y),
+        (":width", &d.width),
+        (":height", &d.height),
+    ])?;
+    Ok(())
+}
+
+/// Lists the objects detected within the given event, in the order they were added.
+pub(crate) fn list_event_detections(conn: &rusqlite::Connection, event_id: i64)
+                                    -> Result<Vec<db::Detection>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          label,
+          score,
+          x,
+          y,
+          width,
+          height
+        from
+          event_detection
+        where
+          event_id = :event_id
+        order by
+          rowid
+    "#)?;
+    let mut rows = stmt.query_named(&[(":event_id", &event_id)])?;
+    let mut detections = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        detections.push(db::Detection {
+            label: row.get_checked(0)?,
+            score: row.get_checked(1)?,
+            x: row.get_checked(2)?,
+            y: row.get_checked(3)?,
+            width: row.get_checked(4)?,
+            height: row.get_checked(5)?,
+        });
+    }
+    Ok(detections)
+}
+
+/// Gets the snapshot of the given event, if any.
+pub(crate) fn get_event_snapshot(conn: &rusqlite::Connection, event_id: i64)
+                                 -> Result<Option<Vec<u8>>, Error> {
+    let mut stmt = conn.prepare_cached(
+        "select jpeg from event_snapshot where event_id = :event_id")?;
+    let mut rows = stmt.query_named(&[(":event_id", &event_id)])?;
+    match rows.next() {
+        None => Ok(None),
+        Some(r) => Ok(Some(r?.get_checked(0)?)),
+    }
+}
+
+/// Inserts or replaces the given push subscription, keyed by endpoint.
+pub(crate) fn insert_push_subscription(conn: &rusqlite::Connection, s: &db::PushSubscription)
+                                       -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert or replace into push_subscription (endpoint,  p256dh,  auth)
+                                          values (:endpoint, :p256dh, :auth)
+    "#)?;
+    stmt.execute_named(&[
+        (":endpoint", &s.endpoint),
+        (":p256dh", &s.p256dh),
+        (":auth", &s.auth),
+    ])?;
+    Ok(())
+}
+
+/// Deletes the push subscription with the given endpoint, returning true iff it existed.
+pub(crate) fn delete_push_subscription(conn: &rusqlite::Connection, endpoint: &str)
+                                       -> Result<bool, Error> {
+    let mut stmt = conn.prepare_cached(
+        "delete from push_subscription where endpoint = :endpoint")?;
+    Ok(stmt.execute_named(&[(":endpoint", &endpoint)])? > 0)
+}
+
+/// Lists all push subscriptions.
+pub(crate) fn list_push_subscriptions(conn: &rusqlite::Connection)
+                                      -> Result<Vec<db::PushSubscription>, Error> {
+    let mut stmt = conn.prepare_cached("select endpoint, p256dh, auth from push_subscription")?;
+    let mut rows = stmt.query(&[] as &[&ToSql])?;
+    let mut subs = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        subs.push(db::PushSubscription {
+            endpoint: row.get_checked(0)?,
+            p256dh: row.get_checked(1)?,
+            auth: row.get_checked(2)?,
+        });
+    }
+    Ok(subs)
+}
+
+/// Inserts the given job, returning its id.
+pub(crate) fn insert_job(conn: &rusqlite::Connection, j: &db::Job) -> Result<i64, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert into job (uuid,  type,  params,  state,  created_sec,  finished_sec,  result)
+                 values (:uuid, :type, :params, :state, :created_sec, :finished_sec, :result)
+    "#)?;
+    let uuid = &j.uuid.as_bytes()[..];
+    stmt.execute_named(&[
+        (":uuid", &uuid),
+        (":type", &j.type_),
+        (":params", &j.params),
+        (":state", &(j.state as i32)),
+        (":created_sec", &j.created_sec),
+        (":finished_sec", &j.finished_sec),
+        (":result", &j.result),
+    ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Updates the state, finish time, and result of the given job.
+pub(crate) fn update_job(conn: &rusqlite::Connection, j: &db::Job) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        update job
+        set state = :state, finished_sec = :finished_sec, result = :result
+        where id = :id
+    "#)?;
+    let rows = stmt.execute_named(&[
+        (":state", &(j.state as i32)),
+        (":finished_sec", &j.finished_sec),
+        (":result", &j.result),
+        (":id", &j.id),
+    ])?;
+    if rows != 1 {
+        bail!("no such job {}", j.uuid);
+    }
+    Ok(())
+}
+
+/// Deletes the given job, returning true iff it existed.
+pub(crate) fn delete_job(conn: &rusqlite::Connection, id: i32) -> Result<bool, Error> {
+    let mut stmt = conn.prepare_cached("delete from job where id = :id")?;
+    Ok(stmt.execute_named(&[(":id", &id)])? > 0)
+}
+
+/// Lists all jobs, in ascending order by id.
+pub(crate) fn list_jobs(conn: &rusqlite::Connection) -> Result<Vec<db::Job>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          id,
+          uuid,
+          type,
+          params,
+          state,
+          created_sec,
+          finished_sec,
+          result
+        from
+          job
+        order by
+          id
+    "#)?;
+    let mut rows = stmt.query(&[] as &[&ToSql])?;
+    let mut jobs = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let uuid: FromSqlUuid = row.get_checked(1)?;
+        let state: i32 = row.get_checked(4)?;
+        jobs.push(db::Job {
+            id: row.get_checked(0)?,
+            uuid: uuid.0,
+            type_: row.get_checked(2)?,
+            params: row.get_checked(3)?,
+            state: db::JobState::parse(state)
+                       .ok_or_else(|| format_err!("job {} has bad state {}", uuid.0, state))?,
+            created_sec: row.get_checked(5)?,
+            finished_sec: row.get_checked(6)?,
+            result: row.get_checked(7)?,
+        });
+    }
+    Ok(jobs)
+}
+
+/// Inserts the given incident, returning its id.
+pub(crate) fn insert_incident(conn: &rusqlite::Connection, i: &db::Incident)
+                              -> Result<i64, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert into incident (uuid,  title,  description,  created_sec)
+                      values (:uuid, :title, :description, :created_sec)
+    "#)?;
+    let uuid = &i.uuid.as_bytes()[..];
+    stmt.execute_named(&[
+        (":uuid", &uuid),
+        (":title", &i.title),
+        (":description", &i.description),
+        (":created_sec", &i.created_sec),
+    ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Deletes the given incident and its items, returning true iff it existed.
+pub(crate) fn delete_incident(tx: &rusqlite::Transaction, id: i32) -> Result<bool, Error> {
+    let mut stmt = tx.prepare_cached("delete from incident_item where incident_id = :id")?;
+    stmt.execute_named(&[(":id", &id)])?;
+    let mut stmt = tx.prepare_cached("delete from incident where id = :id")?;
+    Ok(stmt.execute_named(&[(":id", &id)])? == 1)
+}
+
+/// Lists all incidents, in the order they were added.
+pub(crate) fn list_incidents(conn: &rusqlite::Connection) -> Result<Vec<db::Incident>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          id,
+          uuid,
+          title,
+          description,
+          created_sec
+        from
+          incident
+        order by
+          id
+    "#)?;
+    let mut rows = stmt.query(&[] as &[&ToSql])?;
+    let mut incidents = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let uuid: FromSqlUuid = row.get_checked(1)?;
+        incidents.push(db::Incident {
+            id: row.get_checked(0)?,
+            uuid: uuid.0,
+            title: row.get_checked(2)?,
+            description: row.get_checked(3)?,
+            created_sec: row.get_checked(4)?,
+        });
+    }
+    Ok(incidents)
+}
+
+/// Inserts an item into the given incident.
+pub(crate) fn insert_incident_item(conn: &rusqlite::Connection, incident_id: i32,
+                                   item: &db::IncidentItem) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert into incident_item (incident_id,  event_id,  note_id,  export_uuid,  camera_id,
+                                   start_time_90k,  end_time_90k)
+                           values (:incident_id, :event_id, :note_id, :export_uuid, :camera_id,
+                                   :start_time_90k, :end_time_90k)
+    "#)?;
+    let (mut event_id, mut note_id, mut export_uuid, mut camera_id, mut start, mut end) =
+        (None, None, None, None, None, None);
+    match *item {
+        db::IncidentItem::Event(id) => event_id = Some(id),
+        db::IncidentItem::Note(id) => note_id = Some(id),
+        db::IncidentItem::Export(ref u) => export_uuid = Some(&u.as_bytes()[..]),
+        db::IncidentItem::Range { camera_id: c, ref time } => {
+            camera_id = Some(c);
+            start = Some(time.start.0);
+            end = Some(time.end.0);
+        },
+    }
+    stmt.execute_named(&[
+        (":incident_id", &incident_id),
+        (":event_id", &event_id),
+        (":note_id", &note_id),
+        (":export_uuid", &export_uuid),
+        (":camera_id", &camera_id),
+        (":start_time_90k", &start),
+        (":end_time_90k", &end),
+    ])?;
+    Ok(())
+}
+
+/// Lists the items of the given incident, in the order they were added.
+pub(crate) fn list_incident_items(conn: &rusqlite::Connection, incident_id: i32)
+                                  -> Result<Vec<db::IncidentItem>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          event_id,
+          note_id,
+          export_uuid,
+          camera_id,
+          start_time_90k,
+          end_time_90k
+        from
+          incident_item
+        where
+          incident_id = :incident_id
+        order by
+          id
+    "#)?;
+    let mut rows = stmt.query_named(&[(":incident_id", &incident_id)])?;
+    let mut items = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let event_id: Option<i64> = row.get_checked(0)?;
+        let note_id: Option<i64> = row.get_checked(1)?;
+        let export_uuid: Option<FromSqlUuid> = row.get_checked(2)?;
+        let camera_id: Option<i32> = row.get_checked(3)?;
+        items.push(match (event_id, note_id, export_uuid, camera_id) {
+            (Some(id), _, _, _) => db::IncidentItem::Event(id),
+            (_, Some(id), _, _) => db::IncidentItem::Note(id),
+            (_, _, Some(u), _) => db::IncidentItem::Export(u.0),
+            (_, _, _, Some(camera_id)) => db::IncidentItem::Range {
+                camera_id,
+                time: recording::Time(row.get_checked(4)?) .. recording::Time(row.get_checked(5)?),
+            },
+            _ => bail!("incident {} has an empty item", incident_id),
+        });
+    }
+    Ok(items)
+}
+
+/// Returns true iff the given user exists.
+pub(crate) fn user_exists(conn: &rusqlite::Connection, id: i32) -> Result<bool, Error> {
+    let mut stmt = conn.prepare_cached("select 1 from user where id = :id")?;
+    let mut rows = stmt.query_named(&[(":id", &id)])?;
+    match rows.next() {
+        None => Ok(false),
+        Some(r) => { r?; Ok(true) },
+    }
+}
+
+/// Gets the given user by name.
+pub(crate) fn get_user(conn: &rusqlite::Connection, username: &str)
+                       -> Result<Option<db::User>, Error> {
+    let mut stmt = conn.prepare_cached(
+        "select id, tenant_id from user where username = :username")?;
+    let mut rows = stmt.query_named(&[(":username", &username)])?;
+    match rows.next() {
+        None => Ok(None),
+        Some(r) => {
+            let r = r?;
+            Ok(Some(db::User {
+                id: r.get_checked(0)?,
+                username: username.to_owned(),
+                tenant_id: r.get_checked(1)?,
+            }))
+        },
+    }
+}
+
+/// Lists the names of the given tenant's users, in order.
+pub(crate) fn list_tenant_users(conn: &rusqlite::Connection, tenant_id: i32)
+                                -> Result<Vec<String>, Error> {
+    let mut stmt = conn.prepare_cached(
+        "select username from user where tenant_id = :tenant_id order by username")?;
+    let mut rows = stmt.query_named(&[(":tenant_id", &tenant_id)])?;
+    let mut users = Vec::new();
+    while let Some(row) = rows.next() {
+        users.push(row?.get_checked(0)?);
+    }
+    Ok(users)
+}
+
+/// Lists the preferences of the given user, ordered by key.
+pub(crate) fn list_user_preferences(conn: &rusqlite::Connection, user_id: i32)
+                                    -> Result<Vec<db::UserPreference>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          key,
+          value,
+          version
+        from
+          user_preference
+        where
+          user_id = :user_id
+        order by
+          key
+    "#)?;
+    let mut rows = stmt.query_named(&[(":user_id", &user_id)])?;
+    let mut prefs = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        prefs.push(db::UserPreference {
+            key: row.get_checked(0)?,
+            value: row.get_checked(1)?,
+            version: row.get_checked(2)?,
+        });
+    }
+    Ok(prefs)
+}
+
+/// Returns the current version of the given preference, if it exists.
+pub(crate) fn get_user_preference_version(conn: &rusqlite::Connection, user_id: i32, key: &str)
+                                          -> Result<Option<i64>, Error> {
+    let mut stmt = conn.prepare_cached(
+        "select version from user_preference where user_id = :user_id and key = :key")?;
+    let mut rows = stmt.query_named(&[(":user_id", &user_id), (":key", &key)])?;
+    match rows.next() {
+        None => Ok(None),
+        Some(r) => Ok(Some(r?.get_checked(0)?)),
+    }
+}
+
+/// Inserts or replaces the given preference.
+pub(crate) fn set_user_preference(conn: &rusqlite::Connection, user_id: i32, key: &str,
+                                  value: &str, version: i64) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert or replace into user_preference (user_id,  key,  value,  version)
+                                        values (:user_id, :key, :value, :version)
+    "#)?;
+    stmt.execute_named(&[
+        (":user_id", &user_id),
+        (":key", &key),
+        (":value", &value),
+        (":version", &version),
+    ])?;
+    Ok(())
+}
+
+/// Inserts the given layout and its cells, returning its id.
+pub(crate) fn insert_layout(tx: &rusqlite::Transaction, l: &db::Layout) -> Result<i64, Error> {
+    let mut stmt = tx.prepare_cached(r#"
+        insert into layout (uuid,  name,  owner_user_id,  shared,  rows,  columns,  created_sec)
+                    values (:uuid, :name, :owner_user_id, :shared, :rows, :columns, :created_sec)
+    "#)?;
+    let uuid = &l.uuid.as_bytes()[..];
+    stmt.execute_named(&[
+        (":uuid", &uuid),
+        (":name", &l.name),
+        (":owner_user_id", &l.owner_user_id),
+        (":shared", &l.shared),
+        (":rows", &i32::from(l.rows)),
+        (":columns", &i32::from(l.columns)),
+        (":created_sec", &l.created_sec),
+    ])?;
+    let id = tx.last_insert_rowid();
+    let mut stmt = tx.prepare_cached(r#"
+        insert into layout_cell (layout_id,  position,  camera_uuid,  stream_type)
+                         values (:layout_id, :position, :camera_uuid, :stream_type)
+    "#)?;
+    for c in &l.cells {
+        let camera_uuid = &c.camera_uuid.as_bytes()[..];
+        stmt.execute_named(&[
+            (":layout_id", &id),
+            (":position", &i32::from(c.position)),
+            (":camera_uuid", &camera_uuid),
+            (":stream_type", &c.stream_type.as_str()),
+        ])?;
+    }
+    Ok(id)
+}
+
+/// Lists all layouts with their cells, in the order they were added.
+pub(crate) fn list_layouts(conn: &rusqlite::Connection) -> Result<Vec<db::Layout>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          id,
+          uuid,
+          name,
+          owner_user_id,
+          shared,
+          rows,
+          columns,
+          created_sec
+        from
+          layout
+        order by
+          id
+    "#)?;
+    let mut rows = stmt.query(&[] as &[&ToSql])?;
+    let mut layouts = Vec::new();
+    let mut by_id = FnvHashMap::default();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let uuid: FromSqlUuid = row.get_checked(1)?;
+        let id = row.get_checked(0)?;
+        by_id.insert(id, layouts.len());
+        layouts.push(db::Layout {
+            id,
+            uuid: uuid.0,
+            name: row.get_checked(2)?,
+            owner_user_id: row.get_checked(3)?,
+            shared: row.get_checked(4)?,
+            rows: row.get_checked::<_, i32>(5)? as u8,
+            columns: row.get_checked::<_, i32>(6)? as u8,
+            created_sec: row.get_checked(7)?,
+            cells: Vec::new(),
+        });
+    }
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          layout_id,
+          position,
+          camera_uuid,
+          stream_type
+        from
+          layout_cell
+        order by
+          layout_id,
+          position
+    "#)?;
+    let mut rows = stmt.query(&[] as &[&ToSql])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let layout_id: i32 = row.get_checked(0)?;
+        let i = *by_id.get(&layout_id)
+                      .ok_or_else(|| format_err!("cell of missing layout {}", layout_id))?;
+        let camera_uuid: FromSqlUuid = row.get_checked(2)?;
+        let stream_type: String = row.get_checked(3)?;
+        layouts[i].cells.push(db::LayoutCell {
+            position: row.get_checked::<_, i32>(1)? as u16,
+            camera_uuid: camera_uuid.0,
+            stream_type: db::StreamType::parse(&stream_type).ok_or_else(|| {
+                format_err!("layout {} has bad stream type {}", layout_id, stream_type)
+            })?,
+        });
+    }
+    Ok(layouts)
+}
+
+/// Sets whether the given layout is shared, returning true iff it exists.
+pub(crate) fn set_layout_shared(conn: &rusqlite::Connection, id: i32, shared: bool)
+                                -> Result<bool, Error> {
+    let mut stmt = conn.prepare_cached("update layout set shared = :shared where id = :id")?;
+    Ok(stmt.execute_named(&[(":shared", &shared), (":id", &id)])? == 1)
+}
+
+/// Deletes the given layout and its cells, returning true iff it existed.
+pub(crate) fn delete_layout(tx: &rusqlite::Transaction, id: i32) -> Result<bool, Error> {
+    let mut stmt = tx.prepare_cached("delete from layout_cell where layout_id = :id")?;
+    stmt.execute_named(&[(":id", &id)])?;
+    let mut stmt = tx.prepare_cached("delete from layout where id = :id")?;
+    Ok(stmt.execute_named(&[(":id", &id)])? == 1)
+}
+
+/// Inserts the specified note, returning its id.
+pub(crate) fn insert_note(conn: &rusqlite::Connection, stream_id: i32,
+                          time: &Range<recording::Time>, text: &str, created_sec: i64)
+                          -> Result<i64, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert into note (stream_id,  start_time_90k,  end_time_90k,  text,  created_sec)
+                  values (:stream_id, :start_time_90k, :end_time_90k, :text, :created_sec)
+    "#)?;
+    stmt.execute_named(&[
+        (":stream_id", &stream_id),
+        (":start_time_90k", &time.start.0),
+        (":end_time_90k", &time.end.0),
+        (":text", &text),
+        (":created_sec", &created_sec),
+    ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Lists notes for the given stream which overlap the given time range and contain the given
+/// text (case-insensitively, for ASCII), in ascending order by start time.
+pub(crate) fn list_notes(conn: &rusqlite::Connection, stream_id: i32,
+                         desired_time: Range<recording::Time>, query: Option<&str>,
+                         f: &mut FnMut(db::Note) -> Result<(), Error>) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          id,
+          start_time_90k,
+          end_time_90k,
+          text,
+          created_sec
+        from
+          note
+        where
+          stream_id = :stream_id and
+          start_time_90k < :end_time_90k and
+          end_time_90k > :start_time_90k and
+          (:query is null or instr(lower(text), lower(:query)) > 0)
+        order by
+          start_time_90k
+    "#)?;
+    let mut rows = stmt.query_named(&[
+        (":stream_id", &stream_id),
+        (":start_time_90k", &desired_time.start.0),
+        (":end_time_90k", &desired_time.end.0),
+        (":query", &query),
+    ])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        f(db::Note {
+            id: row.get_checked(0)?,
+            stream_id,
+            time: recording::Time(row.get_checked(1)?) .. recording::Time(row.get_checked(2)?),
+            text: row.get_checked(3)?,
+            created_sec: row.get_checked(4)?,
+        })?;
+    }
+    Ok(())
+}
+
+/// Inserts a thumbnail, replacing any existing one of the same stream and time.
+pub(crate) fn insert_thumbnail(conn: &rusqlite::Connection, stream_id: i32, time: recording::Time,
+                               jpeg: &[u8]) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert or replace into thumbnail (stream_id,  time_90k,  jpeg)
+                                  values (:stream_id, :time_90k, :jpeg)
+    "#)?;
+    stmt.execute_named(&[
+        (":stream_id", &stream_id),
+        (":time_90k", &time.0),
+        (":jpeg", &jpeg),
+    ])?;
+    Ok(())
+}
+
+/// Lists the times and sizes of the given stream's thumbnails within the given time range, in
+/// ascending order.
+pub(crate) fn list_thumbnails(conn: &rusqlite::Connection, stream_id: i32,
+                              desired_time: Range<recording::Time>)
+                              -> Result<Vec<db::ListThumbnailsRow>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          time_90k,
+          length(jpeg)
+        from
+          thumbnail
+        where
+          stream_id = :stream_id and
+          time_90k >= :start_time_90k and
+          time_90k < :end_time_90k
+        order by
+          time_90k
+    "#)?;
+    let mut rows = stmt.query_named(&[
+        (":stream_id", &stream_id),
+        (":start_time_90k", &desired_time.start.0),
+        (":end_time_90k", &desired_time.end.0),
+    ])?;
+    let mut thumbnails = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        thumbnails.push(db::ListThumbnailsRow {
+            time: recording::Time(row.get_checked(0)?),
+            bytes: row.get_checked(1)?,
+        });
+    }
+    Ok(thumbnails)
+}
+
+/// Gets the given stream's latest thumbnail at or before the given time, if any.
+pub(crate) fn get_thumbnail(conn: &rusqlite::Connection, stream_id: i32, time: recording::Time)
+                            -> Result<Option<(recording::Time, Vec<u8>)>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          time_90k,
+          jpeg
+        from
+          thumbnail
+        where
+          stream_id = :stream_id and
+          time_90k <= :time_90k
+        order by
+          time_90k desc
+        limit 1
+    "#)?;
+    let mut rows = stmt.query_named(&[
+        (":stream_id", &stream_id),
+        (":time_90k", &time.0),
+    ])?;
+    match rows.next() {
+        None => Ok(None),
+        Some(r) => {
+            let r = r?;
+            Ok(Some((recording::Time(r.get_checked(0)?), r.get_checked(1)?)))
+        },
+    }
+}
+
+/// Inserts a scheduled snapshot, replacing any existing one of the same camera and time.
+pub(crate) fn insert_scheduled_snapshot(conn: &rusqlite::Connection, camera_id: i32,
+                                        time: recording::Time, jpeg: &[u8]) -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        insert or replace into scheduled_snapshot (camera_id,  time_90k,  jpeg)
+                                           values (:camera_id, :time_90k, :jpeg)
+    "#)?;
+    stmt.execute_named(&[
+        (":camera_id", &camera_id),
+        (":time_90k", &time.0),
+        (":jpeg", &jpeg),
+    ])?;
+    Ok(())
+}
+
+/// Lists the times and sizes of the given camera's scheduled snapshots within the given time
+/// range, in ascending order.
+pub(crate) fn list_scheduled_snapshots(conn: &rusqlite::Connection, camera_id: i32,
+                                       desired_time: Range<recording::Time>)
+                                       -> Result<Vec<db::ListThumbnailsRow>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          time_90k,
+          length(jpeg)
+        from
+          scheduled_snapshot
+        where
+          camera_id = :camera_id and
+          time_90k >= :start_time_90k and
+          time_90k < :end_time_90k
+        order by
+          time_90k
+    "#)?;
+    let mut rows = stmt.query_named(&[
+        (":camera_id", &camera_id),
+        (":start_time_90k", &desired_time.start.0),
+        (":end_time_90k", &desired_time.end.0),
+    ])?;
+    let mut snapshots = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        snapshots.push(db::ListThumbnailsRow {
+            time: recording::Time(row.get_checked(0)?),
+            bytes: row.get_checked(1)?,
+        });
+    }
+    Ok(snapshots)
+}
+
+/// Gets the given camera's latest scheduled snapshot at or before the given time, if any.
+pub(crate) fn get_scheduled_snapshot(conn: &rusqlite::Connection, camera_id: i32,
+                                     time: recording::Time)
+                                     -> Result<Option<(recording::Time, Vec<u8>)>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          time_90k,
+          jpeg
+        from
+          scheduled_snapshot
+        where
+          camera_id = :camera_id and
+          time_90k <= :time_90k
+        order by
+          time_90k desc
+        limit 1
+    "#)?;
+    let mut rows = stmt.query_named(&[
+        (":camera_id", &camera_id),
+        (":time_90k", &time.0),
+    ])?;
+    match rows.next() {
+        None => Ok(None),
+        Some(r) => {
+            let r = r?;
+            Ok(Some((recording::Time(r.get_checked(0)?), r.get_checked(1)?)))
+        },
+    }
+}
+
+/// Deletes the given camera's scheduled snapshots before the given time, or all of them if
+/// `None`.
+pub(crate) fn delete_scheduled_snapshots(conn: &rusqlite::Connection, camera_id: i32,
+                                         before: Option<recording::Time>)
+                                         -> Result<usize, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        delete from scheduled_snapshot
+        where
+          camera_id = :camera_id and
+          (:before_90k is null or time_90k < :before_90k)
+    "#)?;
+    Ok(stmt.execute_named(&[
+        (":camera_id", &camera_id),
+        (":before_90k", &before.map(|t| t.0)),
+    ])?)
+}
+
+/// Deletes the given stream's thumbnails before the given time, or all of them if `None`.
+pub(crate) fn delete_thumbnails(conn: &rusqlite::Connection, stream_id: i32,
+                                before: Option<recording::Time>) -> Result<usize, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        delete from thumbnail
+        where
+          stream_id = :stream_id and
+          (:before_90k is null or time_90k < :before_90k)
+    "#)?;
+    Ok(stmt.execute_named(&[
+        (":stream_id", &stream_id),
+        (":before_90k", &before.map(|t| t.0)),
+    ])?)
+}
+
+/// Returns the id of the given stream's oldest recording, archived or not, if any.
+pub(crate) fn get_first_recording_id(conn: &rusqlite::Connection, stream_id: i32)
+                                     -> Result<Option<i32>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          min(composite_id)
+        from
+          recording
+        where
+          :start <= composite_id and
+          composite_id < :end
+    "#)?;
+    let mut rows = stmt.query_named(&[
+        (":start", &CompositeId::new(stream_id, 0).0),
+        (":end", &CompositeId::new(stream_id + 1, 0).0),
+    ])?;
+    let id: Option<i64> = match rows.next() {
+        None => None,
+        Some(row) => row?.get_checked(0)?,
+    };
+    Ok(id.map(|id| CompositeId(id).recording()))
+}
+
+/// Deletes the given stream's thumbnails within the given time range.
+pub(crate) fn delete_thumbnails_in(conn: &rusqlite::Connection, stream_id: i32,
+                                   time: Range<recording::Time>) -> Result<usize, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        delete from thumbnail
+        where
+          stream_id = :stream_id and
+          :start_90k <= time_90k and
+          time_90k < :end_90k
+    "#)?;
+    Ok(stmt.execute_named(&[
+        (":stream_id", &stream_id),
+        (":start_90k", &time.start.0),
+        (":end_90k", &time.end.0),
+    ])?)
+}
+
+/// Gets the event with the given id, if any.
+pub(crate) fn get_event(conn: &rusqlite::Connection, id: i64)
+                        -> Result<Option<db::ListEventsRow>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          camera_id,
+          type,
+          start_time_90k,
+          end_time_90k,
+          description,
+          score
+        from
+          event
+        where
+          id = :id
+    "#)?;
+    let mut rows = stmt.query_named(&[(":id", &id)])?;
+    let row = match rows.next() {
+        None => return Ok(None),
+        Some(r) => r?,
+    };
+    Ok(Some(db::ListEventsRow {
+        id,
+        camera_id: row.get_checked(0)?,
+        type_: row.get_checked(1)?,
+        time: recording::Time(row.get_checked(2)?) .. recording::Time(row.get_checked(3)?),
+        description: row.get_checked(4)?,
+        score: row.get_checked(5)?,
+    }))
+}
+
+/// Lists events for the given camera which overlap the given time range, in ascending order by
+/// start time.
+pub(crate) fn list_events(conn: &rusqlite::Connection, camera_id: i32,
+                          desired_time: Range<recording::Time>,
+                          f: &mut FnMut(db::ListEventsRow) -> Result<(), Error>)
+                          -> Result<(), Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          id,
+          type,
+          start_time_90k,
+          end_time_90k,
+          description,
+          score
+        from
+          event
+        where
+          camera_id = :camera_id and
+          start_time_90k < :end_time_90k and
+          end_time_90k > :start_time_90k
+        order by
+          start_time_90k
+    "#)?;
+    let mut rows = stmt.query_named(&[
+        (":camera_id", &camera_id),
+        (":start_time_90k", &desired_time.start.0),
+        (":end_time_90k", &desired_time.end.0),
+    ])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        f(db::ListEventsRow {
+            id: row.get_checked(0)?,
+            camera_id,
+            type_: row.get_checked(1)?,
+            time: recording::Time(row.get_checked(2)?) .. recording::Time(row.get_checked(3)?),
+            description: row.get_checked(4)?,
+            score: row.get_checked(5)?,
+        })?;
+    }
+    Ok(())
+}
+
+/// Adds to the matching hourly bandwidth total, inserting it if necessary.
+pub(crate) fn add_bandwidth_usage(conn: &rusqlite::Connection, u: &db::BandwidthUsage)
+                                  -> Result<(), Error> {
+    let camera_uuid = u.camera_uuid.as_ref().map(|u| &u.as_bytes()[..]);
+    let params: &[(&str, &ToSql)] = &[
+        (":start_sec", &u.start_sec),
+        (":user_name", &u.user_name),
+        (":camera_uuid", &camera_uuid),
+        (":requests", &u.requests),
+        (":bytes", &u.bytes),
+    ];
+
+    // camera_uuid may be null, so there's no unique key for "insert or replace"; instead match
+    // with "is".
+    let mut stmt = conn.prepare_cached(r#"
+        update bandwidth_hour
+        set
+          requests = requests + :requests,
+          bytes = bytes + :bytes
+        where
+          start_sec = :start_sec and
+          user_name = :user_name and
+          camera_uuid is :camera_uuid
+    "#)?;
+    if stmt.execute_named(params)? > 0 {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare_cached(r#"
+        insert into bandwidth_hour (start_sec,  user_name,  camera_uuid,  requests,  bytes)
+                            values (:start_sec, :user_name, :camera_uuid, :requests, :bytes)
+    "#)?;
+    stmt.execute_named(params)?;
+    Ok(())
+}
+
+/// Lists hourly bandwidth totals starting within the given range, ordered by start.
+pub(crate) fn list_bandwidth_usage(conn: &rusqlite::Connection, start_sec: Range<i64>)
+                                   -> Result<Vec<db::BandwidthUsage>, Error> {
+    let mut stmt = conn.prepare_cached(r#"
+        select
+          start_sec,
+          user_name,
+          camera_uuid,
+          requests,
+          bytes
+        from
+          bandwidth_hour
+        where
+          start_sec >= :start and
+          start_sec < :end
+        order by
+          start_sec,
+          user_name
+    "#)?;
+    let mut rows = stmt.query_named(&[(":start", &start_sec.start), (":end", &start_sec.end)])?;
+    let mut usage = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        usage.push(db::BandwidthUsage {
+            start_sec: row.get_checked(0)?,
+            user_name: row.get_checked(1)?,
+            camera_uuid: row.get_checked::<_, Option<FromSqlUuid>>(2)?.map(|u| u.0),
+            requests: row.get_checked(3)?,
+            bytes: row.get_checked(4)?,
+        });
+    }
+    Ok(usage)
+}
+
+pub(crate) fn delete_bandwidth_usage_before(conn: &rusqlite::Connection, sec: i64)
+                                            -> Result<usize, Error> {
+    let mut stmt = conn.prepare_cached("delete from bandwidth_hour where start_sec < ?")?;
+    Ok(stmt.execute(&[&sec])?)
+}
@@ -32,6 +32,7 @@ use coding::{append_varint32, decode_varint32, unzigzag32, zigzag32};
 use db;
 use failure::Error;
 use regex::Regex;
+use std::cmp;
 use std::ops;
 use std::fmt;
 use std::ops::Range;
@@ -40,6 +41,11 @@ use time;
 
 pub const TIME_UNITS_PER_SEC: i64 = 90000;
 pub const DESIRED_RECORDING_DURATION: i64 = 60 * TIME_UNITS_PER_SEC;
+
+/// The maximum of a stream's `recording_duration_sec`. A run's first recording may be up to a
+/// minute longer than requested, and any recording may extend to the next key frame, so this is
+/// kept well below `MAX_RECORDING_DURATION`.
+pub const MAX_DESIRED_RECORDING_DURATION_SEC: i64 = 180;
 pub const MAX_RECORDING_DURATION: i64 = 5 * 60 * TIME_UNITS_PER_SEC;
 
 /// A time specified as 90,000ths of a second since 1970-01-01 00:00:00 UTC.
@@ -315,6 +321,12 @@ impl SampleIndexEncoder {
         let duration_delta = duration_90k - self.prev_duration_90k;
         self.prev_duration_90k = duration_90k;
         r.duration_90k += duration_90k;
+        if duration_90k > 0 {
+            if r.min_frame_interval_90k == 0 || duration_90k < r.min_frame_interval_90k {
+                r.min_frame_interval_90k = duration_90k;
+            }
+            r.max_frame_interval_90k = cmp::max(r.max_frame_interval_90k, duration_90k);
+        }
         r.sample_file_bytes += bytes;
         r.video_samples += 1;
         let bytes_delta = bytes - if is_key {
@@ -332,6 +344,44 @@ impl SampleIndexEncoder {
     }
 }
 
+/// Returns the minimum and maximum frame intervals (durations) within the given video index,
+/// as `SampleIndexEncoder` records in `db::RecordingToInsert`. A trailing frame of zero duration
+/// is excluded; if there are no other frames, returns `(0, 0)`.
+pub fn frame_interval_range(video_index: &[u8]) -> Result<(i32, i32), Error> {
+    let mut it = SampleIndexIterator::new();
+    let (mut min, mut max) = (0, 0);
+    while it.next(video_index)? {
+        if it.duration_90k > 0 {
+            if min == 0 || it.duration_90k < min {
+                min = it.duration_90k;
+            }
+            max = cmp::max(max, it.duration_90k);
+        }
+    }
+    Ok((min, max))
+}
+
+/// A frame decoded by `Segment::foreach_chunk`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Frame {
+    /// The starting data byte position of this frame within the segment.
+    pub pos: i32,
+
+    /// The starting time of this frame within the segment (in 90 kHz units).
+    pub start_90k: i32,
+
+    /// The duration of this frame (in 90 kHz units).
+    pub duration_90k: i32,
+
+    /// The byte length of this frame.
+    pub bytes: i32,
+
+    pub is_key: bool,
+}
+
+/// The maximum number of frames passed to each call of `Segment::foreach_chunk`'s function.
+pub const FRAME_CHUNK: usize = 256;
+
 /// A segment represents a view of some or all of a single recording, starting from a key frame.
 /// Used by the `Mp4FileBuilder` class to splice together recordings into a single virtual .mp4.
 #[derive(Debug)]
@@ -363,6 +413,20 @@ impl Segment {
     pub fn new(db: &db::LockedDatabase,
                recording: &db::ListRecordingsRow,
                desired_range_90k: Range<i32>) -> Result<Segment, Error> {
+        Segment::new_inner(db, recording, desired_range_90k, false)
+    }
+
+    /// Creates a segment which starts exactly at the frame beginning at
+    /// `desired_range_90k.start`, even if it isn't a key frame. Such a segment is only decodable
+    /// as a continuation of one which ended just before it; see `tail`'s low-latency mode.
+    pub fn continuation(db: &db::LockedDatabase,
+                        recording: &db::ListRecordingsRow,
+                        desired_range_90k: Range<i32>) -> Result<Segment, Error> {
+        Segment::new_inner(db, recording, desired_range_90k, true)
+    }
+
+    fn new_inner(db: &db::LockedDatabase, recording: &db::ListRecordingsRow,
+                 desired_range_90k: Range<i32>, continuation: bool) -> Result<Segment, Error> {
         let mut self_ = Segment {
             id: recording.id,
             open_id: recording.open_id,
@@ -417,7 +481,8 @@ impl Segment {
             };
 
             loop {
-                if it.start_90k <= self_.desired_range_90k.start && it.is_key() {
+                if it.start_90k <= self_.desired_range_90k.start &&
+                   (it.is_key() || continuation) {
                     // new start candidate.
                     *begin = it;
                     self_.frames = 0;
@@ -448,11 +513,44 @@ impl Segment {
 
     pub fn have_trailing_zero(&self) -> bool { self.video_sample_entry_id_and_trailing_zero < 0 }
 
+    /// Excludes the trailing frame of zero duration, if any, so that another segment can follow
+    /// this one. Such a frame ends a run, so no other frame depends on it.
+    pub fn drop_trailing_zero(&mut self, db: &db::LockedDatabase) -> Result<(), Error> {
+        if !self.have_trailing_zero() {
+            return Ok(());
+        }
+        let last = db.with_recording_playback(self.id, &mut |playback| {
+            let mut last = None;
+            self.foreach(playback, |it| { last = Some(*it); Ok(()) })?;
+            Ok(last)
+        })?;
+
+        // The flag may also be set when the zero-duration frame is just past the desired range
+        // and thus not actually included.
+        if let Some(it) = last {
+            if it.duration_90k == 0 {
+                if self.frames == 1 {
+                    bail!("recording {}: can't drop only frame", self.id);
+                }
+                self.frames -= 1;
+                self.key_frames -= it.is_key() as u16;
+                self.file_end = it.pos;
+            }
+        }
+        self.video_sample_entry_id_and_trailing_zero &= 0x7FFF_FFFF;
+        Ok(())
+    }
+
     /// Returns the byte range within the sample file of data associated with this segment.
     pub fn sample_file_range(&self) -> Range<u64> {
         self.begin.as_ref().map(|b| b.pos as u64).unwrap_or(0) .. self.file_end as u64
     }
 
+    /// Returns true if the segment's first frame is a key frame; false only for a continuation.
+    pub fn begins_with_key_frame(&self) -> bool {
+        self.begin.as_ref().map(|b| b.is_key()).unwrap_or(true)
+    }
+
     /// Returns the actual start time as described in `new`.
     pub fn actual_start_90k(&self) -> i32 { self.begin.as_ref().map(|b| b.start_90k).unwrap_or(0) }
 
@@ -501,6 +599,83 @@ impl Segment {
         }
         Ok(())
     }
+
+    /// Iterates through the frames in the segment as `foreach` does, but decodes up to
+    /// `FRAME_CHUNK` of them at a time into `buf` before calling `f`. Callers which reuse `buf`
+    /// don't allocate, and their tight loop over each chunk (without the iterator's state in
+    /// between) is considerably faster than a per-frame callback on some CPUs.
+    pub fn foreach_chunk<F>(&self, playback: &db::RecordingPlayback, buf: &mut Vec<Frame>,
+                            mut f: F) -> Result<(), Error>
+    where F: FnMut(&[Frame]) -> Result<(), Error> {
+        buf.clear();
+        buf.reserve(FRAME_CHUNK);
+        self.foreach(playback, |it| {
+            buf.push(Frame {
+                pos: it.pos,
+                start_90k: it.start_90k,
+                duration_90k: it.duration_90k,
+                bytes: it.bytes,
+                is_key: it.is_key(),
+            });
+            if buf.len() == FRAME_CHUNK {
+                f(&buf[..])?;
+                buf.clear();
+            }
+            Ok(())
+        })?;
+        if !buf.is_empty() {
+            f(&buf[..])?;
+            buf.clear();
+        }
+        Ok(())
+    }
+}
+
+/// The maximum size of a recording's metadata sidecar. Packets beyond this are dropped.
+pub const MAX_METADATA_BYTES: usize = 4 << 20;
+
+/// A packet from a stream's metadata track (such as ONVIF analytics XML), as stored in its
+/// recording's sidecar; see `append_metadata`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct MetadataSample<'a> {
+    /// The time of the packet, relative to the start of the recording.
+    pub rel_90k: i32,
+    pub data: &'a [u8],
+}
+
+/// Appends a packet to a recording's metadata sidecar. Each is encoded as a varint of its time
+/// (zigzagged, as a packet may slightly precede the first frame), a varint of its length, and
+/// its data. Returns false without appending if the sidecar would exceed `MAX_METADATA_BYTES`.
+pub fn append_metadata(rel_90k: i32, data: &[u8], sidecar: &mut Vec<u8>) -> bool {
+    if sidecar.len() + data.len() + 10 > MAX_METADATA_BYTES {
+        return false;
+    }
+    append_varint32(zigzag32(rel_90k), sidecar);
+    append_varint32(data.len() as u32, sidecar);
+    sidecar.extend_from_slice(data);
+    true
+}
+
+/// Parses a metadata sidecar as written by `append_metadata`.
+pub fn parse_metadata(sidecar: &[u8]) -> Result<Vec<MetadataSample>, Error> {
+    let mut samples = Vec::new();
+    let mut i = 0;
+    while i < sidecar.len() {
+        let (rel_90k, i1) = decode_varint32(sidecar, i)
+            .map_err(|()| format_err!("bad metadata time at offset {}", i))?;
+        let (len, i2) = decode_varint32(sidecar, i1)
+            .map_err(|()| format_err!("bad metadata length at offset {}", i1))?;
+        let end = i2 + len as usize;
+        if end > sidecar.len() {
+            bail!("metadata sample at offset {} extends past end {}", i, sidecar.len());
+        }
+        samples.push(MetadataSample {
+            rel_90k: unzigzag32(rel_90k),
+            data: &sidecar[i2 .. end],
+        });
+        i = end;
+    }
+    Ok(samples)
 }
 
 #[cfg(test)]
@@ -605,6 +780,28 @@ mod tests {
         assert!(!it.next(&r.video_index).unwrap());
     }
 
+    /// Tests a very irregular frame rate, as from a battery camera which drops to 1 fps or less
+    /// at night. Durations should round-trip exactly and the interval range should be recorded.
+    #[test]
+    fn test_variable_frame_rate() {
+        testutil::init();
+        let durations = [3003, 3003, 90000, 450000, 1, 2997, 0];
+        let mut r = db::RecordingToInsert::default();
+        let mut e = SampleIndexEncoder::new();
+        for (i, &d) in durations.iter().enumerate() {
+            e.add_sample(d, 1000, i == 0, &mut r);
+        }
+        let mut it = SampleIndexIterator::new();
+        for &d in &durations {
+            assert!(it.next(&r.video_index).unwrap());
+            assert_eq!(d, it.duration_90k);
+        }
+        assert!(!it.next(&r.video_index).unwrap());
+        assert_eq!(durations.iter().sum::<i32>(), r.duration_90k);
+        assert_eq!((1, 450000), (r.min_frame_interval_90k, r.max_frame_interval_90k));
+        assert_eq!((1, 450000), frame_interval_range(&r.video_index).unwrap());
+    }
+
     /// Tests that `SampleIndexIterator` spots several classes of errors.
     /// TODO: test and fix overflow cases.
     #[test]
@@ -737,6 +934,88 @@ mod tests {
         assert_eq!(&get_frames(&db.db, &segment, |it| it.bytes), &[1, 2, 3]);
     }
 
+    #[test]
+    fn test_segment_drop_trailing_zero() {
+        testutil::init();
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = SampleIndexEncoder::new();
+        encoder.add_sample(1, 1, true, &mut r);
+        encoder.add_sample(1, 2, false, &mut r);
+        encoder.add_sample(0, 3, false, &mut r);
+        let db = TestDb::new(RealClocks {});
+        let row = db.insert_recording_from_encoder(r);
+
+        // Both the fast and slow paths.
+        for range in &[0 .. 2, 1 .. 2] {
+            let mut segment = Segment::new(&db.db.lock(), &row, range.clone()).unwrap();
+            assert!(segment.have_trailing_zero());
+            segment.drop_trailing_zero(&db.db.lock()).unwrap();
+            assert!(!segment.have_trailing_zero());
+            assert_eq!(&get_frames(&db.db, &segment, |it| it.bytes), &[1, 2]);
+            assert_eq!(segment.sample_file_range(), 0 .. 3);
+            assert_eq!(segment.key_frames, 1);
+        }
+    }
+
+    /// Tests that `foreach_chunk` splits frames into chunks and agrees with `foreach`.
+    #[test]
+    fn test_segment_foreach_chunk() {
+        testutil::init();
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = SampleIndexEncoder::new();
+        let n = 2 * FRAME_CHUNK + 3;
+        for i in 0 .. n as i32 {
+            encoder.add_sample(3000, 100 + i, i % 30 == 0, &mut r);
+        }
+        let db = TestDb::new(RealClocks {});
+        let row = db.insert_recording_from_encoder(r);
+        let segment = Segment::new(&db.db.lock(), &row, 0 .. 3000 * n as i32).unwrap();
+        let expected = get_frames(&db.db, &segment, |it| Frame {
+            pos: it.pos,
+            start_90k: it.start_90k,
+            duration_90k: it.duration_90k,
+            bytes: it.bytes,
+            is_key: it.is_key(),
+        });
+        let mut buf = Vec::new();
+        let mut chunk_lens = Vec::new();
+        let mut frames = Vec::new();
+        db.db.lock().with_recording_playback(segment.id, &mut |playback| {
+            segment.foreach_chunk(playback, &mut buf, |chunk| {
+                chunk_lens.push(chunk.len());
+                frames.extend_from_slice(chunk);
+                Ok(())
+            })
+        }).unwrap();
+        assert_eq!(&chunk_lens, &[FRAME_CHUNK, FRAME_CHUNK, 3]);
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        testutil::init();
+        let mut sidecar = Vec::new();
+        assert!(append_metadata(-3, b"<a/>", &mut sidecar));
+        assert!(append_metadata(90000, b"", &mut sidecar));
+        assert!(append_metadata(180000, &[b'x'; 300], &mut sidecar));
+        let samples = parse_metadata(&sidecar).unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[0], MetadataSample { rel_90k: -3, data: b"<a/>" });
+        assert_eq!(samples[1], MetadataSample { rel_90k: 90000, data: b"" });
+        assert_eq!(samples[2].rel_90k, 180000);
+        assert_eq!(samples[2].data.len(), 300);
+
+        // Truncation should be detected.
+        let l = sidecar.len();
+        parse_metadata(&sidecar[.. l - 1]).unwrap_err();
+
+        // So should overflow.
+        let mut big = Vec::new();
+        let data = vec![0; MAX_METADATA_BYTES / 2];
+        assert!(append_metadata(0, &data, &mut big));
+        assert!(!append_metadata(1, &data, &mut big));
+    }
+
     // TODO: test segment error cases involving mismatch between row frames/key_frames and index.
 }
 
@@ -81,14 +81,51 @@ create table sample_file_dir (
 
   -- The last (read/write) open of this directory which fully completed.
   -- See schema.proto:DirMeta for a more complete description.
-  last_complete_open_id integer references open (id)
+  last_complete_open_id integer references open (id),
+
+  -- True (1) iff the directory is on a network filesystem such as NFS, which
+  -- may lack reliable locking and exclusive creation. Such directories are
+  -- protected by a lease file rather than a lock; see dir.rs.
+  network_fs integer not null default 0 check (network_fs in (0, 1)),
+
+  -- The number of bytes to keep free on the directory's filesystem. When free
+  -- space falls below this, the oldest recordings of the directory's streams
+  -- are deleted, regardless of the streams' own retain_bytes limits.
+  reserved_bytes integer not null default 0 check (reserved_bytes >= 0),
+
+  -- If non-null, the directory's sample files are encrypted with AES-256-GCM.
+  -- This is the directory's key, wrapped (RFC 3394) with the master key given
+  -- on the command line. See dir.rs:Cipher.
+  wrapped_key blob check (wrapped_key is null or length(wrapped_key) = 40)
+);
+
+-- A tenant: a group of cameras (such as an apartment or business unit) which
+-- share a storage quota and are meant to be isolated from other tenants.
+create table tenant (
+  id integer primary key,
+  uuid blob unique not null check (length(uuid) = 16),
+
+  -- A short name of the tenant, used in log messages.
+  short_name text not null,
+
+  -- The maximum number of bytes of completed recordings to retain across all
+  -- of the tenant's streams. Older files will be deleted as necessary to stay
+  -- within this limit, in addition to each stream's own retain_bytes limit.
+  -- If null, there is no tenant-wide limit.
+  retain_bytes integer check (retain_bytes >= 0),
+
+  -- If true, exports requested by the tenant's users (see user.tenant_id) are
+  -- watermarked with the exporting user and time.
+  watermark_exports integer not null default 0
+      check (watermark_exports in (0, 1))
 );
 
 create table camera (
   id integer primary key,
   uuid blob unique not null check (length(uuid) = 16),
 
-  -- A short name of the camera, used in log messages.
+  -- A short name of the camera, used in log messages and as a friendlier
+  -- alternative to the uuid in API URLs. Unique; see camera_short_name below.
   short_name text not null,
 
   -- A short description of the camera.
@@ -102,27 +139,94 @@ create table camera (
   username text,
 
   -- The password to use when accessing the camera.
-  password text
+  password text,
+
+  -- The username and password in use before they were last rotated via the
+  -- HTTP API, kept so that a rotation can be rolled back. Both null if the
+  -- credentials have never been rotated.
+  previous_username text,
+  previous_password text
+      check ((previous_username is null) = (previous_password is null)),
+
+  -- The tenant owning this camera, or null if the camera is not part of any
+  -- tenant.
+  tenant_id integer references tenant (id),
+
+  -- The camera's own feed of analytics events (motion, line crossing,
+  -- tamper, etc.) to subscribe to, or null for none. Received events are
+  -- stored in the event table.
+  event_source text check (event_source in ('onvif', 'hikvision', 'dahua')),
+
+  -- If non-null, a snapshot of the camera is stored this often (in seconds,
+  -- at multiples of the interval in local time) in the
+  -- scheduled_snapshot table, as a cheap alternative to video for seeing how
+  -- a scene changes over days or seasons.
+  snapshot_interval_sec integer
+      check (snapshot_interval_sec is null or snapshot_interval_sec > 0),
+
+  -- How long to keep scheduled snapshots, in days.
+  snapshot_retain_days integer not null default 30 check (snapshot_retain_days > 0)
 );
 
+create unique index camera_short_name on camera (short_name);
+
 create table stream (
   id integer primary key,
   camera_id integer not null references camera (id),
   sample_file_dir_id integer references sample_file_dir (id),
   type text not null check (type in ('main', 'sub')),
 
+  -- If non-null, a second directory to which this stream's recordings are
+  -- copied once committed, as protection against a single disk failure.
+  -- See mirror.rs.
+  mirror_sample_file_dir_id integer references sample_file_dir (id),
+
   -- If record is true, the stream should start recording when moonfire
   -- starts. If false, no new recordings will be made, but old recordings
   -- will not be deleted.
   record integer not null check (record in (1, 0)),
 
+  -- If paused is true, recording is temporarily suspended (such as while
+  -- people are home, for an indoor camera) without changing the rest of the
+  -- configuration. It's toggled via the HTTP API. Unlike record, it takes
+  -- effect immediately rather than at the next start.
+  paused integer not null default 0 check (paused in (0, 1)),
+
   -- The path (starting with "/") to use in rtsp:// URLs to for this stream.
   rtsp_path text not null,
 
+  -- Where the stream's video comes from: 'rtsp' (from the camera's host at
+  -- rtsp_path), or for cameras which only serve video over HTTP, 'hls' (an
+  -- HTTP Live Streaming playlist of H.264) or 'mjpeg' (Motion JPEG, which is
+  -- transcoded to H.264 by an external ffmpeg before recording), or 'v4l2'
+  -- (a local Video4Linux2 device such as a Raspberry Pi camera module,
+  -- captured and encoded by an external ffmpeg), or 'push' (video which the
+  -- device sends to a listener on this server via RTMP or SRT).
+  source text not null default 'rtsp'
+      check (source in ('rtsp', 'hls', 'mjpeg', 'v4l2', 'push')),
+
+  -- For HTTP sources, the http:// or https:// URL (without credentials) of
+  -- the playlist or Motion JPEG stream. For v4l2, the device path, such as
+  -- /dev/video0. For push, the rtmp:// or srt:// URL to listen on, such as
+  -- rtmp://0.0.0.0:1935/live/<key>. Null for RTSP.
+  source_url text check (source_url is null or
+                         (source = 'v4l2') = (source_url like '/dev/%') and
+                         (source = 'push') = (source_url like 'rtmp://%' or
+                                              source_url like 'srt://%') and
+                         (source_url like '/dev/%' or source_url like 'http://%' or
+                          source_url like 'https://%' or source_url like 'rtmp://%' or
+                          source_url like 'srt://%')),
+
   -- The number of bytes of video to retain, excluding the currently-recording
   -- file. Older files will be deleted as necessary to stay within this limit.
   retain_bytes integer not null check (retain_bytes >= 0),
 
+  -- The relative priority of this stream's recordings when space is shared
+  -- with other streams, as in a tenant's quota or a directory's reserved
+  -- free space. Recordings are evicted so that the ages of each stream's
+  -- oldest recordings are roughly proportional to their weights.
+  retain_weight integer not null default 1 check (retain_weight > 0),
+
   -- Flush the database when the first instant of completed recording is this
   -- many seconds old. A value of 0 means that every completed recording will
   -- cause an immediate flush. Higher values may allow flushes to be combined,
@@ -136,11 +240,64 @@ create table stream (
   --   then fails again, forever.
   flush_if_sec integer not null,
 
+  -- The desired duration of each recording, in seconds. Recordings are
+  -- rotated at the first key frame after each multiple of this (staggered
+  -- between streams). Shorter recordings reduce the latency of viewing
+  -- recent video; longer ones reduce index overhead. The maximum keeps
+  -- recordings within recording.rs:MAX_RECORDING_DURATION.
+  recording_duration_sec integer not null default 60
+      check (recording_duration_sec > 0 and recording_duration_sec <= 180),
+
+  -- If non-null, the UUID of the "user_data_unregistered" SEI messages in
+  -- which the camera reports its own motion detection. The first byte after
+  -- the UUID is non-zero while there is motion. Such periods are recorded as
+  -- "motion" events. (All SEI messages are stored in the sample file
+  -- regardless of this setting.)
+  sei_motion_uuid blob check (sei_motion_uuid is null or length(sei_motion_uuid) = 16),
+
+  -- If non-null, an http:// or https:// URL (without credentials) from which
+  -- the camera serves a JPEG of this stream's current frame. Snapshots are
+  -- fetched from here rather than decoded from the stream, which is useful
+  -- when key frames are too infrequent.
+  snapshot_url text check (snapshot_url is null or snapshot_url like 'http://%' or
+                           snapshot_url like 'https://%'),
+
+  -- If ONVIF analytics in the stream's metadata track (such as object
+  -- positions and rule triggers) should be recorded as events. The metadata
+  -- track itself is kept in recording_metadata regardless of this setting.
+  metadata_events integer not null default 0 check (metadata_events in (0, 1)),
+
+  -- If nonzero, the approximate interval in seconds at which to store a
+  -- thumbnail of a key frame while recording. See the thumbnail table.
+  thumbnail_interval_sec integer not null default 0
+      check (thumbnail_interval_sec >= 0),
+
+  -- Video encoder settings to push to the camera via ONVIF, if any. A null
+  -- column leaves that setting as the camera has it. encoder_gov_length is the
+  -- key frame interval in frames; short intervals are needed for low-latency
+  -- live view and fine-grained exports.
+  encoder_gov_length integer check (encoder_gov_length > 0),
+  encoder_bitrate_kbps integer check (encoder_bitrate_kbps > 0),
+  encoder_frame_rate integer check (encoder_frame_rate > 0),
+  encoder_width integer check (encoder_width > 0),
+  encoder_height integer check (encoder_height > 0),
+
+  -- Extra ffmpeg input options to apply when opening the source, as
+  -- whitespace-separated key=value pairs (such as "analyzeduration=10000000"),
+  -- for cameras which need them. Null for none.
+  input_options text,
+
   -- The low 32 bits of the next recording id to assign for this stream.
   -- Typically this is the maximum current recording + 1, but it does
   -- not decrease if that recording is deleted.
   next_recording_id integer not null check (next_recording_id >= 0),
 
+  -- The hash chain value of the most recent committed recording, or null if
+  -- there has been none since the chain was started. See chain.rs.
+  chain_sha1 blob check (chain_sha1 is null or length(chain_sha1) = 20),
+
+  check ((encoder_width is null) = (encoder_height is null)),
+  check ((source = 'rtsp') = (source_url is null)),
   unique (camera_id, type)
 );
 
@@ -177,6 +334,12 @@ create table recording (
   -- * 1, or "trailing zero", indicates that this recording is the last in a
   --   stream. As the duration of a sample is not known until the next sample
   --   is received, the final sample in this recording will have duration 0.
+  -- * 2, or "degraded", indicates that this recording was taken from a
+  --   fallback source (such as the camera's sub stream) because the stream's
+  --   own source was failing.
+  -- * 4, or "archived", indicates that the sample file has been moved out of
+  --   the sample file directory; see recording_archive. The recording is
+  --   still listed but can't be viewed, and retention doesn't delete it.
   flags integer not null,
 
   sample_file_bytes integer not null check (sample_file_bytes > 0),
@@ -195,6 +358,15 @@ create table recording (
   video_sync_samples integer not null check (video_sync_samples > 0),
   video_sample_entry_id integer references video_sample_entry (id),
 
+  -- The shortest and longest durations of any frame in the recording,
+  -- excluding a trailing frame of zero duration (see flags above), in 90 kHz
+  -- units. Both are 0 if there are no such frames. These describe irregular
+  -- frame rates without consulting video_index.
+  min_frame_interval_90k integer not null default 0
+      check (min_frame_interval_90k >= 0),
+  max_frame_interval_90k integer not null default 0
+      check (max_frame_interval_90k >= min_frame_interval_90k),
+
   check (composite_id >> 32 = stream_id)
 );
 
@@ -213,7 +385,9 @@ create index recording_cover on recording (
   video_sample_entry_id,
   sample_file_bytes,
   run_offset,
-  flags
+  flags,
+  min_frame_interval_90k,
+  max_frame_interval_90k
 );
 
 -- Fields which are only needed to check/correct database integrity problems
@@ -250,7 +424,12 @@ create table recording_integrity (
   wall_time_delta_90k integer,
 
   -- The sha1 hash of the contents of the sample file.
-  sample_file_sha1 blob check (length(sample_file_sha1) <= 20)
+  sample_file_sha1 blob check (length(sample_file_sha1) <= 20),
+
+  -- The sha1 hash of the previous recording's chain_sha1 and this recording's
+  -- metadata, forming a tamper-evident chain. Null for recordings made before
+  -- the chain was started. See chain.rs.
+  chain_sha1 blob check (chain_sha1 is null or length(chain_sha1) = 20)
 );
 
 -- Large fields for a recording which are needed ony for playback.
@@ -268,6 +447,27 @@ create table recording_playback (
   -- audio_index could be added here in the future.
 );
 
+-- A sidecar of packets from a recording's RTSP metadata track, such as ONVIF
+-- analytics XML, for recordings of streams which have one. Deleted along
+-- with the recording.
+create table recording_metadata (
+  composite_id integer primary key references recording (composite_id),
+
+  -- A sequence of packets, each a varint of its time relative to the start
+  -- of the recording (zigzag-encoded), a varint of its length, and its bytes.
+  metadata blob not null check (length(metadata) > 0)
+);
+
+-- Where the sample file of each archived recording (see the recording
+-- table's flags) went.
+create table recording_archive (
+  composite_id integer primary key references recording (composite_id),
+
+  -- As given when archiving: a URL, a path on an external disk, or anything
+  -- else meaningful to whoever needs to retrieve it.
+  location text not null
+);
+
 -- Files which are to be deleted (may or may not still exist).
 -- Note that besides these files, for each stream, any recordings >= its
 -- next_recording_id should be discarded on startup.
@@ -328,7 +528,12 @@ create table user (
   -- a Unix domain socket. (Additionally, the UID running Moonfire NVR can authenticate
   -- as anyone; there's no point in trying to do otherwise.) This might be an easy
   -- bootstrap method once configuration happens through a web UI rather than text UI.
-  unix_uid integer
+  unix_uid integer,
+
+  -- If set, the tenant this user belongs to. The API shows such a user only
+  -- that tenant's cameras and refuses requests which aren't specific to one of
+  -- them.
+  tenant_id integer references tenant (id)
 );
 
 -- A single session, whether for browser or robot use.
@@ -403,5 +608,304 @@ create table user_session (
 
 create index user_session_uid on user_session (user_id);
 
+-- Something of interest which happened in front of a camera during a given
+-- time range, such as "loud noise". Events are produced by analytics stages
+-- and are associated with a camera rather than a stream because they describe
+-- the scene, not a particular encoding of it.
+create table event (
+  id integer primary key,
+  camera_id integer not null references camera (id),
+
+  -- The kind of event, such as "sound_level". See design/api.md.
+  type text not null,
+
+  -- The time range of the event, in 90 kHz units since
+  -- 1970-01-01 00:00:00 UTC excluding leap seconds.
+  start_time_90k integer not null check (start_time_90k > 0),
+  end_time_90k integer not null check (end_time_90k >= start_time_90k),
+
+  -- An optional human-readable description, such as "loud noise".
+  description text,
+
+  -- An optional type-specific magnitude, such as the peak sound level in
+  -- dBFS.
+  score real
+);
+
+create index event_camera_start on event (camera_id, start_time_90k);
+
+-- A still image of an event, such as the view from a doorbell camera when its
+-- button was pressed. Taken by the server shortly after the event is added.
+create table event_snapshot (
+  event_id integer primary key references event (id),
+  jpeg blob not null check (length(jpeg) > 0)
+);
+
+-- Objects detected within an event, such as by a camera's own analytics, which
+-- can be outlined on the event's snapshot.
+create table event_detection (
+  event_id integer not null references event (id),
+
+  -- What was detected, such as "person".
+  label text not null check (length(label) > 0),
+
+  -- The detector's confidence, from 0 to 1, if known.
+  score real check (score between 0 and 1),
+
+  -- The bounding box, as fractions of the frame's width and height, so that
+  -- it applies to any resolution.
+  x real not null check (x between 0 and 1),
+  y real not null check (y between 0 and 1),
+  width real not null check (width > 0 and x + width <= 1),
+  height real not null check (height > 0 and y + height <= 1)
+);
+
+create index event_detection_event on event_detection (event_id);
+
+-- A user-supplied note on a time range of a stream, such as "package stolen
+-- here". Unlike events, notes are associated with a stream, as they're made
+-- while watching its recordings.
+create table note (
+  id integer primary key,
+  stream_id integer not null references stream (id),
+
+  -- The time range of the note, in 90 kHz units since
+  -- 1970-01-01 00:00:00 UTC excluding leap seconds.
+  start_time_90k integer not null check (start_time_90k > 0),
+  end_time_90k integer not null check (end_time_90k >= start_time_90k),
+
+  text text not null check (length(text) > 0),
+  created_sec integer not null
+);
+
+create index note_stream_start on note (stream_id, start_time_90k);
+
+-- A periodic record of a stream's hash chain head, so that removing the
+-- newest recordings or rewriting the chain is detectable. Anchors are also
+-- published for external witnesses; see chain.rs.
+create table chain_anchor (
+  id integer primary key,
+  stream_id integer not null references stream (id),
+
+  -- The most recent recording covered by the anchor, and its chain_sha1.
+  -- The recording itself may since have been deleted by retention.
+  composite_id integer not null,
+  chain_sha1 blob not null check (length(chain_sha1) = 20),
+
+  time_sec integer not null
+);
+
+create index chain_anchor_stream on chain_anchor (stream_id, composite_id);
+
+-- An incident: a named collection of related events, time ranges, notes, and
+-- exports, gathered for an investigation.
+create table incident (
+  id integer primary key,
+  uuid blob unique not null check (length(uuid) = 16),
+  title text not null check (length(title) > 0),
+  description text not null,
+  created_sec integer not null
+);
+
+-- Something referenced by an incident: exactly one of an event, a note, an
+-- export, or a time range of a camera.
+create table incident_item (
+  id integer primary key,
+  incident_id integer not null references incident (id),
+  event_id integer references event (id),
+  note_id integer references note (id),
+
+  -- The uuid of an export job. This isn't a reference to the job table, as
+  -- the export may be deleted independently of the incident.
+  export_uuid blob check (length(export_uuid) = 16),
+
+  camera_id integer references camera (id),
+  start_time_90k integer,
+  end_time_90k integer,
+
+  check ((event_id is not null) + (note_id is not null) +
+         (export_uuid is not null) + (camera_id is not null) = 1),
+  check ((camera_id is null) = (start_time_90k is null) and
+         (camera_id is null) = (end_time_90k is null)),
+  check (end_time_90k >= start_time_90k)
+);
+
+create index incident_item_incident on incident_item (incident_id);
+
+-- Per-user settings of the web UI (such as a default camera layout, playback
+-- speed, or theme), so they follow the user across devices.
+create table user_preference (
+  user_id integer not null references user (id),
+  key text not null check (length(key) > 0),
+
+  -- An arbitrary JSON value, opaque to the server.
+  value text not null,
+
+  -- Incremented on each change, starting from 1, so that a client can avoid
+  -- overwriting a change made on another device since it last read the value.
+  version integer not null check (version > 0),
+
+  primary key (user_id, key)
+) without rowid;
+
+-- A saved view: a named grid of cameras, such as for a monitoring wall. A
+-- layout is visible only to its owner unless shared, in which case any user
+-- can use it.
+create table layout (
+  id integer primary key,
+  uuid blob unique not null check (length(uuid) = 16),
+  name text not null check (length(name) > 0),
+  owner_user_id integer not null references user (id),
+  shared integer not null check (shared in (0, 1)),
+  rows integer not null check (rows between 1 and 16),
+  columns integer not null check (columns between 1 and 16),
+  created_sec integer not null
+);
+
+-- A non-empty cell of a layout's grid.
+create table layout_cell (
+  layout_id integer not null references layout (id),
+
+  -- The cell's index within the grid, in row-major order.
+  position integer not null check (position >= 0),
+
+  -- The uuid of the camera to show. This isn't a reference to the camera
+  -- table, so that removing a camera leaves an empty cell rather than
+  -- requiring every layout using it to be edited first.
+  camera_uuid blob not null check (length(camera_uuid) = 16),
+  stream_type text not null check (stream_type in ('main', 'sub')),
+
+  primary key (layout_id, position)
+) without rowid;
+
+-- Hourly totals of bytes served by the web server, for spotting clients stuck
+-- in download loops and for enforcing tenant quotas.
+create table bandwidth_hour (
+  -- The start of the hour, in seconds since epoch.
+  start_sec integer not null check (start_sec % 3600 = 0),
+
+  -- The name of the requesting user, as supplied by an authenticating reverse
+  -- proxy, or '' if unknown.
+  user_name text not null,
+
+  -- The camera whose data was served, or null for other requests (such as
+  -- the UI's static files). Like layout_cell's, this isn't a reference to the
+  -- camera table, so that history is kept after removing a camera.
+  camera_uuid blob check (camera_uuid is null or length(camera_uuid) = 16),
+
+  requests integer not null check (requests >= 0),
+  bytes integer not null check (bytes >= 0)
+);
+
+create index bandwidth_hour_start on bandwidth_hour (start_sec);
+
+-- User-defined key/value labels on a camera, such as "location" => "garage".
+create table camera_label (
+  camera_id integer not null references camera (id),
+  key text not null check (length(key) > 0),
+  value text not null,
+  primary key (camera_id, key)
+) without rowid;
+
+-- Web Push subscriptions, to which notifications (such as cameras going
+-- offline) are delivered.
+create table push_subscription (
+  id integer primary key,
+
+  -- The push service URL, as supplied by the browser's PushManager.
+  endpoint text unique not null,
+
+  -- The subscription's public key and authentication secret, base64url-
+  -- encoded, as supplied by the browser. These are needed to encrypt
+  -- notification payloads.
+  p256dh text not null,
+  auth text not null
+);
+
+-- A litigation hold: a camera and time range whose recordings must be
+-- preserved (for example, as evidence for a pending claim). Recordings
+-- overlapping a hold can't be deleted, either manually or by retention, until
+-- it's released (deleted).
+create table hold (
+  id integer primary key,
+  uuid blob unique not null check (length(uuid) = 16),
+  camera_id integer not null references camera (id),
+  start_time_90k integer not null check (start_time_90k > 0),
+  end_time_90k integer not null check (end_time_90k > start_time_90k),
+
+  -- A human-readable reason for the hold, such as a claim number.
+  reason text not null,
+  created_sec integer not null,
+
+  -- When the hold expires, in seconds since epoch, or null if it lasts until
+  -- released. Expired holds are removed by the next flush.
+  expires_sec integer check (expires_sec > created_sec)
+);
+
+-- Named sets of export options, so that organizational policy is applied
+-- consistently. A preset named "default" applies to exports which don't name
+-- one.
+create table export_preset (
+  id integer primary key,
+  name text unique not null check (length(name) > 0),
+
+  -- The maximum height of exported video, in pixels; taller video is scaled
+  -- down. Null for no limit.
+  max_height integer check (max_height > 0 and max_height % 2 = 0),
+
+  -- If exports must be watermarked with the requesting user.
+  watermark integer not null check (watermark in (0, 1)),
+
+  -- If exports include the timestamp subtitle track.
+  timestamps integer not null check (timestamps in (0, 1)),
+
+  -- The container format: 'mp4' or 'mkv' (Matroska).
+  container text not null check (container in ('mp4', 'mkv'))
+);
+
+-- Background jobs (such as exports), as run by the server's job queue. These
+-- persist across restarts; jobs which were running are run again.
+create table job (
+  id integer primary key,
+  uuid blob unique not null check (length(uuid) = 16),
+
+  -- The kind of job, such as "export". The server has a handler for each.
+  type text not null,
+
+  -- Kind-specific parameters, as a JSON object.
+  params text not null,
+
+  -- 0 = pending, 1 = running, 2 = done, 3 = failed, 4 = cancelled.
+  state integer not null check (state between 0 and 4),
+
+  created_sec integer not null,
+  finished_sec integer,
+
+  -- A kind-specific JSON result (if done) or an error message (if failed).
+  result text
+);
+
+-- Small JPEGs of a stream's key frames, taken while recording (at intervals of
+-- the stream's thumbnail_interval_sec) for thumbnails, storyboards, and
+-- scrubbing without reading sample files. Thumbnails are deleted along with
+-- the recordings they cover.
+create table thumbnail (
+  id integer primary key,
+  stream_id integer not null references stream (id),
+  time_90k integer not null,
+  jpeg blob not null check (length(jpeg) > 0),
+  unique (stream_id, time_90k)
+);
+
+-- Snapshots of cameras taken on a schedule (see camera.snapshot_interval_sec).
+-- Unlike thumbnails, these are independent of recordings; they're deleted
+-- after the camera's snapshot_retain_days.
+create table scheduled_snapshot (
+  camera_id integer not null references camera (id),
+  time_90k integer not null,
+  jpeg blob not null check (length(jpeg) > 0),
+  primary key (camera_id, time_90k)
+) without rowid;
+
 insert into version (id, unix_time,                           notes)
-             values (3,  cast(strftime('%s', 'now') as int), 'db creation');
+             values (4,  cast(strftime('%s', 'now') as int), 'db creation');
@@ -48,6 +48,9 @@ static INIT: sync::Once = sync::ONCE_INIT;
 pub const TEST_CAMERA_ID: i32 = 1;
 pub const TEST_STREAM_ID: i32 = 1;
 
+/// username of the user created by `TestDb::new` below.
+pub const TEST_USER: &'static str = "test user";
+
 /// Performs global initialization for tests.
 ///    * set up logging. (Note the output can be confusing unless `RUST_TEST_THREADS=1` is set in
 ///      the program's environment prior to running.)
@@ -67,26 +70,28 @@ pub fn init() {
 pub struct TestDb<C: Clocks + Clone> {
     pub db: Arc<db::Database<C>>,
     pub dirs_by_stream_id: Arc<FnvHashMap<i32, Arc<dir::SampleFileDir>>>,
-    pub syncer_channel: writer::SyncerChannel<::std::fs::File>,
+    pub syncer_channel: writer::SyncerChannel<dir::SampleFileWriter>,
     pub syncer_join: thread::JoinHandle<()>,
     pub tmpdir: TempDir,
     pub test_camera_uuid: Uuid,
 }
 
 impl<C: Clocks + Clone> TestDb<C> {
-    /// Creates a test database with one camera.
+    /// Creates a test database with one camera and one user.
     pub fn new(clocks: C) -> Self {
         let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
 
         let mut conn = rusqlite::Connection::open_in_memory().unwrap();
         db::init(&mut conn).unwrap();
+        conn.execute("insert into user (id, username, flags) values (1, ?, 0)", &[&TEST_USER])
+            .unwrap();
         let db = Arc::new(db::Database::new(clocks, conn, true).unwrap());
         let (test_camera_uuid, sample_file_dir_id);
         let path = tmpdir.path().to_str().unwrap().to_owned();
         let dir;
         {
             let mut l = db.lock();
-            sample_file_dir_id = l.add_sample_file_dir(path.to_owned()).unwrap();
+            sample_file_dir_id = l.add_sample_file_dir(path.to_owned(), false).unwrap();
             assert_eq!(TEST_CAMERA_ID, l.add_camera(db::CameraChange {
                 short_name: "test camera".to_owned(),
                 description: "".to_owned(),
@@ -96,18 +101,32 @@ impl<C: Clocks + Clone> TestDb<C> {
                 streams: [
                     db::StreamChange {
                         sample_file_dir_id: Some(sample_file_dir_id),
+                        mirror_sample_file_dir_id: None,
                         rtsp_path: "/main".to_owned(),
+                        source: db::StreamSource::Rtsp,
                         record: true,
                         flush_if_sec: 0,
+                        recording_duration_sec: 60,
+                        sei_motion_uuid: None,
+                        snapshot_url: None,
+                        metadata_events: false,
+                        thumbnail_interval_sec: 0,
+                        encoder: Default::default(),
+                        input_options: None,
                     },
                     Default::default(),
                 ],
+                labels: Default::default(),
+                tenant_id: None,
+                event_source: None,
+                snapshot_schedule: None,
             }).unwrap());
             test_camera_uuid = l.cameras_by_id().get(&TEST_CAMERA_ID).unwrap().uuid;
             l.update_retention(&[db::RetentionChange {
                 stream_id: TEST_STREAM_ID,
                 new_record: true,
                 new_limit: 1048576,
+                new_weight: 1,
             }]).unwrap();
             dir = l.sample_file_dirs_by_id().get(&sample_file_dir_id).unwrap().get().unwrap();
         }
@@ -126,14 +145,18 @@ impl<C: Clocks + Clone> TestDb<C> {
     }
 
     /// Creates a recording with a fresh `RecordingToInsert` row which has been touched only by
-    /// a `SampleIndexEncoder`. Fills in a video sample entry id and such to make it valid.
+    /// a `SampleIndexEncoder`. Fills in a video sample entry id (unless one is already set) and
+    /// such to make it valid.
     /// There will no backing sample file, so it won't be possible to generate a full `.mp4`.
     pub fn insert_recording_from_encoder(&self, r: db::RecordingToInsert)
                                                 -> db::ListRecordingsRow {
         use recording::{self, TIME_UNITS_PER_SEC};
         let mut db = self.db.lock();
-        let video_sample_entry_id = db.insert_video_sample_entry(
-            1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap();
+        let video_sample_entry_id = match r.video_sample_entry_id {
+            0 => db.insert_video_sample_entry(
+                1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap(),
+            id => id,
+        };
         let (id, _) = db.add_recording(TEST_STREAM_ID, db::RecordingToInsert {
             start: recording::Time(1430006400i64 * TIME_UNITS_PER_SEC),
             video_sample_entry_id,
@@ -39,6 +39,7 @@ use rusqlite::{self, types::ToSql};
 mod v0_to_v1;
 mod v1_to_v2;
 mod v2_to_v3;
+mod v3_to_v4;
 
 const UPGRADE_NOTES: &'static str =
     concat!("upgraded using moonfire-db ", env!("CARGO_PKG_VERSION"));
@@ -63,6 +64,7 @@ pub fn run(args: &Args, conn: &mut rusqlite::Connection) -> Result<(), Error> {
         v0_to_v1::run,
         v1_to_v2::run,
         v2_to_v3::run,
+        v3_to_v4::run,
     ];
 
     {
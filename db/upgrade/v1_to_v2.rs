@@ -32,11 +32,10 @@
 
 use dir;
 use failure::Error;
-use libc;
+use platform;
 use rusqlite::{self, types::ToSql};
 use schema::DirMeta;
 use std::fs;
-use std::os::unix::ffi::OsStrExt;
 use uuid::Uuid;
 
 pub fn run(args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
@@ -46,7 +45,7 @@ pub fn run(args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error>
                                         schema version 1 to 2."))?;
 
     let d = dir::Fd::open(sample_file_path, false)?;
-    d.lock(libc::LOCK_EX | libc::LOCK_NB)?;
+    d.lock(dir::LockMode::Exclusive)?;
     verify_dir_contents(sample_file_path, tx)?;
 
     // These create statements match the schema.sql when version 2 was the latest.
@@ -303,7 +302,7 @@ fn verify_dir_contents(sample_file_path: &str, tx: &rusqlite::Transaction) -> Re
     for e in fs::read_dir(sample_file_path)? {
         let e = e?;
         let f = e.file_name();
-        match f.as_bytes() {
+        match platform::name_bytes(&f) {
             b"." | b".." => continue,
             b"meta" | b"meta-tmp" => {
                 // Ignore metadata files. These might from a half-finished update attempt.
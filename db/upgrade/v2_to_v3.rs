@@ -35,10 +35,8 @@
 use db::{self, FromSqlUuid};
 use dir;
 use failure::Error;
-use libc;
 use schema;
-use std::io::{self, Write};
-use std::mem;
+use std::io;
 use std::sync::Arc;
 use rusqlite::{self, types::ToSql};
 use uuid::Uuid;
@@ -72,7 +70,7 @@ fn open_sample_file_dir(tx: &rusqlite::Transaction) -> Result<Arc<dir::SampleFil
         open.id = o_id as u32;
         open.uuid.extend_from_slice(&o_uuid.0.as_bytes()[..]);
     }
-    dir::SampleFileDir::open(&p, &meta)
+    dir::SampleFileDir::open(&p, &meta, false, None)
 }
 
 pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
@@ -91,7 +89,7 @@ pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error>
         let sample_file_uuid: FromSqlUuid = row.get_checked(1)?;
         let from_path = get_uuid_pathname(sample_file_uuid.0);
         let to_path = get_id_pathname(id);
-        let r = unsafe { dir::renameat(&d.fd, from_path.as_ptr(), &d.fd, to_path.as_ptr()) };
+        let r = d.fd.rename(&from_path, &to_path);
         if let Err(e) = r {
             if e.kind() == io::ErrorKind::NotFound {
                 continue;  // assume it was already moved.
@@ -122,17 +120,6 @@ pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error>
 }
 
 /// Gets a pathname for a sample file suitable for passing to open or unlink.
-fn get_uuid_pathname(uuid: Uuid) -> [libc::c_char; 37] {
-    let mut buf = [0u8; 37];
-    write!(&mut buf[..36], "{}", uuid.to_hyphenated_ref())
-        .expect("can't format uuid to pathname buf");
+fn get_uuid_pathname(uuid: Uuid) -> String { uuid.to_hyphenated_ref().to_string() }
 
-    // libc::c_char seems to be i8 on some platforms (Linux/arm) and u8 on others (Linux/amd64).
-    unsafe { mem::transmute::<[u8; 37], [libc::c_char; 37]>(buf) }
-}
-
-fn get_id_pathname(id: db::CompositeId) -> [libc::c_char; 17] {
-    let mut buf = [0u8; 17];
-    write!(&mut buf[..16], "{:016x}", id.0).expect("can't format id to pathname buf");
-    unsafe { mem::transmute::<[u8; 17], [libc::c_char; 17]>(buf) }
-}
+fn get_id_pathname(id: db::CompositeId) -> String { format!("{:016x}", id.0) }
@@ -0,0 +1,351 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+/// Upgrades a version 3 schema to a version 4 schema.
+
+use failure::Error;
+use recording;
+use rusqlite;
+
+pub fn run(_args: &super::Args, tx: &rusqlite::Transaction) -> Result<(), Error> {
+    // Camera short names become unique, so that they can be used in URLs. Rather than pick new
+    // names on the user's behalf, refuse to upgrade until duplicates are renamed.
+    {
+        let mut stmt = tx.prepare(r#"
+            select short_name from camera group by short_name having count(*) > 1
+        "#)?;
+        let mut rows = stmt.query(&[] as &[&rusqlite::types::ToSql])?;
+        let mut dups = Vec::new();
+        while let Some(row) = rows.next() {
+            let name: String = row?.get_checked(0)?;
+            dups.push(name);
+        }
+        if !dups.is_empty() {
+            bail!("camera short names must be unique; rename cameras named {:?} before upgrading",
+                  dups);
+        }
+    }
+
+    // These create statements match the schema.sql when version 4 was the latest.
+    tx.execute_batch(r#"
+        create table event (
+          id integer primary key,
+          camera_id integer not null references camera (id),
+          type text not null,
+          start_time_90k integer not null check (start_time_90k > 0),
+          end_time_90k integer not null check (end_time_90k >= start_time_90k),
+          description text,
+          score real
+        );
+        create index event_camera_start on event (camera_id, start_time_90k);
+
+        create table event_snapshot (
+          event_id integer primary key references event (id),
+          jpeg blob not null check (length(jpeg) > 0)
+        );
+
+        create table thumbnail (
+          id integer primary key,
+          stream_id integer not null references stream (id),
+          time_90k integer not null,
+          jpeg blob not null check (length(jpeg) > 0),
+          unique (stream_id, time_90k)
+        );
+
+        create table scheduled_snapshot (
+          camera_id integer not null references camera (id),
+          time_90k integer not null,
+          jpeg blob not null check (length(jpeg) > 0),
+          primary key (camera_id, time_90k)
+        ) without rowid;
+
+        create table export_preset (
+          id integer primary key,
+          name text unique not null check (length(name) > 0),
+          max_height integer check (max_height > 0 and max_height % 2 = 0),
+          watermark integer not null check (watermark in (0, 1)),
+          timestamps integer not null check (timestamps in (0, 1)),
+          container text not null check (container in ('mp4', 'mkv'))
+        );
+
+        create table event_detection (
+          event_id integer not null references event (id),
+          label text not null check (length(label) > 0),
+          score real check (score between 0 and 1),
+          x real not null check (x between 0 and 1),
+          y real not null check (y between 0 and 1),
+          width real not null check (width > 0 and x + width <= 1),
+          height real not null check (height > 0 and y + height <= 1)
+        );
+        create index event_detection_event on event_detection (event_id);
+
+        create table note (
+          id integer primary key,
+          stream_id integer not null references stream (id),
+          start_time_90k integer not null check (start_time_90k > 0),
+          end_time_90k integer not null check (end_time_90k >= start_time_90k),
+          text text not null check (length(text) > 0),
+          created_sec integer not null
+        );
+        create index note_stream_start on note (stream_id, start_time_90k);
+
+        create table chain_anchor (
+          id integer primary key,
+          stream_id integer not null references stream (id),
+          composite_id integer not null,
+          chain_sha1 blob not null check (length(chain_sha1) = 20),
+          time_sec integer not null
+        );
+        create index chain_anchor_stream on chain_anchor (stream_id, composite_id);
+
+        create table incident (
+          id integer primary key,
+          uuid blob unique not null check (length(uuid) = 16),
+          title text not null check (length(title) > 0),
+          description text not null,
+          created_sec integer not null
+        );
+        create table incident_item (
+          id integer primary key,
+          incident_id integer not null references incident (id),
+          event_id integer references event (id),
+          note_id integer references note (id),
+          export_uuid blob check (length(export_uuid) = 16),
+          camera_id integer references camera (id),
+          start_time_90k integer,
+          end_time_90k integer,
+          check ((event_id is not null) + (note_id is not null) +
+                 (export_uuid is not null) + (camera_id is not null) = 1),
+          check ((camera_id is null) = (start_time_90k is null) and
+                 (camera_id is null) = (end_time_90k is null)),
+          check (end_time_90k >= start_time_90k)
+        );
+        create index incident_item_incident on incident_item (incident_id);
+
+        create table camera_label (
+          camera_id integer not null references camera (id),
+          key text not null check (length(key) > 0),
+          value text not null,
+          primary key (camera_id, key)
+        ) without rowid;
+
+        create table tenant (
+          id integer primary key,
+          uuid blob unique not null check (length(uuid) = 16),
+          short_name text not null,
+          retain_bytes integer check (retain_bytes >= 0),
+          watermark_exports integer not null default 0
+              check (watermark_exports in (0, 1))
+        );
+        alter table camera add column tenant_id integer references tenant (id);
+        alter table user add column tenant_id integer references tenant (id);
+        alter table camera add column event_source text
+            check (event_source in ('onvif', 'hikvision', 'dahua'));
+        alter table camera add column snapshot_interval_sec integer
+            check (snapshot_interval_sec is null or snapshot_interval_sec > 0);
+        alter table camera add column snapshot_retain_days integer not null default 30
+            check (snapshot_retain_days > 0);
+        alter table camera add column previous_username text;
+        alter table camera add column previous_password text
+            check ((previous_username is null) = (previous_password is null));
+        create unique index camera_short_name on camera (short_name);
+        alter table sample_file_dir add column network_fs integer not null default 0
+            check (network_fs in (0, 1));
+        alter table sample_file_dir add column reserved_bytes integer not null default 0
+            check (reserved_bytes >= 0);
+        alter table sample_file_dir add column wrapped_key blob
+            check (wrapped_key is null or length(wrapped_key) = 40);
+        alter table stream add column retain_weight integer not null default 1
+            check (retain_weight > 0);
+        alter table stream add column recording_duration_sec integer not null default 60
+            check (recording_duration_sec > 0 and recording_duration_sec <= 180);
+        alter table stream add column sei_motion_uuid blob
+            check (sei_motion_uuid is null or length(sei_motion_uuid) = 16);
+        alter table stream add column snapshot_url text
+            check (snapshot_url is null or snapshot_url like 'http://%' or
+                   snapshot_url like 'https://%');
+        alter table stream add column source text not null default 'rtsp'
+            check (source in ('rtsp', 'hls', 'mjpeg', 'v4l2', 'push'));
+        alter table stream add column source_url text
+            check ((source = 'rtsp') = (source_url is null) and
+                   (source_url is null or
+                    (source = 'v4l2') = (source_url like '/dev/%') and
+                    (source = 'push') = (source_url like 'rtmp://%' or
+                                         source_url like 'srt://%') and
+                    (source_url like '/dev/%' or source_url like 'http://%' or
+                     source_url like 'https://%' or source_url like 'rtmp://%' or
+                     source_url like 'srt://%')));
+        alter table stream add column metadata_events integer not null default 0
+            check (metadata_events in (0, 1));
+        alter table stream add column thumbnail_interval_sec integer not null default 0
+            check (thumbnail_interval_sec >= 0);
+        alter table stream add column paused integer not null default 0
+            check (paused in (0, 1));
+        alter table stream add column encoder_gov_length integer
+            check (encoder_gov_length > 0);
+        alter table stream add column encoder_bitrate_kbps integer
+            check (encoder_bitrate_kbps > 0);
+        alter table stream add column encoder_frame_rate integer
+            check (encoder_frame_rate > 0);
+        alter table stream add column encoder_width integer check (encoder_width > 0);
+        alter table stream add column encoder_height integer
+            check (encoder_height > 0 and (encoder_width is null) = (encoder_height is null));
+        alter table stream add column input_options text;
+        alter table stream add column mirror_sample_file_dir_id integer
+            references sample_file_dir (id);
+        alter table stream add column chain_sha1 blob
+            check (chain_sha1 is null or length(chain_sha1) = 20);
+        alter table recording_integrity add column chain_sha1 blob
+            check (chain_sha1 is null or length(chain_sha1) = 20);
+
+        create table user_preference (
+          user_id integer not null references user (id),
+          key text not null check (length(key) > 0),
+          value text not null,
+          version integer not null check (version > 0),
+          primary key (user_id, key)
+        ) without rowid;
+
+        create table layout (
+          id integer primary key,
+          uuid blob unique not null check (length(uuid) = 16),
+          name text not null check (length(name) > 0),
+          owner_user_id integer not null references user (id),
+          shared integer not null check (shared in (0, 1)),
+          rows integer not null check (rows between 1 and 16),
+          columns integer not null check (columns between 1 and 16),
+          created_sec integer not null
+        );
+
+        create table layout_cell (
+          layout_id integer not null references layout (id),
+          position integer not null check (position >= 0),
+          camera_uuid blob not null check (length(camera_uuid) = 16),
+          stream_type text not null check (stream_type in ('main', 'sub')),
+          primary key (layout_id, position)
+        ) without rowid;
+
+        create table bandwidth_hour (
+          start_sec integer not null check (start_sec % 3600 = 0),
+          user_name text not null,
+          camera_uuid blob check (camera_uuid is null or length(camera_uuid) = 16),
+          requests integer not null check (requests >= 0),
+          bytes integer not null check (bytes >= 0)
+        );
+        create index bandwidth_hour_start on bandwidth_hour (start_sec);
+
+        create table push_subscription (
+          id integer primary key,
+          endpoint text unique not null,
+          p256dh text not null,
+          auth text not null
+        );
+
+        create table hold (
+          id integer primary key,
+          uuid blob unique not null check (length(uuid) = 16),
+          camera_id integer not null references camera (id),
+          start_time_90k integer not null check (start_time_90k > 0),
+          end_time_90k integer not null check (end_time_90k > start_time_90k),
+          reason text not null,
+          created_sec integer not null,
+          expires_sec integer check (expires_sec > created_sec)
+        );
+
+        create table job (
+          id integer primary key,
+          uuid blob unique not null check (length(uuid) = 16),
+          type text not null,
+          params text not null,
+          state integer not null check (state between 0 and 4),
+          created_sec integer not null,
+          finished_sec integer,
+          result text
+        );
+
+        create table recording_metadata (
+          composite_id integer primary key references recording (composite_id),
+          metadata blob not null check (length(metadata) > 0)
+        );
+
+        create table recording_archive (
+          composite_id integer primary key references recording (composite_id),
+          location text not null
+        );
+
+        alter table recording add column min_frame_interval_90k integer not null default 0
+            check (min_frame_interval_90k >= 0);
+        alter table recording add column max_frame_interval_90k integer not null default 0
+            check (max_frame_interval_90k >= min_frame_interval_90k);
+        drop index recording_cover;
+        create index recording_cover on recording (
+          stream_id,
+          start_time_90k,
+          open_id,
+          duration_90k,
+          video_samples,
+          video_sync_samples,
+          video_sample_entry_id,
+          sample_file_bytes,
+          run_offset,
+          flags,
+          min_frame_interval_90k,
+          max_frame_interval_90k
+        );
+    "#)?;
+    fill_frame_intervals(tx)?;
+    Ok(())
+}
+
+/// Fills in the frame intervals of existing recordings from their video indexes.
+fn fill_frame_intervals(tx: &rusqlite::Transaction) -> Result<(), Error> {
+    let mut select = tx.prepare(r#"
+        select composite_id, video_index from recording_playback
+    "#)?;
+    let mut update = tx.prepare(r#"
+        update recording
+        set min_frame_interval_90k = :min, max_frame_interval_90k = :max
+        where composite_id = :composite_id
+    "#)?;
+    let mut rows = select.query(&[] as &[&rusqlite::types::ToSql])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let id: i64 = row.get_checked(0)?;
+        let video_index: Vec<u8> = row.get_checked(1)?;
+        let (min, max) = recording::frame_interval_range(&video_index)
+            .map_err(|e| format_err!("recording {}: bad video index: {}", id, e))?;
+        update.execute_named(&[
+            (":min", &min),
+            (":max", &max),
+            (":composite_id", &id),
+        ])?;
+    }
+    Ok(())
+}
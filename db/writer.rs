@@ -33,17 +33,19 @@
 //! This includes opening files for serving, rotating away old files, and saving new files.
 
 use base::clock::{self, Clocks};
+use base::sched;
+use base::trace;
 use db::{self, CompositeId};
 use dir;
 use failure::Error;
 use fnv::FnvHashMap;
 use parking_lot::Mutex;
+use platform;
 use recording;
 use openssl::hash;
 use std::cmp;
 use std::io;
 use std::mem;
-use std::os::unix::ffi::OsStrExt;
 use std::sync::Arc;
 use std::sync::mpsc;
 use std::thread;
@@ -59,15 +61,15 @@ pub trait DirWriter : 'static + Send {
 }
 
 pub trait FileWriter : 'static {
-    /// As in `std::fs::File::sync_all`.
-    fn sync_all(&self) -> Result<(), io::Error>;
+    /// As in `std::fs::File::sync_all`, but also writing out any buffered data first.
+    fn sync_all(&mut self) -> Result<(), io::Error>;
 
     /// As in `std::io::Writer::write`.
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error>;
 }
 
 impl DirWriter for Arc<dir::SampleFileDir> {
-    type File = ::std::fs::File;
+    type File = dir::SampleFileWriter;
 
     fn create_file(&self, id: CompositeId) -> Result<Self::File, io::Error> {
         dir::SampleFileDir::create_file(self, id)
@@ -78,9 +80,11 @@ impl DirWriter for Arc<dir::SampleFileDir> {
     }
 }
 
-impl FileWriter for ::std::fs::File {
-    fn sync_all(&self) -> Result<(), io::Error> { self.sync_all() }
-    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> { io::Write::write(self, buf) }
+impl FileWriter for dir::SampleFileWriter {
+    fn sync_all(&mut self) -> Result<(), io::Error> { dir::SampleFileWriter::sync_all(self) }
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        dir::SampleFileWriter::write(self, buf)
+    }
 }
 
 /// A command sent to the syncer. These correspond to methods in the `SyncerChannel` struct.
@@ -129,10 +133,11 @@ struct Syncer<C: Clocks + Clone, D: DirWriter> {
 /// `LockedDatabase::clear_on_flush`, as this function installs a hook to watch database flushes.
 /// TODO: add a join wrapper which arranges for the on flush hook to be removed automatically.
 pub fn start_syncer<C>(db: Arc<db::Database<C>>, dir_id: i32)
-                       -> Result<(SyncerChannel<::std::fs::File>, thread::JoinHandle<()>), Error>
+                       -> Result<(SyncerChannel<dir::SampleFileWriter>, thread::JoinHandle<()>), Error>
 where C: Clocks + Clone {
     let db2 = db.clone();
-    let (mut syncer, path) = Syncer::new(&db.lock(), db2, dir_id)?;
+    let (mut syncer, path, abandoned) = Syncer::new(&db.lock(), db2, dir_id)?;
+    db.lock().note_abandoned_recordings(&abandoned);
     syncer.initial_rotation()?;
     let (snd, rcv) = mpsc::channel();
     db.lock().on_flush(Box::new({
@@ -144,7 +149,42 @@ where C: Clocks + Clone {
     Ok((SyncerChannel(snd),
         thread::Builder::new()
             .name(format!("sync-{}", path))
-            .spawn(move || syncer.run(rcv)).unwrap()))
+            .spawn(move || {
+                sched::enter(sched::Class::Recorder);
+                syncer.run(rcv)
+            }).unwrap()))
+}
+
+/// Unlinks the given directory's garbage and syncs the directory, as its syncer would after a
+/// flush, then flushes. Returns the number of files collected.
+///
+/// This is for directories without a syncer, such as when the server isn't running. A directory's
+/// syncer expects to be the only one collecting its garbage, so this mustn't be used while it runs.
+pub fn collect_garbage<C: Clocks + Clone>(db: &db::Database<C>, dir_id: i32)
+                                          -> Result<usize, Error> {
+    let (dir, mut garbage) = {
+        let l = db.lock();
+        let d = l.sample_file_dirs_by_id()
+                 .get(&dir_id)
+                 .ok_or_else(|| format_err!("no dir {}", dir_id))?;
+        (d.get()?, d.garbage_needs_unlink.iter().map(|id| *id).collect::<Vec<_>>())
+    };
+    if garbage.is_empty() {
+        return Ok(0);
+    }
+    for &id in &garbage {
+        if let Err(e) = dir.unlink_file(id) {
+            if e.kind() != io::ErrorKind::NotFound {
+                bail!("Unable to unlink {}: {}", id, e);
+            }
+        }
+    }
+    dir.sync()?;
+    let n = garbage.len();
+    let mut l = db.lock();
+    l.delete_garbage(dir_id, &mut garbage)?;
+    l.flush("garbage collection")?;
+    Ok(n)
 }
 
 pub struct NewLimit {
@@ -158,22 +198,21 @@ pub struct NewLimit {
 pub fn lower_retention(db: Arc<db::Database>, dir_id: i32, limits: &[NewLimit])
                        -> Result<(), Error> {
     let db2 = db.clone();
-    let (mut syncer, _) = Syncer::new(&db.lock(), db2, dir_id)?;
+    let (mut syncer, _, _) = Syncer::new(&db.lock(), db2, dir_id)?;
     syncer.do_rotation(|db| {
         for l in limits {
             let (bytes_before, extra);
             {
                 let stream = db.streams_by_id().get(&l.stream_id)
                                .ok_or_else(|| format_err!("no such stream {}", l.stream_id))?;
-                bytes_before = stream.sample_file_bytes + stream.bytes_to_add -
-                               stream.bytes_to_delete;
+                bytes_before = stream.retention_usage();
                 extra = stream.retain_bytes - l.limit;
             }
             if l.limit >= bytes_before { continue }
             delete_recordings(db, l.stream_id, extra)?;
             let stream = db.streams_by_id().get(&l.stream_id).unwrap();
             info!("stream {}, deleting: {}->{}", l.stream_id, bytes_before,
-                  stream.sample_file_bytes + stream.bytes_to_add - stream.bytes_to_delete);
+                  stream.retention_usage());
         }
         Ok(())
     })
@@ -187,8 +226,7 @@ fn delete_recordings(db: &mut db::LockedDatabase, stream_id: i32,
             None => bail!("no stream {}", stream_id),
             Some(s) => s,
         };
-        stream.sample_file_bytes + stream.bytes_to_add - stream.bytes_to_delete + extra_bytes_needed
-            - stream.retain_bytes
+        stream.retention_usage() + extra_bytes_needed - stream.retain_bytes
     };
     let mut bytes_to_delete = 0;
     if bytes_needed <= 0 {
@@ -209,6 +247,140 @@ fn delete_recordings(db: &mut db::LockedDatabase, stream_id: i32,
     Ok(())
 }
 
+/// Deletes recordings to bring a stream's disk usage within its `retain_bytes`, once any retention
+/// grace period has passed. See `LockedDatabase::set_retention_grace`.
+fn delete_recordings_after_grace(db: &mut db::LockedDatabase, stream_id: i32,
+                                 now: recording::Time) -> Result<(), Error> {
+    let grace = db.retention_grace();
+    if grace.0 > 0 {
+        let (usage, limit, was_over) = match db.streams_by_id().get(&stream_id) {
+            None => bail!("no stream {}", stream_id),
+            Some(s) => (s.retention_usage(), s.retain_bytes, s.over_limit_since.is_some()),
+        };
+        if let Some(since) = db.note_over_limit(stream_id, usage > limit, now)? {
+            if now < since + grace {
+                if !was_over {
+                    warn!("{}: usage of {} bytes exceeds limit of {}; deleting in {} unless it \
+                           drops", stream_id, usage, limit, grace);
+                }
+                return Ok(());
+            }
+        }
+    }
+    delete_recordings(db, stream_id, 0)
+}
+
+/// Deletes at least `bytes_needed` bytes of recordings from the given streams, one recording at a
+/// time. Each step takes the oldest recording of the stream whose oldest recording's age divided
+/// by its `retain_weight` is greatest, so a stream with weight 2 keeps roughly twice as much
+/// history as one with weight 1. Returns the number of bytes queued for deletion.
+fn delete_weighted_recordings(db: &mut db::LockedDatabase, stream_ids: &[i32], bytes_needed: i64,
+                              now: recording::Time) -> Result<i64, Error> {
+    let mut bytes_to_delete = 0;
+    while bytes_to_delete < bytes_needed {
+        let mut best: Option<(f64, i32)> = None;
+        for &id in stream_ids {
+            let weight = match db.streams_by_id().get(&id) {
+                None => bail!("no stream {}", id),
+                Some(s) => s.retain_weight,
+            };
+            let mut oldest = None;
+            db.delete_oldest_recordings(id, &mut |row| {
+                oldest = Some(row.start);
+                false
+            })?;
+            if let Some(start) = oldest {
+                let score = (now - start).0 as f64 / weight as f64;
+                if best.map(|(b, _)| score > b).unwrap_or(true) {
+                    best = Some((score, id));
+                }
+            }
+        }
+        let id = match best {
+            None => break,  // nothing left to delete.
+            Some((_, id)) => id,
+        };
+        let mut deleted = false;
+        db.delete_oldest_recordings(id, &mut |row| {
+            if deleted {
+                return false;
+            }
+            deleted = true;
+            bytes_to_delete += row.sample_file_bytes as i64;
+            true
+        })?;
+    }
+    Ok(bytes_to_delete)
+}
+
+/// Deletes recordings to bring the disk usage of the tenant owning the given stream (if any)
+/// within the tenant's quota. Recordings are taken from the tenant's streams according to their
+/// `retain_weight`s; see `delete_weighted_recordings`.
+fn delete_tenant_recordings(db: &mut db::LockedDatabase, stream_id: i32, now: recording::Time)
+                            -> Result<(), Error> {
+    let (tenant_id, limit) = {
+        let s = db.streams_by_id().get(&stream_id)
+                  .ok_or_else(|| format_err!("no stream {}", stream_id))?;
+        let tenant_id = match db.cameras_by_id().get(&s.camera_id).and_then(|c| c.tenant_id) {
+            None => return Ok(()),
+            Some(t) => t,
+        };
+        match db.tenants_by_id().get(&tenant_id).and_then(|t| t.retain_bytes) {
+            None => return Ok(()),
+            Some(l) => (tenant_id, l),
+        }
+    };
+    let mut usage = 0;
+    let ids: Vec<i32> = {
+        let cameras = db.cameras_by_id();
+        db.streams_by_id()
+          .values()
+          .filter(|s| cameras.get(&s.camera_id).and_then(|c| c.tenant_id) == Some(tenant_id))
+          .map(|s| {
+              usage += s.retention_usage();
+              s.id
+          })
+          .collect()
+    };
+    let bytes_needed = usage - limit;
+    if bytes_needed <= 0 {
+        return Ok(());
+    }
+    let bytes_to_delete = delete_weighted_recordings(db, &ids, bytes_needed, now)?;
+    info!("tenant {}: deleting {} bytes ({} bytes needed)", tenant_id, bytes_to_delete,
+          bytes_needed);
+    Ok(())
+}
+
+/// Deletes recordings to keep the given sample file directory's filesystem from falling below its
+/// `reserved_bytes` of free space. As with tenants, recordings are taken from the directory's
+/// streams according to their `retain_weight`s.
+fn delete_reserved_recordings(db: &mut db::LockedDatabase, dir_id: i32, now: recording::Time)
+                              -> Result<(), Error> {
+    let (free, reserved) = {
+        let d = db.sample_file_dirs_by_id().get(&dir_id)
+                  .ok_or_else(|| format_err!("no dir {}", dir_id))?;
+        if d.reserved_bytes == 0 {
+            return Ok(());
+        }
+        (d.get()?.fs_stats()?.available_bytes, d.reserved_bytes)
+    };
+    let mut ids = Vec::new();
+    let mut queued = 0;  // recordings already queued for deletion will free space once unlinked.
+    for s in db.streams_by_id().values().filter(|s| s.sample_file_dir_id == Some(dir_id)) {
+        ids.push(s.id);
+        queued += s.bytes_to_delete;
+    }
+    let bytes_needed = reserved - free - queued;
+    if bytes_needed <= 0 {
+        return Ok(());
+    }
+    let bytes_to_delete = delete_weighted_recordings(db, &ids, bytes_needed, now)?;
+    info!("dir {}: deleting {} bytes to keep {} free ({} bytes needed)",
+          dir_id, bytes_to_delete, reserved, bytes_needed);
+    Ok(())
+}
+
 impl<F: FileWriter> SyncerChannel<F> {
     /// Asynchronously syncs the given writer, closes it, records it into the database, and
     /// starts rotation.
@@ -233,7 +405,7 @@ fn list_files_to_abandon(path: &str, streams_to_next: FnvHashMap<i32, i32>)
     let mut v = Vec::new();
     for e in ::std::fs::read_dir(path)? {
         let e = e?;
-        let id = match dir::parse_id(e.file_name().as_bytes()) {
+        let id = match dir::parse_id(platform::name_bytes(&e.file_name())) {
             Ok(i) => i,
             Err(_) => continue,
         };
@@ -249,8 +421,10 @@ fn list_files_to_abandon(path: &str, streams_to_next: FnvHashMap<i32, i32>)
 }
 
 impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
+    /// Creates a syncer, deleting the sample files of recordings which were never committed.
+    /// Returns the syncer, the directory's path, and the ids of those abandoned recordings.
     fn new(l: &db::LockedDatabase, db: Arc<db::Database<C>>, dir_id: i32)
-           -> Result<(Self, String), Error> {
+           -> Result<(Self, String, Vec<CompositeId>), Error> {
         let d = l.sample_file_dirs_by_id()
                  .get(&dir_id)
                  .ok_or_else(|| format_err!("no dir {}", dir_id))?;
@@ -290,17 +464,21 @@ impl<C: Clocks + Clone> Syncer<C, Arc<dir::SampleFileDir>> {
             dir,
             db,
             next_flush: None,
-        }, d.path.clone()))
+        }, d.path.clone(), to_abandon))
     }
 
     /// Rotates files for all streams and deletes stale files from previous runs.
     /// Called from main thread.
     fn initial_rotation(&mut self) -> Result<(), Error> {
+        let dir_id = self.dir_id;
+        let now = recording::Time::new(self.db.clocks().realtime());
         self.do_rotation(|db| {
             let streams: Vec<i32> = db.streams_by_id().keys().map(|&id| id).collect();
             for &stream_id in &streams {
-                delete_recordings(db, stream_id, 0)?;
+                delete_recordings_after_grace(db, stream_id, now)?;
+                delete_tenant_recordings(db, stream_id, now)?;
             }
+            delete_reserved_recordings(db, dir_id, now)?;
             Ok(())
         })
     }
@@ -421,15 +599,21 @@ impl<C: Clocks + Clone, D: DirWriter> Syncer<C, D> {
     /// so that there can be only one dir sync and database transaction per save.
     /// Internal helper for `save`. This is separated out so that the question-mark operator
     /// can be used in the many error paths.
-    fn save(&mut self, id: CompositeId, duration: recording::Duration, f: D::File) {
+    fn save(&mut self, id: CompositeId, duration: recording::Duration, mut f: D::File) {
+        let _span = trace::span_with("syncer save", || Some(id.to_string()));
         let stream_id = id.stream();
 
         // Free up a like number of bytes.
         clock::retry_forever(&self.db.clocks(), &mut || f.sync_all());
         clock::retry_forever(&self.db.clocks(), &mut || self.dir.sync());
+        let now = recording::Time::new(self.db.clocks().realtime());
         let mut db = self.db.lock();
         db.mark_synced(id).unwrap();
-        delete_recordings(&mut db, stream_id, 0).unwrap();
+        delete_recordings_after_grace(&mut db, stream_id, now).unwrap();
+        delete_tenant_recordings(&mut db, stream_id, now).unwrap();
+        if let Err(e) = delete_reserved_recordings(&mut db, self.dir_id, now) {
+            warn!("dir {}: unable to maintain reserved space: {}", self.dir_id, e);
+        }
         let s = db.streams_by_id().get(&stream_id).unwrap();
         let c = db.cameras_by_id().get(&s.camera_id).unwrap();
 
@@ -470,6 +654,8 @@ pub struct Writer<'a, C: Clocks + Clone, D: DirWriter> {
     channel: &'a SyncerChannel<D::File>,
     stream_id: i32,
     video_sample_entry_id: i32,
+    degraded: bool,
+    max_spool_bytes: usize,
     state: WriterState<D::File>,
 }
 
@@ -484,7 +670,13 @@ enum WriterState<F: FileWriter> {
 /// Note that the recording created by every `InnerWriter` must be written to the `SyncerChannel`
 /// with at least one sample. The sample may have zero duration.
 struct InnerWriter<F: FileWriter> {
-    f: F,
+    /// The sample file, or `None` if it couldn't be created yet. See `Writer::set_max_spool_bytes`.
+    f: Option<F>,
+
+    /// Sample data which has been accepted but not yet written to `f`, because the sample file
+    /// directory is (hopefully temporarily) unavailable.
+    spool: Vec<u8>,
+
     r: Arc<Mutex<db::RecordingToInsert>>,
     e: recording::SampleIndexEncoder,
     id: CompositeId,
@@ -499,6 +691,13 @@ struct InnerWriter<F: FileWriter> {
 
     adjuster: ClockAdjuster,
 
+    /// The pts of the recording's first frame, once known. Metadata packets are timed relative
+    /// to it.
+    first_pts_90k: Option<i64>,
+
+    /// True once a metadata packet has been dropped for exceeding `MAX_METADATA_BYTES`.
+    metadata_full: bool,
+
     /// A sample which has been written to disk but not added to `index`. Index writes are one
     /// sample behind disk writes because the duration of a sample is the difference between its
     /// pts and the next sample's pts. A sample is flushed when the next sample is written, when
@@ -583,10 +782,35 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             channel,
             stream_id,
             video_sample_entry_id,
+            degraded: false,
+            max_spool_bytes: 0,
             state: WriterState::Unopened,
         }
     }
 
+    /// Sets the maximum number of bytes to hold in memory when the sample file directory is
+    /// unavailable (as when a USB disk is unplugged or an NFS server is unresponsive). Until the
+    /// limit is reached, `write` accepts frames without blocking; they're written out once the
+    /// directory returns. Beyond it, `write` blocks until the directory is writable again, as it
+    /// does always if the limit is 0 (the default).
+    pub fn set_max_spool_bytes(&mut self, max_spool_bytes: usize) {
+        self.max_spool_bytes = max_spool_bytes;
+    }
+
+    /// Returns the number of bytes currently spooled in memory; see `set_max_spool_bytes`.
+    pub fn spooled_bytes(&self) -> usize {
+        match self.state {
+            WriterState::Open(ref w) => w.spool.len(),
+            _ => 0,
+        }
+    }
+
+    /// Marks subsequently opened recordings as taken from a fallback source.
+    /// See `db::RecordingFlags::Degraded`.
+    pub fn set_degraded(&mut self, degraded: bool) {
+        self.degraded = degraded;
+    }
+
     /// Opens a new writer.
     /// This returns a writer that violates the invariant that `unflushed_sample` is `Some`.
     /// The caller (`write`) is responsible for correcting this.
@@ -600,19 +824,33 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
             run_offset: prev.map(|p| p.run_offset + 1).unwrap_or(0),
             start: prev.map(|p| p.end).unwrap_or(recording::Time(i64::max_value())),
             video_sample_entry_id: self.video_sample_entry_id,
-            flags: db::RecordingFlags::Growing as i32,
+            flags: db::RecordingFlags::Growing as i32 |
+                   if self.degraded { db::RecordingFlags::Degraded as i32 } else { 0 },
             ..Default::default()
         })?;
-        let f = clock::retry_forever(&self.db.clocks(), &mut || self.dir.create_file(id));
+        let f = if self.max_spool_bytes > 0 {
+            match self.dir.create_file(id) {
+                Ok(f) => Some(f),
+                Err(e) => {
+                    warn!("{}: unable to create sample file; will spool: {}", id, e);
+                    None
+                },
+            }
+        } else {
+            Some(clock::retry_forever(&self.db.clocks(), &mut || self.dir.create_file(id)))
+        };
 
         self.state = WriterState::Open(InnerWriter {
             f,
+            spool: Vec::new(),
             r,
             e: recording::SampleIndexEncoder::new(),
             id,
             hasher: hash::Hasher::new(hash::MessageDigest::sha1())?,
             local_start: recording::Time(i64::max_value()),
             adjuster: ClockAdjuster::new(prev.map(|p| p.local_time_delta.0)),
+            first_pts_90k: None,
+            metadata_full: false,
             unflushed_sample: None,
         });
         match self.state {
@@ -633,27 +871,34 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
     /// `local_time` should be the local clock's time as of when this packet was received.
     pub fn write(&mut self, pkt: &[u8], local_time: recording::Time, pts_90k: i64,
                  is_key: bool) -> Result<(), Error> {
+        let (dir, clocks, max_spool_bytes) = (self.dir, self.db.clocks(), self.max_spool_bytes);
         let w = self.open()?;
 
         // Note w's invariant that `unflushed_sample` is `None` may currently be violated.
         // We must restore it on all success or error paths.
 
         if let Some(unflushed) = w.unflushed_sample.take() {
-            let duration = (pts_90k - unflushed.pts_90k) as i32;
+            let duration = pts_90k - unflushed.pts_90k;
             if duration <= 0 {
                 // Restore invariant.
                 w.unflushed_sample = Some(unflushed);
                 bail!("pts not monotonically increasing; got {} then {}",
                       unflushed.pts_90k, pts_90k);
             }
-            let duration = w.adjuster.adjust(duration);
+
+            // A frame can't outlast a recording. Checking here also ensures the interval fits
+            // exactly in the index (rather than wrapping) even for a camera which sends frames
+            // very rarely.
+            if duration >= recording::MAX_RECORDING_DURATION {
+                w.unflushed_sample = Some(unflushed);
+                bail!("frame interval {} (from pts {} to {}) is too long",
+                      recording::Duration(duration), unflushed.pts_90k, pts_90k);
+            }
+            let duration = w.adjuster.adjust(duration as i32);
             w.add_sample(duration, unflushed.len, unflushed.is_key, unflushed.local_time);
         }
-        let mut remaining = pkt;
-        while !remaining.is_empty() {
-            let written = clock::retry_forever(&self.db.clocks(), &mut || w.f.write(remaining));
-            remaining = &remaining[written..];
-        }
+        w.write_data(dir, &clocks, max_spool_bytes, pkt);
+        w.first_pts_90k.get_or_insert(pts_90k);
         w.unflushed_sample = Some(UnflushedSample {
             local_time,
             pts_90k,
@@ -664,13 +909,36 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
         Ok(())
     }
 
+    /// Adds a packet from the stream's metadata track (such as ONVIF analytics XML) to the current
+    /// recording's sidecar. `pts_90k` should be on the same timeline as the frames'. Packets
+    /// received while no recording is open, or beyond `recording::MAX_METADATA_BYTES`, are
+    /// dropped.
+    pub fn write_metadata(&mut self, pkt: &[u8], pts_90k: i64) {
+        let w = match self.state {
+            WriterState::Open(ref mut w) => w,
+            _ => return,
+        };
+        let first = match w.first_pts_90k {
+            None => return,
+            Some(p) => p,
+        };
+        let rel_90k = cmp::max(cmp::min(pts_90k - first, i32::max_value() as i64),
+                               i32::min_value() as i64) as i32;
+        let mut l = w.r.lock();
+        if !recording::append_metadata(rel_90k, pkt, &mut l.metadata) && !w.metadata_full {
+            warn!("{}: metadata sidecar is full at {} bytes; dropping packets",
+                  w.id, l.metadata.len());
+            w.metadata_full = true;
+        }
+    }
+
     /// Cleanly closes the writer, using a supplied pts of the next sample for the last sample's
     /// duration (if known). If `close` is not called, the `Drop` trait impl will close the trait,
     /// swallowing errors and using a zero duration for the last sample.
     pub fn close(&mut self, next_pts: Option<i64>) {
         self.state = match mem::replace(&mut self.state, WriterState::Unopened) {
             WriterState::Open(w) => {
-                let prev = w.close(self.channel, next_pts);
+                let prev = w.close(self.dir, &self.db.clocks(), self.channel, next_pts);
                 WriterState::Closed(prev)
             },
             s => s,
@@ -679,6 +947,59 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Writer<'a, C, D> {
 }
 
 impl<F: FileWriter> InnerWriter<F> {
+    /// Writes any spooled data to the sample file, creating it first if necessary.
+    fn unspool<D: DirWriter<File = F>>(&mut self, dir: &D) -> Result<(), io::Error> {
+        if self.f.is_none() {
+            self.f = Some(dir.create_file(self.id)?);
+        }
+        let f = self.f.as_mut().unwrap();
+        let had = self.spool.len();
+        while !self.spool.is_empty() {
+            let written = f.write(&self.spool)?;
+            self.spool.drain(..written);
+        }
+        if had > 0 {
+            info!("{}: wrote {} spooled bytes", self.id, had);
+        }
+        Ok(())
+    }
+
+    /// Writes `pkt` after any spooled data. On error, spools the remainder if it fits within
+    /// `max_spool_bytes`; otherwise retries until successful.
+    fn write_data<C: Clocks, D: DirWriter<File = F>>(&mut self, dir: &D, clocks: &C,
+                                                      max_spool_bytes: usize, pkt: &[u8]) {
+        let mut remaining = pkt;
+        let mut err = match self.unspool(dir) {
+            Ok(()) => None,
+            Err(e) => Some(e),
+        };
+        if err.is_none() {
+            let f = self.f.as_mut().unwrap();
+            while !remaining.is_empty() {
+                match f.write(remaining) {
+                    Ok(written) => remaining = &remaining[written..],
+                    Err(e) => {
+                        err = Some(e);
+                        break;
+                    },
+                }
+            }
+        }
+        if remaining.is_empty() {
+            return;
+        }
+        let was_spooling = !self.spool.is_empty();
+        self.spool.extend_from_slice(remaining);
+        if self.spool.len() > max_spool_bytes {
+            if max_spool_bytes > 0 {
+                warn!("{}: spool is full at {} bytes; blocking", self.id, self.spool.len());
+            }
+            clock::retry_forever(clocks, &mut || self.unspool(dir));
+        } else if !was_spooling {
+            warn!("{}: spooling up to {} bytes in memory after error: {}",
+                  self.id, max_spool_bytes, err.expect("error should be set"));
+        }
+    }
     fn add_sample(&mut self, duration_90k: i32, bytes: i32, is_key: bool,
                   pkt_local_time: recording::Time) {
         let mut l = self.r.lock();
@@ -690,7 +1011,10 @@ impl<F: FileWriter> InnerWriter<F> {
         }
     }
 
-    fn close(mut self, channel: &SyncerChannel<F>, next_pts: Option<i64>) -> PreviousWriter {
+    fn close<C: Clocks, D: DirWriter<File = F>>(mut self, dir: &D, clocks: &C,
+                                                 channel: &SyncerChannel<F>,
+                                                 next_pts: Option<i64>) -> PreviousWriter {
+        clock::retry_forever(clocks, &mut || self.unspool(dir));
         let unflushed = self.unflushed_sample.take().expect("should always be an unflushed sample");
         let (last_sample_duration, flags) = match next_pts {
             None => (self.adjuster.adjust(0), db::RecordingFlags::TrailingZero as i32),
@@ -704,7 +1028,7 @@ impl<F: FileWriter> InnerWriter<F> {
         let total_duration;
         {
             let mut l = self.r.lock();
-            l.flags = flags;
+            l.flags = flags | (l.flags & db::RecordingFlags::Degraded as i32);
             local_time_delta = self.local_start - l.start;
             l.local_time_delta = local_time_delta;
             l.sample_file_sha1 = sha1_bytes;
@@ -713,7 +1037,8 @@ impl<F: FileWriter> InnerWriter<F> {
             end = l.start + total_duration;
         }
         drop(self.r);
-        channel.async_save_recording(self.id, total_duration, self.f);
+        channel.async_save_recording(self.id, total_duration,
+                                     self.f.expect("file should exist after unspool"));
         PreviousWriter {
             end,
             local_time_delta,
@@ -732,7 +1057,7 @@ impl<'a, C: Clocks + Clone, D: DirWriter> Drop for Writer<'a, C, D> {
             // Swallow any error. The caller should only drop the Writer without calling close()
             // if there's already been an error. The caller should report that. No point in
             // complaining again.
-            let _ = w.close(self.channel, None);
+            let _ = w.close(self.dir, &self.db.clocks(), self.channel, None);
         }
     }
 }
@@ -817,7 +1142,7 @@ mod tests {
     }
 
     impl super::FileWriter for MockFile {
-        fn sync_all(&self) -> Result<(), io::Error> {
+        fn sync_all(&mut self) -> Result<(), io::Error> {
             match self.0.lock().pop_front().expect("got sync_all with no expectation") {
                 MockFileAction::SyncAll(f) => f(),
                 _ => panic!("got sync_all, expected something else"),
@@ -891,6 +1216,7 @@ mod tests {
             stream_id: testutil::TEST_STREAM_ID,
             new_record: true,
             new_limit: 3,
+            new_weight: 1,
         }]).unwrap();
 
         // Setup: add a 3-byte recording.
@@ -1014,6 +1340,131 @@ mod tests {
         h.join.join().unwrap();
     }
 
+    #[test]
+    fn write_metadata() {
+        testutil::init();
+        let h = new_harness();
+        let video_sample_entry_id = h.db.lock().insert_video_sample_entry(
+            1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap();
+        {
+            let mut w = Writer::new(&h.dir, &h.db, &h.channel, testutil::TEST_STREAM_ID,
+                                    video_sample_entry_id);
+            w.write_metadata(b"early", 0);  // no recording is open yet; dropped.
+            let f = MockFile::new();
+            h.dir.expect(MockDirAction::Create(CompositeId::new(1, 1),
+                         Box::new({ let f = f.clone(); move |_id| Ok(f.clone()) })));
+            f.expect(MockFileAction::Write(Box::new(|buf| Ok(buf.len()))));
+            f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+            w.write(b"1234", recording::Time(1), 1000, true).unwrap();
+            w.write_metadata(b"<a/>", 4000);
+            h.db.lock().with_recording_metadata(CompositeId::new(1, 1), &mut |m| {
+                assert_eq!(recording::parse_metadata(m)?,
+                           &[recording::MetadataSample { rel_90k: 3000, data: b"<a/>" }]);
+                Ok(())
+            }).unwrap();
+            h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+            drop(w);
+            h.channel.flush();
+            f.ensure_done();
+            h.dir.ensure_done();
+        }
+        drop(h.channel);
+        h.db.lock().clear_on_flush();
+        h.join.join().unwrap();
+    }
+
+    #[test]
+    fn too_long_interval() {
+        testutil::init();
+        let h = new_harness();
+        let video_sample_entry_id = h.db.lock().insert_video_sample_entry(
+            1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap();
+        {
+            let mut w = Writer::new(&h.dir, &h.db, &h.channel, testutil::TEST_STREAM_ID,
+                                    video_sample_entry_id);
+            let f = MockFile::new();
+            h.dir.expect(MockDirAction::Create(CompositeId::new(1, 1),
+                         Box::new({ let f = f.clone(); move |_id| Ok(f.clone()) })));
+            f.expect(MockFileAction::Write(Box::new(|buf| Ok(buf.len()))));
+            f.expect(MockFileAction::Write(Box::new(|buf| Ok(buf.len()))));
+            f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+            w.write(b"1234", recording::Time(1), 0, true).unwrap();
+            let e = w.write(b"5678", recording::Time(2), recording::MAX_RECORDING_DURATION,
+                            false).unwrap_err();
+            assert!(e.to_string().contains("too long"), "{}", e);
+
+            // A long but valid interval, as from a camera sending 1 frame per second.
+            w.write(b"5678", recording::Time(3), 90000, false).unwrap();
+            let mut rows = 0;
+            h.db.lock().list_recordings_by_id(testutil::TEST_STREAM_ID, 1 .. 2, &mut |r| {
+                rows += 1;
+                assert_eq!(90000, r.min_frame_interval_90k);
+                assert_eq!(90000, r.max_frame_interval_90k);
+                Ok(())
+            }).unwrap();
+            assert_eq!(1, rows);
+            h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+            drop(w);
+            h.channel.flush();
+            f.ensure_done();
+            h.dir.ensure_done();
+        }
+        drop(h.channel);
+        h.db.lock().clear_on_flush();
+        h.join.join().unwrap();
+    }
+
+    #[test]
+    fn write_path_spools() {
+        testutil::init();
+        let h = new_harness();
+        let video_sample_entry_id = h.db.lock().insert_video_sample_entry(
+            1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap();
+        {
+            let mut w = Writer::new(&h.dir, &h.db, &h.channel, testutil::TEST_STREAM_ID,
+                                    video_sample_entry_id);
+            w.set_max_spool_bytes(4);
+
+            // The directory is unavailable; the first frame should be spooled without blocking.
+            h.dir.expect(MockDirAction::Create(CompositeId::new(1, 1), Box::new(|_id| Err(eio()))));
+            h.dir.expect(MockDirAction::Create(CompositeId::new(1, 1), Box::new(|_id| Err(eio()))));
+            w.write(b"1234", recording::Time(1), 0, true).unwrap();
+            assert_eq!(w.spooled_bytes(), 4);
+            h.dir.ensure_done();
+
+            // Once it returns, the spool should be written before the next frame.
+            let f = MockFile::new();
+            h.dir.expect(MockDirAction::Create(CompositeId::new(1, 1),
+                         Box::new({ let f = f.clone(); move |_id| Ok(f.clone()) })));
+            f.expect(MockFileAction::Write(Box::new(|buf| {
+                assert_eq!(buf, b"1234");
+                Ok(4)
+            })));
+            f.expect(MockFileAction::Write(Box::new(|buf| {
+                assert_eq!(buf, b"56");
+                Ok(2)
+            })));
+            w.write(b"56", recording::Time(2), 1, true).unwrap();
+            assert_eq!(w.spooled_bytes(), 0);
+            f.expect(MockFileAction::SyncAll(Box::new(|| Ok(()))));
+            h.dir.expect(MockDirAction::Sync(Box::new(|| Ok(()))));
+            drop(w);
+            h.channel.flush();
+            f.ensure_done();
+            h.dir.ensure_done();
+        }
+
+        {
+            let l = h.db.lock();
+            let s = l.streams_by_id().get(&testutil::TEST_STREAM_ID).unwrap();
+            assert_eq!(s.bytes_to_add, 0);
+            assert_eq!(s.sample_file_bytes, 6);
+        }
+        drop(h.channel);
+        h.db.lock().clear_on_flush();
+        h.join.join().unwrap();
+    }
+
     #[test]
     fn gc_path_retries() {
         testutil::init();
@@ -1022,6 +1473,7 @@ mod tests {
             stream_id: testutil::TEST_STREAM_ID,
             new_record: true,
             new_limit: 3,
+            new_weight: 1,
         }]).unwrap();
 
         // Setup: add a 3-byte recording.
@@ -0,0 +1,122 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! ZFS dataset properties relevant to sample file directories.
+//!
+//! When a sample file directory is on ZFS (as is common on FreeNAS/TrueNAS), `SampleFileDir`
+//! adapts to its dataset's properties. With `sync=always`, every write is already committed to
+//! stable storage (typically a SLOG device) before returning, so the syncer's `fsync` calls are
+//! redundant and skipped. Sample files are written in whole records of the dataset's
+//! `recordsize` rather than a frame at a time.
+
+use failure::Error;
+use std::cmp;
+use std::process::Command;
+use std::str;
+
+/// The largest write to buffer, regardless of `recordsize` (which may be up to 16 MiB).
+const MAX_WRITE_SIZE: usize = 1 << 20;
+
+/// Properties of the ZFS dataset holding a sample file directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Properties {
+    pub dataset: String,
+
+    /// True iff the dataset has `sync=always`.
+    pub sync_always: bool,
+
+    /// The dataset's `recordsize`, in bytes.
+    pub recordsize: usize,
+}
+
+impl Properties {
+    /// Gets the properties of the dataset holding `path` via the `zfs` command.
+    pub fn get(path: &str) -> Result<Self, Error> {
+        let out = Command::new("zfs")
+            .args(&["get", "-H", "-p", "-o", "name,property,value", "sync,recordsize", path])
+            .output()?;
+        if !out.status.success() {
+            bail!("zfs get failed: {}", String::from_utf8_lossy(&out.stderr).trim());
+        }
+        Properties::parse(str::from_utf8(&out.stdout)?)
+    }
+
+    /// Parses the output of `zfs get -H -p -o name,property,value sync,recordsize`.
+    fn parse(out: &str) -> Result<Self, Error> {
+        let (mut dataset, mut sync, mut recordsize) = (None, None, None);
+        for line in out.lines() {
+            let mut parts = line.split('\t');
+            let (name, property, value) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(n), Some(p), Some(v)) => (n, p, v),
+                _ => bail!("malformed zfs get line {:?}", line),
+            };
+            dataset = Some(name);
+            match property {
+                "sync" => sync = Some(value),
+                "recordsize" => recordsize = Some(value.parse::<usize>()
+                    .map_err(|_| format_err!("bad recordsize {:?}", value))?),
+                _ => bail!("unexpected zfs property {:?}", property),
+            }
+        }
+        match (dataset, sync, recordsize) {
+            (Some(d), Some(s), Some(r)) if r > 0 => Ok(Properties {
+                dataset: d.to_owned(),
+                sync_always: s == "always",
+                recordsize: r,
+            }),
+            _ => bail!("incomplete zfs get output {:?}", out),
+        }
+    }
+
+    /// The size of the writes `SampleFileWriter` should make.
+    pub fn write_size(&self) -> usize { cmp::min(self.recordsize, MAX_WRITE_SIZE) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Properties;
+
+    #[test]
+    fn parse() {
+        let p = Properties::parse("tank/nvr\tsync\talways\ntank/nvr\trecordsize\t1048576\n")
+            .unwrap();
+        assert_eq!(p, Properties {
+            dataset: "tank/nvr".to_owned(),
+            sync_always: true,
+            recordsize: 1 << 20,
+        });
+        let p = Properties::parse("tank\trecordsize\t16777216\ntank\tsync\tstandard\n").unwrap();
+        assert!(!p.sync_always);
+        assert_eq!(p.write_size(), 1 << 20);
+        Properties::parse("").unwrap_err();
+        Properties::parse("tank\tsync\tstandard\n").unwrap_err();
+        Properties::parse("tank\trecordsize\tbig\ntank\tsync\tstandard\n").unwrap_err();
+    }
+}
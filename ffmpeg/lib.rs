@@ -42,6 +42,7 @@ static START: sync::Once = sync::ONCE_INIT;
 //#[link(name = "avcodec")]
 extern "C" {
     fn avcodec_version() -> libc::c_int;
+    fn avcodec_get_name(id: libc::c_int) -> *const libc::c_char;
     fn av_init_packet(p: *mut AVPacket);
     fn av_packet_unref(p: *mut AVPacket);
 
@@ -93,6 +94,8 @@ extern "C" {
     static moonfire_ffmpeg_av_nopts_value: libc::int64_t;
 
     static moonfire_ffmpeg_av_codec_id_h264: libc::c_int;
+    static moonfire_ffmpeg_avmedia_type_audio: libc::c_int;
+    static moonfire_ffmpeg_avmedia_type_data: libc::c_int;
     static moonfire_ffmpeg_avmedia_type_video: libc::c_int;
 
     static moonfire_ffmpeg_averror_eof: libc::c_int;
@@ -294,6 +297,11 @@ pub struct CodecId(libc::c_int);
 
 impl CodecId {
     pub fn is_h264(self) -> bool { self.0 == unsafe { moonfire_ffmpeg_av_codec_id_h264 } }
+
+    /// Returns a short name for the codec, such as `h264` or `pcm_mulaw`.
+    pub fn name(self) -> &'static str {
+        unsafe { CStr::from_ptr(avcodec_get_name(self.0)) }.to_str().unwrap_or("unknown")
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -301,6 +309,8 @@ pub struct MediaType(libc::c_int);
 
 impl MediaType {
     pub fn is_video(self) -> bool { self.0 == unsafe { moonfire_ffmpeg_avmedia_type_video } }
+    pub fn is_audio(self) -> bool { self.0 == unsafe { moonfire_ffmpeg_avmedia_type_audio } }
+    pub fn is_data(self) -> bool { self.0 == unsafe { moonfire_ffmpeg_avmedia_type_data } }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -111,10 +111,12 @@ Environment=TZ=:/etc/localtime
 Environment=MOONFIRE_FORMAT=google-systemd
 Environment=MOONFIRE_LOG=info
 Environment=RUST_BACKTRACE=1
-Type=simple
+Type=notify
+WatchdogSec=60
 User=${NVR_USER}
 Nice=-20
 Restart=on-abnormal
+RestartPreventExitStatus=2
 CPUAccounting=true
 MemoryAccounting=true
 BlockIOAccounting=true
@@ -0,0 +1,362 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Analytics stages which turn decoded media into events (see `db::EventToInsert`).
+//!
+//! The sound-level detector operates on decoded signed 16-bit PCM samples; it will be fed by the
+//! streamer once the stream layer surfaces audio. The SEI motion detector needs no decoding; it
+//! relays motion detection which the camera itself embeds in the video stream. Events which
+//! come from an external classifier (such as "glass break") can be stored directly via
+//! `db::LockedDatabase::add_event`.
+
+use db::{self, recording};
+use h264;
+use uuid::Uuid;
+
+/// The event type produced by `SoundLevelDetector`.
+#[allow(dead_code)]  // TODO: remove once the streamer supplies audio.
+pub const SOUND_LEVEL_EVENT_TYPE: &'static str = "sound_level";
+
+/// The event type produced by `SeiMotionDetector`.
+pub const MOTION_EVENT_TYPE: &'static str = "motion";
+
+/// Returns the root mean square level of the given samples in dBFS, where 0 dBFS is a
+/// full-scale square wave. Silence (including an empty slice) is `-inf`.
+#[allow(dead_code)]
+pub fn rms_dbfs(samples: &[i16]) -> f64 {
+    if samples.is_empty() {
+        return ::std::f64::NEG_INFINITY;
+    }
+    let sum_sq: f64 = samples.iter().map(|&s| { let s = s as f64; s * s }).sum();
+    let rms = (sum_sq / samples.len() as f64).sqrt();
+    20. * (rms / 32768.).log10()
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct SoundLevelConfig {
+    /// The level above which a chunk of audio is considered "loud".
+    pub threshold_dbfs: f64,
+
+    /// Loud periods shorter than this are discarded.
+    pub min_duration: recording::Duration,
+
+    /// Quiet gaps shorter than this don't end an event; this keeps a series of knocks as a
+    /// single event.
+    pub hold: recording::Duration,
+
+    /// Once an event has started, chunks this much below `threshold_dbfs` still count as loud,
+    /// so that a level hovering around the threshold doesn't split into many short events.
+    pub hysteresis_db: f64,
+}
+
+impl Default for SoundLevelConfig {
+    fn default() -> Self {
+        SoundLevelConfig {
+            threshold_dbfs: -20.,
+            min_duration: recording::Duration(recording::TIME_UNITS_PER_SEC / 4),
+            hold: recording::Duration(2 * recording::TIME_UNITS_PER_SEC),
+            hysteresis_db: 6.,
+        }
+    }
+}
+
+#[allow(dead_code)]
+struct Pending {
+    start: recording::Time,
+
+    /// The end of the last loud chunk.
+    end: recording::Time,
+
+    peak_dbfs: f64,
+}
+
+/// Produces a `sound_level` event for each period in which the audio level exceeds a threshold.
+#[allow(dead_code)]
+pub struct SoundLevelDetector {
+    camera_id: i32,
+    config: SoundLevelConfig,
+    pending: Option<Pending>,
+}
+
+#[allow(dead_code)]
+impl SoundLevelDetector {
+    pub fn new(camera_id: i32, config: SoundLevelConfig) -> Self {
+        SoundLevelDetector {
+            camera_id,
+            config,
+            pending: None,
+        }
+    }
+
+    /// Processes a chunk of audio covering `start .. start + duration`. Chunks are expected to be
+    /// supplied in order; each should be short (say, 100 ms) relative to `config.hold`.
+    ///
+    /// Returns an event if a previous loud period has now ended.
+    pub fn process(&mut self, start: recording::Time, duration: recording::Duration,
+                   samples: &[i16]) -> Option<db::EventToInsert> {
+        let level = rms_dbfs(samples);
+        let end = start + duration;
+        let mut done = None;
+        if let Some(p) = self.pending.take() {
+            if start - p.end > self.config.hold {
+                done = self.to_event(p);
+            } else {
+                self.pending = Some(p);
+            }
+        }
+        let threshold = match self.pending {
+            Some(_) => self.config.threshold_dbfs - self.config.hysteresis_db,
+            None => self.config.threshold_dbfs,
+        };
+        if level >= threshold {
+            match self.pending {
+                Some(ref mut p) => {
+                    p.end = end;
+                    if level > p.peak_dbfs {
+                        p.peak_dbfs = level;
+                    }
+                },
+                None => self.pending = Some(Pending { start, end, peak_dbfs: level }),
+            }
+        }
+        done
+    }
+
+    /// Flushes any loud period in progress, as when the stream ends.
+    pub fn finish(&mut self) -> Option<db::EventToInsert> {
+        self.pending.take().and_then(|p| self.to_event(p))
+    }
+
+    fn to_event(&self, p: Pending) -> Option<db::EventToInsert> {
+        if p.end - p.start < self.config.min_duration {
+            return None;
+        }
+        Some(db::EventToInsert {
+            camera_id: self.camera_id,
+            type_: SOUND_LEVEL_EVENT_TYPE.to_owned(),
+            time: p.start .. p.end,
+            description: Some("loud noise".to_owned()),
+            score: Some(p.peak_dbfs),
+        })
+    }
+}
+
+/// Gaps between motion-flagged frames shorter than this don't end a `SeiMotionDetector` event.
+const SEI_MOTION_HOLD: recording::Duration = recording::Duration(2 * recording::TIME_UNITS_PER_SEC);
+
+/// Produces a `motion` event for each period in which the camera flags motion via
+/// `user_data_unregistered` SEI messages with a configured UUID. The first payload byte after the
+/// UUID is the flag; non-zero means motion. Frames without such a message don't extend the
+/// period.
+pub struct SeiMotionDetector {
+    camera_id: i32,
+    uuid: Uuid,
+
+    /// The `start .. end` of the current period of motion, where `end` is the time of the last
+    /// motion-flagged frame.
+    pending: Option<(recording::Time, recording::Time)>,
+}
+
+impl SeiMotionDetector {
+    pub fn new(camera_id: i32, uuid: Uuid) -> Self {
+        SeiMotionDetector {
+            camera_id,
+            uuid,
+            pending: None,
+        }
+    }
+
+    /// Processes the SEI messages of the frame at `time`. Frames are expected to be supplied in
+    /// order.
+    ///
+    /// Returns an event if a previous period of motion has now ended.
+    pub fn process(&mut self, time: recording::Time, messages: &[h264::SeiMessage])
+                   -> Option<db::EventToInsert> {
+        let mut done = None;
+        if let Some((start, end)) = self.pending.take() {
+            if time - end > SEI_MOTION_HOLD {
+                done = Some(self.to_event(start, end));
+            } else {
+                self.pending = Some((start, end));
+            }
+        }
+        let motion = messages.iter().any(|m| {
+            m.payload_type == h264::SEI_USER_DATA_UNREGISTERED && m.payload.len() > 16 &&
+            &m.payload[..16] == self.uuid.as_bytes() && m.payload[16] != 0
+        });
+        if motion {
+            self.pending = Some(match self.pending {
+                Some((start, _)) => (start, time),
+                None => (time, time),
+            });
+        }
+        done
+    }
+
+    /// Flushes any period of motion in progress, as when the stream ends.
+    pub fn finish(&mut self) -> Option<db::EventToInsert> {
+        self.pending.take().map(|(start, end)| self.to_event(start, end))
+    }
+
+    fn to_event(&self, start: recording::Time, end: recording::Time) -> db::EventToInsert {
+        db::EventToInsert {
+            camera_id: self.camera_id,
+            type_: MOTION_EVENT_TYPE.to_owned(),
+            time: start .. end,
+            description: Some("motion detected by camera".to_owned()),
+            score: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use super::*;
+
+    const CHUNK: recording::Duration = recording::Duration(TIME_UNITS_PER_SEC / 10);
+
+    #[test]
+    fn test_rms_dbfs() {
+        assert_eq!(rms_dbfs(&[]), ::std::f64::NEG_INFINITY);
+        assert_eq!(rms_dbfs(&[0, 0, 0]), ::std::f64::NEG_INFINITY);
+        assert!(rms_dbfs(&[-32768, -32768]).abs() < 1e-9);
+        let half = rms_dbfs(&[16384, -16384]);
+        assert!((half - -6.0206).abs() < 1e-3, "half = {}", half);
+    }
+
+    fn feed(d: &mut SoundLevelDetector, samples: &[i16], n: usize, t: &mut recording::Time,
+            events: &mut Vec<db::EventToInsert>) {
+        for _ in 0..n {
+            events.extend(d.process(*t, CHUNK, samples));
+            *t += CHUNK;
+        }
+    }
+
+    #[test]
+    fn test_sound_level_detector() {
+        let mut d = SoundLevelDetector::new(1, SoundLevelConfig::default());
+        let quiet = [0i16; 16];
+        let loud = [16384i16; 16];
+        let mut t = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let mut events = Vec::new();
+
+        // 1 second of quiet, then a short blip (which should be discarded), then quiet.
+        feed(&mut d, &quiet, 10, &mut t, &mut events);
+        feed(&mut d, &loud, 1, &mut t, &mut events);
+        feed(&mut d, &quiet, 30, &mut t, &mut events);
+        assert!(events.is_empty());
+
+        // Two loud periods separated by less than the hold should be a single event.
+        let start = t;
+        feed(&mut d, &loud, 5, &mut t, &mut events);
+        feed(&mut d, &quiet, 10, &mut t, &mut events);
+        feed(&mut d, &loud, 5, &mut t, &mut events);
+        let end = t;
+        assert!(events.is_empty());
+        feed(&mut d, &quiet, 30, &mut t, &mut events);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].type_, SOUND_LEVEL_EVENT_TYPE);
+        assert_eq!(events[0].time, start .. end);
+        assert!(d.finish().is_none());
+    }
+
+    #[test]
+    fn test_sound_level_hysteresis() {
+        let mut d = SoundLevelDetector::new(1, SoundLevelConfig::default());
+        let quiet = [0i16; 16];
+        let loud = [16384i16; 16];
+        let medium = [2500i16; 16];  // about -22 dBFS: below threshold, within hysteresis.
+        let mut t = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let mut events = Vec::new();
+
+        // A medium level alone doesn't start an event.
+        feed(&mut d, &medium, 30, &mut t, &mut events);
+        feed(&mut d, &quiet, 30, &mut t, &mut events);
+        assert!(events.is_empty());
+
+        // But once started, it sustains one, even beyond the hold.
+        let start = t;
+        feed(&mut d, &loud, 5, &mut t, &mut events);
+        feed(&mut d, &medium, 50, &mut t, &mut events);
+        let end = t;
+        feed(&mut d, &quiet, 30, &mut t, &mut events);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].time, start .. end);
+    }
+
+    #[test]
+    fn test_sei_motion_detector() {
+        let uuid = Uuid::parse_str("6a5f0b2e-6b3c-4d1a-9f1e-2c3b4a5d6e7f").unwrap();
+        let flag = |on: bool| {
+            let mut payload = uuid.as_bytes().to_vec();
+            payload.push(on as u8);
+            vec![h264::SeiMessage {
+                payload_type: h264::SEI_USER_DATA_UNREGISTERED,
+                payload,
+            }]
+        };
+        let mut d = SeiMotionDetector::new(1, uuid);
+        let mut t = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let mut events = Vec::new();
+
+        // Frames with no messages or with the flag off produce nothing.
+        for _ in 0..10 {
+            events.extend(d.process(t, &[]));
+            events.extend(d.process(t, &flag(false)));
+            t += CHUNK;
+        }
+        assert!(events.is_empty());
+
+        // Motion, a short gap, and more motion make a single event, ending after the hold.
+        let start = t;
+        for i in 0..30 {
+            events.extend(d.process(t, &flag(i < 10 || i >= 20)));
+            t += CHUNK;
+        }
+        let end = t - CHUNK;
+        for _ in 0..30 {
+            events.extend(d.process(t, &flag(false)));
+            t += CHUNK;
+        }
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].type_, MOTION_EVENT_TYPE);
+        assert_eq!(events[0].time, start .. end);
+
+        // A different UUID is ignored.
+        let other = vec![h264::SeiMessage {
+            payload_type: h264::SEI_USER_DATA_UNREGISTERED,
+            payload: vec![1; 17],
+        }];
+        events.extend(d.process(t, &other));
+        assert!(d.finish().is_none());
+    }
+}
@@ -0,0 +1,351 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Drawing of detected objects' bounding boxes and labels on event snapshots, so that (for
+//! example) a notification's image shows what triggered it. To avoid image-processing
+//! dependencies, the JPEG is decoded to and re-encoded from a PPM by the external `ffmpeg`
+//! binary also used to take snapshots, and drawing is done by a tiny rasterizer with a built-in
+//! 5x7 pixel font.
+
+use db;
+use failure::Error;
+use std::cmp;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// The colors of successive boxes.
+const COLORS: [[u8; 3]; 4] = [[255, 221, 0], [0, 200, 255], [255, 80, 80], [80, 255, 80]];
+
+/// The largest width or height accepted, to bound memory use.
+const MAX_DIMENSION: usize = 16384;
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+
+/// Returns the glyph of the given character: one byte per row, the low 5 bits of each giving the
+/// pixels from left to right. Lowercase letters are drawn as uppercase and unknown characters as
+/// `?`.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        'A' => [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'B' => [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+        'C' => [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+        'D' => [0x1e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1e],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'F' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+        'G' => [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f],
+        'H' => [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'I' => [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f],
+        'M' => [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'P' => [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10],
+        'Q' => [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d],
+        'R' => [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11],
+        'S' => [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e],
+        'T' => [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a],
+        'X' => [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04],
+        'Z' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f],
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        ':' => [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        _ => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],  // '?'
+    }
+}
+
+/// An 8-bit RGB image.
+#[derive(Debug)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+
+    /// The pixels, in row-major order, 3 bytes each.
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    /// Parses a binary (`P6`) PPM with 8-bit samples, as written by ffmpeg's `ppm` encoder.
+    pub fn parse_ppm(data: &[u8]) -> Result<Image, Error> {
+        // The header is four whitespace-separated fields (possibly with `#` comments between
+        // them), then a single whitespace character before the samples.
+        let mut fields = Vec::with_capacity(4);
+        let mut pos = 0;
+        while fields.len() < 4 {
+            while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+                if data[pos] == b'#' {
+                    while pos < data.len() && data[pos] != b'\n' {
+                        pos += 1;
+                    }
+                } else {
+                    pos += 1;
+                }
+            }
+            let start = pos;
+            while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if start == pos {
+                bail!("truncated PPM header");
+            }
+            fields.push(&data[start .. pos]);
+        }
+        pos += 1;
+        if fields[0] != b"P6" {
+            bail!("not a binary PPM");
+        }
+        let num = |f: &[u8]| -> Result<usize, Error> {
+            ::std::str::from_utf8(f).ok().and_then(|s| s.parse().ok())
+                .ok_or_else(|| format_err!("bad PPM header field {:?}", String::from_utf8_lossy(f)))
+        };
+        let (width, height, maxval) = (num(fields[1])?, num(fields[2])?, num(fields[3])?);
+        if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+            bail!("unsupported PPM size {}x{}", width, height);
+        }
+        if maxval != 255 {
+            bail!("unsupported PPM maxval {}", maxval);
+        }
+        let len = width * height * 3;
+        if data.len() < pos + len {
+            bail!("truncated PPM: expected {} bytes of samples, got {}", len,
+                  data.len().saturating_sub(pos));
+        }
+        Ok(Image {
+            width,
+            height,
+            rgb: data[pos .. pos + len].to_vec(),
+        })
+    }
+
+    /// Returns the image as a binary PPM.
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend_from_slice(&self.rgb);
+        out
+    }
+
+    /// Fills the rectangle `[x0, x1) x [y0, y1)`, clipped to the image.
+    pub fn fill_rect(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, color: [u8; 3]) {
+        let (x1, y1) = (cmp::min(x1, self.width), cmp::min(y1, self.height));
+        for y in y0 .. y1 {
+            for x in x0 .. x1 {
+                let i = 3 * (y * self.width + x);
+                self.rgb[i .. i + 3].copy_from_slice(&color);
+            }
+        }
+    }
+
+    /// Outlines the rectangle `[x0, x1) x [y0, y1)` with lines of the given thickness, inside
+    /// its bounds.
+    pub fn draw_box(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, thickness: usize,
+                    color: [u8; 3]) {
+        self.fill_rect(x0, y0, x1, cmp::min(y0 + thickness, y1), color);
+        self.fill_rect(x0, y1.saturating_sub(thickness), x1, y1, color);
+        self.fill_rect(x0, y0, cmp::min(x0 + thickness, x1), y1, color);
+        self.fill_rect(x1.saturating_sub(thickness), y0, x1, y1, color);
+    }
+
+    /// Draws text with its top left corner at the given point, each font pixel as a
+    /// `scale`x`scale` square.
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, scale: usize, color: [u8; 3]) {
+        for (i, c) in text.chars().enumerate() {
+            let left = x + i * (GLYPH_WIDTH + 1) * scale;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0 .. GLYPH_WIDTH {
+                    if bits & (0x10 >> col) != 0 {
+                        let (px, py) = (left + col * scale, y + row * scale);
+                        self.fill_rect(px, py, px + scale, py + scale, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the width of the given text as drawn by `Image::draw_text`.
+fn text_width(text: &str, scale: usize) -> usize {
+    (text.chars().count() * (GLYPH_WIDTH + 1)).saturating_sub(1) * scale
+}
+
+/// Draws each detection's bounding box, with a label of its name and score above it (or just
+/// inside it, if it's at the top of the frame).
+pub fn annotate(img: &mut Image, detections: &[db::Detection]) {
+    let scale = cmp::max(1, img.height / 360);
+    let thickness = cmp::max(2, img.height / 240);
+    for (i, d) in detections.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let (w, h) = (img.width as f64, img.height as f64);
+        let (x0, y0) = ((d.x * w) as usize, (d.y * h) as usize);
+        let (x1, y1) = (((d.x + d.width) * w).round() as usize,
+                        ((d.y + d.height) * h).round() as usize);
+        img.draw_box(x0, y0, x1, y1, thickness, color);
+        let label = match d.score {
+            Some(s) => format!("{} {}%", d.label, (s * 100.).round()),
+            None => d.label.clone(),
+        };
+        let label_height = (GLYPH_HEIGHT + 2) * scale;
+        let label_width = text_width(&label, scale) + 2 * scale;
+        let y = if y0 >= label_height { y0 - label_height } else { y0 };
+        img.fill_rect(x0, y, x0 + label_width, y + label_height, color);
+        img.draw_text(x0 + scale, y + scale, &label, scale, [0, 0, 0]);
+    }
+}
+
+/// Runs ffmpeg with the given arguments, supplying `input` on stdin and returning stdout.
+fn run(ffmpeg: &Path, args: &[&str], input: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let mut child = Command::new(ffmpeg)
+        .args(&["-nostdin", "-loglevel", "error", "-f", "image2pipe"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Write from another thread so that ffmpeg can't block on a full stdout pipe while this
+    // thread blocks on a full stdin pipe. Dropping stdin at the end signals EOF.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let out = child.wait_with_output()?;
+    let _ = writer.join();
+    if !out.status.success() || out.stdout.is_empty() {
+        bail!("ffmpeg failed with {}: {}", out.status, String::from_utf8_lossy(&out.stderr).trim());
+    }
+    Ok(out.stdout)
+}
+
+/// Returns a copy of the given JPEG with the given detections drawn on it.
+pub fn annotate_jpeg(ffmpeg: &Path, jpeg: Vec<u8>, detections: &[db::Detection])
+                     -> Result<Vec<u8>, Error> {
+    let ppm = run(ffmpeg, &["-c:v", "mjpeg", "-i", "pipe:0", "-f", "image2pipe", "-c:v", "ppm",
+                            "-pix_fmt", "rgb24", "pipe:1"], jpeg)?;
+    let mut img = Image::parse_ppm(&ppm)?;
+    annotate(&mut img, detections);
+    run(ffmpeg, &["-c:v", "ppm", "-i", "pipe:0", "-f", "image2pipe", "-c:v", "mjpeg",
+                  "-pix_fmt", "yuvj420p", "-q:v", "3", "pipe:1"], img.to_ppm())
+}
+
+#[cfg(test)]
+mod tests {
+    use db;
+    use super::*;
+
+    fn blank(width: usize, height: usize) -> Image {
+        Image { width, height, rgb: vec![0; width * height * 3] }
+    }
+
+    fn pixel(img: &Image, x: usize, y: usize) -> [u8; 3] {
+        let i = 3 * (y * img.width + x);
+        [img.rgb[i], img.rgb[i + 1], img.rgb[i + 2]]
+    }
+
+    #[test]
+    fn test_ppm() {
+        let mut img = blank(3, 2);
+        img.rgb[3 * 4] = 255;
+        let ppm = img.to_ppm();
+        assert_eq!(&ppm[.. 11], b"P6\n3 2\n255\n");
+        let parsed = Image::parse_ppm(&ppm).unwrap();
+        assert_eq!((parsed.width, parsed.height), (3, 2));
+        assert_eq!(parsed.rgb, img.rgb);
+
+        // Comments and other whitespace are allowed in the header.
+        let mut commented = b"P6 # from ffmpeg\n3\t2 255\n".to_vec();
+        commented.extend_from_slice(&img.rgb);
+        assert_eq!(Image::parse_ppm(&commented).unwrap().rgb, img.rgb);
+
+        assert!(Image::parse_ppm(&ppm[.. ppm.len() - 1]).is_err());
+        assert!(Image::parse_ppm(b"P3\n3 2\n255\n").is_err());
+        assert!(Image::parse_ppm(b"P6\n3 2\n65535\n").is_err());
+        assert!(Image::parse_ppm(b"P6\n3").is_err());
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut img = blank(10, 10);
+        img.draw_box(2, 2, 8, 8, 1, [255, 0, 0]);
+        assert_eq!(pixel(&img, 2, 2), [255, 0, 0]);
+        assert_eq!(pixel(&img, 7, 5), [255, 0, 0]);
+        assert_eq!(pixel(&img, 5, 5), [0, 0, 0]);
+        assert_eq!(pixel(&img, 8, 8), [0, 0, 0]);
+
+        // Drawing beyond the edges is clipped rather than panicking.
+        img.draw_text(8, 8, "HI", 2, [255, 255, 255]);
+        assert_eq!(pixel(&img, 8, 8), [255, 255, 255]);  // the top left of the H.
+    }
+
+    #[test]
+    fn test_annotate() {
+        let mut img = blank(64, 48);
+        annotate(&mut img, &[
+            db::Detection {
+                label: "person".to_owned(),
+                score: Some(0.87),
+                x: 0.5,
+                y: 0.5,
+                width: 0.5,
+                height: 0.5,
+            },
+            db::Detection {  // at the top of the frame, so the label goes inside.
+                label: "car".to_owned(),
+                score: None,
+                x: 0.,
+                y: 0.,
+                width: 0.25,
+                height: 0.25,
+            },
+        ]);
+        assert_eq!(pixel(&img, 32, 24), COLORS[0]);
+        assert_eq!(pixel(&img, 63, 47), COLORS[0]);
+        assert_eq!(pixel(&img, 32, 24 - 9), COLORS[0]);  // the label's background.
+        assert_eq!(pixel(&img, 0, 0), COLORS[1]);
+        assert_eq!(pixel(&img, 40, 30), [0, 0, 0]);
+    }
+}
@@ -0,0 +1,210 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Accounting of bytes served by the web server, by user and camera, persisted as hourly totals
+//! and served as `/api/stats/bandwidth`.
+//!
+//! Bytes are counted as each chunk of a response body is sent, and the request is recorded when
+//! the body finishes or is dropped, so interrupted downloads count only what was sent. Totals
+//! are kept in memory and added to the database periodically, rather than once per request.
+
+use body::{Body, BodyStream, BoxedError, Chunk};
+use bytes::Buf;
+use clock::Clocks;
+use db;
+use failure::Error;
+use fnv::FnvHashMap;
+use futures::{Async, Poll, Stream};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How often pending totals are written to the database.
+const FLUSH_INTERVAL_SEC: u64 = 60;
+
+/// How long hourly totals are kept.
+const RETAIN_SEC: i64 = 90 * 24 * 60 * 60;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Key {
+    start_sec: i64,
+    user_name: String,
+    camera_uuid: Option<Uuid>,
+}
+
+pub struct Accountant {
+    db: Arc<db::Database>,
+
+    /// Totals not yet written to the database, as (requests, bytes).
+    pending: Mutex<FnvHashMap<Key, (i64, i64)>>,
+}
+
+impl Accountant {
+    pub fn new(db: Arc<db::Database>) -> Arc<Self> {
+        Arc::new(Accountant {
+            db,
+            pending: Mutex::new(FnvHashMap::default()),
+        })
+    }
+
+    /// Wraps a response body so that its bytes are counted toward the given user and camera.
+    pub fn wrap(acct: &Arc<Self>, body: Body, user_name: String, camera_uuid: Option<Uuid>)
+                -> Body {
+        let counted: BodyStream = Box::new(Counted {
+            inner: body.into_stream(),
+            acct: acct.clone(),
+            user_name,
+            camera_uuid,
+            bytes: 0,
+        });
+        counted.into()
+    }
+
+    fn record(&self, user_name: String, camera_uuid: Option<Uuid>, bytes: i64) {
+        let now = self.db.clocks().realtime().sec;
+        let key = Key {
+            start_sec: now - now % 3600,
+            user_name,
+            camera_uuid,
+        };
+        let mut l = self.pending.lock();
+        let e = l.entry(key).or_insert((0, 0));
+        e.0 += 1;
+        e.1 += bytes;
+    }
+
+    /// Writes pending totals to the database. Call before reading them so recent requests are
+    /// included.
+    pub fn flush(&self) -> Result<(), Error> {
+        let pending = ::std::mem::replace(&mut *self.pending.lock(), FnvHashMap::default());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let usage: Vec<_> = pending.into_iter().map(|(k, (requests, bytes))| {
+            db::BandwidthUsage {
+                start_sec: k.start_sec,
+                user_name: k.user_name,
+                camera_uuid: k.camera_uuid,
+                requests,
+                bytes,
+            }
+        }).collect();
+        self.db.lock().add_bandwidth_usage(&usage)
+    }
+
+    fn flush_and_expire(&self) -> Result<(), Error> {
+        self.flush()?;
+        let now = self.db.clocks().realtime().sec;
+        let deleted = self.db.lock().delete_bandwidth_usage_before(now - RETAIN_SEC)?;
+        if deleted > 0 {
+            debug!("deleted {} expired hourly bandwidth totals", deleted);
+        }
+        Ok(())
+    }
+}
+
+/// Starts a thread which periodically writes pending totals and deletes expired ones.
+pub fn start(acct: Arc<Accountant>) -> Result<(), Error> {
+    thread::Builder::new()
+        .name("bandwidth".to_owned())
+        .spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(FLUSH_INTERVAL_SEC));
+                if let Err(e) = acct.flush_and_expire() {
+                    warn!("unable to write bandwidth totals: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// A body stream which counts its bytes, recording them when dropped.
+struct Counted {
+    inner: BodyStream,
+    acct: Arc<Accountant>,
+    user_name: String,
+    camera_uuid: Option<Uuid>,
+    bytes: i64,
+}
+
+impl Stream for Counted {
+    type Item = Chunk;
+    type Error = BoxedError;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, BoxedError> {
+        let r = self.inner.poll();
+        if let Ok(Async::Ready(Some(ref c))) = r {
+            self.bytes += c.remaining() as i64;
+        }
+        r
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        let user_name = ::std::mem::replace(&mut self.user_name, String::new());
+        self.acct.record(user_name, self.camera_uuid, self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::clock::RealClocks;
+    use body::Body;
+    use db::testutil::{self, TestDb};
+    use futures::{Future, Stream};
+    use super::Accountant;
+
+    #[test]
+    fn test_count() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let acct = Accountant::new(db.db.clone());
+        let u = db.test_camera_uuid;
+        for &(body, camera) in &[(&b"hello"[..], Some(u)), (&b"world!"[..], Some(u)),
+                                 (&b"x"[..], None)] {
+            let b = Accountant::wrap(&acct, Body::from(body.to_vec()), "slamb".to_owned(), camera);
+            b.into_stream().collect().wait().unwrap();
+        }
+
+        // A body dropped partway (here, before being read at all) counts as a request.
+        drop(Accountant::wrap(&acct, Body::from(vec![0; 10]), "".to_owned(), None));
+
+        acct.flush().unwrap();
+        let mut usage = db.db.lock().list_bandwidth_usage(0 .. i64::max_value()).unwrap();
+        usage.sort_by(|a, b| (&a.user_name, a.camera_uuid).cmp(&(&b.user_name, b.camera_uuid)));
+        let got: Vec<_> = usage.iter().map(|u| (&u.user_name[..], u.camera_uuid, u.requests,
+                                                 u.bytes)).collect();
+        assert_eq!(got, vec![("", None, 1, 0), ("slamb", None, 1, 1),
+                             ("slamb", Some(u), 2, 11)]);
+    }
+}
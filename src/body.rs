@@ -68,6 +68,11 @@ impl ::bytes::Buf for Chunk {
 
 pub struct Body(BodyStream);
 
+impl Body {
+    /// Returns the underlying stream of chunks, for reading a response within the server.
+    pub fn into_stream(self) -> BodyStream { self.0 }
+}
+
 impl Payload for Body {
     type Data = Chunk;
     type Error = BoxedError;
@@ -87,6 +92,12 @@ impl From<&'static [u8]> for Body {
     }
 }
 
+impl From<Vec<u8>> for Body {
+    fn from(v: Vec<u8>) -> Self {
+        Body(Box::new(stream::once(Ok(v.into()))))
+    }
+}
+
 impl From<Error> for Body {
     fn from(e: Error) -> Self {
         Body(Box::new(stream::once(Err(wrap_error(e)))))
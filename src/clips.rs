@@ -0,0 +1,263 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Clips of events, served as `/api/events/<id>.mp4`. Clips of new events are built in the
+//! background as soon as their recordings are committed, so that following a notification plays
+//! the event immediately rather than waiting on the `.mp4` file's construction.
+
+use base::sched;
+use db::{self, recording};
+use export;
+use failure::Error;
+use http_serve::Entity;
+use maintenance::Maintenance;
+use mp4;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use web::StreamDirs;
+
+/// A bounded cache of built event clips.
+pub struct EventClips {
+    db: Arc<db::Database>,
+    dirs: Arc<StreamDirs>,
+    max_entries: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Built clips by event id, least recently added first.
+    entries: VecDeque<(i64, mp4::File)>,
+    hits: u64,
+    misses: u64,
+    builds: u64,
+    failures: u64,
+}
+
+/// A snapshot of the cache's state and counters, as served by `/api/metrics`.
+pub struct Metrics {
+    pub entries: usize,
+    pub max_entries: usize,
+
+    /// The total length of the cached `.mp4` files. Their sample data is read from the sample
+    /// file directories as needed rather than held in memory.
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub builds: u64,
+    pub failures: u64,
+}
+
+/// How much of the video before and after an instantaneous event (such as a doorbell press or
+/// line crossing) to include in its clip.
+const INSTANT_BEFORE: recording::Duration = recording::Duration(5 * recording::TIME_UNITS_PER_SEC);
+const INSTANT_AFTER: recording::Duration = recording::Duration(10 * recording::TIME_UNITS_PER_SEC);
+
+/// Returns the time range to clip for an event. This is the event's own range, except that
+/// instantaneous events are given some context.
+pub fn clip_range(time: &Range<recording::Time>) -> Range<recording::Time> {
+    if time.start == time.end {
+        time.start - INSTANT_BEFORE .. time.end + INSTANT_AFTER
+    } else {
+        time.clone()
+    }
+}
+
+/// Returns the stream from which to build a clip of the given time range of a camera, and
+/// whether the range has been fully committed to it.
+pub fn stream_for(db: &db::LockedDatabase, camera_id: i32, time: &Range<recording::Time>)
+              -> Option<(i32, bool)> {
+    let c = db.cameras_by_id().get(&camera_id)?;
+    let stream_id = c.streams[db::StreamType::MAIN.index()]?;
+    let s = db.streams_by_id().get(&stream_id)?;
+    Some((stream_id, s.range.as_ref().map(|r| r.end >= time.end).unwrap_or(false)))
+}
+
+impl EventClips {
+    pub fn new(db: Arc<db::Database>, dirs: Arc<StreamDirs>, max_entries: usize) -> Arc<Self> {
+        Arc::new(EventClips {
+            db,
+            dirs,
+            max_entries,
+            inner: Mutex::new(Inner {
+                entries: VecDeque::with_capacity(max_entries),
+                hits: 0,
+                misses: 0,
+                builds: 0,
+                failures: 0,
+            }),
+        })
+    }
+
+    /// Returns a clip of the given event, or `None` if there is no such event. Clips not already
+    /// in the cache are built now and cached if complete. Must be called without the database
+    /// lock held.
+    pub fn get(&self, id: i64) -> Result<Option<mp4::File>, Error> {
+        {
+            let mut l = self.inner.lock();
+            let f = l.entries.iter().find(|e| e.0 == id).map(|e| e.1.clone());
+            if let Some(f) = f {
+                l.hits += 1;
+                return Ok(Some(f));
+            }
+            l.misses += 1;
+        }
+        let (stream_id, time, complete) = {
+            let db = self.db.lock();
+            let e = match db.get_event(id)? {
+                None => return Ok(None),
+                Some(e) => e,
+            };
+            let time = clip_range(&e.time);
+            match stream_for(&db, e.camera_id, &time) {
+                None => bail!("camera {} has no main stream for event {}", e.camera_id, id),
+                Some((s, c)) => (s, time, c),
+            }
+        };
+        self.build(id, stream_id, time, complete).map(Some)
+    }
+
+    fn build(&self, id: i64, stream_id: i32, time: Range<recording::Time>, cache: bool)
+             -> Result<mp4::File, Error> {
+        let r = self.dirs.get().and_then(|d| export::build(&self.db, &d, stream_id, time, None));
+        let mut l = self.inner.lock();
+        let f = match r {
+            Err(e) => {
+                l.failures += 1;
+                return Err(e);
+            },
+            Ok((_, f)) => f,
+        };
+        l.builds += 1;
+        if cache && self.max_entries > 0 {
+            l.entries.retain(|e| e.0 != id);
+            if l.entries.len() >= self.max_entries {
+                l.entries.pop_front();
+            }
+            l.entries.push_back((id, f.clone()));
+        }
+        Ok(f)
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let l = self.inner.lock();
+        Metrics {
+            entries: l.entries.len(),
+            max_entries: self.max_entries,
+            bytes: l.entries.iter().map(|e| e.1.len()).sum(),
+            hits: l.hits,
+            misses: l.misses,
+            builds: l.builds,
+            failures: l.failures,
+        }
+    }
+}
+
+enum Message {
+    /// An event was added.
+    Event { id: i64, stream_id: i32, time: Range<recording::Time>, complete: bool },
+
+    /// Recordings were committed to the given stream, which now ends at the given time.
+    Committed { stream_id: i32, end: recording::Time },
+}
+
+/// Starts a thread which builds a clip of each new (or extended) event once its recordings are
+/// committed.
+/// Clips aren't built while `maintenance` is active.
+pub fn start(clips: Arc<EventClips>, maintenance: Arc<Maintenance>) -> Result<(), Error> {
+    // The watcher is called with the database lock held, so clips can't be built directly.
+    let (tx, rx) = mpsc::channel();
+    clips.db.lock().watch(Box::new(move |db, c| {
+        match *c {
+            db::Change::EventAdded { id, ref event } |
+            db::Change::EventUpdated { id, ref event } => {
+                let time = clip_range(&event.time);
+                if let Some((stream_id, complete)) = stream_for(db, event.camera_id, &time) {
+                    let _ = tx.send(Message::Event {
+                        id,
+                        stream_id,
+                        time,
+                        complete,
+                    });
+                }
+            },
+            db::Change::RecordingsAdded { stream_id, .. } => {
+                let end = db.streams_by_id().get(&stream_id)
+                            .and_then(|s| s.range.as_ref().map(|r| r.end));
+                if let Some(end) = end {
+                    let _ = tx.send(Message::Committed { stream_id, end });
+                }
+            },
+            _ => {},
+        }
+    }));
+    thread::Builder::new()
+        .name("event-clips".to_owned())
+        .spawn(move || {
+            sched::enter(sched::Class::Web);
+            // Events whose recordings are still being written, least recent first. This is
+            // bounded like the cache itself; older events would be evicted anyway.
+            let mut pending: VecDeque<(i64, i32, Range<recording::Time>)> = VecDeque::new();
+            for m in rx {
+                if let Message::Event { id, .. } = m {
+                    // A merged event supersedes its earlier, shorter version.
+                    pending.retain(|p| p.0 != id);
+                }
+                let ready = match m {
+                    Message::Event { id, stream_id, time, complete: true } => {
+                        vec![(id, stream_id, time)]
+                    },
+                    Message::Event { id, stream_id, time, complete: false } => {
+                        if pending.len() >= clips.max_entries {
+                            pending.pop_front();
+                        }
+                        pending.push_back((id, stream_id, time));
+                        continue;
+                    },
+                    Message::Committed { stream_id, end } => {
+                        let (ready, rest): (Vec<_>, VecDeque<_>) =
+                            pending.drain(..).partition(|p| p.1 == stream_id && p.2.end <= end);
+                        pending = rest;
+                        ready
+                    },
+                };
+                for (id, stream_id, time) in ready {
+                    let _work = maintenance.work();
+                    if let Err(e) = clips.build(id, stream_id, time, true) {
+                        warn!("event {}: unable to build clip: {}", id, e);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
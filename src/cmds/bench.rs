@@ -0,0 +1,367 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to benchmark this machine's database and `.mp4` building.
+
+use clock::{self, Clocks};
+use db::{self, dir, recording};
+use failure::Error;
+use fnv::FnvHashMap;
+use mp4;
+use rusqlite;
+use serde_json;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tempdir::TempDir;
+
+static USAGE: &'static str = r#"
+Benchmarks this machine's database and .mp4 building.
+
+Replays synthetic recordings into a temporary database, then measures the
+throughput and latency of database flushes, the latency of listing
+recordings, and the rate of building .mp4 files. These help estimate how many
+cameras a machine can handle. Use --output on one machine and --baseline on
+another to compare them. Doesn't touch the real database or any sample files.
+
+Usage:
+
+    moonfire-nvr bench [options]
+    moonfire-nvr bench --help
+
+Options:
+
+    --dir=DIR              Set the directory in which to create the temporary
+                           database. This should be on the filesystem which
+                           will hold the real database. Defaults to the
+                           system's temporary directory.
+    --streams=N            Set the number of streams. [default: 8]
+    --hours=N              Set the hours of recordings per stream.
+                           [default: 24]
+    --fps=N                Set the frames per second of each stream.
+                           [default: 10]
+    --bitrate=BPS          Set the bits per second of each stream.
+                           [default: 2000000]
+    --iterations=N         Set the number of flushes, lists, and .mp4
+                           builds to time. [default: 100]
+    --output=FILE          Write the results as JSON to the given file.
+    --baseline=FILE        Compare the results with those written by an
+                           earlier --output.
+"#;
+
+/// The duration of each synthetic recording, as with the default `recording_duration_sec`.
+const RECORDING_SEC: i32 = 60;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_dir: Option<String>,
+    flag_streams: usize,
+    flag_hours: usize,
+    flag_fps: i32,
+    flag_bitrate: i32,
+    flag_iterations: usize,
+    flag_output: Option<String>,
+    flag_baseline: Option<String>,
+}
+
+struct Params {
+    streams: usize,
+    hours: usize,
+    fps: i32,
+    bitrate: i32,
+    iterations: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+struct Results {
+    streams: usize,
+    recordings: usize,
+
+    /// Recordings written per second when flushing an hour of every stream at once.
+    bulk_recordings_per_sec: f64,
+
+    /// Milliseconds per flush of a single recording, as when each stream flushes as soon as a
+    /// recording completes.
+    flush_ms: f64,
+
+    /// Milliseconds to list all of a stream's recordings.
+    list_ms: f64,
+
+    /// `.mp4` files of an hour of a stream built per second.
+    mp4_builds_per_sec: f64,
+}
+
+pub fn run() -> Result<(), Error> {
+    let args: Args = super::parse_args(USAGE)?;
+    if args.flag_streams == 0 || args.flag_hours == 0 || args.flag_fps <= 0 ||
+       args.flag_bitrate <= 0 || args.flag_iterations == 0 {
+        return Err(super::ConfigError("numeric options must be positive".to_owned()).into());
+    }
+    let baseline: Option<Results> = match args.flag_baseline {
+        None => None,
+        Some(ref f) => Some(serde_json::from_reader(fs::File::open(f)?)?),
+    };
+    let tmpdir = match args.flag_dir {
+        Some(ref d) => TempDir::new_in(d, "moonfire-nvr-bench")?,
+        None => TempDir::new("moonfire-nvr-bench")?,
+    };
+    info!("Using temporary directory {}", tmpdir.path().display());
+    let r = bench(tmpdir.path(), &Params {
+        streams: args.flag_streams,
+        hours: args.flag_hours,
+        fps: args.flag_fps,
+        bitrate: args.flag_bitrate,
+        iterations: args.flag_iterations,
+    })?;
+    print(&r, baseline.as_ref());
+    if let Some(ref f) = args.flag_output {
+        let mut f = fs::File::create(f)?;
+        serde_json::to_writer_pretty(&mut f, &r)?;
+    }
+    Ok(())
+}
+
+/// Returns a recording of `RECORDING_SEC` with a GOP of 2 seconds, in which key frames are four
+/// times the size of others.
+fn synthetic_recording(p: &Params, video_sample_entry_id: i32) -> db::RecordingToInsert {
+    let mut r = db::RecordingToInsert {
+        video_sample_entry_id,
+        ..Default::default()
+    };
+    let mut e = recording::SampleIndexEncoder::new();
+    let gop = 2 * p.fps;
+    let bytes_per_gop = p.bitrate / 8 * 2;
+    let nonkey_bytes = bytes_per_gop / (gop + 3);
+    let duration_90k = recording::TIME_UNITS_PER_SEC as i32 / p.fps;
+    for i in 0 .. RECORDING_SEC * p.fps {
+        let is_key = i % gop == 0;
+        e.add_sample(duration_90k, if is_key { 4 * nonkey_bytes } else { nonkey_bytes }, is_key,
+                     &mut r);
+    }
+    r
+}
+
+fn bench(path: &Path, p: &Params) -> Result<Results, Error> {
+    let clocks = clock::RealClocks {};
+    let mut conn = rusqlite::Connection::open(path.join("db"))?;
+    db::init(&mut conn)?;
+    db::Pragmas::default().apply(&conn)?;
+    let db = Arc::new(db::Database::new(clocks.clone(), conn, true)?);
+    let mut stream_ids = Vec::with_capacity(p.streams);
+    let (template, dir) = {
+        let mut l = db.lock();
+        let sample_path = path.join("sample");
+        fs::create_dir(&sample_path)?;
+        let sample_path = sample_path.to_str()
+            .ok_or_else(|| format_err!("path {:?} isn't UTF-8", sample_path))?.to_owned();
+        let dir_id = l.add_sample_file_dir(sample_path, false)?;
+        for i in 0 .. p.streams {
+            let camera_id = l.add_camera(db::CameraChange {
+                short_name: format!("bench-{}", i),
+                description: "synthetic recordings".to_owned(),
+                host: "".to_owned(),
+                username: "".to_owned(),
+                password: "".to_owned(),
+                streams: [
+                    db::StreamChange {
+                        sample_file_dir_id: Some(dir_id),
+                        mirror_sample_file_dir_id: None,
+                        rtsp_path: "".to_owned(),
+                        source: db::StreamSource::Rtsp,
+                        record: true,
+                        flush_if_sec: 0,
+                        recording_duration_sec: RECORDING_SEC as i64,
+                        sei_motion_uuid: None,
+                        snapshot_url: None,
+                        metadata_events: false,
+                        thumbnail_interval_sec: 0,
+                        encoder: Default::default(),
+                        input_options: None,
+                    },
+                    Default::default(),
+                ],
+                labels: Default::default(),
+                tenant_id: None,
+                event_source: None,
+                snapshot_schedule: None,
+            })?;
+            stream_ids.push(l.cameras_by_id().get(&camera_id).unwrap()
+                             .streams[db::StreamType::MAIN.index()].unwrap());
+        }
+        let video_sample_entry_id = l.insert_video_sample_entry(
+            1920, 1080, [0u8; 100].to_vec(), "avc1.000000".to_owned())?;
+        let dir = l.sample_file_dirs_by_id().get(&dir_id).unwrap().get()?;
+        (synthetic_recording(p, video_sample_entry_id), dir)
+    };
+    let dirs: FnvHashMap<i32, Arc<dir::SampleFileDir>> =
+        stream_ids.iter().map(|&id| (id, dir.clone())).collect();
+    let dirs = Arc::new(dirs);
+
+    // Bulk insert, flushing an hour of every stream at a time.
+    let recording_90k = recording::Duration(RECORDING_SEC as i64 * recording::TIME_UNITS_PER_SEC);
+    let first = recording::Time::new(clocks.realtime()) -
+                recording::Duration(recording_90k.0 * (p.hours as i64 * 60 + 1));
+    let mut start = first;
+    let mut run_offset = 0;
+    let bulk_start = Instant::now();
+    for _ in 0 .. p.hours {
+        let mut l = db.lock();
+        for _ in 0 .. 60 {
+            for &id in &stream_ids {
+                l.add_synced_recording(id, db::RecordingToInsert {
+                    start,
+                    run_offset,
+                    ..template.clone()
+                })?;
+            }
+            start = start + recording_90k;
+            run_offset += 1;
+        }
+        l.flush("bench bulk")?;
+    }
+    let recordings = p.hours * 60 * p.streams;
+    let bulk_recordings_per_sec = recordings as f64 / secs(bulk_start);
+    info!("Inserted {} recordings", recordings);
+
+    // Individual flushes.
+    let flush_start = Instant::now();
+    for i in 0 .. p.iterations {
+        let mut l = db.lock();
+        l.add_synced_recording(stream_ids[i % stream_ids.len()], db::RecordingToInsert {
+            start: start + recording::Duration(recording_90k.0 * (i / stream_ids.len()) as i64),
+            ..template.clone()
+        })?;
+        l.flush("bench")?;
+    }
+    let flush_ms = 1000. * secs(flush_start) / p.iterations as f64;
+
+    // Lists of each stream's recordings.
+    let all_time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+    let list_start = Instant::now();
+    for i in 0 .. p.iterations {
+        let mut n = 0;
+        db.lock().list_recordings_by_time(stream_ids[i % stream_ids.len()], all_time.clone(),
+                                          &mut |_| { n += 1; Ok(()) })?;
+        if n < p.hours * 60 {
+            bail!("listed {} recordings; expected at least {}", n, p.hours * 60);
+        }
+    }
+    let list_ms = 1000. * secs(list_start) / p.iterations as f64;
+
+    // .mp4 builds of the first hour.
+    let hour = first .. first + recording::Duration(recording_90k.0 * 60);
+    let build_start = Instant::now();
+    for i in 0 .. p.iterations {
+        let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+        {
+            let l = db.lock();
+            l.list_recordings_by_time(stream_ids[i % stream_ids.len()], hour.clone(),
+                                      &mut |r| {
+                let d = r.duration_90k;
+                builder.append(&*l, r, 0 .. d)
+            })?;
+        }
+        builder.build(db.clone(), dirs.clone())?;
+    }
+    let mp4_builds_per_sec = p.iterations as f64 / secs(build_start);
+
+    Ok(Results {
+        streams: p.streams,
+        recordings,
+        bulk_recordings_per_sec,
+        flush_ms,
+        list_ms,
+        mp4_builds_per_sec,
+    })
+}
+
+fn secs(since: Instant) -> f64 {
+    let e = since.elapsed();
+    e.as_secs() as f64 + e.subsec_nanos() as f64 / 1e9
+}
+
+/// Formats the ratio of `cur` to `base`, where higher is better if `higher_better`.
+fn compare(cur: f64, base: Option<f64>, higher_better: bool) -> String {
+    match base {
+        Some(b) if b > 0. && cur > 0. => {
+            format!(" ({:.2}x baseline)", if higher_better { cur / b } else { b / cur })
+        },
+        _ => String::new(),
+    }
+}
+
+fn print(r: &Results, baseline: Option<&Results>) {
+    println!("{} streams, {} recordings", r.streams, r.recordings);
+    println!("bulk insert:  {:10.1} recordings/sec{}", r.bulk_recordings_per_sec,
+             compare(r.bulk_recordings_per_sec, baseline.map(|b| b.bulk_recordings_per_sec),
+                     true));
+    println!("flush:        {:10.1} ms{}", r.flush_ms,
+             compare(r.flush_ms, baseline.map(|b| b.flush_ms), false));
+    println!("list:         {:10.1} ms{}", r.list_ms,
+             compare(r.list_ms, baseline.map(|b| b.list_ms), false));
+    println!(".mp4 build:   {:10.1} builds/sec{}", r.mp4_builds_per_sec,
+             compare(r.mp4_builds_per_sec, baseline.map(|b| b.mp4_builds_per_sec), true));
+
+    // Each stream flushes about once per recording (or less often, with flush_if_sec).
+    if r.flush_ms > 0. {
+        println!("The database can keep up with roughly {:.0} streams with {}-second recordings.",
+                 RECORDING_SEC as f64 * 1000. / r.flush_ms, RECORDING_SEC);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+    use super::*;
+
+    #[test]
+    fn small() {
+        testutil::init();
+        let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
+        let r = bench(tmpdir.path(), &Params {
+            streams: 2,
+            hours: 1,
+            fps: 10,
+            bitrate: 1_000_000,
+            iterations: 3,
+        }).unwrap();
+        assert_eq!(r.recordings, 120);
+        assert!(r.flush_ms > 0.);
+    }
+
+    #[test]
+    fn compare_ratios() {
+        assert_eq!(compare(2., Some(1.), true), " (2.00x baseline)");
+        assert_eq!(compare(2., Some(1.), false), " (0.50x baseline)");
+        assert_eq!(compare(2., None, true), "");
+    }
+}
@@ -33,13 +33,14 @@ extern crate cursive;
 use self::cursive::Cursive;
 use self::cursive::traits::{Boxable, Identifiable, Finder};
 use self::cursive::views;
-use db::{self, writer};
+use db::{self, recording, writer};
 use failure::Error;
 use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::Arc;
-use stream::{self, Opener, Stream};
+use stream;
 use super::{decode_size, encode_size};
+use uuid::Uuid;
 
 /// Builds a `CameraChange` from an active `edit_camera_dialog`.
 fn get_change(siv: &mut Cursive) -> db::CameraChange {
@@ -50,12 +51,28 @@ fn get_change(siv: &mut Cursive) -> db::CameraChange {
     let h = siv.find_id::<views::EditView>("host").unwrap().get_content().as_str().into();
     let u = siv.find_id::<views::EditView>("username").unwrap().get_content().as_str().into();
     let p = siv.find_id::<views::EditView>("password").unwrap().get_content().as_str().into();
+    let l = parse_labels(siv.find_id::<views::TextArea>("labels").unwrap().get_content());
+    let t = *siv.find_id::<views::SelectView<Option<i32>>>("tenant").unwrap().selection().unwrap();
+    let e = *siv.find_id::<views::SelectView<Option<db::EventSource>>>("event_source").unwrap()
+                .selection().unwrap();
+    let si = i64::from_str(siv.find_id::<views::EditView>("snapshot_interval_sec").unwrap()
+                              .get_content().as_str()).unwrap_or(0);
+    let sr = i64::from_str(siv.find_id::<views::EditView>("snapshot_retain_days").unwrap()
+                              .get_content().as_str()).unwrap_or(0);
     let mut c = db::CameraChange {
         short_name: sn,
         description: d,
         host: h,
         username: u,
         password: p,
+        labels: l,
+        tenant_id: t,
+        event_source: e,
+        snapshot_schedule: if si > 0 {
+            Some(db::SnapshotSchedule { interval_sec: si, retain_days: sr })
+        } else {
+            None
+        },
         streams: Default::default(),
     };
     for &t in &db::ALL_STREAM_TYPES {
@@ -66,22 +83,187 @@ fn get_change(siv: &mut Cursive) -> db::CameraChange {
         let f = i64::from_str(siv.find_id::<views::EditView>(
                 &format!("{}_flush_if_sec", t.as_str())).unwrap().get_content().as_str())
                 .unwrap_or(0);
+        let rd = i64::from_str(siv.find_id::<views::EditView>(
+                &format!("{}_recording_duration_sec", t.as_str())).unwrap().get_content()
+                .as_str())
+                .unwrap_or(0);
+        let sei = Uuid::parse_str(siv.find_id::<views::EditView>(
+                &format!("{}_sei_motion_uuid", t.as_str())).unwrap().get_content().as_str())
+                .ok();
+        let su = siv.find_id::<views::EditView>(&format!("{}_snapshot_url", t.as_str()))
+                 .unwrap().get_content().trim().to_owned();
+        let io = siv.find_id::<views::EditView>(&format!("{}_input_options", t.as_str()))
+                 .unwrap().get_content().trim().to_owned();
+        let me = siv.find_id::<views::Checkbox>(&format!("{}_metadata_events", t.as_str()))
+                .unwrap().is_checked();
+        let ti = i64::from_str(siv.find_id::<views::EditView>(
+                &format!("{}_thumbnail_interval_sec", t.as_str())).unwrap().get_content()
+                .as_str())
+                .unwrap_or(0);
+        let source = siv.find_id::<views::SelectView<&'static str>>(
+                &format!("{}_source", t.as_str())).unwrap().selection().unwrap();
+        let source_url = siv.find_id::<views::EditView>(&format!("{}_source_url", t.as_str()))
+                         .unwrap().get_content().trim().to_owned();
+        let mut enc = Vec::new();
+        for f in &["gov_length", "bitrate_kbps", "frame_rate", "resolution"] {
+            enc.push(siv.find_id::<views::EditView>(&format!("{}_encoder_{}", t.as_str(), f))
+                        .unwrap().get_content().trim().to_owned());
+        }
+        let encoder = db::EncoderSettings {
+            gov_length: i32::from_str(&enc[0]).ok(),
+            bitrate_kbps: i32::from_str(&enc[1]).ok(),
+            frame_rate: i32::from_str(&enc[2]).ok(),
+            resolution: parse_resolution(&enc[3]),
+        };
         let d = *siv.find_id::<views::SelectView<Option<i32>>>(
             &format!("{}_sample_file_dir", t.as_str()))
             .unwrap().selection().unwrap();
+        let m = *siv.find_id::<views::SelectView<Option<i32>>>(
+            &format!("{}_mirror_sample_file_dir", t.as_str()))
+            .unwrap().selection().unwrap();
         c.streams[t.index()] = db::StreamChange {
             rtsp_path: p,
+            source: match *source {
+                "hls" => db::StreamSource::Hls(source_url),
+                "mjpeg" => db::StreamSource::Mjpeg(source_url),
+                "v4l2" => db::StreamSource::V4l2(source_url),
+                "push" => db::StreamSource::Push(source_url),
+                _ => db::StreamSource::Rtsp,
+            },
             sample_file_dir_id: d,
+            mirror_sample_file_dir_id: m,
             record: r,
             flush_if_sec: f,
+            recording_duration_sec: rd,
+            sei_motion_uuid: sei,
+            snapshot_url: if su.is_empty() { None } else { Some(su) },
+            metadata_events: me,
+            thumbnail_interval_sec: ti,
+            encoder,
+            input_options: if io.is_empty() { None } else { Some(io) },
         };
     }
     c
 }
 
+/// Parses a resolution such as `1280x720`, returning `None` if blank or invalid.
+fn parse_resolution(s: &str) -> Option<(i32, i32)> {
+    let mut parts = s.splitn(2, 'x');
+    let w = i32::from_str(parts.next()?).ok()?;
+    let h = i32::from_str(parts.next()?).ok()?;
+    Some((w, h))
+}
+
+/// Parses labels from `key=value` lines, ignoring blank lines and lines without a `=`.
+fn parse_labels(s: &str) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    for line in s.lines() {
+        if let Some(i) = line.find('=') {
+            let k = line[..i].trim();
+            if !k.is_empty() {
+                labels.insert(k.to_owned(), line[i+1..].trim().to_owned());
+            }
+        }
+    }
+    labels
+}
+
+/// Formats labels as `key=value` lines, the inverse of `parse_labels`.
+fn format_labels(labels: &BTreeMap<String, String>) -> String {
+    let mut s = String::new();
+    for (k, v) in labels {
+        s.push_str(k);
+        s.push('=');
+        s.push_str(v);
+        s.push('\n');
+    }
+    s
+}
+
+/// Returns a warning if a recorded sub stream at the given resolution isn't smaller than the main
+/// stream. This usually means the sub stream's path actually refers to the main stream, which
+/// silently doubles storage use.
+fn sub_stream_warning(main: (u16, u16), sub: (u16, u16)) -> Option<String> {
+    let pixels = |r: (u16, u16)| r.0 as u32 * r.1 as u32;
+    if pixels(main) == 0 || pixels(sub) < pixels(main) {
+        return None;
+    }
+    Some(format!("The sub stream is {}x{}, which isn't smaller than the main stream's {}x{}. It \
+                  may be a second copy of the main stream, which would roughly double storage \
+                  use. Check the sub stream's path.", sub.0, sub.1, main.0, main.1))
+}
+
+/// Checks the main and sub streams before saving, as described in `sub_stream_warning`. Streams
+/// are only probed if they're new or their paths have changed; failures to connect aren't
+/// reported here, as "Test" is the place to diagnose those.
 fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
     let change = get_change(siv);
+    let (main_path, sub_path) = (change.streams[db::StreamType::MAIN.index()].rtsp_path.clone(),
+                                 change.streams[db::StreamType::SUB.index()].rtsp_path.clone());
+    if !change.streams[db::StreamType::SUB.index()].record || main_path.is_empty() ||
+       sub_path.is_empty() ||
+       change.streams.iter().any(|s| s.source != db::StreamSource::Rtsp) {
+        return save(siv, db, id, change);
+    }
+    if main_path == sub_path {
+        let warning = "The main and sub streams have the same path, so the same video would be \
+                       recorded twice.".to_owned();
+        return confirm_save(siv, db, id, change, warning);
+    }
+    let unchanged = id.map(|id| {
+        let l = db.lock();
+        let c = &l.cameras_by_id()[&id];
+        c.host == change.host && c.streams.iter().zip(change.streams.iter()).all(|(s, sc)| {
+            s.map(|s| l.streams_by_id()[&s].rtsp_path == sc.rtsp_path).unwrap_or(false)
+        })
+    }).unwrap_or(false);
+    if unchanged {
+        return save(siv, db, id, change);
+    }
+    let main_url = stream::rtsp_url(&change.username, &change.password, &change.host, &main_path);
+    let sub_url = stream::rtsp_url(&change.username, &change.password, &change.host, &sub_path);
+
+    // Invalid options are reported when saving.
+    let main_options = input_options(&change.streams[db::StreamType::MAIN.index()])
+                       .unwrap_or_else(|_| Vec::new());
+    let sub_options = input_options(&change.streams[db::StreamType::SUB.index()])
+                      .unwrap_or_else(|_| Vec::new());
+    siv.add_layer(views::Dialog::text("Checking stream resolutions...").title("Checking"));
+
+    // As in press_test, do the work in a background thread and poll for its completion.
+    siv.set_fps(5);
+    let sink = siv.cb_sink().clone();
+    let db = db.clone();
+    ::std::thread::spawn(move || {
+        let warning = match (stream::probe_resolution(&main_url, &main_options),
+                             stream::probe_resolution(&sub_url, &sub_options)) {
+            (Ok(m), Ok(s)) => sub_stream_warning(m, s),
+            _ => None,
+        };
+        sink.send(Box::new(move |siv: &mut Cursive| {
+            siv.set_fps(0);
+            siv.pop_layer();
+            match warning {
+                None => save(siv, &db, id, change.clone()),
+                Some(ref w) => confirm_save(siv, &db, id, change.clone(), w.clone()),
+            }
+        })).unwrap();
+    });
+}
+
+fn confirm_save(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>,
+                change: db::CameraChange, warning: String) {
+    let db = db.clone();
+    siv.add_layer(views::Dialog::text(warning)
+                  .title("Check streams")
+                  .button("Save anyway", move |siv| {
+                      siv.pop_layer();
+                      save(siv, &db, id, change.clone());
+                  })
+                  .dismiss_button("Back"));
+}
 
+fn save(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>, change: db::CameraChange) {
     let result = {
         let mut l = db.lock();
         if let Some(id) = id {
@@ -103,16 +285,39 @@ fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
     }
 }
 
-fn press_test_inner(url: &str) -> Result<String, Error> {
-    let stream = stream::FFMPEG.open(stream::Source::Rtsp(url))?;
-    let extra_data = stream.get_extra_data()?;
-    Ok(format!("{}x{} video stream", extra_data.width, extra_data.height))
+/// Returns the parsed `input_options` of the given stream.
+fn input_options(sc: &db::StreamChange) -> Result<Vec<(String, String)>, Error> {
+    match sc.input_options {
+        None => Ok(Vec::new()),
+        Some(ref o) => db::parse_input_options(o),
+    }
+}
+
+fn press_test_inner(url: &str, options: Result<Vec<(String, String)>, Error>)
+                    -> Result<String, Error> {
+    let p = stream::probe(url, &options?)?;
+    if let Some(r) = p.unsupported_reason {
+        bail!("{}x{} {} video stream can't be recorded: {}", p.width, p.height, p.video_codec, r);
+    }
+    Ok(format!("{}x{} {} video stream, {:.1} fps, {} kbps; {}",
+               p.width, p.height, p.video_codec, p.frames_per_sec, p.bits_per_sec / 1000,
+               match p.audio_codec {
+                   Some(a) => format!("{} audio (not recorded)", a),
+                   None => "no audio".to_owned(),
+               }))
 }
 
 fn press_test(siv: &mut Cursive, t: db::StreamType) {
     let c = get_change(siv);
-    let url = format!("rtsp://{}:{}@{}{}", c.username, c.password, c.host,
-                      c.streams[t.index()].rtsp_path);
+    if c.streams[t.index()].source != db::StreamSource::Rtsp {
+        siv.add_layer(views::Dialog::text("Test only supports RTSP sources.")
+                      .title("Test")
+                      .dismiss_button("Back"));
+        return;
+    }
+    let url = stream::rtsp_url(&c.username, &c.password, &c.host,
+                               &c.streams[t.index()].rtsp_path);
+    let options = input_options(&c.streams[t.index()]);
     siv.add_layer(views::Dialog::text(format!("Testing {} stream at {}. This may take a while \
                                                on timeout or if you have a long key frame interval",
                                               t.as_str(), url))
@@ -123,7 +328,7 @@ fn press_test(siv: &mut Cursive, t: db::StreamType) {
     siv.set_fps(5);
     let sink = siv.cb_sink().clone();
     ::std::thread::spawn(move || {
-        let r = press_test_inner(&url);
+        let r = press_test_inner(&url, options);
         sink.send(Box::new(move |siv: &mut Cursive| {
             // Polling is no longer necessary.
             siv.set_fps(0);
@@ -239,6 +444,12 @@ fn actually_delete(siv: &mut Cursive, db: &Arc<db::Database>, id: i32) {
 /// Adds or updates a camera.
 /// (The former if `item` is None; the latter otherwise.)
 fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i32>) {
+    let tenants: Vec<_> = ::std::iter::once(("<none>".to_owned(), None))
+                          .chain(db.lock()
+                                   .tenants_by_id()
+                                   .iter()
+                                   .map(|(&id, t)| (t.short_name.clone(), Some(id))))
+                          .collect();
     let camera_list = views::ListView::new()
         .child("id", views::TextView::new(match *item {
             None => "<new>".to_string(),
@@ -249,11 +460,27 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
         .child("host", views::EditView::new().with_id("host"))
         .child("username", views::EditView::new().with_id("username"))
         .child("password", views::EditView::new().with_id("password"))
-        .min_height(6);
+        .child("tenant", views::SelectView::<Option<i32>>::new()
+                         .with_all(tenants.iter().map(|t| t.clone()))
+                         .popup()
+                         .with_id("tenant"))
+        .child("event source", views::SelectView::<Option<db::EventSource>>::new()
+                               .item("<none>", None)
+                               .with_all(db::ALL_EVENT_SOURCES.iter()
+                                                              .map(|&s| (s.as_str(), Some(s))))
+                               .popup()
+                               .with_id("event_source"))
+        .child("snapshot interval (sec, 0=none)",
+               views::EditView::new().content("0").with_id("snapshot_interval_sec"))
+        .child("snapshot retention (days)",
+               views::EditView::new().content("30").with_id("snapshot_retain_days"))
+        .min_height(9);
     let mut layout = views::LinearLayout::vertical()
         .child(camera_list)
         .child(views::TextView::new("description"))
-        .child(views::TextArea::new().with_id("description").min_height(3));
+        .child(views::TextArea::new().with_id("description").min_height(3))
+        .child(views::TextView::new("labels (key=value per line)"))
+        .child(views::TextArea::new().with_id("labels").min_height(2));
 
     let dirs: Vec<_> = ::std::iter::once(("<none>".to_owned(), None))
                        .chain(db.lock()
@@ -269,14 +496,49 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                        .full_width())
                 .child(views::DummyView)
                 .child(views::Button::new("Test", move |siv| press_test(siv, type_))))
+            .child("source", views::SelectView::<&'static str>::new()
+                   .with_all(["rtsp", "hls", "mjpeg", "v4l2", "push"].iter().map(|&s| (s, s)))
+                   .popup()
+                   .with_id(format!("{}_source", type_.as_str())))
+            .child("source_url (hls/mjpeg url, v4l2 device, or push listen url)",
+                   views::EditView::new()
+                   .with_id(format!("{}_source_url", type_.as_str())))
             .child("sample file dir",
                    views::SelectView::<Option<i32>>::new()
                    .with_all(dirs.iter().map(|d| d.clone()))
                    .popup()
                    .with_id(format!("{}_sample_file_dir", type_.as_str())))
+            .child("mirror dir",
+                   views::SelectView::<Option<i32>>::new()
+                   .with_all(dirs.iter().map(|d| d.clone()))
+                   .popup()
+                   .with_id(format!("{}_mirror_sample_file_dir", type_.as_str())))
             .child("record", views::Checkbox::new().with_id(format!("{}_record", type_.as_str())))
             .child("flush_if_sec", views::EditView::new()
                    .with_id(format!("{}_flush_if_sec", type_.as_str())))
+            .child("recording_duration_sec", views::EditView::new()
+                   .content((recording::DESIRED_RECORDING_DURATION /
+                             recording::TIME_UNITS_PER_SEC).to_string())
+                   .with_id(format!("{}_recording_duration_sec", type_.as_str())))
+            .child("sei_motion_uuid", views::EditView::new()
+                   .with_id(format!("{}_sei_motion_uuid", type_.as_str())))
+            .child("snapshot_url", views::EditView::new()
+                   .with_id(format!("{}_snapshot_url", type_.as_str())))
+            .child("input_options", views::EditView::new()
+                   .with_id(format!("{}_input_options", type_.as_str())))
+            .child("metadata_events", views::Checkbox::new()
+                   .with_id(format!("{}_metadata_events", type_.as_str())))
+            .child("thumbnail_interval_sec", views::EditView::new()
+                   .content("0")
+                   .with_id(format!("{}_thumbnail_interval_sec", type_.as_str())))
+            .child("encoder_gov_length", views::EditView::new()
+                   .with_id(format!("{}_encoder_gov_length", type_.as_str())))
+            .child("encoder_bitrate_kbps", views::EditView::new()
+                   .with_id(format!("{}_encoder_bitrate_kbps", type_.as_str())))
+            .child("encoder_frame_rate", views::EditView::new()
+                   .with_id(format!("{}_encoder_frame_rate", type_.as_str())))
+            .child("encoder_resolution", views::EditView::new()
+                   .with_id(format!("{}_encoder_resolution", type_.as_str())))
             .child("usage/capacity",
                    views::TextView::new("").with_id(format!("{}_usage_cap", type_.as_str())))
             .min_height(5);
@@ -296,17 +558,15 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
         for (i, sid) in camera.streams.iter().enumerate() {
             let t = db::StreamType::from_index(i).unwrap();
 
-            // Find the index into dirs of the stored sample file dir.
+            // Find the index into dirs of the stored sample file dir and mirror.
             let mut selected_dir = 0;
+            let mut selected_mirror = 0;
             if let Some(s) = sid.map(|sid| l.streams_by_id().get(&sid).unwrap()) {
-                if let Some(id) = s.sample_file_dir_id {
-                    for (i, &(_, d_id)) in dirs.iter().skip(1).enumerate() {
-                        if Some(id) == d_id {
-                            selected_dir = i + 1;
-                            break;
-                        }
-                    }
-                }
+                selected_dir = dirs.iter().position(|&(_, d)| d == s.sample_file_dir_id)
+                                   .unwrap_or(0);
+                selected_mirror = dirs.iter()
+                                      .position(|&(_, d)| d == s.mirror_sample_file_dir_id)
+                                      .unwrap_or(0);
                 bytes += s.sample_file_bytes;
                 let u = if s.retain_bytes == 0 {
                     "0 / 0 (0.0%)".to_owned()
@@ -322,9 +582,61 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
                                |v: &mut views::Checkbox| v.set_checked(s.record));
                 dialog.find_id(&format!("{}_flush_if_sec", t.as_str()),
                                |v: &mut views::EditView| v.set_content(s.flush_if_sec.to_string()));
+                dialog.find_id(&format!("{}_recording_duration_sec", t.as_str()),
+                               |v: &mut views::EditView| {
+                                   v.set_content(s.recording_duration_sec.to_string())
+                               });
+                if let Some(u) = s.sei_motion_uuid {
+                    dialog.find_id(&format!("{}_sei_motion_uuid", t.as_str()),
+                                   |v: &mut views::EditView| v.set_content(u.to_string()));
+                }
+                let selected_source = match s.source {
+                    db::StreamSource::Rtsp => 0,
+                    db::StreamSource::Hls(_) => 1,
+                    db::StreamSource::Mjpeg(_) => 2,
+                    db::StreamSource::V4l2(_) => 3,
+                    db::StreamSource::Push(_) => 4,
+                };
+                dialog.find_id(&format!("{}_source", t.as_str()),
+                               |v: &mut views::SelectView<&'static str>| {
+                                   v.set_selection(selected_source)
+                               });
+                if let Some(u) = s.source.url() {
+                    dialog.find_id(&format!("{}_source_url", t.as_str()),
+                                   |v: &mut views::EditView| v.set_content(u.to_owned()));
+                }
+                if let Some(ref u) = s.snapshot_url {
+                    dialog.find_id(&format!("{}_snapshot_url", t.as_str()),
+                                   |v: &mut views::EditView| v.set_content(u.to_owned()));
+                }
+                if let Some(ref o) = s.input_options {
+                    dialog.find_id(&format!("{}_input_options", t.as_str()),
+                                   |v: &mut views::EditView| v.set_content(o.to_owned()));
+                }
+                dialog.find_id(&format!("{}_metadata_events", t.as_str()),
+                               |v: &mut views::Checkbox| v.set_checked(s.metadata_events));
+                dialog.find_id(&format!("{}_thumbnail_interval_sec", t.as_str()),
+                               |v: &mut views::EditView| {
+                                   v.set_content(s.thumbnail_interval_sec.to_string())
+                               });
+                let e = &s.encoder;
+                for (f, v) in vec![("gov_length", e.gov_length.map(|v| v.to_string())),
+                                 ("bitrate_kbps", e.bitrate_kbps.map(|v| v.to_string())),
+                                 ("frame_rate", e.frame_rate.map(|v| v.to_string())),
+                                 ("resolution",
+                                  e.resolution.map(|(w, h)| format!("{}x{}", w, h)))] {
+                    if let Some(v) = v {
+                        dialog.find_id(&format!("{}_encoder_{}", t.as_str(), f),
+                                       |view: &mut views::EditView| view.set_content(v));
+                    }
+                }
             }
             dialog.find_id(&format!("{}_sample_file_dir", t.as_str()),
                            |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_dir));
+            dialog.find_id(&format!("{}_mirror_sample_file_dir", t.as_str()),
+                           |v: &mut views::SelectView<Option<i32>>| {
+                               v.set_selection(selected_mirror)
+                           });
         }
         let name = camera.short_name.clone();
         for &(view_id, content) in &[("short_name", &*camera.short_name),
@@ -337,6 +649,25 @@ fn edit_camera_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i
         dialog.find_id("description",
                        |v: &mut views::TextArea| v.set_content(camera.description.to_string()))
               .expect("missing TextArea");
+        dialog.find_id("labels",
+                       |v: &mut views::TextArea| v.set_content(format_labels(&camera.labels)))
+              .expect("missing TextArea");
+        let selected_tenant = tenants.iter().position(|&(_, id)| id == camera.tenant_id)
+                                     .unwrap_or(0);
+        dialog.find_id("tenant",
+                       |v: &mut views::SelectView<Option<i32>>| v.set_selection(selected_tenant));
+        let selected_event_source = camera.event_source.map(|s| {
+            1 + db::ALL_EVENT_SOURCES.iter().position(|&a| a == s).unwrap()
+        }).unwrap_or(0);
+        dialog.find_id("event_source", |v: &mut views::SelectView<Option<db::EventSource>>| {
+            v.set_selection(selected_event_source)
+        });
+        if let Some(s) = camera.snapshot_schedule {
+            dialog.find_id("snapshot_interval_sec",
+                           |v: &mut views::EditView| v.set_content(s.interval_sec.to_string()));
+            dialog.find_id("snapshot_retain_days",
+                           |v: &mut views::EditView| v.set_content(s.retain_days.to_string()));
+        }
         dialog.title("Edit camera")
               .button("Edit", {
                   let db = db.clone();
@@ -376,3 +707,14 @@ pub fn top_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
         .dismiss_button("Done")
         .title("Edit cameras"));
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_sub_stream_warning() {
+        assert!(super::sub_stream_warning((1920, 1080), (704, 480)).is_none());
+        assert!(super::sub_stream_warning((1920, 1080), (1920, 1080)).is_some());
+        assert!(super::sub_stream_warning((1920, 1080), (3840, 2160)).is_some());
+        assert!(super::sub_stream_warning((0, 0), (704, 480)).is_none());
+    }
+}
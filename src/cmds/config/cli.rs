@@ -0,0 +1,209 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Non-interactive configuration subcommands, for use by scripts and configuration management
+//! tools. Each makes a single idempotent change and prints a JSON object describing the result to
+//! stdout.
+
+use db::{self, writer};
+use failure::Error;
+use serde_json;
+use super::declarative::{self, CameraConfig, Config, DirConfig};
+
+/// The machine-readable result of a subcommand.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Output {
+    /// The id of the affected object.
+    pub id: i32,
+
+    /// True iff the database was modified.
+    pub changed: bool,
+}
+
+/// Adds the sample file directory at `path` if it doesn't exist yet. `reserved_bytes` is applied
+/// if specified; otherwise an existing directory's value is kept.
+pub fn add_dir(db: &mut db::LockedDatabase, path: &str, network_fs: bool,
+               reserved_bytes: Option<i64>) -> Result<Output, Error> {
+    let existing = declarative::dir_id(db, path);
+    let reserved_bytes = reserved_bytes.unwrap_or_else(|| match existing {
+        Some(id) => db.sample_file_dirs_by_id()[&id].reserved_bytes,
+        None => 0,
+    });
+    let config = Config {
+        sample_file_dirs: vec![DirConfig {
+            path: path.to_owned(),
+            network_fs,
+            reserved_bytes,
+        }],
+        ..Default::default()
+    };
+    let changed = !declarative::apply(db, &config)?.is_empty();
+    let id = declarative::dir_id(db, path).expect("dir should exist after apply");
+    Ok(Output { id, changed })
+}
+
+/// Adds a camera described as JSON in the same form as a `cameras` entry of
+/// `moonfire-nvr config export`, or updates the existing camera of the same short name to match.
+pub fn add_camera(db: &mut db::LockedDatabase, json: &str) -> Result<Output, Error> {
+    let camera: CameraConfig = serde_json::from_str(json)?;
+    let short_name = camera.short_name.clone();
+    let config = Config {
+        cameras: vec![camera],
+        ..Default::default()
+    };
+    let changed = !declarative::apply(db, &config)?.is_empty();
+    let id = db.get_camera_by_short_name(&short_name).expect("camera should exist after apply").id;
+    Ok(Output { id, changed })
+}
+
+/// Sets the retention limit of the given stream, and its weight if specified. Recording is left
+/// enabled or disabled as before.
+pub fn set_stream_retention(db: &mut db::LockedDatabase, camera: &str, type_: &str,
+                            retain_bytes: i64, retain_weight: Option<i32>)
+                            -> Result<Output, Error> {
+    let type_ = db::StreamType::parse(type_)
+        .ok_or_else(|| format_err!("unknown stream type {}", type_))?;
+    let id = {
+        let c = db.get_camera_by_short_name(camera)
+                  .ok_or_else(|| format_err!("no such camera {}", camera))?;
+        c.streams[type_.index()]
+            .ok_or_else(|| format_err!("camera {} has no {} stream", camera, type_.as_str()))?
+    };
+    let (record, old_bytes, old_weight) = {
+        let s = &db.streams_by_id()[&id];
+        (s.record, s.retain_bytes, s.retain_weight)
+    };
+    let retain_weight = retain_weight.unwrap_or(old_weight);
+    if old_bytes == retain_bytes && old_weight == retain_weight {
+        return Ok(Output { id, changed: false });
+    }
+    db.update_retention(&[db::RetentionChange {
+        stream_id: id,
+        new_record: record,
+        new_limit: retain_bytes,
+        new_weight: retain_weight,
+    }])?;
+    Ok(Output { id, changed: true })
+}
+
+/// The machine-readable result of `camera delete`.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct DeleteOutput {
+    /// The id of the deleted camera.
+    pub id: i32,
+    pub streams: usize,
+    pub recordings: usize,
+    pub events: usize,
+    pub sample_files: usize,
+}
+
+/// Deletes the given camera along with its recordings, events, and sample files; see
+/// `LockedDatabase::teardown_camera`. As a guard, `confirm` must repeat the camera's short name.
+/// Unlike the other subcommands, this isn't idempotent: a second run fails.
+pub fn delete_camera(db: &db::Database, short_name: &str, confirm: &str)
+                     -> Result<DeleteOutput, Error> {
+    if confirm != short_name {
+        bail!("--confirm must repeat the camera's short name, {:?}", short_name);
+    }
+    let (id, removed) = {
+        let mut l = db.lock();
+        let (id, mut dirs) = {
+            let c = l.get_camera_by_short_name(short_name)
+                     .ok_or_else(|| format_err!("no such camera {}", short_name))?;
+            let dirs: Vec<i32> = c.streams.iter()
+                                  .filter_map(|s| *s)
+                                  .filter_map(|s| l.streams_by_id()[&s].sample_file_dir_id)
+                                  .collect();
+            (c.id, dirs)
+        };
+
+        // Open the directories first, so that a failure leaves the camera intact.
+        dirs.sort();
+        dirs.dedup();
+        l.open_sample_file_dirs(&dirs)?;
+        (id, l.teardown_camera(id)?)
+    };
+    info!("deleted camera {}: {} streams, {} recordings, {} events; unlinking {} sample files",
+          short_name, removed.streams, removed.recordings, removed.events,
+          removed.sample_files.len());
+    let mut dirs: Vec<i32> = removed.sample_files.iter().map(|&(d, _)| d).collect();
+    dirs.sort();
+    dirs.dedup();
+    for d in dirs {
+        let n = writer::collect_garbage(db, d)?;
+        info!("unlinked {} sample files from dir {}", n, d);
+    }
+    Ok(DeleteOutput {
+        id,
+        streams: removed.streams,
+        recordings: removed.recordings,
+        events: removed.events,
+        sample_files: removed.sample_files.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use clock;
+    use db::testutil::{self, TestDb};
+    use super::*;
+
+    #[test]
+    fn test_add_camera() {
+        testutil::init();
+        let tdb = TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        let json = r#"{
+            "short_name": "driveway",
+            "host": "192.168.1.101",
+            "streams": {"main": {"rtsp_path": "/main"}}
+        }"#;
+        let added = add_camera(&mut l, json).unwrap();
+        assert!(added.changed);
+        assert_eq!(add_camera(&mut l, json).unwrap(), Output { id: added.id, changed: false });
+        assert!(add_camera(&mut l, r#"{"short_name": "driveway"}"#).is_err());
+    }
+
+    #[test]
+    fn test_set_stream_retention() {
+        testutil::init();
+        let tdb = TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        let o = set_stream_retention(&mut l, "test camera", "main", 1 << 21, None).unwrap();
+        assert_eq!(o, Output { id: testutil::TEST_STREAM_ID, changed: true });
+        let o = set_stream_retention(&mut l, "test camera", "main", 1 << 21, Some(1)).unwrap();
+        assert!(!o.changed);
+        assert_eq!(l.streams_by_id()[&testutil::TEST_STREAM_ID].retain_bytes, 1 << 21);
+        set_stream_retention(&mut l, "test camera", "sub", 1 << 21, None).unwrap_err();
+        set_stream_retention(&mut l, "nonexistent", "main", 1 << 21, None).unwrap_err();
+    }
+}
@@ -0,0 +1,622 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Declarative configuration files.
+//!
+//! `moonfire-nvr config export` describes the sample file directories, tenants (and their users),
+//! export presets, and cameras (including their streams' retention) as YAML;
+//! `moonfire-nvr config import` applies such a file.
+//! Objects are matched by path or short name rather than id, so a file exported from one
+//! installation can be applied to another, and applying the same file twice changes nothing the
+//! second time. Objects missing from the file are left alone rather than deleted.
+
+use db::{self, recording};
+use failure::Error;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub sample_file_dirs: Vec<DirConfig>,
+
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+
+    #[serde(default)]
+    pub export_presets: Vec<ExportPresetConfig>,
+
+    #[serde(default)]
+    pub cameras: Vec<CameraConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirConfig {
+    pub path: String,
+
+    #[serde(default)]
+    pub network_fs: bool,
+
+    #[serde(default)]
+    pub reserved_bytes: i64,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub short_name: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retain_bytes: Option<i64>,
+
+    /// If exports by this tenant's users are watermarked.
+    #[serde(default)]
+    pub watermark_exports: bool,
+
+    /// The names of the users limited to this tenant's cameras.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportPresetConfig {
+    pub name: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<i32>,
+
+    #[serde(default)]
+    pub watermark: bool,
+
+    #[serde(default)]
+    pub timestamps: bool,
+
+    /// `mp4` (the default) or `mkv`.
+    #[serde(default = "default_container")]
+    pub container: String,
+}
+
+fn default_container() -> String { db::ExportContainer::Mp4.as_str().to_owned() }
+
+impl ExportPresetConfig {
+    fn change(&self) -> Result<db::ExportPresetChange, Error> {
+        Ok(db::ExportPresetChange {
+            name: self.name.clone(),
+            max_height: self.max_height,
+            watermark: self.watermark,
+            timestamps: self.timestamps,
+            container: db::ExportContainer::parse(&self.container).ok_or_else(
+                || format_err!("export preset {}: unknown container {:?}",
+                               self.name, self.container))?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraConfig {
+    pub short_name: String,
+
+    #[serde(default)]
+    pub description: String,
+
+    pub host: String,
+
+    #[serde(default)]
+    pub username: String,
+
+    #[serde(default)]
+    pub password: String,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    /// The owning tenant's short name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_source: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_schedule: Option<SnapshotScheduleConfig>,
+
+    /// Keyed by stream type (`main` or `sub`).
+    #[serde(default)]
+    pub streams: BTreeMap<String, StreamConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotScheduleConfig {
+    pub interval_sec: i64,
+
+    #[serde(default = "default_snapshot_retain_days")]
+    pub retain_days: i64,
+}
+
+fn default_snapshot_retain_days() -> i64 { 30 }
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamConfig {
+    #[serde(default)]
+    pub rtsp_path: String,
+
+    /// `hls` or `mjpeg` for cameras which only serve video over HTTP, from `source_url`; `v4l2`
+    /// for a local device at the `source_url` path; or `push` for devices which send video to
+    /// the `rtmp://` or `srt://` `source_url`. Absent for RTSP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+
+    /// The sample file directory's path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_file_dir: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror_sample_file_dir: Option<String>,
+
+    #[serde(default)]
+    pub record: bool,
+
+    #[serde(default)]
+    pub flush_if_sec: i64,
+
+    #[serde(default = "default_recording_duration_sec")]
+    pub recording_duration_sec: i64,
+
+    #[serde(default)]
+    pub retain_bytes: i64,
+
+    #[serde(default = "default_retain_weight")]
+    pub retain_weight: i32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sei_motion_uuid: Option<Uuid>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_url: Option<String>,
+
+    #[serde(default)]
+    pub metadata_events: bool,
+
+    #[serde(default)]
+    pub thumbnail_interval_sec: i64,
+
+    /// Video encoder settings to push to the camera via ONVIF.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<EncoderConfig>,
+
+    /// Extra ffmpeg input options, such as `analyzeduration=10000000`; see
+    /// `db::Stream::input_options`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_options: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncoderConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gov_length: Option<i32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitrate_kbps: Option<i32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<i32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+}
+
+impl EncoderConfig {
+    fn settings(&self) -> Result<db::EncoderSettings, Error> {
+        Ok(db::EncoderSettings {
+            gov_length: self.gov_length,
+            bitrate_kbps: self.bitrate_kbps,
+            frame_rate: self.frame_rate,
+            resolution: match (self.width, self.height) {
+                (Some(w), Some(h)) => Some((w, h)),
+                (None, None) => None,
+                _ => bail!("encoder width and height must be specified together"),
+            },
+        })
+    }
+}
+
+fn default_recording_duration_sec() -> i64 {
+    recording::DESIRED_RECORDING_DURATION / recording::TIME_UNITS_PER_SEC
+}
+
+fn default_retain_weight() -> i32 { 1 }
+
+fn dir_path(db: &db::LockedDatabase, id: Option<i32>) -> Option<String> {
+    id.map(|id| db.sample_file_dirs_by_id()[&id].path.clone())
+}
+
+pub fn dir_id(db: &db::LockedDatabase, path: &str) -> Option<i32> {
+    db.sample_file_dirs_by_id().values().find(|d| d.path == path).map(|d| d.id)
+}
+
+fn tenant_id(db: &db::LockedDatabase, short_name: &str) -> Option<i32> {
+    db.tenants_by_id().values().find(|t| t.short_name == short_name).map(|t| t.id)
+}
+
+/// Describes the current configuration.
+pub fn export(db: &db::LockedDatabase) -> Result<Config, Error> {
+    let sample_file_dirs = db.sample_file_dirs_by_id().values().map(|d| DirConfig {
+        path: d.path.clone(),
+        network_fs: d.network_fs,
+        reserved_bytes: d.reserved_bytes,
+    }).collect();
+    let tenants = db.tenants_by_id().values().map(|t| Ok(TenantConfig {
+        short_name: t.short_name.clone(),
+        retain_bytes: t.retain_bytes,
+        watermark_exports: t.watermark_exports,
+        users: db.list_tenant_users(t.id)?,
+    })).collect::<Result<_, Error>>()?;
+    let export_presets = db.export_presets_by_id().values().map(|p| ExportPresetConfig {
+        name: p.name.clone(),
+        max_height: p.max_height,
+        watermark: p.watermark,
+        timestamps: p.timestamps,
+        container: p.container.as_str().to_owned(),
+    }).collect();
+    let cameras = db.cameras_by_id().values().map(|c| {
+        let mut streams = BTreeMap::new();
+        for (i, id) in c.streams.iter().enumerate() {
+            let s = match *id {
+                None => continue,
+                Some(id) => &db.streams_by_id()[&id],
+            };
+            let type_ = db::StreamType::from_index(i).unwrap();
+            streams.insert(type_.as_str().to_owned(), StreamConfig {
+                rtsp_path: s.rtsp_path.clone(),
+                source: match s.source {
+                    db::StreamSource::Rtsp => None,
+                    ref o => Some(o.type_str().to_owned()),
+                },
+                source_url: s.source.url().map(str::to_owned),
+                sample_file_dir: dir_path(db, s.sample_file_dir_id),
+                mirror_sample_file_dir: dir_path(db, s.mirror_sample_file_dir_id),
+                record: s.record,
+                flush_if_sec: s.flush_if_sec,
+                recording_duration_sec: s.recording_duration_sec,
+                retain_bytes: s.retain_bytes,
+                retain_weight: s.retain_weight,
+                sei_motion_uuid: s.sei_motion_uuid,
+                snapshot_url: s.snapshot_url.clone(),
+                metadata_events: s.metadata_events,
+                thumbnail_interval_sec: s.thumbnail_interval_sec,
+                encoder: if s.encoder.is_empty() { None } else {
+                    Some(EncoderConfig {
+                        gov_length: s.encoder.gov_length,
+                        bitrate_kbps: s.encoder.bitrate_kbps,
+                        frame_rate: s.encoder.frame_rate,
+                        width: s.encoder.resolution.map(|r| r.0),
+                        height: s.encoder.resolution.map(|r| r.1),
+                    })
+                },
+                input_options: s.input_options.clone(),
+            });
+        }
+        CameraConfig {
+            short_name: c.short_name.clone(),
+            description: c.description.clone(),
+            host: c.host.clone(),
+            username: c.username.clone(),
+            password: c.password.clone(),
+            labels: c.labels.clone(),
+            tenant: c.tenant_id.map(|id| db.tenants_by_id()[&id].short_name.clone()),
+            event_source: c.event_source.map(|e| e.as_str().to_owned()),
+            snapshot_schedule: c.snapshot_schedule.map(|s| SnapshotScheduleConfig {
+                interval_sec: s.interval_sec,
+                retain_days: s.retain_days,
+            }),
+            streams,
+        }
+    }).collect();
+    Ok(Config {
+        sample_file_dirs,
+        tenants,
+        export_presets,
+        cameras,
+    })
+}
+
+fn lookup_dir(db: &db::LockedDatabase, path: &Option<String>) -> Result<Option<i32>, Error> {
+    match *path {
+        None => Ok(None),
+        Some(ref p) => Ok(Some(dir_id(db, p).ok_or_else(
+            || format_err!("no such sample file dir {}", p))?)),
+    }
+}
+
+fn camera_change(db: &db::LockedDatabase, c: &CameraConfig) -> Result<db::CameraChange, Error> {
+    let mut streams: [db::StreamChange; 2] = Default::default();
+    for (type_, s) in &c.streams {
+        let t = db::StreamType::parse(type_).ok_or_else(
+            || format_err!("camera {}: unknown stream type {}", c.short_name, type_))?;
+        streams[t.index()] = db::StreamChange {
+            sample_file_dir_id: lookup_dir(db, &s.sample_file_dir)?,
+            mirror_sample_file_dir_id: lookup_dir(db, &s.mirror_sample_file_dir)?,
+            rtsp_path: s.rtsp_path.clone(),
+            source: db::StreamSource::parse(s.source.as_ref().map(String::as_str).unwrap_or("rtsp"),
+                                            s.source_url.clone()).map_err(
+                |e| format_err!("camera {} stream {}: {}", c.short_name, type_, e))?,
+            record: s.record,
+            flush_if_sec: s.flush_if_sec,
+            recording_duration_sec: s.recording_duration_sec,
+            sei_motion_uuid: s.sei_motion_uuid,
+            snapshot_url: s.snapshot_url.clone(),
+            metadata_events: s.metadata_events,
+            thumbnail_interval_sec: s.thumbnail_interval_sec,
+            encoder: match s.encoder {
+                None => db::EncoderSettings::default(),
+                Some(ref e) => e.settings().map_err(
+                    |e| format_err!("camera {} stream {}: {}", c.short_name, type_, e))?,
+            },
+            input_options: s.input_options.clone(),
+        };
+    }
+    let tenant_id = match c.tenant {
+        None => None,
+        Some(ref t) => Some(tenant_id(db, t).ok_or_else(|| format_err!("no such tenant {}", t))?),
+    };
+    let event_source = match c.event_source {
+        None => None,
+        Some(ref e) => Some(db::EventSource::parse(e).ok_or_else(
+            || format_err!("camera {}: unknown event source {}", c.short_name, e))?),
+    };
+    Ok(db::CameraChange {
+        short_name: c.short_name.clone(),
+        description: c.description.clone(),
+        host: c.host.clone(),
+        username: c.username.clone(),
+        password: c.password.clone(),
+        streams,
+        labels: c.labels.clone(),
+        tenant_id,
+        event_source,
+        snapshot_schedule: c.snapshot_schedule.as_ref().map(|s| db::SnapshotSchedule {
+            interval_sec: s.interval_sec,
+            retain_days: s.retain_days,
+        }),
+    })
+}
+
+/// Applies `config`, returning a description of each change made. Sample file directories must
+/// be applied before the cameras which reference them, and so on, so this is done in the order of
+/// `Config`'s fields.
+pub fn apply(db: &mut db::LockedDatabase, config: &Config) -> Result<Vec<String>, Error> {
+    let mut changes = Vec::new();
+    for d in &config.sample_file_dirs {
+        let id = match dir_id(db, &d.path) {
+            Some(id) => {
+                if db.sample_file_dirs_by_id()[&id].network_fs != d.network_fs {
+                    bail!("can't change network_fs of existing sample file dir {}", d.path);
+                }
+                id
+            },
+            None => {
+                let id = db.add_sample_file_dir(d.path.clone(), d.network_fs)?;
+                changes.push(format!("added sample file dir {}", d.path));
+                id
+            },
+        };
+        if db.sample_file_dirs_by_id()[&id].reserved_bytes != d.reserved_bytes {
+            db.update_reserved_bytes(id, d.reserved_bytes)?;
+            changes.push(format!("set reserved bytes of sample file dir {}", d.path));
+        }
+    }
+
+    for t in &config.tenants {
+        let id = match tenant_id(db, &t.short_name) {
+            None => {
+                let id = db.add_tenant(t.short_name.clone(), t.retain_bytes,
+                                       t.watermark_exports)?;
+                changes.push(format!("added tenant {}", t.short_name));
+                id
+            },
+            Some(id) => {
+                let changed = {
+                    let cur = &db.tenants_by_id()[&id];
+                    cur.retain_bytes != t.retain_bytes ||
+                    cur.watermark_exports != t.watermark_exports
+                };
+                if changed {
+                    db.update_tenant(id, t.short_name.clone(), t.retain_bytes,
+                                     t.watermark_exports)?;
+                    changes.push(format!("updated tenant {}", t.short_name));
+                }
+                id
+            },
+        };
+        for u in &t.users {
+            let current = db.get_user(u)?.ok_or_else(|| format_err!("no such user {}", u))?;
+            if current.tenant_id != Some(id) {
+                db.set_user_tenant(u, Some(id))?;
+                changes.push(format!("assigned user {} to tenant {}", u, t.short_name));
+            }
+        }
+    }
+
+    for p in &config.export_presets {
+        let change = p.change()?;
+        match db.get_export_preset(&p.name).map(|e| e.id) {
+            None => {
+                db.add_export_preset(change)?;
+                changes.push(format!("added export preset {}", p.name));
+            },
+            Some(id) => if export(db)?.export_presets.iter().all(|e| e != p) {
+                db.update_export_preset(id, change)?;
+                changes.push(format!("updated export preset {}", p.name));
+            },
+        }
+    }
+
+    let existing = export(db)?.cameras;
+    for c in &config.cameras {
+        if existing.iter().any(|e| e == c) {
+            continue;
+        }
+        let change = camera_change(db, c)?;
+        let id = match db.get_camera_by_short_name(&c.short_name).map(|c| c.id) {
+            None => {
+                let id = db.add_camera(change)?;
+                changes.push(format!("added camera {}", c.short_name));
+                id
+            },
+            Some(id) => {
+                db.update_camera(id, change)?;
+                changes.push(format!("updated camera {}", c.short_name));
+                id
+            },
+        };
+
+        // Retention isn't part of CameraChange; set it separately.
+        let mut retention = Vec::new();
+        for (i, id) in db.cameras_by_id()[&id].streams.iter().enumerate() {
+            let type_ = db::StreamType::from_index(i).unwrap();
+            if let (Some(id), Some(s)) = (*id, c.streams.get(type_.as_str())) {
+                retention.push(db::RetentionChange {
+                    stream_id: id,
+                    new_record: s.record,
+                    new_limit: s.retain_bytes,
+                    new_weight: s.retain_weight,
+                });
+            }
+        }
+        db.update_retention(&retention)?;
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use clock;
+    use db::testutil::{self, TestDb};
+    use serde_yaml;
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        testutil::init();
+        let tdb = TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        let config = export(&l).unwrap();
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert_eq!(serde_yaml::from_str::<Config>(&yaml).unwrap(), config);
+
+        // Applying the current configuration is a no-op.
+        assert!(apply(&mut l, &config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn idempotent() {
+        testutil::init();
+        let tdb = TestDb::new(clock::RealClocks {});
+        let mut l = tdb.db.lock();
+        let dir = tdb.tmpdir.path().to_str().unwrap();
+        let yaml = format!(r#"
+sample_file_dirs:
+  - path: {dir}
+    reserved_bytes: 1024
+tenants:
+  - short_name: apt1
+    retain_bytes: 1048576
+    watermark_exports: true
+export_presets:
+  - name: external
+    max_height: 720
+    watermark: true
+    timestamps: true
+    container: mkv
+cameras:
+  - short_name: test camera
+    host: test-camera
+    username: foo
+    password: bar
+    streams:
+      main:
+        rtsp_path: /main
+        sample_file_dir: {dir}
+        record: true
+        retain_bytes: 2097152
+  - short_name: driveway
+    host: 192.168.1.101
+    tenant: apt1
+    labels:
+      location: outside
+    snapshot_schedule:
+      interval_sec: 3600
+    streams:
+      main:
+        rtsp_path: /Streaming/Channels/1
+        sample_file_dir: {dir}
+        record: true
+        retain_bytes: 1048576
+      sub:
+        rtsp_path: /Streaming/Channels/2
+        encoder:
+          gov_length: 15
+          width: 640
+          height: 360
+  - short_name: phone
+    host: ""
+    streams:
+      main:
+        source: mjpeg
+        source_url: http://phone:8080/video
+        sample_file_dir: {dir}
+        record: true
+"#, dir=dir);
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(apply(&mut l, &config).unwrap(), vec![
+            format!("set reserved bytes of sample file dir {}", dir),
+            "added tenant apt1".to_owned(),
+            "added export preset external".to_owned(),
+            "updated camera test camera".to_owned(),
+            "added camera driveway".to_owned(),
+            "added camera phone".to_owned(),
+        ]);
+        assert_eq!(export(&l).unwrap(), config);
+        assert!(apply(&mut l, &config).unwrap().is_empty());
+    }
+}
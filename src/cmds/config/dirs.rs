@@ -46,6 +46,7 @@ struct Stream {
     used: i64,
     record: bool,
     retain: Option<i64>,  // None if unparseable
+    weight: Option<i32>,  // None if unparseable or non-positive
 }
 
 struct Model {
@@ -54,6 +55,7 @@ struct Model {
     fs_capacity: i64,
     total_used: i64,
     total_retain: i64,
+    reserved: Option<i64>,  // None if unparseable
     errors: isize,
     streams: BTreeMap<i32, Stream>,
 }
@@ -66,9 +68,12 @@ fn update_limits_inner(model: &Model) -> Result<(), Error> {
             stream_id,
             new_record: stream.record,
             new_limit: stream.retain.unwrap(),
+            new_weight: stream.weight.unwrap(),
         });
     }
-    model.db.lock().update_retention(&changes)
+    let mut l = model.db.lock();
+    l.update_retention(&changes)?;
+    l.update_reserved_bytes(model.dir_id, model.reserved.unwrap())
 }
 
 fn update_limits(model: &Model, siv: &mut Cursive) {
@@ -117,6 +122,44 @@ fn edit_limit(model: &RefCell<Model>, siv: &mut Cursive, id: i32, content: &str)
     }
 }
 
+fn edit_weight(model: &RefCell<Model>, siv: &mut Cursive, id: i32, content: &str) {
+    let mut model = model.borrow_mut();
+    let model: &mut Model = &mut *model;
+    let stream = model.streams.get_mut(&id).unwrap();
+    let new_value = content.parse::<i32>().ok().and_then(|w| if w > 0 { Some(w) } else { None });
+    let old_errors = model.errors;
+    if new_value.is_none() != stream.weight.is_none() {
+        model.errors += if new_value.is_none() { 1 } else { -1 };
+        siv.find_id::<views::TextView>(&format!("{}_weight_ok", id))
+            .unwrap()
+            .set_content(if new_value.is_none() { "*" } else { " " });
+    }
+    stream.weight = new_value;
+    if (model.errors == 0) != (old_errors == 0) {
+        siv.find_id::<views::Button>("change")
+           .unwrap()
+           .set_enabled(model.errors == 0);
+    }
+}
+
+fn edit_reserved(model: &RefCell<Model>, siv: &mut Cursive, content: &str) {
+    let mut model = model.borrow_mut();
+    let new_value = decode_size(content).ok();
+    let old_errors = model.errors;
+    if new_value.is_none() != model.reserved.is_none() {
+        model.errors += if new_value.is_none() { 1 } else { -1 };
+        siv.find_id::<views::TextView>("reserved_ok")
+            .unwrap()
+            .set_content(if new_value.is_none() { "*" } else { " " });
+    }
+    model.reserved = new_value;
+    if (model.errors == 0) != (old_errors == 0) {
+        siv.find_id::<views::Button>("change")
+           .unwrap()
+           .set_enabled(model.errors == 0);
+    }
+}
+
 fn edit_record(model: &RefCell<Model>, id: i32, record: bool) {
     let mut model = model.borrow_mut();
     let model: &mut Model = &mut *model;
@@ -221,7 +264,10 @@ fn add_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
                         move |siv, path| add_dir(&db, siv, path)
                     })
                     .with_id("path")
-                    .fixed_width(60)))
+                    .fixed_width(60))
+                .child(views::LinearLayout::horizontal()
+                    .child(views::Checkbox::new().with_id("network_fs"))
+                    .child(views::TextView::new(" network filesystem (NFS, SMB)"))))
             .button("Add", {
                 let db = db.clone();
                 move |siv| {
@@ -234,7 +280,8 @@ fn add_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
 }
 
 fn add_dir(db: &Arc<db::Database>, siv: &mut Cursive, path: &str) {
-    if let Err(e) = db.lock().add_sample_file_dir(path.to_owned()) {
+    let network_fs = siv.find_id::<views::Checkbox>("network_fs").unwrap().is_checked();
+    if let Err(e) = db.lock().add_sample_file_dir(path.to_owned(), network_fs) {
         siv.add_layer(views::Dialog::text(format!("Unable to add path {}: {}", path, e))
                       .dismiss_button("Back")
                       .title("Error"));
@@ -282,6 +329,7 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
         let mut total_used = 0;
         let mut total_retain = 0;
         let fs_capacity;
+        let reserved;
         {
             let mut l = db.lock();
             for (&id, s) in l.streams_by_id() {
@@ -294,6 +342,7 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
                     used: s.sample_file_bytes,
                     record: s.record,
                     retain: Some(s.retain_bytes),
+                    weight: Some(s.retain_weight),
                 });
                 total_used += s.sample_file_bytes;
                 total_retain += s.retain_bytes;
@@ -303,8 +352,9 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
             }
             l.open_sample_file_dirs(&[dir_id]).unwrap();  // TODO: don't unwrap.
             let dir = l.sample_file_dirs_by_id().get(&dir_id).unwrap();
-            let stat = dir.get().unwrap().statfs().unwrap();
-            fs_capacity = stat.f_bsize as i64 * stat.f_bavail as i64 + total_used;
+            let stats = dir.get().unwrap().fs_stats().unwrap();
+            fs_capacity = stats.available_bytes + total_used;
+            reserved = dir.reserved_bytes;
             path = dir.path.clone();
         }
         Rc::new(RefCell::new(Model {
@@ -313,6 +363,7 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
             fs_capacity,
             total_used,
             total_retain,
+            reserved: Some(reserved),
             errors: (total_retain > fs_capacity) as isize,
             streams,
         }))
@@ -320,6 +371,7 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
 
     const RECORD_WIDTH: usize = 8;
     const BYTES_WIDTH: usize = 22;
+    const WEIGHT_WIDTH: usize = 8;
 
     let mut list = views::ListView::new();
     list.add_child(
@@ -327,7 +379,8 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
         views::LinearLayout::horizontal()
             .child(views::TextView::new("record").fixed_width(RECORD_WIDTH))
             .child(views::TextView::new("usage").fixed_width(BYTES_WIDTH))
-            .child(views::TextView::new("limit").fixed_width(BYTES_WIDTH)));
+            .child(views::TextView::new("limit").fixed_width(BYTES_WIDTH))
+            .child(views::TextView::new("weight").fixed_width(WEIGHT_WIDTH)));
     for (&id, stream) in &model.borrow().streams {
         let mut record_cb = views::Checkbox::new();
         record_cb.set_checked(stream.record);
@@ -351,7 +404,20 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
                         move |siv, _| press_change(&model, siv)
                     })
                     .fixed_width(20))
-                .child(views::TextView::new("").with_id(format!("{}_ok", id)).fixed_width(1)));
+                .child(views::TextView::new("").with_id(format!("{}_ok", id)).fixed_width(2))
+                .child(views::EditView::new()
+                    .content(stream.weight.unwrap().to_string())
+                    .on_edit({
+                        let model = model.clone();
+                        move |siv, content, _pos| edit_weight(&model, siv, id, content)
+                    })
+                    .on_submit({
+                        let model = model.clone();
+                        move |siv, _| press_change(&model, siv)
+                    })
+                    .fixed_width(WEIGHT_WIDTH - 2))
+                .child(views::TextView::new("").with_id(format!("{}_weight_ok", id))
+                       .fixed_width(1)));
     }
     let over = model.borrow().total_retain > model.borrow().fs_capacity;
     list.add_child(
@@ -369,6 +435,23 @@ fn edit_dir_dialog(db: &Arc<db::Database>, siv: &mut Cursive, dir_id: i32) {
             .child(views::DummyView{}.fixed_width(3))
             .child(views::DummyView{}.fixed_width(20))
             .child(views::TextView::new(encode_size(model.borrow().fs_capacity)).fixed_width(25)));
+    list.add_child(
+        "keep free",
+        views::LinearLayout::horizontal()
+            .child(views::DummyView{}.fixed_width(RECORD_WIDTH))
+            .child(views::DummyView{}.fixed_width(BYTES_WIDTH))
+            .child(views::EditView::new()
+                .content(encode_size(model.borrow().reserved.unwrap()))
+                .on_edit({
+                    let model = model.clone();
+                    move |siv, content, _pos| edit_reserved(&model, siv, content)
+                })
+                .on_submit({
+                    let model = model.clone();
+                    move |siv, _| press_change(&model, siv)
+                })
+                .fixed_width(20))
+            .child(views::TextView::new("").with_id("reserved_ok").fixed_width(1)));
     let mut change_button = views::Button::new("Change", {
         let model = model.clone();
         move |siv| press_change(&model, siv)
@@ -41,12 +41,17 @@ use clock;
 use db;
 use failure::Error;
 use regex::Regex;
+use serde_json;
+use serde_yaml;
 use std::sync::Arc;
 use std::fmt::Write;
 use std::str::FromStr;
 
 mod cameras;
+mod cli;
+mod declarative;
 mod dirs;
+mod tenants;
 
 static USAGE: &'static str = r#"
 Interactive configuration editor.
@@ -54,13 +59,49 @@ Interactive configuration editor.
 Usage:
 
     moonfire-nvr config [options]
+    moonfire-nvr config export [options]
+    moonfire-nvr config import [options] <file>
+    moonfire-nvr config dir add [options] <path>
+    moonfire-nvr config camera add [options] --json=JSON
+    moonfire-nvr config camera delete [options] <camera> --confirm=NAME
+    moonfire-nvr config stream set-retention [options] <camera> <type> <bytes>
     moonfire-nvr config --help
 
+`export` writes the sample file directories, tenants, and cameras (including
+stream retention and camera passwords) to stdout as YAML. `import` applies
+such a file, adding or updating objects matched by path or short name. It
+never deletes anything, and applying the same file again makes no changes.
+
+`dir add`, `camera add`, and `stream set-retention` make a single change
+without the interactive interface, for use by scripts and configuration
+management tools. Each is idempotent and prints a JSON object such as
+`{"id": 1, "changed": true}` to stdout. `camera add` takes a camera in the
+form of a `cameras` entry of `export`, as JSON; if a camera of the same short
+name exists, it's updated to match. Sizes may use suffixes such as `100G`.
+
+`camera delete` permanently removes a camera along with its streams,
+recordings (including archived ones), events, and sample files. As a guard,
+`--confirm` must repeat the camera's short name. The camera must have no
+holds. Unlike the other subcommands, it's not idempotent. It prints the
+numbers of objects removed as JSON, logging its progress as it unlinks the
+sample files. It can't run alongside the server; use
+`DELETE /api/cameras/<uuid>/` there instead.
+
 Options:
 
     --db-dir=DIR           Set the directory holding the SQLite3 index database.
                            This is typically on a flash device.
                            [default: /var/lib/moonfire-nvr/db]
+    --master-key=FILE      Encrypts newly added sample file directories with
+                           the master key in the given file. Required to
+                           manage already-encrypted directories.
+    --json=JSON            The camera to add, as JSON, or - to read it from
+                           stdin.
+    --confirm=NAME         The short name of the camera to delete, repeated.
+    --network-fs           The sample file directory is on a network
+                           filesystem.
+    --reserved-bytes=SIZE  The sample file directory's "keep free" space.
+    --weight=WEIGHT        The stream's retention weight.
 "#;
 
 static MULTIPLIERS: [(char, u64); 4] = [
@@ -120,13 +161,96 @@ fn decode_size(encoded: &str) -> Result<i64, ()> {
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_db_dir: String,
+    flag_master_key: Option<String>,
+    cmd_export: bool,
+    cmd_import: bool,
+    arg_file: Option<String>,
+    cmd_dir: bool,
+    cmd_camera: bool,
+    cmd_delete: bool,
+    cmd_stream: bool,
+    flag_json: Option<String>,
+    flag_confirm: Option<String>,
+    flag_network_fs: bool,
+    flag_reserved_bytes: Option<String>,
+    flag_weight: Option<i32>,
+    arg_path: Option<String>,
+    arg_camera: Option<String>,
+    arg_type: Option<String>,
+    arg_bytes: Option<String>,
+}
+
+fn decode_size_arg(encoded: &str) -> Result<i64, Error> {
+    decode_size(encoded).map_err(|()| format_err!("invalid size {:?}", encoded))
+}
+
+/// Runs one of the non-interactive subcommands, if specified, returning its result.
+fn run_cli(db: &db::Database, args: &Args) -> Result<Option<cli::Output>, Error> {
+    let mut l = db.lock();
+    if args.cmd_dir {
+        let reserved_bytes = match args.flag_reserved_bytes {
+            None => None,
+            Some(ref b) => Some(decode_size_arg(b)?),
+        };
+        return Ok(Some(cli::add_dir(&mut l, args.arg_path.as_ref().unwrap(),
+                                    args.flag_network_fs, reserved_bytes)?));
+    }
+    if args.cmd_camera {
+        let json = args.flag_json.as_ref().unwrap();
+        let json = if json == "-" {
+            let mut buf = String::new();
+            ::std::io::Read::read_to_string(&mut ::std::io::stdin(), &mut buf)?;
+            buf
+        } else {
+            json.clone()
+        };
+        return Ok(Some(cli::add_camera(&mut l, &json)?));
+    }
+    if args.cmd_stream {
+        return Ok(Some(cli::set_stream_retention(
+            &mut l, args.arg_camera.as_ref().unwrap(), args.arg_type.as_ref().unwrap(),
+            decode_size_arg(args.arg_bytes.as_ref().unwrap())?, args.flag_weight)?));
+    }
+    Ok(None)
 }
 
 pub fn run() -> Result<(), Error> {
     let args: Args = super::parse_args(USAGE)?;
-    let (_db_dir, conn) = super::open_conn(&args.flag_db_dir, super::OpenMode::ReadWrite)?;
+    let mode = if args.cmd_export { super::OpenMode::ReadOnly } else { super::OpenMode::ReadWrite };
+    let (_db_dir, conn) = super::open_conn(&args.flag_db_dir, mode)?;
     let clocks = clock::RealClocks {};
-    let db = Arc::new(db::Database::new(clocks, conn, true)?);
+    let db = Arc::new(db::Database::new(clocks, conn, !args.cmd_export)?);
+    if let Some(ref k) = args.flag_master_key {
+        db.lock().set_master_key(db::dir::MasterKey::load(k)?);
+    }
+
+    if args.cmd_export {
+        let config = declarative::export(&db.lock())?;
+        println!("{}", serde_yaml::to_string(&config)?);
+        return Ok(());
+    }
+    if args.cmd_import {
+        let f = ::std::fs::File::open(args.arg_file.as_ref().unwrap())?;
+        let config: declarative::Config = serde_yaml::from_reader(f)?;
+        let changes = declarative::apply(&mut db.lock(), &config)?;
+        if changes.is_empty() {
+            info!("configuration is already up to date");
+        }
+        for c in &changes {
+            info!("{}", c);
+        }
+        return Ok(());
+    }
+    if args.cmd_camera && args.cmd_delete {
+        let o = cli::delete_camera(&db, args.arg_camera.as_ref().unwrap(),
+                                   args.flag_confirm.as_ref().unwrap())?;
+        println!("{}", serde_json::to_string(&o)?);
+        return Ok(());
+    }
+    if let Some(o) = run_cli(&db, &args)? {
+        println!("{}", serde_json::to_string(&o)?);
+        return Ok(());
+    }
 
     let mut siv = Cursive::ncurses();
     //siv.add_global_callback('q', |s| s.quit());
@@ -139,6 +263,7 @@ pub fn run() -> Result<(), Error> {
             })
             .item("Directories and retention".to_string(), dirs::top_dialog)
             .item("Cameras and streams".to_string(), cameras::top_dialog)
+            .item("Tenants".to_string(), tenants::top_dialog)
             )
         .button("Quit", |siv| siv.quit())
         .title("Main menu"));
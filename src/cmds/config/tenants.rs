@@ -0,0 +1,141 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate cursive;
+
+use self::cursive::Cursive;
+use self::cursive::traits::{Boxable, Identifiable, Finder};
+use self::cursive::views;
+use db;
+use std::sync::Arc;
+use super::{decode_size, encode_size};
+
+fn press_edit(siv: &mut Cursive, db: &Arc<db::Database>, id: Option<i32>) {
+    let short_name = siv.find_id::<views::EditView>("short_name").unwrap().get_content();
+    let limit = siv.find_id::<views::EditView>("limit").unwrap().get_content();
+    let limit = if limit.trim().is_empty() {
+        None
+    } else {
+        match decode_size(limit.as_str()) {
+            Ok(l) => Some(l),
+            Err(()) => {
+                siv.add_layer(views::Dialog::text("Unparseable limit.")
+                              .title("Error")
+                              .dismiss_button("Back"));
+                return;
+            },
+        }
+    };
+    let watermark = siv.find_id::<views::Checkbox>("watermark_exports").unwrap().is_checked();
+    let result = {
+        let mut l = db.lock();
+        match id {
+            Some(id) => l.update_tenant(id, short_name.as_str().to_owned(), limit, watermark),
+            None => l.add_tenant(short_name.as_str().to_owned(), limit, watermark).map(|_| ()),
+        }
+    };
+    finish(siv, db, result.map_err(|e| format!("Unable to save tenant: {}", e)));
+}
+
+fn press_delete(siv: &mut Cursive, db: &Arc<db::Database>, id: i32) {
+    let result = db.lock().delete_tenant(id);
+    finish(siv, db, result.map_err(|e| format!("Unable to delete tenant: {}", e)));
+}
+
+fn finish(siv: &mut Cursive, db: &Arc<db::Database>, result: Result<(), String>) {
+    if let Err(e) = result {
+        siv.add_layer(views::Dialog::text(e)
+                      .title("Error")
+                      .dismiss_button("Abort"));
+    } else {
+        siv.pop_layer();  // get rid of the add/edit tenant dialog.
+
+        // Recreate the "Edit tenants" dialog from scratch; it's easier than adding the new entry.
+        siv.pop_layer();
+        top_dialog(db, siv);
+    }
+}
+
+/// Adds or updates a tenant.
+/// (The former if `item` is None; the latter otherwise.)
+fn edit_tenant_dialog(db: &Arc<db::Database>, siv: &mut Cursive, item: &Option<i32>) {
+    let mut list = views::ListView::new()
+        .child("short name", views::EditView::new().with_id("short_name"))
+        .child("limit (blank for none)", views::EditView::new().with_id("limit"))
+        .child("watermark exports", views::Checkbox::new().with_id("watermark_exports"));
+    let dialog = if let Some(id) = *item {
+        let l = db.lock();
+        let t = l.tenants_by_id().get(&id).expect("missing tenant");
+        list.find_id("short_name", |v: &mut views::EditView| v.set_content(t.short_name.clone()))
+            .expect("missing EditView");
+        if let Some(b) = t.retain_bytes {
+            list.find_id("limit", |v: &mut views::EditView| v.set_content(encode_size(b)))
+                .expect("missing EditView");
+        }
+        list.find_id("watermark_exports",
+                     |v: &mut views::Checkbox| v.set_checked(t.watermark_exports))
+            .expect("missing Checkbox");
+        views::Dialog::around(list.min_width(40))
+            .title("Edit tenant")
+            .button("Edit", {
+                let db = db.clone();
+                move |s| press_edit(s, &db, Some(id))
+            })
+            .button("Delete", {
+                let db = db.clone();
+                move |s| press_delete(s, &db, id)
+            })
+    } else {
+        views::Dialog::around(list.min_width(40))
+            .title("Add tenant")
+            .button("Add", {
+                let db = db.clone();
+                move |s| press_edit(s, &db, None)
+            })
+    };
+    siv.add_layer(dialog.dismiss_button("Cancel"));
+}
+
+pub fn top_dialog(db: &Arc<db::Database>, siv: &mut Cursive) {
+    siv.add_layer(views::Dialog::around(
+        views::SelectView::new()
+            .on_submit({
+                let db = db.clone();
+                move |siv, item| edit_tenant_dialog(&db, siv, item)
+            })
+            .item("<new tenant>".to_string(), None)
+            .with_all(db.lock()
+                        .tenants_by_id()
+                        .iter()
+                        .map(|(&id, t)| (format!("{}: {}", id, t.short_name), Some(id))))
+            .full_width())
+        .dismiss_button("Done")
+        .title("Edit tenants"));
+}
@@ -31,23 +31,31 @@
 use db::dir;
 use docopt;
 use failure::{Error, Fail};
-use libc;
+use regex::Regex;
 use rusqlite;
+use std::env;
+use std::fmt;
 use std::path::Path;
 
+mod bench;
 mod check;
 mod config;
 mod init;
 mod run;
+mod selftest;
+mod simulate;
 mod ts;
 mod upgrade;
 
 #[derive(Debug, Deserialize)]
 pub enum Command {
+    Bench,
     Check,
     Config,
     Init,
     Run,
+    Selftest,
+    Simulate,
     Ts,
     Upgrade,
 }
@@ -55,16 +63,33 @@ pub enum Command {
 impl Command {
     pub fn run(&self) -> Result<(), Error> {
         match *self {
+            Command::Bench => bench::run(),
             Command::Check => check::run(),
             Command::Config => config::run(),
             Command::Init => init::run(),
             Command::Run => run::run(),
+            Command::Selftest => selftest::run(),
+            Command::Simulate => simulate::run(),
             Command::Ts => ts::run(),
             Command::Upgrade => upgrade::run(),
         }
     }
 }
 
+/// The process exit status for an error in the commandline or configuration, which restarting
+/// won't fix. Other failures exit with status 1.
+pub const EXIT_CONFIG: i32 = 2;
+
+/// An error in the commandline or configuration; see `EXIT_CONFIG`.
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&self.0) }
+}
+
+impl Fail for ConfigError {}
+
 #[derive(PartialEq, Eq)]
 enum OpenMode {
     ReadOnly,
@@ -77,7 +102,7 @@ enum OpenMode {
 fn open_conn(db_dir: &str, mode: OpenMode) -> Result<(dir::Fd, rusqlite::Connection), Error> {
     let dir = dir::Fd::open(db_dir, mode == OpenMode::Create)?;
     let ro = mode == OpenMode::ReadOnly;
-    dir.lock(if ro { libc::LOCK_SH } else { libc::LOCK_EX } | libc::LOCK_NB)
+    dir.lock(if ro { dir::LockMode::Shared } else { dir::LockMode::Exclusive })
        .map_err(|e| e.context(format!("db dir {:?} already in use; can't get {} lock",
                                       db_dir, if ro { "shared" } else { "exclusive" })))?;
     let conn = rusqlite::Connection::open_with_flags(
@@ -95,8 +120,86 @@ fn open_conn(db_dir: &str, mode: OpenMode) -> Result<(dir::Fd, rusqlite::Connect
     Ok((dir, conn))
 }
 
+/// Options never taken from the environment. `--confirm` guards destructive commands, so a
+/// lingering variable must not answer for the operator.
+const NO_ENV_ARGS: &'static [&'static str] = &["help", "version", "confirm"];
+
+/// Appends to `args` options taken from the environment, for those options of `usage` which
+/// aren't already specified. `--db-dir=DIR` is taken from `MOONFIRE_DB_DIR`, for example. Boolean
+/// options such as `--read-only` are set by any value other than empty, `0`, or `false`.
+/// Options in `NO_ENV_ARGS` must be given on the commandline.
+fn add_env_args(usage: &str, args: &mut Vec<String>, env: &Fn(&str) -> Option<String>) {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"(?m)^[ \t]{1,8}(?:-\w, )?--([a-z0-9-]+)(=)?").unwrap();
+    }
+    for cap in RE.captures_iter(usage) {
+        let name = &cap[1];
+        if NO_ENV_ARGS.contains(&name) {
+            continue;
+        }
+        let flag = format!("--{}", name);
+        let flag_eq = format!("--{}=", name);
+        if args.iter().any(|a| *a == flag || a.starts_with(&flag_eq)) {
+            continue;
+        }
+        let var = format!("MOONFIRE_{}", name.to_uppercase().replace('-', "_"));
+        let value = match env(&var) {
+            None => continue,
+            Some(v) => v,
+        };
+        if cap.get(2).is_some() {
+            args.push(format!("{}{}", flag_eq, value));
+        } else if value != "" && value != "0" && value != "false" {
+            args.push(flag);
+        }
+    }
+}
+
 fn parse_args<'a, T>(usage: &str) -> Result<T, Error> where T: ::serde::Deserialize<'a> {
+    let mut args: Vec<String> = env::args().collect();
+    add_env_args(usage, &mut args, &|v| env::var(v).ok());
     Ok(docopt::Docopt::new(usage)
-                      .and_then(|d| d.deserialize())
-                      .unwrap_or_else(|e| e.exit()))
+                      .and_then(|d| d.argv(args).deserialize())
+                      .unwrap_or_else(|e| exit_usage(e)))
+}
+
+/// Exits after a commandline parsing error (with `EXIT_CONFIG`) or `--help`/`--version`.
+pub fn exit_usage(e: docopt::Error) -> ! {
+    if !e.fatal() {
+        e.exit();
+    }
+    eprintln!("{}", e);
+    ::std::process::exit(EXIT_CONFIG);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    #[test]
+    fn add_env_args() {
+        let usage = "
+Usage: moonfire-nvr run [options]
+
+Options:
+    -h, --help             Show this message.
+    --db-dir=DIR           Set the directory holding the SQLite3 index database.
+    --read-only            Forces read-only mode / disables recording.
+    --allow-probe          Allows probing.
+    --spool-bytes=BYTES    The maximum bytes.
+    --confirm=NAME         The short name of the camera to delete.
+";
+        let mut env = HashMap::new();
+        env.insert("MOONFIRE_DB_DIR", "/db");
+        env.insert("MOONFIRE_READ_ONLY", "1");
+        env.insert("MOONFIRE_ALLOW_PROBE", "false");
+        env.insert("MOONFIRE_SPOOL_BYTES", "0");
+        env.insert("MOONFIRE_HELP", "1");
+        env.insert("MOONFIRE_CONFIRM", "driveway");
+        let mut args = vec!["moonfire-nvr".to_owned(), "run".to_owned(),
+                            "--spool-bytes=1024".to_owned()];
+        super::add_env_args(usage, &mut args, &|v| env.get(v).map(|s| s.to_string()));
+        assert_eq!(args, &["moonfire-nvr", "run", "--spool-bytes=1024", "--db-dir=/db",
+                           "--read-only"]);
+    }
 }
@@ -28,19 +28,43 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use bandwidth;
+use base::sched;
+use base::trace;
 use clock;
-use db::{self, dir, writer};
+use clips;
+use crash;
+use db::{self, dir, recording, writer};
+use email;
+use embed;
+use export;
+use jobs;
+use l10n;
 use failure::Error;
+use maintenance::Maintenance;
+use memory;
 use fnv::FnvHashMap;
 use futures::{Future, Stream};
+use push;
+use reachability;
+use relocate;
+use snapshot;
+use std::collections::HashMap;
 use std::error::Error as StdError;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use stream;
 use streamer;
+use systemd;
+use teardown;
+use thumbnail;
+use timelapse;
+use timesync;
 use tokio;
-use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+#[cfg(unix)] use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+use vendor_events;
 use web;
 
 // These are used in a hack to get the name of the current time zone (e.g. America/Los_Angeles).
@@ -60,26 +84,212 @@ Options:
     --db-dir=DIR           Set the directory holding the SQLite3 index database.
                            This is typically on a flash device.
                            [default: /var/lib/moonfire-nvr/db]
+    --db-cache-kib=KIB     Set the size of SQLite's page cache. Defaults to
+                           16384 on 64-bit platforms, 2000 elsewhere.
+    --db-mmap-bytes=BYTES  Set the maximum bytes of the database to access
+                           via memory mapping. 0 disables mapping. Defaults
+                           to 268435456 on 64-bit platforms, 0 elsewhere.
+    --db-synchronous=MODE  Set SQLite's synchronous mode: full (each flush is
+                           durable) or normal (fewer syncs, but the latest
+                           flushes may be lost on power failure).
+                           [default: full]
     --ui-dir=DIR           Set the directory with the user interface files
                            (.html, .js, etc).
                            [default: /usr/local/lib/moonfire-nvr/ui]
     --http-addr=ADDR       Set the bind address for the unencrypted HTTP server.
                            [default: 0.0.0.0:8080]
     --read-only            Forces read-only mode / disables recording.
+    --master-key=FILE      The master key for encrypted sample file
+                           directories: 64 hex digits, as generated by
+                           `openssl rand -hex 32`. Required if any directory
+                           was added with this key.
     --allow-origin=ORIGIN  If present, adds a Access-Control-Allow-Origin:
                            header to HTTP responses. This may be useful for
                            Javascript development.
+    --failover-to-sub-stream
+                           When a camera's main stream fails repeatedly,
+                           record its sub stream in the main stream's place
+                           (flagged as degraded) until the main stream
+                           recovers.
+    --spool-bytes=BYTES    The maximum bytes of video per stream to hold in
+                           memory while its sample file directory is
+                           unavailable (such as an unplugged USB disk),
+                           rather than dropping frames. 0 disables spooling.
+                           [default: 67108864]
+    --allow-camera-reboot  Allows camera reboots via the HTTP API, using the
+                           ONVIF credentials stored in the database. There is
+                           currently no authentication, so enable this only
+                           if the HTTP port is restricted to trusted users.
+    --allow-probe          Allows probing arbitrary RTSP URLs via the HTTP API
+                           (/api/probe). As with --allow-camera-reboot, enable
+                           this only if the HTTP port is restricted to trusted
+                           users.
+    --vapid-key=FILE       Enables Web Push notifications (cameras going
+                           offline and events), signed with the P-256 private
+                           key in the given PEM file.
+    --vapid-subject=URI    A contact URI for push service operators, such as
+                           mailto:admin@example.com. Required with
+                           --vapid-key.
+    --mosaic-ffmpeg=PATH   Enables multi-camera mosaics (/api/mosaic.mjpeg),
+                           composited by the given ffmpeg binary. Each
+                           viewer runs a separate ffmpeg process which
+                           decodes every included stream, so this can be
+                           CPU-intensive.
+    --snapshot-ffmpeg=PATH
+                           Enables snapshots (/api/cameras/.../snapshot.jpg)
+                           and snapshots of doorbell presses
+                           (/api/events/<id>.jpg), decoded from streams
+                           by the given ffmpeg binary, and drawing
+                           detected objects on them. Streams with a
+                           snapshot URL don't need ffmpeg. Also enables
+                           thumbnails of streams with a thumbnail
+                           interval, decoded from key frames as they're
+                           recorded, and scheduled snapshots of cameras
+                           (/api/cameras/<uuid>/snapshots).
+    --embed-key=FILE       Enables public clip pages (/embed/<token>), whose
+                           tokens are signed with the secret in the given
+                           file, such as one created via
+                           `openssl rand -hex 32`.
+    --email-to=ADDRS       Enables emailing clips, to the given
+                           comma-separated recipients. Clips of new events
+                           are emailed automatically; others can be sent via
+                           the HTTP API.
+    --email-from=ADDR      The From: address of emailed clips.
+                           [default: moonfire-nvr@localhost]
+    --sendmail=PATH        The sendmail-compatible binary used to send email.
+                           [default: /usr/sbin/sendmail]
+    --email-max-attachment=BYTES
+                           Clips larger than this are sent as a link rather
+                           than an attachment. [default: 20000000]
+    --external-url=URL     The base URL of this server as seen by email
+                           recipients, such as https://nvr.example.com, for
+                           links to clips too large to attach.
+    --messages=FILE        A YAML file of translations of error messages,
+                           view.vtt timestamps, and emails, by locale; see
+                           guide/localization.md. Requests are answered in
+                           the locale negotiated from Accept-Language.
+    --locale=LOCALE        The locale of emails and of requests which accept
+                           none in --messages. [default: en]
+    --export-dir=DIR       Enables background exports (/api/export), spooled
+                           to the given directory.
+    --export-ffmpeg=PATH   Enables precise exports, which re-encode the first
+                           partial GOP with the given ffmpeg binary (which
+                           must support libx264) so the file starts exactly
+                           at the requested time, and redacted exports,
+                           which re-encode the whole clip.
+    --transcode-ffmpeg=PATH
+                           Enables recording of streams whose source is
+                           Motion JPEG, transcoded to H.264 by the given
+                           ffmpeg binary (which must support libx264), or a
+                           local V4L2 device, encoded via h264_v4l2m2m. Each
+                           such stream runs a separate ffmpeg process.
+    --job-concurrency=N    The maximum number of background jobs (such as
+                           exports) to run at once. [default: 1]
+    --watermark-exports    Watermarks every export with the requesting user
+                           (see --user-header) and export time.
+    --event-clips=N        The number of event clips (/api/events/<id>.mp4)
+                           to keep built. Clips of new events are built as
+                           soon as they're recorded, so following a
+                           notification plays instantly. 0 disables
+                           building in advance. [default: 16]
+    --event-merge-gap-sec=SEC
+                           Events of the same camera, type, and description
+                           less than this far apart are merged into a single
+                           event. 0 disables merging. [default: 30]
+    --event-max-sec=SEC    Events aren't merged beyond this length.
+                           [default: 600]
+    --retention-grace-hours=HOURS
+                           How long a stream may exceed its retention limit
+                           before its oldest recordings are deleted, so
+                           that a temporary bitrate spike doesn't eat into
+                           history. Streams may use this many hours of
+                           extra recording beyond their limits; 0 deletes
+                           immediately. [default: 0]
+    --log-file=FILE        The file to which this process's log is
+                           redirected, if any (such as by the service
+                           manager). Its tail is served via the HTTP API
+                           (/api/admin/logs?file=true) alongside the recent
+                           records kept in memory.
+    --user-header=NAME     The request header naming the user, such as
+                           X-Forwarded-User as set by an authenticating
+                           reverse proxy. Bytes served are totalled by this
+                           user and camera (/api/stats/bandwidth), and the
+                           user is named in audit logs and export
+                           watermarks and limited to its tenant's cameras.
+    --crash-dir=DIR        The directory in which to write a report (with
+                           backtrace, recording streams, and recent HTTP
+                           requests) if the server panics. The most recent
+                           is shown in /api/. Empty disables reports.
+                           [default: /var/lib/moonfire-nvr/crashes]
+    --memory-budget=BYTES  Limits the memory held by in-flight responses (such
+                           as .mp4 files' indexes). Requests which would
+                           exceed it fail with status 503, and background
+                           exports wait for room. 0 is unlimited.
+                           [default: 268435456]
+    --recorder-nice=N      The niceness (-20 to 19) of the threads which read
+                           from cameras and write recordings. Negative
+                           values require CAP_SYS_NICE.
+    --recorder-cpus=LIST   The CPUs on which to run those threads, in the
+                           format of taskset --cpu-list, such as 0-1,3.
+    --web-nice=N           The niceness of HTTP serving and background jobs
+                           (exports, clips, thumbnails, mosaics). Raising
+                           this keeps heavy viewing from causing dropped
+                           frames on a saturated machine.
+    --web-cpus=LIST        The CPUs on which to run those threads.
+    --trace-file=FILE      Writes profiling spans (database flushes, .mp4
+                           builds, and RTSP reads) to the given file, in
+                           the Chrome trace event format. The file grows
+                           quickly, so enable this only briefly.
 "#;
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_db_dir: String,
+    flag_db_cache_kib: Option<u64>,
+    flag_db_mmap_bytes: Option<u64>,
+    flag_db_synchronous: String,
     flag_http_addr: String,
     flag_ui_dir: String,
     flag_read_only: bool,
+    flag_master_key: Option<String>,
     flag_allow_origin: Option<String>,
+    flag_allow_camera_reboot: bool,
+    flag_failover_to_sub_stream: bool,
+    flag_spool_bytes: usize,
+    flag_allow_probe: bool,
+    flag_vapid_key: Option<String>,
+    flag_vapid_subject: Option<String>,
+    flag_mosaic_ffmpeg: Option<String>,
+    flag_snapshot_ffmpeg: Option<String>,
+    flag_embed_key: Option<String>,
+    flag_email_to: Option<String>,
+    flag_email_from: String,
+    flag_sendmail: String,
+    flag_email_max_attachment: u64,
+    flag_external_url: Option<String>,
+    flag_messages: Option<String>,
+    flag_locale: String,
+    flag_export_dir: Option<String>,
+    flag_export_ffmpeg: Option<String>,
+    flag_transcode_ffmpeg: Option<String>,
+    flag_job_concurrency: usize,
+    flag_watermark_exports: bool,
+    flag_event_clips: usize,
+    flag_event_merge_gap_sec: i64,
+    flag_retention_grace_hours: i64,
+    flag_event_max_sec: i64,
+    flag_log_file: Option<String>,
+    flag_user_header: Option<String>,
+    flag_crash_dir: String,
+    flag_trace_file: Option<String>,
+    flag_memory_budget: usize,
+    flag_recorder_nice: Option<i32>,
+    flag_recorder_cpus: Option<String>,
+    flag_web_nice: Option<i32>,
+    flag_web_cpus: Option<String>,
 }
 
+#[cfg(unix)]
 fn setup_shutdown() -> impl Future<Item = (), Error = ()> + Send {
     let int = Signal::new(SIGINT).flatten_stream().into_future();
     let term = Signal::new(SIGTERM).flatten_stream().into_future();
@@ -88,6 +298,14 @@ fn setup_shutdown() -> impl Future<Item = (), Error = ()> + Send {
        .map_err(|_| ())
 }
 
+/// Elsewhere, only Ctrl-C is available.
+#[cfg(not(unix))]
+fn setup_shutdown() -> impl Future<Item = (), Error = ()> + Send {
+    ::tokio_signal::ctrl_c().flatten_stream().into_future()
+       .map(|_| ())
+       .map_err(|_| ())
+}
+
 fn trim_zoneinfo(p: &str) -> &str {
     for zp in &ZONEINFO_PATHS {
         if p.starts_with(zp) {
@@ -157,34 +375,217 @@ fn resolve_zone() -> Result<String, Error> {
 
 struct Syncer {
     dir: Arc<dir::SampleFileDir>,
-    channel: writer::SyncerChannel<::std::fs::File>,
+    channel: writer::SyncerChannel<dir::SampleFileWriter>,
     join: thread::JoinHandle<()>,
 }
 
+/// Logs what was cleaned up from the previous run; see `db::StartupReport`.
+fn log_startup_report(l: &db::LockedDatabase) {
+    let r = l.startup_report();
+    match r.previous_run_end {
+        None => info!("Startup report: no previous run"),
+        Some(t) => info!("Startup report: previous run last wrote to the database at {}", t),
+    }
+    for (&id, s) in &r.streams {
+        let name = match l.streams_by_id().get(&id) {
+            None => format!("stream {}", id),
+            Some(st) => format!("{}-{}", l.cameras_by_id()[&st.camera_id].short_name,
+                                st.type_.as_str()),
+        };
+        if s.abandoned_recordings > 0 {
+            warn!("Startup report: {}: rolled back {} recordings which the previous run wrote \
+                   but never committed", name, s.abandoned_recordings);
+        }
+        if s.garbage_collected > 0 {
+            info!("Startup report: {}: unlinked {} recordings which the previous run deleted",
+                  name, s.garbage_collected);
+        }
+        if let Some(g) = s.gap {
+            info!("Startup report: {}: nothing recorded in the {} before startup", name, g);
+        }
+    }
+}
+
 pub fn run() -> Result<(), Error> {
     let args: Args = super::parse_args(USAGE)?;
+    let addr: ::std::net::SocketAddr = args.flag_http_addr.parse().map_err(
+        |_| super::ConfigError(format!("invalid --http-addr {:?}", args.flag_http_addr)))?;
+    if !args.flag_crash_dir.is_empty() {
+        let d = PathBuf::from(&args.flag_crash_dir);
+        match crash::init(&d) {
+            Ok(()) => crash::install(d),
+            Err(e) => warn!("Crash reports are disabled; unable to prepare {}: {}",
+                            d.display(), e),
+        }
+    }
+    if let Some(ref f) = args.flag_trace_file {
+        trace::start(::std::path::Path::new(f))?;
+    }
+    memory::BUDGET.set_limit(args.flag_memory_budget);
+    {
+        let policy = |nice, cpus: &Option<String>| {
+            sched::Policy::parse(nice, cpus.as_ref().map(String::as_str))
+                .map_err(|e| super::ConfigError(e.to_string()))
+        };
+        sched::configure(policy(args.flag_recorder_nice, &args.flag_recorder_cpus)?,
+                         policy(args.flag_web_nice, &args.flag_web_cpus)?)
+            .map_err(|e| super::ConfigError(e.to_string()))?;
+    }
     let clocks = clock::RealClocks {};
     let (_db_dir, conn) = super::open_conn(
         &args.flag_db_dir,
         if args.flag_read_only { super::OpenMode::ReadOnly } else { super::OpenMode::ReadWrite })?;
+    let mut pragmas = db::Pragmas::default();
+    if let Some(k) = args.flag_db_cache_kib {
+        pragmas.cache_kib = k;
+    }
+    if let Some(b) = args.flag_db_mmap_bytes {
+        pragmas.mmap_bytes = b;
+    }
+    pragmas.synchronous = db::Synchronous::parse(&args.flag_db_synchronous)
+        .map_err(|e| super::ConfigError(e.to_string()))?;
+    pragmas.apply(&conn)?;
     let db = Arc::new(db::Database::new(clocks.clone(), conn, !args.flag_read_only).unwrap());
     info!("Database is loaded.");
-
+    if let Some(ref k) = args.flag_master_key {
+        db.lock().set_master_key(dir::MasterKey::load(k)?);
+    }
     {
-        let mut l = db.lock();
-        let dirs_to_open: Vec<_> =
-            l.streams_by_id().values().filter_map(|s| s.sample_file_dir_id).collect();
-        l.open_sample_file_dirs(&dirs_to_open)?;
+        let sec = |s: i64| recording::Duration(s * recording::TIME_UNITS_PER_SEC);
+        db.lock().set_event_merge(db::EventMerge {
+            min_gap: sec(args.flag_event_merge_gap_sec),
+            max_duration: sec(args.flag_event_max_sec),
+        });
+        db.lock().set_retention_grace(sec(args.flag_retention_grace_hours * 3600));
     }
+
+    let stream_dirs = web::StreamDirs::new(db.clone())?;
     info!("Directories are opened.");
 
-    let zone = resolve_zone()?;
+    let vapid = match args.flag_vapid_key {
+        None => None,
+        Some(ref k) => {
+            let subject = args.flag_vapid_subject.clone()
+                              .ok_or_else(|| super::ConfigError(
+                                  "--vapid-key requires --vapid-subject".to_owned()))?;
+            Some(push::Vapid::load(k, subject)?)
+        },
+    };
+
+    if !args.flag_read_only {
+        db::mirror::start(db.clone())?;
+    }
+
+    let catalog = Arc::new(match args.flag_messages {
+        None => l10n::Catalog::new(HashMap::new(), &args.flag_locale)?,
+        Some(ref f) => l10n::Catalog::load(f, &args.flag_locale)?,
+    });
+
+    let maintenance = Maintenance::new();
+    let mut handlers: HashMap<&'static str, Arc<jobs::Handler>> = HashMap::new();
+    if let Some(ref to) = args.flag_email_to {
+        handlers.insert("email", Arc::new(email::Emailer {
+            db: db.clone(),
+            dirs: stream_dirs.clone(),
+            mailer: email::Mailer {
+                sendmail: PathBuf::from(&args.flag_sendmail),
+                from: args.flag_email_from.clone(),
+                to: to.split(',').map(|a| a.trim().to_owned()).collect(),
+                max_attachment_bytes: args.flag_email_max_attachment,
+                base_url: args.flag_external_url.clone(),
+                catalog: catalog.clone(),
+            },
+        }));
+    }
+    let exporter = match args.flag_export_dir {
+        None => None,
+        Some(ref d) => {
+            let e = Arc::new(export::Exporter::new(db.clone(), stream_dirs.clone(),
+                                                   PathBuf::from(d),
+                                                   args.flag_export_ffmpeg.as_ref()
+                                                       .map(PathBuf::from))?);
+            handlers.insert("export", e.clone());
+            Some(e)
+        },
+    };
+    if !args.flag_read_only {
+        handlers.insert("deleteCamera", Arc::new(teardown::CameraDeleter::new(db.clone())));
+        handlers.insert("moveStream", Arc::new(relocate::StreamMover::new(db.clone())));
+    }
+    let jobs = if args.flag_read_only {
+        None
+    } else {
+        Some(jobs::Queue::start(db.clone(), handlers, args.flag_job_concurrency,
+                                maintenance.clone())?)
+    };
+
+    let event_clips = clips::EventClips::new(db.clone(), stream_dirs.clone(),
+                                             args.flag_event_clips);
+    if args.flag_event_clips > 0 {
+        clips::start(event_clips.clone(), maintenance.clone())?;
+    }
+
+    let bandwidth = if args.flag_read_only {
+        None
+    } else {
+        let b = bandwidth::Accountant::new(db.clone());
+        bandwidth::start(b.clone())?;
+        Some(b)
+    };
+
+    let zone = resolve_zone().map_err(|e| super::ConfigError(e.to_string()))?;
     info!("Resolved timezone: {}", &zone);
-    let s = web::Service::new(db.clone(), Some(&args.flag_ui_dir), args.flag_allow_origin, zone)?;
+    let ready = Arc::new(AtomicBool::new(false));
+    let s = web::Service::new(web::Config {
+        db: db.clone(),
+        dirs: stream_dirs,
+        ui_dir: Some(&args.flag_ui_dir),
+        allow_origin: args.flag_allow_origin,
+        zone,
+        allow_camera_reboot: args.flag_allow_camera_reboot,
+        allow_probe: args.flag_allow_probe,
+        push_public_key: vapid.as_ref().map(|v| v.public_key().to_owned()),
+        mosaic_ffmpeg: args.flag_mosaic_ffmpeg.map(PathBuf::from),
+        snapshot_ffmpeg: args.flag_snapshot_ffmpeg.as_ref().map(PathBuf::from),
+        embed_signer: match args.flag_embed_key {
+            None => None,
+            Some(ref k) => Some(embed::Signer::load(k)?),
+        },
+        jobs: jobs.clone(),
+        exporter,
+        watermark_exports: args.flag_watermark_exports,
+        event_clips: Some(event_clips),
+        maintenance: maintenance.clone(),
+        log_file: args.flag_log_file.map(PathBuf::from),
+        bandwidth: bandwidth.clone(),
+        user_header: args.flag_user_header,
+        ready: ready.clone(),
+        catalog: catalog.clone(),
+    })?;
+    if let Some(v) = vapid {
+        push::start(db.clone(), v)?;
+    }
+    if let Some(ref j) = jobs {
+        if j.has_handler("email") {
+            email::start(&db, j.clone(), catalog.clone())?;
+        }
+    }
+    let mut thumbnails = None;
+    if !args.flag_read_only {
+        vendor_events::start(&db, &maintenance)?;
+        reachability::start(db.clone())?;
+        timesync::start(db.clone())?;
+        if let Some(ref f) = args.flag_snapshot_ffmpeg {
+            thumbnails = Some(thumbnail::start(db.clone(), PathBuf::from(f))?);
+        }
+        snapshot::start(db.clone(), args.flag_snapshot_ffmpeg.as_ref().map(PathBuf::from))?;
+        timelapse::start(db.clone(), args.flag_snapshot_ffmpeg.map(PathBuf::from))?;
+    }
 
     // Start a streamer for each stream.
     let shutdown_streamers = Arc::new(AtomicBool::new(false));
     let mut streamers = Vec::new();
+    let mut recording = Vec::new();
     let syncers = if !args.flag_read_only {
         let l = db.lock();
         let mut dirs = FnvHashMap::with_capacity_and_hasher(
@@ -194,6 +595,10 @@ pub fn run() -> Result<(), Error> {
             db: &db,
             opener: &*stream::FFMPEG,
             shutdown: &shutdown_streamers,
+            maintenance: &maintenance,
+            max_spool_bytes: args.flag_spool_bytes,
+            thumbnails,
+            transcode_ffmpeg: args.flag_transcode_ffmpeg.as_ref().map(PathBuf::from),
         };
 
         // Get the directories that need syncers.
@@ -218,6 +623,7 @@ pub fn run() -> Result<(), Error> {
                 join,
             });
         }
+        log_startup_report(&db.lock());
 
         // Then start up streams.
         let l = db.lock();
@@ -234,36 +640,56 @@ pub fn run() -> Result<(), Error> {
                     continue;
                 },
             };
-            let rotate_offset_sec = streamer::ROTATE_INTERVAL_SEC * i as i64 / streams as i64;
+            let rotate_offset_sec = stream.recording_duration_sec * i as i64 / streams as i64;
             let syncer = syncers.get(&sample_file_dir_id).unwrap();
             let mut streamer = streamer::Streamer::new(&env, syncer.dir.clone(),
                                                        syncer.channel.clone(), *id, camera, stream,
                                                        rotate_offset_sec,
-                                                       streamer::ROTATE_INTERVAL_SEC);
+                                                       stream.recording_duration_sec);
+            if args.flag_failover_to_sub_stream && stream.type_ == db::StreamType::MAIN {
+                if let Some(sub_id) = camera.streams[db::StreamType::SUB.index()] {
+                    streamer.set_fallback(l.streams_by_id().get(&sub_id).unwrap());
+                }
+            }
             info!("Starting streamer for {}", streamer.short_name());
+            recording.push(streamer.short_name().to_owned());
             let name = format!("s-{}", streamer.short_name());
             streamers.push(thread::Builder::new().name(name).spawn(move|| {
+                sched::enter(sched::Class::Recorder);
                 streamer.run();
             }).expect("can't create thread"));
         }
         drop(l);
         Some(syncers)
     } else { None };
+    crash::set_streams(recording);
 
     // Start the web interface.
-    let addr = args.flag_http_addr.parse().unwrap();
     let server = ::hyper::server::Server::bind(&addr).tcp_nodelay(true).serve(
         move || Ok::<_, Box<StdError + Send + Sync>>(s.clone()));
 
     let shutdown = setup_shutdown().shared();
 
     info!("Ready to serve HTTP requests");
+    ready.store(true, Ordering::SeqCst);
+    if let Err(e) = systemd::notify("READY=1") {
+        warn!("unable to notify systemd of readiness: {}", e);
+    }
+    systemd::start_watchdog(db.clone())?;
     let reactor = ::std::thread::spawn({
         let shutdown = shutdown.clone();
-        || tokio::run(server.with_graceful_shutdown(shutdown.map(|_| ()))
-                            .map_err(|e| error!("hyper error: {}", e)))
+        || {
+            // tokio's worker threads inherit this thread's policy.
+            sched::enter(sched::Class::Web);
+            tokio::run(server.with_graceful_shutdown(shutdown.map(|_| ()))
+                                   .map_err(|e| error!("hyper error: {}", e)))
+        }
     });
     shutdown.wait().unwrap();
+    ready.store(false, Ordering::SeqCst);
+    if let Err(e) = systemd::notify("STOPPING=1") {
+        warn!("unable to notify systemd of shutdown: {}", e);
+    }
 
     info!("Shutting down streamers.");
     shutdown_streamers.store(true, Ordering::SeqCst);
@@ -283,6 +709,14 @@ pub fn run() -> Result<(), Error> {
 
     info!("Waiting for HTTP requests to finish.");
     reactor.join().unwrap();
+    if let Some(b) = bandwidth {
+        if let Err(e) = b.flush() {
+            warn!("unable to write bandwidth totals: {}", e);
+        }
+    }
+    if let Err(e) = trace::stop() {
+        warn!("unable to finish trace file: {}", e);
+    }
     info!("Exiting.");
     Ok(())
 }
@@ -0,0 +1,255 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to check that this machine can record and serve video, without a camera.
+
+use clock::{self, Clocks};
+use db::{self, recording, writer};
+use failure::Error;
+use futures::{Future, sync::oneshot};
+use h264;
+use hyper;
+use l10n;
+use maintenance::Maintenance;
+use reqwest;
+use rusqlite;
+use serde_json;
+use std::error::Error as StdError;
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use synth;
+use tempdir::TempDir;
+use tokio;
+use web;
+
+static USAGE: &'static str = r#"
+Checks that this machine can record and serve video.
+
+Records a few seconds of synthetic video to a temporary directory, serves it
+over HTTP on the loopback interface, and verifies that the served .mp4 file
+matches what was recorded. Useful as a quick check of new hardware or a new
+filesystem before adding cameras. Doesn't touch the database.
+
+Usage:
+
+    moonfire-nvr selftest [options]
+    moonfire-nvr selftest --help
+
+Options:
+
+    --dir=DIR              Set the directory in which to create the temporary
+                           sample file directory. This should be on the
+                           filesystem which will hold recordings. Defaults to
+                           the system's temporary directory.
+"#;
+
+/// The number of frames to record, and the frame after which to start a second recording.
+const FRAMES: usize = 100;
+const SPLIT_FRAME: usize = 50;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_dir: Option<String>,
+}
+
+pub fn run() -> Result<(), Error> {
+    let args: Args = super::parse_args(USAGE)?;
+    let tmpdir = match args.flag_dir {
+        Some(ref d) => TempDir::new_in(d, "moonfire-nvr-selftest")?,
+        None => TempDir::new("moonfire-nvr-selftest")?,
+    };
+    let path = tmpdir.path().to_str()
+                     .ok_or_else(|| format_err!("path {:?} isn't UTF-8", tmpdir.path()))?
+                     .to_owned();
+    info!("Using temporary sample file directory {}", &path);
+
+    // Set up an in-memory database with one camera.
+    let clocks = clock::RealClocks {};
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    db::init(&mut conn)?;
+    let db = Arc::new(db::Database::new(clocks.clone(), conn, true)?);
+    let (camera_uuid, stream_id, dir_id) = {
+        let mut l = db.lock();
+        let dir_id = l.add_sample_file_dir(path.clone(), false)?;
+        let camera_id = l.add_camera(db::CameraChange {
+            short_name: "selftest".to_owned(),
+            description: "synthetic video".to_owned(),
+            host: "".to_owned(),
+            username: "".to_owned(),
+            password: "".to_owned(),
+            streams: [
+                db::StreamChange {
+                    sample_file_dir_id: Some(dir_id),
+                    mirror_sample_file_dir_id: None,
+                    rtsp_path: "".to_owned(),
+                    source: db::StreamSource::Rtsp,
+                    record: true,
+                    flush_if_sec: 0,
+                    recording_duration_sec: 60,
+                    sei_motion_uuid: None,
+                    snapshot_url: None,
+                    metadata_events: false,
+                    thumbnail_interval_sec: 0,
+                    encoder: Default::default(),
+                    input_options: None,
+                },
+                Default::default(),
+            ],
+            labels: Default::default(),
+            tenant_id: None,
+            event_source: None,
+            snapshot_schedule: None,
+        })?;
+        let (camera_uuid, stream_id) = {
+            let c = l.cameras_by_id().get(&camera_id).unwrap();
+            (c.uuid, c.streams[db::StreamType::MAIN.index()].unwrap())
+        };
+        l.update_retention(&[db::RetentionChange {
+            stream_id,
+            new_record: true,
+            new_limit: 1 << 30,
+            new_weight: 1,
+        }])?;
+        (camera_uuid, stream_id, dir_id)
+    };
+
+    // Record two recordings of synthetic video.
+    let mut source = synth::Source::new(synth::Config::default())?;
+    let extra_data = h264::ExtraData::parse(&source.extra_data(), source.config().width,
+                                            source.config().height)?;
+    let video_sample_entry_id = db.lock().insert_video_sample_entry(
+        extra_data.width, extra_data.height, extra_data.sample_entry,
+        extra_data.rfc6381_codec)?;
+    let (channel, join) = writer::start_syncer(db.clone(), dir_id)?;
+    let mut expected = Vec::new();
+    {
+        let dir = db.lock().sample_file_dirs_by_id().get(&dir_id).unwrap().get()?;
+        let mut w = writer::Writer::new(&dir, &db, &channel, stream_id, video_sample_entry_id);
+        let start = recording::Time::new(clocks.realtime());
+        let mut transformed = Vec::new();
+        for (i, f) in source.by_ref().take(FRAMES).enumerate() {
+            if i == SPLIT_FRAME {
+                w.close(Some(f.pts_90k));
+            }
+            h264::transform_sample_data(&f.data, &mut transformed)?;
+            w.write(&transformed, start + recording::Duration(f.pts_90k), f.pts_90k, f.is_key)?;
+            expected.extend_from_slice(&transformed);
+        }
+        w.close(Some(source.next_frame().pts_90k));
+    }
+    channel.flush();
+    db.lock().flush("selftest")?;
+    let mut ids = Vec::new();
+    db.lock().list_recordings_by_id(stream_id, 0 .. i32::max_value(), &mut |r| {
+        ids.push(r.id.recording());
+        Ok(())
+    })?;
+    if ids.len() != 2 {
+        bail!("expected 2 recordings; database has {:?}", ids);
+    }
+    info!("Recorded {} bytes of video in {:?}", expected.len(), ids);
+
+    // Serve them over HTTP.
+    let service = web::Service::new(web::Config {
+        db: db.clone(),
+        dirs: web::StreamDirs::new(db.clone())?,
+        ui_dir: None,
+        allow_origin: None,
+        zone: "UTC".to_owned(),
+        allow_camera_reboot: false,
+        allow_probe: false,
+        push_public_key: None,
+        mosaic_ffmpeg: None,
+        snapshot_ffmpeg: None,
+        embed_signer: None,
+        jobs: None,
+        exporter: None,
+        watermark_exports: false,
+        event_clips: None,
+        maintenance: Maintenance::new(),
+        log_file: None,
+        bandwidth: None,
+        user_header: None,
+        ready: Arc::new(AtomicBool::new(true)),
+        catalog: Arc::new(l10n::Catalog::english()),
+    })?;
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let server = hyper::server::Server::bind(&addr).tcp_nodelay(true).serve(
+        move || Ok::<_, Box<StdError + Send + Sync>>(service.clone()));
+    let base_url = format!("http://{}/api/cameras/{}/main", server.local_addr(), camera_uuid);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let reactor = ::std::thread::spawn(
+        || tokio::run(server.with_graceful_shutdown(shutdown_rx.map_err(|_| ()))
+                            .map_err(|e| error!("hyper error: {}", e))));
+    let result = check_served(&base_url, &ids, &expected);
+    let _ = shutdown_tx.send(());
+    reactor.join().unwrap();
+
+    // Shut down the syncer. As in `run`, this requires dropping the database's channel too.
+    db.lock().clear_on_flush();
+    drop(channel);
+    join.join().unwrap();
+    result?;
+    println!("selftest passed: recorded and served {} bytes of video in {}",
+             expected.len(), &path);
+    Ok(())
+}
+
+/// Checks the recordings are listed and that the `.mp4` of them holds exactly `expected`.
+fn check_served(base_url: &str, ids: &[i32], expected: &[u8]) -> Result<(), Error> {
+    let client = reqwest::Client::new();
+    let mut resp = client.get(&format!("{}/recordings", base_url)).send()?;
+    if !resp.status().is_success() {
+        bail!("/recordings returned status {}", resp.status());
+    }
+    let list: serde_json::Value = resp.json()?;
+    let n = list["recordings"].as_array().map(|a| a.len()).unwrap_or(0);
+    if n == 0 {
+        bail!("/recordings listed no recordings: {}", list);
+    }
+
+    let url = format!("{}/view.mp4?s={}-{}", base_url, ids[0], ids[ids.len() - 1]);
+    let mut resp = client.get(&url).send()?;
+    if !resp.status().is_success() {
+        bail!("{} returned status {}", url, resp.status());
+    }
+    let mut body = Vec::new();
+    resp.read_to_end(&mut body)?;
+
+    // The media data (`mdat`) box is last, and should consist of exactly the recorded samples.
+    if !body.ends_with(expected) || body.len() < expected.len() + 16 ||
+       &body[body.len() - expected.len() - 12 .. body.len() - expected.len() - 8] != b"mdat" {
+        bail!("{} returned {} bytes which don't end with an mdat of the {} bytes recorded",
+              url, body.len(), expected.len());
+    }
+    info!("Served {} bytes of .mp4 via {}", body.len(), url);
+    Ok(())
+}
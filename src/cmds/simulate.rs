@@ -0,0 +1,106 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Subcommand to simulate a camera, for development.
+
+use failure::Error;
+use simulator;
+use std::net::TcpListener;
+use std::thread;
+use synth;
+
+static USAGE: &'static str = r#"
+Simulates a camera, serving synthetic video over RTSP.
+
+This is intended for developing Moonfire NVR without physical cameras. Add a
+camera with host 127.0.0.1:8554 (or as given by --rtsp-addr) and any RTSP
+path; every path serves the same stream: a box moving across a gradient. The
+video is valid H.264 but uncompressed, so key frames are large.
+
+Usage:
+
+    moonfire-nvr simulate [options]
+    moonfire-nvr simulate --help
+
+Options:
+
+    --rtsp-addr=ADDR       Set the bind address for the RTSP server.
+                           [default: 127.0.0.1:8554]
+    --onvif-addr=ADDR      If present, also serves a minimal ONVIF device
+                           service which accepts reboot requests. Note
+                           Moonfire NVR always sends ONVIF requests to port
+                           80 of the camera's host, so this should be
+                           something like 127.0.0.2:80.
+    --width=PIXELS         Set the frame width, a multiple of 16.
+                           [default: 640]
+    --height=PIXELS        Set the frame height, a multiple of 16.
+                           [default: 480]
+    --fps=N                Set the frames per second. [default: 10]
+    --gop=N                Set the frames from one key frame to the next.
+                           [default: 20]
+    --bitrate=BPS          Pad frames with filler data to at least this many
+                           bits per second. [default: 0]
+"#;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_rtsp_addr: String,
+    flag_onvif_addr: Option<String>,
+    flag_width: u16,
+    flag_height: u16,
+    flag_fps: u32,
+    flag_gop: u32,
+    flag_bitrate: u32,
+}
+
+pub fn run() -> Result<(), Error> {
+    let args: Args = super::parse_args(USAGE)?;
+    let config = synth::Config {
+        width: args.flag_width,
+        height: args.flag_height,
+        frames_per_sec: args.flag_fps,
+        gop_frames: args.flag_gop,
+        bits_per_sec: args.flag_bitrate,
+    };
+    synth::Source::new(config.clone())?;  // validate config before binding.
+    if let Some(ref a) = args.flag_onvif_addr {
+        let l = TcpListener::bind(a)?;
+        info!("Serving ONVIF at http://{}/onvif/device_service", l.local_addr()?);
+        thread::Builder::new().name("onvif".to_owned()).spawn(move || {
+            if let Err(e) = simulator::serve_onvif(l) {
+                error!("ONVIF server failed: {}", e);
+            }
+        })?;
+    }
+    let l = TcpListener::bind(&args.flag_rtsp_addr)?;
+    info!("Serving {}x{} at {} fps via rtsp://{}/", config.width, config.height,
+          config.frames_per_sec, l.local_addr()?);
+    simulator::serve_rtsp(l, config)
+}
@@ -0,0 +1,252 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Crash reports, to make bug reports about overnight failures actionable.
+//!
+//! `install` adds a panic hook which writes a JSON report (message, backtrace, version, recording
+//! streams, and recent HTTP requests) to the crash directory. The most recent report is surfaced
+//! as `/api/`'s `lastCrash`.
+
+use backtrace::Backtrace;
+use failure::Error;
+use parking_lot::Mutex;
+use serde_json;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::thread;
+use time;
+
+/// The number of recent HTTP requests to include in each report.
+const MAX_REQUESTS: usize = 32;
+
+/// The number of reports to keep in the crash directory; older ones are removed at startup.
+const MAX_REPORTS: usize = 10;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Report {
+    pub time_sec: i64,
+    pub version: String,
+    pub thread: Option<String>,
+    pub message: String,
+    pub location: Option<String>,
+    pub backtrace: String,
+
+    /// The short names of streams being recorded, as in `camera-main`.
+    pub streams: Vec<String>,
+
+    /// The most recent HTTP requests, oldest first, as `<time_sec> <method> <path>`. Query
+    /// strings are omitted.
+    pub requests: Vec<String>,
+}
+
+/// The parts of a `Report` shown in `/api/`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Summary {
+    pub time_sec: i64,
+    pub version: String,
+    pub thread: Option<String>,
+    pub message: String,
+    pub location: Option<String>,
+
+    /// The full report's file name within the crash directory.
+    pub file: String,
+}
+
+struct State {
+    streams: Vec<String>,
+    requests: VecDeque<String>,
+    last: Option<Summary>,
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State {
+        streams: Vec::new(),
+        requests: VecDeque::with_capacity(MAX_REQUESTS),
+        last: None,
+    });
+}
+
+/// Prepares the crash directory, creating it if necessary and removing all but the most recent
+/// `MAX_REPORTS` reports. Notes the most recent for `last`.
+pub fn init(dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+    let mut names = Vec::new();
+    for e in fs::read_dir(dir)? {
+        let name = match e?.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        if name.starts_with("crash-") && name.ends_with(".json") {
+            names.push(name);
+        }
+    }
+
+    // Names contain a fixed-width time, so they sort chronologically.
+    names.sort_unstable();
+    let excess = names.len().saturating_sub(MAX_REPORTS);
+    for name in names.drain(..excess) {
+        if let Err(e) = fs::remove_file(dir.join(&name)) {
+            warn!("unable to remove old crash report {}: {}", name, e);
+        }
+    }
+    if let Some(name) = names.pop() {
+        let f = fs::File::open(dir.join(&name))?;
+        let r: Report = serde_json::from_reader(f)
+            .map_err(|e| format_err!("unable to parse crash report {}: {}", name, e))?;
+        warn!("Last crash at {}: {}", time::at(time::Timespec::new(r.time_sec, 0)).rfc3339(),
+              &r.message);
+        STATE.lock().last = Some(r.summary(name));
+    }
+    Ok(())
+}
+
+/// Installs the panic hook, which writes reports to `dir` after the default hook runs.
+pub fn install(dir: PathBuf) {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default(info);
+        let r = Report {
+            time_sec: time::get_time().sec,
+            version: ::version(),
+            thread: thread::current().name().map(str::to_owned),
+            message: message(info.payload()),
+            location: info.location().map(|l| format!("{}:{}", l.file(), l.line())),
+            backtrace: format!("{:?}", Backtrace::new()),
+            streams: Vec::new(),
+            requests: Vec::new(),
+        };
+        match write(&dir, r) {
+            Ok(name) => error!("Wrote crash report {}", dir.join(name).display()),
+            Err(e) => error!("Unable to write crash report: {}", e),
+        }
+    }));
+}
+
+/// Writes `r`, filling in its streams and requests.
+fn write(dir: &Path, mut r: Report) -> Result<String, Error> {
+    let name = format!("crash-{:012}-{}.json", r.time_sec, ::std::process::id());
+
+    // The panicking thread might hold the lock; don't deadlock on it.
+    let mut l = STATE.try_lock();
+    if let Some(ref l) = l {
+        r.streams = l.streams.clone();
+        r.requests = l.requests.iter().cloned().collect();
+    }
+    let mut f = fs::OpenOptions::new().write(true).create_new(true).open(dir.join(&name))?;
+    serde_json::to_writer_pretty(&mut f, &r)?;
+    f.write_all(b"\n")?;
+    f.sync_all()?;
+    if let Some(ref mut l) = l {
+        l.last = Some(r.summary(name.clone()));
+    }
+    Ok(name)
+}
+
+fn message(payload: &(Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        return (*s).to_owned();
+    }
+    if let Some(s) = payload.downcast_ref::<String>() {
+        return s.clone();
+    }
+    "Box<Any>".to_owned()
+}
+
+impl Report {
+    fn summary(&self, file: String) -> Summary {
+        Summary {
+            time_sec: self.time_sec,
+            version: self.version.clone(),
+            thread: self.thread.clone(),
+            message: self.message.clone(),
+            location: self.location.clone(),
+            file,
+        }
+    }
+}
+
+/// Sets the streams being recorded, by short name.
+pub fn set_streams(streams: Vec<String>) {
+    STATE.lock().streams = streams;
+}
+
+/// Notes an HTTP request, keeping the most recent `MAX_REQUESTS`.
+pub fn note_request(method: &::http::Method, path: &str) {
+    let r = format!("{} {} {}", time::get_time().sec, method, path);
+    let mut l = STATE.lock();
+    if l.requests.len() == MAX_REQUESTS {
+        l.requests.pop_front();
+    }
+    l.requests.push_back(r);
+}
+
+/// Returns a summary of the most recent crash, from a previous run or a panicked thread of this
+/// one.
+pub fn last() -> Option<Summary> {
+    STATE.lock().last.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use db;
+    use std::fs;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn write_and_init() {
+        db::testutil::init();
+        let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
+        for i in 0 .. MAX_REPORTS + 2 {
+            let r = Report {
+                time_sec: 1_500_000_000 + i as i64,
+                version: "0.1.0".to_owned(),
+                thread: Some("s-driveway-main".to_owned()),
+                message: format!("panic {}", i),
+                location: Some("src/streamer.rs:1".to_owned()),
+                backtrace: String::new(),
+                streams: Vec::new(),
+                requests: Vec::new(),
+            };
+            write(tmpdir.path(), r).unwrap();
+        }
+        init(tmpdir.path()).unwrap();
+        assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), MAX_REPORTS);
+        let l = last().unwrap();
+        assert_eq!(l.message, format!("panic {}", MAX_REPORTS + 1));
+        assert_eq!(l.time_sec, 1_500_000_000 + MAX_REPORTS as i64 + 1);
+    }
+}
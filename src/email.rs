@@ -0,0 +1,316 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Emailing of clips, as in `/api/cameras/<uuid>/<stream>/export/email` and for events.
+//! Clips are sent by `email` jobs; see `jobs`.
+//!
+//! Mail is handed to a local `sendmail`-compatible binary (as provided by Postfix, exim, msmtp,
+//! and the like) rather than spoken via SMTP directly, so relaying and authentication are
+//! configured there.
+
+use clips;
+use db::{self, recording};
+use db::dir::SampleFileDir;
+use failure::Error;
+use fnv::FnvHashMap;
+use futures::{Future, Stream};
+use http_serve::Entity;
+use export;
+use jobs;
+use l10n;
+use openssl::base64;
+use serde_json;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use web::StreamDirs;
+
+/// The MIME boundary between the text and attachment parts of a message.
+const BOUNDARY: &'static str = "moonfire-nvr-clip";
+
+/// The maximum number of events waiting for their recordings to be committed before their clips
+/// are emailed. Beyond this, the oldest are dropped.
+const MAX_PENDING_EVENTS: usize = 100;
+
+/// Returns `s` as a single-line header value, so that (say) a camera name can't inject headers.
+fn header_value(s: &str) -> String { s.replace(|c| c == '\r' || c == '\n', " ") }
+
+/// Configuration for sending clips by email.
+pub struct Mailer {
+    /// The `sendmail`-compatible binary, which must accept `-t -i`.
+    pub sendmail: PathBuf,
+    pub from: String,
+    pub to: Vec<String>,
+
+    /// Clips larger than this are sent as a link rather than an attachment.
+    pub max_attachment_bytes: u64,
+
+    /// The externally-visible base URL of this server, such as `https://nvr.example.com`, used
+    /// for links to clips too large to attach. If `None`, such clips can't be emailed.
+    pub base_url: Option<String>,
+
+    /// Messages are written in the catalog's default locale.
+    pub catalog: Arc<l10n::Catalog>,
+}
+
+impl Mailer {
+    /// Emails a clip of the given stream and time range, attaching it if small enough or linking
+    /// to it otherwise. Must be called without the database lock held.
+    pub fn send_clip(&self, db: &Arc<db::Database>,
+                     dirs_by_stream_id: &Arc<FnvHashMap<i32, Arc<SampleFileDir>>>,
+                     stream_id: i32, range: Range<recording::Time>, subject: &str)
+                     -> Result<(), Error> {
+        let (clip, mp4) = export::build(db, dirs_by_stream_id, stream_id, range.clone(), None)?;
+        let locale = self.catalog.default_locale();
+        let (start, end) = (range.start.to_string(), range.end.to_string());
+        let text = locale.format("{subject} from {start} to {end}.", &[
+            ("subject", subject),
+            ("start", start.as_str()),
+            ("end", end.as_str()),
+        ]) + "\n";
+        if mp4.len() <= self.max_attachment_bytes {
+            let data = mp4.get_range(0 .. mp4.len())
+                          .fold(Vec::with_capacity(mp4.len() as usize), |mut v, c| {
+                              v.extend_from_slice(::bytes::Buf::bytes(&c));
+                              Ok::<_, ::body::BoxedError>(v)
+                          })
+                          .wait()
+                          .map_err(|e| format_err!("unable to read clip: {}", e))?;
+            let name = format!("{}-{}.mp4", clip.camera_uuid, range.start.0);
+            return self.send(subject, &text, Some((&name, &data)));
+        }
+        let base_url = self.base_url.as_ref().ok_or_else(|| format_err!(
+            "clip is {} bytes, over the {}-byte attachment limit, and no base URL is configured",
+            mp4.len(), self.max_attachment_bytes))?;
+        let too_large = locale.format(
+            "The clip is too large to attach ({bytes} bytes). View it at:",
+            &[("bytes", mp4.len().to_string().as_str())]);
+        let text = format!("{}\n{}\n{}{}\n", text, too_large, base_url, clip.view_path());
+        self.send(subject, &text, None)
+    }
+
+    /// Sends a message to all recipients, with an optional `video/mp4` attachment.
+    fn send(&self, subject: &str, text: &str, attachment: Option<(&str, &[u8])>)
+            -> Result<(), Error> {
+        let msg = self.message(subject, text, attachment);
+        let mut child = Command::new(&self.sendmail)
+            .args(&["-t", "-i"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| format_err!("unable to run {}: {}", self.sendmail.display(), e))?;
+        child.stdin.take().expect("stdin is piped").write_all(&msg)?;
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("{} failed with {}", self.sendmail.display(), status);
+        }
+        Ok(())
+    }
+
+    fn message(&self, subject: &str, text: &str, attachment: Option<(&str, &[u8])>) -> Vec<u8> {
+        let mut m = format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n",
+                            header_value(&self.from), header_value(&self.to.join(", ")),
+                            header_value(subject));
+        match attachment {
+            None => {
+                m.push_str("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+                m.push_str(&text.replace('\n', "\r\n"));
+            },
+            Some((name, data)) => {
+                m.push_str(&format!("Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n\
+                                     --{}\r\n\
+                                     Content-Type: text/plain; charset=utf-8\r\n\r\n\
+                                     {}\r\n\
+                                     --{}\r\n\
+                                     Content-Type: video/mp4\r\n\
+                                     Content-Transfer-Encoding: base64\r\n\
+                                     Content-Disposition: attachment; filename=\"{}\"\r\n\r\n",
+                                    BOUNDARY, BOUNDARY, text.replace('\n', "\r\n"), BOUNDARY,
+                                    header_value(name).replace('"', "")));
+
+                // RFC 2045 section 6.8 limits encoded lines to 76 characters.
+                let encoded = base64::encode_block(data);
+                for line in encoded.as_bytes().chunks(76) {
+                    m.push_str(::std::str::from_utf8(line).expect("base64 is ASCII"));
+                    m.push_str("\r\n");
+                }
+                m.push_str(&format!("--{}--\r\n", BOUNDARY));
+            },
+        }
+        m.into_bytes()
+    }
+}
+
+/// Parameters of an `email` job.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Params {
+    pub stream_id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub subject: String,
+}
+
+/// Runs `email` jobs.
+pub struct Emailer {
+    pub db: Arc<db::Database>,
+    pub dirs: Arc<StreamDirs>,
+    pub mailer: Mailer,
+}
+
+impl jobs::Handler for Emailer {
+    fn run(&self, job: &db::Job, _cancel: &AtomicBool) -> Result<String, Error> {
+        let p: Params = serde_json::from_str(&job.params)?;
+        let range = recording::Time(p.start_time_90k) .. recording::Time(p.end_time_90k);
+        self.mailer.send_clip(&self.db, &self.dirs.get()?, p.stream_id, range, &p.subject)?;
+        Ok("{}".to_owned())
+    }
+}
+
+enum Message {
+    /// An event was added; its clip should be emailed once `complete`.
+    Event { params: Params, complete: bool },
+
+    /// Recordings were committed to the given stream, which now ends at the given time.
+    Committed { stream_id: i32, end: recording::Time },
+}
+
+/// Starts emailing a clip of each new event, from the main stream of its camera, via `email`
+/// jobs in the given queue. Each job is created once the event's recordings are committed, so
+/// the clip covers the whole event. Subjects are in the catalog's default locale.
+pub fn start(db: &db::Database, queue: Arc<jobs::Queue>, catalog: Arc<l10n::Catalog>)
+             -> Result<(), Error> {
+    // The watcher is called with the database lock held, so jobs can't be created directly.
+    let (tx, rx) = mpsc::channel();
+    db.lock().watch(Box::new(move |db, c| {
+        match *c {
+            db::Change::EventAdded { ref event, .. } => {
+                let time = clips::clip_range(&event.time);
+                let (stream_id, complete) = match clips::stream_for(db, event.camera_id, &time) {
+                    None => return,
+                    Some(s) => s,
+                };
+                let camera = &db.cameras_by_id()[&event.camera_id];
+                let _ = tx.send(Message::Event {
+                    params: Params {
+                        stream_id,
+                        start_time_90k: time.start.0,
+                        end_time_90k: time.end.0,
+                        subject: catalog.default_locale().format("{type} event on {camera}", &[
+                            ("type", event.type_.as_str()),
+                            ("camera", camera.short_name.as_str()),
+                        ]),
+                    },
+                    complete,
+                });
+            },
+            db::Change::RecordingsAdded { stream_id, .. } => {
+                let end = db.streams_by_id().get(&stream_id)
+                            .and_then(|s| s.range.as_ref().map(|r| r.end));
+                if let Some(end) = end {
+                    let _ = tx.send(Message::Committed { stream_id, end });
+                }
+            },
+            _ => {},
+        }
+    }));
+    thread::Builder::new()
+        .name("email".to_owned())
+        .spawn(move || {
+            // Events whose recordings are still being written, least recent first.
+            let mut pending: VecDeque<Params> = VecDeque::new();
+            for m in rx {
+                let ready = match m {
+                    Message::Event { params, complete: true } => vec![params],
+                    Message::Event { params, complete: false } => {
+                        if pending.len() >= MAX_PENDING_EVENTS {
+                            let p = pending.pop_front().expect("pending is non-empty");
+                            warn!("email: too many pending events; dropping {:?}", p.subject);
+                        }
+                        pending.push_back(params);
+                        continue;
+                    },
+                    Message::Committed { stream_id, end } => {
+                        let (ready, rest): (Vec<_>, VecDeque<_>) =
+                            pending.drain(..).partition(|p| p.stream_id == stream_id &&
+                                                            p.end_time_90k <= end.0);
+                        pending = rest;
+                        ready
+                    },
+                };
+                for p in ready {
+                    if let Err(e) = queue.create("email", &p) {
+                        warn!("email: unable to create job for {:?}: {}", p.subject, e);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use l10n;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use super::{Mailer, header_value};
+
+    #[test]
+    fn message() {
+        let m = Mailer {
+            sendmail: PathBuf::from("/usr/sbin/sendmail"),
+            from: "nvr@example.com".to_owned(),
+            to: vec!["a@example.com".to_owned(), "b@example.com".to_owned()],
+            max_attachment_bytes: 1 << 20,
+            base_url: None,
+            catalog: Arc::new(l10n::Catalog::english()),
+        };
+        let msg = String::from_utf8(m.message("subj", "hi\n", None)).unwrap();
+        assert_eq!(msg, "From: nvr@example.com\r\nTo: a@example.com, b@example.com\r\n\
+                         Subject: subj\r\nMIME-Version: 1.0\r\n\
+                         Content-Type: text/plain; charset=utf-8\r\n\r\nhi\r\n");
+
+        let msg = String::from_utf8(m.message("subj", "hi\n", Some(("a.mp4", &[0u8; 60][..]))))
+            .unwrap();
+        assert!(msg.contains("filename=\"a.mp4\"\r\n\r\n"));
+        // 60 bytes encode to 80 characters, which must be split across two lines.
+        assert!(msg.contains(&format!("\r\n{}\r\n{}\r\n--moonfire-nvr-clip--\r\n",
+                                      "A".repeat(76), "A".repeat(4))));
+
+        // Line breaks in header values can't start new headers.
+        assert_eq!(header_value("a\r\nBcc: x@example.com"), "a  Bcc: x@example.com");
+        let msg = String::from_utf8(m.message("a\nBcc: x@example.com", "hi\n", None)).unwrap();
+        assert!(msg.contains("\r\nSubject: a Bcc: x@example.com\r\n"));
+    }
+}
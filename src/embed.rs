@@ -0,0 +1,210 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Public, read-only embedding of a clip, as `/embed/<token>`: a minimal player page which can
+//! be placed in an `<iframe>` on (for example) a neighborhood watch page. Each token names a
+//! single stream and time range and is signed with a server-side key, so holders can't alter it
+//! to see other footage. Tokens expire but can't otherwise be revoked, short of changing the key.
+
+use base::strutil;
+use db::{self, recording};
+use failure::Error;
+use openssl::{hash, memcmp, pkey, sign};
+use request;
+use std::fs;
+use std::ops::Range;
+use uuid::Uuid;
+
+/// The minimum length of a signing key, in bytes.
+const MIN_KEY_LEN: usize = 32;
+
+/// How long a token is valid by default: 30 days.
+pub const DEFAULT_EXPIRATION_SEC: i64 = 30 * 24 * 60 * 60;
+
+/// What a token grants access to.
+#[derive(Debug, PartialEq)]
+pub struct Grant {
+    pub camera_uuid: Uuid,
+    pub stream_type: db::StreamType,
+    pub time: Range<recording::Time>,
+    pub expires_sec: i64,
+}
+
+impl Grant {
+    /// Returns the signed portion of the token: the fields, separated by `.`.
+    fn payload(&self) -> String {
+        format!("{}.{}.{}.{}.{}", self.camera_uuid, self.stream_type.as_str(), self.time.start.0,
+                self.time.end.0, self.expires_sec)
+    }
+
+    fn parse(payload: &str) -> Result<Self, Error> {
+        let f: Vec<&str> = payload.split('.').collect();
+        if f.len() != 5 {
+            bail!("malformed token");
+        }
+        let start = recording::Time(request::parse_decimal(f[2])?);
+        let end = recording::Time(request::parse_decimal(f[3])?);
+        if end <= start {
+            bail!("malformed token");
+        }
+        Ok(Grant {
+            camera_uuid: request::parse_uuid(f[0])?,
+            stream_type: db::StreamType::parse(f[1]).ok_or_else(|| format_err!("bad stream"))?,
+            time: start .. end,
+            expires_sec: request::parse_decimal(f[4])?,
+        })
+    }
+}
+
+/// Signs and verifies tokens with HMAC-SHA256.
+pub struct Signer {
+    key: pkey::PKey<pkey::Private>,
+}
+
+impl Signer {
+    /// Loads a key from the given file, such as one created via
+    /// `openssl rand -hex 32 > embed.key`. The file's (whitespace-trimmed) contents are used
+    /// as-is.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let secret = fs::read(path).map_err(|e| format_err!("unable to read {}: {}", path, e))?;
+        Signer::new(String::from_utf8_lossy(&secret).trim().as_bytes())
+    }
+
+    fn new(secret: &[u8]) -> Result<Self, Error> {
+        if secret.len() < MIN_KEY_LEN {
+            bail!("embed key must be at least {} bytes; is {}", MIN_KEY_LEN, secret.len());
+        }
+        Ok(Signer { key: pkey::PKey::hmac(secret)? })
+    }
+
+    fn mac(&self, payload: &str) -> Result<String, Error> {
+        let mut s = sign::Signer::new(hash::MessageDigest::sha256(), &self.key)?;
+        s.update(payload.as_bytes())?;
+        Ok(strutil::hex(&s.sign_to_vec()?))
+    }
+
+    /// Returns a token for the given grant. It contains only URL-safe characters.
+    pub fn sign(&self, g: &Grant) -> Result<String, Error> {
+        let payload = g.payload();
+        let mac = self.mac(&payload)?;
+        Ok(format!("{}.{}", payload, mac))
+    }
+
+    /// Verifies the given token, returning its grant if it's authentic and unexpired.
+    pub fn verify(&self, token: &str, now_sec: i64) -> Result<Grant, Error> {
+        let dot = token.rfind('.').ok_or_else(|| format_err!("malformed token"))?;
+        let (payload, mac) = (&token[.. dot], &token[dot + 1 ..]);
+        let expected = self.mac(payload)?;
+        if mac.len() != expected.len() || !memcmp::eq(mac.as_bytes(), expected.as_bytes()) {
+            bail!("bad token signature");
+        }
+        let g = Grant::parse(payload)?;
+        if g.expires_sec <= now_sec {
+            bail!("token expired at {}", g.expires_sec);
+        }
+        Ok(g)
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Returns the player page for `/embed/<token>`. It has no external resources, so it works
+/// regardless of `--ui-dir`.
+pub fn page(title: &str, token: &str) -> String {
+    // The page's URL has no trailing slash, so this relative URL resolves to
+    // `/embed/<token>/video.mp4`.
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+html, body {{ margin: 0; height: 100%; background: #000; }}
+video {{ display: block; width: 100%; height: 100%; object-fit: contain; }}
+</style>
+</head>
+<body>
+<video src="{token}/video.mp4" controls playsinline preload="metadata"></video>
+</body>
+</html>
+"#, title = html_escape(title), token = html_escape(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use db::{self, recording};
+    use super::*;
+    use uuid::Uuid;
+
+    fn grant() -> Grant {
+        Grant {
+            camera_uuid: Uuid::parse_str("fd20f7a2-9d69-4cb3-94ed-d51a20c3edfe").unwrap(),
+            stream_type: db::StreamType::MAIN,
+            time: recording::Time(130985461191810) .. recording::Time(130985466591817),
+            expires_sec: 1_500_000_000,
+        }
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let s = Signer::new(&[b'k'; 32]).unwrap();
+        let t = s.sign(&grant()).unwrap();
+        assert!(t.starts_with("fd20f7a2-9d69-4cb3-94ed-d51a20c3edfe.main.130985461191810."));
+        assert_eq!(s.verify(&t, 1_400_000_000).unwrap(), grant());
+
+        // Expired.
+        assert!(s.verify(&t, 1_500_000_000).is_err());
+
+        // Altered.
+        let altered = t.replace(".main.", ".sub.");
+        assert!(s.verify(&altered, 1_400_000_000).is_err());
+        assert!(s.verify(&t[.. t.len() - 1], 1_400_000_000).is_err());
+        assert!(s.verify("", 1_400_000_000).is_err());
+
+        // Signed with another key.
+        let other = Signer::new(&[b'j'; 32]).unwrap();
+        assert!(other.verify(&t, 1_400_000_000).is_err());
+    }
+
+    #[test]
+    fn test_short_key() {
+        assert!(Signer::new(&[b'k'; 31]).is_err());
+    }
+
+    #[test]
+    fn test_page() {
+        let p = page("front <door>", "a.b");
+        assert!(p.contains("<title>front &lt;door&gt;</title>"));
+        assert!(p.contains(r#"src="a.b/video.mp4""#));
+    }
+}
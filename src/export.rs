@@ -0,0 +1,727 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Clip exports: building `.mp4` files of a time range, either on demand (as for email) or as
+//! background jobs spooled to disk (as in `/api/export`). See `design/api.md` for details.
+//!
+//! Spooled exports avoid the browser timing out on a long synchronous build and allow resumable
+//! downloads of multi-gigabyte files.
+//!
+//! `view.mp4` can only start a clip on a key frame; it uses an edit list to hide the frames before
+//! the requested start, which some players ignore. A "precise" export instead re-encodes the
+//! first partial GOP with an external `ffmpeg` binary and stream-copies the rest, so the file
+//! itself starts at exactly the requested time. Only the re-encoded frames lose quality.
+//!
+//! Exports may also be scaled down or remuxed to Matroska, typically as required by an export
+//! preset (see `db::ExportPreset`). Each of these steps is a separate ffmpeg pass over the file.
+
+use db::{self, recording};
+use db::dir::SampleFileDir;
+use failure::Error;
+use fnv::FnvHashMap;
+use futures::Stream;
+use http_serve::Entity;
+use jobs;
+use mp4;
+use request::Segments;
+use serde_json;
+use std::fs;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use uuid::Uuid;
+use web::StreamDirs;
+
+/// A clip of a single stream, as a list of recordings and the portion of each to include.
+pub struct Clip {
+    pub camera_uuid: Uuid,
+    pub stream_type: db::StreamType,
+
+    /// The recording id, its full duration, and the included portion, for each recording.
+    segments: Vec<(i32, i32, Range<i32>)>,
+}
+
+impl Clip {
+    /// Finds the recordings of the given stream and time range, appending each to `builder`.
+    pub fn find(db: &db::LockedDatabase, stream_id: i32, range: Range<recording::Time>,
+                builder: &mut mp4::FileBuilder) -> Result<Self, Error> {
+        let s = db.streams_by_id().get(&stream_id)
+                  .ok_or_else(|| format_err!("no such stream {}", stream_id))?;
+        let mut segments = Vec::new();
+        db.list_recordings_by_time(stream_id, range.clone(), &mut |r| {
+            let start = ::std::cmp::max(0, (range.start - r.start).0) as i32;
+            let end = ::std::cmp::min(r.duration_90k as i64, (range.end - r.start).0) as i32;
+            if start < end {
+                segments.push((r.id.recording(), r.duration_90k, start .. end));
+                builder.append(db, r, start .. end)?;
+            }
+            Ok(())
+        })?;
+        Ok(Clip {
+            camera_uuid: db.cameras_by_id()[&s.camera_id].uuid,
+            stream_type: s.type_,
+            segments,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool { self.segments.is_empty() }
+
+    /// Returns the path and query of the equivalent `view.mp4` URL.
+    pub fn view_path(&self) -> String {
+        let first = &self.segments[0];
+        let last = &self.segments[self.segments.len() - 1];
+        let mut end = 0;
+        for &(_, duration_90k, _) in &self.segments[.. self.segments.len() - 1] {
+            end += duration_90k as i64;
+        }
+        end += last.2.end as i64;
+        let s = Segments {
+            ids: first.0 .. last.0 + 1,
+            open_id: None,
+            start_time: first.2.start as i64,
+            end_time: Some(end),
+        };
+        format!("/api/cameras/{}/{}/view.mp4?s={}", self.camera_uuid, self.stream_type.as_str(),
+                s)
+    }
+}
+
+/// How long `build` waits for room in the memory budget. Most of its callers are background
+/// jobs, which are better delayed than failed.
+const MEMORY_WAIT: Duration = Duration::from_secs(60);
+
+/// Builds a clip of the given stream and time range, optionally watermarked with the given text
+/// (see `mp4::FileBuilder::watermark`). Must be called without the database lock held.
+pub fn build(db: &Arc<db::Database>,
+             dirs_by_stream_id: &Arc<FnvHashMap<i32, Arc<SampleFileDir>>>,
+             stream_id: i32, range: Range<recording::Time>, watermark: Option<String>)
+             -> Result<(Clip, mp4::File), Error> {
+    build_with_timestamps(db, dirs_by_stream_id, stream_id, range, watermark, false)
+}
+
+/// As `build`, optionally including the timestamp subtitle track even without a watermark.
+fn build_with_timestamps(db: &Arc<db::Database>,
+                         dirs_by_stream_id: &Arc<FnvHashMap<i32, Arc<SampleFileDir>>>,
+                         stream_id: i32, range: Range<recording::Time>,
+                         watermark: Option<String>, timestamps: bool)
+                         -> Result<(Clip, mp4::File), Error> {
+    let mut builder = mp4::FileBuilder::new(mp4::Type::Normal);
+    builder.memory_wait(MEMORY_WAIT);
+    builder.include_timestamp_subtitle_track(timestamps);
+    if let Some(w) = watermark {
+        builder.watermark(w);
+    }
+    let clip = {
+        let l = db.lock();
+        let clip = Clip::find(&l, stream_id, range.clone(), &mut builder)?;
+        builder.append_origin(&l)?;
+        clip
+    };
+    if clip.is_empty() {
+        bail!("no recordings for stream {} in {}-{}", stream_id, range.start, range.end);
+    }
+    let mp4 = builder.build(db.clone(), dirs_by_stream_id.clone())?;
+    Ok((clip, mp4))
+}
+
+/// Parameters of an `export` job.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Params {
+    pub stream_id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+
+    /// Text identifying who requested the export and when, as described in `design/api.md`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<String>,
+
+    /// If true, re-encode the first partial GOP so the file starts exactly at `start_time_90k`.
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub precise: bool,
+
+    /// Regions to obscure, which requires re-encoding the whole clip.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redactions: Vec<Redaction>,
+
+    /// How to obscure `redactions`; defaults to `RedactionStyle::Blur`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redaction_style: Option<RedactionStyle>,
+
+    /// The name of the export preset which supplied some of these parameters, for the record.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+
+    /// If true, include the timestamp subtitle track even without a watermark.
+    #[serde(default, skip_serializing_if = "::std::ops::Not::not")]
+    pub timestamps: bool,
+
+    /// If present, video taller than this is scaled down, which requires re-encoding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<i32>,
+
+    /// The container, as in `db::ExportContainer::as_str`. Absent means `.mp4`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+}
+
+impl Params {
+    pub fn container(&self) -> Result<db::ExportContainer, Error> {
+        match self.container {
+            None => Ok(db::ExportContainer::Mp4),
+            Some(ref c) => db::ExportContainer::parse(c).ok_or_else(
+                || format_err!("unknown export container {:?}", c)),
+        }
+    }
+}
+
+/// The maximum number of redactions in an export.
+pub const MAX_REDACTIONS: usize = 16;
+
+/// A region of the frame to obscure in an export, as fractions of the frame's width and height
+/// (as with `db::Detection`), optionally only within a time range.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Redaction {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time_90k: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_time_90k: Option<i64>,
+}
+
+impl Redaction {
+    /// Parses a `redact` request parameter: `x,y,width,height`, optionally followed by
+    /// `,startTime90k,endTime90k` in any format accepted by `recording::Time::parse`.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let parts: Vec<&str> = s.split(',').collect();
+        if parts.len() != 4 && parts.len() != 6 {
+            bail!("redaction {:?} should be x,y,width,height[,start,end]", s);
+        }
+        let mut f = [0.; 4];
+        for (f, p) in f.iter_mut().zip(&parts) {
+            *f = p.parse().map_err(|_| format_err!("invalid redaction coordinate {:?}", p))?;
+        }
+        let (x, y, width, height) = (f[0], f[1], f[2], f[3]);
+        if !(x >= 0. && y >= 0. && width > 0. && height > 0. && x + width <= 1. &&
+             y + height <= 1.) {
+            bail!("redaction {:?} must be within the frame", s);
+        }
+        let (start_time_90k, end_time_90k) = if parts.len() == 6 {
+            let (start, end) = (recording::Time::parse(parts[4])?,
+                                recording::Time::parse(parts[5])?);
+            if start >= end {
+                bail!("redaction {:?} has an empty time range", s);
+            }
+            (Some(start.0), Some(end.0))
+        } else {
+            (None, None)
+        };
+        Ok(Redaction { x, y, width, height, start_time_90k, end_time_90k })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all="camelCase")]
+pub enum RedactionStyle {
+    /// A heavy box blur, which leaves the region's rough colors.
+    Blur,
+
+    /// A solid black box.
+    Black,
+}
+
+impl RedactionStyle {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "blur" => Some(RedactionStyle::Blur),
+            "black" => Some(RedactionStyle::Black),
+            _ => None,
+        }
+    }
+}
+
+/// Returns an ffmpeg `-filter_complex` graph which applies `redactions` to input `0:v` in the
+/// given style, and its output label. `clip_start_90k` is the time of the clip's first frame, from
+/// which filter times are measured.
+fn redaction_filter(redactions: &[Redaction], style: RedactionStyle, clip_start_90k: i64)
+                    -> (String, String) {
+    let mut graph = String::new();
+    let mut prev = "0:v".to_owned();
+    for (i, r) in redactions.iter().enumerate() {
+        let enable = match (r.start_time_90k, r.end_time_90k) {
+            (Some(s), Some(e)) => {
+                let sec = |t: i64| {
+                    (t - clip_start_90k) as f64 / recording::TIME_UNITS_PER_SEC as f64
+                };
+                format!(":enable='between(t,{:.3},{:.3})'", sec(s), sec(e))
+            },
+            _ => String::new(),
+        };
+        if i > 0 {
+            graph.push(';');
+        }
+        match style {
+            RedactionStyle::Blur => graph.push_str(&format!(
+                "[{p}]split[m{i}][c{i}];\
+                 [c{i}]crop=w=iw*{w}:h=ih*{h}:x=iw*{x}:y=ih*{y},\
+                 boxblur=luma_radius='min(w,h)/5':luma_power=3[b{i}];\
+                 [m{i}][b{i}]overlay=x=main_w*{x}:y=main_h*{y}{e}[v{i}]",
+                p=prev, i=i, x=r.x, y=r.y, w=r.width, h=r.height, e=enable)),
+            RedactionStyle::Black => graph.push_str(&format!(
+                "[{p}]drawbox=x=iw*{x}:y=ih*{y}:w=iw*{w}:h=ih*{h}:color=black:t=fill{e}[v{i}]",
+                p=prev, i=i, x=r.x, y=r.y, w=r.width, h=r.height, e=enable)),
+        }
+        prev = format!("v{}", i);
+    }
+    (graph, format!("[{}]", prev))
+}
+
+/// The result of a successful `export` job.
+#[derive(Debug, Serialize)]
+struct Output {
+    bytes: u64,
+}
+
+/// Runs `export` jobs, spooling the results to a directory.
+pub struct Exporter {
+    db: Arc<db::Database>,
+    dirs: Arc<StreamDirs>,
+    dir: PathBuf,
+
+    /// The `ffmpeg` binary used for precise exports, or `None` if they're not supported.
+    ffmpeg: Option<PathBuf>,
+}
+
+/// Temporary files of an export, removed when dropped.
+struct TempFiles(Vec<PathBuf>);
+
+impl TempFiles {
+    fn add(&mut self, p: PathBuf) -> PathBuf {
+        self.0.push(p.clone());
+        p
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for p in &self.0 {
+            let _ = fs::remove_file(p);
+        }
+    }
+}
+
+/// Returns the start of the first key frame after `t` within the recording of the given stream
+/// which contains `t`, or the end of that recording if there is none. Returns `None` if `t`
+/// isn't within a recording or is exactly the start of a key frame, so no re-encoding is needed.
+fn next_key_frame(db: &db::LockedDatabase, stream_id: i32, t: recording::Time)
+                  -> Result<Option<recording::Time>, Error> {
+    let mut row = None;
+    db.list_recordings_by_time(stream_id, t .. t + recording::Duration(1), &mut |r| {
+        if r.start <= t && t < r.start + recording::Duration(r.duration_90k as i64) {
+            row = Some((r.id, r.start, r.duration_90k));
+        }
+        Ok(())
+    })?;
+    let (id, start, duration_90k) = match row {
+        None => return Ok(None),
+        Some(r) => r,
+    };
+    let rel = (t - start).0 as i32;
+    let k = db.with_recording_playback(id, &mut |playback| {
+        let data = &(&playback).video_index;
+        let mut it = recording::SampleIndexIterator::new();
+        while it.next(data)? {
+            if it.start_90k == rel && it.is_key() {
+                return Ok(None);
+            }
+            if it.start_90k > rel && it.is_key() {
+                return Ok(Some(it.start_90k));
+            }
+        }
+        Ok(Some(duration_90k))
+    })?;
+    Ok(k.map(|k| start + recording::Duration(k as i64)))
+}
+
+/// Returns an ffmpeg `-vf` filter which scales video taller than `max_height` down to it,
+/// preserving the aspect ratio with an even width as libx264 requires.
+fn scale_filter(max_height: i32) -> String {
+    format!("scale=-2:'min(ih,{})'", max_height)
+}
+
+/// Returns an ffmpeg command with the arguments common to all invocations.
+fn ffmpeg_command(ffmpeg: &Path) -> Command {
+    let mut c = Command::new(ffmpeg);
+    c.args(&["-nostdin", "-loglevel", "error", "-y"]);
+    c
+}
+
+/// Runs the given ffmpeg command, returning an error with its output on failure.
+fn run_ffmpeg(c: &mut Command) -> Result<(), Error> {
+    let out = c.output().map_err(|e| format_err!("unable to run ffmpeg: {}", e))?;
+    if !out.status.success() {
+        bail!("ffmpeg failed with {}: {}", out.status, String::from_utf8_lossy(&out.stderr).trim());
+    }
+    Ok(())
+}
+
+impl Exporter {
+    /// Creates the exporter, removing any partial files left in `dir` by a previous run.
+    /// Precise exports are supported only if `ffmpeg` is given.
+    pub fn new(db: Arc<db::Database>, dirs: Arc<StreamDirs>, dir: PathBuf,
+               ffmpeg: Option<PathBuf>) -> Result<Self, Error> {
+        fs::create_dir_all(&dir)?;
+        for e in fs::read_dir(&dir)? {
+            let e = e?;
+            if e.file_name().to_str().map(|n| n.ends_with(".tmp")).unwrap_or(false) {
+                fs::remove_file(e.path())?;
+            }
+        }
+        Ok(Exporter { db, dirs, dir, ffmpeg })
+    }
+
+    /// Returns true if exports which require re-encoding or remuxing (see `Params::precise`,
+    /// `Params::redactions`, `Params::max_height`, and `Params::container`) are supported.
+    pub fn supports_reencoding(&self) -> bool { self.ffmpeg.is_some() }
+
+    /// Returns the path of the file produced by the given job.
+    pub fn path(&self, uuid: Uuid, container: db::ExportContainer) -> PathBuf {
+        self.dir.join(format!("{}.{}", uuid, container.as_str()))
+    }
+
+    /// Builds the given job's file, writing it to a temporary name and renaming when complete.
+    fn write(&self, uuid: Uuid, p: &Params, cancel: &AtomicBool, tmp: &PathBuf)
+             -> Result<u64, Error> {
+        let range = recording::Time(p.start_time_90k) .. recording::Time(p.end_time_90k);
+        let dirs = self.dirs.get()?;
+        let container = p.container()?;
+        let (_, mp4) = build_with_timestamps(&self.db, &dirs, p.stream_id, range.clone(),
+                                             p.watermark.clone(), p.timestamps)?;
+        write_file(&mp4, cancel, tmp)?;
+        let k = if p.precise { next_key_frame(&self.db.lock(), p.stream_id, range.start)? }
+                else { None };
+        let bytes = match k {
+            None => mp4.len(),
+            Some(k) => {
+                let out = self.dir.join(format!("{}.precise.tmp", uuid));
+                let mut temps = TempFiles(vec![out.clone()]);
+                self.splice(uuid, p, k, cancel, tmp, &out, &mut temps)?;
+                fs::rename(&out, tmp)?;
+                fs::metadata(tmp)?.len()
+            },
+        };
+        let bytes = if p.redactions.is_empty() { bytes } else {
+            if cancel.load(Ordering::SeqCst) {
+                bail!("cancelled");
+            }
+            let out = self.dir.join(format!("{}.redacted.tmp", uuid));
+            let _temps = TempFiles(vec![out.clone()]);
+            self.redact(p, tmp, &out)?;
+            fs::rename(&out, tmp)?;
+            fs::metadata(tmp)?.len()
+        };
+        let converting = p.max_height.is_some() || container != db::ExportContainer::Mp4;
+        let bytes = if !converting { bytes } else {
+            if cancel.load(Ordering::SeqCst) {
+                bail!("cancelled");
+            }
+            let out = self.dir.join(format!("{}.converted.tmp", uuid));
+            let _temps = TempFiles(vec![out.clone()]);
+            self.convert(p, container, tmp, &out)?;
+            fs::rename(&out, tmp)?;
+            fs::metadata(tmp)?.len()
+        };
+        fs::rename(tmp, self.path(uuid, container))?;
+        Ok(bytes)
+    }
+
+    /// Writes to `out` a copy of the clip at `full` scaled down to `p.max_height` (if any) and in
+    /// the given container. The video track is re-encoded only when scaling. Matroska files get
+    /// the subtitle track (if any) as SRT, which more players support there than `mov_text`.
+    fn convert(&self, p: &Params, container: db::ExportContainer, full: &Path, out: &Path)
+               -> Result<(), Error> {
+        let ffmpeg = match self.ffmpeg {
+            None => bail!("scaled and Matroska exports are not enabled on this server"),
+            Some(ref f) => f,
+        };
+        let mut c = ffmpeg_command(ffmpeg);
+        c.args(&["-f", "mp4", "-i"]).arg(full)
+         .args(&["-map", "0:v", "-map", "0:s?", "-map_metadata", "0"]);
+        match p.max_height {
+            None => { c.args(&["-c:v", "copy"]); },
+            Some(h) => {
+                c.args(&["-vf", &scale_filter(h)[..], "-c:v", "libx264", "-preset", "veryfast",
+                         "-crf", "18"]);
+            },
+        }
+        match container {
+            db::ExportContainer::Mp4 => {
+                c.args(&["-c:s", "copy", "-movflags", "+faststart+use_metadata_tags",
+                         "-f", "mp4"]);
+            },
+            db::ExportContainer::Matroska => { c.args(&["-c:s", "srt", "-f", "matroska"]); },
+        }
+        run_ffmpeg(c.arg(out))
+    }
+
+    /// Writes to `out` a copy of the clip at `full` with `p.redactions` obscured. The whole video
+    /// track is re-encoded; the subtitle track (if any) and metadata are copied.
+    fn redact(&self, p: &Params, full: &Path, out: &Path) -> Result<(), Error> {
+        let ffmpeg = match self.ffmpeg {
+            None => bail!("redaction is not enabled on this server"),
+            Some(ref f) => f,
+        };
+        let style = p.redaction_style.unwrap_or(RedactionStyle::Blur);
+        let (graph, label) = redaction_filter(&p.redactions, style, p.start_time_90k);
+        run_ffmpeg(ffmpeg_command(ffmpeg)
+                   .args(&["-f", "mp4", "-i"]).arg(full)
+                   .args(&["-filter_complex", &graph[..], "-map", &label[..], "-map", "0:s?",
+                           "-map_metadata", "0", "-c:v", "libx264", "-preset", "veryfast",
+                           "-crf", "18", "-c:s", "copy",
+                           "-movflags", "+faststart+use_metadata_tags", "-f", "mp4"]).arg(out))
+    }
+
+    /// Writes to `out` a precise version of the clip written to `full`, given that its first key
+    /// frame after the start is at `k`.
+    ///
+    /// The partial GOP `start .. k` is built as a `.mp4` with an edit list hiding the
+    /// leading frames, which ffmpeg honors when re-encoding it. The concat demuxer then joins the
+    /// re-encoded part with the untouched rest via MPEG-TS, which keeps each part's parameter sets
+    /// in-band, and the result is remuxed with `full`'s subtitle track (if any), metadata (including
+    /// the origin written by `build`), and chapters.
+    fn splice(&self, uuid: Uuid, p: &Params, k: recording::Time, cancel: &AtomicBool,
+              full: &Path, out: &Path, temps: &mut TempFiles) -> Result<(), Error> {
+        let ffmpeg = match self.ffmpeg {
+            None => bail!("precise exports are not enabled on this server"),
+            Some(ref f) => f,
+        };
+        let range = recording::Time(p.start_time_90k) .. recording::Time(p.end_time_90k);
+        let dirs = self.dirs.get()?;
+        let k = ::std::cmp::min(k, range.end);
+        let head = temps.add(self.dir.join(format!("{}.head.tmp", uuid)));
+        let (_, mp4) = build(&self.db, &dirs, p.stream_id, range.start .. k, None)?;
+        write_file(&mp4, cancel, &head)?;
+        let encoded = temps.add(self.dir.join(format!("{}.encoded.tmp", uuid)));
+        run_ffmpeg(ffmpeg_command(ffmpeg)
+                   .args(&["-f", "mp4", "-i"]).arg(&head)
+                   .args(&["-map", "0:v", "-vsync", "passthrough", "-c:v", "libx264",
+                           "-preset", "veryfast", "-crf", "18", "-f", "mp4"]).arg(&encoded))?;
+        let mut list = format!("file '{}'\n", encoded.display());
+        if k < range.end {
+            let tail = temps.add(self.dir.join(format!("{}.tail.tmp", uuid)));
+            let (_, mp4) = build(&self.db, &dirs, p.stream_id, k .. range.end, None)?;
+            write_file(&mp4, cancel, &tail)?;
+            list.push_str(&format!("file '{}'\n", tail.display()));
+        }
+        if cancel.load(Ordering::SeqCst) {
+            bail!("cancelled");
+        }
+        let list_path = temps.add(self.dir.join(format!("{}.list.tmp", uuid)));
+        fs::write(&list_path, list)?;
+        let joined = temps.add(self.dir.join(format!("{}.ts.tmp", uuid)));
+        run_ffmpeg(ffmpeg_command(ffmpeg)
+                   .args(&["-f", "concat", "-safe", "0", "-i"]).arg(&list_path)
+                   .args(&["-c", "copy", "-f", "mpegts"]).arg(&joined))?;
+        run_ffmpeg(ffmpeg_command(ffmpeg)
+                   .args(&["-f", "mpegts", "-i"]).arg(&joined)
+                   .args(&["-f", "mp4", "-i"]).arg(full)
+                   .args(&["-map", "0:v", "-map", "1:s?", "-map_metadata", "1",
+                           "-map_chapters", "1", "-c", "copy",
+                           "-movflags", "+faststart+use_metadata_tags", "-f", "mp4"]).arg(out))
+    }
+}
+
+/// Writes the given `.mp4` to `path`, checking for cancellation between chunks.
+fn write_file(mp4: &mp4::File, cancel: &AtomicBool, path: &Path) -> Result<(), Error> {
+    let mut f = fs::File::create(path)?;
+    for c in mp4.get_range(0 .. mp4.len()).wait() {
+        if cancel.load(Ordering::SeqCst) {
+            bail!("cancelled");
+        }
+        let c = c.map_err(|e| format_err!("unable to read clip: {}", e))?;
+        f.write_all(::bytes::Buf::bytes(&c))?;
+    }
+    f.sync_all()?;
+    Ok(())
+}
+
+impl jobs::Handler for Exporter {
+    fn run(&self, job: &db::Job, cancel: &AtomicBool) -> Result<String, Error> {
+        let p: Params = serde_json::from_str(&job.params)?;
+        let tmp = self.dir.join(format!("{}.tmp", job.uuid));
+        match self.write(job.uuid, &p, cancel, &tmp) {
+            Ok(bytes) => Ok(serde_json::to_string(&Output { bytes })?),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                Err(e)
+            },
+        }
+    }
+
+    fn cleanup(&self, job: &db::Job) {
+        let container = serde_json::from_str::<Params>(&job.params).ok()
+                                   .and_then(|p| p.container().ok())
+                                   .unwrap_or(db::ExportContainer::Mp4);
+        if let Err(e) = fs::remove_file(self.path(job.uuid, container)) {
+            if e.kind() != ::std::io::ErrorKind::NotFound {
+                warn!("export: unable to remove {}: {}", job.uuid, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clock::RealClocks;
+    use db::{self, recording};
+    use db::testutil::{self, TestDb, TEST_STREAM_ID};
+    use mp4;
+    use serde_json;
+    use std::fs;
+    use std::process::Command;
+    use std::sync::atomic::AtomicBool;
+    use super::{Clip, Exporter, Params, Redaction, RedactionStyle};
+    use tempdir::TempDir;
+    use uuid::Uuid;
+    use web::StreamDirs;
+
+    #[test]
+    fn params() {
+        // Jobs created before precise exports existed have no such field.
+        let p: Params = serde_json::from_str(
+            r#"{"streamId": 1, "startTime90k": 90000, "endTime90k": 180000}"#).unwrap();
+        assert!(!p.precise);
+        assert_eq!(serde_json::to_string(&p).unwrap(),
+                   r#"{"streamId":1,"startTime90k":90000,"endTime90k":180000}"#);
+        let p: Params = serde_json::from_str(
+            r#"{"streamId": 1, "startTime90k": 90000, "endTime90k": 180000, "precise": true}"#)
+            .unwrap();
+        assert!(p.precise);
+        assert_eq!(p.container().unwrap(), db::ExportContainer::Mp4);
+        let p: Params = serde_json::from_str(
+            r#"{"streamId": 1, "startTime90k": 90000, "endTime90k": 180000, "preset": "external",
+                "timestamps": true, "maxHeight": 720, "container": "mkv"}"#).unwrap();
+        assert_eq!(p.container().unwrap(), db::ExportContainer::Matroska);
+        assert_eq!(super::scale_filter(p.max_height.unwrap()), "scale=-2:'min(ih,720)'");
+    }
+
+    #[test]
+    fn redaction() {
+        let r = Redaction::parse("0.25,0,0.5,0.5").unwrap();
+        assert_eq!(r, Redaction { x: 0.25, y: 0., width: 0.5, height: 0.5,
+                                  start_time_90k: None, end_time_90k: None });
+        let r2 = Redaction::parse("0,0.5,0.1,0.5,180000,270000").unwrap();
+        assert_eq!((r2.start_time_90k, r2.end_time_90k), (Some(180000), Some(270000)));
+        Redaction::parse("0.75,0,0.5,0.5").unwrap_err();  // extends past the right edge.
+        Redaction::parse("0,0,0.5").unwrap_err();
+        Redaction::parse("0,0,0.5,0.5,270000,180000").unwrap_err();
+
+        let (graph, label) = super::redaction_filter(&[r.clone(), r2.clone()],
+                                                     RedactionStyle::Black, 90000);
+        assert_eq!(graph, "[0:v]drawbox=x=iw*0.25:y=ih*0:w=iw*0.5:h=ih*0.5:color=black:t=fill\
+                           [v0];\
+                           [v0]drawbox=x=iw*0:y=ih*0.5:w=iw*0.1:h=ih*0.5:color=black:t=fill\
+                           :enable='between(t,1.000,2.000)'[v1]");
+        assert_eq!(label, "[v1]");
+        let (graph, label) = super::redaction_filter(&[r], RedactionStyle::Blur, 90000);
+        assert!(graph.starts_with("[0:v]split[m0][c0];[c0]crop=w=iw*0.5:h=ih*0.5:x=iw*0.25:"));
+        assert_eq!(label, "[v0]");
+    }
+
+    #[test]
+    fn splice_keeps_origin() {
+        testutil::init();
+        if Command::new("ffmpeg").arg("-version").output().is_err() {
+            warn!("skipping splice_keeps_origin: no ffmpeg binary");
+            return;
+        }
+        let tdb = TestDb::new(RealClocks {});
+        mp4::tests::copy_mp4_to_db(&tdb);
+        let (start, duration_90k) = {
+            let l = tdb.db.lock();
+            let all_time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+            let mut row = None;
+            l.list_recordings_by_time(TEST_STREAM_ID, all_time, &mut |r| {
+                row = Some((r.start, r.duration_90k));
+                Ok(())
+            }).unwrap();
+            row.unwrap()
+        };
+
+        // Start after the first key frame, so that the partial GOP is re-encoded and spliced.
+        let p: Params = serde_json::from_str(&format!(
+            r#"{{"streamId": {}, "startTime90k": {}, "endTime90k": {}, "precise": true}}"#,
+            TEST_STREAM_ID, start.0 + 3000, start.0 + duration_90k as i64)).unwrap();
+        let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
+        let exporter = Exporter::new(tdb.db.clone(), StreamDirs::new(tdb.db.clone()).unwrap(),
+                                     tmpdir.path().to_owned(), Some("ffmpeg".into())).unwrap();
+        let out = tmpdir.path().join("out.mp4");
+        exporter.write(Uuid::new_v4(), &p, &AtomicBool::new(false), &out).unwrap();
+        let data = fs::read(&out).unwrap();
+        let (server_uuid, camera_uuid) = {
+            let l = tdb.db.lock();
+            (l.uuid(), l.cameras_by_id()[&testutil::TEST_CAMERA_ID].uuid)
+        };
+        let contains = |s: String| data.windows(s.len()).any(|w| w == s.as_bytes());
+        assert!(contains(server_uuid.to_string()));
+        assert!(contains(camera_uuid.to_string()));
+    }
+
+    #[test]
+    fn view_path() {
+        let uuid = Uuid::parse_str("fd20f7a2-9d69-4cb3-94ed-d51a20c3edfe").unwrap();
+        let clip = Clip {
+            camera_uuid: uuid,
+            stream_type: db::StreamType::MAIN,
+            segments: vec![(3, 100, 26 .. 100), (4, 100, 0 .. 100), (5, 100, 0 .. 42)],
+        };
+        assert_eq!(clip.view_path(),
+                   "/api/cameras/fd20f7a2-9d69-4cb3-94ed-d51a20c3edfe/main/view.mp4?s=3-5.26-242");
+
+        // A single recording from its start is written in canonical form.
+        let clip = Clip {
+            camera_uuid: uuid,
+            stream_type: db::StreamType::MAIN,
+            segments: vec![(3, 100, 0 .. 42)],
+        };
+        assert_eq!(clip.view_path(),
+                   "/api/cameras/fd20f7a2-9d69-4cb3-94ed-d51a20c3edfe/main/view.mp4?s=3.-42");
+    }
+}
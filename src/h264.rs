@@ -39,13 +39,18 @@
 //! ffmpeg of course has logic to do the same thing, but unfortunately it is not exposed except
 //! through ffmpeg's own generated `.mp4` file. Extracting just this part of their `.mp4` files
 //! would be more trouble than it's worth.
+//!
+//! Samples are otherwise stored as received, so SEI (supplemental enhancement information) NAL
+//! units such as camera-embedded timestamps pass through to the `.mp4` output. `sei_messages`
+//! extracts them for analytics.
 
-use byteorder::{BigEndian, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use failure::Error;
 use regex::bytes::Regex;
 
 // See ISO/IEC 14496-10 table 7-1 - NAL unit type codes, syntax element categories, and NAL unit
 // type classes.
+const NAL_UNIT_SEI: u8 = 6;
 const NAL_UNIT_SEQ_PARAMETER_SET: u8 = 7;
 const NAL_UNIT_PIC_PARAMETER_SET: u8 = 8;
 
@@ -59,7 +64,7 @@ const NAL_UNIT_TYPE_MASK: u8 = 0x1F;  // bottom 5 bits of first byte of unit.
 ///
 /// TODO: detect invalid byte streams. For example, several 0x00s not followed by a 0x01, a stream
 /// stream not starting with 0x00 0x00 0x00 0x01, or an empty NAL unit.
-fn decode_h264_annex_b<'a, F>(data: &'a [u8], mut f: F) -> Result<(), Error>
+pub fn decode_h264_annex_b<'a, F>(data: &'a [u8], mut f: F) -> Result<(), Error>
 where F: FnMut(&'a [u8]) -> Result<(), Error> {
     lazy_static! {
         static ref START_CODE: Regex = Regex::new(r"(\x00{2,}\x01)").unwrap();
@@ -246,6 +251,137 @@ pub fn transform_sample_data(annexb_sample: &[u8], avc_sample: &mut Vec<u8>) ->
     Ok(())
 }
 
+/// The `payloadType` of a `user_data_unregistered` SEI message, which begins with a 16-byte UUID
+/// identifying its (vendor-specific) format. See ISO/IEC 14496-10 section D.1.6.
+pub const SEI_USER_DATA_UNREGISTERED: u32 = 5;
+
+/// A SEI message, as in ISO/IEC 14496-10 section 7.3.2.3.1.
+#[derive(Debug, Eq, PartialEq)]
+pub struct SeiMessage {
+    pub payload_type: u32,
+
+    /// The payload, with emulation prevention bytes removed.
+    pub payload: Vec<u8>,
+}
+
+/// Returns the SEI messages within an AVC-format sample (as produced by `transform_sample_data`).
+pub fn sei_messages(avc_sample: &[u8]) -> Result<Vec<SeiMessage>, Error> {
+    let mut messages = Vec::new();
+    let mut data = avc_sample;
+    while !data.is_empty() {
+        if data.len() < 4 {
+            bail!("truncated NAL unit length");
+        }
+        let len = BigEndian::read_u32(&data[..4]) as usize;
+        data = &data[4..];
+        if len == 0 || len > data.len() {
+            bail!("bad NAL unit length {} with {} bytes remaining", len, data.len());
+        }
+        let (unit, rest) = data.split_at(len);
+        data = rest;
+        if unit[0] & NAL_UNIT_TYPE_MASK != NAL_UNIT_SEI {
+            continue;
+        }
+        let rbsp = unescape(&unit[1..]);
+        let mut p = &rbsp[..];
+
+        // sei_rbsp: sei_message()s until rbsp_trailing_bits, which here is the byte 0x80.
+        while !p.is_empty() && p != &[0x80] {
+            let payload_type = read_sei_value(&mut p)?;
+            let payload_size = read_sei_value(&mut p)? as usize;
+            if payload_size > p.len() {
+                bail!("SEI payload of {} bytes with {} bytes remaining", payload_size, p.len());
+            }
+            messages.push(SeiMessage {
+                payload_type,
+                payload: p[..payload_size].to_vec(),
+            });
+            p = &p[payload_size..];
+        }
+    }
+    Ok(messages)
+}
+
+/// Converts an AVC-format sample to Annex B format, prefixed with the SPS and PPS from the given
+/// `avc1` sample entry (as produced by `ExtraData::parse`). This makes a key frame decodable on
+/// its own, as by `ffmpeg -f h264`.
+pub fn to_annex_b(sample_entry: &[u8], avc_sample: &[u8]) -> Result<Vec<u8>, Error> {
+    // The AVCDecoderConfiguration follows the 86-byte VisualSampleEntry and the avcC box header.
+    if sample_entry.len() < 100 || &sample_entry[90..94] != b"avcC" {
+        bail!("not an avc1 sample entry with avcC");
+    }
+    let length_size = (sample_entry[98] & 0x3) as usize + 1;
+    let mut out = Vec::with_capacity(sample_entry.len() + avc_sample.len());
+    let mut p = &sample_entry[99..];
+    for &mask in &[0x1f, 0xff] {
+        let n = match p.first() {
+            Some(&n) => n & mask,
+            None => bail!("truncated AVCDecoderConfiguration"),
+        };
+        p = &p[1..];
+        for _ in 0..n {
+            if p.len() < 2 {
+                bail!("truncated AVCDecoderConfiguration");
+            }
+            let len = BigEndian::read_u16(&p[..2]) as usize;
+            if 2 + len > p.len() {
+                bail!("truncated parameter set");
+            }
+            out.extend_from_slice(b"\x00\x00\x00\x01");
+            out.extend_from_slice(&p[2..2+len]);
+            p = &p[2+len..];
+        }
+    }
+    let mut data = avc_sample;
+    while !data.is_empty() {
+        if data.len() < length_size {
+            bail!("truncated NAL unit length");
+        }
+        let len = BigEndian::read_uint(&data[..length_size], length_size) as usize;
+        data = &data[length_size..];
+        if len == 0 || len > data.len() {
+            bail!("bad NAL unit length {} with {} bytes remaining", len, data.len());
+        }
+        out.extend_from_slice(b"\x00\x00\x00\x01");
+        out.extend_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+    Ok(out)
+}
+
+/// Reads a SEI `payloadType` or `payloadSize`, encoded as a run of 0xFF bytes (each adding 255)
+/// followed by a final byte.
+fn read_sei_value(p: &mut &[u8]) -> Result<u32, Error> {
+    let mut v = 0u32;
+    loop {
+        let b = match p.first() {
+            Some(&b) => b,
+            None => bail!("truncated SEI message"),
+        };
+        *p = &p[1..];
+        v = v.checked_add(b as u32).ok_or_else(|| format_err!("SEI value overflow"))?;
+        if b != 0xff {
+            return Ok(v);
+        }
+    }
+}
+
+/// Removes the `emulation_prevention_three_byte`s from a NAL unit's payload, yielding the RBSP.
+/// See ISO/IEC 14496-10 section 7.4.1.
+fn unescape(escaped: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(escaped.len());
+    let mut zeros = 0;
+    for &b in escaped {
+        if zeros >= 2 && b == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use db::testutil;
@@ -356,5 +492,46 @@ mod tests {
         let mut out = Vec::new();
         super::transform_sample_data(&INPUT, &mut out).unwrap();
         assert_eq!(&out[..], &EXPECTED_OUTPUT[..]);
+
+        // The SEI unit (a recovery point message) passes through unchanged.
+        assert_eq!(super::sei_messages(&out).unwrap(), vec![super::SeiMessage {
+            payload_type: 6,
+            payload: vec![0xc4],
+        }]);
+    }
+
+    #[test]
+    fn test_sei_messages() {
+        testutil::init();
+        const SAMPLE: [u8; 34] = [
+            0x00, 0x00, 0x00, 0x19, 0x06,
+
+            // user_data_unregistered, 20 bytes (escaped to 21).
+            0x05, 0x14,
+            0x00, 0x00, 0x03, 0x01, 0x02, 0x03, 0x04, 0x05,
+            0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x01, 0x02, 0x03, 0x04,
+            0x80,
+
+            0x00, 0x00, 0x00, 0x01, 0x65,
+        ];
+        assert_eq!(super::sei_messages(&SAMPLE).unwrap(), vec![super::SeiMessage {
+            payload_type: super::SEI_USER_DATA_UNREGISTERED,
+            payload: vec![0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a,
+                          0x0b, 0x0c, 0x0d, 0x0e, 0x01, 0x02, 0x03, 0x04],
+        }]);
+        assert!(super::sei_messages(&SAMPLE[..20]).is_err());
+    }
+
+    #[test]
+    fn test_to_annex_b() {
+        testutil::init();
+        let sample = [0x00, 0x00, 0x00, 0x03, 0x65, 0x88, 0x80];
+        let out = super::to_annex_b(&TEST_OUTPUT, &sample).unwrap();
+        let mut expected = ANNEX_B_TEST_INPUT.to_vec();
+        expected.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x65, 0x88, 0x80]);
+        assert_eq!(out, expected);
+        assert!(super::to_annex_b(&TEST_OUTPUT, &sample[..6]).is_err());
+        assert!(super::to_annex_b(&TEST_OUTPUT[..100], &sample).is_err());
     }
 }
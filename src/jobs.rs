@@ -0,0 +1,303 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A queue of background jobs (such as exports), as in `/api/jobs`. See `design/api.md` for
+//! details.
+//!
+//! Jobs are persisted in the database's `job` table so that they survive restarts; each kind of
+//! job has a `Handler` which does the actual work. At most a fixed number of jobs run at once.
+
+use base::sched;
+use db;
+use failure::Error;
+use maintenance::Maintenance;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json;
+use std::collections::HashMap;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use time;
+use uuid::Uuid;
+
+/// How long finished jobs are kept before being deleted.
+const FINISHED_JOB_TTL_SEC: i64 = 24 * 60 * 60;
+
+/// Runs jobs of a particular kind.
+pub trait Handler : Send + Sync {
+    /// Runs the given job, returning a JSON result on success. `cancel` is set when the job is
+    /// cancelled; long-running handlers should check it periodically and return early.
+    fn run(&self, job: &db::Job, cancel: &AtomicBool) -> Result<String, Error>;
+
+    /// Cleans up after a job is deleted, such as by removing its output.
+    fn cleanup(&self, _job: &db::Job) {}
+}
+
+/// Records a running job's progress as its `result`, which `/api/jobs` shows as `progress` until
+/// the handler returns and its result replaces it.
+pub fn set_progress<T: Serialize>(db: &db::Database, job: &db::Job, progress: &T)
+                                  -> Result<(), Error> {
+    let mut j = job.clone();
+    j.result = Some(serde_json::to_string(progress)?);
+    db.lock().update_job(&j)
+}
+
+pub struct Queue {
+    db: Arc<db::Database>,
+    handlers: HashMap<&'static str, Arc<Handler>>,
+
+    /// Cancellation flags of running jobs.
+    running: Mutex<HashMap<Uuid, Arc<AtomicBool>>>,
+    tx: Mutex<mpsc::Sender<Uuid>>,
+}
+
+impl Queue {
+    /// Starts `concurrency` worker threads running jobs with the given handlers. Jobs left
+    /// pending or running by a previous run are started again. Jobs don't start while
+    /// `maintenance` is active.
+    pub fn start(db: Arc<db::Database>, handlers: HashMap<&'static str, Arc<Handler>>,
+                 concurrency: usize, maintenance: Arc<Maintenance>) -> Result<Arc<Self>, Error> {
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut l = db.lock();
+            for mut j in l.list_jobs()? {
+                if j.state == db::JobState::Running {
+                    info!("jobs: restarting interrupted job {}", j.uuid);
+                    j.state = db::JobState::Pending;
+                    j.result = None;  // discard any progress.
+                    l.update_job(&j)?;
+                }
+                if j.state == db::JobState::Pending {
+                    tx.send(j.uuid).expect("receiver is alive");
+                }
+            }
+        }
+        let q = Arc::new(Queue {
+            db,
+            handlers,
+            running: Mutex::new(HashMap::new()),
+            tx: Mutex::new(tx),
+        });
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0 .. concurrency {
+            let q = q.clone();
+            let rx = rx.clone();
+            let maintenance = maintenance.clone();
+            thread::Builder::new()
+                .name(format!("job-{}", i))
+                .spawn(move || {
+                    sched::enter(sched::Class::Web);
+                    loop {
+                        let id = match rx.lock().recv() {
+                            Ok(id) => id,
+                            Err(_) => return,
+                        };
+                        let _work = maintenance.work();
+                        q.run(id);
+                    }
+                })?;
+        }
+        Ok(q)
+    }
+
+    pub fn has_handler(&self, type_: &str) -> bool { self.handlers.contains_key(type_) }
+
+    /// Creates a pending job with the given type and parameters, returning it.
+    pub fn create<T: Serialize>(&self, type_: &str, params: &T) -> Result<db::Job, Error> {
+        if !self.has_handler(type_) {
+            bail!("no handler for jobs of type {}", type_);
+        }
+        let now_sec = time::get_time().sec;
+        self.expire(now_sec)?;
+        let j = self.db.lock().add_job(type_.to_owned(), serde_json::to_string(params)?,
+                                       now_sec)?;
+        self.tx.lock().send(j.uuid).expect("job threads should be running");
+        Ok(j)
+    }
+
+    pub fn get(&self, uuid: Uuid) -> Result<Option<db::Job>, Error> {
+        Ok(self.db.lock().list_jobs()?.into_iter().find(|j| j.uuid == uuid))
+    }
+
+    pub fn list(&self) -> Result<Vec<db::Job>, Error> { self.db.lock().list_jobs() }
+
+    /// Cancels the given job if it's pending or running, or deletes it if finished.
+    /// Returns false if there's no such job.
+    pub fn cancel_or_delete(&self, uuid: Uuid) -> Result<bool, Error> {
+        let j = {
+            // Hold `running` so the state can't change under us; see `try_run`.
+            let running = self.running.lock();
+            let mut j = match self.get(uuid)? {
+                None => return Ok(false),
+                Some(j) => j,
+            };
+            match j.state {
+                db::JobState::Pending => {
+                    j.state = db::JobState::Cancelled;
+                    j.finished_sec = Some(time::get_time().sec);
+                    self.db.lock().update_job(&j)?;
+                    return Ok(true);
+                },
+                db::JobState::Running => {
+                    // The worker thread will mark it cancelled when the handler returns.
+                    if let Some(c) = running.get(&uuid) {
+                        c.store(true, Ordering::SeqCst);
+                    }
+                    return Ok(true);
+                },
+                _ => j,
+            }
+        };
+        self.delete(&j)?;
+        Ok(true)
+    }
+
+    fn delete(&self, j: &db::Job) -> Result<(), Error> {
+        self.db.lock().delete_job(j.id)?;
+        if let Some(h) = self.handlers.get(&j.type_[..]) {
+            h.cleanup(j);
+        }
+        Ok(())
+    }
+
+    /// Deletes jobs which finished more than `FINISHED_JOB_TTL_SEC` ago.
+    fn expire(&self, now_sec: i64) -> Result<(), Error> {
+        for j in self.list()? {
+            if j.finished_sec.map(|f| now_sec - f >= FINISHED_JOB_TTL_SEC).unwrap_or(false) {
+                self.delete(&j)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&self, uuid: Uuid) {
+        if let Err(e) = self.try_run(uuid) {
+            warn!("jobs: unable to run {}: {}", uuid, e);
+        }
+    }
+
+    fn try_run(&self, uuid: Uuid) -> Result<(), Error> {
+        // State transitions happen with `running` held (ahead of the database lock), so that
+        // `cancel_or_delete` sees either a pending job or a running one with its cancel flag.
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (mut j, h) = {
+            let mut running = self.running.lock();
+            let mut j = match self.get(uuid)? {
+                Some(ref j) if j.state == db::JobState::Pending => j.clone(),
+                _ => return Ok(()),  // cancelled or deleted while pending.
+            };
+            let h = self.handlers.get(&j.type_[..])
+                        .ok_or_else(|| format_err!("no handler for jobs of type {}", j.type_))?;
+            running.insert(uuid, cancel.clone());
+            j.state = db::JobState::Running;
+            if let Err(e) = self.db.lock().update_job(&j) {
+                running.remove(&uuid);
+                return Err(e);
+            }
+            (j, h)
+        };
+        info!("jobs: running {} job {}", j.type_, uuid);
+        let r = h.run(&j, &cancel);
+        let mut running = self.running.lock();
+        running.remove(&uuid);
+        let (state, result) = match r {
+            _ if cancel.load(Ordering::SeqCst) => (db::JobState::Cancelled, None),
+            Ok(r) => (db::JobState::Done, Some(r)),
+            Err(e) => {
+                warn!("jobs: {} job {} failed: {}", j.type_, uuid, e);
+                (db::JobState::Failed, Some(e.to_string()))
+            },
+        };
+        j.state = state;
+        j.result = result;
+        j.finished_sec = Some(time::get_time().sec);
+        self.db.lock().update_job(&j)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clock::RealClocks;
+    use db;
+    use db::testutil::{self, TestDb};
+    use failure::Error;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+    use maintenance::Maintenance;
+    use super::{Handler, Queue};
+
+    struct Echo;
+
+    impl Handler for Echo {
+        fn run(&self, job: &db::Job, _cancel: &AtomicBool) -> Result<String, Error> {
+            if job.params == "\"fail\"" {
+                bail!("failed as requested");
+            }
+            Ok(job.params.clone())
+        }
+    }
+
+    fn wait_for_finish(q: &Queue, j: &db::Job) -> db::Job {
+        for _ in 0 .. 1000 {
+            let j = q.get(j.uuid).unwrap().unwrap();
+            if j.state.is_finished() {
+                return j;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("job {} didn't finish", j.uuid);
+    }
+
+    #[test]
+    fn run() {
+        testutil::init();
+        let tdb = TestDb::new(RealClocks {});
+        let mut handlers: HashMap<&'static str, Arc<Handler>> = HashMap::new();
+        handlers.insert("echo", Arc::new(Echo));
+        let q = Queue::start(tdb.db.clone(), handlers, 2, Maintenance::new()).unwrap();
+        assert!(q.create("nonexistent", &1).is_err());
+
+        let j = wait_for_finish(&q, &q.create("echo", &[1, 2]).unwrap());
+        assert_eq!(j.state, db::JobState::Done);
+        assert_eq!(j.result.as_ref().unwrap(), "[1,2]");
+
+        let j = wait_for_finish(&q, &q.create("echo", &"fail").unwrap());
+        assert_eq!(j.state, db::JobState::Failed);
+        assert_eq!(j.result.as_ref().unwrap(), "failed as requested");
+
+        assert!(q.cancel_or_delete(j.uuid).unwrap());  // finished, so deletes.
+        assert!(q.get(j.uuid).unwrap().is_none());
+        assert!(!q.cancel_or_delete(j.uuid).unwrap());
+    }
+}
@@ -28,11 +28,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use base::strutil;
+use crash;
 use db;
 use failure::Error;
+use log;
+use logs;
+use maintenance;
+use onvif;
 use serde::ser::{SerializeMap, SerializeSeq, Serializer};
-use std::collections::BTreeMap;
+use serde_json;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Not;
+use stream;
 use uuid::Uuid;
 
 #[derive(Serialize)]
@@ -41,9 +50,109 @@ pub struct TopLevel<'a> {
     pub time_zone_name: &'a str,
 
     // Use a custom serializer which presents the map's values as a sequence and includes the
-    // "days" attribute or not, according to the bool in the tuple.
+    // "days" and "sampleFileDir" attributes or not, according to the bools in the tuple. Only
+    // cameras matching the filter are included.
     #[serde(serialize_with = "TopLevel::serialize_cameras")]
-    pub cameras: (&'a db::LockedDatabase, bool),
+    pub cameras: (&'a db::LockedDatabase, bool, bool, &'a CameraFilter),
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tenants: Vec<Tenant<'a>>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub export_presets: Vec<ExportPreset<'a>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_crash: Option<crash::Summary>,
+
+    pub startup: StartupReport,
+}
+
+/// What the server cleaned up from the previous run at startup; see `db::StartupReport`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct StartupReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_run_end_90k: Option<i64>,
+    pub streams: Vec<StreamStartupReport>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct StreamStartupReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_uuid: Option<Uuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<&'static str>,
+    pub abandoned_recordings: usize,
+    pub garbage_collected: usize,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gap_90k: Option<i64>,
+}
+
+impl StartupReport {
+    pub fn new(db: &db::LockedDatabase) -> Self {
+        let r = db.startup_report();
+        StartupReport {
+            previous_run_end_90k: r.previous_run_end.map(|t| t.0),
+            streams: r.streams.iter().map(|(id, s)| {
+                // The stream may have been deleted since, in which case only the counts are known.
+                let st = db.streams_by_id().get(id);
+                StreamStartupReport {
+                    camera_uuid: st.and_then(|st| db.cameras_by_id().get(&st.camera_id))
+                                   .map(|c| c.uuid),
+                    stream: st.map(|st| st.type_.as_str()),
+                    abandoned_recordings: s.abandoned_recordings,
+                    garbage_collected: s.garbage_collected,
+                    gap_90k: s.gap.map(|g| g.0),
+                }
+            }).collect(),
+        }
+    }
+}
+
+/// Criteria for the cameras to include in `/api/`. A camera must match the tenant (if specified)
+/// and all of the label filters.
+#[derive(Debug, Default)]
+pub struct CameraFilter {
+    pub tenant_id: Option<i32>,
+    pub labels: Vec<LabelFilter>,
+}
+
+impl CameraFilter {
+    pub fn matches(&self, c: &db::Camera) -> bool {
+        if self.tenant_id.is_some() && c.tenant_id != self.tenant_id {
+            return false;
+        }
+        self.labels.iter().all(|f| f.matches(c))
+    }
+}
+
+/// A filter on camera labels, as in the `label` parameter to `/api/`: a camera matches if it has
+/// a label with the given key and (if specified) value.
+#[derive(Debug, Eq, PartialEq)]
+pub struct LabelFilter {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl LabelFilter {
+    /// Parses a filter of the form `key` or `key:value`.
+    pub fn parse(s: &str) -> Self {
+        match s.find(':') {
+            None => LabelFilter { key: s.to_owned(), value: None },
+            Some(i) => LabelFilter { key: s[..i].to_owned(), value: Some(s[i+1..].to_owned()) },
+        }
+    }
+
+    pub fn matches(&self, c: &db::Camera) -> bool {
+        match (c.labels.get(&self.key), &self.value) {
+            (None, _) => false,
+            (Some(_), &None) => true,
+            (Some(v), &Some(ref want)) => v == want,
+        }
+    }
 }
 
 /// JSON serialization wrapper for a single camera when processing `/api/` and
@@ -54,6 +163,34 @@ pub struct Camera<'a> {
     pub uuid: Uuid,
     pub short_name: &'a str,
     pub description: &'a str,
+    pub labels: &'a BTreeMap<String, String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant_uuid: Option<Uuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_source: Option<&'static str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_interval_sec: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_retain_days: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reachable: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reachability_error: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_skew_sec: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_ntp: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_error: Option<&'a str>,
 
     #[serde(serialize_with = "Camera::serialize_streams")]
     pub streams: [Option<Stream<'a>>; 2],
@@ -62,26 +199,74 @@ pub struct Camera<'a> {
 #[derive(Debug, Serialize)]
 #[serde(rename_all="camelCase")]
 pub struct Stream<'a> {
+    pub id: i32,
+    pub source: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_file_dir: Option<&'a str>,
     pub retain_bytes: i64,
+    pub retain_weight: i32,
+    pub recording_duration_sec: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sei_motion_uuid: Option<Uuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_url: Option<String>,
+
+    pub metadata_events: bool,
+    pub thumbnail_interval_sec: i64,
+    pub paused: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<EncoderSettings>,
+
     pub min_start_time_90k: Option<i64>,
     pub max_end_time_90k: Option<i64>,
     pub total_duration_90k: i64,
     pub total_sample_file_bytes: i64,
+    pub health: StreamHealth<'a>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(serialize_with = "Stream::serialize_days")]
     pub days: Option<&'a BTreeMap<db::StreamDayKey, db::StreamDayValue>>,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct StreamHealth<'a> {
+    pub state: &'static str,
+    pub consecutive_failures: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cause: Option<&'static str>,
+}
+
 impl<'a> Camera<'a> {
-    pub fn wrap(c: &'a db::Camera, db: &'a db::LockedDatabase, include_days: bool) -> Result<Self, Error> {
+    /// Wraps the given camera. `include_dirs` should be false for tenant users, who shouldn't
+    /// learn the server's filesystem layout.
+    pub fn wrap(c: &'a db::Camera, db: &'a db::LockedDatabase, include_days: bool,
+                include_dirs: bool) -> Result<Self, Error> {
         Ok(Camera {
             uuid: c.uuid,
             short_name: &c.short_name,
             description: &c.description,
+            labels: &c.labels,
+            tenant_uuid: c.tenant_id.and_then(|id| db.tenants_by_id().get(&id)).map(|t| t.uuid),
+            event_source: c.event_source.map(db::EventSource::as_str),
+            snapshot_interval_sec: c.snapshot_schedule.map(|s| s.interval_sec),
+            snapshot_retain_days: c.snapshot_schedule.map(|s| s.retain_days),
+            reachable: c.reachability.reachable,
+            reachability_error: c.reachability.last_error.as_ref().map(String::as_str),
+            clock_skew_sec: c.clock.skew_sec,
+            clock_ntp: c.clock.ntp,
+            clock_error: c.clock.last_error.as_ref().map(String::as_str),
             streams: [
-                Stream::wrap(db, c.streams[0], include_days)?,
-                Stream::wrap(db, c.streams[1], include_days)?,
+                Stream::wrap(db, c.streams[0], include_days, include_dirs)?,
+                Stream::wrap(db, c.streams[1], include_days, include_dirs)?,
             ],
         })
     }
@@ -99,19 +284,106 @@ impl<'a> Camera<'a> {
     }
 }
 
+/// JSON serialization for `/api/coverage`.
+#[derive(Debug, Serialize)]
+pub struct Coverage<'a> {
+    pub cameras: Vec<CameraCoverage<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct CameraCoverage<'a> {
+    pub uuid: Uuid,
+    pub short_name: &'a str,
+
+    #[serde(serialize_with = "CameraCoverage::serialize_streams")]
+    pub streams: [Option<StreamCoverage>; 2],
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct StreamCoverage {
+    pub oldest_time_90k: Option<i64>,
+    pub newest_time_90k: Option<i64>,
+    pub total_duration_90k: i64,
+    pub total_sample_file_bytes: i64,
+}
+
+impl<'a> CameraCoverage<'a> {
+    pub fn wrap(c: &'a db::Camera, db: &db::LockedDatabase) -> Self {
+        let wrap_stream = |id: Option<i32>| id.map(|id| {
+            let s = &db.streams_by_id()[&id];
+            StreamCoverage {
+                oldest_time_90k: s.range.as_ref().map(|r| r.start.0),
+                newest_time_90k: s.newest_end().map(|t| t.0),
+                total_duration_90k: s.duration.0,
+                total_sample_file_bytes: s.sample_file_bytes,
+            }
+        });
+        CameraCoverage {
+            uuid: c.uuid,
+            short_name: &c.short_name,
+            streams: [wrap_stream(c.streams[0]), wrap_stream(c.streams[1])],
+        }
+    }
+
+    fn serialize_streams<S>(streams: &[Option<StreamCoverage>; 2], serializer: S)
+                            -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        let mut map = serializer.serialize_map(Some(streams.len()))?;
+        for (i, s) in streams.iter().enumerate() {
+            if let &Some(ref s) = s {
+                map.serialize_key(db::StreamType::from_index(i).expect("invalid stream type index").as_str())?;
+                map.serialize_value(s)?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl<'a> StreamHealth<'a> {
+    pub fn wrap(h: &'a db::StreamHealth, r: &db::CameraReachability) -> Self {
+        StreamHealth {
+            state: h.state(),
+            consecutive_failures: h.consecutive_failures,
+            last_error: h.last_error.as_ref().map(String::as_str),
+            cause: h.cause(r),
+        }
+    }
+}
+
 impl<'a> Stream<'a> {
-    fn wrap(db: &'a db::LockedDatabase, id: Option<i32>, include_days: bool) -> Result<Option<Self>, Error> {
+    fn wrap(db: &'a db::LockedDatabase, id: Option<i32>, include_days: bool, include_dirs: bool)
+            -> Result<Option<Self>, Error> {
         let id = match id {
             Some(id) => id,
             None => return Ok(None),
         };
         let s = db.streams_by_id().get(&id).ok_or_else(|| format_err!("missing stream {}", id))?;
+        let c = db.cameras_by_id().get(&s.camera_id)
+                  .ok_or_else(|| format_err!("missing camera {}", s.camera_id))?;
         Ok(Some(Stream {
+            id,
+            source: s.source.type_str(),
+            sample_file_dir: s.sample_file_dir_id.filter(|_| include_dirs)
+                              .and_then(|d| db.sample_file_dirs_by_id().get(&d))
+                              .map(|d| d.path.as_str()),
             retain_bytes: s.retain_bytes,
+            retain_weight: s.retain_weight,
+            recording_duration_sec: s.recording_duration_sec,
+            sei_motion_uuid: s.sei_motion_uuid,
+            snapshot_url: s.snapshot_url.as_ref().map(|u| stream::redacted_http_url(u)),
+            metadata_events: s.metadata_events,
+            thumbnail_interval_sec: s.thumbnail_interval_sec,
+            paused: s.paused,
+            encoder: if s.encoder.is_empty() { None } else {
+                Some(EncoderSettings::wrap(&s.encoder))
+            },
             min_start_time_90k: s.range.as_ref().map(|r| r.start.0),
             max_end_time_90k: s.range.as_ref().map(|r| r.end.0),
             total_duration_90k: s.duration.0,
             total_sample_file_bytes: s.sample_file_bytes,
+            health: StreamHealth::wrap(&s.health, &c.reachability),
             days: if include_days { Some(&s.days) } else { None },
         }))
     }
@@ -137,6 +409,60 @@ impl<'a> Stream<'a> {
     }
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Tenant<'a> {
+    pub uuid: Uuid,
+    pub short_name: &'a str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retain_bytes: Option<i64>,
+    pub total_sample_file_bytes: i64,
+    pub watermark_exports: bool,
+}
+
+impl<'a> Tenant<'a> {
+    pub fn wrap(t: &'a db::Tenant, db: &'a db::LockedDatabase) -> Self {
+        let cameras = db.cameras_by_id();
+        Tenant {
+            uuid: t.uuid,
+            short_name: &t.short_name,
+            retain_bytes: t.retain_bytes,
+            total_sample_file_bytes:
+                db.streams_by_id()
+                  .values()
+                  .filter(|s| cameras.get(&s.camera_id).and_then(|c| c.tenant_id) == Some(t.id))
+                  .map(|s| s.sample_file_bytes)
+                  .sum(),
+            watermark_exports: t.watermark_exports,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct ExportPreset<'a> {
+    pub name: &'a str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<i32>,
+    pub watermark: bool,
+    pub timestamps: bool,
+    pub container: &'static str,
+}
+
+impl<'a> ExportPreset<'a> {
+    pub fn wrap(p: &'a db::ExportPreset) -> Self {
+        ExportPreset {
+            name: &p.name,
+            max_height: p.max_height,
+            watermark: p.watermark,
+            timestamps: p.timestamps,
+            container: p.container.as_str(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all="camelCase")]
 struct StreamDayValue {
@@ -147,22 +473,79 @@ struct StreamDayValue {
 
 impl<'a> TopLevel<'a> {
     /// Serializes cameras as a list (rather than a map), optionally including the `days` field.
-    fn serialize_cameras<S>(cameras: &(&db::LockedDatabase, bool),
+    fn serialize_cameras<S>(cameras: &(&db::LockedDatabase, bool, bool, &CameraFilter),
                             serializer: S) -> Result<S::Ok, S::Error>
     where S: Serializer {
-        let (db, include_days) = *cameras;
-        let cs = db.cameras_by_id();
+        let (db, include_days, include_dirs, filter) = *cameras;
+        let cs: Vec<_> = db.cameras_by_id()
+                           .values()
+                           .filter(|c| filter.matches(c))
+                           .collect();
         let mut seq = serializer.serialize_seq(Some(cs.len()))?;
-        for (_, c) in cs {
-            seq.serialize_element(&Camera::wrap(c, db, include_days).unwrap())?;  // TODO: no unwrap.
+        for c in cs {
+            seq.serialize_element(&Camera::wrap(c, db, include_days, include_dirs).unwrap())?;  // TODO: no unwrap.
         }
         seq.end()
     }
 }
 
+/// A selection of fields from the `fields` query parameter, which lets clients on slow
+/// connections fetch only what they display.
+///
+/// Members named in the selection are kept whole. Other members whose values are objects or
+/// arrays are kept and filtered in turn, so the response keeps its shape. All other members are
+/// dropped.
+#[derive(Debug)]
+pub struct Fields(BTreeSet<String>);
+
+impl Fields {
+    /// Parses a comma-separated list of member names.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut names = BTreeSet::new();
+        for n in s.split(',') {
+            if n.is_empty() {
+                bail!("malformed fields {:?}", s);
+            }
+            names.insert(n.to_owned());
+        }
+        Ok(Fields(names))
+    }
+
+    pub fn retain(&self, v: &mut serde_json::Value) {
+        match *v {
+            serde_json::Value::Object(ref mut m) => {
+                let old = ::std::mem::replace(m, serde_json::Map::new());
+                for (k, mut v) in old {
+                    if !self.0.contains(&k) {
+                        if !v.is_object() && !v.is_array() {
+                            continue;
+                        }
+                        self.retain(&mut v);
+                    }
+                    m.insert(k, v);
+                }
+            },
+            serde_json::Value::Array(ref mut a) => for e in a { self.retain(e); },
+            _ => {},
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct ListRecordings {
     pub recordings: Vec<Recording>,
+    pub notes: Vec<Note>,
+}
+
+/// JSON serialization for `/api/cameras/<uuid>/<stream>/recordings/updates`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct RecordingUpdates {
+    pub latest_id: i32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub growing_end_90k: Option<i64>,
+    pub recordings: Vec<Recording>,
 }
 
 #[derive(Debug, Serialize)]
@@ -186,4 +569,743 @@ pub struct Recording {
 
     #[serde(skip_serializing_if = "Not::not")]
     pub growing: bool,
+
+    #[serde(skip_serializing_if = "Not::not")]
+    pub degraded: bool,
+
+    #[serde(skip_serializing_if = "Not::not")]
+    pub archived: bool,
+
+    pub min_frame_interval_90k: i32,
+    pub avg_frame_interval_90k: i32,
+    pub max_frame_interval_90k: i32,
+}
+
+impl Recording {
+    pub fn wrap(row: &db::ListAggregatedRecordingsRow, db: &db::LockedDatabase) -> Self {
+        let end = row.ids.end - 1;  // in api, ids are inclusive.
+        let vse = db.video_sample_entries_by_id().get(&row.video_sample_entry_id).unwrap();
+        Recording {
+            start_id: row.ids.start,
+            end_id: if end == row.ids.start { None } else { Some(end) },
+            start_time_90k: row.time.start.0,
+            end_time_90k: row.time.end.0,
+            sample_file_bytes: row.sample_file_bytes,
+            open_id: row.open_id,
+            first_uncommitted: row.first_uncommitted,
+            video_samples: row.video_samples,
+            video_sample_entry_width: vse.width,
+            video_sample_entry_height: vse.height,
+            video_sample_entry_sha1: strutil::hex(&vse.sha1),
+            growing: row.growing,
+            degraded: row.degraded,
+            archived: row.archived,
+            min_frame_interval_90k: row.min_frame_interval_90k,
+            avg_frame_interval_90k:
+                ((row.time.end - row.time.start).0 / cmp::max(1, row.video_samples)) as i32,
+            max_frame_interval_90k: row.max_frame_interval_90k,
+        }
+    }
+}
+
+/// Data of the `recordings` message in `/api/events/stream`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct RecordingsAddedMessage {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub count: usize,
+}
+
+/// Data of the `streamHealth` message in `/api/events/stream`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct StreamHealthMessage<'a> {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub health: StreamHealth<'a>,
+}
+
+/// Data of the `chainAnchor` message in `/api/events/stream`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct ChainAnchorMessage {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub recording_id: i32,
+    pub chain_sha1: String,
+    pub time_sec: i64,
+}
+
+/// Data of the `retentionExceeded` message in `/api/events/stream`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct RetentionExceededMessage {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub usage_bytes: i64,
+    pub retain_bytes: i64,
+    pub delete_after_90k: Option<i64>,
+}
+
+/// Data of the `cameraReachability` message in `/api/events/stream`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct CameraReachabilityMessage<'a> {
+    pub camera_uuid: Uuid,
+    pub reachable: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+}
+
+/// Data of the `event` message in `/api/events/stream`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct EventAddedMessage {
+    pub camera_uuid: Uuid,
+    pub event: Event,
+}
+
+/// JSON serialization for `GET /api/push`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Push<'a> {
+    pub application_server_key: &'a str,
+}
+
+/// JSON serialization for `/api/jobs`.
+#[derive(Debug, Serialize)]
+pub struct Jobs {
+    pub jobs: Vec<Job>,
+}
+
+/// JSON serialization for `/api/jobs/<id>`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Job {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub state: &'static str,
+    pub params: serde_json::Value,
+    pub created_sec: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_sec: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<serde_json::Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Job {
+    pub fn wrap(j: &db::Job) -> Self {
+        let (progress, result, error) = match (j.state, &j.result) {
+            (db::JobState::Running, &Some(ref p)) => (serde_json::from_str(p).ok(), None, None),
+            (db::JobState::Done, &Some(ref r)) => (None, serde_json::from_str(r).ok(), None),
+            (db::JobState::Failed, r) => (None, None, r.clone()),
+            _ => (None, None, None),
+        };
+        Job {
+            id: j.uuid,
+            type_: j.type_.clone(),
+            state: j.state.as_str(),
+            params: serde_json::from_str(&j.params).unwrap_or(serde_json::Value::Null),
+            created_sec: j.created_sec,
+            finished_sec: j.finished_sec,
+            progress,
+            result,
+            error,
+        }
+    }
+}
+
+/// JSON serialization for `/api/holds`.
+#[derive(Debug, Serialize)]
+pub struct Holds {
+    pub holds: Vec<Hold>,
+}
+
+/// JSON serialization for `/api/holds/<id>`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Hold {
+    pub id: Uuid,
+    pub camera: Uuid,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub reason: String,
+    pub created_sec: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_sec: Option<i64>,
+}
+
+impl Hold {
+    pub fn wrap(h: &db::Hold, db: &db::LockedDatabase) -> Self {
+        Hold {
+            id: h.uuid,
+            camera: db.cameras_by_id().get(&h.camera_id).unwrap().uuid,
+            start_time_90k: h.time.start.0,
+            end_time_90k: h.time.end.0,
+            reason: h.reason.clone(),
+            created_sec: h.created_sec,
+            expires_sec: h.expires_sec,
+        }
+    }
+}
+
+/// JSON serialization for `/api/cameras/<uuid>/<type>/index`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct StreamIndex {
+    pub recordings: Vec<IndexRecording>,
+
+    /// The `startId` with which to request the next page, if this one was cut short.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_start_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct IndexRecording {
+    pub id: i32,
+    pub start_time_90k: i64,
+    pub duration_90k: i32,
+    pub video_samples: i32,
+    pub video_sync_samples: i32,
+    pub sample_file_bytes: i32,
+    pub run_offset: i32,
+    pub open_id: u32,
+    pub flags: i32,
+    pub video_sample_entry_sha1: String,
+    pub video_index: String,
+}
+
+/// JSON serialization of `stream::Probe` for `/api/probe`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Probe<'a> {
+    pub video_codec: &'a str,
+    pub width: u16,
+    pub height: u16,
+    pub frames_per_sec: f64,
+    pub bits_per_sec: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_codec: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsupported_reason: Option<&'a str>,
+}
+
+/// A stream's configured encoder settings, as in `db::EncoderSettings`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct EncoderSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gov_length: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate_kbps: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+}
+
+impl EncoderSettings {
+    pub fn wrap(s: &db::EncoderSettings) -> Self {
+        EncoderSettings {
+            gov_length: s.gov_length,
+            bitrate_kbps: s.bitrate_kbps,
+            frame_rate: s.frame_rate,
+            width: s.resolution.map(|r| r.0),
+            height: s.resolution.map(|r| r.1),
+        }
+    }
+}
+
+/// JSON serialization for `/api/cameras/<uuid>/<type>/encoder`: the camera's video encoder
+/// configuration as read via ONVIF, and the settings configured in Moonfire NVR.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct StreamEncoder<'a> {
+    pub encoding: &'a str,
+    pub width: i32,
+    pub height: i32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate_limit: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate_limit_kbps: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gov_length: Option<i32>,
+
+    /// The number of the camera's media profiles sharing this configuration.
+    pub use_count: i32,
+    pub configured: EncoderSettings,
+}
+
+impl<'a> StreamEncoder<'a> {
+    pub fn wrap(c: &'a onvif::VideoEncoderConfiguration, configured: &db::EncoderSettings)
+                -> Self {
+        StreamEncoder {
+            encoding: &c.encoding,
+            width: c.width,
+            height: c.height,
+            frame_rate_limit: c.rate_control.as_ref().map(|r| r.frame_rate_limit),
+            bitrate_limit_kbps: c.rate_control.as_ref().map(|r| r.bitrate_limit_kbps),
+            gov_length: c.h264.as_ref().map(|h| h.gov_length),
+            use_count: c.use_count,
+            configured: EncoderSettings::wrap(configured),
+        }
+    }
+}
+
+/// JSON serialization for `/api/cameras/<uuid>/<type>/usage`: the stream's disk usage as seen
+/// when deciding which recordings to delete.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct StreamUsage {
+    pub committed_bytes: i64,
+    pub unflushed_bytes: i64,
+    pub uncommitted_bytes: i64,
+    pub pending_deletion_bytes: i64,
+    pub usage_bytes: i64,
+    pub retain_bytes: i64,
+
+    /// When usage went over `retain_bytes` and when old recordings will be deleted if it's still
+    /// over, if there's a retention grace period.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub over_limit_since_90k: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delete_after_90k: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantUsage>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct TenantUsage {
+    pub short_name: String,
+    pub usage_bytes: i64,
+    pub retain_bytes: i64,
+}
+
+impl StreamUsage {
+    pub fn new(db: &db::LockedDatabase, s: &db::Stream) -> Self {
+        let tenant = db.cameras_by_id().get(&s.camera_id)
+                       .and_then(|c| c.tenant_id)
+                       .and_then(|id| db.tenants_by_id().get(&id).map(|t| (id, t)));
+        StreamUsage {
+            committed_bytes: s.sample_file_bytes,
+            unflushed_bytes: s.bytes_to_add,
+            uncommitted_bytes: s.uncommitted_bytes(),
+            pending_deletion_bytes: s.bytes_to_delete,
+            usage_bytes: s.retention_usage(),
+            retain_bytes: s.retain_bytes,
+            over_limit_since_90k: s.over_limit_since.map(|t| t.0),
+            delete_after_90k: s.over_limit_since.map(|t| (t + db.retention_grace()).0),
+            tenant: tenant.and_then(|(id, t)| t.retain_bytes.map(|retain_bytes| {
+                let cameras = db.cameras_by_id();
+                TenantUsage {
+                    short_name: t.short_name.clone(),
+                    usage_bytes: db.streams_by_id().values()
+                                   .filter(|s| cameras.get(&s.camera_id)
+                                                      .and_then(|c| c.tenant_id) == Some(id))
+                                   .map(|s| s.retention_usage())
+                                   .sum(),
+                    retain_bytes,
+                }
+            })),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CameraReboot<'a> {
+    /// The camera's response message, such as "Rebooting in 30 seconds".
+    pub message: &'a str,
+}
+
+/// JSON serialization for `/api/admin/maintenance`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Maintenance {
+    pub active: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_sec: Option<i64>,
+    pub quiesced: bool,
+    pub paused_streams: Vec<MaintenancePausedStream>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct MaintenancePausedStream {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+}
+
+impl Maintenance {
+    pub fn wrap(m: &maintenance::State, db: &db::LockedDatabase) -> Self {
+        Maintenance {
+            active: m.active.is_some(),
+            reason: m.active.as_ref().map(|a| a.1.clone()),
+            since_sec: m.active.as_ref().map(|a| a.0),
+            quiesced: m.quiesced,
+            paused_streams: m.paused_streams.iter().filter_map(|id| {
+                let s = db.streams_by_id().get(id)?;
+                Some(MaintenancePausedStream {
+                    camera_uuid: db.cameras_by_id().get(&s.camera_id)?.uuid,
+                    stream: s.type_.as_str(),
+                })
+            }).collect(),
+        }
+    }
+}
+
+/// JSON serialization for `/api/admin/logs`.
+#[derive(Debug, Serialize)]
+pub struct Logs {
+    pub records: Vec<LogRecord>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct LogRecord {
+    pub id: u64,
+    pub time_90k: i64,
+    pub level: &'static str,
+    pub target: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+    pub message: String,
+}
+
+impl LogRecord {
+    pub fn wrap(r: &logs::Record) -> Self {
+        LogRecord {
+            id: r.id,
+            time_90k: db::recording::Time::new(r.time).0,
+            level: match r.level {
+                log::Level::Error => "error",
+                log::Level::Warn => "warn",
+                log::Level::Info => "info",
+                log::Level::Debug => "debug",
+                log::Level::Trace => "trace",
+            },
+            target: r.target.clone(),
+            thread: r.thread.clone(),
+            message: r.message.clone(),
+        }
+    }
+}
+
+/// JSON serialization for `/api/batch`.
+#[derive(Debug, Serialize)]
+pub struct Batch {
+    pub responses: Vec<BatchResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub path: String,
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+/// JSON serialization for `/api/users/<id>/preferences`, keyed by preference key.
+#[derive(Debug, Serialize)]
+pub struct UserPreferences {
+    pub preferences: BTreeMap<String, UserPreference>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserPreference {
+    pub value: serde_json::Value,
+    pub version: i64,
+}
+
+impl UserPreferences {
+    pub fn wrap(prefs: Vec<db::UserPreference>) -> Result<Self, Error> {
+        let mut preferences = BTreeMap::new();
+        for p in prefs {
+            let value = serde_json::from_str(&p.value)
+                .map_err(|e| format_err!("preference {} has invalid JSON: {}", p.key, e))?;
+            preferences.insert(p.key, UserPreference { value, version: p.version });
+        }
+        Ok(UserPreferences { preferences })
+    }
+}
+
+/// JSON serialization for `/api/viewers`.
+#[derive(Debug, Serialize)]
+pub struct Viewers {
+    pub streams: Vec<StreamViewers>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct StreamViewers {
+    pub camera_uuid: Uuid,
+    pub stream: &'static str,
+    pub viewers: usize,
+}
+
+/// JSON serialization for `/api/stats/bandwidth`.
+#[derive(Debug, Serialize)]
+pub struct Bandwidth {
+    pub usage: Vec<BandwidthUsage>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct BandwidthUsage {
+    pub start_sec: i64,
+    pub user_name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_uuid: Option<Uuid>,
+    pub requests: i64,
+    pub bytes: i64,
+}
+
+impl BandwidthUsage {
+    pub fn wrap(u: db::BandwidthUsage) -> Self {
+        BandwidthUsage {
+            start_sec: u.start_sec,
+            user_name: u.user_name,
+            camera_uuid: u.camera_uuid,
+            requests: u.requests,
+            bytes: u.bytes,
+        }
+    }
+}
+
+/// JSON serialization of a `POST /api/embed` response.
+#[derive(Debug, Serialize)]
+pub struct EmbedToken {
+    pub token: String,
+    pub path: String,
+}
+
+/// JSON serialization for `/api/layouts`.
+#[derive(Debug, Serialize)]
+pub struct Layouts {
+    pub layouts: Vec<Layout>,
+}
+
+/// JSON serialization for `/api/layouts/<id>`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Layout {
+    pub id: Uuid,
+    pub name: String,
+    pub owner_user_id: i32,
+    pub shared: bool,
+    pub rows: u8,
+    pub columns: u8,
+    pub created_sec: i64,
+    pub cells: Vec<LayoutCell>,
+}
+
+impl Layout {
+    pub fn wrap(l: db::Layout) -> Self {
+        Layout {
+            id: l.uuid,
+            name: l.name,
+            owner_user_id: l.owner_user_id,
+            shared: l.shared,
+            rows: l.rows,
+            columns: l.columns,
+            created_sec: l.created_sec,
+            cells: l.cells.into_iter().map(|c| LayoutCell {
+                position: c.position,
+                camera: c.camera_uuid,
+                stream: c.stream_type.as_str(),
+            }).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LayoutCell {
+    pub position: u16,
+    pub camera: Uuid,
+    pub stream: &'static str,
+}
+
+/// JSON serialization for `/api/incidents`.
+#[derive(Debug, Serialize)]
+pub struct Incidents {
+    pub incidents: Vec<Incident>,
+}
+
+/// JSON serialization for `/api/incidents/<id>`. `items` is only included in the detail view.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Incident {
+    pub id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub created_sec: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Vec<IncidentItem>>,
+}
+
+impl Incident {
+    pub fn wrap(i: db::Incident, items: Option<&[db::IncidentItem]>, db: &db::LockedDatabase)
+                -> Self {
+        Incident {
+            id: i.uuid,
+            title: i.title,
+            description: i.description,
+            created_sec: i.created_sec,
+            items: items.map(|items| items.iter().map(|i| IncidentItem::wrap(i, db)).collect()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag="type", rename_all="camelCase")]
+pub enum IncidentItem {
+    Event { id: i64 },
+    Range {
+        camera: Uuid,
+        #[serde(rename="startTime90k")]
+        start_time_90k: i64,
+        #[serde(rename="endTime90k")]
+        end_time_90k: i64,
+    },
+    Note { id: i64 },
+    Export { id: Uuid },
+}
+
+impl IncidentItem {
+    fn wrap(i: &db::IncidentItem, db: &db::LockedDatabase) -> Self {
+        match *i {
+            db::IncidentItem::Event(id) => IncidentItem::Event { id },
+            db::IncidentItem::Range { camera_id, ref time } => IncidentItem::Range {
+                camera: db.cameras_by_id().get(&camera_id).unwrap().uuid,
+                start_time_90k: time.start.0,
+                end_time_90k: time.end.0,
+            },
+            db::IncidentItem::Note(id) => IncidentItem::Note { id },
+            db::IncidentItem::Export(id) => IncidentItem::Export { id },
+        }
+    }
+}
+
+/// JSON serialization for `/api/cameras/<uuid>/<type>/metadata`.
+#[derive(Debug, Serialize)]
+pub struct ListMetadata {
+    pub packets: Vec<MetadataPacket>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct MetadataPacket {
+    pub recording_id: i32,
+    pub time_90k: i64,
+    pub data: String,
+}
+
+/// JSON serialization for `/api/cameras/<uuid>/<type>/notes`.
+#[derive(Debug, Serialize)]
+pub struct ListNotes {
+    pub notes: Vec<Note>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Note {
+    pub id: i64,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub text: String,
+    pub created_sec: i64,
+}
+
+impl Note {
+    pub fn wrap(n: db::Note) -> Self {
+        Note {
+            id: n.id,
+            start_time_90k: n.time.start.0,
+            end_time_90k: n.time.end.0,
+            text: n.text,
+            created_sec: n.created_sec,
+        }
+    }
+}
+
+/// JSON serialization for `/api/cameras/<uuid>/<type>/thumbnails`.
+#[derive(Debug, Serialize)]
+pub struct ListThumbnails {
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// JSON serialization for `/api/cameras/<uuid>/snapshots`.
+#[derive(Debug, Serialize)]
+pub struct ListScheduledSnapshots {
+    pub snapshots: Vec<Thumbnail>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Thumbnail {
+    pub time_90k: i64,
+    pub bytes: i64,
+}
+
+impl Thumbnail {
+    pub fn wrap(t: &db::ListThumbnailsRow) -> Self {
+        Thumbnail {
+            time_90k: t.time.0,
+            bytes: t.bytes,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListEvents {
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Event {
+    pub id: i64,
+
+    #[serde(rename="type")]
+    pub type_: String,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
 }
@@ -0,0 +1,225 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Localization of the strings the server itself writes for end users: `text/plain` error
+//! bodies, the timestamps of `view.vtt` tracks, and emails. The UI localizes its own strings.
+//!
+//! As with gettext, messages are identified by their English text. A catalog (as loaded from
+//! `--messages`) maps locales to translations of any of them; untranslated messages are served
+//! in English.
+
+use failure::Error;
+use mp4;
+use serde_yaml;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::File;
+use time;
+
+pub struct Catalog {
+    /// Translations by lowercase locale tag, such as `de` or `pt-br`.
+    locales: HashMap<String, HashMap<String, String>>,
+
+    /// The locale for emails and for requests which accept none of `locales`.
+    default_locale: String,
+}
+
+/// A locale chosen from a `Catalog`.
+#[derive(Copy, Clone)]
+pub struct Locale<'a> {
+    tag: &'a str,
+
+    /// The translations, or `None` for English.
+    messages: Option<&'a HashMap<String, String>>,
+}
+
+impl Catalog {
+    /// Returns a catalog without translations.
+    pub fn english() -> Self {
+        Catalog {
+            locales: HashMap::new(),
+            default_locale: "en".to_owned(),
+        }
+    }
+
+    /// Loads a YAML file which maps each locale tag to a map of English messages to their
+    /// translations, as described in `guide/localization.md`.
+    pub fn load(path: &str, default_locale: &str) -> Result<Self, Error> {
+        let f = File::open(path).map_err(|e| format_err!("unable to open {}: {}", path, e))?;
+        let locales: HashMap<String, HashMap<String, String>> = serde_yaml::from_reader(f)?;
+        Catalog::new(locales, default_locale)
+    }
+
+    /// Creates a catalog from translations by locale tag. Fails if the default locale is
+    /// neither `en` nor among them.
+    pub fn new(locales: HashMap<String, HashMap<String, String>>, default_locale: &str)
+               -> Result<Self, Error> {
+        let mut c = Catalog {
+            locales: HashMap::new(),
+            default_locale: default_locale.to_lowercase(),
+        };
+        for (tag, messages) in locales {
+            // Catch bad timestamp formats now rather than failing each `view.vtt` request.
+            if let Some(f) = messages.get(mp4::SUBTITLE_TEMPLATE) {
+                time::now().strftime(f).map_err(|e| format_err!(
+                    "locale {}: bad timestamp format {:?}: {}", tag, f, e))?;
+            }
+            c.locales.insert(tag.to_lowercase(), messages);
+        }
+        if c.locale(&c.default_locale).is_none() {
+            bail!("default locale {} has no translations", c.default_locale);
+        }
+        Ok(c)
+    }
+
+    fn locale(&self, tag: &str) -> Option<Locale> {
+        if let Some((t, m)) = self.locales.iter().find(|&(t, _)| t == tag) {
+            return Some(Locale { tag: t, messages: Some(m) });
+        }
+        if tag == "en" {
+            return Some(Locale { tag: "en", messages: None });
+        }
+        None
+    }
+
+    pub fn default_locale(&self) -> Locale {
+        self.locale(&self.default_locale).expect("default locale was checked by new")
+    }
+
+    /// Chooses the locale best matching an `Accept-Language` header (RFC 7231 section 5.3.5),
+    /// falling back to the default locale. A range such as `de-at` matches the catalog's `de-at`
+    /// or, failing that, its `de`.
+    pub fn negotiate(&self, accept_language: Option<&str>) -> Locale {
+        let mut ranges = Vec::new();
+        for part in accept_language.unwrap_or("").split(',') {
+            let mut params = part.split(';');
+            let range = params.next().unwrap().trim().to_lowercase();
+            let mut q = 1.;
+            for p in params {
+                let p = p.trim();
+                if p.starts_with("q=") {
+                    q = p[2..].parse().unwrap_or(0.);
+                }
+            }
+            if !range.is_empty() && q > 0. {
+                ranges.push((range, q));
+            }
+        }
+        ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        for &(ref range, _) in &ranges {
+            if let Some(l) = self.locale(range) {
+                return l;
+            }
+            if let Some(l) = range.find('-').and_then(|i| self.locale(&range[..i])) {
+                return l;
+            }
+        }
+        self.default_locale()
+    }
+}
+
+impl<'a> Locale<'a> {
+    pub fn tag(&self) -> &'a str { self.tag }
+
+    /// Returns the translation of `msg`, or `msg` itself if there is none.
+    pub fn get<'b>(&self, msg: &'b str) -> &'b str where 'a: 'b {
+        self.messages.and_then(|m| m.get(msg)).map(String::as_str).unwrap_or(msg)
+    }
+
+    /// Translates `msg`, then replaces each `{name}` within it with the corresponding value.
+    pub fn format(&self, msg: &str, args: &[(&str, &str)]) -> String {
+        let mut rest = self.get(msg);
+        let mut out = String::with_capacity(rest.len());
+        while let Some(i) = rest.find('{') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+            let arg = rest.find('}').and_then(
+                |j| args.iter().find(|a| a.0 == &rest[1..j]).map(|a| (j, a.1)));
+            match arg {
+                Some((j, v)) => {
+                    out.push_str(v);
+                    rest = &rest[j+1..];
+                },
+                None => {
+                    out.push('{');
+                    rest = &rest[1..];
+                },
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::Catalog;
+
+    fn catalog() -> Catalog {
+        let mut de = HashMap::new();
+        de.insert("not found".to_owned(), "nicht gefunden".to_owned());
+        de.insert("Clip from {camera}".to_owned(), "Clip von {camera}".to_owned());
+        let mut pt_br = HashMap::new();
+        pt_br.insert("not found".to_owned(), "não encontrado".to_owned());
+        let mut locales = HashMap::new();
+        locales.insert("de".to_owned(), de);
+        locales.insert("pt-BR".to_owned(), pt_br);
+        Catalog::new(locales, "en").unwrap()
+    }
+
+    #[test]
+    fn negotiate() {
+        let c = catalog();
+        let tag = |h: Option<&str>| c.negotiate(h).tag();
+        assert_eq!(tag(None), "en");
+        assert_eq!(tag(Some("de")), "de");
+        assert_eq!(tag(Some("de-AT, en;q=0.5")), "de");
+        assert_eq!(tag(Some("en;q=0.5, pt-br")), "pt-br");
+        assert_eq!(tag(Some("fr, de;q=0")), "en");
+        assert_eq!(tag(Some("fr, *;q=0.1")), "en");
+        assert_eq!(c.negotiate(Some("de")).get("not found"), "nicht gefunden");
+        assert_eq!(c.negotiate(Some("de")).get("POST expected"), "POST expected");
+    }
+
+    #[test]
+    fn format() {
+        let c = catalog();
+        let de = c.negotiate(Some("de"));
+        assert_eq!(de.format("Clip from {camera}", &[("camera", "back {yard}")]),
+                   "Clip von back {yard}");
+        assert_eq!(c.default_locale().format("{a} {b} {", &[("a", "1")]), "1 {b} {");
+    }
+
+    #[test]
+    fn bad_default() {
+        assert!(Catalog::new(HashMap::new(), "de").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! An in-memory ring of recent log records, for `/api/admin/logs`.
+//!
+//! `install` wraps the usual `mylog` logger, additionally keeping the most recent records it
+//! emits so that admins can diagnose problems (such as RTSP failures) from the web UI.
+
+use log::{self, Log};
+use mylog;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::thread;
+use time;
+
+/// The number of records to keep.
+const CAPACITY: usize = 4096;
+
+/// The longest message to keep; longer ones are truncated.
+const MAX_MESSAGE_LEN: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// A sequence number, increasing with each record logged since startup.
+    pub id: u64,
+    pub time: time::Timespec,
+    pub level: log::Level,
+    pub target: String,
+    pub thread: Option<String>,
+    pub message: String,
+}
+
+struct Ring {
+    next_id: u64,
+    records: VecDeque<Record>,
+}
+
+lazy_static! {
+    static ref RING: Mutex<Ring> = Mutex::new(Ring {
+        next_id: 1,
+        records: VecDeque::with_capacity(CAPACITY),
+    });
+}
+
+struct Logger(mylog::Handle);
+
+impl Log for Logger {
+    fn enabled(&self, m: &log::Metadata) -> bool { self.0.enabled(m) }
+
+    fn log(&self, r: &log::Record) {
+        if !self.0.enabled(r.metadata()) {
+            return;
+        }
+        let mut message = String::new();
+        let _ = write!(&mut message, "{}", r.args());
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        let rec = Record {
+            id: 0,
+            time: time::get_time(),
+            level: r.level(),
+            target: r.target().to_owned(),
+            thread: thread::current().name().map(str::to_owned),
+            message,
+        };
+        {
+            let mut l = RING.lock();
+            if l.records.len() == CAPACITY {
+                l.records.pop_front();
+            }
+            let id = l.next_id;
+            l.next_id += 1;
+            l.records.push_back(Record { id, ..rec });
+        }
+        self.0.log(r)
+    }
+
+    fn flush(&self) { self.0.flush() }
+}
+
+/// Installs `h` as the global logger, retaining recent records for `list`.
+pub fn install(h: mylog::Handle) -> Result<(), log::SetLoggerError> {
+    log::set_boxed_logger(Box::new(Logger(h)))?;
+    log::set_max_level(log::LevelFilter::Trace);
+    Ok(())
+}
+
+/// Returns the retained records with id greater than `since` and level at least as severe as
+/// `level`, oldest first.
+pub fn list(level: log::Level, since: u64) -> Vec<Record> {
+    let l = RING.lock();
+    l.records.iter().filter(|r| r.id > since && r.level <= level).cloned().collect()
+}
@@ -30,6 +30,7 @@
 
 #![cfg_attr(all(feature="nightly", test), feature(test))]
 
+extern crate backtrace;
 extern crate bytes;
 extern crate byteorder;
 extern crate core;
@@ -54,10 +55,13 @@ extern crate mylog;
 extern crate openssl;
 extern crate parking_lot;
 extern crate regex;
+extern crate reqwest;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
+extern crate serde_yaml;
 extern crate smallvec;
+extern crate tempdir;
 extern crate time;
 extern crate tokio;
 extern crate tokio_signal;
@@ -66,14 +70,47 @@ extern crate uuid;
 
 use base::clock as clock;
 
+mod analytics;
+mod annotate;
+mod bandwidth;
 mod body;
+mod clips;
 mod cmds;
+mod crash;
+mod email;
+mod embed;
+mod export;
 mod h264;
+mod jobs;
 mod json;
+mod l10n;
+mod logs;
+mod maintenance;
+mod memory;
+mod mosaic;
 mod mp4;
+mod onvif;
+mod push;
+mod reachability;
+mod relocate;
+mod request;
+mod resolve;
+mod simulator;
 mod slices;
+mod snapshot;
+mod sse;
+mod tail;
+mod teardown;
+mod thumbnail;
+mod timelapse;
+mod timesync;
 mod stream;
 mod streamer;
+mod synth;
+mod systemd;
+mod updates;
+mod vendor_events;
+mod vtt;
 mod web;
 
 /// Commandline usage string. This is in the particular format expected by the `docopt` crate.
@@ -88,9 +125,12 @@ Options:
     --version              Show the version of moonfire-nvr.
 
 Commands:
+    bench                  Benchmark the database and .mp4 building
     check                  Check database integrity
     init                   Initialize a database
     run                    Run the daemon: record from cameras and serve HTTP
+    selftest               Check that this machine can record and serve video
+    simulate               Simulate a camera, for development
     shell                  Start an interactive shell to modify the database
     ts                     Translate human-readable and numeric timestamps
     upgrade                Upgrade the database to the latest schema
@@ -127,7 +167,7 @@ fn main() {
                                     .and_then(|d| d.options_first(true)
                                                    .version(Some(version()))
                                                    .deserialize())
-                                    .unwrap_or_else(|e| e.exit());
+                                    .unwrap_or_else(|e| cmds::exit_usage(e));
 
     let mut h = mylog::Builder::new()
         .set_format(::std::env::var("MOONFIRE_FORMAT")
@@ -136,11 +176,12 @@ fn main() {
                     .unwrap_or(mylog::Format::Google))
         .set_spec(&::std::env::var("MOONFIRE_LOG").unwrap_or("info".to_owned()))
         .build();
-    h.clone().install().unwrap();
+    logs::install(h.clone()).unwrap();
 
     if let Err(e) = { let _a = h.async(); args.arg_command.unwrap().run() } {
         error!("{:?}", e);
-        ::std::process::exit(1);
+        let config = e.downcast_ref::<cmds::ConfigError>().is_some();
+        ::std::process::exit(if config { cmds::EXIT_CONFIG } else { 1 });
     }
     info!("Success.");
 }
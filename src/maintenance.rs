@@ -0,0 +1,248 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Maintenance mode (`/api/admin/maintenance`), which quiesces the server so that disks can be
+//! swapped or the database backed up with predictable state. While active, background jobs (such
+//! as exports) and event clip builds wait rather than starting, events aren't recorded, and
+//! recording of selected streams is paused. Once the paused streams have committed their last
+//! recordings and work already in progress has finished, the database is flushed and the state is
+//! marked quiesced.
+
+use db;
+use failure::Error;
+use parking_lot::{Condvar, Mutex};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use teardown;
+
+#[derive(Clone, Debug, Default)]
+pub struct State {
+    /// If maintenance mode is active, when it was entered (in seconds since epoch) and why.
+    pub active: Option<(i64, String)>,
+
+    /// Streams whose recording is paused while maintenance mode is active.
+    pub paused_streams: BTreeSet<i32>,
+
+    /// True once maintenance mode is active and the server is idle: the paused streams are
+    /// settled, no background work is running, and the database has been flushed.
+    pub quiesced: bool,
+
+    /// The number of background tasks (jobs and clip builds) running; see `Maintenance::work`.
+    pub busy: usize,
+
+    /// Incremented on each `enter`, so that a stale `quiesce` thread can tell it's been replaced.
+    generation: u64,
+}
+
+pub struct Maintenance {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// A background task in progress, as returned by `Maintenance::work`.
+pub struct Work<'a>(&'a Maintenance);
+
+impl<'a> Drop for Work<'a> {
+    fn drop(&mut self) {
+        self.0.state.lock().busy -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Maintenance {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Maintenance {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        })
+    }
+
+    pub fn state(&self) -> State { self.state.lock().clone() }
+
+    /// Enters maintenance mode, replacing the set of paused streams if it was already active.
+    /// The caller should then call `quiesce` with the returned generation.
+    pub fn enter(&self, reason: String, paused_streams: BTreeSet<i32>, now_sec: i64) -> u64 {
+        let mut l = self.state.lock();
+        let since = l.active.as_ref().map(|a| a.0).unwrap_or(now_sec);
+        l.active = Some((since, reason));
+        l.paused_streams = paused_streams;
+        l.quiesced = false;
+        l.generation += 1;
+        l.generation
+    }
+
+    /// Exits maintenance mode, resuming paused work. Returns false if it wasn't active.
+    pub fn exit(&self) -> bool {
+        let mut l = self.state.lock();
+        if l.active.is_none() {
+            return false;
+        }
+        l.active = None;
+        l.paused_streams.clear();
+        l.quiesced = false;
+        self.changed.notify_all();
+        true
+    }
+
+    pub fn is_active(&self) -> bool { self.state.lock().active.is_some() }
+
+    pub fn is_stream_paused(&self, stream_id: i32) -> bool {
+        self.state.lock().paused_streams.contains(&stream_id)
+    }
+
+    /// Blocks until maintenance mode is inactive, then marks a background task as running until
+    /// the returned `Work` is dropped. Maintenance mode isn't quiesced while any are running.
+    pub fn work(&self) -> Work {
+        let mut l = self.state.lock();
+        while l.active.is_some() {
+            self.changed.wait(&mut l);
+        }
+        l.busy += 1;
+        Work(self)
+    }
+
+    /// Marks maintenance mode as quiesced, unless it's been exited or re-entered since
+    /// `generation`. Returns true if marked.
+    fn mark_quiesced(&self, generation: u64) -> bool {
+        let mut l = self.state.lock();
+        if l.active.is_none() || l.generation != generation {
+            return false;
+        }
+        l.quiesced = true;
+        true
+    }
+}
+
+/// Starts a thread which waits for the server to become idle after `Maintenance::enter` returned
+/// `generation`, then flushes the database and marks maintenance mode as quiesced. The thread
+/// gives up if maintenance mode is exited or re-entered first.
+pub fn quiesce(m: Arc<Maintenance>, db: Arc<db::Database>, generation: u64)
+               -> Result<(), Error> {
+    thread::Builder::new()
+        .name("maintenance".to_owned())
+        .spawn(move || {
+            loop {
+                let s = m.state();
+                if s.active.is_none() || s.generation != generation {
+                    return;
+                }
+                let streams: Vec<i32> = s.paused_streams.iter().cloned().collect();
+                let r = {
+                    let mut l = db.lock();
+                    teardown::poll_settled(&mut l, &streams).and_then(|settled| {
+                        if !settled || s.busy > 0 {
+                            return Ok(false);
+                        }
+                        l.flush("maintenance")?;
+                        Ok(true)
+                    })
+                };
+                match r {
+                    Ok(true) => {
+                        if m.mark_quiesced(generation) {
+                            info!(target: "audit", "maintenance mode: quiesced");
+                        }
+                        return;
+                    },
+                    Ok(false) => {},
+                    Err(e) => warn!(target: "audit", "maintenance mode: unable to flush: {}", e),
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use super::Maintenance;
+
+    #[test]
+    fn enter_and_exit() {
+        let m = Maintenance::new();
+        assert!(!m.is_active());
+        assert!(!m.exit());
+        drop(m.work());  // shouldn't block.
+
+        assert_eq!(m.enter("backup".to_owned(), vec![1].into_iter().collect(), 42), 1);
+        assert_eq!(m.enter("disk swap".to_owned(), vec![2].into_iter().collect(), 43), 2);
+        assert_eq!(m.state().active, Some((42, "disk swap".to_owned())));
+        assert!(!m.is_stream_paused(1));
+        assert!(m.is_stream_paused(2));
+
+        let (tx, rx) = mpsc::channel();
+        let waiter = thread::spawn({
+            let m = m.clone();
+            move || {
+                drop(m.work());
+                tx.send(()).unwrap();
+            }
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        assert!(m.exit());
+        rx.recv().unwrap();
+        waiter.join().unwrap();
+        assert!(!m.is_stream_paused(2));
+    }
+
+    #[test]
+    fn work() {
+        let m = Maintenance::new();
+        let w = m.work();  // shouldn't block.
+        let g = m.enter("backup".to_owned(), Default::default(), 42);
+        assert_eq!(m.state().busy, 1);
+        assert!(!m.mark_quiesced(g + 1));  // stale generation.
+        assert!(m.mark_quiesced(g));
+        assert!(m.state().quiesced);
+
+        // New work waits for maintenance mode to end.
+        let (tx, rx) = mpsc::channel();
+        let worker = thread::spawn({
+            let m = m.clone();
+            move || {
+                let _w = m.work();
+                tx.send(()).unwrap();
+            }
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        drop(w);
+        assert_eq!(m.state().busy, 0);
+        assert!(m.exit());
+        assert!(!m.state().quiesced);
+        rx.recv().unwrap();
+        worker.join().unwrap();
+        assert_eq!(m.state().busy, 0);
+    }
+}
@@ -0,0 +1,178 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A global budget for memory held by in-flight responses, chiefly `.mp4` files' indexes and
+//! other builder state (see `mp4::FileBuilder::build`). Without one, a burst of requests for
+//! long exports could exhaust a small device's memory, and the OOM killer might take the
+//! recorders with it.
+//!
+//! `BUDGET` is unlimited until `Budget::set_limit` is called.
+
+use failure::{Error, Fail};
+use parking_lot::{Condvar, Mutex};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+struct State {
+    /// The limit in bytes, or 0 for unlimited.
+    limit: usize,
+    used: usize,
+    rejected: u64,
+}
+
+pub struct Budget {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+lazy_static! {
+    /// The budget shared by all responses.
+    pub static ref BUDGET: Budget = Budget::new();
+}
+
+/// An error returned when a reservation doesn't fit within the budget. The web interface
+/// responds to these with `503 Service Unavailable`, so clients can retry later.
+#[derive(Debug)]
+pub struct OverBudget {
+    pub requested: usize,
+    pub used: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes requested with {} of {} bytes of the memory budget in use",
+               self.requested, self.used, self.limit)
+    }
+}
+
+impl Fail for OverBudget {}
+
+/// Bytes reserved from a budget, returned when dropped.
+pub struct Reservation<'a> {
+    budget: &'a Budget,
+    bytes: usize,
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        self.budget.state.lock().used -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub limit: usize,
+    pub used: usize,
+    pub rejected: u64,
+}
+
+impl Budget {
+    pub fn new() -> Self {
+        Budget {
+            state: Mutex::new(State {
+                limit: 0,
+                used: 0,
+                rejected: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Sets the limit in bytes, or 0 for unlimited. Existing reservations are unaffected.
+    pub fn set_limit(&self, limit: usize) {
+        self.state.lock().limit = limit;
+        self.released.notify_all();
+    }
+
+    /// Reserves `bytes`, waiting up to `wait` for other reservations to be returned if
+    /// necessary. On failure, returns an `OverBudget`.
+    pub fn reserve(&self, bytes: usize, wait: Duration) -> Result<Reservation, Error> {
+        let deadline = Instant::now() + wait;
+        let mut l = self.state.lock();
+        loop {
+            if l.limit == 0 || l.used + bytes <= l.limit {
+                l.used += bytes;
+                return Ok(Reservation {
+                    budget: self,
+                    bytes,
+                });
+            }
+
+            // A reservation larger than the whole limit will never fit; don't bother waiting.
+            if bytes > l.limit || self.released.wait_until(&mut l, deadline).timed_out() {
+                l.rejected += 1;
+                return Err(OverBudget {
+                    requested: bytes,
+                    used: l.used,
+                    limit: l.limit,
+                }.into());
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let l = self.state.lock();
+        Metrics {
+            limit: l.limit,
+            used: l.used,
+            rejected: l.rejected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn reserve_and_release() {
+        let budget: &'static Budget = Box::leak(Box::new(Budget::new()));
+        budget.set_limit(100);
+        let a = budget.reserve(60, Duration::from_secs(0)).unwrap();
+        let e = budget.reserve(50, Duration::from_secs(0)).unwrap_err();
+        assert!(e.downcast_ref::<OverBudget>().is_some());
+        budget.reserve(101, Duration::from_secs(60)).unwrap_err();  // returns immediately.
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            drop(a);
+        });
+        let b = budget.reserve(50, Duration::from_secs(60)).unwrap();
+        t.join().unwrap();
+        let m = budget.metrics();
+        assert_eq!(m.used, 50);
+        assert_eq!(m.rejected, 2);
+        drop(b);
+        assert_eq!(budget.metrics().used, 0);
+    }
+}
@@ -0,0 +1,255 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Server-side multi-camera mosaic, as in `/api/mosaic.mjpeg`. See `design/api.md` for details.
+//!
+//! Moonfire NVR doesn't otherwise decode video, so compositing is delegated to an external
+//! `ffmpeg` binary. It's given each camera's RTSP URL and produces a single Motion JPEG stream,
+//! which is suitable for TVs and old set-top boxes that can only decode one stream at a time.
+//! The URLs include the cameras' credentials, so they're passed within a filter graph script on
+//! ffmpeg's standard input rather than on its command line, which any local user can read.
+
+use base::sched;
+use body::{BodyStream, BoxedError, Chunk};
+use failure::Error;
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+
+/// The `Content-Type` of a mosaic stream, matching the boundary used by ffmpeg's `mpjpeg` muxer.
+pub const CONTENT_TYPE: &'static str = "multipart/x-mixed-replace;boundary=ffmpeg";
+
+/// The maximum number of inputs to a single mosaic.
+pub const MAX_INPUTS: usize = 16;
+
+/// The maximum number of mosaics (each an `ffmpeg` process decoding every input) at once.
+const MAX_MOSAICS: usize = 4;
+
+/// The number of mosaics currently running.
+static RUNNING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A reservation of one of the `MAX_MOSAICS` slots, released on drop.
+struct Slot;
+
+impl Slot {
+    fn acquire() -> Option<Slot> {
+        if RUNNING.fetch_add(1, Ordering::SeqCst) >= MAX_MOSAICS {
+            RUNNING.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) { RUNNING.fetch_sub(1, Ordering::SeqCst); }
+}
+
+/// Size of the tile for each input, in pixels.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TileSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for TileSize {
+    fn default() -> Self { TileSize { width: 640, height: 360 } }
+}
+
+/// Returns the number of columns and rows in a grid of `n` tiles, as close to square as possible.
+fn grid(n: usize) -> (usize, usize) {
+    let mut cols = 1;
+    while cols * cols < n {
+        cols += 1;
+    }
+    (cols, (n + cols - 1) / cols)
+}
+
+/// Returns `s` with a backslash before each of `special`, backslashes, and single quotes, as
+/// ffmpeg's `av_get_token` expects.
+fn escape(s: &str, special: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || c == '\'' || special.contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Returns the ffmpeg arguments to composite a grid, written as Motion JPEG to stdout. The
+/// filter graph (see `filter`) is read from stdin.
+fn args(fps: u32) -> Vec<String> {
+    let mut a: Vec<String> = vec!["-nostdin".into(), "-loglevel".into(), "error".into(),
+                                  "-filter_complex_script".into(), "pipe:0".into()];
+    a.extend_from_slice(&["-map".into(), "[out]".into(), "-an".into(), "-r".into(),
+                          fps.to_string(), "-q:v".into(), "5".into(), "-f".into(),
+                          "mpjpeg".into(), "pipe:1".into()]);
+    a
+}
+
+/// Returns the filter graph which reads `urls` (via `movie` sources) and composites them into a
+/// grid labelled `[out]`.
+fn filter(urls: &[String], tile: TileSize) -> String {
+    let mut filter = String::new();
+    for (i, u) in urls.iter().enumerate() {
+        // Use TCP, via the RTSP URL option ffmpeg strips before connecting.
+        let u = format!("{}{}tcp", u, if u.contains('?') { '&' } else { '?' });
+
+        // The URL is an option value within a filter within the graph; escape for both.
+        let u = escape(&escape(&u, ":="), "[],;");
+        filter.push_str(&format!("movie=filename={},scale={}:{},setsar=1[v{}];",
+                                 u, tile.width, tile.height, i));
+    }
+    if urls.len() == 1 {
+        filter.push_str("[v0]null[out]");
+    } else {
+        let (cols, _) = grid(urls.len());
+        let mut layout = Vec::with_capacity(urls.len());
+        for i in 0 .. urls.len() {
+            filter.push_str(&format!("[v{}]", i));
+            layout.push(format!("{}_{}", (i % cols) as u32 * tile.width,
+                                (i / cols) as u32 * tile.height));
+        }
+        filter.push_str(&format!("xstack=inputs={}:layout={}[out]",
+                                 urls.len(), layout.join("|")));
+    }
+    filter
+}
+
+/// Starts compositing the given RTSP URLs, returning a body of `CONTENT_TYPE`, or `None` if
+/// `MAX_MOSAICS` are already running. The `ffmpeg` process is killed once the body is dropped.
+pub fn start(ffmpeg: &PathBuf, urls: &[String], tile: TileSize, fps: u32)
+             -> Result<Option<BodyStream>, Error> {
+    if urls.is_empty() || urls.len() > MAX_INPUTS {
+        bail!("mosaic must have between 1 and {} inputs; got {}", MAX_INPUTS, urls.len());
+    }
+    let slot = match Slot::acquire() {
+        None => return Ok(None),
+        Some(s) => s,
+    };
+    let mut child = Command::new(ffmpeg)
+        .args(&args(fps))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format_err!("unable to run {}: {}", ffmpeg.display(), e))?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+
+    // The script is small enough to fit in the pipe's buffer, so this doesn't block on ffmpeg.
+    // Closing stdin marks its end.
+    let r = child.stdin.take().expect("stdin is piped").write_all(filter(urls, tile).as_bytes());
+    if let Err(e) = r {
+        let _ = child.kill();
+        let _ = child.wait();
+        bail!("unable to write filter graph to ffmpeg: {}", e);
+    }
+
+    // A small bounded channel, so that a slow client applies backpressure to ffmpeg rather than
+    // causing unbounded buffering.
+    let (mut tx, rx) = mpsc::channel(4);
+    thread::Builder::new()
+        .name("mosaic".to_owned())
+        .spawn(move || {
+            let _slot = slot;
+            sched::enter(sched::Class::Web);
+            let mut buf = [0u8; 65536];
+            loop {
+                let n = match stdout.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) => {
+                        warn!("mosaic: error reading from ffmpeg: {}", e);
+                        break;
+                    },
+                };
+                tx = match tx.send(Chunk::from(buf[..n].to_vec())).wait() {
+                    Ok(tx) => tx,
+                    Err(_) => break,  // client went away.
+                };
+            }
+            let _ = child.kill();
+            match child.wait() {
+                Ok(s) => debug!("mosaic: ffmpeg exited with {}", s),
+                Err(e) => warn!("mosaic: unable to wait for ffmpeg: {}", e),
+            }
+        })?;
+    Ok(Some(Box::new(rx.map_err(|()| -> BoxedError { unreachable!() }))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Slot, TileSize, args, escape, filter, grid, MAX_MOSAICS};
+
+    #[test]
+    fn test_grid() {
+        assert_eq!(grid(1), (1, 1));
+        assert_eq!(grid(2), (2, 1));
+        assert_eq!(grid(4), (2, 2));
+        assert_eq!(grid(5), (3, 2));
+        assert_eq!(grid(9), (3, 3));
+        assert_eq!(grid(10), (4, 3));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("rtsp://u:p@h/a?b=c", ":="), "rtsp\\://u\\:p@h/a?b\\=c");
+        assert_eq!(escape("it's [a,b];\\", "[],;"), "it\\'s \\[a\\,b\\]\\;\\\\");
+    }
+
+    #[test]
+    fn test_filter() {
+        let urls = vec!["rtsp://u:p@a/".to_owned(), "rtsp://b/?x=1".to_owned(),
+                        "rtsp://c/".to_owned()];
+        assert_eq!(filter(&urls, TileSize { width: 320, height: 180 }),
+                   "movie=filename=rtsp\\\\://u\\\\:p@a/?tcp,scale=320:180,setsar=1[v0];\
+                    movie=filename=rtsp\\\\://b/?x\\\\=1&tcp,scale=320:180,setsar=1[v1];\
+                    movie=filename=rtsp\\\\://c/?tcp,scale=320:180,setsar=1[v2];\
+                    [v0][v1][v2]xstack=inputs=3:layout=0_0|320_0|0_180[out]");
+
+        let a = args(5);
+        let i = a.iter().position(|a| a == "-filter_complex_script").unwrap();
+        assert_eq!(a[i+1], "pipe:0");
+        assert_eq!(&a[a.len() - 3 ..], &["-f", "mpjpeg", "pipe:1"]);
+    }
+
+    #[test]
+    fn test_slots() {
+        let slots: Vec<_> = (0 .. MAX_MOSAICS).map(|_| Slot::acquire().unwrap()).collect();
+        assert!(Slot::acquire().is_none());
+        drop(slots);
+        assert!(Slot::acquire().is_some());
+    }
+}
@@ -73,12 +73,19 @@
 //! ***** stsz (samples sizes (framing))
 //! ***** co64 (64-bit chunk offset)
 //!
+//! ** (optional) udta (user data container)
+//! *** (optional) chpl (chapter list)
+//! *** (optional) meta (metadata: the NVR, camera, and software which produced the file)
+//! **** hdlr (handler)
+//! **** ilst (metadata item list)
+//!
 //! * mdat (media data container)
 //! ```
 
 extern crate time;
 
 use base::strutil;
+use base::trace;
 use bytes::{Buf, BytesMut};
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use body::{Chunk, BoxedError, wrap_error};
@@ -87,23 +94,27 @@ use db::{self, dir};
 use failure::Error;
 use futures::Stream;
 use futures::stream;
+use futures_cpupool;
 use http;
 use http::header::HeaderValue;
 use http_serve;
 use memmap;
+use memory;
 use openssl::hash;
 use parking_lot::{Once, ONCE_INIT};
 use reffers::ARefs;
 use slices::{self, Slices};
 use smallvec::SmallVec;
-use std::cell::UnsafeCell;
+use std::cell::{RefCell, UnsafeCell};
 use std::cmp;
 use std::fmt;
 use std::io;
 use std::ops::Range;
 use std::mem;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
 
 /// This value should be incremented any time a change is made to this file that causes different
 /// bytes to be output for a particular set of `Mp4Builder` options. Incrementing this value will
@@ -326,11 +337,33 @@ enum StaticBytestring {
 /// The template fed into strtime for a timestamp subtitle. This must produce fixed-length output
 /// (see `SUBTITLE_LENGTH`) to allow quick calculation of the total size of the subtitles for
 /// a given time range.
-const SUBTITLE_TEMPLATE: &'static str = "%Y-%m-%d %H:%M:%S %z";
+pub const SUBTITLE_TEMPLATE: &'static str = "%Y-%m-%d %H:%M:%S %z";
 
 /// The length of the output of `SUBTITLE_TEMPLATE`.
 const SUBTITLE_LENGTH: usize = 25;  // "2015-07-02 17:10:00 -0700".len();
 
+/// The maximum length of a watermark; see `FileBuilder::watermark`.
+const MAX_WATERMARK_LENGTH: usize = 256;
+
+/// `.mp4` files with at least this many segments have their indexes built in parallel; see
+/// `FileInner::prebuild_indexes`.
+const PARALLEL_INDEX_MIN_SEGMENTS: usize = 32;
+
+/// The number of threads building such files' indexes, shared by all files.
+const PARALLEL_INDEX_THREADS: usize = 4;
+
+lazy_static! {
+    static ref INDEX_POOL: futures_cpupool::CpuPool =
+        futures_cpupool::Builder::new().pool_size(PARALLEL_INDEX_THREADS)
+                                       .name_prefix("mp4-index-")
+                                       .create();
+}
+
+/// Returns the length of each subtitle sample, including its 16-bit length prefix.
+fn subtitle_sample_len(watermark: &Option<String>) -> usize {
+    mem::size_of::<u16>() + SUBTITLE_LENGTH + watermark.as_ref().map(|w| 1 + w.len()).unwrap_or(0)
+}
+
 /// The lengths of the indexes associated with a `Segment`; for use within `Segment` only.
 struct SegmentLengths {
     stts: usize,
@@ -338,10 +371,25 @@ struct SegmentLengths {
     stss: usize,
 }
 
+/// A key frame within a `Segment` of a key-frame-only file; see `FileBuilder::key_frames_only`.
+#[derive(Debug)]
+struct KeyFrame {
+    /// The starting byte position of this frame within the sample file.
+    pos: u64,
+    bytes: u32,
+    start_90k: i32,
+
+    /// The duration until the next key frame or the end of the desired range.
+    duration_90k: i32,
+}
+
 /// A wrapper around `recording::Segment` that keeps some additional `.mp4`-specific state.
 struct Segment {
     s: recording::Segment,
 
+    /// If the file is key-frame-only, the key frames of this segment.
+    key_frames: Option<Box<[KeyFrame]>>,
+
     /// If generated, the `.mp4`-format sample indexes, accessed only through `get_index`:
     ///    1. stts: `slice[.. stsz_start]`
     ///    2. stsz: `slice[stsz_start .. stss_start]`
@@ -352,6 +400,14 @@ struct Segment {
     first_frame_num: u32,
     num_subtitle_samples: u16,
 
+    /// If the recording was still being written when this segment was created. Its frames and
+    /// length are then part of the etag, as they may differ for the same desired range later.
+    growing: bool,
+
+    /// If this segment begins partway through a GOP (see `FileBuilder::append_continuation`).
+    /// Its leading non-key frames then get a `trun` of their own.
+    partial_gop: bool,
+
     index_once: Once,
 }
 
@@ -362,30 +418,112 @@ impl fmt::Debug for Segment {
            .field("s", &self.s)
            .field("first_frame_num", &self.first_frame_num)
            .field("num_subtitle_samples", &self.num_subtitle_samples)
+           .field("growing", &self.growing)
            .finish()
     }
 }
 
 unsafe impl Sync for Segment {}
 
+thread_local! {
+    /// A buffer for `recording::Segment::foreach_chunk`, reused so building indexes and `trun`
+    /// boxes doesn't allocate.
+    static FRAMES: RefCell<Vec<recording::Frame>> =
+        RefCell::new(Vec::with_capacity(recording::FRAME_CHUNK));
+}
+
+/// Calls `f` with this thread's frame buffer.
+fn with_frames<R, F: FnOnce(&mut Vec<recording::Frame>) -> R>(f: F) -> R {
+    FRAMES.with(|b| f(&mut b.borrow_mut()))
+}
+
 impl Segment {
     fn new(db: &db::LockedDatabase, row: &db::ListRecordingsRow, rel_range_90k: Range<i32>,
-           first_frame_num: u32) -> Result<Self, Error> {
+           first_frame_num: u32, key_frames_only: bool, continuation: bool)
+           -> Result<Self, Error> {
+        let s = if continuation {
+            recording::Segment::continuation(db, row, rel_range_90k)?
+        } else {
+            recording::Segment::new(db, row, rel_range_90k)?
+        };
+        let partial_gop = !s.begins_with_key_frame();
+        let key_frames = if key_frames_only {
+            Some(db.with_recording_playback(s.id, &mut |playback| {
+                Segment::find_key_frames(&s, playback)
+            })?)
+        } else {
+            None
+        };
         Ok(Segment{
-            s: recording::Segment::new(db, row, rel_range_90k)?,
+            s,
+            key_frames,
             index: UnsafeCell::new(Err(())),
             index_once: ONCE_INIT,
             first_frame_num,
             num_subtitle_samples: 0,
+            growing: (row.flags & (db::RecordingFlags::Uncommitted as i32 |
+                                   db::RecordingFlags::Growing as i32)) != 0,
+            partial_gop,
         })
     }
 
+    /// Finds the key frames within `s`, stretching each to last until the next.
+    fn find_key_frames(s: &recording::Segment, playback: &db::RecordingPlayback)
+                       -> Result<Box<[KeyFrame]>, Error> {
+        let mut key_frames: Vec<KeyFrame> = Vec::with_capacity(s.key_frames as usize);
+        let mut end_90k = 0;
+        s.foreach(playback, |it| {
+            if it.is_key() {
+                if let Some(prev) = key_frames.last_mut() {
+                    prev.duration_90k = it.start_90k - prev.start_90k;
+                }
+                key_frames.push(KeyFrame {
+                    pos: it.pos as u64,
+                    bytes: it.bytes as u32,
+                    start_90k: it.start_90k,
+                    duration_90k: 0,
+                });
+            }
+            end_90k = it.start_90k + it.duration_90k;
+            Ok(())
+        })?;
+        if let Some(last) = key_frames.last_mut() {
+            last.duration_90k = cmp::min(s.desired_range_90k.end, end_90k) - last.start_90k;
+        }
+        Ok(key_frames.into_boxed_slice())
+    }
+
+    /// Returns the number of frames this segment contributes to the file.
+    fn frames(&self) -> u16 {
+        match self.key_frames {
+            Some(ref k) => k.len() as u16,
+            None => self.s.frames,
+        }
+    }
+
+    /// Returns the number of bytes of video sample data this segment contributes to the file.
+    fn data_len(&self) -> u64 {
+        match self.key_frames {
+            Some(ref k) => k.iter().map(|k| k.bytes as u64).sum(),
+            None => {
+                let r = self.s.sample_file_range();
+                r.end - r.start
+            },
+        }
+    }
+
     fn get_index<'a, F>(&'a self, db: &db::Database, f: F) -> Result<&'a [u8], Error>
     where F: FnOnce(&[u8], SegmentLengths) -> &[u8] {
         self.index_once.call_once(|| {
             let index = unsafe { &mut *self.index.get() };
-            *index = db.lock()
-                .with_recording_playback(self.s.id, &mut |playback| self.build_index(playback))
+
+            // Build from a copy of the video index, so that other segments' indexes can be
+            // built in parallel (see `FileInner::prebuild_indexes`) rather than serially under
+            // the database lock.
+            let video_index = db.lock().with_recording_playback(
+                self.s.id, &mut |playback| Ok(playback.video_index.to_vec()));
+            *index = video_index
+                .and_then(|v| self.build_index(&db::RecordingPlayback { video_index: &v }))
                 .map_err(|e| { error!("Unable to build index for segment: {:?}", e); });
         });
         let index: &'a _ = unsafe { &*self.index.get() };
@@ -397,8 +535,8 @@ impl Segment {
 
     fn lens(&self) -> SegmentLengths {
         SegmentLengths {
-            stts: mem::size_of::<u32>() * 2 * (self.s.frames as usize),
-            stsz: mem::size_of::<u32>() * self.s.frames as usize,
+            stts: mem::size_of::<u32>() * 2 * (self.frames() as usize),
+            stsz: mem::size_of::<u32>() * self.frames() as usize,
             stss: mem::size_of::<u32>() * self.s.key_frames as usize,
         }
     }
@@ -417,41 +555,102 @@ impl Segment {
             v.into_boxed_slice()
         };
 
+        if let Some(ref key_frames) = self.key_frames {
+            let (stts, rest) = buf.split_at_mut(lens.stts);
+            let (stsz, stss) = rest.split_at_mut(lens.stsz);
+            for (i, k) in key_frames.iter().enumerate() {
+                BigEndian::write_u32(&mut stts[8*i .. 8*i+4], 1);
+                BigEndian::write_u32(&mut stts[8*i+4 .. 8*i+8], k.duration_90k as u32);
+                BigEndian::write_u32(&mut stsz[4*i .. 4*i+4], k.bytes);
+                BigEndian::write_u32(&mut stss[4*i .. 4*i+4], self.first_frame_num + (i as u32));
+            }
+            return Ok(buf);
+        }
+
+        let mut frame = 0;
+        let mut last_start_and_dur = None;
         {
             let (stts, rest) = buf.split_at_mut(lens.stts);
             let (stsz, stss) = rest.split_at_mut(lens.stsz);
-            let mut frame = 0;
-            let mut key_frame = 0;
-            let mut last_start_and_dur = None;
-            s.foreach(playback, |it| {
-                last_start_and_dur = Some((it.start_90k, it.duration_90k));
-                BigEndian::write_u32(&mut stts[8*frame .. 8*frame+4], 1);
-                BigEndian::write_u32(&mut stts[8*frame+4 .. 8*frame+8], it.duration_90k as u32);
-                BigEndian::write_u32(&mut stsz[4*frame .. 4*frame+4], it.bytes as u32);
-                if it.is_key() {
-                    BigEndian::write_u32(&mut stss[4*key_frame .. 4*key_frame+4],
-                                         self.first_frame_num + (frame as u32));
-                    key_frame += 1;
+
+            // `foreach` checks the frame and key frame counts match the segment's, which
+            // determined the lengths of these slices, so these iterators can't run out.
+            let mut stts = stts.chunks_mut(8);
+            let mut stsz = stsz.chunks_mut(4);
+            let mut stss = stss.chunks_mut(4);
+            with_frames(|frames| s.foreach_chunk(playback, frames, |chunk| {
+                for f in chunk {
+                    let t = stts.next().unwrap();
+                    BigEndian::write_u32(&mut t[.. 4], 1);
+                    BigEndian::write_u32(&mut t[4 ..], f.duration_90k as u32);
+                    BigEndian::write_u32(stsz.next().unwrap(), f.bytes as u32);
+                    if f.is_key {
+                        BigEndian::write_u32(stss.next().unwrap(),
+                                             self.first_frame_num + (frame as u32));
+                    }
+                    frame += 1;
                 }
-                frame += 1;
+                let last = &chunk[chunk.len() - 1];
+                last_start_and_dur = Some((last.start_90k, last.duration_90k));
                 Ok(())
-            })?;
+            }))?;
+        }
 
-            // Fix up the final frame's duration.
-            // Doing this after the fact is more efficient than having a condition on every
-            // iteration.
-            if let Some((last_start, dur)) = last_start_and_dur {
-                BigEndian::write_u32(&mut stts[8*frame-4 ..],
-                                     cmp::min(s.desired_range_90k.end - last_start, dur) as u32);
-            }
+        // Fix up the final frame's duration.
+        // Doing this after the fact is more efficient than having a condition on every
+        // iteration.
+        if let Some((last_start, dur)) = last_start_and_dur {
+            BigEndian::write_u32(&mut buf[8*frame-4 ..],
+                                 cmp::min(s.desired_range_90k.end - last_start, dur) as u32);
         }
 
         Ok(buf)
     }
 
     fn truns_len(&self) -> usize {
-        (self.s.key_frames as usize) * (mem::size_of::<u32>() * 6) +
-        (    self.s.frames as usize) * (mem::size_of::<u32>() * 2)
+        (self.s.key_frames as usize + self.partial_gop as usize) * (mem::size_of::<u32>() * 6) +
+        (    self.frames() as usize) * (mem::size_of::<u32>() * 2)
+    }
+
+    /// Writes the start of a `trun` box beginning at `data_pos`. Its first sample is a key frame
+    /// unless the segment is a continuation beginning partway through a GOP.
+    /// Returns the position of the sample count, to be filled in by the caller.
+    fn append_trun_header(v: &mut Vec<u8>, data_pos: u64, is_key: bool)
+                          -> Result<usize, Error> {
+        v.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x00,  // placeholder for size
+            b't', b'r', b'u', b'n',
+
+            // version 0, tr_flags:
+            // 0x000001 data-offset-present
+            // 0x000004 first-sample-flags-present
+            // 0x000100 sample-duration-present
+            // 0x000200 sample-size-present
+            0x00, 0x00, 0x03, 0x05,
+            ]);
+        let sample_count_pos = v.len();
+        v.write_u32::<BigEndian>(0)?;  // placeholder for sample count
+        v.write_u32::<BigEndian>(data_pos as u32)?;
+
+        // first_sample_flags. See trex (8.8.3.1).
+        if !is_key {
+            v.write_u32::<BigEndian>(
+                (1 << 24) |  // sample_depends_on: this sample depends on others
+                (1 << 16) |  // sample_is_non_sync_sample=1
+                0)?;
+            return Ok(sample_count_pos);
+        }
+        v.write_u32::<BigEndian>(
+            // As defined by the Independent and Disposable Samples Box (sdp, 8.6.4).
+            (2 << 26) |  // is_leading: this sample is not a leading sample
+            (2 << 24) |  // sample_depends_on: this sample does not depend on others
+            (1 << 22) |  // sample_is_depend_on: others may depend on this one
+            (2 << 20) |  // sample_has_redundancy: no redundant coding
+            // As defined by the sample padding bits (padb, 8.7.6).
+            (0 << 17) |  // no padding
+            (0 << 16) |  // sample_is_non_sync_sample=0
+            0)?;         // TODO: sample_degradation_priority
+        Ok(sample_count_pos)
     }
 
     // TrackRunBox / trun (8.8.8).
@@ -459,6 +658,23 @@ impl Segment {
              -> Result<Vec<u8>, Error> {
         let mut v = Vec::with_capacity(len);
 
+        if let Some(ref key_frames) = self.key_frames {
+            // Each sample is a key frame and so gets a run of its own.
+            let mut data_pos = initial_pos;
+            for k in key_frames.iter() {
+                let box_len_pos = v.len();
+                let sample_count_pos = Segment::append_trun_header(&mut v, data_pos, true)?;
+                v.write_u32::<BigEndian>(k.duration_90k as u32)?;
+                v.write_u32::<BigEndian>(k.bytes)?;
+                let p = v.len();
+                BigEndian::write_u32(&mut v[box_len_pos .. box_len_pos + 4],
+                                     (p - box_len_pos) as u32);
+                BigEndian::write_u32(&mut v[sample_count_pos .. sample_count_pos + 4], 1);
+                data_pos += k.bytes as u64;
+            }
+            return Ok(v);
+        }
+
         struct RunInfo {
             box_len_pos: usize,
             sample_count_pos: usize,
@@ -468,60 +684,43 @@ impl Segment {
         }
         let mut run_info: Option<RunInfo> = None;
         let mut data_pos = initial_pos;
-        self.s.foreach(playback, |it| {
-            if it.is_key() {
-                if let Some(r) = run_info.take() {
-                    // Finish a non-terminal run.
-                    let p = v.len();
-                    BigEndian::write_u32(&mut v[r.box_len_pos .. r.box_len_pos + 4],
-                                         (p - r.box_len_pos) as u32);
-                    BigEndian::write_u32(&mut v[r.sample_count_pos .. r.sample_count_pos + 4],
-                                         r.count);
+        with_frames(|frames| self.s.foreach_chunk(playback, frames, |chunk| {
+            for f in chunk {
+                if f.is_key || run_info.is_none() {
+                    if let Some(r) = run_info.take() {
+                        // Finish a non-terminal run.
+                        let p = v.len();
+                        BigEndian::write_u32(&mut v[r.box_len_pos .. r.box_len_pos + 4],
+                                             (p - r.box_len_pos) as u32);
+                        BigEndian::write_u32(
+                            &mut v[r.sample_count_pos .. r.sample_count_pos + 4], r.count);
+                    }
+                    let box_len_pos = v.len();
+                    let sample_count_pos =
+                        Segment::append_trun_header(&mut v, data_pos, f.is_key)?;
+                    run_info = Some(RunInfo {
+                        box_len_pos,
+                        sample_count_pos,
+                        count: 1,
+                        last_start: f.start_90k,
+                        last_dur: f.duration_90k,
+                    });
+                } else {
+                    let r = run_info.as_mut().unwrap();
+                    r.count += 1;
+                    r.last_start = f.start_90k;
+                    r.last_dur = f.duration_90k;
                 }
-                let box_len_pos = v.len();
-                v.extend_from_slice(&[
-                    0x00, 0x00, 0x00, 0x00,  // placeholder for size
-                    b't', b'r', b'u', b'n',
-
-                    // version 0, tr_flags:
-                    // 0x000001 data-offset-present
-                    // 0x000004 first-sample-flags-present
-                    // 0x000100 sample-duration-present
-                    // 0x000200 sample-size-present
-                    0x00, 0x00, 0x03, 0x05,
-                    ]);
-                run_info = Some(RunInfo {
-                    box_len_pos,
-                    sample_count_pos: v.len(),
-                    count: 1,
-                    last_start: it.start_90k,
-                    last_dur: it.duration_90k,
-                });
-                v.write_u32::<BigEndian>(0)?;  // placeholder for sample count
-                v.write_u32::<BigEndian>(data_pos as u32)?;
-
-                // first_sample_flags. See trex (8.8.3.1).
-                v.write_u32::<BigEndian>(
-                    // As defined by the Independent and Disposable Samples Box (sdp, 8.6.4).
-                    (2 << 26) |  // is_leading: this sample is not a leading sample
-                    (2 << 24) |  // sample_depends_on: this sample does not depend on others
-                    (1 << 22) |  // sample_is_depend_on: others may depend on this one
-                    (2 << 20) |  // sample_has_redundancy: no redundant coding
-                    // As defined by the sample padding bits (padb, 8.7.6).
-                    (0 << 17) |  // no padding
-                    (0 << 16) |  // sample_is_non_sync_sample=0
-                    0)?;         // TODO: sample_degradation_priority
-            } else {
-                let r = run_info.as_mut().expect("non-key sample must be preceded by key sample");
-                r.count += 1;
-                r.last_start = it.start_90k;
-                r.last_dur = it.duration_90k;
+
+                // Write both fields at once, rather than through two `io::Write` calls.
+                let mut sample = [0u8; 8];
+                BigEndian::write_u32(&mut sample[.. 4], f.duration_90k as u32);
+                BigEndian::write_u32(&mut sample[4 ..], f.bytes as u32);
+                v.extend_from_slice(&sample);
+                data_pos += f.bytes as u64;
             }
-            v.write_u32::<BigEndian>(it.duration_90k as u32)?;
-            v.write_u32::<BigEndian>(it.bytes as u32)?;
-            data_pos += it.bytes as u64;
             Ok(())
-        })?;
+        }))?;
         if let Some(r) = run_info.take() {
             // Finish the run as in the non-terminal case above.
             let p = v.len();
@@ -553,8 +752,35 @@ pub struct FileBuilder {
     body: BodyState,
     type_: Type,
     include_timestamp_subtitle_track: bool,
+    watermark: Option<String>,
+    key_frames_only: bool,
+    chapters: Vec<Chapter>,
+    origin: Option<Origin>,
+    memory_wait: Duration,
+}
+
+/// Where a file's video came from, as set by `FileBuilder::append_origin`.
+#[derive(Clone, Copy, Debug)]
+struct Origin {
+    /// The database's UUID, which identifies the NVR.
+    server_uuid: Uuid,
+    camera_uuid: Uuid,
+}
+
+/// The `mean` (namespace) of the custom metadata items written for `Origin`.
+const ORIGIN_MEAN: &[u8] = b"com.github.scottlamb.moonfire-nvr";
+
+/// A chapter marker, as added by `FileBuilder::append_event_chapters`.
+#[derive(Debug)]
+struct Chapter {
+    /// The start of the chapter, in 90 kHz units relative to the start of the file.
+    start_90k: i64,
+    title: String,
 }
 
+/// The maximum number of chapters, limited by the 8-bit count in the `chpl` box.
+const MAX_CHAPTERS: usize = 255;
+
 /// The portion of `FileBuilder` which is mutated while building the body of the file.
 /// This is separated out from the rest so that it can be borrowed in a loop over
 /// `FileBuilder::segments`; otherwise this would cause a double-self-borrow.
@@ -625,8 +851,7 @@ impl Slice {
         let s = &mp4.0.segments[self.p()];
         let mut pos = mp4.0.initial_sample_byte_pos;
         for ps in &mp4.0.segments[0 .. self.p()] {
-            let r = ps.s.sample_file_range();
-            pos += r.end - r.start;
+            pos += ps.data_len();
         }
         let truns =
             mp4.0.db.lock()
@@ -734,15 +959,95 @@ impl FileBuilder {
             },
             type_: type_,
             include_timestamp_subtitle_track: false,
+            watermark: None,
+            key_frames_only: false,
+            chapters: Vec::new(),
+            origin: None,
+            memory_wait: Duration::from_secs(0),
         }
     }
 
+    /// Sets how long `build` waits for room in the memory budget (see `memory::BUDGET`) before
+    /// failing with `memory::OverBudget`. Default is not to wait, as suits HTTP requests; the
+    /// client can retry.
+    pub fn memory_wait(&mut self, d: Duration) {
+        self.memory_wait = d;
+    }
+
     /// Sets if the generated `.mp4` should include a subtitle track with second-level timestamps.
     /// Default is false.
     pub fn include_timestamp_subtitle_track(&mut self, b: bool) {
         self.include_timestamp_subtitle_track = b;
     }
 
+    /// Sets a line of text to show below every timestamp subtitle, such as identifying who
+    /// exported the file so that leaked copies can be traced. Implies the timestamp subtitle
+    /// track. Note this is easily stripped; it's not burned into the video.
+    pub fn watermark(&mut self, text: String) {
+        self.include_timestamp_subtitle_track = true;
+        self.watermark = Some(text);
+    }
+
+    /// Sets if the generated `.mp4` should include only key frames, each lasting until the next.
+    /// This is a low-bandwidth mode for quickly reviewing video. It applies only to segments
+    /// appended after this call. Default is false.
+    pub fn key_frames_only(&mut self, b: bool) {
+        self.key_frames_only = b;
+    }
+
+    /// Adds a chapter marker for each event (see `db::LockedDatabase::list_events`) which starts
+    /// within the segments appended so far. Events which were already in progress at the start of
+    /// the file are marked at its beginning. This should be called after all segments have been
+    /// appended; chapters are included only in `Type::Normal` files.
+    pub fn append_event_chapters(&mut self, db: &db::LockedDatabase) -> Result<(), Error> {
+        let mut chapters = Vec::new();
+        let mut pos_90k = 0;
+        for (i, s) in self.segments.iter().enumerate() {
+            let d = &s.s.desired_range_90k;
+            let start = s.s.start + recording::Duration(d.start as i64);
+            let end = s.s.start + recording::Duration(d.end as i64);
+            let camera_id = db.streams_by_id().get(&s.s.id.stream())
+                              .ok_or_else(|| format_err!("no such stream {}", s.s.id.stream()))?
+                              .camera_id;
+            db.list_events(camera_id, start .. end, &mut |e| {
+                if i == 0 || e.time.start >= start {
+                    chapters.push(Chapter {
+                        start_90k: pos_90k + cmp::max(0, (e.time.start - start).0),
+                        title: e.description.unwrap_or(e.type_),
+                    });
+                }
+                Ok(())
+            })?;
+            pos_90k += (d.end - d.start) as i64;
+        }
+        if chapters.len() > MAX_CHAPTERS {
+            warn!("{} events in requested range; only marking the first {}",
+                  chapters.len(), MAX_CHAPTERS);
+            chapters.truncate(MAX_CHAPTERS);
+        }
+        self.chapters.extend(chapters);
+        Ok(())
+    }
+
+    /// Records the NVR (via the database's UUID), the camera of the segments appended so far, and
+    /// the software version in the file's metadata, so that the origin of a clip found later can
+    /// be established. Like the watermark, this is easily stripped. This only applies to
+    /// `Type::Normal` files, and does nothing if no segments have been appended.
+    pub fn append_origin(&mut self, db: &db::LockedDatabase) -> Result<(), Error> {
+        let stream_id = match self.segments.first() {
+            None => return Ok(()),
+            Some(s) => s.s.id.stream(),
+        };
+        let camera_id = db.streams_by_id().get(&stream_id)
+                          .ok_or_else(|| format_err!("no such stream {}", stream_id))?
+                          .camera_id;
+        self.origin = Some(Origin {
+            server_uuid: db.uuid(),
+            camera_uuid: db.cameras_by_id()[&camera_id].uuid,
+        });
+        Ok(())
+    }
+
     /// Reserves space for the given number of additional segments.
     pub fn reserve(&mut self, additional: usize) {
         self.segments.reserve(additional);
@@ -755,17 +1060,56 @@ impl FileBuilder {
     /// Appends a segment for (a subset of) the given recording.
     pub fn append(&mut self, db: &db::LockedDatabase, row: db::ListRecordingsRow,
                   rel_range_90k: Range<i32>) -> Result<(), Error> {
-        if let Some(prev) = self.segments.last() {
+        self.append_inner(db, row, rel_range_90k, false)
+    }
+
+    /// Appends a segment which begins exactly at the frame starting at `rel_range_90k.start`,
+    /// even if that isn't a key frame. This is only valid for a `.m4s` media segment which the
+    /// client will decode immediately after one ending at that frame, as in a low-latency live
+    /// tail.
+    pub fn append_continuation(&mut self, db: &db::LockedDatabase, row: db::ListRecordingsRow,
+                               rel_range_90k: Range<i32>) -> Result<(), Error> {
+        if self.type_ != Type::MediaSegment || self.key_frames_only {
+            bail!("continuation segments are only supported in full-rate media segments");
+        }
+        if !self.segments.is_empty() {
+            bail!("a continuation segment must be the first in the file");
+        }
+        self.append_inner(db, row, rel_range_90k, true)
+    }
+
+    fn append_inner(&mut self, db: &db::LockedDatabase, row: db::ListRecordingsRow,
+                    rel_range_90k: Range<i32>, continuation: bool) -> Result<(), Error> {
+        if let Some(prev) = self.segments.last_mut() {
             if prev.s.have_trailing_zero() {
-                bail!("unable to append recording {} after recording {} with trailing zero",
-                      row.id, prev.s.id);
+                // The previous recording ended its run, as when the camera reconnected (perhaps
+                // with new parameters). Its last frame has zero duration, which is only valid at
+                // the end of the track, so drop it.
+                let before = prev.frames();
+                prev.s.drop_trailing_zero(db)?;
+                if prev.key_frames.is_some() {
+                    let k = db.with_recording_playback(prev.s.id, &mut |playback| {
+                        Segment::find_key_frames(&prev.s, playback)
+                    })?;
+                    prev.key_frames = Some(k);
+                }
+                self.next_frame_num -= (before - prev.frames()) as u32;
             }
         }
-        let s = Segment::new(db, &row, rel_range_90k, self.next_frame_num)?;
+        let new_entry = !self.video_sample_entries.iter().any(
+            |e| e.id == row.video_sample_entry_id);
+        if new_entry && self.type_ == Type::MediaSegment && !self.video_sample_entries.is_empty() {
+            // A media segment has a single `traf` referring to the first sample entry of its
+            // initialization segment, which can only have one.
+            bail!("unable to append recording {} with different parameters to a media segment",
+                  row.id);
+        }
+        let s = Segment::new(db, &row, rel_range_90k, self.next_frame_num, self.key_frames_only,
+                             continuation)?;
 
-        self.next_frame_num += s.s.frames as u32;
+        self.next_frame_num += s.frames() as u32;
         self.segments.push(s);
-        if !self.video_sample_entries.iter().any(|e| e.id == row.video_sample_entry_id) {
+        if new_entry {
             let vse = db.video_sample_entries_by_id().get(&row.video_sample_entry_id).unwrap();
             self.video_sample_entries.push(vse.clone());
         }
@@ -776,12 +1120,36 @@ impl FileBuilder {
     pub fn build(mut self, db: Arc<db::Database>,
                  dirs_by_stream_id: Arc<::fnv::FnvHashMap<i32, Arc<dir::SampleFileDir>>>)
                  -> Result<File, Error> {
+        let _span = trace::span("mp4 build");
+        let memory = memory::BUDGET.reserve(self.estimate_memory(), self.memory_wait)?;
         let mut max_end = None;
         let mut etag = hash::Hasher::new(hash::MessageDigest::sha1())?;
         etag.update(&FORMAT_VERSION[..])?;
         if self.include_timestamp_subtitle_track {
             etag.update(b":ts:")?;
         }
+        if let Some(ref w) = self.watermark {
+            if w.len() > MAX_WATERMARK_LENGTH || w.contains('\n') {
+                bail!("watermark must be a single line of at most {} bytes", MAX_WATERMARK_LENGTH);
+            }
+            etag.update(b":wm:")?;
+            etag.update(w.as_bytes())?;
+        }
+        if self.key_frames_only {
+            etag.update(b":kf:")?;
+        }
+        if let Some(ref o) = self.origin {
+            etag.update(b":or:")?;
+            etag.update(o.server_uuid.as_bytes())?;
+            etag.update(o.camera_uuid.as_bytes())?;
+        }
+        for c in &self.chapters {
+            let mut data = [0_u8; 8];
+            BigEndian::write_i64(&mut data, c.start_90k);
+            etag.update(b":ch:")?;
+            etag.update(&data)?;
+            etag.update(c.title.as_bytes())?;
+        }
         match self.type_ {
             Type::Normal => {},
             Type::InitSegment => etag.update(b":init:")?,
@@ -815,6 +1183,23 @@ impl FileBuilder {
             cursor.write_i32::<BigEndian>(d.start)?;
             cursor.write_i32::<BigEndian>(d.end)?;
             etag.update(cursor.into_inner())?;
+            if s.growing {
+                // The etag is otherwise derived only from values which are fixed once a
+                // recording is committed, so it's stable across restarts and interrupted
+                // downloads can be resumed. A growing recording's segment for the same desired
+                // range may include more frames later (and after a restart, the recording may be
+                // gone entirely), so also identify its actual extent.
+                let mut data = [0_u8; 10];
+                let mut cursor = io::Cursor::new(&mut data[..]);
+                cursor.write_u16::<BigEndian>(s.s.frames)?;
+                cursor.write_i32::<BigEndian>(s.s.actual_start_90k())?;
+                cursor.write_i32::<BigEndian>(s.s.file_end)?;
+                etag.update(b":growing:")?;
+                etag.update(cursor.into_inner())?;
+            }
+            if s.partial_gop {
+                etag.update(b":partial-gop:")?;
+            }
         }
         let max_end = match max_end {
             None => 0,
@@ -828,6 +1213,7 @@ impl FileBuilder {
         self.body.slices.reserve(est_slices);
         const EST_BUF_LEN: usize = 2048;
         self.body.buf.reserve(EST_BUF_LEN);
+        let mut moov_pos = 0;
         let initial_sample_byte_pos = match self.type_ {
             Type::MediaSegment => {
                 self.append_moof()?;
@@ -850,6 +1236,7 @@ impl FileBuilder {
             },
             Type::Normal => {
                 self.body.append_static(StaticBytestring::NormalFtypBox)?;
+                moov_pos = self.body.slices.len();
                 self.append_moov(creation_ts)?;
                 self.append_mdat()?
             },
@@ -871,18 +1258,36 @@ impl FileBuilder {
         debug!("slices: {:?}", self.body.slices);
         let last_modified = ::std::time::UNIX_EPOCH +
                             ::std::time::Duration::from_secs(max_end as u64);
-        Ok(File(Arc::new(FileInner {
+        let prebuild = self.type_ == Type::Normal &&
+                       self.segments.len() >= PARALLEL_INDEX_MIN_SEGMENTS;
+        let inner = Arc::new(FileInner {
             db,
             dirs_by_stream_id,
             segments: self.segments,
             slices: self.body.slices,
             buf: self.body.buf,
             video_sample_entries: self.video_sample_entries,
+            moov_pos,
             initial_sample_byte_pos,
             last_modified,
             etag: HeaderValue::from_str(&format!("\"{}\"", &strutil::hex(&etag.finish()?)))
                   .expect("hex string should be valid UTF-8"),
-        })))
+            watermark: self.watermark,
+            prebuild,
+            prebuild_once: ONCE_INIT,
+            _memory: memory,
+        });
+        Ok(File(inner))
+    }
+
+    /// Estimates the memory the built file holds: mostly its segments' indexes, once built.
+    fn estimate_memory(&self) -> usize {
+        const EST_FIXED: usize = 4096;
+        let per_segment = mem::size_of::<Segment>() + 5 * mem::size_of::<Slice>();
+        EST_FIXED + self.segments.iter().map(|s| {
+            let l = s.lens();
+            per_segment + l.stts + l.stsz + l.stss
+        }).sum::<usize>()
     }
 
     fn append_mdat(&mut self) -> Result<u64, Error> {
@@ -894,15 +1299,13 @@ impl FileBuilder {
         self.body.flush_buf()?;
         let initial_sample_byte_pos = self.body.slices.len();
         for (i, s) in self.segments.iter().enumerate() {
-            let r = s.s.sample_file_range();
-            self.body.append_slice(r.end - r.start, SliceType::VideoSampleData, i)?;
+            self.body.append_slice(s.data_len(), SliceType::VideoSampleData, i)?;
         }
         if let Some(p) = self.subtitle_co64_pos {
             BigEndian::write_u64(&mut self.body.buf[p .. p + 8], self.body.slices.len());
             for (i, s) in self.segments.iter().enumerate() {
                 self.body.append_slice(
-                    s.num_subtitle_samples as u64 *
-                    (mem::size_of::<u16>() + SUBTITLE_LENGTH) as u64,
+                    s.num_subtitle_samples as u64 * subtitle_sample_len(&self.watermark) as u64,
                     SliceType::SubtitleSampleData, i)?;
             }
         }
@@ -922,6 +1325,9 @@ impl FileBuilder {
             if self.include_timestamp_subtitle_track {
                 self.append_subtitle_trak(creation_ts)?;
             }
+            if self.type_ == Type::Normal && (!self.chapters.is_empty() || self.origin.is_some()) {
+                self.append_udta()?;
+            }
             if self.type_ == Type::InitSegment {
                 self.append_mvex()?;
             }
@@ -1000,6 +1406,97 @@ impl FileBuilder {
         Ok(())
     }
 
+    /// Appends a `UserDataBox` (ISO/IEC 14496-12 section 8.10.1) holding a Nero-style `chpl`
+    /// chapter list, if there are chapters, and the origin's `meta`, if set. Neither is part of the
+    /// standard, but both are understood by ffmpeg, mp4v2, and many players.
+    fn append_udta(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"udta");
+            if !self.chapters.is_empty() {
+                self.append_chpl()?;
+            }
+            if let Some(o) = self.origin {
+                self.append_origin_meta(o)?;
+            }
+        })
+    }
+
+    /// Appends a `chpl` box of `self.chapters`.
+    fn append_chpl(&mut self) -> Result<(), Error> {
+        write_length!(self, {
+            // version 1, flags 0, reserved.
+            self.body.buf.extend_from_slice(b"chpl\x01\x00\x00\x00\x00\x00\x00\x00");
+            self.body.buf.push(self.chapters.len() as u8);
+            for c in &self.chapters {
+                // chpl times are in 100-nanosecond units.
+                self.body.append_u64((c.start_90k * 10_000_000 / TIME_UNITS_PER_SEC) as u64);
+
+                // The title is limited to 255 bytes; truncate on a character boundary.
+                let mut len = cmp::min(c.title.len(), 255);
+                while !c.title.is_char_boundary(len) {
+                    len -= 1;
+                }
+                self.body.buf.push(len as u8);
+                self.body.buf.extend_from_slice(&c.title.as_bytes()[.. len]);
+            }
+        })
+    }
+
+    /// Appends an Apple-style `meta` box (as in ISO/IEC 14496-12 section 8.11.1, with an `mdir`
+    /// handler) describing `o`. The software version is the standard `©too` (encoding tool)
+    /// item; the UUIDs are custom `----` items, which ffmpeg reports as `server_uuid` and
+    /// `camera_uuid`.
+    fn append_origin_meta(&mut self, o: Origin) -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(b"meta\x00\x00\x00\x00");  // version + flags
+            write_length!(self, {
+                self.body.buf.extend_from_slice(&[
+                    b'h', b'd', b'l', b'r',
+                    0x00, 0x00, 0x00, 0x00,  // version + flags
+                    0x00, 0x00, 0x00, 0x00,  // pre_defined
+                    b'm', b'd', b'i', b'r',  // handler_type
+                    b'a', b'p', b'p', b'l',  // reserved
+                    0x00, 0x00, 0x00, 0x00,  // reserved
+                    0x00, 0x00, 0x00, 0x00,  // reserved
+                    0x00,                    // name (empty)
+                ]);
+            })?;
+            write_length!(self, {
+                self.body.buf.extend_from_slice(b"ilst");
+                let tool = format!("moonfire-nvr {}", env!("CARGO_PKG_VERSION"));
+                self.append_ilst_item(b"\xa9too", None, &tool)?;
+                self.append_ilst_item(b"----", Some(&b"server_uuid"[..]),
+                                      &o.server_uuid.to_string())?;
+                self.append_ilst_item(b"----", Some(&b"camera_uuid"[..]),
+                                      &o.camera_uuid.to_string())?;
+            })?;
+        })
+    }
+
+    /// Appends an `ilst` item of the given type holding a UTF-8 `value`. `name` is required for
+    /// custom (`----`) items, which are namespaced by `ORIGIN_MEAN`.
+    fn append_ilst_item(&mut self, type_: &[u8; 4], name: Option<&[u8]>, value: &str)
+                        -> Result<(), Error> {
+        write_length!(self, {
+            self.body.buf.extend_from_slice(type_);
+            if let Some(n) = name {
+                write_length!(self, {
+                    self.body.buf.extend_from_slice(b"mean\x00\x00\x00\x00");
+                    self.body.buf.extend_from_slice(ORIGIN_MEAN);
+                })?;
+                write_length!(self, {
+                    self.body.buf.extend_from_slice(b"name\x00\x00\x00\x00");
+                    self.body.buf.extend_from_slice(n);
+                })?;
+            }
+            write_length!(self, {
+                // type 1 (UTF-8), default locale.
+                self.body.buf.extend_from_slice(b"data\x00\x00\x00\x01\x00\x00\x00\x00");
+                self.body.buf.extend_from_slice(value.as_bytes());
+            })?;
+        })
+    }
+
     /// Appends a `MovieHeaderBox` version 0 (ISO/IEC 14496-12 section 8.2.2).
     fn append_mvhd(&mut self, creation_ts: u32) -> Result<(), Error> {
         write_length!(self, {
@@ -1219,14 +1716,14 @@ impl FileBuilder {
             self.body.buf.extend_from_slice(b"stts\x00\x00\x00\x00");
             let mut entry_count = 0;
             for s in &self.segments {
-                entry_count += s.s.frames as u32;
+                entry_count += s.frames() as u32;
             }
             self.body.append_u32(entry_count);
             if !self.segments.is_empty() {
                 self.body.flush_buf()?;
                 for (i, s) in self.segments.iter().enumerate() {
                     self.body.append_slice(
-                        2 * (mem::size_of::<u32>() as u64) * (s.s.frames as u64),
+                        2 * (mem::size_of::<u32>() as u64) * (s.frames() as u64),
                         SliceType::Stts, i)?;
                 }
             }
@@ -1286,7 +1783,7 @@ impl FileBuilder {
             self.body.append_u32(self.segments.len() as u32);
             for (i, s) in self.segments.iter().enumerate() {
                 self.body.append_u32((i + 1) as u32);
-                self.body.append_u32(s.s.frames as u32);
+                self.body.append_u32(s.frames() as u32);
 
                 // Write sample_description_index.
                 let i = self.video_sample_entries.iter().position(
@@ -1312,14 +1809,14 @@ impl FileBuilder {
             self.body.buf.extend_from_slice(b"stsz\x00\x00\x00\x00\x00\x00\x00\x00");
             let mut entry_count = 0;
             for s in &self.segments {
-                entry_count += s.s.frames as u32;
+                entry_count += s.frames() as u32;
             }
             self.body.append_u32(entry_count);
             if !self.segments.is_empty() {
                 self.body.flush_buf()?;
                 for (i, s) in self.segments.iter().enumerate() {
                     self.body.append_slice(
-                        (mem::size_of::<u32>()) as u64 * (s.s.frames as u64), SliceType::Stsz, i)?;
+                        (mem::size_of::<u32>()) as u64 * (s.frames() as u64), SliceType::Stsz, i)?;
                 }
             }
         })
@@ -1329,7 +1826,7 @@ impl FileBuilder {
     fn append_subtitle_stsz(&mut self) -> Result<(), Error> {
         write_length!(self, {
             self.body.buf.extend_from_slice(b"stsz\x00\x00\x00\x00");
-            self.body.append_u32((mem::size_of::<u16>() + SUBTITLE_LENGTH) as u32);
+            self.body.append_u32(subtitle_sample_len(&self.watermark) as u32);
             self.body.append_u32(self.num_subtitle_samples as u32);
         })
     }
@@ -1421,19 +1918,57 @@ struct FileInner {
     slices: Slices<Slice>,
     buf: Vec<u8>,
     video_sample_entries: SmallVec<[Arc<db::VideoSampleEntry>; 1]>,
+
+    /// The byte range of the `moov` box in a `Type::Normal` file is
+    /// `moov_pos .. initial_sample_byte_pos`. Its length is known without building any segment's
+    /// index, so only requests overlapping it need the indexes.
+    moov_pos: u64,
     initial_sample_byte_pos: u64,
     last_modified: SystemTime,
     etag: HeaderValue,
+    watermark: Option<String>,
+
+    /// If the segments' indexes should be built in advance once the body is served; see
+    /// `prebuild_indexes`.
+    prebuild: bool,
+    prebuild_once: Once,
+
+    /// The reservation for this file's memory, held as long as any response is using it.
+    _memory: memory::Reservation<'static>,
 }
 
 impl FileInner {
+    /// Starts building the segments' indexes on `INDEX_POOL`, roughly in order, so most are
+    /// ready by the time serving the `moov` box needs them. Otherwise the first request for a
+    /// long `.mp4` (such as a day-long export) would build every segment's index serially
+    /// before returning its first bytes. Called when a range including the `moov` box is first
+    /// served, so `HEAD` requests, `304 Not Modified` responses, and ranges within the `mdat`
+    /// box don't pay for it.
+    fn prebuild_indexes(this: &Arc<FileInner>) {
+        let next = Arc::new(AtomicUsize::new(0));
+        for _ in 0 .. PARALLEL_INDEX_THREADS {
+            let this = this.clone();
+            let next = next.clone();
+            INDEX_POOL.spawn_fn(move || -> Result<(), ()> {
+                loop {
+                    let s = match this.segments.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        None => return Ok(()),
+                        Some(s) => s,
+                    };
+
+                    // Errors are logged by get_index and returned again when serving.
+                    let _ = s.get_index(&this.db, |b, _| b);
+                }
+            }).forget();
+        }
+    }
+
     fn get_co64(&self, r: Range<u64>, l: u64) -> Result<Chunk, Error> {
         let mut v = Vec::with_capacity(l as usize);
         let mut pos = self.initial_sample_byte_pos;
         for s in &self.segments {
             v.write_u64::<BigEndian>(pos)?;
-            let r = s.s.sample_file_range();
-            pos += r.end - r.start;
+            pos += s.data_len();
         }
         Ok(ARefs::new(v).map(|v| &v[r.start as usize .. r.end as usize]).into())
     }
@@ -1449,11 +1984,19 @@ impl FileInner {
     ///      happen because nothing should be touching Moonfire NVR's files but itself.
     fn get_video_sample_data(&self, i: usize, r: Range<u64>) -> Result<Chunk, Error> {
         let s = &self.segments[i];
-        let f = self.dirs_by_stream_id
-                    .get(&s.s.id.stream())
-                    .ok_or_else(|| format_err!("{}: stream not found", s.s.id))?
-                    .open_file(s.s.id)?;
+        let dir = self.dirs_by_stream_id
+                      .get(&s.s.id.stream())
+                      .ok_or_else(|| format_err!("{}: stream not found", s.s.id))?;
+        let f = dir.open_file(s.s.id)?;
+        if let Some(ref key_frames) = s.key_frames {
+            return FileInner::get_key_frame_data(&f, dir.cipher(), s, key_frames, r);
+        }
         let start = s.s.sample_file_range().start + r.start;
+        if let Some(c) = dir.cipher() {
+            // Encrypted data must be copied to be decrypted.
+            let v = c.read(&f, s.s.id, s.s.open_id, start .. start + r.end - r.start, s.growing)?;
+            return Ok(ARefs::new(v).map(|v| &v[..]).into());
+        }
         let mmap = Box::new(unsafe {
             memmap::MmapOptions::new()
                 .offset(start)
@@ -1464,6 +2007,38 @@ impl FileInner {
         Ok(ARefs::new(mmap).map(|m| m.deref()).into())
     }
 
+    /// Gets a `Chunk` of the concatenated key frames in `key_frames`, copying from the file.
+    fn get_key_frame_data(f: &::std::fs::File, cipher: Option<&dir::Cipher>, s: &Segment,
+                          key_frames: &[KeyFrame], r: Range<u64>) -> Result<Chunk, Error> {
+        let mut v = Vec::with_capacity((r.end - r.start) as usize);
+        let mut pos = 0;
+        for k in key_frames {
+            let k_end = pos + k.bytes as u64;
+            if k_end > r.start && pos < r.end {
+                let start = cmp::max(pos, r.start) - pos;
+                let end = cmp::min(k_end, r.end) - pos;
+                if let Some(c) = cipher {
+                    let data = c.read(f, s.s.id, s.s.open_id, k.pos + start .. k.pos + end,
+                                      s.growing)?;
+                    v.extend_from_slice(&data);
+                } else {
+                    let mmap = unsafe {
+                        memmap::MmapOptions::new()
+                            .offset(k.pos + start)
+                            .len((end - start) as usize)
+                            .map(f)?
+                    };
+                    v.extend_from_slice(&mmap[..]);
+                }
+            }
+            pos = k_end;
+            if pos >= r.end {
+                break;
+            }
+        }
+        Ok(ARefs::new(v).map(|v| &v[..]).into())
+    }
+
     fn get_subtitle_sample_data(&self, i: usize, r: Range<u64>, l: u64) -> Result<Chunk, Error> {
         let s = &self.segments[i];
         let d = &s.s.desired_range_90k;
@@ -1472,10 +2047,14 @@ impl FileInner {
                       .unix_seconds();
         let mut v = Vec::with_capacity(l as usize);
         for ts in start_sec .. end_sec {
-            v.write_u16::<BigEndian>(SUBTITLE_LENGTH as u16)?;
+            v.write_u16::<BigEndian>(
+                (subtitle_sample_len(&self.watermark) - mem::size_of::<u16>()) as u16)?;
             let tm = time::at(time::Timespec{sec: ts, nsec: 0});
             use std::io::Write;
             write!(v, "{}", tm.strftime(SUBTITLE_TEMPLATE)?)?;
+            if let Some(ref w) = self.watermark {
+                write!(v, "\n{}", w)?;
+            }
         }
         Ok(ARefs::new(v).map(|v| &v[r.start as usize .. r.end as usize]).into())
     }
@@ -1503,12 +2082,20 @@ impl http_serve::Entity for File {
         mime.extend_from_slice(b"\"");
         hdrs.insert(http::header::CONTENT_TYPE,
                     http::header::HeaderValue::from_shared(mime.freeze()).unwrap());
+
+        // Advertise range support even on full (200) responses, as AVPlayer won't seek within a
+        // long file otherwise.
+        hdrs.insert(http::header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
     }
     fn last_modified(&self) -> Option<SystemTime> { Some(self.0.last_modified) }
     fn etag(&self) -> Option<HeaderValue> { Some(self.0.etag.clone()) }
     fn len(&self) -> u64 { self.0.slices.len() }
     fn get_range(&self, range: Range<u64>)
                  -> Box<Stream<Item = Self::Data, Error = Self::Error> + Send> {
+        if self.0.prebuild && range.start < self.0.initial_sample_byte_pos &&
+           range.end > self.0.moov_pos {
+            self.0.prebuild_once.call_once(|| FileInner::prebuild_indexes(&self.0));
+        }
         self.0.slices.get_range(self, range)
     }
 }
@@ -1524,7 +2111,7 @@ impl http_serve::Entity for File {
 ///      detect misunderstandings of the specification or incompatibilities, but they can be used
 ///      to verify the output is byte-for-byte as expected.
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use base::strutil;
     use bytes::Buf;
     use byteorder::{BigEndian, ByteOrder};
@@ -1756,9 +2343,9 @@ mod tests {
         }
     }
 
-    fn copy_mp4_to_db(db: &TestDb<RealClocks>) {
+    pub fn copy_mp4_to_db(db: &TestDb<RealClocks>) {
         let mut input =
-            stream::FFMPEG.open(stream::Source::File("src/testdata/clip.mp4")).unwrap();
+            stream::FFMPEG.open(stream::Source::File("src/testdata/clip.mp4"), &[]).unwrap();
 
         // 2015-04-26 00:00:00 UTC.
         const START_TIME: recording::Time = recording::Time(1430006400i64 * TIME_UNITS_PER_SEC);
@@ -1829,8 +2416,9 @@ mod tests {
     }
 
     fn compare_mp4s(new_filename: &str, pts_offset: i64, shorten: i64) {
-        let mut orig = stream::FFMPEG.open(stream::Source::File("src/testdata/clip.mp4")).unwrap();
-        let mut new = stream::FFMPEG.open(stream::Source::File(new_filename)).unwrap();
+        let mut orig =
+            stream::FFMPEG.open(stream::Source::File("src/testdata/clip.mp4"), &[]).unwrap();
+        let mut new = stream::FFMPEG.open(stream::Source::File(new_filename), &[]).unwrap();
         assert_eq!(orig.get_extra_data().unwrap(), new.get_extra_data().unwrap());
         let mut final_durations = None;
         loop {
@@ -2003,6 +2591,140 @@ mod tests {
         ]);
     }
 
+    /// Tests a file spanning two runs with different parameters, as after a camera reconnects
+    /// with a new resolution. The first run's trailing zero-duration frame should be dropped, and
+    /// the second recording should use a second sample entry.
+    #[test]
+    fn test_parameter_change() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r1 = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::new();
+        encoder.add_sample(1, 1, true, &mut r1);
+        encoder.add_sample(2, 2, false, &mut r1);
+        encoder.add_sample(0, 3, false, &mut r1);
+        let mut r2 = db::RecordingToInsert::default();
+        r2.video_sample_entry_id = db.db.lock().insert_video_sample_entry(
+            1280, 720, [1u8; 100].to_vec(), "avc1.000000".to_owned()).unwrap();
+        let mut encoder = recording::SampleIndexEncoder::new();
+        encoder.add_sample(3, 4, true, &mut r2);
+        encoder.add_sample(4, 5, false, &mut r2);
+        let mp4 = make_mp4_from_encoders(Type::Normal, &db, vec![r1, r2], 0 .. 3+7);
+        let track = find_track(mp4, 1);
+        let mut cursor = track.stbl_cursor;
+        cursor.down();
+        cursor.find(b"stsd");
+        assert_eq!(&cursor.get_all()[.. 8], &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x02,  // entry_count
+        ]);
+
+        cursor.find(b"stts");
+        assert_eq!(cursor.get_all(), &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x04,  // entry_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,  // run length / timestamps.
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02,
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04,
+        ]);
+
+        cursor.find(b"stsc");
+        assert_eq!(cursor.get_all(), &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x02,  // entry_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x01,  // first_chunk
+            0x00, 0x00, 0x00, 0x02,  // samples_per_chunk
+            0x00, 0x00, 0x00, 0x01,  // sample_description_index
+            0x00, 0x00, 0x00, 0x02,  // first_chunk
+            0x00, 0x00, 0x00, 0x02,  // samples_per_chunk
+            0x00, 0x00, 0x00, 0x02,  // sample_description_index
+        ]);
+
+        cursor.find(b"stsz");
+        assert_eq!(cursor.get_all(), &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x00,  // sample_size
+            0x00, 0x00, 0x00, 0x04,  // sample_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x01,  // size
+            0x00, 0x00, 0x00, 0x02,
+            0x00, 0x00, 0x00, 0x04,
+            0x00, 0x00, 0x00, 0x05,
+        ]);
+
+        cursor.find(b"stss");
+        assert_eq!(cursor.get_all(), &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x02,  // entry_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x01,  // sample_number
+            0x00, 0x00, 0x00, 0x03,
+        ]);
+    }
+
+    /// Tests sample table for a key-frame-only file from a video index with half sync frames.
+    #[test]
+    fn test_key_frames_only() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::new();
+        for i in 1..6 {
+            let duration_90k = 2 * i;
+            let bytes = 3 * i;
+            encoder.add_sample(duration_90k, bytes, (i % 2) == 1, &mut r);
+        }
+        let row = db.insert_recording_from_encoder(r);
+        let mut builder = FileBuilder::new(Type::Normal);
+        builder.key_frames_only(true);
+        builder.append(&db.db.lock(), row, 0 .. 2+4+6+8+10).unwrap();
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_stream_id.clone()).unwrap();
+        let track = find_track(mp4, 1);
+        assert!(track.edts_cursor.is_none());
+        let mut cursor = track.stbl_cursor;
+        cursor.down();
+        cursor.find(b"stts");
+        assert_eq!(cursor.get_all(), &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x03,  // entry_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06,  // run length / timestamps.
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0e,
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0a,
+        ]);
+
+        cursor.find(b"stsz");
+        assert_eq!(cursor.get_all(), &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x00,  // sample_size
+            0x00, 0x00, 0x00, 0x03,  // sample_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x03,  // size
+            0x00, 0x00, 0x00, 0x09,
+            0x00, 0x00, 0x00, 0x0f,
+        ]);
+
+        cursor.find(b"stss");
+        assert_eq!(cursor.get_all(), &[
+            0x00, 0x00, 0x00, 0x00,  // version + flags
+            0x00, 0x00, 0x00, 0x03,  // entry_count
+
+            // entries
+            0x00, 0x00, 0x00, 0x01,  // sample_number
+            0x00, 0x00, 0x00, 0x02,
+            0x00, 0x00, 0x00, 0x03,
+        ]);
+    }
+
     #[test]
     fn test_multi_segment() {
         testutil::init();
@@ -2040,6 +2762,154 @@ mod tests {
         assert_eq!(cursor.get_u32(12), 2);
     }
 
+    /// Tests a file with enough segments that their indexes are built in parallel.
+    #[test]
+    fn test_parallel_index() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let n = PARALLEL_INDEX_MIN_SEGMENTS + 5;
+        let mut encoders = Vec::new();
+        for i in 0 .. n {
+            let mut r = db::RecordingToInsert::default();
+            let mut encoder = recording::SampleIndexEncoder::new();
+            encoder.add_sample(1, 1 + i as i32, true, &mut r);
+            encoder.add_sample(2, 2, false, &mut r);
+            encoders.push(r);
+        }
+        let mp4 = make_mp4_from_encoders(Type::Normal, &db, encoders, 0 .. 3 * n as i32);
+        let mut cursor = BoxCursor::new(mp4);
+        cursor.down();
+        assert!(cursor.find(b"moov"));
+        cursor.down();
+        assert!(cursor.find(b"trak"));
+        cursor.down();
+        assert!(cursor.find(b"mdia"));
+        cursor.down();
+        assert!(cursor.find(b"minf"));
+        cursor.down();
+        assert!(cursor.find(b"stbl"));
+        cursor.down();
+        assert!(cursor.find(b"stsz"));
+        assert_eq!(cursor.get_u32(8), 2 * n as u32);  // sample_count
+        for i in 0 .. n as u64 {
+            assert_eq!(cursor.get_u32(12 + 8 * i), 1 + i as u32);
+            assert_eq!(cursor.get_u32(16 + 8 * i), 2);
+        }
+        assert!(cursor.find(b"stss"));
+        assert_eq!(cursor.get_u32(4), n as u32);  // entry_count
+        for i in 0 .. n as u64 {
+            assert_eq!(cursor.get_u32(8 + 4 * i), 1 + 2 * i as u32);
+        }
+    }
+
+    #[test]
+    fn test_event_chapters() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::new();
+        for i in 1..6 {
+            encoder.add_sample(90000, i, true, &mut r);
+        }
+        let row = db.insert_recording_from_encoder(r);
+        let start = row.start;
+        db.db.lock().add_event(&db::EventToInsert {
+            camera_id: testutil::TEST_CAMERA_ID,
+            type_: "sound_level".to_owned(),
+            time: start + recording::Duration(2 * 90000) .. start + recording::Duration(3 * 90000),
+            description: Some("loud noise".to_owned()),
+            score: None,
+        }).unwrap();
+        let mut builder = FileBuilder::new(Type::Normal);
+        {
+            let l = db.db.lock();
+            builder.append(&l, row, 90000 .. 5 * 90000).unwrap();
+            builder.append_event_chapters(&l).unwrap();
+        }
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_stream_id.clone()).unwrap();
+        let mut cursor = BoxCursor::new(mp4);
+        cursor.down();
+        assert!(cursor.find(b"moov"));
+        cursor.down();
+        assert!(cursor.find(b"udta"));
+        cursor.down();
+        assert!(cursor.find(b"chpl"));
+        let mut buf = [0u8; 20];
+        cursor.get(8, &mut buf);
+        assert_eq!(buf[0], 1);  // chapter count
+        assert_eq!(BigEndian::read_u64(&buf[1..9]), 10_000_000);  // 1 second into the file.
+        assert_eq!(buf[9], 10);  // title length
+        assert_eq!(&buf[10..20], b"loud noise");
+    }
+
+    #[test]
+    fn test_origin() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::new();
+        for i in 1..6 {
+            encoder.add_sample(90000, i, true, &mut r);
+        }
+        let row = db.insert_recording_from_encoder(r);
+        let mut builder = FileBuilder::new(Type::Normal);
+        let (server_uuid, camera_uuid) = {
+            let l = db.db.lock();
+            builder.append(&l, row, 0 .. 5 * 90000).unwrap();
+            builder.append_origin(&l).unwrap();
+            (l.uuid(), l.cameras_by_id()[&testutil::TEST_CAMERA_ID].uuid)
+        };
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_stream_id.clone()).unwrap();
+        let mut cursor = BoxCursor::new(mp4);
+        cursor.down();
+        assert!(cursor.find(b"moov"));
+        cursor.down();
+        assert!(cursor.find(b"udta"));
+        cursor.down();
+        assert!(cursor.find(b"meta"));
+        let meta = cursor.get_all();
+        let contains = |s: &[u8]| meta.windows(s.len()).any(|w| w == s);
+        assert!(contains(b"mdir"));
+        assert!(contains(b"moonfire-nvr "));
+        assert!(contains(server_uuid.to_string().as_bytes()));
+        assert!(contains(camera_uuid.to_string().as_bytes()));
+    }
+
+    #[test]
+    fn test_watermark() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::new();
+        for i in 1..6 {
+            encoder.add_sample(90000, i, true, &mut r);
+        }
+        let row = db.insert_recording_from_encoder(r);
+        let mut builder = FileBuilder::new(Type::Normal);
+        builder.watermark("exported by bob".to_owned());
+        builder.append(&db.db.lock(), row, 0 .. 2 * 90000).unwrap();
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_stream_id.clone()).unwrap();
+        let sample_len = 2 + SUBTITLE_LENGTH + 1 + "exported by bob".len();
+        let mut cursor = find_track(mp4.clone(), 2).stbl_cursor;
+        cursor.down();
+        assert!(cursor.find(b"stsz"));
+        assert_eq!(cursor.get_u32(4) as usize, sample_len);
+        assert_eq!(cursor.get_u32(8), 2);
+
+        // The subtitle samples are at the end of the mdat.
+        let mut cursor = BoxCursor::new(mp4);
+        cursor.down();
+        assert!(cursor.find(b"mdat"));
+        let data = cursor.get_all();
+        let last = &data[data.len() - sample_len ..];
+        assert_eq!(BigEndian::read_u16(&last[0..2]) as usize, sample_len - 2);
+        assert_eq!(&last[2 + SUBTITLE_LENGTH ..], b"\nexported by bob");
+
+        let mut builder = FileBuilder::new(Type::Normal);
+        builder.watermark("two\nlines".to_owned());
+        builder.build(db.db.clone(), db.dirs_by_stream_id.clone()).unwrap_err();
+    }
+
     #[test]
     fn test_zero_duration_recording() {
         testutil::init();
@@ -2109,6 +2979,50 @@ mod tests {
         assert_eq!(cursor.get_u32(20), 15);   // sample size
     }
 
+    /// Tests a continuation segment beginning partway through a GOP, as used by low-latency
+    /// live tails.
+    #[test]
+    fn test_media_segment_continuation() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let mut r = db::RecordingToInsert::default();
+        let mut encoder = recording::SampleIndexEncoder::new();
+        for i in 1..6 {
+            let duration_90k = 2 * i;
+            let bytes = 3 * i;
+            encoder.add_sample(duration_90k, bytes, (i % 2) == 1, &mut r);
+        }
+        let row = db.insert_recording_from_encoder(r);
+
+        // Unlike test_media_segment, the 3rd (sync) sample should not be pulled in.
+        let mut builder = FileBuilder::new(Type::MediaSegment);
+        builder.append_continuation(&db.db.lock(), row, 2+4+6 .. 2+4+6+8+1).unwrap();
+        let mp4 = builder.build(db.db.clone(), db.dirs_by_stream_id.clone()).unwrap();
+        let mut cursor = BoxCursor::new(mp4);
+        cursor.down();
+
+        let mut mdat = cursor.clone();
+        assert!(mdat.find(b"mdat"));
+
+        assert!(cursor.find(b"moof"));
+        cursor.down();
+        assert!(cursor.find(b"traf"));
+        cursor.down();
+        assert!(cursor.find(b"trun"));
+        assert_eq!(cursor.get_u32(4), 1);
+        assert_eq!(cursor.get_u32(8) as u64, mdat.interior().start);
+        assert_eq!(cursor.get_u32(12), 0x0101_0000);  // first_sample_flags: non-sync
+        assert_eq!(cursor.get_u32(16), 8);   // sample duration
+        assert_eq!(cursor.get_u32(20), 12);  // sample size
+        assert!(cursor.next());
+        assert_eq!(cursor.name(), "trun");
+        assert_eq!(cursor.get_u32(4), 1);
+        assert_eq!(cursor.get_u32(8) as u64, mdat.interior().start + 12);
+        assert_eq!(cursor.get_u32(12), 174063616);  // first_sample_flags
+        assert_eq!(cursor.get_u32(16), 1);    // sample duration
+        assert_eq!(cursor.get_u32(20), 15);   // sample size
+    }
+
     #[test]
     fn test_round_trip() {
         testutil::init();
@@ -2192,6 +3106,25 @@ mod tests {
         db.db.lock().clear_on_flush();
         db.syncer_join.join().unwrap();
     }
+
+    /// Independently built files of the same recordings and options (as after a server restart)
+    /// should be identical, so that interrupted downloads can be resumed with `If-Range`.
+    #[test]
+    fn test_etag_is_deterministic() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        copy_mp4_to_db(&db);
+        let a = create_mp4_from_db(&db, 1, 1, true);
+        let b = create_mp4_from_db(&db, 1, 1, true);
+        assert_eq!(a.etag(), b.etag());
+        assert_eq!(a.last_modified(), b.last_modified());
+        assert_eq!(a.len(), b.len());
+        assert_eq!(&digest(&a)[..], &digest(&b)[..]);
+        assert!(a.etag() != create_mp4_from_db(&db, 0, 1, true).etag());
+        drop(db.syncer_channel);
+        db.db.lock().clear_on_flush();
+        db.syncer_join.join().unwrap();
+    }
 }
 
 #[cfg(all(test, feature="nightly"))]
@@ -2279,7 +3212,7 @@ mod bench {
             }).unwrap();
             let row = row.unwrap();
             let rel_range_90k = 0 .. row.duration_90k;
-            super::Segment::new(&db, &row, rel_range_90k, 1).unwrap()
+            super::Segment::new(&db, &row, rel_range_90k, 1, false, false).unwrap()
         };
         db.with_recording_playback(segment.s.id, &mut |playback| {
             let v = segment.build_index(playback).unwrap();  // warm.
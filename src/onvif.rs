@@ -0,0 +1,770 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A minimal [ONVIF](https://www.onvif.org/) client: just enough to perform maintenance actions
+//! on cameras, so that users don't need to keep camera admin credentials in their browsers, and
+//! to receive cameras' events via a `PullPointSubscription` (see `vendor_events`). Also parses
+//! the analytics sent in a stream's RTSP metadata track. Also reads and writes the video encoder
+//! configuration of the media profile matching a stream (see `get_encoder` and `set_encoder`).
+
+use db;
+use failure::Error;
+use openssl::{base64, hash, rand};
+use regex::Regex;
+use reqwest;
+use std::io::Read;
+use std::time::{Duration, Instant};
+use time;
+
+lazy_static! {
+    static ref MESSAGE_RE: Regex = Regex::new(r"<(?:\w+:)?Message>([^<]*)</").unwrap();
+    static ref EVENTS_XADDR_RE: Regex =
+        Regex::new(r"<(?:\w+:)?Events>\s*<(?:\w+:)?XAddr>([^<]*)</").unwrap();
+    static ref MEDIA_XADDR_RE: Regex =
+        Regex::new(r"<(?:\w+:)?Media>\s*<(?:\w+:)?XAddr>([^<]*)</").unwrap();
+    static ref PROFILE_RE: Regex =
+        Regex::new(r#"(?s)<(?:\w+:)?Profiles\s[^>]*token="([^"]*)"[^>]*>(.*?)</(?:\w+:)?Profiles>"#)
+        .unwrap();
+    static ref VIDEO_ENCODER_RE: Regex =
+        Regex::new(concat!(r#"(?s)<(?:\w+:)?VideoEncoderConfiguration\s[^>]*token="([^"]*)"[^>]*>"#,
+                           r"(.*?)</(?:\w+:)?VideoEncoderConfiguration>")).unwrap();
+    static ref ELEMENT_PREFIX_RE: Regex = Regex::new(r"<(/?)(?:\w+:)?(\w)").unwrap();
+    static ref ELEMENT_START_RE: Regex = Regex::new(r"<(?:\w+:)?(\w+)(?:\s[^>]*)?>").unwrap();
+    static ref ELEMENT_END_RE: Regex = Regex::new(r"</(?:\w+:)?(\w+)>").unwrap();
+    static ref URI_RE: Regex = Regex::new(r"<(?:\w+:)?Uri>([^<]*)</").unwrap();
+    static ref SUBSCRIPTION_ADDRESS_RE: Regex =
+        Regex::new(r"<(?:\w+:)?SubscriptionReference>\s*<(?:\w+:)?Address>([^<]*)</").unwrap();
+    static ref NOTIFICATION_MESSAGE_RE: Regex =
+        Regex::new(r"(?s)<(?:\w+:)?NotificationMessage>(.*?)</(?:\w+:)?NotificationMessage>")
+        .unwrap();
+    static ref TOPIC_RE: Regex = Regex::new(r"<(?:\w+:)?Topic[^>]*>([^<]*)</").unwrap();
+    static ref SOURCE_RE: Regex =
+        Regex::new(r"(?s)<(?:\w+:)?Source>(.*?)</(?:\w+:)?Source>").unwrap();
+    static ref DATA_RE: Regex = Regex::new(r"(?s)<(?:\w+:)?Data>(.*?)</(?:\w+:)?Data>").unwrap();
+    static ref SIMPLE_ITEM_RE: Regex =
+        Regex::new(r#"<(?:\w+:)?SimpleItem\s+Name="([^"]*)"\s+Value="([^"]*)""#).unwrap();
+    static ref OBJECT_RE: Regex =
+        Regex::new(r#"(?s)<(?:\w+:)?Object\s[^>]*ObjectId="([^"]*)"[^>]*>(.*?)</(?:\w+:)?Object>"#)
+        .unwrap();
+    static ref BOUNDING_BOX_RE: Regex = Regex::new(r"<(?:\w+:)?BoundingBox\s([^>]*)>").unwrap();
+    static ref CLASS_TYPE_RE: Regex =
+        Regex::new(r"<(?:\w+:)?Type(\s[^>]*)?>([^<]*)</").unwrap();
+    static ref ATTRIBUTE_RE: Regex = Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+    pub static ref METADATA_STREAM_END_RE: Regex =
+        Regex::new(r"</(?:\w+:)?MetadataStream>").unwrap();
+}
+
+const SOAP_ENVELOPE_START: &'static str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"
+            xmlns:tds="http://www.onvif.org/ver10/device/wsdl"
+            xmlns:tev="http://www.onvif.org/ver10/events/wsdl"
+            xmlns:trt="http://www.onvif.org/ver10/media/wsdl"
+            xmlns:tt="http://www.onvif.org/ver10/schema"
+            xmlns:wsnt="http://docs.oasis-open.org/wsn/b-2">"#;
+
+/// The lifetime of a `PullPoint` subscription; it's renewed at half this interval.
+const SUBSCRIPTION_SEC: u64 = 60;
+
+/// Returns the URL of the device management service for the given camera host.
+/// The camera's `host` field is used for RTSP; any port specified there is dropped in favor of
+/// the standard HTTP port.
+fn device_service_url(host: &str) -> String {
+    let host = match host.rfind(':') {
+        Some(i) if !host.contains(']') && host.matches(':').count() == 1 => &host[..i],
+        _ => host,
+    };
+    format!("http://{}/onvif/device_service", host)
+}
+
+/// Returns the `PasswordDigest` as defined in the WS-Security UsernameToken profile:
+/// `Base64(SHA-1(nonce + created + password))`.
+fn password_digest(nonce: &[u8], created: &str, password: &str) -> Result<String, Error> {
+    let mut h = hash::Hasher::new(hash::MessageDigest::sha1())?;
+    h.update(nonce)?;
+    h.update(created.as_bytes())?;
+    h.update(password.as_bytes())?;
+    Ok(base64::encode_block(&h.finish()?))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Reverses `xml_escape` (and the `&apos;` entity), for text and attribute values in responses.
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'")
+     .replace("&amp;", "&")
+}
+
+/// Returns a SOAP envelope containing the given body, with a WS-Security header.
+fn envelope(username: &str, password: &str, body: &str) -> Result<String, Error> {
+    let mut nonce = [0u8; 16];
+    rand::rand_bytes(&mut nonce)?;
+    let created = time::strftime("%Y-%m-%dT%H:%M:%SZ", &time::now_utc())?;
+    let digest = password_digest(&nonce, &created, password)?;
+    Ok(format!(r#"{}
+<s:Header>
+<Security s:mustUnderstand="1"
+          xmlns="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd">
+<UsernameToken>
+<Username>{}</Username>
+<Password Type="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordDigest">{}</Password>
+<Nonce EncodingType="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-soap-message-security-1.0#Base64Binary">{}</Nonce>
+<Created xmlns="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd">{}</Created>
+</UsernameToken>
+</Security>
+</s:Header>
+<s:Body>{}</s:Body>
+</s:Envelope>"#, SOAP_ENVELOPE_START, xml_escape(username), digest, base64::encode_block(&nonce),
+       created, body))
+}
+
+/// Performs the SOAP operation `op` with the given `body` at `url`, returning the response text.
+fn call(client: &reqwest::Client, url: &str, username: &str, password: &str, op: &str,
+        body: &str) -> Result<String, Error> {
+    let body = envelope(username, password, body)?;
+    let mut resp = client.post(url)
+                         .header(reqwest::header::CONTENT_TYPE,
+                                 "application/soap+xml; charset=utf-8")
+                         .body(body)
+                         .send()?;
+    let mut text = String::new();
+    resp.read_to_string(&mut text)?;
+    if !resp.status().is_success() {
+        bail!("{} returned status {} to {}: {}", url, resp.status(), op, text);
+    }
+    Ok(text)
+}
+
+/// Returns the first capture of `re` in `text`, if any.
+fn capture(re: &Regex, text: &str) -> Option<String> {
+    re.captures(text).map(|c| c.get(1).unwrap().as_str().to_owned())
+}
+
+/// Asks the camera to reboot via the ONVIF `SystemReboot` operation.
+/// Returns the camera's message (typically something like "Rebooting in 30 seconds").
+pub fn reboot(host: &str, username: &str, password: &str) -> Result<String, Error> {
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+    let text = call(&client, &device_service_url(host), username, password, "SystemReboot",
+                    "<tds:SystemReboot/>")?;
+    Ok(capture(&MESSAGE_RE, &text).unwrap_or_else(String::new))
+}
+
+/// The result of the ONVIF `GetSystemDateAndTime` operation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SystemDateAndTime {
+    /// The camera's current time, in seconds since epoch.
+    pub utc_sec: i64,
+
+    /// True iff the camera sets its clock via NTP rather than manually.
+    pub ntp: bool,
+}
+
+fn parse_system_date_and_time(text: &str) -> Result<SystemDateAndTime, Error> {
+    let utc = element(text, "UTCDateTime").ok_or_else(|| format_err!("missing UTCDateTime"))?;
+    let t = element(&utc, "Time").ok_or_else(|| format_err!("missing Time"))?;
+    let d = element(&utc, "Date").ok_or_else(|| format_err!("missing Date"))?;
+    let (year, month, day) = (int_element(&d, "Year")?, int_element(&d, "Month")?,
+                              int_element(&d, "Day")?);
+    let (hour, minute, second) = (int_element(&t, "Hour")?, int_element(&t, "Minute")?,
+                                  int_element(&t, "Second")?);
+    if year < 1970 || month < 1 || month > 12 || day < 1 || day > 31 || hour > 23 ||
+       minute > 59 || second > 60 || hour < 0 || minute < 0 || second < 0 {
+        bail!("bad UTCDateTime {:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+              year, month, day, hour, minute, second);
+    }
+    let tm = time::Tm {
+        tm_year: year - 1900,
+        tm_mon: month - 1,
+        tm_mday: day,
+        tm_hour: hour,
+        tm_min: minute,
+        tm_sec: second,
+        ..time::empty_tm()
+    };
+    Ok(SystemDateAndTime {
+        utc_sec: tm.to_timespec().sec,
+        ntp: element(text, "DateTimeType").map(|t| t == "NTP").unwrap_or(false),
+    })
+}
+
+/// Gets the camera's clock via the ONVIF `GetSystemDateAndTime` operation.
+pub fn get_system_date_and_time(host: &str, username: &str, password: &str)
+                                -> Result<SystemDateAndTime, Error> {
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+    let text = call(&client, &device_service_url(host), username, password,
+                    "GetSystemDateAndTime", "<tds:GetSystemDateAndTime/>")?;
+    parse_system_date_and_time(&text)
+}
+
+/// The rate control settings of a `VideoEncoderConfiguration`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RateControl {
+    pub frame_rate_limit: i32,
+    pub encoding_interval: i32,
+    pub bitrate_limit_kbps: i32,
+}
+
+/// The H.264-specific settings of a `VideoEncoderConfiguration`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct H264 {
+    pub gov_length: i32,
+    pub profile: String,
+}
+
+/// A video encoder configuration, as in the ONVIF Media Service Specification section 5.6.
+/// Fields which Moonfire NVR doesn't change are kept as the camera sent them, so that they can be
+/// sent back unmodified by `set_encoder`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VideoEncoderConfiguration {
+    pub token: String,
+    pub name: String,
+
+    /// The number of media profiles using this configuration. If more than one, changing it
+    /// affects the camera's other streams as well.
+    pub use_count: i32,
+
+    /// The codec, such as `H264` or `JPEG`.
+    pub encoding: String,
+    pub width: i32,
+    pub height: i32,
+    quality: String,
+    pub rate_control: Option<RateControl>,
+    pub h264: Option<H264>,
+
+    /// The `Multicast` and `SessionTimeout` elements, with namespace prefixes set to `tt:`.
+    rest: String,
+}
+
+/// Returns the contents of the first element named `name` in `text`, regardless of its namespace
+/// prefix. These are still escaped; see `text_element` for leaf elements.
+fn element(text: &str, name: &str) -> Option<String> {
+    let start = ELEMENT_START_RE.captures_iter(text).find(|c| &c[1] == name)?.get(0)?.end();
+    let len = ELEMENT_END_RE.captures_iter(&text[start..]).find(|c| &c[1] == name)?.get(0)?.start();
+    Some(text[start .. start + len].trim().to_owned())
+}
+
+/// Returns the unescaped text of the first element named `name` in `text`.
+fn text_element(text: &str, name: &str) -> Option<String> {
+    element(text, name).map(|e| xml_unescape(&e))
+}
+
+fn int_element(text: &str, name: &str) -> Result<i32, Error> {
+    let e = element(text, name).ok_or_else(|| format_err!("missing {}", name))?;
+    e.parse().map_err(|_| format_err!("bad {} {:?}", name, e))
+}
+
+impl VideoEncoderConfiguration {
+    fn parse(token: &str, text: &str) -> Result<Self, Error> {
+        let resolution = element(text, "Resolution")
+            .ok_or_else(|| format_err!("missing Resolution"))?;
+        let rate_control = match element(text, "RateControl") {
+            None => None,
+            Some(r) => Some(RateControl {
+                frame_rate_limit: int_element(&r, "FrameRateLimit")?,
+                encoding_interval: int_element(&r, "EncodingInterval")?,
+                bitrate_limit_kbps: int_element(&r, "BitrateLimit")?,
+            }),
+        };
+        let h264 = match element(text, "H264") {
+            None => None,
+            Some(h) => Some(H264 {
+                gov_length: int_element(&h, "GovLength")?,
+                profile: text_element(&h, "H264Profile").unwrap_or_else(|| "Main".to_owned()),
+            }),
+        };
+        let mut rest = String::new();
+        for &name in &["Multicast", "SessionTimeout"] {
+            if let Some(e) = element(text, name) {
+                rest.push_str(&format!("<tt:{0}>{1}</tt:{0}>", name,
+                                       ELEMENT_PREFIX_RE.replace_all(&e, "<${1}tt:${2}")));
+            }
+        }
+        Ok(VideoEncoderConfiguration {
+            token: xml_unescape(token),
+            name: text_element(text, "Name").unwrap_or_else(String::new),
+            use_count: int_element(text, "UseCount").unwrap_or(1),
+            encoding: text_element(text, "Encoding")
+                .ok_or_else(|| format_err!("missing Encoding"))?,
+            width: int_element(&resolution, "Width")?,
+            height: int_element(&resolution, "Height")?,
+            quality: text_element(text, "Quality").unwrap_or_else(|| "1".to_owned()),
+            rate_control,
+            h264,
+            rest,
+        })
+    }
+
+    /// Applies the given settings, failing if the camera's configuration doesn't support them.
+    fn apply(&mut self, s: &db::EncoderSettings) -> Result<(), Error> {
+        if let Some(g) = s.gov_length {
+            match self.h264 {
+                Some(ref mut h) => h.gov_length = g,
+                None => bail!("can't set GOP length of {} encoder", self.encoding),
+            }
+        }
+        if s.bitrate_kbps.is_some() || s.frame_rate.is_some() {
+            let r = self.rate_control.as_mut()
+                        .ok_or_else(|| format_err!("encoder has no rate control"))?;
+            if let Some(b) = s.bitrate_kbps {
+                r.bitrate_limit_kbps = b;
+            }
+            if let Some(f) = s.frame_rate {
+                r.frame_rate_limit = f;
+            }
+        }
+        if let Some((w, h)) = s.resolution {
+            self.width = w;
+            self.height = h;
+        }
+        Ok(())
+    }
+
+    /// Returns the body of a `SetVideoEncoderConfiguration` request for this configuration.
+    fn set_request(&self) -> String {
+        let mut x = format!(
+            "<trt:SetVideoEncoderConfiguration><trt:Configuration token=\"{}\">\
+             <tt:Name>{}</tt:Name><tt:UseCount>{}</tt:UseCount><tt:Encoding>{}</tt:Encoding>\
+             <tt:Resolution><tt:Width>{}</tt:Width><tt:Height>{}</tt:Height></tt:Resolution>\
+             <tt:Quality>{}</tt:Quality>",
+            xml_escape(&self.token), xml_escape(&self.name), self.use_count,
+            xml_escape(&self.encoding), self.width, self.height, xml_escape(&self.quality));
+        if let Some(ref r) = self.rate_control {
+            x.push_str(&format!(
+                "<tt:RateControl><tt:FrameRateLimit>{}</tt:FrameRateLimit>\
+                 <tt:EncodingInterval>{}</tt:EncodingInterval>\
+                 <tt:BitrateLimit>{}</tt:BitrateLimit></tt:RateControl>",
+                r.frame_rate_limit, r.encoding_interval, r.bitrate_limit_kbps));
+        }
+        if let Some(ref h) = self.h264 {
+            x.push_str(&format!("<tt:H264><tt:GovLength>{}</tt:GovLength>\
+                                 <tt:H264Profile>{}</tt:H264Profile></tt:H264>",
+                                h.gov_length, xml_escape(&h.profile)));
+        }
+        x.push_str(&self.rest);
+        x.push_str("</trt:Configuration><trt:ForcePersistence>true</trt:ForcePersistence>\
+                    </trt:SetVideoEncoderConfiguration>");
+        x
+    }
+}
+
+/// Returns true if the `uri` from `GetStreamUri` is for the stream at `rtsp_path`. Cameras often
+/// add query parameters, so if the full path doesn't match, the path without them is compared.
+fn uri_matches(uri: &str, rtsp_path: &str) -> bool {
+    let path = match uri.find("://").map(|i| i + 3) {
+        Some(i) => uri[i..].find('/').map(|j| &uri[i+j..]).unwrap_or(""),
+        None => return false,
+    };
+    fn strip(p: &str) -> &str { p.split('?').next().unwrap() }
+    path == rtsp_path || strip(path) == strip(rtsp_path)
+}
+
+/// Finds the video encoder configuration of the media profile which streams `rtsp_path`.
+/// Returns the media service URL and the configuration.
+fn find_encoder(client: &reqwest::Client, host: &str, username: &str, password: &str,
+                rtsp_path: &str) -> Result<(String, VideoEncoderConfiguration), Error> {
+    let text = call(client, &device_service_url(host), username, password, "GetCapabilities",
+                    "<tds:GetCapabilities><tds:Category>Media</tds:Category>\
+                     </tds:GetCapabilities>")?;
+    let media_url = capture(&MEDIA_XADDR_RE, &text)
+        .ok_or_else(|| format_err!("{} has no media service", host))?;
+    let text = call(client, &media_url, username, password, "GetProfiles", "<trt:GetProfiles/>")?;
+    for p in PROFILE_RE.captures_iter(&text) {
+        let uri = call(client, &media_url, username, password, "GetStreamUri",
+                       &format!("<trt:GetStreamUri><trt:StreamSetup>\
+                                 <tt:Stream>RTP-Unicast</tt:Stream><tt:Transport>\
+                                 <tt:Protocol>RTSP</tt:Protocol></tt:Transport>\
+                                 </trt:StreamSetup><trt:ProfileToken>{}</trt:ProfileToken>\
+                                 </trt:GetStreamUri>", &p[1]))?;
+        if !capture(&URI_RE, &uri).map(|u| uri_matches(&u, rtsp_path)).unwrap_or(false) {
+            continue;
+        }
+        let e = VIDEO_ENCODER_RE.captures(&p[2])
+            .ok_or_else(|| format_err!("{} profile {} has no video encoder", host, &p[1]))?;
+        let c = VideoEncoderConfiguration::parse(&e[1], &e[2])
+            .map_err(|e| format_err!("{} profile {}: {}", host, &p[1], e))?;
+        return Ok((media_url, c));
+    }
+    bail!("{} has no media profile streaming {}", host, rtsp_path)
+}
+
+/// Returns the video encoder configuration of the camera's stream at `rtsp_path`.
+pub fn get_encoder(host: &str, username: &str, password: &str, rtsp_path: &str)
+                   -> Result<VideoEncoderConfiguration, Error> {
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+    Ok(find_encoder(&client, host, username, password, rtsp_path)?.1)
+}
+
+/// Pushes the given settings to the video encoder of the camera's stream at `rtsp_path`, asking
+/// the camera to persist them across reboots. Returns the new configuration.
+pub fn set_encoder(host: &str, username: &str, password: &str, rtsp_path: &str,
+                   s: &db::EncoderSettings) -> Result<VideoEncoderConfiguration, Error> {
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+    let (media_url, mut c) = find_encoder(&client, host, username, password, rtsp_path)?;
+    c.apply(s)?;
+    call(&client, &media_url, username, password, "SetVideoEncoderConfiguration",
+         &c.set_request())?;
+    Ok(c)
+}
+
+/// A notification received from a `PullPoint`, as in the ONVIF Core Specification section 9.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Notification {
+    /// The topic, such as `tns1:RuleEngine/CellMotionDetector/Motion`.
+    pub topic: String,
+
+    /// `SimpleItem`s identifying the source, such as the video source token and rule name.
+    pub source: Vec<(String, String)>,
+
+    /// `SimpleItem`s describing the event, such as `IsMotion` => `true`.
+    pub data: Vec<(String, String)>,
+}
+
+/// A `PullPointSubscription` to a camera's event service.
+pub struct PullPoint {
+    client: reqwest::Client,
+    username: String,
+    password: String,
+    address: String,
+    renewed: Instant,
+}
+
+impl PullPoint {
+    /// Subscribes to all events of the given camera, finding its event service via the device
+    /// service's `GetCapabilities` operation.
+    pub fn create(host: &str, username: &str, password: &str) -> Result<Self, Error> {
+        // PullMessages waits for up to 10 seconds for messages; allow some slack beyond that.
+        let client = reqwest::Client::builder().timeout(Duration::from_secs(30)).build()?;
+        let text = call(&client, &device_service_url(host), username, password, "GetCapabilities",
+                        "<tds:GetCapabilities><tds:Category>Events</tds:Category>\
+                         </tds:GetCapabilities>")?;
+        let events_url = capture(&EVENTS_XADDR_RE, &text)
+            .ok_or_else(|| format_err!("{} has no event service", host))?;
+        let text = call(&client, &events_url, username, password, "CreatePullPointSubscription",
+                        &format!("<tev:CreatePullPointSubscription><tev:InitialTerminationTime>\
+                                  PT{}S</tev:InitialTerminationTime>\
+                                  </tev:CreatePullPointSubscription>", SUBSCRIPTION_SEC))?;
+        let address = capture(&SUBSCRIPTION_ADDRESS_RE, &text)
+            .ok_or_else(|| format_err!("{} returned no subscription address", events_url))?;
+        Ok(PullPoint {
+            client,
+            username: username.to_owned(),
+            password: password.to_owned(),
+            address,
+            renewed: Instant::now(),
+        })
+    }
+
+    /// Waits up to 10 seconds for notifications, renewing the subscription as necessary.
+    pub fn pull(&mut self) -> Result<Vec<Notification>, Error> {
+        if self.renewed.elapsed() > Duration::from_secs(SUBSCRIPTION_SEC / 2) {
+            call(&self.client, &self.address, &self.username, &self.password, "Renew",
+                 &format!("<wsnt:Renew><wsnt:TerminationTime>PT{}S</wsnt:TerminationTime>\
+                           </wsnt:Renew>", SUBSCRIPTION_SEC))?;
+            self.renewed = Instant::now();
+        }
+        let text = call(&self.client, &self.address, &self.username, &self.password,
+                        "PullMessages",
+                        "<tev:PullMessages><tev:Timeout>PT10S</tev:Timeout>\
+                         <tev:MessageLimit>32</tev:MessageLimit></tev:PullMessages>")?;
+        Ok(parse_notifications(&text))
+    }
+}
+
+impl Drop for PullPoint {
+    fn drop(&mut self) {
+        let _ = call(&self.client, &self.address, &self.username, &self.password, "Unsubscribe",
+                     "<wsnt:Unsubscribe/>");
+    }
+}
+
+/// An object described in a `VideoAnalytics` frame of a metadata stream, as in the ONVIF
+/// Analytics Service Specification section 5.
+#[derive(Debug, PartialEq)]
+pub struct MetadataObject {
+    pub id: String,
+
+    /// The most likely class, such as `Human` or `Vehicle`, and its likelihood if given.
+    pub class: Option<(String, Option<f64>)>,
+
+    /// The bounding box as `[left, top, right, bottom]`, in the default normalized coordinate
+    /// system: -1 to 1, with y increasing upward.
+    pub bounding_box: Option<[f64; 4]>,
+}
+
+fn attributes(text: &str) -> Vec<(&str, &str)> {
+    ATTRIBUTE_RE.captures_iter(text)
+                .map(|c| (c.get(1).unwrap().as_str(), c.get(2).unwrap().as_str()))
+                .collect()
+}
+
+/// Parses the objects of a `MetadataStream` document. Objects without an appearance (such as
+/// those only being removed or merged) are included with no class or bounding box.
+pub fn parse_metadata_objects(text: &str) -> Vec<MetadataObject> {
+    OBJECT_RE.captures_iter(text).map(|m| {
+        let body = m.get(2).unwrap().as_str();
+        let bounding_box = BOUNDING_BOX_RE.captures(body).and_then(|b| {
+            let attrs = attributes(&b[1]);
+            let get = |k: &str| -> Option<f64> {
+                attrs.iter().find(|&&(n, _)| n == k).and_then(|&(_, v)| v.parse().ok())
+            };
+            Some([get("left")?, get("top")?, get("right")?, get("bottom")?])
+        });
+        let mut class: Option<(String, Option<f64>)> = None;
+        for t in CLASS_TYPE_RE.captures_iter(body) {
+            let likelihood: Option<f64> = t.get(1).and_then(|a| {
+                attributes(a.as_str()).iter().find(|&&(n, _)| n == "Likelihood")
+                                      .and_then(|&(_, v)| v.parse().ok())
+            });
+            let better = match class {
+                None => true,
+                Some(ref c) => likelihood.unwrap_or(0.) > c.1.unwrap_or(0.),
+            };
+            if better {
+                class = Some((t[2].trim().to_owned(), likelihood));
+            }
+        }
+        MetadataObject {
+            id: m[1].to_owned(),
+            class,
+            bounding_box,
+        }
+    }).collect()
+}
+
+fn simple_items(text: &str) -> Vec<(String, String)> {
+    SIMPLE_ITEM_RE.captures_iter(text)
+                  .map(|c| (c[1].to_owned(), c[2].to_owned()))
+                  .collect()
+}
+
+/// Parses the `NotificationMessage`s of a `PullMessagesResponse` or of the `Event` section of a
+/// metadata stream.
+pub fn parse_notifications(text: &str) -> Vec<Notification> {
+    NOTIFICATION_MESSAGE_RE.captures_iter(text).map(|m| {
+        let m = m.get(1).unwrap().as_str();
+        Notification {
+            topic: capture(&TOPIC_RE, m).map(|t| t.trim().to_owned()).unwrap_or_else(String::new),
+            source: SOURCE_RE.captures(m).map(|s| simple_items(&s[1])).unwrap_or_else(Vec::new),
+            data: DATA_RE.captures(m).map(|d| simple_items(&d[1])).unwrap_or_else(Vec::new),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::base64;
+
+    #[test]
+    fn test_password_digest() {
+        // Example from the ONVIF Application Programmer's Guide.
+        let nonce = base64::decode_block("LKqI6G/AikKCQrN0zqZFlg==").unwrap();
+        assert_eq!(super::password_digest(&nonce, "2010-09-16T07:50:45Z", "userpassword").unwrap(),
+                   "tuOSpGlFlIXsozq4HFNeeGeFLEI=");
+    }
+
+    #[test]
+    fn test_parse_system_date_and_time() {
+        let text = r#"<SOAP-ENV:Envelope><SOAP-ENV:Body><tds:GetSystemDateAndTimeResponse>
+<tds:SystemDateAndTime><tt:DateTimeType>NTP</tt:DateTimeType>
+<tt:DaylightSavings>false</tt:DaylightSavings><tt:TimeZone><tt:TZ>PST8PDT</tt:TZ></tt:TimeZone>
+<tt:UTCDateTime><tt:Time><tt:Hour>20</tt:Hour><tt:Minute>30</tt:Minute>
+<tt:Second>15</tt:Second></tt:Time><tt:Date><tt:Year>2019</tt:Year><tt:Month>3</tt:Month>
+<tt:Day>5</tt:Day></tt:Date></tt:UTCDateTime><tt:LocalDateTime><tt:Time><tt:Hour>12</tt:Hour>
+<tt:Minute>30</tt:Minute><tt:Second>15</tt:Second></tt:Time><tt:Date><tt:Year>2019</tt:Year>
+<tt:Month>3</tt:Month><tt:Day>5</tt:Day></tt:Date></tt:LocalDateTime></tds:SystemDateAndTime>
+</tds:GetSystemDateAndTimeResponse></SOAP-ENV:Body></SOAP-ENV:Envelope>"#;
+        assert_eq!(super::parse_system_date_and_time(text).unwrap(), super::SystemDateAndTime {
+            utc_sec: 1551817815,
+            ntp: true,
+        });
+        assert!(super::parse_system_date_and_time("<tt:UTCDateTime></tt:UTCDateTime>").is_err());
+    }
+
+    #[test]
+    fn test_device_service_url() {
+        assert_eq!(super::device_service_url("192.168.1.101"),
+                   "http://192.168.1.101/onvif/device_service");
+        assert_eq!(super::device_service_url("192.168.1.101:554"),
+                   "http://192.168.1.101/onvif/device_service");
+        assert_eq!(super::device_service_url("[fe80::1]"), "http://[fe80::1]/onvif/device_service");
+    }
+
+    #[test]
+    fn test_message_re() {
+        let resp = "<SOAP-ENV:Body><tds:SystemRebootResponse><tds:Message>Rebooting in 30 \
+                    seconds</tds:Message></tds:SystemRebootResponse></SOAP-ENV:Body>";
+        assert_eq!(super::MESSAGE_RE.captures(resp).unwrap().get(1).unwrap().as_str(),
+                   "Rebooting in 30 seconds");
+    }
+    #[test]
+    fn test_parse_notifications() {
+        let resp = r#"<SOAP-ENV:Body><tev:PullMessagesResponse>
+<tev:CurrentTime>2018-03-01T00:00:10Z</tev:CurrentTime>
+<wsnt:NotificationMessage>
+<wsnt:Topic Dialect="http://www.onvif.org/ver10/tev/topicExpression/ConcreteSet">
+tns1:RuleEngine/CellMotionDetector/Motion</wsnt:Topic>
+<wsnt:Message><tt:Message UtcTime="2018-03-01T00:00:09Z" PropertyOperation="Changed">
+<tt:Source><tt:SimpleItem Name="VideoSourceConfigurationToken" Value="VideoSourceToken"/>
+<tt:SimpleItem Name="Rule" Value="MyMotionDetectorRule"/></tt:Source>
+<tt:Data><tt:SimpleItem Name="IsMotion" Value="true"/></tt:Data>
+</tt:Message></wsnt:Message>
+</wsnt:NotificationMessage>
+<wsnt:NotificationMessage>
+<wsnt:Topic Dialect="http://www.onvif.org/ver10/tev/topicExpression/ConcreteSet">tns1:RuleEngine/LineDetector/Crossed</wsnt:Topic>
+<wsnt:Message><tt:Message UtcTime="2018-03-01T00:00:09Z">
+<tt:Data><tt:SimpleItem Name="ObjectId" Value="3"/></tt:Data>
+</tt:Message></wsnt:Message>
+</wsnt:NotificationMessage>
+</tev:PullMessagesResponse></SOAP-ENV:Body>"#;
+        let s = |a: &str, b: &str| (a.to_owned(), b.to_owned());
+        assert_eq!(super::parse_notifications(resp), vec![
+            super::Notification {
+                topic: "tns1:RuleEngine/CellMotionDetector/Motion".to_owned(),
+                source: vec![s("VideoSourceConfigurationToken", "VideoSourceToken"),
+                             s("Rule", "MyMotionDetectorRule")],
+                data: vec![s("IsMotion", "true")],
+            },
+            super::Notification {
+                topic: "tns1:RuleEngine/LineDetector/Crossed".to_owned(),
+                source: vec![],
+                data: vec![s("ObjectId", "3")],
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_metadata_objects() {
+        let doc = r#"<tt:MetadataStream xmlns:tt="http://www.onvif.org/ver10/schema">
+<tt:VideoAnalytics><tt:Frame UtcTime="2018-03-01T00:00:09.321Z">
+<tt:Object ObjectId="12"><tt:Appearance><tt:Shape>
+<tt:BoundingBox left="-0.5" top="0.5" right="0.0" bottom="-0.5"/>
+<tt:CenterOfGravity x="-0.25" y="0.0"/>
+</tt:Shape><tt:Class>
+<tt:Type Likelihood="0.3">Vehicle</tt:Type>
+<tt:Type Likelihood="0.8">Human</tt:Type>
+</tt:Class></tt:Appearance></tt:Object>
+<tt:Object ObjectId="13"></tt:Object>
+</tt:Frame></tt:VideoAnalytics></tt:MetadataStream>"#;
+        assert_eq!(super::parse_metadata_objects(doc), vec![
+            super::MetadataObject {
+                id: "12".to_owned(),
+                class: Some(("Human".to_owned(), Some(0.8))),
+                bounding_box: Some([-0.5, 0.5, 0.0, -0.5]),
+            },
+            super::MetadataObject {
+                id: "13".to_owned(),
+                class: None,
+                bounding_box: None,
+            },
+        ]);
+        assert!(super::METADATA_STREAM_END_RE.is_match(doc));
+    }
+
+    #[test]
+    fn test_uri_matches() {
+        assert!(super::uri_matches("rtsp://192.168.1.101:554/Streaming/Channels/101",
+                                   "/Streaming/Channels/101"));
+        assert!(super::uri_matches(
+            "rtsp://192.168.1.101/Streaming/Channels/102?transportmode=unicast&profile=Profile_2",
+            "/Streaming/Channels/102"));
+        assert!(!super::uri_matches("rtsp://192.168.1.101/Streaming/Channels/102",
+                                    "/Streaming/Channels/101"));
+        assert!(!super::uri_matches("bogus", "/"));
+    }
+
+    #[test]
+    fn test_video_encoder_configuration() {
+        let resp = r#"<trt:GetProfilesResponse>
+<trt:Profiles token="Profile_1" fixed="true"><tt:Name>mainStream</tt:Name>
+<tt:VideoSourceConfiguration token="VideoSourceToken"><tt:Name>VideoSourceConfig</tt:Name>
+</tt:VideoSourceConfiguration>
+<tt:VideoEncoderConfiguration token="VideoEncoderToken_1">
+<tt:Name>VideoEncoder_1</tt:Name><tt:UseCount>1</tt:UseCount><tt:Encoding>H264</tt:Encoding>
+<tt:Resolution><tt:Width>1920</tt:Width><tt:Height>1080</tt:Height></tt:Resolution>
+<tt:Quality>3.000000</tt:Quality>
+<tt:RateControl><tt:FrameRateLimit>30</tt:FrameRateLimit>
+<tt:EncodingInterval>1</tt:EncodingInterval><tt:BitrateLimit>4096</tt:BitrateLimit>
+</tt:RateControl>
+<tt:H264><tt:GovLength>60</tt:GovLength><tt:H264Profile>Main</tt:H264Profile></tt:H264>
+<tt:Multicast><tt:Address><tt:Type>IPv4</tt:Type><tt:IPv4Address>0.0.0.0</tt:IPv4Address>
+</tt:Address><tt:Port>8860</tt:Port><tt:TTL>128</tt:TTL><tt:AutoStart>false</tt:AutoStart>
+</tt:Multicast><tt:SessionTimeout>PT5S</tt:SessionTimeout>
+</tt:VideoEncoderConfiguration></trt:Profiles></trt:GetProfilesResponse>"#;
+        let p = super::PROFILE_RE.captures(resp).unwrap();
+        assert_eq!(&p[1], "Profile_1");
+        let e = super::VIDEO_ENCODER_RE.captures(&p[2]).unwrap();
+        let mut c = super::VideoEncoderConfiguration::parse(&e[1], &e[2]).unwrap();
+        assert_eq!(c.token, "VideoEncoderToken_1");
+        assert_eq!((c.width, c.height), (1920, 1080));
+        assert_eq!(c.rate_control, Some(super::RateControl {
+            frame_rate_limit: 30,
+            encoding_interval: 1,
+            bitrate_limit_kbps: 4096,
+        }));
+        c.apply(&::db::EncoderSettings {
+            gov_length: Some(15),
+            bitrate_kbps: Some(2048),
+            ..Default::default()
+        }).unwrap();
+        let req = c.set_request();
+        assert!(req.contains("<tt:GovLength>15</tt:GovLength>"));
+        assert!(req.contains("<tt:BitrateLimit>2048</tt:BitrateLimit>"));
+        assert!(req.contains("<tt:Width>1920</tt:Width>"));
+        assert!(req.contains("<tt:Port>8860</tt:Port>"));
+        assert!(req.contains("<tt:SessionTimeout>PT5S</tt:SessionTimeout>"));
+
+        c.h264 = None;
+        c.apply(&::db::EncoderSettings { gov_length: Some(15), ..Default::default() })
+         .unwrap_err();
+    }
+
+    #[test]
+    fn test_video_encoder_configuration_escaping() {
+        let text = "<tt:Name>front &amp; &lt;back&gt;</tt:Name><tt:Encoding>H264</tt:Encoding>\
+                    <tt:Resolution><tt:Width>640</tt:Width><tt:Height>480</tt:Height>\
+                    </tt:Resolution>";
+        let c = super::VideoEncoderConfiguration::parse("a&amp;b", text).unwrap();
+        assert_eq!(c.token, "a&b");
+        assert_eq!(c.name, "front & <back>");
+        let req = c.set_request();
+        assert!(req.contains(r#"token="a&amp;b""#), "{}", req);
+        assert!(req.contains("<tt:Name>front &amp; &lt;back&gt;</tt:Name>"), "{}", req);
+    }
+
+    #[test]
+    fn test_subscription_address_re() {
+        let resp = "<tev:CreatePullPointSubscriptionResponse><tev:SubscriptionReference>\
+                    <wsa5:Address>http://192.168.1.101/onvif/Subscription?Idx=0</wsa5:Address>\
+                    </tev:SubscriptionReference>";
+        assert_eq!(super::capture(&super::SUBSCRIPTION_ADDRESS_RE, resp).unwrap(),
+                   "http://192.168.1.101/onvif/Subscription?Idx=0");
+    }
+}
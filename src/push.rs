@@ -0,0 +1,304 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Web Push (RFC 8030) notifications, authenticated with VAPID (RFC 8292).
+//!
+//! Notifications are currently sent without a payload, as payloads require RFC 8291 message
+//! encryption. On receiving one, the UI's service worker is expected to fetch `/api/` to describe
+//! what happened.
+
+use db;
+use failure::Error;
+use openssl::{base64, bn, ec, ecdsa, hash, nid, pkey};
+use reqwest;
+use serde_json;
+use std::fs;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+use time;
+use url::Url;
+use vendor_events;
+
+/// The lifetime of each VAPID token. RFC 8292 section 2 limits this to 24 hours.
+const TOKEN_LIFETIME_SEC: i64 = 12 * 60 * 60;
+
+/// How long the push service should retain an undelivered notification.
+const TTL_SEC: u32 = 24 * 60 * 60;
+
+/// How long the push service should retain an undelivered urgent notification. A doorbell press
+/// is worth little once the visitor has left.
+const URGENT_TTL_SEC: u32 = 5 * 60;
+
+fn base64url(data: &[u8]) -> String {
+    base64::encode_block(data).trim_right_matches('=').replace('+', "-").replace('/', "_")
+}
+
+/// Returns the origin of the given push endpoint, as needed for the VAPID `aud` claim.
+fn origin(endpoint: &str) -> Result<String, Error> {
+    Ok(Url::parse(endpoint)?.origin().ascii_serialization())
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    aud: &'a str,
+    exp: i64,
+    sub: &'a str,
+}
+
+/// The server's VAPID identity.
+pub struct Vapid {
+    key: ec::EcKey<pkey::Private>,
+
+    /// The uncompressed public key, base64url-encoded, as expected by `PushManager.subscribe()`'s
+    /// `applicationServerKey`.
+    public_key: String,
+
+    /// A contact for the push service operator, such as `mailto:admin@example.com`.
+    subject: String,
+}
+
+impl Vapid {
+    /// Loads a P-256 private key in PEM format, such as one created via
+    /// `openssl ecparam -name prime256v1 -genkey -noout -out vapid.pem`.
+    pub fn load(path: &str, subject: String) -> Result<Self, Error> {
+        let pem = fs::read(path)?;
+        Vapid::new(ec::EcKey::private_key_from_pem(&pem)?, subject)
+    }
+
+    fn new(key: ec::EcKey<pkey::Private>, subject: String) -> Result<Self, Error> {
+        if key.group().curve_name() != Some(nid::Nid::X9_62_PRIME256V1) {
+            bail!("VAPID key must be on the P-256 curve");
+        }
+        let mut ctx = bn::BigNumContext::new()?;
+        let point = key.public_key().to_bytes(key.group(), ec::PointConversionForm::UNCOMPRESSED,
+                                               &mut ctx)?;
+        Ok(Vapid {
+            key,
+            public_key: base64url(&point),
+            subject,
+        })
+    }
+
+    pub fn public_key(&self) -> &str { &self.public_key }
+
+    /// Returns an `Authorization` header value for a push to the given endpoint.
+    fn authorization(&self, endpoint: &str, now_sec: i64) -> Result<String, Error> {
+        let aud = origin(endpoint)?;
+        let claims = serde_json::to_vec(&Claims {
+            aud: &aud,
+            exp: now_sec + TOKEN_LIFETIME_SEC,
+            sub: &self.subject,
+        })?;
+        let signing_input = format!("{}.{}", base64url(br#"{"typ":"JWT","alg":"ES256"}"#),
+                                    base64url(&claims));
+        let digest = hash::hash(hash::MessageDigest::sha256(), signing_input.as_bytes())?;
+        let sig = ecdsa::EcdsaSig::sign(&digest, &self.key)?;
+
+        // JWS wants the raw big-endian r and s, each padded to 32 bytes, rather than DER.
+        let mut raw = [0u8; 64];
+        let (r, s) = (sig.r().to_vec(), sig.s().to_vec());
+        raw[32 - r.len() .. 32].copy_from_slice(&r);
+        raw[64 - s.len() ..].copy_from_slice(&s);
+        Ok(format!("vapid t={}.{}, k={}", signing_input, base64url(&raw), self.public_key))
+    }
+}
+
+/// A notification to deliver to all subscribers.
+struct Notice {
+    why: String,
+
+    /// If true, the notification is delivered with `Urgency: high` (RFC 8030 section 5.3), waking
+    /// devices in power-saving modes, and a short TTL.
+    urgent: bool,
+}
+
+/// Describes a change worth notifying subscribers about, if any.
+fn describe(db: &db::LockedDatabase, c: &db::Change) -> Option<Notice> {
+    let (why, urgent) = match *c {
+        db::Change::StreamHealth { stream_id } => {
+            let s = db.streams_by_id().get(&stream_id)?;
+            let c = db.cameras_by_id().get(&s.camera_id)?;
+            match (s.health.state(), s.health.cause(&c.reachability)) {
+                // Notified via the camera's reachability change instead.
+                (_, Some("unreachable")) => return None,
+                (_, Some("authentication")) => {
+                    (format!("{}-{}: camera rejected the credentials", c.short_name,
+                             s.type_.as_str()), false)
+                },
+                ("failing", _) => {
+                    (format!("{}-{} is offline", c.short_name, s.type_.as_str()), false)
+                },
+                ("spooling", _) => (format!("{}-{}: storage is unavailable", c.short_name,
+                                            s.type_.as_str()), false),
+                ("degraded", _) => (format!("{}-{}: failing; recording from fallback stream",
+                                            c.short_name, s.type_.as_str()), false),
+                _ => return None,
+            }
+        },
+        db::Change::CameraReachability { camera_id } => {
+            let c = db.cameras_by_id().get(&camera_id)?;
+            if c.reachability.reachable != Some(false) {
+                return None;
+            }
+            (format!("{} is unreachable on the network", c.short_name), false)
+        },
+        db::Change::RetentionExceeded { stream_id } => {
+            let s = db.streams_by_id().get(&stream_id)?;
+            let c = db.cameras_by_id().get(&s.camera_id)?;
+            (format!("{}-{} exceeded its retention limit; old recordings will be deleted in {}",
+                     c.short_name, s.type_.as_str(), db.retention_grace()), false)
+        },
+        db::Change::EventAdded { ref event, .. } => {
+            let c = db.cameras_by_id().get(&event.camera_id)?;
+            if event.type_ == vendor_events::DOORBELL_EVENT_TYPE {
+                (format!("someone is at the door on {}", c.short_name), true)
+            } else {
+                (format!("{} event on {}", event.type_, c.short_name), false)
+            }
+        },
+        _ => return None,
+    };
+    Some(Notice { why, urgent })
+}
+
+/// Starts a thread which delivers notifications of interesting database changes (cameras going
+/// offline and events) to all push subscribers.
+pub fn start(db: Arc<db::Database>, vapid: Vapid) -> Result<(), Error> {
+    let (tx, rx) = mpsc::channel();
+    db.lock().watch(Box::new(move |db, c| {
+        if let Some(n) = describe(db, c) {
+            let _ = tx.send(n);
+        }
+    }));
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(30)).build()?;
+    thread::Builder::new()
+        .name("push".to_owned())
+        .spawn(move || {
+            for n in rx {
+                notify_all(&db, &vapid, &client, &n);
+            }
+        })?;
+    Ok(())
+}
+
+fn notify_all(db: &db::Database, vapid: &Vapid, client: &reqwest::Client, n: &Notice) {
+    let subs = match db.lock().list_push_subscriptions() {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("push: unable to list subscriptions: {}", e);
+            return;
+        },
+    };
+    info!("push: notifying {} subscribers: {}", subs.len(), n.why);
+    let now_sec = time::get_time().sec;
+    for s in &subs {
+        match notify(vapid, client, &s.endpoint, now_sec, n.urgent) {
+            Ok(true) => {},
+            Ok(false) => {
+                info!("push: subscription {} is gone; removing", &s.endpoint);
+                if let Err(e) = db.lock().delete_push_subscription(&s.endpoint) {
+                    warn!("push: unable to remove subscription {}: {}", &s.endpoint, e);
+                }
+            },
+            Err(e) => warn!("push: unable to notify {}: {}", &s.endpoint, e),
+        }
+    }
+}
+
+/// Sends a payload-less notification to the given endpoint.
+/// Returns false if the subscription no longer exists.
+fn notify(vapid: &Vapid, client: &reqwest::Client, endpoint: &str, now_sec: i64, urgent: bool)
+          -> Result<bool, Error> {
+    let (ttl, urgency) = if urgent { (URGENT_TTL_SEC, "high") } else { (TTL_SEC, "normal") };
+    let resp = client.post(endpoint)
+                     .header(reqwest::header::AUTHORIZATION, vapid.authorization(endpoint, now_sec)?)
+                     .header("TTL", ttl.to_string())
+                     .header("Urgency", urgency)
+                     .header(reqwest::header::CONTENT_LENGTH, "0")
+                     .send()?;
+    let status = resp.status();
+    if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::GONE {
+        return Ok(false);
+    }
+    if !status.is_success() {
+        bail!("push service returned status {}", status);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{base64, bn, ec, ecdsa, hash, nid};
+    use super::*;
+
+    fn unbase64url(s: &str) -> Vec<u8> {
+        let mut s = s.replace('-', "+").replace('_', "/");
+        while s.len() % 4 != 0 {
+            s.push('=');
+        }
+        base64::decode_block(&s).unwrap()
+    }
+
+    #[test]
+    fn test_origin() {
+        assert_eq!(origin("https://fcm.googleapis.com/fcm/send/abc:def").unwrap(),
+                   "https://fcm.googleapis.com");
+    }
+
+    #[test]
+    fn test_authorization() {
+        let group = ec::EcGroup::from_curve_name(nid::Nid::X9_62_PRIME256V1).unwrap();
+        let key = ec::EcKey::generate(&group).unwrap();
+        let vapid = Vapid::new(key.clone(), "mailto:admin@example.com".to_owned()).unwrap();
+        assert_eq!(unbase64url(vapid.public_key()).len(), 65);
+        let a = vapid.authorization("https://push.example.com/abc", 1_500_000_000).unwrap();
+        assert!(a.starts_with("vapid t="));
+        let k = a.rfind(", k=").unwrap();
+        assert_eq!(&a[k+4..], vapid.public_key());
+        let token = &a["vapid t=".len() .. k];
+        let parts: Vec<&str> = token.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let claims: serde_json::Value = serde_json::from_slice(&unbase64url(parts[1])).unwrap();
+        assert_eq!(claims["aud"], "https://push.example.com");
+        assert_eq!(claims["exp"], 1_500_000_000 + TOKEN_LIFETIME_SEC);
+        assert_eq!(claims["sub"], "mailto:admin@example.com");
+
+        // Verify the signature.
+        let raw = unbase64url(parts[2]);
+        assert_eq!(raw.len(), 64);
+        let sig = ecdsa::EcdsaSig::from_private_components(
+            bn::BigNum::from_slice(&raw[..32]).unwrap(),
+            bn::BigNum::from_slice(&raw[32..]).unwrap()).unwrap();
+        let signing_input = &token[.. parts[0].len() + 1 + parts[1].len()];
+        let digest = hash::hash(hash::MessageDigest::sha256(), signing_input.as_bytes()).unwrap();
+        assert!(sig.verify(&digest, &key).unwrap());
+    }
+}
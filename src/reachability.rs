@@ -0,0 +1,159 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Network reachability checks of cameras, independent of their RTSP streams, so that a failing
+//! stream can be reported as the camera being unreachable rather than (say) rejecting its
+//! credentials.
+//!
+//! A camera is checked by opening a TCP connection to its RTSP port (554 unless its `host`
+//! specifies another). This needs no privileges, unlike ICMP echo requests. A refused connection
+//! still counts as reachable: the camera is answering on the network, even if nothing is
+//! listening. Cameras are checked every `INTERVAL_SEC` seconds, and a camera is checked
+//! immediately when one of its streams starts failing.
+
+use db;
+use failure::Error;
+use resolve;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+
+/// How often to check all cameras, in seconds.
+const INTERVAL_SEC: u64 = 30;
+
+/// How long to wait for each connection attempt, in seconds.
+const TIMEOUT_SEC: u64 = 3;
+
+const DEFAULT_RTSP_PORT: u16 = 554;
+
+/// Returns `host` with the default RTSP port appended if it has none. Bare IPv6 addresses are
+/// bracketed.
+fn with_default_port(host: &str) -> String {
+    let (name, port) = resolve::split_port(host);
+    let port = port.map(str::to_owned).unwrap_or_else(|| DEFAULT_RTSP_PORT.to_string());
+    if name.contains(':') {
+        format!("[{}]:{}", name, port)
+    } else {
+        format!("{}:{}", name, port)
+    }
+}
+
+/// Checks if the given camera host answers on the network.
+pub fn check(host: &str) -> Result<(), Error> {
+    let resolved = resolve::resolve_local(host)?;
+    let addr = with_default_port(resolved.as_ref().map(String::as_str).unwrap_or(host));
+    let addrs = addr.to_socket_addrs()
+                    .map_err(|e| format_err!("unable to resolve {}: {}", addr, e))?;
+    let mut last_err = None;
+    for a in addrs {
+        match TcpStream::connect_timeout(&a, Duration::from_secs(TIMEOUT_SEC)) {
+            Ok(_) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => return Ok(()),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    match last_err {
+        None => bail!("{} resolved to no addresses", addr),
+        Some(e) => bail!("unable to connect to {}: {}", addr, e),
+    }
+}
+
+/// Starts a thread which checks the reachability of all cameras, recording the results with
+/// `LockedDatabase::update_camera_reachability`.
+pub fn start(db: Arc<db::Database>) -> Result<(), Error> {
+    // The watcher is called with the database lock held, so checks can't be done directly.
+    let (tx, rx) = mpsc::channel();
+    db.lock().watch(Box::new(move |db, c| {
+        if let db::Change::StreamHealth { stream_id } = *c {
+            if let Some(s) = db.streams_by_id().get(&stream_id) {
+                if s.health.state() == "failing" {
+                    let _ = tx.send(s.camera_id);
+                }
+            }
+        }
+    }));
+    thread::Builder::new()
+        .name("reachability".to_owned())
+        .spawn(move || {
+            loop {
+                let only = match rx.recv_timeout(Duration::from_secs(INTERVAL_SEC)) {
+                    Ok(id) => Some(id),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                };
+                let cameras: Vec<(i32, String, String)> = {
+                    let l = db.lock();
+                    l.cameras_by_id().values()
+                     .filter(|c| only.map(|id| id == c.id).unwrap_or(true))
+                     .map(|c| (c.id, c.short_name.clone(), c.host.clone()))
+                     .collect()
+                };
+                for (id, short_name, host) in cameras {
+                    let error = check(&host).err().map(|e| e.to_string());
+                    match db.lock().update_camera_reachability(id, error.clone()) {
+                        Ok(false) => {},
+                        Ok(true) => match error {
+                            None => info!("{}: camera is reachable", short_name),
+                            Some(e) => warn!("{}: camera is unreachable: {}", short_name, e),
+                        },
+                        Err(e) => warn!("{}: unable to update reachability: {}", short_name, e),
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_with_default_port() {
+        assert_eq!(super::with_default_port("192.168.1.5"), "192.168.1.5:554");
+        assert_eq!(super::with_default_port("cam.local:8554"), "cam.local:8554");
+        assert_eq!(super::with_default_port("fe80::1"), "[fe80::1]:554");
+        assert_eq!(super::with_default_port("[fe80::1]"), "[fe80::1]:554");
+        assert_eq!(super::with_default_port("[fe80::1]:8554"), "[fe80::1]:8554");
+    }
+
+    #[test]
+    fn test_check() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = l.local_addr().unwrap();
+        super::check(&addr.to_string()).unwrap();
+
+        // A refused connection means the host answered.
+        drop(l);
+        super::check(&addr.to_string()).unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Moving a stream's recordings to another sample file directory (such as when replacing a
+//! disk), via `moveStream` jobs (see `jobs`) created by `/api/streams/<id>/move`.
+//!
+//! The job pauses the stream and waits for its recordings in progress to be committed, then
+//! copies each sample file to the new directory, checking it against the recording's SHA-1 hash
+//! and reading it back to verify the copy. It then switches the stream to the new directory with
+//! `LockedDatabase::move_stream`, which moves the old files to the garbage table to be unlinked as
+//! if deleted. The stream's writer is bound to the old directory's syncer, so recording resumes
+//! in the new directory only after the server restarts.
+
+use db::{self, CompositeId, RecordingFlags};
+use db::dir::SampleFileDir;
+use failure::Error;
+use fnv::FnvHashSet;
+use jobs;
+use openssl::hash;
+use serde_json;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use teardown;
+use time;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Params {
+    pub stream: i32,
+
+    /// The id of the sample file directory to move to.
+    pub dir: i32,
+
+    /// The user who requested the move, for the audit log.
+    pub user: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+struct Progress {
+    stage: &'static str,
+    sample_files: usize,
+    sample_files_copied: usize,
+    sample_files_unlinked: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+struct Summary {
+    sample_files: usize,
+    bytes: i64,
+    sample_files_pending: usize,
+}
+
+/// Copies the given sample file from `src` to `dst`, syncing it. The copy is verified against
+/// `sha1` if known and in any case against the source's contents as read. Returns its length.
+fn copy_file(src: &SampleFileDir, dst: &SampleFileDir, id: CompositeId, sha1: Option<[u8; 20]>)
+             -> Result<usize, Error> {
+    let mut buf = Vec::new();
+    src.open_file(id)?.read_to_end(&mut buf)?;
+    let actual = hash::hash(hash::MessageDigest::sha1(), &buf)?;
+    if let Some(ref s) = sha1 {
+        if &actual[..] != &s[..] {
+            bail!("recording {} doesn't match its SHA-1 hash; not moving a corrupt file", id);
+        }
+    }
+    let mut f = match dst.create_file(id) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            // Left by an earlier, interrupted attempt.
+            dst.unlink_unreferenced(&[id])?;
+            dst.create_file(id)?
+        },
+        r => r?,
+    };
+    let mut remaining = &buf[..];
+    while !remaining.is_empty() {
+        let n = f.write(remaining)?;
+        remaining = &remaining[n..];
+    }
+    f.sync_all()?;
+    drop(f);
+    let mut copy = Vec::with_capacity(buf.len());
+    dst.open_file(id)?.read_to_end(&mut copy)?;
+    if copy != buf {
+        bail!("copy of recording {} doesn't match the original", id);
+    }
+    Ok(buf.len())
+}
+
+pub struct StreamMover {
+    db: Arc<db::Database>,
+
+    /// See `teardown::syncer_dirs`.
+    syncer_dirs: FnvHashSet<i32>,
+}
+
+impl StreamMover {
+    pub fn new(db: Arc<db::Database>) -> Self {
+        let syncer_dirs = teardown::syncer_dirs(&db);
+        StreamMover { db, syncer_dirs }
+    }
+
+    /// Copies the stream's recordings (other than archived ones, which have no sample files)
+    /// from `src` to `dst`. Returns the range of recording ids covered and the ids and total
+    /// bytes copied.
+    fn copy(&self, job: &db::Job, stream_id: i32, src: &SampleFileDir, dst: &SampleFileDir,
+            cancel: &AtomicBool) -> Result<(Range<i32>, Vec<CompositeId>, i64), Error> {
+        let mut ids = Vec::new();
+        let covered = {
+            let l = self.db.lock();
+            let covered = 0 .. l.streams_by_id()[&stream_id].next_recording_id;
+            l.list_recordings_by_id(stream_id, covered.clone(), &mut |r| {
+                if (r.flags & RecordingFlags::Archived as i32) == 0 {
+                    ids.push(r.id);
+                }
+                Ok(())
+            })?;
+            covered
+        };
+        let mut copied = Vec::with_capacity(ids.len());
+        let mut bytes = 0;
+        let mut last_progress_sec = 0;
+        for &id in &ids {
+            if cancel.load(Ordering::SeqCst) {
+                dst.unlink_unreferenced(&copied)?;
+                bail!("cancelled");
+            }
+            let now_sec = time::get_time().sec;
+            if now_sec != last_progress_sec {
+                jobs::set_progress(&self.db, job, &Progress {
+                    stage: "copying",
+                    sample_files: ids.len(),
+                    sample_files_copied: copied.len(),
+                    sample_files_unlinked: 0,
+                })?;
+                last_progress_sec = now_sec;
+            }
+            let sha1 = self.db.lock().get_sample_file_sha1(id)?;
+            match copy_file(src, dst, id, sha1) {
+                Ok(n) => bytes += n as i64,
+                Err(e) => {
+                    copied.push(id);  // possibly partially.
+                    dst.unlink_unreferenced(&copied)?;
+                    return Err(e);
+                },
+            }
+            copied.push(id);
+        }
+        dst.sync()?;
+        Ok((covered, copied, bytes))
+    }
+}
+
+impl jobs::Handler for StreamMover {
+    fn run(&self, job: &db::Job, cancel: &AtomicBool) -> Result<String, Error> {
+        let p: Params = serde_json::from_str(&job.params)?;
+        let (name, was_paused, old_dir_id, src, dst) = {
+            let mut l = self.db.lock();
+            let (name, was_paused, old_dir_id) = {
+                let s = l.streams_by_id().get(&p.stream)
+                         .ok_or_else(|| format_err!("no such stream {}", p.stream))?;
+                let c = &l.cameras_by_id()[&s.camera_id];
+                let old_dir_id = s.sample_file_dir_id
+                    .ok_or_else(|| format_err!("stream {} has no sample file dir", p.stream))?;
+                (format!("{}/{}", c.short_name, s.type_.as_str()), s.paused, old_dir_id)
+            };
+            if old_dir_id == p.dir {
+                bail!("stream {} is already in dir {}", name, p.dir);
+            }
+            l.open_sample_file_dirs(&[p.dir])?;
+            let dirs = l.sample_file_dirs_by_id();
+            let src = dirs[&old_dir_id].get()?;
+            let dst = dirs.get(&p.dir).ok_or_else(|| format_err!("no such dir {}", p.dir))?.get()?;
+            if src.cipher().is_some() || dst.cipher().is_some() {
+                bail!("moving recordings to or from an encrypted dir isn't supported");
+            }
+            (name, was_paused, old_dir_id, src, dst)
+        };
+        jobs::set_progress(&self.db, job, &Progress {
+            stage: "pausing",
+            sample_files: 0,
+            sample_files_copied: 0,
+            sample_files_unlinked: 0,
+        })?;
+        let r = teardown::settle(&self.db, &[p.stream], cancel).and_then(|()| {
+            let (covered, copied, bytes) = self.copy(job, p.stream, &src, &dst, cancel)?;
+            let moved = {
+                let mut l = self.db.lock();
+                let moved = match l.move_stream(p.stream, p.dir, covered) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        drop(l);
+                        dst.unlink_unreferenced(&copied)?;
+                        return Err(e);
+                    },
+                };
+                l.flush("stream move")?;  // notifies the old dir's syncer of the garbage.
+                moved
+            };
+
+            // Copies of recordings deleted by retention while copying are unreferenced.
+            let moved_set: FnvHashSet<CompositeId> = moved.iter().cloned().collect();
+            let orphaned: Vec<CompositeId> =
+                copied.iter().cloned().filter(|id| !moved_set.contains(id)).collect();
+            dst.unlink_unreferenced(&orphaned)?;
+            Ok((moved, bytes))
+        });
+        if !was_paused {
+            // Recording resumes in the new directory after a restart; see the module doc.
+            teardown::resume(&self.db, &[p.stream])?;
+        }
+        let (moved, bytes) = r?;
+        info!(target: "audit", "{} moved stream {} from dir {} to dir {}: {} recordings, {} bytes",
+              p.user, name, old_dir_id, p.dir, moved.len(), bytes);
+
+        let garbage: Vec<(i32, CompositeId)> = moved.iter().map(|&id| (old_dir_id, id)).collect();
+        let pending = teardown::unlink(&self.db, &self.syncer_dirs, &garbage, &mut |n| {
+            jobs::set_progress(&self.db, job, &Progress {
+                stage: "unlinking",
+                sample_files: garbage.len(),
+                sample_files_copied: garbage.len(),
+                sample_files_unlinked: n,
+            })
+        })?;
+        if pending > 0 {
+            warn!("stream {}: {} old sample files not yet unlinked; they remain in the garbage \
+                   table", name, pending);
+        }
+        Ok(serde_json::to_string(&Summary {
+            sample_files: moved.len(),
+            bytes,
+            sample_files_pending: pending,
+        })?)
+    }
+}
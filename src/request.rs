@@ -0,0 +1,801 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Parsing of HTTP request paths and query parameters.
+//!
+//! Everything here is reachable by unauthenticated clients, so it's deliberately strict: each
+//! path and parameter has a single canonical form, and anything else is rejected rather than
+//! guessed at. This keeps cache keys (such as `web::ServiceInner::mp4_cache`'s) from aliasing and
+//! makes the accepted language small enough to test exhaustively.
+
+use base::strutil;
+use db;
+use failure::Error;
+use json;
+use regex::Regex;
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+use url::percent_encoding::percent_decode;
+use uuid::Uuid;
+
+lazy_static! {
+    /// Regex used to parse the `s` query parameter to `view.mp4`.
+    /// As described in `design/api.md`, this is of the form
+    /// `START_ID[-END_ID][@OPEN_ID][.[REL_START_TIME]-[REL_END_TIME]]`.
+    static ref SEGMENTS_RE: Regex =
+        Regex::new(r"^(\d+)(-\d+)?(@\d+)?(?:\.(\d+)?-(\d+)?)?$").unwrap();
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Path {
+    TopLevel,                                    // "/api/"
+    Probe,                                       // "/api/probe"
+    Batch,                                       // "/api/batch"
+    Coverage,                                    // "/api/coverage"
+    InitSegment([u8; 20]),                       // "/api/init/<sha1>.mp4"
+    Camera(Uuid),                                // "/api/cameras/<uuid>/"
+    CameraEvents(Uuid),                          // "/api/cameras/<uuid>/events"
+    CameraReboot(Uuid),                          // "/api/cameras/<uuid>/reboot"
+    CameraCredentials(Uuid),                     // "/api/cameras/<uuid>/credentials"
+    CameraSnapshots(Uuid),                       // "/api/cameras/<uuid>/snapshots"
+    CameraSnapshot(Uuid),                        // "/api/cameras/<uuid>/snapshot.jpg"
+    EventStream,                                 // "/api/events/stream"
+    Maintenance,                                 // "/api/admin/maintenance"
+    Logs,                                        // "/api/admin/logs"
+    EventClip(i64),                              // "/api/events/<id>.mp4"
+    EventSnapshot(i64),                          // "/api/events/<id>.jpg"
+    Metrics,                                     // "/api/metrics"
+    Bandwidth,                                   // "/api/stats/bandwidth"
+    Viewers,                                     // "/api/viewers"
+    Mosaic,                                      // "/api/mosaic.mjpeg"
+    Exports,                                     // "/api/export"
+    ExportFile(Uuid, db::ExportContainer),       // "/api/export/<id>.<mp4|mkv>"
+    Jobs,                                        // "/api/jobs"
+    Holds,                                       // "/api/holds"
+    Incidents,                                   // "/api/incidents"
+    Incident(Uuid),                              // "/api/incidents/<id>"
+    Hold(Uuid),                                  // "/api/holds/<id>"
+    Layouts,                                     // "/api/layouts"
+    Layout(Uuid),                                // "/api/layouts/<id>"
+    Job(Uuid),                                   // "/api/jobs/<id>"
+    Push,                                        // "/api/push"
+    Embeds,                                      // "/api/embed"
+    EmbedPage(String),                           // "/embed/<token>"
+    EmbedMp4(String),                            // "/embed/<token>/video.mp4"
+    UserPreferences(i32),                        // "/api/users/<id>/preferences"
+    StreamMove(i32),                             // "/api/streams/<id>/move"
+    StreamRecordings(Uuid, db::StreamType),      // "/api/cameras/<uuid>/<type>/recordings"
+    StreamRecordingUpdates(Uuid, db::StreamType), // "/api/cameras/<uuid>/<type>/recordings/updates"
+    StreamRecordingArchive(Uuid, db::StreamType), // "/api/cameras/<uuid>/<type>/recordings/archive"
+    StreamIndex(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/index"
+    StreamNotes(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/notes"
+    StreamViewMp4(Uuid, db::StreamType),         // "/api/cameras/<uuid>/<type>/view.mp4"
+    StreamViewMp4Segment(Uuid, db::StreamType),  // "/api/cameras/<uuid>/<type>/view.m4s"
+    StreamViewVtt(Uuid, db::StreamType),         // "/api/cameras/<uuid>/<type>/view.vtt"
+    StreamSnapshot(Uuid, db::StreamType),        // "/api/cameras/<uuid>/<type>/snapshot.jpg"
+    StreamThumbnails(Uuid, db::StreamType),      // "/api/cameras/<uuid>/<type>/thumbnails"
+    StreamThumbnail(Uuid, db::StreamType),       // "/api/cameras/<uuid>/<type>/thumbnail.jpg"
+    StreamMetadata(Uuid, db::StreamType),        // "/api/cameras/<uuid>/<type>/metadata"
+    StreamExportEmail(Uuid, db::StreamType),     // "/api/cameras/<uuid>/<type>/export/email"
+    StreamEnable(Uuid, db::StreamType),          // "/api/cameras/<uuid>/<type>/enable"
+    StreamDisable(Uuid, db::StreamType),         // "/api/cameras/<uuid>/<type>/disable"
+    StreamEncoder(Uuid, db::StreamType),         // "/api/cameras/<uuid>/<type>/encoder"
+    StreamUsage(Uuid, db::StreamType),           // "/api/cameras/<uuid>/<type>/usage"
+    Healthz,                                     // "/healthz"
+    Readyz,                                      // "/readyz"
+    Static,                                      // "<other path>"
+    NotFound,
+}
+
+impl Path {
+    /// Returns the camera this path is specific to, if any.
+    pub fn camera_uuid(&self) -> Option<Uuid> {
+        match *self {
+            Path::Camera(u) | Path::CameraEvents(u) | Path::CameraReboot(u) |
+            Path::CameraCredentials(u) |
+            Path::CameraSnapshots(u) | Path::CameraSnapshot(u) |
+            Path::StreamRecordings(u, _) | Path::StreamRecordingUpdates(u, _) |
+            Path::StreamRecordingArchive(u, _) |
+            Path::StreamIndex(u, _) | Path::StreamNotes(u, _) |
+            Path::StreamViewMp4(u, _) | Path::StreamViewMp4Segment(u, _) |
+            Path::StreamViewVtt(u, _) | Path::StreamSnapshot(u, _) | Path::StreamMetadata(u, _) |
+            Path::StreamThumbnails(u, _) | Path::StreamThumbnail(u, _) |
+            Path::StreamExportEmail(u, _) | Path::StreamEnable(u, _) |
+            Path::StreamDisable(u, _) | Path::StreamEncoder(u, _) |
+            Path::StreamUsage(u, _) => Some(u),
+            _ => None,
+        }
+    }
+
+    /// Returns true if serving this path may block for a while, such as on a request to a
+    /// camera. These are served on a thread pool rather than the reactor.
+    pub fn blocks(&self) -> bool {
+        match *self {
+            Path::Probe | Path::CameraReboot(_) | Path::EventSnapshot(_) |
+            Path::StreamSnapshot(..) | Path::StreamEncoder(..) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Decodes the request path. `db` is used only to resolve camera short names.
+pub fn decode_path(path: &str, db: &db::Database) -> Path {
+    if path.starts_with("/embed/") {
+        return decode_embed_path(&path["/embed".len()..]);
+    }
+    if path == "/healthz" {
+        return Path::Healthz;
+    }
+    if path == "/readyz" {
+        return Path::Readyz;
+    }
+    if !path.starts_with("/api/") {
+        return Path::Static;
+    }
+    let path = &path["/api".len()..];
+    if path == "/" {
+        return Path::TopLevel;
+    }
+    if path == "/probe" {
+        return Path::Probe;
+    }
+    if path == "/batch" {
+        return Path::Batch;
+    }
+    if path == "/coverage" {
+        return Path::Coverage;
+    }
+    if path == "/events/stream" {
+        return Path::EventStream;
+    }
+    if path.starts_with("/events/") && path.ends_with(".mp4") {
+        return match parse_decimal(&path["/events/".len() .. path.len() - ".mp4".len()]) {
+            Ok(id) => Path::EventClip(id),
+            Err(_) => Path::NotFound,
+        };
+    }
+    if path.starts_with("/events/") && path.ends_with(".jpg") {
+        return match parse_decimal(&path["/events/".len() .. path.len() - ".jpg".len()]) {
+            Ok(id) => Path::EventSnapshot(id),
+            Err(_) => Path::NotFound,
+        };
+    }
+    if path == "/metrics" {
+        return Path::Metrics;
+    }
+    if path == "/stats/bandwidth" {
+        return Path::Bandwidth;
+    }
+    if path == "/viewers" {
+        return Path::Viewers;
+    }
+    if path == "/admin/maintenance" {
+        return Path::Maintenance;
+    }
+    if path == "/admin/logs" {
+        return Path::Logs;
+    }
+    if path == "/push" {
+        return Path::Push;
+    }
+    if path == "/embed" {
+        return Path::Embeds;
+    }
+    if path.starts_with("/users/") && path.ends_with("/preferences") &&
+       path.len() >= "/users//preferences".len() {
+        let id = &path["/users/".len() .. path.len() - "/preferences".len()];
+        return match parse_decimal(id) {
+            Ok(id) => Path::UserPreferences(id),
+            Err(_) => Path::NotFound,
+        };
+    }
+    if path.starts_with("/streams/") && path.ends_with("/move") &&
+       path.len() >= "/streams//move".len() {
+        let id = &path["/streams/".len() .. path.len() - "/move".len()];
+        return match parse_decimal(id) {
+            Ok(id) => Path::StreamMove(id),
+            Err(_) => Path::NotFound,
+        };
+    }
+    if path == "/mosaic.mjpeg" {
+        return Path::Mosaic;
+    }
+    if path == "/export" {
+        return Path::Exports;
+    }
+    if path.starts_with("/export/") {
+        let file = &path["/export/".len() ..];
+        let dot = match file.rfind('.') {
+            None => return Path::NotFound,
+            Some(d) => d,
+        };
+        return match (parse_uuid(&file[.. dot]), db::ExportContainer::parse(&file[dot+1 ..])) {
+            (Ok(id), Some(c)) => Path::ExportFile(id, c),
+            _ => Path::NotFound,
+        };
+    }
+    if path == "/jobs" {
+        return Path::Jobs;
+    }
+    if path.starts_with("/jobs/") {
+        return match parse_uuid(&path["/jobs/".len()..]) {
+            Ok(id) => Path::Job(id),
+            Err(_) => Path::NotFound,
+        };
+    }
+    if path == "/holds" {
+        return Path::Holds;
+    }
+    if path == "/layouts" {
+        return Path::Layouts;
+    }
+    if path.starts_with("/layouts/") {
+        return match parse_uuid(&path["/layouts/".len()..]) {
+            Ok(id) => Path::Layout(id),
+            Err(_) => Path::NotFound,
+        };
+    }
+    if path == "/incidents" {
+        return Path::Incidents;
+    }
+    if path.starts_with("/incidents/") {
+        return match parse_uuid(&path["/incidents/".len()..]) {
+            Ok(id) => Path::Incident(id),
+            Err(_) => Path::NotFound,
+        };
+    }
+    if path.starts_with("/holds/") {
+        return match parse_uuid(&path["/holds/".len()..]) {
+            Ok(id) => Path::Hold(id),
+            Err(_) => Path::NotFound,
+        };
+    }
+    if path.starts_with("/init/") {
+        if path.len() != 50 || !path.ends_with(".mp4") {
+            return Path::NotFound;
+        }
+        if let Ok(sha1) = strutil::dehex(&path.as_bytes()[6..46]) {
+            return Path::InitSegment(sha1);
+        }
+        return Path::NotFound;
+    }
+    if !path.starts_with("/cameras/") {
+        return Path::NotFound;
+    }
+    let path = &path["/cameras/".len()..];
+    let slash = match path.find('/') {
+        None => { return Path::NotFound; },
+        Some(s) => s,
+    };
+    let camera = &path[0 .. slash];
+    let path = &path[slash+1 .. ];
+
+    // A camera is identified either by the canonical (lowercase, hyphenated) form of its uuid or
+    // by its percent-encoded short name.
+    let uuid = match parse_uuid(camera) {
+        Ok(u) => u,
+        Err(_) => {
+            let name = match percent_decode(camera.as_bytes()).decode_utf8() {
+                Ok(n) => n,
+                Err(_) => { return Path::NotFound },
+            };
+            match db.lock().get_camera_by_short_name(&name) {
+                Some(c) => c.uuid,
+                None => { return Path::NotFound },
+            }
+        },
+    };
+
+    if path.is_empty() {
+        return Path::Camera(uuid);
+    }
+    match path {
+        "events" => return Path::CameraEvents(uuid),
+        "reboot" => return Path::CameraReboot(uuid),
+        "credentials" => return Path::CameraCredentials(uuid),
+        "snapshots" => return Path::CameraSnapshots(uuid),
+        "snapshot.jpg" => return Path::CameraSnapshot(uuid),
+        _ => {},
+    }
+
+    let slash = match path.find('/') {
+        None => { return Path::NotFound; },
+        Some(s) => s,
+    };
+    let (type_, path) = path.split_at(slash);
+
+    let type_ = match db::StreamType::parse(type_) {
+        None => { return Path::NotFound; },
+        Some(t) => t,
+    };
+    match path {
+        "/recordings" => Path::StreamRecordings(uuid, type_),
+        "/recordings/updates" => Path::StreamRecordingUpdates(uuid, type_),
+        "/recordings/archive" => Path::StreamRecordingArchive(uuid, type_),
+        "/index" => Path::StreamIndex(uuid, type_),
+        "/notes" => Path::StreamNotes(uuid, type_),
+        "/view.mp4" => Path::StreamViewMp4(uuid, type_),
+        "/view.m4s" => Path::StreamViewMp4Segment(uuid, type_),
+        "/view.vtt" => Path::StreamViewVtt(uuid, type_),
+        "/snapshot.jpg" => Path::StreamSnapshot(uuid, type_),
+        "/thumbnails" => Path::StreamThumbnails(uuid, type_),
+        "/thumbnail.jpg" => Path::StreamThumbnail(uuid, type_),
+        "/metadata" => Path::StreamMetadata(uuid, type_),
+        "/export/email" => Path::StreamExportEmail(uuid, type_),
+        "/enable" => Path::StreamEnable(uuid, type_),
+        "/disable" => Path::StreamDisable(uuid, type_),
+        "/encoder" => Path::StreamEncoder(uuid, type_),
+        "/usage" => Path::StreamUsage(uuid, type_),
+        _ => Path::NotFound,
+    }
+}
+
+/// Decodes the portion of an `/embed/` path after `/embed`. The token is only syntax-checked here;
+/// see `embed::Signer::verify`.
+fn decode_embed_path(path: &str) -> Path {
+    let token = &path["/".len()..];
+    let (token, mp4) = if token.ends_with("/video.mp4") {
+        (&token[.. token.len() - "/video.mp4".len()], true)
+    } else {
+        (token, false)
+    };
+    let valid = |b: u8| b.is_ascii_digit() || b.is_ascii_lowercase() || b == b'.' || b == b'-';
+    if token.is_empty() || !token.bytes().all(valid) {
+        return Path::NotFound;
+    }
+    if mp4 { Path::EmbedMp4(token.to_owned()) } else { Path::EmbedPage(token.to_owned()) }
+}
+
+/// Parses a non-negative decimal integer in canonical form: ASCII digits, without leading zeros.
+pub fn parse_decimal<T: FromStr>(s: &str) -> Result<T, Error> {
+    let b = s.as_bytes();
+    if b.is_empty() || !b.iter().all(|c| c.is_ascii_digit()) || (b[0] == b'0' && b.len() > 1) {
+        bail!("invalid number {:?}", s);
+    }
+    T::from_str(s).map_err(|_| format_err!("number {:?} out of range", s))
+}
+
+/// Parses a UUID in canonical (lowercase, hyphenated) form.
+pub fn parse_uuid(s: &str) -> Result<Uuid, Error> {
+    match Uuid::parse_str(s) {
+        Ok(u) if u.to_hyphenated_ref().to_string() == s => Ok(u),
+        _ => bail!("invalid uuid {:?}; expected lowercase hyphenated form", s),
+    }
+}
+
+/// Parses an `application/x-www-form-urlencoded` query string.
+/// Unlike `url::form_urlencoded::parse`, this rejects (rather than skipping or lossily decoding)
+/// empty pairs, pairs without a `=`, empty keys, and invalid UTF-8. It also rejects duplicate
+/// keys, except those named in `repeatable`, so that a handler can't silently act on only the
+/// first or last of conflicting values. The `fields` parameter, which applies to any JSON
+/// response, is skipped here and left to `parse_fields`.
+pub fn parse_query<'a>(q: &'a str, repeatable: &[&str])
+                       -> Result<Vec<(Cow<'a, str>, Cow<'a, str>)>, Error> {
+    let mut out: Vec<(Cow<'a, str>, Cow<'a, str>)> = Vec::new();
+    for pair in q.split('&') {
+        let eq = pair.find('=').ok_or_else(|| format_err!("malformed parameter {:?}", pair))?;
+        let key = decode_component(&pair[..eq])?;
+        let value = decode_component(&pair[eq+1..])?;
+        if key.is_empty() {
+            bail!("malformed parameter {:?}", pair);
+        }
+        if key == FIELDS {
+            continue;
+        }
+        if !repeatable.contains(&&*key) && out.iter().any(|&(ref k, _)| *k == key) {
+            bail!("parameter {} specified more than once", key);
+        }
+        out.push((key, value));
+    }
+    Ok(out)
+}
+
+const FIELDS: &str = "fields";
+
+/// Parses the `fields` parameter from a query string, as in `fields=startId,endTime90k`.
+/// Malformed pairs are left for `parse_query` to reject.
+pub fn parse_fields(q: &str) -> Result<Option<json::Fields>, Error> {
+    let mut out = None;
+    for pair in q.split('&') {
+        let eq = match pair.find('=') {
+            None => continue,
+            Some(eq) => eq,
+        };
+        if decode_component(&pair[..eq])? != FIELDS {
+            continue;
+        }
+        if out.is_some() {
+            bail!("parameter {} specified more than once", FIELDS);
+        }
+        out = Some(json::Fields::parse(&decode_component(&pair[eq+1..])?)?);
+    }
+    Ok(out)
+}
+
+fn decode_component(c: &str) -> Result<Cow<str>, Error> {
+    if c.contains('+') {
+        let c = c.replace('+', " ");
+        let d = percent_decode(c.as_bytes()).decode_utf8()?.into_owned();
+        return Ok(Cow::Owned(d));
+    }
+    Ok(percent_decode(c.as_bytes()).decode_utf8()?)
+}
+
+/// Parses a `cell` parameter to `/api/layouts`, of the form `POSITION,CAMERA_UUID,STREAM`.
+pub fn parse_layout_cell(s: &str) -> Result<db::LayoutCell, Error> {
+    let mut parts = s.split(',');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(p), Some(c), Some(t), None) => Ok(db::LayoutCell {
+            position: parse_decimal(p)?,
+            camera_uuid: parse_uuid(c)?,
+            stream_type: db::StreamType::parse(t)
+                             .ok_or_else(|| format_err!("invalid stream type {:?}", t))?,
+        }),
+        _ => bail!("invalid cell {:?}; expected POSITION,CAMERA_UUID,STREAM", s),
+    }
+}
+
+/// The parsed form of `view.mp4`'s `s` parameter; see `design/api.md`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Segments {
+    pub ids: Range<i32>,
+    pub open_id: Option<u32>,
+    pub start_time: i64,
+    pub end_time: Option<i64>,
+}
+
+impl Segments {
+    /// Parses the canonical form, as produced by `Display`. Equivalent but non-canonical forms
+    /// (such as `1-1` for `1`, or `1.0-` for `1`) and numbers with leading zeros are rejected.
+    pub fn parse(input: &str) -> Result<Segments, ()> {
+        let caps = SEGMENTS_RE.captures(input).ok_or(())?;
+        let ids_start: i32 = parse_decimal(caps.get(1).unwrap().as_str()).map_err(|_| ())?;
+        let ids_end = match caps.get(2) {
+            Some(m) => {
+                let end: i32 = parse_decimal(&m.as_str()[1..]).map_err(|_| ())?;
+                if end <= ids_start {
+                    return Err(());
+                }
+                end
+            },
+            None => ids_start,
+        }.checked_add(1).ok_or(())?;
+        let open_id = match caps.get(3) {
+            Some(m) => Some(parse_decimal(&m.as_str()[1..]).map_err(|_| ())?),
+            None => None,
+        };
+        let (start_time, end_time) = match (caps.get(4), caps.get(5)) {
+            (None, None) if input.ends_with('-') => return Err(()),  // the empty "X.-"
+            (s, e) => (s.map(|m| m.as_str()), e.map(|m| m.as_str())),
+        };
+        let start_time = match start_time {
+            Some(s) => {
+                let s: i64 = parse_decimal(s).map_err(|_| ())?;
+                if s == 0 {
+                    return Err(());  // a zero start time is written by omission.
+                }
+                s
+            },
+            None => 0,
+        };
+        let end_time = match end_time {
+            Some(e) => {
+                let e = parse_decimal(e).map_err(|_| ())?;
+                if e <= start_time {
+                    return Err(());
+                }
+                Some(e)
+            },
+            None => None,
+        };
+        Ok(Segments {
+            ids: ids_start .. ids_end,
+            open_id,
+            start_time,
+            end_time,
+        })
+    }
+}
+
+impl fmt::Display for Segments {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.ids.start)?;
+        if self.ids.end != self.ids.start + 1 {
+            write!(f, "-{}", self.ids.end - 1)?;
+        }
+        if let Some(o) = self.open_id {
+            write!(f, "@{}", o)?;
+        }
+        if self.start_time != 0 || self.end_time.is_some() {
+            f.write_str(".")?;
+            if self.start_time != 0 {
+                write!(f, "{}", self.start_time)?;
+            }
+            f.write_str("-")?;
+            if let Some(e) = self.end_time {
+                write!(f, "{}", e)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::clock::RealClocks;
+    use db::testutil::{self, TestDb};
+    use super::*;
+
+    #[test]
+    fn test_segments() {
+        testutil::init();
+        assert_eq!(Segments{ids: 1..2, open_id: None, start_time: 0, end_time: None},
+                   Segments::parse("1").unwrap());
+        assert_eq!(Segments{ids: 1..2, open_id: Some(42), start_time: 0, end_time: None},
+                   Segments::parse("1@42").unwrap());
+        assert_eq!(Segments{ids: 1..2, open_id: None, start_time: 26, end_time: None},
+                   Segments::parse("1.26-").unwrap());
+        assert_eq!(Segments{ids: 1..2, open_id: Some(42), start_time: 26, end_time: None},
+                   Segments::parse("1@42.26-").unwrap());
+        assert_eq!(Segments{ids: 1..2, open_id: None, start_time: 0, end_time: Some(42)},
+                   Segments::parse("1.-42").unwrap());
+        assert_eq!(Segments{ids: 1..2, open_id: None, start_time: 26, end_time: Some(42)},
+                   Segments::parse("1.26-42").unwrap());
+        assert_eq!(Segments{ids: 1..6, open_id: None, start_time: 0, end_time: None},
+                   Segments::parse("1-5").unwrap());
+        assert_eq!(Segments{ids: 1..6, open_id: None, start_time: 26, end_time: None},
+                   Segments::parse("1-5.26-").unwrap());
+        assert_eq!(Segments{ids: 1..6, open_id: None, start_time: 0, end_time: Some(42)},
+                   Segments::parse("1-5.-42").unwrap());
+        assert_eq!(Segments{ids: 1..6, open_id: None, start_time: 26, end_time: Some(42)},
+                   Segments::parse("1-5.26-42").unwrap());
+    }
+
+    /// Calls `f` with every string of up to `max_len` characters from `alphabet`.
+    fn for_each_string<F: FnMut(&str)>(alphabet: &[char], max_len: usize, f: &mut F) {
+        fn recurse<F: FnMut(&str)>(alphabet: &[char], remaining: usize, buf: &mut String,
+                                   f: &mut F) {
+            f(buf);
+            if remaining == 0 {
+                return;
+            }
+            for &c in alphabet {
+                buf.push(c);
+                recurse(alphabet, remaining - 1, buf, f);
+                buf.pop();
+            }
+        }
+        recurse(alphabet, max_len, &mut String::new(), f);
+    }
+
+    #[test]
+    fn segments_accepts_only_canonical() {
+        testutil::init();
+        let mut accepted = 0;
+        for_each_string(&['0', '1', '9', '-', '@', '.', 'x'], 7, &mut |s| {
+            if let Ok(seg) = Segments::parse(s) {
+                assert_eq!(seg.to_string(), s);
+                accepted += 1;
+            }
+        });
+        assert!(accepted > 1000, "accepted={}", accepted);
+        for s in &["01", "1-01", "1-1", "2-1", "1@01", "1.-", "1.0-", "1.00-5", "1.5-5", "1.5-3",
+                   "-1", "+1", "1.5", "1.-0", "2147483648", "0-2147483647", " 1", "1 "] {
+            assert!(Segments::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn segments_round_trip() {
+        testutil::init();
+        for &start in &[0, 1, 10, i32::max_value() - 1] {
+            for &len in &[1, 2, 100] {
+                let end = match start.checked_add(len) {
+                    Some(e) => e,
+                    None => continue,
+                };
+                for &open_id in &[None, Some(0), Some(u32::max_value())] {
+                    for &start_time in &[0, 1, 1 << 40] {
+                        for &end_time in &[None, Some(start_time + 1), Some(i64::max_value())] {
+                            let s = Segments {
+                                ids: start .. end,
+                                open_id,
+                                start_time,
+                                end_time,
+                            };
+                            assert_eq!(Segments::parse(&s.to_string()), Ok(s));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn query() {
+        testutil::init();
+        let q = parse_query("a=1&b=c+d%20e&c=", &[]).unwrap();
+        assert_eq!(q.iter().map(|&(ref k, ref v)| (&**k, &**v)).collect::<Vec<_>>(),
+                   &[("a", "1"), ("b", "c d e"), ("c", "")]);
+        assert_eq!(parse_query("s=1&s=2", &["s"]).unwrap().len(), 2);
+        for q in &["s=1&s=2", "s=1&s=1", "a=1&&b=2", "a", "=1", "a=%ff", "a=1&"] {
+            assert!(parse_query(q, &[]).is_err(), "{}", q);
+        }
+    }
+
+    #[test]
+    fn fields() {
+        testutil::init();
+        let q = "startTime90k=0&fields=startId%2CendTime90k";
+        assert_eq!(parse_query(q, &[]).unwrap().len(), 1);
+        let f = parse_fields(q).unwrap().unwrap();
+        let mut v: ::serde_json::Value = ::serde_json::from_str(r#"{
+            "recordings": [{"startId": 1, "endId": 2, "endTime90k": 3, "days": {"x": 4}}],
+            "notes": [],
+            "total": 5
+        }"#).unwrap();
+        f.retain(&mut v);
+        assert_eq!(v.to_string(),
+                   r#"{"notes":[],"recordings":[{"days":{},"endTime90k":3,"startId":1}]}"#);
+        assert!(parse_fields("a=1").unwrap().is_none());
+        for q in &["fields=a&fields=b", "fields=", "fields=a,,b"] {
+            assert!(parse_fields(q).is_err(), "{}", q);
+        }
+    }
+
+    #[test]
+    fn test_parse_layout_cell() {
+        testutil::init();
+        let u = "fd20f7a2-9d69-4cb3-94ed-d51a20c3edfe";
+        assert_eq!(parse_layout_cell(&format!("3,{},sub", u)).unwrap(), db::LayoutCell {
+            position: 3,
+            camera_uuid: Uuid::parse_str(u).unwrap(),
+            stream_type: db::StreamType::SUB,
+        });
+        for c in &[format!("03,{},sub", u), format!("3,{},SUB", u), format!("3,{}", u),
+                   format!("3,{},sub,", u), format!("65536,{},main", u), "3,,main".to_owned()] {
+            assert!(parse_layout_cell(c).is_err(), "{}", c);
+        }
+    }
+
+    #[test]
+    fn paths() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let u = db.test_camera_uuid;
+        let upper = u.to_string().to_uppercase();
+        let simple = u.to_simple_ref().to_string();
+        let dec = |p: &str| decode_path(p, &db.db);
+        assert_eq!(dec("/index.html"), Path::Static);
+        assert_eq!(dec("/healthz"), Path::Healthz);
+        assert_eq!(dec("/readyz"), Path::Readyz);
+        assert_eq!(dec("/api/"), Path::TopLevel);
+        assert_eq!(dec(&format!("/api/cameras/{}/main/recordings", u)),
+                   Path::StreamRecordings(u, db::StreamType::MAIN));
+        assert_eq!(dec("/api/cameras/test%20camera/sub/view.mp4"),
+                   Path::StreamViewMp4(u, db::StreamType::SUB));
+        assert_eq!(dec(&format!("/api/cameras/{}/sub/recordings/updates", u)),
+                   Path::StreamRecordingUpdates(u, db::StreamType::SUB));
+        assert_eq!(dec(&format!("/api/cameras/{}/main/recordings/archive", u)),
+                   Path::StreamRecordingArchive(u, db::StreamType::MAIN));
+        assert_eq!(dec(&format!("/api/cameras/{}/main/view.vtt", u)),
+                   Path::StreamViewVtt(u, db::StreamType::MAIN));
+        assert_eq!(dec(&format!("/api/cameras/{}/sub/snapshot.jpg", u)),
+                   Path::StreamSnapshot(u, db::StreamType::SUB));
+        assert_eq!(dec(&format!("/api/cameras/{}/main/thumbnails", u)),
+                   Path::StreamThumbnails(u, db::StreamType::MAIN));
+        assert_eq!(dec(&format!("/api/cameras/{}/sub/thumbnail.jpg", u)),
+                   Path::StreamThumbnail(u, db::StreamType::SUB));
+        assert_eq!(dec(&format!("/api/cameras/{}/main/metadata", u)),
+                   Path::StreamMetadata(u, db::StreamType::MAIN));
+        assert_eq!(dec(&format!("/api/cameras/{}/snapshots", u)), Path::CameraSnapshots(u));
+        assert_eq!(dec(&format!("/api/cameras/{}/credentials", u)), Path::CameraCredentials(u));
+        assert_eq!(dec("/api/cameras/test%20camera/snapshot.jpg"), Path::CameraSnapshot(u));
+        assert_eq!(dec(&format!("/api/cameras/{}/sub/enable", u)),
+                   Path::StreamEnable(u, db::StreamType::SUB));
+        assert_eq!(dec(&format!("/api/cameras/{}/main/disable", u)),
+                   Path::StreamDisable(u, db::StreamType::MAIN));
+        assert_eq!(dec(&format!("/api/cameras/{}/main/encoder", u)),
+                   Path::StreamEncoder(u, db::StreamType::MAIN));
+        assert_eq!(dec(&format!("/api/cameras/{}/sub/usage", u)),
+                   Path::StreamUsage(u, db::StreamType::SUB));
+        assert_eq!(dec(&format!("/api/cameras/{}/", upper)), Path::NotFound);
+        assert_eq!(dec(&format!("/api/cameras/{}/", simple)), Path::NotFound);
+        assert_eq!(dec(&format!("/api/cameras/{}/MAIN/recordings", u)), Path::NotFound);
+        assert_eq!(dec("/api/events/12.mp4"), Path::EventClip(12));
+        assert_eq!(dec("/api/stats/bandwidth"), Path::Bandwidth);
+        assert_eq!(dec("/api/viewers"), Path::Viewers);
+        assert_eq!(dec("/api/cameras/test%20camera/sub/view.mp4").camera_uuid(), Some(u));
+        assert_eq!(dec("/api/stats/bandwidth").camera_uuid(), None);
+        assert_eq!(dec("/api/events/12.jpg"), Path::EventSnapshot(12));
+        for p in &["/api/events/012.mp4", "/api/events/+12.mp4", "/api/events/-12.mp4",
+                   "/api/events/.mp4", "/api/events/012.jpg", "/api/recordings", "/api"] {
+            assert_eq!(dec(p), Path::NotFound, "{}", p);
+        }
+        assert_eq!(dec("/api/users/1/preferences"), Path::UserPreferences(1));
+        for p in &["/api/users/01/preferences", "/api/users//preferences",
+                   "/api/users/preferences", "/api/users/1/preferences/"] {
+            assert_eq!(dec(p), Path::NotFound, "{}", p);
+        }
+        assert_eq!(dec("/api/streams/12/move"), Path::StreamMove(12));
+        for p in &["/api/streams/-1/move", "/api/streams//move", "/api/streams/move"] {
+            assert_eq!(dec(p), Path::NotFound, "{}", p);
+        }
+        assert_eq!(dec(&format!("/api/export/{}.mkv", u)),
+                   Path::ExportFile(u, db::ExportContainer::Matroska));
+        assert_eq!(dec(&format!("/api/export/{}.avi", u)), Path::NotFound);
+        assert_eq!(dec(&format!("/api/jobs/{}", u)), Path::Job(u));
+        assert_eq!(dec(&format!("/api/jobs/{}", upper)), Path::NotFound);
+        assert_eq!(dec(&format!("/api/holds/urn:uuid:{}", u)), Path::NotFound);
+        assert_eq!(dec("/api/layouts"), Path::Layouts);
+        assert_eq!(dec(&format!("/api/layouts/{}", u)), Path::Layout(u));
+        assert_eq!(dec(&format!("/api/layouts/{}/", u)), Path::NotFound);
+        assert_eq!(dec("/api/embed"), Path::Embeds);
+        assert_eq!(dec("/embed/a.1-b"), Path::EmbedPage("a.1-b".to_owned()));
+        assert_eq!(dec("/embed/a.1-b/video.mp4"), Path::EmbedMp4("a.1-b".to_owned()));
+        for p in &["/embed/", "/embed//video.mp4", "/embed/video.mp4/", "/embed/A.1",
+                   "/embed/a/b", "/embed/a%2e1"] {
+            assert_eq!(dec(p), Path::NotFound, "{}", p);
+        }
+        let sha1 = "de382684a471f178e4e3a163762711b0653bfd83";
+        assert_eq!(dec(&format!("/api/init/{}.mp4", sha1)),
+                   Path::InitSegment(strutil::dehex(sha1.as_bytes()).unwrap()));
+        assert_eq!(dec(&format!("/api/init/{}.mp4", sha1.to_uppercase())), Path::NotFound);
+    }
+
+    #[test]
+    fn paths_exhaustive() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let alphabet = ['/', '.', '0', '1', 'a', '\u{e9}', '%', '-'];
+        for prefix in &["/api/", "/api/events/", "/api/export/", "/api/jobs/", "/api/init/",
+                        "/api/users/", "/api/streams/", "/api/cameras/",
+                        "/api/cameras/test%20camera/", "/embed/"] {
+            for_each_string(&alphabet, 4, &mut |s| {
+                // Decoding mustn't panic (as slicing within a multi-byte character would), and
+                // event ids must be in canonical form.
+                let p = format!("{}{}", prefix, s);
+                match decode_path(&p, &db.db) {
+                    Path::EventClip(id) => assert_eq!(p, format!("/api/events/{}.mp4", id)),
+                    Path::EventSnapshot(id) => assert_eq!(p, format!("/api/events/{}.jpg", id)),
+                    Path::UserPreferences(id) => {
+                        assert_eq!(p, format!("/api/users/{}/preferences", id))
+                    },
+                    Path::StreamMove(id) => assert_eq!(p, format!("/api/streams/{}/move", id)),
+                    Path::EmbedPage(t) => assert_eq!(p, format!("/embed/{}", t)),
+                    Path::EmbedMp4(t) => assert_eq!(p, format!("/embed/{}/video.mp4", t)),
+                    _ => {},
+                }
+            });
+        }
+    }
+}
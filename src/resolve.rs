@@ -0,0 +1,258 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Resolution of camera hostnames. Cameras often get their addresses via DHCP, so they're
+//! addressed by name and resolved again on every connection attempt rather than once at startup.
+//! ffmpeg resolves ordinary DNS names itself each time it opens a stream. mDNS `.local` names are
+//! resolved here, via the system resolver if it supports them (as with `nss-mdns`) or else by a
+//! minimal mDNS query (RFC 6762 section 5.1 "legacy unicast"), answered by the camera itself.
+
+use byteorder::{BigEndian, ByteOrder};
+use failure::Error;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+const MDNS_ADDR: &str = "224.0.0.251:5353";
+
+/// The number of mDNS queries to send before giving up, and how long to wait for each.
+const MDNS_ATTEMPTS: usize = 3;
+const MDNS_TIMEOUT_MS: u64 = 1000;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+/// Splits a camera's `host` (as in `db::Camera::host`) into the name or address and the port, if
+/// any. IPv6 addresses may be bracketed.
+pub fn split_port(host: &str) -> (&str, Option<&str>) {
+    if host.starts_with('[') {
+        if let Some(i) = host.find(']') {
+            let rest = &host[i+1..];
+            return (&host[1..i], if rest.starts_with(':') { Some(&rest[1..]) } else { None });
+        }
+        return (host, None);
+    }
+    match host.find(':') {
+        Some(i) if host.matches(':').count() == 1 => (&host[..i], Some(&host[i+1..])),
+        _ => (host, None),
+    }
+}
+
+/// Returns true iff the given name is an mDNS name.
+fn is_local(name: &str) -> bool {
+    let name = name.trim_right_matches('.').to_ascii_lowercase();
+    name.ends_with(".local")
+}
+
+/// Resolves a camera `host` with an mDNS `.local` name, returning an address (with the original
+/// port, if any) to use in its place. Returns `None` for other hosts, which should be used as-is.
+pub fn resolve_local(host: &str) -> Result<Option<String>, Error> {
+    let (name, port) = split_port(host);
+    if !is_local(name) {
+        return Ok(None);
+    }
+    let system = (name, 0).to_socket_addrs().ok().and_then(|mut a| a.next()).map(|a| a.ip());
+    let ip = match system {
+        Some(ip) => ip,
+        None => IpAddr::V4(mdns_query(name)?),
+    };
+    Ok(Some(match (ip, port) {
+        (IpAddr::V6(a), Some(p)) => format!("[{}]:{}", a, p),
+        (IpAddr::V6(a), None) => format!("[{}]", a),
+        (IpAddr::V4(a), Some(p)) => format!("{}:{}", a, p),
+        (IpAddr::V4(a), None) => a.to_string(),
+    }))
+}
+
+/// Looks up the IPv4 address of the given `.local` name via mDNS.
+fn mdns_query(name: &str) -> Result<Ipv4Addr, Error> {
+    let name = name.trim_right_matches('.');
+    let query = build_query(name)?;
+    let sock = UdpSocket::bind("0.0.0.0:0")?;
+    sock.set_read_timeout(Some(Duration::from_millis(MDNS_TIMEOUT_MS)))?;
+    let mut buf = [0u8; 9000];
+    for _ in 0..MDNS_ATTEMPTS {
+        sock.send_to(&query, MDNS_ADDR)?;
+        loop {
+            let n = match sock.recv_from(&mut buf) {
+                Ok((n, _)) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e.into()),
+            };
+            match parse_response(&buf[..n], name) {
+                Ok(Some(a)) => return Ok(a),
+                Ok(None) => {},
+                Err(e) => debug!("ignoring bad mDNS response: {}", e),
+            }
+        }
+    }
+    bail!("no mDNS response for {}", name)
+}
+
+/// Builds a DNS query for the `A` record of the given name.
+fn build_query(name: &str) -> Result<Vec<u8>, Error> {
+    let mut q = vec![0u8; 12];
+    q[5] = 1;  // QDCOUNT
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("bad label in name {:?}", name);
+        }
+        q.push(label.len() as u8);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    let mut tail = [0u8; 4];
+    BigEndian::write_u16(&mut tail[0..2], TYPE_A);
+    BigEndian::write_u16(&mut tail[2..4], CLASS_IN);
+    q.extend_from_slice(&tail);
+    Ok(q)
+}
+
+/// Reads a possibly-compressed name starting at `pos`, returning it (dotted) and the position
+/// after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), Error> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *msg.get(pos).ok_or_else(|| format_err!("truncated name"))? as usize;
+        match len & 0xc0 {
+            0 => {},
+            0xc0 => {
+                let low = *msg.get(pos + 1).ok_or_else(|| format_err!("truncated pointer"))?;
+                if end.is_none() {
+                    end = Some(pos + 2);
+                }
+                jumps += 1;
+                if jumps > 16 {
+                    bail!("too many compression pointers");
+                }
+                pos = ((len & 0x3f) << 8) | low as usize;
+                continue;
+            },
+            _ => bail!("bad label length {:#x}", len),
+        }
+        if len == 0 {
+            return Ok((name, end.unwrap_or(pos + 1)));
+        }
+        let label = msg.get(pos + 1 .. pos + 1 + len)
+                       .ok_or_else(|| format_err!("truncated label"))?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(&String::from_utf8_lossy(label));
+        pos += 1 + len;
+    }
+}
+
+/// Parses a DNS response, returning the address of an `A` record of the given name, if any.
+fn parse_response(msg: &[u8], name: &str) -> Result<Option<Ipv4Addr>, Error> {
+    if msg.len() < 12 {
+        bail!("truncated header");
+    }
+    if msg[2] & 0x80 == 0 {
+        return Ok(None);  // a query, not a response.
+    }
+    let questions = BigEndian::read_u16(&msg[4..6]);
+    let records = BigEndian::read_u16(&msg[6..8]) as usize +
+                  BigEndian::read_u16(&msg[8..10]) as usize +
+                  BigEndian::read_u16(&msg[10..12]) as usize;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    for _ in 0..records {
+        let (n, p) = read_name(msg, pos)?;
+        if p + 10 > msg.len() {
+            bail!("truncated record");
+        }
+        let type_ = BigEndian::read_u16(&msg[p..p+2]);
+        let len = BigEndian::read_u16(&msg[p+8..p+10]) as usize;
+        let data = msg.get(p + 10 .. p + 10 + len).ok_or_else(|| format_err!("truncated data"))?;
+        if type_ == TYPE_A && len == 4 && n.eq_ignore_ascii_case(name) {
+            return Ok(Some(Ipv4Addr::new(data[0], data[1], data[2], data[3])));
+        }
+        pos = p + 10 + len;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_split_port() {
+        assert_eq!(super::split_port("192.168.1.5"), ("192.168.1.5", None));
+        assert_eq!(super::split_port("cam.local:8554"), ("cam.local", Some("8554")));
+        assert_eq!(super::split_port("fe80::1"), ("fe80::1", None));
+        assert_eq!(super::split_port("[fe80::1]"), ("fe80::1", None));
+        assert_eq!(super::split_port("[fe80::1]:8554"), ("fe80::1", Some("8554")));
+    }
+
+    #[test]
+    fn test_resolve_local() {
+        assert_eq!(super::resolve_local("192.168.1.5:554").unwrap(), None);
+        assert_eq!(super::resolve_local("camera.example.com").unwrap(), None);
+        assert!(super::is_local("Garage-Cam.LOCAL."));
+    }
+
+    #[test]
+    fn test_query() {
+        assert_eq!(&super::build_query("cam.local").unwrap()[..], &b"\
+            \x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+            \x03cam\x05local\x00\x00\x01\x00\x01"[..]);
+        assert!(super::build_query("cam..local").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        // A response echoing the question, with an answer whose name is a pointer to it.
+        let mut resp = super::build_query("cam.local").unwrap();
+        resp[2] = 0x84;  // QR, AA.
+        resp[7] = 1;  // ANCOUNT
+        resp.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x78\x00\x04\xc0\xa8\x01\x05");
+        assert_eq!(super::parse_response(&resp, "CAM.local").unwrap(),
+                   Some(Ipv4Addr::new(192, 168, 1, 5)));
+        assert_eq!(super::parse_response(&resp, "other.local").unwrap(), None);
+        assert!(super::parse_response(&resp[..resp.len() - 1], "cam.local").is_err());
+
+        // A query (such as another host's, seen via multicast) is ignored.
+        let query = super::build_query("cam.local").unwrap();
+        assert_eq!(super::parse_response(&query, "cam.local").unwrap(), None);
+
+        // Pointer loops are rejected.
+        let mut looped = resp.clone();
+        looped[12] = 0xc0;
+        looped[13] = 0x0c;
+        assert!(super::parse_response(&looped, "cam.local").is_err());
+    }
+}
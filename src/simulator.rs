@@ -0,0 +1,441 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A simulated camera, for development without physical cameras.
+//!
+//! Serves synthetic video (see `synth`) via RTSP and, optionally, a minimal ONVIF device service.
+//! The RTSP server supports just what ffmpeg needs to record a stream: `OPTIONS`, `DESCRIBE`,
+//! `SETUP` with RTP interleaved in the TCP connection (as Moonfire NVR always requests),
+//! `PLAY`, `GET_PARAMETER` (as a keepalive), and `TEARDOWN`. There's no authentication.
+
+use byteorder::{BigEndian, ByteOrder};
+use failure::Error;
+use h264;
+use openssl::{base64, rand};
+use parking_lot::Mutex;
+use regex::Regex;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use synth;
+
+/// The largest RTP payload to send; larger NAL units are fragmented.
+const MAX_PAYLOAD: usize = 1400;
+
+/// The RTP payload type used for H.264.
+const PAYLOAD_TYPE: u8 = 96;
+
+lazy_static! {
+    static ref INTERLEAVED_RE: Regex = Regex::new(r"interleaved=(\d+)-(\d+)").unwrap();
+}
+
+/// Serves RTSP on `listener` forever, with each session streaming from a fresh `synth::Source`.
+pub fn serve_rtsp(listener: TcpListener, config: synth::Config) -> Result<(), Error> {
+    synth::Source::new(config.clone())?;  // validate config.
+    for conn in listener.incoming() {
+        let conn = conn?;
+        let peer = conn.peer_addr()?;
+        let config = config.clone();
+        thread::Builder::new().name(format!("rtsp-{}", peer)).spawn(move || {
+            info!("{}: connected", peer);
+            match Connection::new(conn, config).and_then(|mut c| c.run()) {
+                Ok(()) => info!("{}: closed", peer),
+                Err(e) => warn!("{}: {}", peer, e),
+            }
+        })?;
+    }
+    Ok(())
+}
+
+/// An RTSP request, as parsed by `read_request`.
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    method: String,
+    url: String,
+    cseq: String,
+    transport: Option<String>,
+}
+
+/// Reads a request from `r`, or returns `None` on EOF.
+/// Interleaved binary data (such as RTCP receiver reports) preceding the request is discarded.
+fn read_request<R: BufRead>(r: &mut R) -> Result<Option<Request>, Error> {
+    loop {
+        let first = match r.fill_buf()?.first() {
+            None => return Ok(None),
+            Some(&b) => b,
+        };
+        if first != b'$' {
+            break;
+        }
+        let mut hdr = [0u8; 4];
+        r.read_exact(&mut hdr)?;
+        let len = BigEndian::read_u16(&hdr[2..]) as u64;
+        io::copy(&mut r.by_ref().take(len), &mut io::sink())?;
+    }
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, url) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(u), Some("RTSP/1.0")) => (m.to_owned(), u.to_owned()),
+        _ => bail!("bad request line {:?}", line),
+    };
+    let mut cseq = None;
+    let mut transport = None;
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            bail!("EOF within request headers");
+        }
+        let l = line.trim_right();
+        if l.is_empty() {
+            break;
+        }
+        let colon = l.find(':').ok_or_else(|| format_err!("bad header line {:?}", l))?;
+        let (name, value) = (&l[..colon], l[colon+1..].trim());
+        match name.to_lowercase().as_str() {
+            "cseq" => cseq = Some(value.to_owned()),
+            "transport" => transport = Some(value.to_owned()),
+            "content-length" => content_length = value.parse()?,
+            _ => {},
+        }
+    }
+    io::copy(&mut r.by_ref().take(content_length), &mut io::sink())?;
+    Ok(Some(Request {
+        method,
+        url,
+        cseq: cseq.ok_or_else(|| format_err!("request has no CSeq"))?,
+        transport,
+    }))
+}
+
+/// Splits a NAL unit into RTP payloads of at most `max_payload` bytes: either a single NAL unit
+/// packet or a series of FU-A fragmentation units, as in RFC 6184 sections 5.6 and 5.8.
+fn packetize(nal: &[u8], max_payload: usize) -> Vec<Vec<u8>> {
+    if nal.len() <= max_payload {
+        return vec![nal.to_vec()];
+    }
+    let indicator = (nal[0] & 0xe0) | 28;
+    let nal_type = nal[0] & 0x1f;
+    let chunks: Vec<&[u8]> = nal[1..].chunks(max_payload - 2).collect();
+    let last = chunks.len() - 1;
+    chunks.iter().enumerate().map(|(i, c)| {
+        let mut p = Vec::with_capacity(2 + c.len());
+        p.push(indicator);
+        p.push((if i == 0 { 0x80 } else if i == last { 0x40 } else { 0 }) | nal_type);
+        p.extend_from_slice(c);
+        p
+    }).collect()
+}
+
+/// Returns the SDP describing a source, as in RFC 6184 section 8.2.1.
+fn sdp(source: &synth::Source) -> String {
+    let sps = source.sps();
+    format!("v=0\r\n\
+             o=- 0 0 IN IP4 127.0.0.1\r\n\
+             s=Moonfire NVR simulator\r\n\
+             t=0 0\r\n\
+             m=video 0 RTP/AVP {pt}\r\n\
+             a=rtpmap:{pt} H264/90000\r\n\
+             a=fmtp:{pt} packetization-mode=1;profile-level-id={:02x}{:02x}{:02x};\
+             sprop-parameter-sets={},{}\r\n\
+             a=control:trackID=0\r\n",
+            sps[1], sps[2], sps[3], base64::encode_block(sps),
+            base64::encode_block(source.pps()), pt = PAYLOAD_TYPE)
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: Arc<Mutex<TcpStream>>,
+    config: synth::Config,
+    session: String,
+    channel: Option<u8>,
+    playing: Option<(Arc<AtomicBool>, thread::JoinHandle<()>)>,
+}
+
+impl Connection {
+    fn new(conn: TcpStream, config: synth::Config) -> Result<Self, Error> {
+        let mut session = [0u8; 8];
+        rand::rand_bytes(&mut session)?;
+        Ok(Connection {
+            reader: BufReader::new(conn.try_clone()?),
+            writer: Arc::new(Mutex::new(conn)),
+            config,
+            session: session.iter().map(|b| format!("{:02x}", b)).collect(),
+            channel: None,
+            playing: None,
+        })
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        let r = self.handle_requests();
+        if let Some((stop, join)) = self.playing.take() {
+            stop.store(true, Ordering::SeqCst);
+            join.join().unwrap();
+        }
+        r
+    }
+
+    fn handle_requests(&mut self) -> Result<(), Error> {
+        while let Some(req) = read_request(&mut self.reader)? {
+            debug!("request: {:?}", req);
+            let (status, headers, body) = match req.method.as_str() {
+                "OPTIONS" => ("200 OK",
+                              "Public: OPTIONS, DESCRIBE, SETUP, PLAY, GET_PARAMETER, TEARDOWN\r\n"
+                              .to_owned(),
+                              String::new()),
+                "DESCRIBE" => {
+                    let source = synth::Source::new(self.config.clone())?;
+                    ("200 OK",
+                     format!("Content-Type: application/sdp\r\nContent-Base: {}/\r\n",
+                             req.url.trim_right_matches('/')),
+                     sdp(&source))
+                },
+                "SETUP" => {
+                    let channel = req.transport.as_ref()
+                        .filter(|t| t.contains("RTP/AVP/TCP"))
+                        .and_then(|t| INTERLEAVED_RE.captures(t))
+                        .and_then(|c| c[1].parse().ok());
+                    match channel {
+                        None => ("461 Unsupported Transport", String::new(), String::new()),
+                        Some(c) => {
+                            self.channel = Some(c);
+                            ("200 OK",
+                             format!("Transport: RTP/AVP/TCP;unicast;interleaved={}-{}\r\n\
+                                      Session: {};timeout=60\r\n", c, c + 1, &self.session),
+                             String::new())
+                        },
+                    }
+                },
+                "PLAY" => match (self.channel, self.playing.is_some()) {
+                    (None, _) => ("455 Method Not Valid in This State", String::new(),
+                                  String::new()),
+                    (Some(_), true) => ("200 OK", format!("Session: {}\r\n", &self.session),
+                                        String::new()),
+                    (Some(c), false) => {
+                        // Reply before sending any media.
+                        self.respond(&req, "200 OK",
+                                     &format!("Session: {}\r\n", &self.session), "")?;
+                        self.play(c)?;
+                        continue;
+                    },
+                },
+                "GET_PARAMETER" => ("200 OK", format!("Session: {}\r\n", &self.session),
+                                    String::new()),
+                "TEARDOWN" => {
+                    self.respond(&req, "200 OK", "", "")?;
+                    return Ok(());
+                },
+                _ => ("501 Not Implemented", String::new(), String::new()),
+            };
+            self.respond(&req, status, &headers, &body)?;
+        }
+        Ok(())
+    }
+
+    fn respond(&self, req: &Request, status: &str, headers: &str, body: &str)
+               -> Result<(), Error> {
+        let msg = format!("RTSP/1.0 {}\r\nCSeq: {}\r\nServer: moonfire-nvr simulator\r\n{}\
+                           Content-Length: {}\r\n\r\n{}",
+                          status, req.cseq, headers, body.len(), body);
+        self.writer.lock().write_all(msg.as_bytes())?;
+        Ok(())
+    }
+
+    /// Starts a thread which sends frames in real time on the given interleaved channel.
+    fn play(&mut self, channel: u8) -> Result<(), Error> {
+        let mut source = synth::Source::new(self.config.clone())?;
+        let writer = self.writer.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = stop.clone();
+        let mut ids = [0u8; 8];
+        rand::rand_bytes(&mut ids)?;
+        let ssrc = BigEndian::read_u32(&ids[0..4]);
+        let ts_offset = BigEndian::read_u32(&ids[4..8]);
+        let mut seq = BigEndian::read_u16(&ids[0..2]);
+        let join = thread::Builder::new().name("rtsp-play".to_owned()).spawn(move || {
+            let start = Instant::now();
+            let mut buf = Vec::new();
+            while !stop2.load(Ordering::SeqCst) {
+                let f = source.next_frame();
+                let due = start + Duration::from_millis(f.pts_90k as u64 / 90);
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+                let mut payloads = Vec::new();
+                h264::decode_h264_annex_b(&f.data, |nal| {
+                    payloads.extend(packetize(nal, MAX_PAYLOAD));
+                    Ok(())
+                }).unwrap();
+                buf.clear();
+                let timestamp = ts_offset.wrapping_add(f.pts_90k as u32);
+                let last = payloads.len() - 1;
+                for (i, p) in payloads.iter().enumerate() {
+                    let len = 12 + p.len();
+                    buf.extend_from_slice(&[b'$', channel, (len >> 8) as u8, len as u8]);
+                    buf.push(0x80);  // version 2, no padding, extension, or CSRCs.
+                    buf.push((if i == last { 0x80 } else { 0 }) | PAYLOAD_TYPE);  // marker bit
+                    let mut hdr = [0u8; 10];
+                    BigEndian::write_u16(&mut hdr[0..2], seq);
+                    BigEndian::write_u32(&mut hdr[2..6], timestamp);
+                    BigEndian::write_u32(&mut hdr[6..10], ssrc);
+                    buf.extend_from_slice(&hdr);
+                    buf.extend_from_slice(p);
+                    seq = seq.wrapping_add(1);
+                }
+                if let Err(e) = writer.lock().write_all(&buf) {
+                    debug!("stopping on write error: {}", e);
+                    return;
+                }
+            }
+        })?;
+        self.playing = Some((stop, join));
+        Ok(())
+    }
+}
+
+/// Serves a minimal ONVIF device service on `listener` forever, answering `SystemReboot` and
+/// `GetDeviceInformation` requests without actually doing anything. Requests aren't
+/// authenticated.
+pub fn serve_onvif(listener: TcpListener) -> Result<(), Error> {
+    for conn in listener.incoming() {
+        let conn = conn?;
+        if let Err(e) = handle_onvif(conn) {
+            warn!("ONVIF request failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle_onvif(conn: TcpStream) -> Result<(), Error> {
+    let mut r = BufReader::new(conn.try_clone()?);
+    let mut line = String::new();
+    let mut content_length = 0;
+    r.read_line(&mut line)?;
+    let request_line = line.trim_right().to_owned();
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            bail!("EOF within request headers");
+        }
+        let l = line.trim_right();
+        if l.is_empty() {
+            break;
+        }
+        if let Some(colon) = l.find(':') {
+            if l[..colon].eq_ignore_ascii_case("content-length") {
+                content_length = l[colon+1..].trim().parse()?;
+            }
+        }
+    }
+    let mut body = String::new();
+    r.take(content_length).read_to_string(&mut body)?;
+    info!("ONVIF request {}", request_line);
+    let (status, response) = if body.contains("SystemReboot") {
+        ("200 OK",
+         "<tds:SystemRebootResponse><tds:Message>Simulated reboot</tds:Message>\
+          </tds:SystemRebootResponse>")
+    } else if body.contains("GetDeviceInformation") {
+        ("200 OK",
+         "<tds:GetDeviceInformationResponse><tds:Manufacturer>Moonfire NVR</tds:Manufacturer>\
+          <tds:Model>simulator</tds:Model><tds:FirmwareVersion>0</tds:FirmwareVersion>\
+          <tds:SerialNumber>0</tds:SerialNumber><tds:HardwareId>0</tds:HardwareId>\
+          </tds:GetDeviceInformationResponse>")
+    } else {
+        ("400 Bad Request",
+         "<s:Fault><s:Code><s:Value>s:Sender</s:Value></s:Code>\
+          <s:Reason><s:Text xml:lang=\"en\">Unsupported action</s:Text></s:Reason></s:Fault>")
+    };
+    let body = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                        <s:Envelope xmlns:s=\"http://www.w3.org/2003/05/soap-envelope\" \
+                        xmlns:tds=\"http://www.onvif.org/ver10/device/wsdl\">\
+                        <s:Body>{}</s:Body></s:Envelope>", response);
+    let mut w = conn;
+    write!(w, "HTTP/1.1 {}\r\nContent-Type: application/soap+xml; charset=utf-8\r\n\
+               Content-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+    use std::io::Cursor;
+    use super::*;
+
+    #[test]
+    fn parse_requests() {
+        testutil::init();
+        let mut r = Cursor::new(&b"OPTIONS rtsp://127.0.0.1:8554/main RTSP/1.0\r\n\
+                                   CSeq: 1\r\n\
+                                   User-Agent: moonfire-nvr\r\n\
+                                   \r\n\
+                                   $\x01\x00\x03abc\
+                                   SETUP rtsp://127.0.0.1:8554/main/trackID=0 RTSP/1.0\r\n\
+                                   CSeq: 2\r\n\
+                                   Transport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\
+                                   \r\n"[..]);
+        assert_eq!(read_request(&mut r).unwrap().unwrap(), Request {
+            method: "OPTIONS".to_owned(),
+            url: "rtsp://127.0.0.1:8554/main".to_owned(),
+            cseq: "1".to_owned(),
+            transport: None,
+        });
+        assert_eq!(read_request(&mut r).unwrap().unwrap(), Request {
+            method: "SETUP".to_owned(),
+            url: "rtsp://127.0.0.1:8554/main/trackID=0".to_owned(),
+            cseq: "2".to_owned(),
+            transport: Some("RTP/AVP/TCP;unicast;interleaved=0-1".to_owned()),
+        });
+        assert_eq!(read_request(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn fragmentation() {
+        testutil::init();
+        assert_eq!(packetize(&[0x41, 1, 2], 3), vec![vec![0x41, 1, 2]]);
+        assert_eq!(packetize(&[0x65, 1, 2, 3, 4, 5], 4), vec![
+            vec![0x7c, 0x85, 1, 2],
+            vec![0x7c, 0x05, 3, 4],
+            vec![0x7c, 0x45, 5],
+        ]);
+    }
+
+    #[test]
+    fn describe() {
+        testutil::init();
+        let s = synth::Source::new(synth::Config::default()).unwrap();
+        let sdp = sdp(&s);
+        assert!(sdp.contains("profile-level-id=42c01e;"), "{}", sdp);
+    }
+}
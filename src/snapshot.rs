@@ -0,0 +1,370 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Snapshots: JPEGs of a stream's current frame. These are served live as
+//! `/api/cameras/<uuid>/<stream>/snapshot.jpg` and stored for events as `/api/events/<id>.jpg`
+//! (currently only of doorbell presses, while the visitor is likely still at the door).
+//!
+//! Snapshots are fetched from the stream's `snapshot_url` if it has one. Otherwise, like mosaics,
+//! they're decoded by an external `ffmpeg` binary, reading the stream directly rather than waiting
+//! for the recording to be committed. That can take several seconds on a camera with infrequent
+//! key frames, and some cameras' HTTP snapshots are of higher quality anyway.
+
+use base::sched;
+use base::strutil;
+use db;
+use failure::Error;
+use openssl::{hash, rand};
+use reqwest;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+use url;
+use vendor_events;
+
+/// The maximum size of a snapshot fetched via HTTP.
+const MAX_HTTP_SNAPSHOT_BYTES: u64 = 8 << 20;
+
+/// How long `ffmpeg` may take to produce a snapshot before it's killed. Its own socket timeout
+/// doesn't bound a stream which stalls after connecting or has no key frame for a long time.
+const FFMPEG_TIMEOUT_SEC: u64 = 20;
+
+/// Where to take a stream's snapshots from.
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// The stream's `snapshot_url`, with the camera's credentials.
+    Http { url: String, username: String, password: String },
+
+    /// The stream itself (RTSP or, for HTTP sources, HLS or Motion JPEG), with credentials
+    /// embedded in the URL.
+    Stream(String),
+
+    /// A local V4L2 device or a push listener, described suitably for logging. Either can only
+    /// be opened once, and the recording holds it, so snapshots require a `snapshot_url`.
+    Exclusive(String),
+}
+
+impl Source {
+    pub fn new(camera: &db::Camera, stream: &db::Stream) -> Self {
+        match stream.snapshot_url {
+            Some(ref u) => Source::Http {
+                url: u.clone(),
+                username: camera.username.clone(),
+                password: camera.password.clone(),
+            },
+            None => Source::Stream(match stream.source {
+                db::StreamSource::Rtsp => {
+                    ::stream::rtsp_url(&camera.username, &camera.password, &camera.host,
+                                       &stream.rtsp_path)
+                },
+
+                // The URL was checked when saved; if it still can't take credentials, let ffmpeg
+                // report the problem.
+                db::StreamSource::Hls(ref u) | db::StreamSource::Mjpeg(ref u) => {
+                    ::stream::http_urls(&camera.username, &camera.password, u)
+                        .map(|(u, _)| u)
+                        .unwrap_or_else(|_| u.to_owned())
+                },
+                db::StreamSource::V4l2(ref d) => return Source::Exclusive(d.clone()),
+                db::StreamSource::Push(ref u) => {
+                    return Source::Exclusive(::stream::redacted_push_url(u));
+                },
+            }),
+        }
+    }
+
+    /// Takes a snapshot, using `ffmpeg` if the source is the stream itself.
+    pub fn take(&self, ffmpeg: Option<&PathBuf>) -> Result<Vec<u8>, Error> {
+        match *self {
+            Source::Http { ref url, ref username, ref password } => {
+                fetch(url, username, password)
+            },
+            Source::Stream(ref url) => match ffmpeg {
+                None => bail!("no snapshot URL is configured and no ffmpeg is available"),
+                Some(f) => take(f, url),
+            },
+            Source::Exclusive(ref d) => bail!("{} is in use by the recording; configure a \
+                                            snapshot_url to take snapshots", d),
+        }
+    }
+}
+
+/// Returns the ffmpeg arguments to write a single JPEG frame of `url` to stdout.
+fn args(url: &str) -> Vec<&str> {
+    let mut a = vec!["-nostdin", "-loglevel", "error"];
+    if url.starts_with("rtsp://") {
+        a.extend_from_slice(&["-rtsp_transport", "tcp",
+                              "-stimeout", "5000000"]);  // microseconds.
+    }
+    a.extend_from_slice(&["-i", url, "-frames:v", "1", "-q:v", "3", "-f", "image2", "-c:v",
+                          "mjpeg", "pipe:1"]);
+    a
+}
+
+/// Takes a JPEG snapshot of the given RTSP (or HTTP video) URL, giving up after
+/// `FFMPEG_TIMEOUT_SEC`.
+pub fn take(ffmpeg: &PathBuf, url: &str) -> Result<Vec<u8>, Error> {
+    let mut child = Command::new(ffmpeg).args(&args(url))
+                                        .stdin(Stdio::null())
+                                        .stdout(Stdio::piped())
+                                        .stderr(Stdio::piped())
+                                        .spawn()?;
+
+    // Read both pipes on their own threads so ffmpeg can't block writing either while this one
+    // waits for it to exit.
+    let read = |mut r: Box<Read + Send>| thread::spawn(move || {
+        let mut v = Vec::new();
+        r.read_to_end(&mut v).map(|_| v)
+    });
+    let stdout = read(Box::new(child.stdout.take().expect("stdout is piped")));
+    let stderr = read(Box::new(child.stderr.take().expect("stderr is piped")));
+    let deadline = Instant::now() + Duration::from_secs(FFMPEG_TIMEOUT_SEC);
+    let status = loop {
+        if let Some(s) = child.try_wait()? {
+            break s;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("ffmpeg timed out after {} seconds", FFMPEG_TIMEOUT_SEC);
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    let stdout = stdout.join().expect("stdout reader shouldn't panic")?;
+    let stderr = stderr.join().expect("stderr reader shouldn't panic")?;
+    if !status.success() || stdout.is_empty() {
+        bail!("ffmpeg failed with {}: {}", status, String::from_utf8_lossy(&stderr).trim());
+    }
+    Ok(stdout)
+}
+
+/// Fetches a JPEG snapshot from the given HTTP URL. Credentials are sent via basic
+/// authentication, or via digest authentication if the camera responds with such a challenge
+/// (as Hikvision's ISAPI and Dahua's CGI do by default).
+pub fn fetch(url: &str, username: &str, password: &str) -> Result<Vec<u8>, Error> {
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+    let mut resp = client.get(url).basic_auth(username, Some(password)).send()?;
+    if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+        let challenge = resp.headers().get(reqwest::header::WWW_AUTHENTICATE)
+                            .and_then(|h| h.to_str().ok())
+                            .and_then(DigestChallenge::parse);
+        if let Some(c) = challenge {
+            let parsed = url::Url::parse(url)?;
+            let uri = &parsed[url::Position::BeforePath..];
+            let auth = c.authorization(username, password, "GET", uri, &cnonce()?)?;
+            resp = client.get(url).header(reqwest::header::AUTHORIZATION, auth).send()?;
+        }
+    }
+    if !resp.status().is_success() {
+        bail!("status {}", resp.status());
+    }
+    let mut jpeg = Vec::new();
+    resp.take(MAX_HTTP_SNAPSHOT_BYTES + 1).read_to_end(&mut jpeg)?;
+    if jpeg.len() as u64 > MAX_HTTP_SNAPSHOT_BYTES {
+        bail!("snapshot exceeds {} bytes", MAX_HTTP_SNAPSHOT_BYTES);
+    }
+    if !jpeg.starts_with(b"\xff\xd8") {
+        bail!("response isn't a JPEG");
+    }
+    Ok(jpeg)
+}
+
+fn cnonce() -> Result<String, Error> {
+    let mut buf = [0u8; 8];
+    rand::rand_bytes(&mut buf)?;
+    Ok(strutil::hex(&buf))
+}
+
+fn md5_hex(s: &str) -> Result<String, Error> {
+    Ok(strutil::hex(&hash::hash(hash::MessageDigest::md5(), s.as_bytes())?))
+}
+
+/// An RFC 2617 `WWW-Authenticate: Digest ...` challenge. Only the MD5 algorithm is supported.
+#[derive(Debug, Default, PartialEq)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+
+    /// True iff the challenge offers `qop=auth`.
+    qop_auth: bool,
+}
+
+impl DigestChallenge {
+    fn parse(header: &str) -> Option<Self> {
+        if !header.starts_with("Digest ") {
+            return None;
+        }
+        let mut c = DigestChallenge::default();
+        let mut rest = header["Digest ".len()..].trim_left();
+        while !rest.is_empty() {
+            let eq = rest.find('=')?;
+            let key = rest[..eq].trim();
+            rest = &rest[eq+1..];
+            let value = if rest.starts_with('"') {
+                let end = rest[1..].find('"')? + 1;
+                let v = &rest[1..end];
+                rest = &rest[end+1..];
+                v
+            } else {
+                let end = rest.find(',').unwrap_or(rest.len());
+                let v = rest[..end].trim();
+                rest = &rest[end..];
+                v
+            };
+            rest = rest.trim_left();
+            if rest.starts_with(',') {
+                rest = rest[1..].trim_left();
+            }
+            match key {
+                "realm" => c.realm = value.to_owned(),
+                "nonce" => c.nonce = value.to_owned(),
+                "opaque" => c.opaque = Some(value.to_owned()),
+                "qop" => c.qop_auth = value.split(',').any(|q| q.trim() == "auth"),
+                "algorithm" if !value.eq_ignore_ascii_case("MD5") => return None,
+                _ => {},
+            }
+        }
+        if c.nonce.is_empty() {
+            return None;
+        }
+        Some(c)
+    }
+
+    /// Returns the `Authorization` header value for a single request.
+    fn authorization(&self, username: &str, password: &str, method: &str, uri: &str,
+                     cnonce: &str) -> Result<String, Error> {
+        let ha1 = md5_hex(&format!("{}:{}:{}", username, self.realm, password))?;
+        let ha2 = md5_hex(&format!("{}:{}", method, uri))?;
+        let mut h = if self.qop_auth {
+            let response = md5_hex(&format!("{}:{}:00000001:{}:auth:{}", ha1, self.nonce, cnonce,
+                                            ha2))?;
+            format!("Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", \
+                     qop=auth, nc=00000001, cnonce=\"{}\", response=\"{}\"",
+                    username, self.realm, self.nonce, uri, cnonce, response)
+        } else {
+            let response = md5_hex(&format!("{}:{}:{}", ha1, self.nonce, ha2))?;
+            format!("Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", \
+                     response=\"{}\"", username, self.realm, self.nonce, uri, response)
+        };
+        if let Some(ref o) = self.opaque {
+            h.push_str(&format!(", opaque=\"{}\"", o));
+        }
+        Ok(h)
+    }
+}
+
+/// Starts a thread which takes and stores a snapshot of each new doorbell press. `ffmpeg` is
+/// used for main streams without a `snapshot_url`; if it's `None`, those are skipped.
+pub fn start(db: Arc<db::Database>, ffmpeg: Option<PathBuf>) -> Result<(), Error> {
+    // The watcher is called with the database lock held, so snapshots can't be taken directly.
+    let (tx, rx) = mpsc::channel();
+    let have_ffmpeg = ffmpeg.is_some();
+    db.lock().watch(Box::new(move |db, c| {
+        let (id, event) = match *c {
+            db::Change::EventAdded { id, ref event }
+                if event.type_ == vendor_events::DOORBELL_EVENT_TYPE => (id, event),
+            _ => return,
+        };
+        let c = match db.cameras_by_id().get(&event.camera_id) {
+            None => return,
+            Some(c) => c,
+        };
+        let s = match c.streams[db::StreamType::MAIN.index()] {
+            None => return,
+            Some(sid) => &db.streams_by_id()[&sid],
+        };
+        let source = Source::new(c, s);
+        match source {
+            Source::Stream(_) if !have_ffmpeg => return,
+            Source::Exclusive(_) => return,
+            _ => {},
+        }
+        let _ = tx.send((id, source));
+    }));
+    thread::Builder::new()
+        .name("snapshots".to_owned())
+        .spawn(move || {
+            sched::enter(sched::Class::Web);
+            for (id, source) in rx {
+                let r = source.take(ffmpeg.as_ref())
+                              .and_then(|jpeg| db.lock().add_event_snapshot(id, &jpeg));
+                if let Err(e) = r {
+                    warn!("event {}: unable to take snapshot: {}", id, e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DigestChallenge;
+
+    #[test]
+    fn test_args() {
+        let a = super::args("rtsp://u:p@cam/main");
+        let i = a.iter().position(|&a| a == "-i").unwrap();
+        assert_eq!(a[i + 1], "rtsp://u:p@cam/main");
+        assert_eq!(&a[a.len() - 5 ..], &["-f", "image2", "-c:v", "mjpeg", "pipe:1"]);
+        assert!(a.contains(&"-rtsp_transport"));
+        let a = super::args("http://phone:8080/video");
+        assert!(!a.contains(&"-rtsp_transport"));
+    }
+
+    #[test]
+    fn test_digest() {
+        // The example from RFC 2617 section 3.5.
+        let c = DigestChallenge::parse(concat!(
+            r#"Digest realm="testrealm@host.com", qop="auth,auth-int", "#,
+            r#"nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", "#,
+            r#"opaque="5ccc069c403ebaf9f0171e9517f40e41""#)).unwrap();
+        assert_eq!(c, DigestChallenge {
+            realm: "testrealm@host.com".to_owned(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_owned(),
+            opaque: Some("5ccc069c403ebaf9f0171e9517f40e41".to_owned()),
+            qop_auth: true,
+        });
+        let h = c.authorization("Mufasa", "Circle Of Life", "GET", "/dir/index.html", "0a4f113b")
+                 .unwrap();
+        assert!(h.contains(r#"response="6629fae49393a05397450978507c4ef1""#), "{}", h);
+        assert!(h.ends_with(r#", opaque="5ccc069c403ebaf9f0171e9517f40e41""#), "{}", h);
+
+        assert_eq!(DigestChallenge::parse(r#"Basic realm="x""#), None);
+        assert_eq!(DigestChallenge::parse(r#"Digest realm="x", nonce="y", algorithm=SHA-256"#),
+                   None);
+        assert_eq!(DigestChallenge::parse(r#"Digest realm="x"#), None);
+        let c = DigestChallenge::parse(r#"Digest realm="x",nonce="y",algorithm=MD5"#).unwrap();
+        assert!(!c.qop_auth);
+    }
+}
@@ -0,0 +1,94 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Server-sent events, as in `/api/events/stream`. See `design/api.md` for details.
+
+use body::{BodyStream, BoxedError, Chunk};
+use futures::Stream;
+use futures::sync::mpsc;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json;
+
+/// Distributes messages to all connected server-sent event streams.
+pub struct Hub {
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<Chunk>>>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Hub { subscribers: Mutex::new(Vec::new()) }
+    }
+
+    /// Returns a new `text/event-stream` body which will receive all subsequent messages.
+    pub fn subscribe(&self) -> BodyStream {
+        let (tx, rx) = mpsc::unbounded();
+
+        // Send a comment immediately so that the client sees the stream is open.
+        let _ = tx.unbounded_send(Chunk::from(&b": connected\n\n"[..]));
+        self.subscribers.lock().push(tx);
+        Box::new(rx.map_err(|()| -> BoxedError { unreachable!() }))
+    }
+
+    /// Sends a message with the given event name and JSON data to all subscribers, dropping any
+    /// whose connections have gone away.
+    pub fn publish<T: Serialize>(&self, event: &str, data: &T) {
+        let mut msg = format!("event: {}\ndata: ", event).into_bytes();
+        serde_json::to_writer(&mut msg, data).expect("event serialization should succeed");
+        msg.extend_from_slice(b"\n\n");
+        self.subscribers.lock().retain(|s| s.unbounded_send(Chunk::from(msg.clone())).is_ok());
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize { self.subscribers.lock().len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+    use super::Hub;
+
+    #[test]
+    fn publish() {
+        let hub = Hub::new();
+        let s = hub.subscribe();
+        hub.publish("foo", &[1, 2]);
+        let (first, s) = s.into_future().wait().map_err(|_| ()).unwrap();
+        assert_eq!(::bytes::Buf::bytes(&first.unwrap()), b": connected\n\n");
+        let (second, s) = s.into_future().wait().map_err(|_| ()).unwrap();
+        assert_eq!(::bytes::Buf::bytes(&second.unwrap()), b"event: foo\ndata: [1,2]\n\n");
+
+        // Once the subscriber is dropped, the next publish should forget it.
+        drop(s);
+        assert_eq!(hub.len(), 1);
+        hub.publish("foo", &[3]);
+        assert_eq!(hub.len(), 0);
+    }
+}
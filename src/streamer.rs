@@ -28,66 +28,221 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use analytics;
+use base::trace;
 use clock::{Clocks, TimerGuard};
-use db::{Camera, Database, Stream, dir, recording, writer};
+use db::{self, Camera, Database, Stream, dir, recording, writer};
 use failure::Error;
 use h264;
+use maintenance::Maintenance;
+use onvif;
+use resolve;
+use std::cmp;
+use std::path::PathBuf;
 use std::result::Result;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use stream;
+use thumbnail;
 use time;
+use uuid::Uuid;
+use vendor_events;
 
-pub static ROTATE_INTERVAL_SEC: i64 = 60;
+/// The number of consecutive failures of a stream's own source before switching to its fallback
+/// source, if any.
+const FAILOVER_THRESHOLD: u32 = 3;
+
+/// The encoder settings for a fallback V4L2 source, whose own settings aren't known here.
+const NO_ENCODER_SETTINGS: db::EncoderSettings = db::EncoderSettings {
+    gov_length: None,
+    bitrate_kbps: None,
+    frame_rate: None,
+    resolution: None,
+};
 
 /// Common state that can be used by multiple `Streamer` instances.
 pub struct Environment<'a, 'b, C, S> where C: Clocks + Clone, S: 'a + stream::Stream {
     pub opener: &'a stream::Opener<S>,
     pub db: &'b Arc<Database<C>>,
     pub shutdown: &'b Arc<AtomicBool>,
+    pub maintenance: &'b Arc<Maintenance>,
+
+    /// The maximum bytes per stream to hold in memory while its sample file directory is
+    /// unavailable; see `writer::Writer::set_max_spool_bytes`.
+    pub max_spool_bytes: usize,
+
+    /// Where to send key frames for streams with a `thumbnail_interval_sec`, if anywhere.
+    pub thumbnails: Option<thumbnail::Sender>,
+
+    /// The ffmpeg binary with which to transcode `db::StreamSource::Mjpeg` streams and encode
+    /// `db::StreamSource::V4l2` ones, if any.
+    pub transcode_ffmpeg: Option<PathBuf>,
 }
 
 pub struct Streamer<'a, C, S> where C: Clocks + Clone, S: 'a + stream::Stream {
     shutdown: Arc<AtomicBool>,
+    maintenance: Arc<Maintenance>,
 
     // State below is only used by the thread in Run.
     rotate_offset_sec: i64,
     rotate_interval_sec: i64,
     db: Arc<Database<C>>,
+
+    /// The stream's sample file directory as of startup, and its syncer. If the stream is moved
+    /// to another directory (see `relocate`), the streamer stops.
+    sample_file_dir_id: Option<i32>,
     dir: Arc<dir::SampleFileDir>,
-    syncer_channel: writer::SyncerChannel<::std::fs::File>,
+    syncer_channel: writer::SyncerChannel<dir::SampleFileWriter>,
     opener: &'a stream::Opener<S>,
     stream_id: i32,
+    camera_id: i32,
     short_name: String,
-    url: String,
-    redacted_url: String,
+    rtsp_path: String,
+    source: db::StreamSource,
+    transcode_ffmpeg: Option<PathBuf>,
+
+    /// The camera's `(username, password)` as of the most recent connection attempt. When these
+    /// change in the database (see `db::LockedDatabase::rotate_camera_credentials`), the streamer
+    /// reconnects.
+    credentials: (String, String),
+
+    /// The camera's `host`, and the address it last resolved to if it's an mDNS name. See
+    /// `resolve::resolve_local`.
+    host: String,
+    resolved_host: Option<String>,
+
+    /// The `(rtsp_path, source)` of a stream of the same camera to record from when this stream's
+    /// own source is failing.
+    fallback: Option<(String, db::StreamSource)>,
+    health: db::StreamHealth,
+    max_spool_bytes: usize,
+
+    /// See `db::Stream::sei_motion_uuid`.
+    sei_motion_uuid: Option<Uuid>,
+
+    /// See `db::Stream::metadata_events`.
+    metadata_events: bool,
+
+    /// Where to send key frames every `db::Stream::thumbnail_interval_sec`, if enabled.
+    thumbnails: Option<(thumbnail::Sender, recording::Duration)>,
+
+    /// See `db::Stream::encoder`. These are pushed to the camera before the first connection, and
+    /// again after `encoder_pushed` is cleared because the camera isn't honoring its GOP length.
+    encoder: db::EncoderSettings,
+    encoder_pushed: bool,
+
+    /// See `db::Stream::input_options`. These apply only to the stream's own source, not its
+    /// fallback.
+    input_options: Vec<(String, String)>,
 }
 
 impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::Stream {
     pub fn new<'b>(env: &Environment<'a, 'b, C, S>, dir: Arc<dir::SampleFileDir>,
-                   syncer_channel: writer::SyncerChannel<::std::fs::File>,
+                   syncer_channel: writer::SyncerChannel<dir::SampleFileWriter>,
                    stream_id: i32, c: &Camera, s: &Stream, rotate_offset_sec: i64,
                    rotate_interval_sec: i64) -> Self {
         Streamer {
             shutdown: env.shutdown.clone(),
+            maintenance: env.maintenance.clone(),
             rotate_offset_sec: rotate_offset_sec,
             rotate_interval_sec: rotate_interval_sec,
             db: env.db.clone(),
+            sample_file_dir_id: s.sample_file_dir_id,
             dir,
             syncer_channel: syncer_channel,
             opener: env.opener,
             stream_id: stream_id,
+            camera_id: c.id,
             short_name: format!("{}-{}", c.short_name, s.type_.as_str()),
-            url: format!("rtsp://{}:{}@{}{}", c.username, c.password, c.host, s.rtsp_path),
-            redacted_url: format!("rtsp://{}:redacted@{}{}", c.username, c.host, s.rtsp_path),
+            rtsp_path: s.rtsp_path.clone(),
+            source: s.source.clone(),
+            transcode_ffmpeg: env.transcode_ffmpeg.clone(),
+            credentials: (c.username.clone(), c.password.clone()),
+            host: c.host.clone(),
+            resolved_host: None,
+            fallback: None,
+            health: db::StreamHealth::default(),
+            max_spool_bytes: env.max_spool_bytes,
+            sei_motion_uuid: s.sei_motion_uuid,
+            metadata_events: s.metadata_events,
+            thumbnails: match (env.thumbnails.as_ref(), s.thumbnail_interval_sec) {
+                (Some(t), i) if i > 0 => {
+                    Some((t.clone(), recording::Duration(i * recording::TIME_UNITS_PER_SEC)))
+                },
+                _ => None,
+            },
+            encoder: s.encoder,
+            encoder_pushed: false,
+            input_options: match s.input_options {
+                None => Vec::new(),
+                Some(ref o) => db::parse_input_options(o).unwrap_or_else(|e| {
+                    warn!("{}-{}: ignoring input options: {}", c.short_name, s.type_.as_str(), e);
+                    Vec::new()
+                }),
+            },
         }
     }
 
+    /// Sets a stream to record from (flagged as degraded) when this stream's own source fails
+    /// repeatedly. This must be another stream of the same camera, typically the sub stream used
+    /// as a fallback for the main stream.
+    pub fn set_fallback(&mut self, s: &Stream) {
+        self.fallback = Some((s.rtsp_path.clone(), s.source.clone()));
+    }
+
     pub fn short_name(&self) -> &str { &self.short_name }
 
     pub fn run(&mut self) {
+        // After a degraded run, always retry the stream's own source.
+        let mut retry_own = false;
         while !self.shutdown.load(Ordering::SeqCst) {
-            if let Err(e) = self.run_once() {
+            match self.db.lock().streams_by_id().get(&self.stream_id) {
+                None => {
+                    info!("{}: stream was deleted", self.short_name);
+                    return;
+                },
+                Some(s) if s.sample_file_dir_id != self.sample_file_dir_id => {
+                    warn!("{}: stream was moved to another sample file dir; recording will \
+                           resume there when the server restarts", self.short_name);
+                    return;
+                },
+                Some(_) => {},
+            }
+            if self.maintenance.is_stream_paused(self.stream_id) || self.is_paused() {
+                self.db.clocks().sleep(time::Duration::seconds(1));
+                continue;
+            }
+            let degraded = !retry_own && self.fallback.is_some() &&
+                           self.health.consecutive_failures >= FAILOVER_THRESHOLD;
+            let r = self.run_once(degraded);
+
+            // Whether the degraded run succeeded or failed, try the stream's own source next.
+            retry_own = degraded;
+            if let Err(e) = r {
+                if !degraded {
+                    self.health.consecutive_failures += 1;
+                }
+                let fail_over = !degraded && self.fallback.is_some() &&
+                                self.health.consecutive_failures >= FAILOVER_THRESHOLD;
+
+                // A failed retry of the stream's own source leaves it degraded, so that health
+                // (and notifications) don't flap between each attempt.
+                let was_degraded = self.health.degraded;
+                if !fail_over {
+                    self.health.degraded = false;
+                }
+                self.health.spooling = false;  // the writer has been closed, flushing any spool.
+                self.health.last_error = Some(e.to_string());
+                self.report_health();
+                if fail_over {
+                    if was_degraded {
+                        debug!("{}: own source still failing: {:?}", self.short_name, e);
+                    } else {
+                        warn!("{}: failing over to degraded source after {} failures; last \
+                               error: {:?}", self.short_name, self.health.consecutive_failures, e);
+                    }
+                    continue;
+                }
                 let sleep_time = time::Duration::seconds(1);
                 warn!("{}: sleeping for {:?} after error: {:?}", self.short_name, sleep_time, e);
                 self.db.clocks().sleep(sleep_time);
@@ -96,17 +251,169 @@ impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::
         info!("{}: shutting down", self.short_name);
     }
 
-    fn run_once(&mut self) -> Result<(), Error> {
-        info!("{}: Opening input: {}", self.short_name, self.redacted_url);
+    /// Adds the given event, unless maintenance mode is active. Events aren't recorded then, so
+    /// that the database is left alone until the mode is exited.
+    fn add_event(&self, e: Option<db::EventToInsert>) {
+        if let Some(e) = e {
+            if self.maintenance.is_active() {
+                debug!("{}: maintenance mode; dropping event {:?}", self.short_name, e);
+                return;
+            }
+            if let Err(e) = self.db.lock().add_event(&e) {
+                warn!("{}: unable to add event: {}", self.short_name, e);
+            }
+        }
+    }
+
+    fn add_events_with_detections(&self,
+                                  events: &mut Vec<(db::EventToInsert, Option<db::Detection>)>) {
+        if events.is_empty() {
+            return;
+        }
+        if self.maintenance.is_active() {
+            debug!("{}: maintenance mode; dropping {} events", self.short_name, events.len());
+            events.clear();
+            return;
+        }
+        let mut l = self.db.lock();
+        for (e, d) in events.drain(..) {
+            let r = l.add_event(&e).and_then(|id| match d {
+                Some(d) => l.add_event_detections(id, &[d]),
+                None => Ok(()),
+            });
+            if let Err(err) = r {
+                warn!("{}: unable to add event {:?}: {}", self.short_name, e, err);
+            }
+        }
+    }
+
+    /// Returns true if recording has been paused via the API; see `db::Stream::paused`.
+    fn is_paused(&self) -> bool {
+        self.db.lock().streams_by_id().get(&self.stream_id).map(|s| s.paused).unwrap_or(false)
+    }
+
+    /// Returns why the current run should end at this key frame, if it should: recording was
+    /// paused via the API, or the camera's credentials changed since connecting.
+    fn interruption(&self) -> Option<&'static str> {
+        let l = self.db.lock();
+        if l.streams_by_id().get(&self.stream_id).map(|s| s.paused).unwrap_or(false) {
+            return Some("pausing");
+        }
+        match l.cameras_by_id().get(&self.camera_id) {
+            Some(c) if c.username != self.credentials.0 || c.password != self.credentials.1 => {
+                Some("reconnecting with new credentials")
+            },
+            _ => None,
+        }
+    }
+
+    fn report_health(&self) {
+        if let Err(e) = self.db.lock().update_stream_health(self.stream_id, self.health.clone()) {
+            warn!("{}: unable to update health: {}", self.short_name, e);
+        }
+    }
+
+    /// Pushes `encoder` to the camera via ONVIF. Failures are logged rather than returned, as
+    /// the camera's current settings are still worth recording; they're not retried unless the
+    /// camera is later seen to ignore the GOP length.
+    fn push_encoder_settings(&mut self) {
+        self.encoder_pushed = true;
+        let host = self.resolved_host.as_ref().unwrap_or(&self.host);
+        let (ref username, ref password) = self.credentials;
+        match onvif::set_encoder(host, username, password, &self.rtsp_path, &self.encoder) {
+            Ok(c) => info!("{}: pushed encoder settings {:?}; encoder is now {}x{} {:?} {:?}",
+                           self.short_name, self.encoder, c.width, c.height, c.rate_control,
+                           c.h264),
+            Err(e) => warn!("{}: unable to push encoder settings {:?}: {}",
+                            self.short_name, self.encoder, e),
+        }
+    }
+
+    /// Records from the stream's own source or (if `degraded`) its fallback source. A degraded
+    /// run returns after its first recording so that the caller will retry the stream's own
+    /// source.
+    fn run_once(&mut self, degraded: bool) -> Result<(), Error> {
+        let credentials = {
+            let l = self.db.lock();
+            let c = l.cameras_by_id().get(&self.camera_id)
+                     .ok_or_else(|| format_err!("camera {} is gone", self.camera_id))?;
+            (c.username.clone(), c.password.clone())
+        };
+        if credentials != self.credentials {
+            info!("{}: using new credentials for user {:?}", self.short_name, credentials.0);
+            self.credentials = credentials;
+        }
+        let (path, source) = match (degraded, self.fallback.as_ref()) {
+            (true, Some(f)) => f.clone(),
+            _ => (self.rtsp_path.clone(), self.source.clone()),
+        };
+        let (url, redacted_url) = match source {
+            db::StreamSource::Rtsp => {
+                let (ref username, ref password) = self.credentials;
+                (stream::rtsp_url(username, password, &self.host, &path),
+                 stream::redacted_rtsp_url(username, &self.host, &path))
+            },
+            db::StreamSource::Hls(ref u) | db::StreamSource::Mjpeg(ref u) => {
+                stream::http_urls(&self.credentials.0, &self.credentials.1, u)?
+            },
+            db::StreamSource::V4l2(ref d) => (d.clone(), d.clone()),
+            db::StreamSource::Push(ref u) => (u.clone(), stream::redacted_push_url(u)),
+        };
+
+        // Resolve mDNS names on every attempt, as the camera's address may have changed. (ffmpeg
+        // does likewise for ordinary DNS names.) HTTP sources name their own hosts.
+        let resolved = match source {
+            db::StreamSource::Rtsp => resolve::resolve_local(&self.host)?,
+            _ => None,
+        };
+        let url = match resolved {
+            None => url,
+            Some(a) => {
+                if self.resolved_host.as_ref() != Some(&a) {
+                    info!("{}: {} resolved to {}", self.short_name, self.host, a);
+                    self.resolved_host = Some(a.clone());
+                }
+                url.replacen(&format!("@{}", self.host), &format!("@{}", a), 1)
+            },
+        };
+        if !degraded && source == db::StreamSource::Rtsp && !self.encoder.is_empty() &&
+           !self.encoder_pushed {
+            self.push_encoder_settings();
+        }
+        info!("{}: Opening input: {}", self.short_name, redacted_url);
         let clocks = self.db.clocks();
 
         let mut stream = {
-            let _t = TimerGuard::new(&clocks, || format!("opening {}", self.redacted_url));
-            self.opener.open(stream::Source::Rtsp(&self.url))?
+            let _t = TimerGuard::new(&clocks, || format!("opening {}", redacted_url));
+            let _span = trace::span("rtsp open");
+            let src = match source {
+                db::StreamSource::Rtsp => stream::Source::Rtsp(&url),
+                db::StreamSource::Hls(_) => stream::Source::Hls(&url),
+                db::StreamSource::Mjpeg(_) => match self.transcode_ffmpeg {
+                    None => bail!("recording Motion JPEG requires --transcode-ffmpeg"),
+                    Some(ref f) => stream::Source::Mjpeg { url: &url, ffmpeg: f },
+                },
+                db::StreamSource::V4l2(_) => match self.transcode_ffmpeg {
+                    None => bail!("recording a V4L2 device requires --transcode-ffmpeg"),
+                    Some(ref f) => stream::Source::V4l2 {
+                        device: &url,
+                        encoder: if degraded { &NO_ENCODER_SETTINGS } else { &self.encoder },
+                        ffmpeg: f,
+                    },
+                },
+                db::StreamSource::Push(_) => {
+                    stream::Source::Push { url: &url, passphrase: &self.credentials.1 }
+                },
+            };
+            self.opener.open(src, if degraded { &[] } else { &self.input_options[..] })?
         };
         let realtime_offset = self.db.clocks().realtime() - clocks.monotonic();
         // TODO: verify width/height.
         let extra_data = stream.get_extra_data()?;
+        let thumbnail_sample_entry = match self.thumbnails {
+            Some(_) => Some(extra_data.sample_entry.clone()),
+            None => None,
+        };
         let video_sample_entry_id = {
             let _t = TimerGuard::new(&clocks, || "inserting video sample entry");
             self.db.lock().insert_video_sample_entry(extra_data.width, extra_data.height,
@@ -121,25 +428,95 @@ impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::
         let mut transformed = Vec::new();
         let mut w = writer::Writer::new(&self.dir, &self.db, &self.syncer_channel, self.stream_id,
                                         video_sample_entry_id);
+        w.set_degraded(degraded);
+        w.set_max_spool_bytes(self.max_spool_bytes);
+
+        // The fallback source is a different stream, which may not use the same SEI format.
+        let mut motion = match (degraded, self.sei_motion_uuid) {
+            (false, Some(u)) => Some(analytics::SeiMotionDetector::new(self.camera_id, u)),
+            _ => None,
+        };
+        let mut metadata_events = match (degraded, self.metadata_events) {
+            (false, true) => Some(vendor_events::MetadataEvents::new(self.camera_id)),
+            _ => None,
+        };
+        let mut events = Vec::new();
+        let mut next_thumbnail: Option<recording::Time> = None;
+        let mut frames_since_key = 0;
         while !self.shutdown.load(Ordering::SeqCst) {
+            if self.maintenance.is_stream_paused(self.stream_id) {
+                info!("{}: pausing for maintenance", self.short_name);
+                break;
+            }
+
+            // Metadata packets read along with the previous frame. These are taken before reading
+            // the next frame, which borrows the stream.
+            for (pts, data) in stream.take_metadata() {
+                w.write_metadata(&data, pts);
+                if let Some(ref mut m) = metadata_events {
+                    let now = recording::Time::new(clocks.monotonic() + realtime_offset);
+                    m.process(now, &data, &mut events);
+                }
+            }
+            self.add_events_with_detections(&mut events);
             let pkt = {
                 let _t = TimerGuard::new(&clocks, || "getting next packet");
+                let _span = trace::span("rtsp read");
                 stream.get_next()?
             };
             let pts = pkt.pts().ok_or_else(|| format_err!("packet with no pts"))?;
+
+            // Check for a pause or new credentials only at key frames, to avoid taking the
+            // database lock on every frame. The current recording ends cleanly just before this
+            // one. As with rotation, wait until any spooled data has been written.
+            let interruption = if pkt.is_key() && w.spooled_bytes() == 0 {
+                self.interruption()
+            } else {
+                None
+            };
+            if let Some(why) = interruption {
+                info!("{}: {}", self.short_name, why);
+                if rotate.take().is_some() {
+                    let _t = TimerGuard::new(&clocks, || "closing writer");
+                    w.close(Some(pts));
+                }
+                break;
+            }
             if !seen_key_frame && !pkt.is_key() {
                 continue;
             } else if !seen_key_frame {
                 debug!("{}: have first key frame", self.short_name);
                 seen_key_frame = true;
+                if !degraded {
+                    self.health.consecutive_failures = 0;
+                    self.health.last_error = None;
+                }
+                self.health.degraded = degraded;
+                self.report_health();
+            }
+            if pkt.is_key() {
+                frames_since_key = 0;
+            } else if let (false, Some(g)) = (degraded, self.encoder.gov_length) {
+                // Allow some slack, as cameras may insert a key frame early but not late.
+                frames_since_key += 1;
+                if frames_since_key == 2 * g && self.encoder_pushed {
+                    warn!("{}: {} frames without a key frame despite GOP length {}; will push \
+                           encoder settings again", self.short_name, frames_since_key, g);
+                    self.encoder_pushed = false;
+                }
             }
             let frame_realtime = clocks.monotonic() + realtime_offset;
             let local_time = recording::Time::new(frame_realtime);
             rotate = if let Some(r) = rotate {
-                if frame_realtime.sec > r && pkt.is_key() {
+                // Don't rotate while spooling; the spooled data must be written to this
+                // recording's file before it can be closed.
+                if frame_realtime.sec > r && pkt.is_key() && w.spooled_bytes() == 0 {
                     trace!("{}: write on normal rotation", self.short_name);
                     let _t = TimerGuard::new(&clocks, || "closing writer");
                     w.close(Some(pts));
+                    if degraded {
+                        return Ok(());
+                    }
                     None
                 } else {
                     Some(r)
@@ -155,8 +532,13 @@ impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::
                     // On the first recording, set rotate time to not the next rotate offset, but
                     // the one after, so that it's longer than usual rather than shorter than
                     // usual.  This ensures there's plenty of frame times to use when calculating
-                    // the start time.
-                    let r = r + if w.previously_opened()? { 0 } else { self.rotate_interval_sec };
+                    // the start time. The extension is capped at a minute to keep long rotate
+                    // intervals within MAX_RECORDING_DURATION.
+                    let r = r + if w.previously_opened()? {
+                        0
+                    } else {
+                        cmp::min(self.rotate_interval_sec, 60)
+                    };
                     let _t = TimerGuard::new(&clocks, || "creating writer");
                     r
                 },
@@ -171,15 +553,43 @@ impl<'a, C, S> Streamer<'a, C, S> where C: 'a + Clocks + Clone, S: 'a + stream::
             } else {
                 orig_data
             };
+            if let Some(ref mut m) = motion {
+                match h264::sei_messages(transformed_data) {
+                    Ok(msgs) => self.add_event(m.process(local_time, &msgs)),
+                    Err(e) => debug!("{}: unable to parse SEI: {}", self.short_name, e),
+                }
+            }
+            if let (Some(&(ref t, i)), Some(entry)) = (self.thumbnails.as_ref(),
+                                                       thumbnail_sample_entry.as_ref()) {
+                if pkt.is_key() && next_thumbnail.map(|n| local_time >= n).unwrap_or(true) {
+                    match h264::to_annex_b(entry, transformed_data) {
+                        Ok(d) => t.send(self.stream_id, local_time, d),
+                        Err(e) => debug!("{}: unable to prepare thumbnail: {}", self.short_name, e),
+                    }
+                    next_thumbnail = Some(local_time + i);
+                }
+            }
             let _t = TimerGuard::new(&clocks,
                                       || format!("writing {} bytes", transformed_data.len()));
             w.write(transformed_data, local_time, pts, pkt.is_key())?;
+            let spooling = w.spooled_bytes() > 0;
+            if spooling != self.health.spooling {
+                self.health.spooling = spooling;
+                self.report_health();
+            }
             rotate = Some(r);
         }
         if rotate.is_some() {
             let _t = TimerGuard::new(&clocks, || "closing writer");
             w.close(None);
         }
+        if let Some(ref mut m) = motion {
+            self.add_event(m.finish());
+        }
+        if let Some(ref mut m) = metadata_events {
+            m.finish(&mut events);
+            self.add_events_with_detections(&mut events);
+        }
         Ok(())
     }
 }
@@ -192,6 +602,7 @@ mod tests {
     use db::testutil;
     use failure::Error;
     use h264;
+    use maintenance::Maintenance;
     use moonfire_ffmpeg;
     use parking_lot::Mutex;
     use std::cmp;
@@ -274,10 +685,11 @@ mod tests {
     }
 
     impl<'a> stream::Opener<ProxyingStream<'a>> for MockOpener<'a> {
-        fn open(&self, src: stream::Source) -> Result<ProxyingStream<'a>, Error> {
+        fn open(&self, src: stream::Source, _options: &[(String, String)])
+                -> Result<ProxyingStream<'a>, Error> {
             match src {
                 stream::Source::Rtsp(url) => assert_eq!(url, &self.expected_url),
-                stream::Source::File(_) => panic!("expected rtsp url"),
+                _ => panic!("expected rtsp url"),
             };
             let mut l = self.streams.lock();
             match l.pop() {
@@ -323,7 +735,8 @@ mod tests {
         let clocks = clock::SimulatedClocks::new(time::Timespec::new(1429920000, 0));
         clocks.sleep(time::Duration::seconds(86400));  // to 2015-04-26 00:00:00 UTC
 
-        let stream = stream::FFMPEG.open(stream::Source::File("src/testdata/clip.mp4")).unwrap();
+        let stream =
+            stream::FFMPEG.open(stream::Source::File("src/testdata/clip.mp4"), &[]).unwrap();
         let mut stream = ProxyingStream::new(&clocks, time::Duration::seconds(2), stream);
         stream.ts_offset = 180000;  // starting pts of the input should be irrelevant
         stream.ts_offset_pkts_left = u32::max_value();
@@ -338,6 +751,10 @@ mod tests {
             opener: &opener,
             db: &db.db,
             shutdown: &opener.shutdown,
+            maintenance: &Maintenance::new(),
+            max_spool_bytes: 0,
+            thumbnails: None,
+            transcode_ffmpeg: None,
         };
         let mut stream;
         {
@@ -0,0 +1,467 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Synthetic H.264 video, for testing without a camera.
+//!
+//! This produces a valid (if inefficient) Constrained Baseline profile bitstream without an
+//! encoder: IDR frames consist entirely of `I_PCM` macroblocks, which carry raw samples, and
+//! P frames skip every macroblock except those covered by a box moving across a gradient
+//! background. See ISO/IEC 14496-10 section 7.3 for the syntax.
+
+use failure::Error;
+
+/// Each macroblock is 16x16 luma samples.
+const MB_SIZE: u16 = 16;
+
+/// The side of the moving box, in macroblocks.
+const BOX_MBS: u16 = 2;
+
+/// `log2_max_frame_num_minus4` in the SPS; `frame_num` wraps at 16.
+const LOG2_MAX_FRAME_NUM: u32 = 4;
+
+/// `nal_unit_type` of filler data; see table 7-1.
+const NAL_UNIT_FILLER_DATA: u8 = 12;
+
+/// `mb_type` values of `I_PCM` in I and P slices; see tables 7-11 and 7-13.
+const I_PCM_IN_I_SLICE: u32 = 25;
+const I_PCM_IN_P_SLICE: u32 = 30;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Dimensions, in pixels. Each must be a multiple of 16.
+    pub width: u16,
+    pub height: u16,
+
+    pub frames_per_sec: u32,
+
+    /// The number of frames from one IDR (key) frame to the next.
+    pub gop_frames: u32,
+
+    /// If non-zero, frames are padded with filler data to produce at least this bit rate.
+    /// (The uncompressed IDR frames impose a minimum of their own.)
+    pub bits_per_sec: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            width: 320,
+            height: 240,
+            frames_per_sec: 10,
+            gop_frames: 10,
+            bits_per_sec: 0,
+        }
+    }
+}
+
+/// A single frame of synthetic video.
+#[derive(Debug)]
+pub struct Frame {
+    pub pts_90k: i64,
+    pub is_key: bool,
+
+    /// The frame's NAL units, in Annex B byte stream format.
+    pub data: Vec<u8>,
+}
+
+/// An endless source of synthetic frames.
+pub struct Source {
+    config: Config,
+    width_mbs: u16,
+    height_mbs: u16,
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+    frame: u64,
+    idr_pic_id: u32,
+}
+
+impl Source {
+    pub fn new(config: Config) -> Result<Self, Error> {
+        if config.width % MB_SIZE != 0 || config.height % MB_SIZE != 0 {
+            bail!("dimensions {}x{} must be multiples of {}", config.width, config.height,
+                  MB_SIZE);
+        }
+        let (width_mbs, height_mbs) = (config.width / MB_SIZE, config.height / MB_SIZE);
+        if width_mbs <= BOX_MBS || height_mbs < BOX_MBS {
+            bail!("dimensions {}x{} are too small", config.width, config.height);
+        }
+        if config.frames_per_sec == 0 || config.frames_per_sec > 90000 {
+            bail!("invalid frames_per_sec {}", config.frames_per_sec);
+        }
+        if config.gop_frames == 0 {
+            bail!("gop_frames must be positive");
+        }
+        let sps = sps(width_mbs, height_mbs);
+        let pps = pps();
+        Ok(Source {
+            config,
+            width_mbs,
+            height_mbs,
+            sps,
+            pps,
+            frame: 0,
+            idr_pic_id: 0,
+        })
+    }
+
+    pub fn config(&self) -> &Config { &self.config }
+
+    /// Returns the sequence and picture parameter sets as NAL units (without start codes).
+    pub fn sps(&self) -> &[u8] { &self.sps }
+    pub fn pps(&self) -> &[u8] { &self.pps }
+
+    /// Returns the parameter sets in Annex B format, as ffmpeg would supply for an RTSP stream;
+    /// suitable for `h264::ExtraData::parse`.
+    pub fn extra_data(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        append_start_code(&mut out, &self.sps);
+        append_start_code(&mut out, &self.pps);
+        out
+    }
+
+    /// Returns the column of the box's leftmost macroblock in the given frame.
+    /// The box moves right one macroblock per frame, wrapping around to the left edge.
+    fn box_col(&self, frame: u64) -> u16 {
+        (frame % (self.width_mbs - BOX_MBS + 1) as u64) as u16
+    }
+
+    fn in_box(&self, col: u16, row: u16, box_col: u16) -> bool {
+        let box_row = (self.height_mbs - BOX_MBS) / 2;
+        col >= box_col && col < box_col + BOX_MBS && row >= box_row && row < box_row + BOX_MBS
+    }
+
+    /// Writes an `I_PCM` macroblock's `pcm_alignment_zero_bit`s and samples.
+    fn pcm(&self, w: &mut BitWriter, row: u16, in_box: bool) {
+        w.align();
+        let (y, cb, cr) = if in_box {
+            (210, 16, 146)  // yellow.
+        } else {
+            // A vertical gradient from dark to light gray.
+            ((32 + 192 * row as u32 / self.height_mbs as u32) as u8, 128, 128)
+        };
+        w.bytes(&[y; 256]);
+        w.bytes(&[cb; 64]);
+        w.bytes(&[cr; 64]);
+    }
+
+    pub fn next_frame(&mut self) -> Frame {
+        let n = self.frame;
+        self.frame += 1;
+        let gop_pos = n % self.config.gop_frames as u64;
+        let is_key = gop_pos == 0;
+        let frame_num = (gop_pos % (1 << LOG2_MAX_FRAME_NUM)) as u32;
+        let box_col = self.box_col(n);
+        let mut w = BitWriter::new();
+        w.ue(0);                                   // first_mb_in_slice
+        w.ue(if is_key { 7 } else { 5 });          // slice_type: all I or all P
+        w.ue(0);                                   // pic_parameter_set_id
+        w.u(LOG2_MAX_FRAME_NUM, frame_num);        // frame_num
+        if is_key {
+            w.ue(self.idr_pic_id);                 // idr_pic_id
+            self.idr_pic_id ^= 1;                  // consecutive IDRs must differ.
+        } else {
+            w.u(1, 0);                             // num_ref_idx_active_override_flag
+            w.u(1, 0);                             // ref_pic_list_modification_flag_l0
+        }
+        if is_key {
+            w.u(1, 0);                             // no_output_of_prior_pics_flag
+            w.u(1, 0);                             // long_term_reference_flag
+        } else {
+            w.u(1, 0);                             // adaptive_ref_pic_marking_mode_flag
+        }
+        w.se(0);                                   // slice_qp_delta
+        w.ue(1);                                   // disable_deblocking_filter_idc
+
+        // slice_data()
+        if is_key {
+            for row in 0 .. self.height_mbs {
+                for col in 0 .. self.width_mbs {
+                    w.ue(I_PCM_IN_I_SLICE);        // mb_type
+                    let b = self.in_box(col, row, box_col);
+                    self.pcm(&mut w, row, b);
+                }
+            }
+        } else {
+            // Code the macroblocks which the box covers now or covered in the previous frame;
+            // skip the rest.
+            let prev_box_col = self.box_col(n - 1);
+            let mut skip_run = 0;
+            for row in 0 .. self.height_mbs {
+                for col in 0 .. self.width_mbs {
+                    let b = self.in_box(col, row, box_col);
+                    if !b && !self.in_box(col, row, prev_box_col) {
+                        skip_run += 1;
+                        continue;
+                    }
+                    w.ue(skip_run);                // mb_skip_run
+                    skip_run = 0;
+                    w.ue(I_PCM_IN_P_SLICE);        // mb_type
+                    self.pcm(&mut w, row, b);
+                }
+            }
+            if skip_run > 0 {
+                w.ue(skip_run);                    // mb_skip_run
+            }
+        }
+        w.trailing_bits();
+
+        let mut data = Vec::new();
+        if is_key {
+            // Repeat the parameter sets in-band, as many cameras do.
+            append_start_code(&mut data, &self.sps);
+            append_start_code(&mut data, &self.pps);
+        }
+        append_start_code(&mut data, &nal(if is_key { 0x65 } else { 0x41 }, &w.finish()));
+        self.pad(&mut data);
+        Frame {
+            pts_90k: (n * 90000 / self.config.frames_per_sec as u64) as i64,
+            is_key,
+            data,
+        }
+    }
+}
+
+impl Source {
+    /// Appends a filler data NAL unit (section 7.3.2.7) to bring `data` up to the per-frame share
+    /// of `bits_per_sec`, if it's short.
+    fn pad(&self, data: &mut Vec<u8>) {
+        let target = (self.config.bits_per_sec / 8 / self.config.frames_per_sec) as usize;
+        let overhead = 6;  // start code, header byte, and rbsp_trailing_bits.
+        if data.len() + overhead >= target {
+            return;
+        }
+        let ff_bytes = target - data.len() - overhead;
+        data.extend_from_slice(&[0, 0, 0, 1, NAL_UNIT_FILLER_DATA]);
+        data.resize(data.len() + ff_bytes, 0xff);
+        data.push(0x80);
+    }
+}
+
+impl Iterator for Source {
+    type Item = Frame;
+    fn next(&mut self) -> Option<Frame> { Some(self.next_frame()) }
+}
+
+/// Returns a sequence parameter set, as in ISO/IEC 14496-10 section 7.3.2.1.1.
+fn sps(width_mbs: u16, height_mbs: u16) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    w.u(8, 66);                                    // profile_idc: Baseline
+    w.u(8, 0xc0);                                  // constraint_set0_flag, constraint_set1_flag
+    w.u(8, 30);                                    // level_idc: 3.0
+    w.ue(0);                                       // seq_parameter_set_id
+    w.ue(LOG2_MAX_FRAME_NUM - 4);                  // log2_max_frame_num_minus4
+    w.ue(2);                                       // pic_order_cnt_type
+    w.ue(1);                                       // max_num_ref_frames
+    w.u(1, 0);                                     // gaps_in_frame_num_value_allowed_flag
+    w.ue(width_mbs as u32 - 1);                    // pic_width_in_mbs_minus1
+    w.ue(height_mbs as u32 - 1);                   // pic_height_in_map_units_minus1
+    w.u(1, 1);                                     // frame_mbs_only_flag
+    w.u(1, 1);                                     // direct_8x8_inference_flag
+    w.u(1, 0);                                     // frame_cropping_flag
+    w.u(1, 0);                                     // vui_parameters_present_flag
+    w.trailing_bits();
+    nal(0x67, &w.finish())
+}
+
+/// Returns a picture parameter set, as in ISO/IEC 14496-10 section 7.3.2.2.
+fn pps() -> Vec<u8> {
+    let mut w = BitWriter::new();
+    w.ue(0);                                       // pic_parameter_set_id
+    w.ue(0);                                       // seq_parameter_set_id
+    w.u(1, 0);                                     // entropy_coding_mode_flag: CAVLC
+    w.u(1, 0);                                     // bottom_field_pic_order_in_frame_present_flag
+    w.ue(0);                                       // num_slice_groups_minus1
+    w.ue(0);                                       // num_ref_idx_l0_default_active_minus1
+    w.ue(0);                                       // num_ref_idx_l1_default_active_minus1
+    w.u(1, 0);                                     // weighted_pred_flag
+    w.u(2, 0);                                     // weighted_bipred_idc
+    w.se(0);                                       // pic_init_qp_minus26
+    w.se(0);                                       // pic_init_qs_minus26
+    w.se(0);                                       // chroma_qp_index_offset
+    w.u(1, 1);                                     // deblocking_filter_control_present_flag
+    w.u(1, 0);                                     // constrained_intra_pred_flag
+    w.u(1, 0);                                     // redundant_pic_cnt_present_flag
+    w.trailing_bits();
+    nal(0x68, &w.finish())
+}
+
+/// Returns a NAL unit with the given header byte and RBSP, inserting
+/// `emulation_prevention_three_byte`s as described in ISO/IEC 14496-10 section 7.4.1.
+fn nal(header: u8, rbsp: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + rbsp.len() + rbsp.len() / 64);
+    out.push(header);
+    let mut zeros = 0;
+    for &b in rbsp {
+        if zeros >= 2 && b <= 3 {
+            out.push(3);
+            zeros = 0;
+        }
+        out.push(b);
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+    }
+    out
+}
+
+fn append_start_code(out: &mut Vec<u8>, nal: &[u8]) {
+    out.extend_from_slice(&[0, 0, 0, 1]);
+    out.extend_from_slice(nal);
+}
+
+/// Writes the bit-oriented syntax of ISO/IEC 14496-10 section 7.2, most significant bit first.
+struct BitWriter {
+    buf: Vec<u8>,
+    cur: u8,
+    bits: u32,  // the number of bits used in cur.
+}
+
+impl BitWriter {
+    fn new() -> Self { BitWriter { buf: Vec::new(), cur: 0, bits: 0 } }
+
+    /// Writes the low `n` bits of `v`, as the `u(n)` descriptor.
+    fn u(&mut self, n: u32, v: u32) {
+        for i in (0 .. n).rev() {
+            self.cur = (self.cur << 1) | ((v >> i) & 1) as u8;
+            self.bits += 1;
+            if self.bits == 8 {
+                self.buf.push(self.cur);
+                self.cur = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    /// Writes an unsigned Exp-Golomb code, as the `ue(v)` descriptor (section 9.1).
+    fn ue(&mut self, v: u32) {
+        let v = v as u64 + 1;
+        let len = 64 - v.leading_zeros();
+        self.u(len - 1, 0);
+        self.u(len, v as u32);
+    }
+
+    /// Writes a signed Exp-Golomb code, as the `se(v)` descriptor (section 9.1.1).
+    fn se(&mut self, v: i32) {
+        self.ue(if v > 0 { 2 * v as u32 - 1 } else { 2 * (-v) as u32 });
+    }
+
+    /// Pads with zero bits to a byte boundary.
+    fn align(&mut self) {
+        if self.bits > 0 {
+            let n = 8 - self.bits;
+            self.u(n, 0);
+        }
+    }
+
+    /// Writes whole bytes. Must be byte-aligned.
+    fn bytes(&mut self, b: &[u8]) {
+        debug_assert_eq!(self.bits, 0);
+        self.buf.extend_from_slice(b);
+    }
+
+    /// Writes `rbsp_trailing_bits()`.
+    fn trailing_bits(&mut self) {
+        self.u(1, 1);
+        self.align();
+    }
+
+    fn finish(self) -> Vec<u8> {
+        debug_assert_eq!(self.bits, 0);
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::testutil;
+    use h264;
+    use super::*;
+
+    #[test]
+    fn exp_golomb() {
+        let mut w = BitWriter::new();
+        w.ue(0);   // 1
+        w.ue(1);   // 010
+        w.ue(2);   // 011
+        w.ue(3);   // 00100
+        w.se(-1);  // 011
+        w.se(1);   // 010
+        w.trailing_bits();
+        assert_eq!(w.finish(), &[0b1010_0110, 0b0100_0110, 0b1010_0000]);
+    }
+
+    #[test]
+    fn emulation_prevention() {
+        assert_eq!(nal(0x65, &[0, 0, 1, 0, 0, 0, 0x80]),
+                   &[0x65, 0, 0, 3, 1, 0, 0, 3, 0, 0x80]);
+    }
+
+    #[test]
+    fn extra_data() {
+        testutil::init();
+        let s = Source::new(Config::default()).unwrap();
+        let e = h264::ExtraData::parse(&s.extra_data(), 320, 240).unwrap();
+        assert_eq!(e.rfc6381_codec, "avc1.42c01e");
+        assert!(e.need_transform);
+    }
+
+    #[test]
+    fn frames() {
+        testutil::init();
+        let mut s = Source::new(Config {
+            width: 64,
+            height: 32,
+            frames_per_sec: 30,
+            gop_frames: 3,
+            bits_per_sec: 0,
+        }).unwrap();
+        let frames: Vec<Frame> = s.by_ref().take(4).collect();
+        assert_eq!(frames.iter().map(|f| (f.pts_90k, f.is_key)).collect::<Vec<_>>(),
+                   &[(0, true), (3000, false), (6000, false), (9000, true)]);
+
+        // An IDR frame codes all 8 macroblocks; a P frame codes the 6 of the old and new box.
+        assert!(frames[0].data.len() > 8 * 384);
+        assert!(frames[1].data.len() > 6 * 384 && frames[1].data.len() < 7 * 384);
+        assert!(Source::new(Config { width: 65, ..Config::default() }).is_err());
+    }
+
+    #[test]
+    fn padding() {
+        testutil::init();
+        let mut s = Source::new(Config {
+            width: 64,
+            height: 32,
+            frames_per_sec: 10,
+            gop_frames: 10,
+            bits_per_sec: 800_000,
+        }).unwrap();
+        for f in s.by_ref().take(3) {
+            assert_eq!(f.data.len(), 10_000);
+        }
+    }
+}
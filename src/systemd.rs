@@ -0,0 +1,160 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! systemd service notifications, as in `sd_notify(3)`.
+//!
+//! With `Type=notify`, systemd considers the service started only once it reports `READY=1`. With
+//! `WatchdogSec=`, it restarts the service if `WATCHDOG=1` isn't sent often enough. The watchdog
+//! thread takes the database lock before each ping, so a deadlock stops the pings and gets the
+//! process restarted rather than silently not recording.
+
+use db;
+use failure::Error;
+use std::env;
+use std::io;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Sends `state` (such as `READY=1`) to systemd. Does nothing if not run by systemd with a
+/// notification socket.
+pub fn notify(state: &str) -> Result<(), io::Error> {
+    match env::var("NOTIFY_SOCKET") {
+        Ok(s) => send(&s, state),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(socket: &str, msg: &str) -> Result<(), io::Error> {
+    use libc;
+    use std::mem;
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let path = socket.as_bytes();
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("bad NOTIFY_SOCKET {:?}", socket)));
+    }
+    for (i, &b) in path.iter().enumerate() {
+        addr.sun_path[i] = b as libc::c_char;
+    }
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;  // abstract namespace.
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len();
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe {
+        libc::sendto(fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0,
+                     &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                     len as libc::socklen_t)
+    };
+    let e = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if ret < 0 {
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// systemd is Linux-only.
+#[cfg(not(target_os = "linux"))]
+fn send(_socket: &str, _msg: &str) -> Result<(), io::Error> { Ok(()) }
+
+/// Returns the watchdog timeout from `WATCHDOG_USEC`, if it's set and meant for this process
+/// (per `WATCHDOG_PID`).
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, my_pid: u32) -> Option<Duration> {
+    if let Some(p) = pid {
+        if p.parse::<u32>().ok() != Some(my_pid) {
+            return None;
+        }
+    }
+    match usec.and_then(|u| u.parse::<u64>().ok()) {
+        Some(u) if u > 0 => Some(Duration::from_micros(u)),
+        _ => None,
+    }
+}
+
+/// Starts a thread to ping systemd's watchdog at half its timeout, if it's enabled.
+pub fn start_watchdog(db: Arc<db::Database>) -> Result<(), Error> {
+    let usec = env::var("WATCHDOG_USEC").ok();
+    let pid = env::var("WATCHDOG_PID").ok();
+    let timeout = match parse_watchdog(usec.as_ref().map(String::as_str),
+                                       pid.as_ref().map(String::as_str), process::id()) {
+        None => return Ok(()),
+        Some(t) => t,
+    };
+    info!("Pinging systemd watchdog every {:?}", timeout / 2);
+    thread::Builder::new()
+        .name("watchdog".to_owned())
+        .spawn(move || loop {
+            drop(db.lock());
+            if let Err(e) = notify("WATCHDOG=1") {
+                warn!("unable to ping systemd watchdog: {}", e);
+            }
+            thread::sleep(timeout / 2);
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn parse_watchdog() {
+        use super::parse_watchdog;
+        assert_eq!(parse_watchdog(Some("30000000"), None, 42), Some(Duration::from_secs(30)));
+        assert_eq!(parse_watchdog(Some("30000000"), Some("42"), 42),
+                   Some(Duration::from_secs(30)));
+        assert_eq!(parse_watchdog(Some("30000000"), Some("43"), 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog(Some("x"), None, 42), None);
+        assert_eq!(parse_watchdog(None, None, 42), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn send() {
+        use std::os::unix::net::UnixDatagram;
+        use tempdir::TempDir;
+        let tmpdir = TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().join("notify");
+        let sock = UnixDatagram::bind(&path).unwrap();
+        super::send(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0u8; 16];
+        let n = sock.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
@@ -0,0 +1,397 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tail mode for `/api/cameras/<uuid>/<stream>/view.m4s?tail=true`, as described in
+//! `design/api.md`.
+//!
+//! The response is a sequence of media segments (`moof`+`mdat` pairs) of a single recording,
+//! emitted as it grows. Each ends just before the recording's latest key frame, so that the next
+//! one starts with a key frame and no frame is sent twice.
+//!
+//! In `Latency::Low` mode, the tail instead starts at the recording's latest key frame and each
+//! segment includes all frames written so far, so later segments generally begin partway
+//! through a GOP. This trades robustness (a client which drops a segment can't decode until the
+//! next key frame) and per-segment overhead for less delay.
+//!
+//! All viewers of a recording in the same mode share a single producer thread, which builds and
+//! reads each segment once and sends it to every subscriber; many displays of the same camera
+//! cost little more than one. A viewer joining partway first gets a catch-up segment from its
+//! requested start (or, in low-latency mode, the latest key frame) to the producer's current
+//! position.
+
+use body::{BodyStream, BoxedError, Chunk};
+use db::{self, recording};
+use failure::Error;
+use fnv::FnvHashMap;
+use futures::{Future, Stream};
+use futures::sync::mpsc;
+use http_serve::Entity;
+use mp4;
+use parking_lot::Mutex;
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use web::StreamDirs;
+
+/// How often to check the recording for new frames.
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// How often to check the recording for new frames in `Latency::Low` mode.
+const LOW_LATENCY_POLL_INTERVAL_MS: u64 = 100;
+
+/// How long to wait for the recording to grow before giving up on it. (If its stream fails,
+/// the recording is never marked complete.)
+const IDLE_TIMEOUT_SEC: u64 = 60;
+
+/// The number of segments buffered for each subscriber. A subscriber which falls further behind
+/// (a client reading more slowly than real time) is disconnected rather than holding back the
+/// others.
+const SUBSCRIBER_BUFFER_SEGMENTS: usize = 16;
+
+/// The tradeoff between delay and robustness of a tail.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Latency {
+    /// Start at the requested time and send whole GOPs, each segment beginning with a key frame.
+    Smooth,
+
+    /// Start at the latest key frame, skipping any earlier frames, and send frames as soon as
+    /// they're written.
+    Low,
+}
+
+impl Latency {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "smooth" => Some(Latency::Smooth),
+            "low" => Some(Latency::Low),
+            _ => None,
+        }
+    }
+
+    fn poll_interval(self) -> Duration {
+        Duration::from_millis(match self {
+            Latency::Smooth => POLL_INTERVAL_MS,
+            Latency::Low => LOW_LATENCY_POLL_INTERVAL_MS,
+        })
+    }
+}
+
+/// The recording to tail.
+pub struct Params {
+    pub stream_id: i32,
+    pub recording_id: i32,
+    pub open_id: Option<u32>,
+
+    /// The desired start, relative to the start of the recording. The first segment begins with
+    /// the key frame at or before this time, or in `Latency::Low` mode, the latest key frame if
+    /// that's later.
+    pub start_90k: i32,
+
+    pub latency: Latency,
+}
+
+/// The number of viewers of a tailed recording, as returned by `Hub::viewers`.
+pub struct Viewers {
+    pub stream_id: i32,
+    pub recording_id: i32,
+    pub viewers: usize,
+}
+
+/// The state of a recording's tail, shared between its producer and subscribers.
+struct Shared {
+    /// The end of the last segment sent, relative to the start of the recording.
+    cur: i32,
+    subscribers: Vec<mpsc::Sender<Chunk>>,
+}
+
+/// The tails of all recordings currently being viewed.
+pub struct Hub {
+    db: Arc<db::Database>,
+    dirs: Arc<StreamDirs>,
+    tails: Mutex<FnvHashMap<(db::CompositeId, Latency), Shared>>,
+}
+
+impl Hub {
+    pub fn new(db: Arc<db::Database>, dirs: Arc<StreamDirs>) -> Arc<Self> {
+        Arc::new(Hub {
+            db,
+            dirs,
+            tails: Mutex::new(FnvHashMap::default()),
+        })
+    }
+
+    /// Starts tailing the given recording, returning a body of concatenated media segments.
+    /// Ends once the recording is complete and fully sent.
+    pub fn subscribe(hub: &Arc<Self>, p: Params) -> Result<BodyStream, Error> {
+        let id = db::CompositeId::new(p.stream_id, p.recording_id);
+        let key = (id, p.latency);
+        let start_90k = {
+            let l = hub.db.lock();
+            if let Some(o) = p.open_id {
+                let row = get_row(&l, id)?;
+                if row.open_id != o {
+                    bail!("recording {} has open id {}, requested {}", id, row.open_id, o);
+                }
+            }
+            match p.latency {
+                Latency::Smooth => p.start_90k,
+                Latency::Low => last_key_frame(&l, id, p.start_90k)?,
+            }
+        };
+        let (mut tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER_SEGMENTS);
+        let mut tails = hub.tails.lock();
+        let start_producer = !tails.contains_key(&key);
+        if start_producer {
+            tails.insert(key, Shared {
+                cur: start_90k,
+                subscribers: vec![tx],
+            });
+        } else {
+            // A low-latency tail's position may be partway through a GOP; catch up from the key
+            // frame before it regardless of the requested start.
+            let mut from = match p.latency {
+                Latency::Smooth => start_90k,
+                Latency::Low => {
+                    let l = hub.db.lock();
+                    key_frame_at_or_before(&l, id, tails[&key].cur)?
+                },
+            };
+
+            // Read catch-up segments without the lock, repeating until the producer hasn't sent
+            // anything in the meantime, so that its next segment follows the last of them. If the
+            // tail finishes in the meantime, catch up through the end of the recording instead.
+            let mut continuation = false;
+            loop {
+                let (to, ended) = match tails.get_mut(&key) {
+                    None => (get_row(&hub.db.lock(), id)?.duration_90k, true),
+                    Some(s) => {
+                        if from >= s.cur {
+                            s.subscribers.push(tx);
+                            break;
+                        }
+                        (s.cur, false)
+                    },
+                };
+                if from < to {
+                    drop(tails);
+                    let seg = {
+                        let l = hub.db.lock();
+                        prepare(&l, id, from .. to, continuation)?
+                    };
+                    let seg = read(&hub.db, &hub.dirs, seg)?;
+                    if tx.try_send(seg.into()).is_err() {
+                        bail!("tail of recording {}: unable to catch up", id);
+                    }
+                    from = to;
+                    continuation = p.latency == Latency::Low;
+                    tails = hub.tails.lock();
+                }
+                if ended {
+                    break;  // dropping tx ends the body.
+                }
+            }
+        }
+        drop(tails);
+        if start_producer {
+            let hub = hub.clone();
+            let r = thread::Builder::new()
+                .name(format!("tail-{}-{}", p.stream_id, p.recording_id))
+                .spawn(move || {
+                    if let Err(e) = hub.produce(key) {
+                        warn!("tail of recording {} failed: {}", id, e);
+
+                        // Dropping the subscribers' senders ends their bodies.
+                        hub.tails.lock().remove(&key);
+                    }
+                });
+            if let Err(e) = r {
+                hub.tails.lock().remove(&key);
+                return Err(e.into());
+            }
+        }
+        Ok(Box::new(rx.map_err(|()| -> BoxedError { unreachable!() })))
+    }
+
+    /// Returns the number of viewers of each recording being tailed, in any mode. Viewers which
+    /// have gone away are counted until the next segment is sent.
+    pub fn viewers(&self) -> Vec<Viewers> {
+        let mut viewers: Vec<Viewers> = Vec::new();
+        for (&(id, _), s) in self.tails.lock().iter() {
+            match viewers.iter_mut().find(|v| v.stream_id == id.stream() &&
+                                              v.recording_id == id.recording()) {
+                Some(v) => v.viewers += s.subscribers.len(),
+                None => viewers.push(Viewers {
+                    stream_id: id.stream(),
+                    recording_id: id.recording(),
+                    viewers: s.subscribers.len(),
+                }),
+            }
+        }
+        viewers
+    }
+
+    /// Produces segments of the given recording until it's complete or has no subscribers, then
+    /// removes its tail. On error, the caller must remove the tail.
+    fn produce(&self, key: (db::CompositeId, Latency)) -> Result<(), Error> {
+        let (id, latency) = key;
+        let mut last_growth = Instant::now();
+
+        // The first segment always begins with a key frame; in low-latency mode, later ones
+        // continue exactly where the previous one ended.
+        let mut first = true;
+        loop {
+            let cur = self.tails.lock().get(&key).expect("only the producer removes its tail").cur;
+            let (seg, growing) = {
+                let l = self.db.lock();
+                let row = get_row(&l, id)?;
+                let growing = (row.flags & (db::RecordingFlags::Uncommitted as i32 |
+                                            db::RecordingFlags::Growing as i32)) != 0;
+                let end = match latency {
+                    Latency::Smooth if growing => last_key_frame(&l, id, cur)?,
+                    _ => row.duration_90k,
+                };
+                if end > cur {
+                    let continuation = latency == Latency::Low && !first;
+                    (Some((prepare(&l, id, cur .. end, continuation)?, end)), growing)
+                } else {
+                    (None, growing)
+                }
+            };
+            let seg = match seg {
+                None => None,
+                Some((seg, end)) => {
+                    first = false;
+                    Some((read(&self.db, &self.dirs, seg)?, end))
+                },
+            };
+
+            // Send under the lock, so that a new subscriber gets either this segment or a
+            // catch-up segment which includes it, and decide whether to finish under the same
+            // lock, so that none subscribes to a tail which is about to be removed.
+            let mut tails = self.tails.lock();
+            let done = {
+                let s = tails.get_mut(&key).expect("only the producer removes its tail");
+                if let Some((seg, end)) = seg {
+                    last_growth = Instant::now();
+                    s.cur = end;
+                    let mut i = 0;
+                    while i < s.subscribers.len() {
+                        match s.subscribers[i].try_send(seg.clone().into()) {
+                            Ok(()) => { i += 1; continue; },
+                            Err(ref e) if e.is_full() => {
+                                info!("tail of recording {}: disconnecting slow viewer", id);
+                            },
+                            Err(_) => {},  // client went away.
+                        }
+                        s.subscribers.swap_remove(i);
+                    }
+                }
+                s.subscribers.is_empty() || !growing
+            };
+            if done {
+                tails.remove(&key);
+                return Ok(());
+            }
+            drop(tails);
+            if last_growth.elapsed() > Duration::from_secs(IDLE_TIMEOUT_SEC) {
+                bail!("recording {} hasn't grown in {} seconds", id, IDLE_TIMEOUT_SEC);
+            }
+            thread::sleep(latency.poll_interval());
+        }
+    }
+}
+
+fn get_row(l: &db::LockedDatabase, id: db::CompositeId) -> Result<db::ListRecordingsRow, Error> {
+    let mut row = None;
+    l.list_recordings_by_id(id.stream(), id.recording() .. id.recording() + 1,
+                            &mut |r| { row = Some(r); Ok(()) })?;
+    row.ok_or_else(|| format_err!("no such recording {}", id))
+}
+
+/// Prepares a media segment of the given portion of a recording. If `continuation`, it begins
+/// exactly at `range.start` rather than at the key frame at or before it.
+fn prepare(l: &db::LockedDatabase, id: db::CompositeId, range: Range<i32>, continuation: bool)
+           -> Result<mp4::FileBuilder, Error> {
+    let mut builder = mp4::FileBuilder::new(mp4::Type::MediaSegment);
+    let row = get_row(l, id)?;
+    if continuation {
+        builder.append_continuation(l, row, range)?;
+    } else {
+        builder.append(l, row, range)?;
+    }
+    Ok(builder)
+}
+
+/// Builds and reads a prepared media segment into memory. Must be called without the database
+/// lock held.
+fn read(db: &Arc<db::Database>, dirs: &StreamDirs, builder: mp4::FileBuilder)
+        -> Result<Vec<u8>, Error> {
+    let mp4 = builder.build(db.clone(), dirs.get()?)?;
+    let mut seg = Vec::with_capacity(mp4.len() as usize);
+    for c in mp4.get_range(0 .. mp4.len()).wait() {
+        let c = c.map_err(|e| format_err!("unable to read segment: {}", e))?;
+        seg.extend_from_slice(::bytes::Buf::bytes(&c));
+    }
+    Ok(seg)
+}
+
+/// Returns the start of the last key frame at or before `t` in the given recording.
+fn key_frame_at_or_before(db: &db::LockedDatabase, id: db::CompositeId, t: i32)
+                          -> Result<i32, Error> {
+    db.with_recording_playback(id, &mut |playback| {
+        let data = &(&playback).video_index;
+        let mut it = recording::SampleIndexIterator::new();
+        let mut k = 0;
+        while it.next(data)? && it.start_90k <= t {
+            if it.is_key() {
+                k = it.start_90k;
+            }
+        }
+        Ok(k)
+    })
+}
+
+/// Returns the start of the last key frame after `after` in the given recording, or `after` if
+/// there is none.
+fn last_key_frame(db: &db::LockedDatabase, id: db::CompositeId, after: i32)
+                  -> Result<i32, Error> {
+    db.with_recording_playback(id, &mut |playback| {
+        let data = &(&playback).video_index;
+        let mut it = recording::SampleIndexIterator::new();
+        let mut k = after;
+        while it.next(data)? {
+            if it.is_key() && it.start_90k > k {
+                k = it.start_90k;
+            }
+        }
+        Ok(k)
+    })
+}
@@ -0,0 +1,251 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Deletion of a camera along with its recordings, events, and sample files, via `deleteCamera`
+//! jobs (see `jobs`) created by `DELETE /api/cameras/<uuid>/`.
+//!
+//! The job first pauses the camera's streams and waits for the recordings in progress to be
+//! committed, then removes everything from the database in a single transaction with
+//! `LockedDatabase::teardown_camera`. The sample files are unlinked afterward; the job's progress
+//! reports how many remain. The pausing and unlinking steps are shared with `relocate`.
+
+use db::{self, CompositeId};
+use db::writer;
+use failure::Error;
+use fnv::FnvHashSet;
+use jobs;
+use serde_json;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use time;
+use uuid::Uuid;
+
+/// How long to wait for streams to finish their recordings in progress.
+const SETTLE_TIMEOUT_SEC: i64 = 300;
+
+/// How long to wait for the directories' syncers to unlink the sample files.
+const UNLINK_TIMEOUT_SEC: i64 = 600;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct Params {
+    pub camera: Uuid,
+
+    /// The user who requested the deletion, for the audit log.
+    pub user: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+struct Progress {
+    stage: &'static str,
+    sample_files: usize,
+    sample_files_unlinked: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+struct Summary {
+    streams: usize,
+    recordings: usize,
+    events: usize,
+    sample_files: usize,
+    sample_files_pending: usize,
+}
+
+/// Returns the sample file directories which `cmds::run` starts syncers for: those of streams
+/// which record. Their syncers unlink garbage after each flush; the others' garbage must be
+/// collected with `writer::collect_garbage`.
+pub fn syncer_dirs(db: &db::Database) -> FnvHashSet<i32> {
+    db.lock().streams_by_id().values()
+             .filter(|s| s.record)
+             .filter_map(|s| s.sample_file_dir_id)
+             .collect()
+}
+
+/// Pauses the given streams and waits until they're settled (see `db::Stream::is_settled`),
+/// flushing as soon as their recordings are synced rather than waiting for the syncer to.
+pub fn settle(db: &db::Database, streams: &[i32], cancel: &AtomicBool) -> Result<(), Error> {
+    let deadline = time::get_time().sec + SETTLE_TIMEOUT_SEC;
+    loop {
+        {
+            let mut l = db.lock();
+            for &id in streams {
+                l.set_stream_paused(id, true)?;
+            }
+            if poll_settled(&mut l, streams)? {
+                return Ok(());
+            }
+        }
+        if cancel.load(Ordering::SeqCst) {
+            bail!("cancelled");
+        }
+        if time::get_time().sec >= deadline {
+            bail!("streams didn't stop recording within {} sec", SETTLE_TIMEOUT_SEC);
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Returns true if the given streams, which should no longer be recording, are settled (see
+/// `db::Stream::is_settled`). Otherwise flushes if that would commit their remaining recordings.
+/// Streams which no longer exist are ignored.
+pub fn poll_settled(l: &mut db::LockedDatabase, streams: &[i32]) -> Result<bool, Error> {
+    let mut settled = true;
+    let mut synced = true;
+    for id in streams {
+        if let Some(s) = l.streams_by_id().get(id) {
+            settled &= s.is_settled();
+            synced &= s.is_synced();
+        }
+    }
+    if !settled && synced {
+        l.flush("settling streams")?;
+    }
+    Ok(settled)
+}
+
+/// Unpauses the given streams, which `settle` paused, if they still exist.
+pub fn resume(db: &db::Database, streams: &[i32]) -> Result<(), Error> {
+    let mut l = db.lock();
+    for &id in streams {
+        if l.streams_by_id().contains_key(&id) {
+            l.set_stream_paused(id, false)?;
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the given `(sample_file_dir_id, id)` garbage files to be unlinked, collecting the
+/// garbage of directories without syncers directly. While waiting, `progress` is called with the
+/// number unlinked so far. Returns the number still not unlinked after `UNLINK_TIMEOUT_SEC`;
+/// these remain in the garbage table for a later syncer.
+pub fn unlink(db: &db::Database, syncer_dirs: &FnvHashSet<i32>, files: &[(i32, CompositeId)],
+              progress: &mut FnMut(usize) -> Result<(), Error>) -> Result<usize, Error> {
+    let mut dirs: Vec<i32> = files.iter().map(|&(d, _)| d).collect();
+    dirs.sort();
+    dirs.dedup();
+    for &d in &dirs {
+        if !syncer_dirs.contains(&d) {
+            writer::collect_garbage(db, d)?;
+        }
+    }
+    let deadline = time::get_time().sec + UNLINK_TIMEOUT_SEC;
+    let mut pending = files.to_vec();
+    loop {
+        {
+            let l = db.lock();
+            pending.retain(|&(d, id)| {
+                l.sample_file_dirs_by_id().get(&d).map(|d| d.needs_unlink(id)).unwrap_or(false)
+            });
+        }
+        if pending.is_empty() || time::get_time().sec >= deadline {
+            return Ok(pending.len());
+        }
+        progress(files.len() - pending.len())?;
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+pub struct CameraDeleter {
+    db: Arc<db::Database>,
+
+    /// See `syncer_dirs`.
+    syncer_dirs: FnvHashSet<i32>,
+}
+
+impl CameraDeleter {
+    pub fn new(db: Arc<db::Database>) -> Self {
+        let syncer_dirs = syncer_dirs(&db);
+        CameraDeleter { db, syncer_dirs }
+    }
+}
+
+impl jobs::Handler for CameraDeleter {
+    fn run(&self, job: &db::Job, cancel: &AtomicBool) -> Result<String, Error> {
+        let p: Params = serde_json::from_str(&job.params)?;
+        let (camera_id, short_name, to_pause) = {
+            let l = self.db.lock();
+            let c = l.get_camera(p.camera)
+                     .ok_or_else(|| format_err!("no such camera {}", p.camera))?;
+            let to_pause: Vec<i32> = c.streams.iter()
+                                      .filter_map(|s| *s)
+                                      .filter(|id| !l.streams_by_id()[id].paused)
+                                      .collect();
+            (c.id, c.short_name.clone(), to_pause)
+        };
+        jobs::set_progress(&self.db, job, &Progress {
+            stage: "pausing",
+            sample_files: 0,
+            sample_files_unlinked: 0,
+        })?;
+        let streams: Vec<i32> = self.db.lock().streams_by_id().iter()
+                                    .filter(|&(_, s)| s.camera_id == camera_id)
+                                    .map(|(&id, _)| id)
+                                    .collect();
+        let removed = settle(&self.db, &streams, cancel).and_then(|()| {
+            let mut l = self.db.lock();
+            let removed = l.teardown_camera(camera_id)?;
+            l.flush("camera teardown")?;  // notifies the syncers of the garbage.
+            Ok(removed)
+        });
+        let removed = match removed {
+            Ok(r) => r,
+            Err(e) => {
+                resume(&self.db, &to_pause)?;
+                return Err(e);
+            },
+        };
+        info!(target: "audit", "{} deleted camera {} ({}): {} streams, {} recordings, {} events",
+              p.user, short_name, p.camera, removed.streams, removed.recordings, removed.events);
+
+        let total = removed.sample_files.len();
+        let pending = unlink(&self.db, &self.syncer_dirs, &removed.sample_files, &mut |n| {
+            jobs::set_progress(&self.db, job, &Progress {
+                stage: "unlinking",
+                sample_files: total,
+                sample_files_unlinked: n,
+            })
+        })?;
+        if pending > 0 {
+            warn!("camera {}: {} sample files not yet unlinked; they remain in the garbage table",
+                  short_name, pending);
+        }
+        Ok(serde_json::to_string(&Summary {
+            streams: removed.streams,
+            recordings: removed.recordings,
+            events: removed.events,
+            sample_files: total,
+            sample_files_pending: pending,
+        })?)
+    }
+}
@@ -0,0 +1,132 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Thumbnails: small JPEGs of a stream's key frames, stored in the database while recording (at
+//! intervals of the stream's `thumbnail_interval_sec`). They're served as
+//! `/api/cameras/<uuid>/<stream>/thumbnails` and `.../thumbnail.jpg` for storyboards and fast
+//! scrubbing, without reading or decoding sample files.
+//!
+//! Streamers hand key frames (in Annex B format, with the stream's parameter sets) to a single
+//! background thread, which decodes and scales each with the external `ffmpeg` binary also used
+//! to take snapshots. Frames are dropped rather than delaying recording if that thread falls
+//! behind.
+
+use base::sched;
+use db::{self, recording};
+use failure::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, mpsc};
+use std::thread;
+
+/// The width of stored thumbnails, in pixels. The height preserves the aspect ratio.
+const WIDTH: u32 = 320;
+
+/// The number of key frames which may be waiting to be decoded before new ones are dropped.
+const QUEUE_LEN: usize = 16;
+
+struct Request {
+    stream_id: i32,
+    time: recording::Time,
+    annex_b: Vec<u8>,
+}
+
+/// A handle for submitting key frames to the thumbnail thread.
+#[derive(Clone)]
+pub struct Sender(mpsc::SyncSender<Request>);
+
+impl Sender {
+    /// Submits a key frame (as returned by `h264::to_annex_b`) seen on the given stream at the
+    /// given time. Never blocks; drops the frame if the thread is busy.
+    pub fn send(&self, stream_id: i32, time: recording::Time, annex_b: Vec<u8>) {
+        let r = Request { stream_id, time, annex_b };
+        if let Err(mpsc::TrySendError::Full(_)) = self.0.try_send(r) {
+            debug!("stream {}: thumbnail queue full; dropping frame at {}", stream_id, time);
+        }
+    }
+}
+
+/// Starts the thumbnail thread, which runs until all `Sender`s are dropped.
+pub fn start(db: Arc<db::Database>, ffmpeg: PathBuf) -> Result<Sender, Error> {
+    let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+    thread::Builder::new()
+        .name("thumbnails".to_owned())
+        .spawn(move || {
+            sched::enter(sched::Class::Web);
+            for r in rx {
+                let res = decode(&ffmpeg, r.annex_b)
+                    .and_then(|jpeg| db.lock().add_thumbnail(r.stream_id, r.time, &jpeg));
+                if let Err(e) = res {
+                    warn!("stream {}: unable to store thumbnail at {}: {}", r.stream_id, r.time, e);
+                }
+            }
+        })?;
+    Ok(Sender(tx))
+}
+
+/// Returns the ffmpeg arguments to decode a single H.264 frame from stdin and write it to stdout
+/// as a scaled JPEG.
+fn args(scale: &str) -> Vec<&str> {
+    vec!["-nostdin", "-loglevel", "error", "-f", "h264", "-i", "pipe:0", "-frames:v", "1",
+         "-vf", scale, "-q:v", "5", "-f", "image2pipe", "-c:v", "mjpeg", "pipe:1"]
+}
+
+/// Decodes the given Annex B key frame to a thumbnail-sized JPEG.
+fn decode(ffmpeg: &Path, annex_b: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let scale = format!("scale={}:-2", WIDTH);
+    let mut child = Command::new(ffmpeg)
+        .args(&args(&scale))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // As in annotate.rs, write from another thread so ffmpeg can't deadlock on a full pipe.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&annex_b));
+    let out = child.wait_with_output()?;
+    let _ = writer.join();
+    if !out.status.success() || out.stdout.is_empty() {
+        bail!("ffmpeg failed with {}: {}", out.status, String::from_utf8_lossy(&out.stderr).trim());
+    }
+    Ok(out.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_args() {
+        let a = super::args("scale=320:-2");
+        let i = a.iter().position(|&a| a == "-i").unwrap();
+        assert_eq!(&a[i - 2 .. i + 2], &["-f", "h264", "-i", "pipe:0"]);
+        assert_eq!(&a[a.len() - 5 ..], &["-f", "image2pipe", "-c:v", "mjpeg", "pipe:1"]);
+    }
+}
@@ -0,0 +1,178 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Scheduled snapshots of cameras (see `db::SnapshotSchedule`), a cheap alternative to video for
+//! seeing how a scene changes over days or seasons. They're served as
+//! `/api/cameras/<uuid>/snapshots` and `.../snapshot.jpg`.
+//!
+//! A single thread takes each due snapshot in turn, from the camera's main stream (or its sub
+//! stream if it has no main stream) as in `snapshot`. Snapshots are stored at their scheduled
+//! times rather than the times they were actually taken, so that (say) the noon snapshots of
+//! each day line up. Once an hour, snapshots older than each camera's retention are deleted.
+//! Snapshots of a camera whose schedule has been removed are kept until the camera is deleted.
+
+use base::clock::Clocks;
+use base::sched;
+use db::{self, recording};
+use failure::Error;
+use fnv::FnvHashMap;
+use snapshot::Source;
+use std::cmp;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use time;
+
+/// The longest to sleep between checks, so that schedule changes are noticed promptly.
+const MAX_SLEEP_SEC: i64 = 60;
+
+/// How often to delete expired snapshots.
+const PRUNE_INTERVAL_SEC: i64 = 3600;
+
+/// Returns the first time after `now_sec` which is a multiple of `interval_sec` in local time
+/// (at the given offset from UTC). For intervals which divide a day, this gives the same times
+/// of day each day.
+fn next_due(now_sec: i64, utcoff_sec: i64, interval_sec: i64) -> i64 {
+    let into = ((now_sec + utcoff_sec) % interval_sec + interval_sec) % interval_sec;
+    now_sec - into + interval_sec
+}
+
+/// A snapshot to take.
+struct Due {
+    camera_id: i32,
+    short_name: String,
+    time_sec: i64,
+    source: Source,
+}
+
+/// Starts the scheduled snapshot thread. `ffmpeg` is used for streams without a `snapshot_url`;
+/// if it's `None`, those cameras' snapshots fail.
+pub fn start(db: Arc<db::Database>, ffmpeg: Option<PathBuf>) -> Result<(), Error> {
+    thread::Builder::new()
+        .name("timelapse".to_owned())
+        .spawn(move || {
+            sched::enter(sched::Class::Web);
+            let clocks = db.clocks();
+
+            // Camera id -> (interval, next due time).
+            let mut next: FnvHashMap<i32, (i64, i64)> = FnvHashMap::default();
+            let mut next_prune = 0;
+            loop {
+                let now = clocks.realtime().sec;
+                let utcoff = time::at(time::Timespec::new(now, 0)).tm_utcoff as i64;
+                let mut due = Vec::new();
+                let mut sleep = MAX_SLEEP_SEC;
+                {
+                    let l = db.lock();
+                    next.retain(|id, _| l.cameras_by_id().get(id)
+                                         .map(|c| c.snapshot_schedule.is_some())
+                                         .unwrap_or(false));
+                    for c in l.cameras_by_id().values() {
+                        let s = match c.snapshot_schedule {
+                            None => continue,
+                            Some(s) => s,
+                        };
+                        let n = next.entry(c.id).or_insert((0, 0));
+                        if n.0 != s.interval_sec {
+                            *n = (s.interval_sec, next_due(now, utcoff, s.interval_sec));
+                        }
+                        if n.1 <= now {
+                            let stream = c.streams[db::StreamType::MAIN.index()]
+                                          .or(c.streams[db::StreamType::SUB.index()])
+                                          .and_then(|id| l.streams_by_id().get(&id));
+                            match stream {
+                                None => warn!("{}: no stream for scheduled snapshot",
+                                              c.short_name),
+                                Some(stream) => due.push(Due {
+                                    camera_id: c.id,
+                                    short_name: c.short_name.clone(),
+                                    time_sec: n.1,
+                                    source: Source::new(c, stream),
+                                }),
+                            }
+                            n.1 = next_due(now, utcoff, s.interval_sec);
+                        }
+                        sleep = cmp::min(sleep, n.1 - now);
+                    }
+                }
+                for d in due {
+                    let time = recording::Time(d.time_sec * recording::TIME_UNITS_PER_SEC);
+                    let r = d.source.take(ffmpeg.as_ref())
+                             .and_then(|jpeg| db.lock().add_scheduled_snapshot(d.camera_id, time,
+                                                                                &jpeg));
+                    if let Err(e) = r {
+                        warn!("{}: unable to take scheduled snapshot: {}", d.short_name, e);
+                    }
+                }
+                if now >= next_prune {
+                    prune(&db, now);
+                    next_prune = now + PRUNE_INTERVAL_SEC;
+                }
+                clocks.sleep(time::Duration::seconds(cmp::max(sleep, 1)));
+            }
+        })?;
+    Ok(())
+}
+
+/// Deletes snapshots older than their cameras' retention.
+fn prune(db: &db::Database, now_sec: i64) {
+    let mut l = db.lock();
+    let cameras: Vec<_> = l.cameras_by_id().values()
+                           .filter_map(|c| c.snapshot_schedule.map(|s| (c.id, s.retain_days)))
+                           .collect();
+    for (id, retain_days) in cameras {
+        let before = recording::Time((now_sec - retain_days * 86400) *
+                                     recording::TIME_UNITS_PER_SEC);
+        match l.delete_scheduled_snapshots(id, before) {
+            Ok(0) => {},
+            Ok(n) => debug!("camera {}: deleted {} expired scheduled snapshots", id, n),
+            Err(e) => warn!("camera {}: unable to delete expired scheduled snapshots: {}", id, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::next_due;
+
+    #[test]
+    fn test_next_due() {
+        // 2017-07-14 02:40:00 UTC, or 2017-07-13 19:40:00 PDT.
+        let now = 1500000000;
+        assert_eq!(next_due(now, 0, 300), now + 300);
+        assert_eq!(next_due(now + 1, 0, 300), now + 300);
+        assert_eq!(next_due(now - 1, 0, 300), now);
+
+        // Midnight each day and the top of each hour, local time.
+        let pdt = -7 * 3600;
+        assert_eq!(next_due(now, pdt, 86400), now + (4 * 3600 + 20 * 60));
+        assert_eq!(next_due(now, pdt, 3600), now + 20 * 60);
+    }
+}
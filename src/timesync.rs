@@ -0,0 +1,121 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Checks of cameras' clocks against the server's, via ONVIF `GetSystemDateAndTime`. A camera
+//! with a skewed clock stamps its video with the wrong times (when the stream carries them) and
+//! sends events which don't line up with recordings.
+//!
+//! Cameras are checked every `INTERVAL_SEC` seconds. The camera reports its time in whole
+//! seconds, and the server's time is taken as the midpoint of the request, so the skew is only
+//! accurate to a second or so plus half the request's round trip.
+
+use db;
+use failure::Error;
+use onvif;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use time;
+
+/// How often to check all cameras, in seconds.
+const INTERVAL_SEC: u64 = 600;
+
+/// The skew, in seconds, beyond which a camera's clock is logged as wrong.
+const WARN_SKEW_SEC: i64 = 5;
+
+/// Returns the midpoint of `before` and `after`, rounded to the nearest second.
+fn midpoint_sec(before: time::Timespec, after: time::Timespec) -> i64 {
+    let ms = |t: time::Timespec| t.sec * 1000 + i64::from(t.nsec) / 1_000_000;
+    let mid_ms = (ms(before) + ms(after)) / 2;
+    (mid_ms + 500) / 1000
+}
+
+/// Checks the given camera's clock, returning its skew in seconds and whether it uses NTP.
+fn check(host: &str, username: &str, password: &str) -> Result<(i64, bool), Error> {
+    let before = time::get_time();
+    let t = onvif::get_system_date_and_time(host, username, password)?;
+    let after = time::get_time();
+    Ok((t.utc_sec - midpoint_sec(before, after), t.ntp))
+}
+
+/// Starts a thread which checks the clocks of all cameras with a host, recording the results
+/// with `LockedDatabase::update_camera_clock`.
+pub fn start(db: Arc<db::Database>) -> Result<(), Error> {
+    thread::Builder::new()
+        .name("timesync".to_owned())
+        .spawn(move || {
+            let mut skewed = HashSet::new();
+            loop {
+                let cameras: Vec<(i32, String, String, String, String)> = {
+                    let l = db.lock();
+                    l.cameras_by_id().values()
+                     .filter(|c| !c.host.is_empty())
+                     .map(|c| (c.id, c.short_name.clone(), c.host.clone(), c.username.clone(),
+                               c.password.clone()))
+                     .collect()
+                };
+                for (id, short_name, host, username, password) in cameras {
+                    let result = check(&host, &username, &password).map_err(|e| e.to_string());
+                    match result {
+                        Ok((skew_sec, ntp)) if skew_sec.abs() > WARN_SKEW_SEC => {
+                            if skewed.insert(id) {
+                                warn!("{}: camera clock is off by {} sec{}", short_name,
+                                      skew_sec, if ntp { "" } else { "; it doesn't use NTP" });
+                            }
+                        },
+                        Ok(_) => {
+                            if skewed.remove(&id) {
+                                info!("{}: camera clock is back in sync", short_name);
+                            }
+                        },
+                        Err(ref e) => debug!("{}: unable to check camera clock: {}", short_name, e),
+                    }
+                    if let Err(e) = db.lock().update_camera_clock(id, result) {
+                        warn!("{}: unable to update clock: {}", short_name, e);
+                    }
+                }
+                thread::sleep(Duration::from_secs(INTERVAL_SEC));
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Timespec;
+
+    #[test]
+    fn test_midpoint_sec() {
+        assert_eq!(super::midpoint_sec(Timespec::new(10, 0), Timespec::new(12, 0)), 11);
+        assert_eq!(super::midpoint_sec(Timespec::new(10, 0), Timespec::new(11, 0)), 11);
+        assert_eq!(super::midpoint_sec(Timespec::new(10, 0), Timespec::new(10, 900_000_000)), 10);
+    }
+}
@@ -0,0 +1,268 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Long-polling for new recordings, as in `/api/cameras/<uuid>/<stream>/recordings/updates`.
+//! See `design/api.md` for details.
+//!
+//! All waiting requests are checked by a single thread, which exits when there are none. It's
+//! woken as soon as a flush commits recordings (see `Hub::notify`) and otherwise checks every
+//! `POLL_INTERVAL_MS`, as the growth of a recording in progress isn't signalled.
+
+use body::{BodyStream, BoxedError, Chunk};
+use db::{self, recording};
+use failure::Error;
+use futures::Stream;
+use futures::sync::mpsc;
+use json;
+use parking_lot::{Condvar, Mutex};
+use serde_json;
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to check waiters for growth of the recording in progress.
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// The longest a request may wait, in seconds.
+pub const MAX_TIMEOUT_SEC: u64 = 120;
+
+/// What a request is waiting for, and what to return once it's happened.
+pub struct Params {
+    pub stream_id: i32,
+
+    /// Wait for a recording with a greater id to be committed.
+    pub after_id: i32,
+
+    /// If present, also wait for the recording in progress to extend past this time.
+    pub growing_end_90k: Option<i64>,
+
+    /// The time range and split of the listing returned, as in `/recordings`.
+    pub time: Range<recording::Time>,
+    pub split: db::AggregationSplit,
+
+    /// How long to wait before returning the current listing regardless.
+    pub timeout: Duration,
+}
+
+/// The state of a stream that's compared against `Params`.
+#[derive(Debug, Eq, PartialEq)]
+struct StreamState {
+    /// The id of the latest committed recording, or 0 if there are none.
+    latest_id: i32,
+
+    /// The end of the latest uncommitted recording, if any.
+    growing_end_90k: Option<i64>,
+}
+
+impl StreamState {
+    fn get(db: &db::LockedDatabase, stream_id: i32) -> Result<Self, Error> {
+        let s = db.streams_by_id().get(&stream_id)
+                  .ok_or_else(|| format_err!("no such stream {}", stream_id))?;
+        let next_id = s.next_recording_id();
+        let mut growing_end_90k = None;
+        db.list_recordings_by_id(stream_id, next_id .. i32::max_value(), &mut |r| {
+            growing_end_90k = Some(r.start.0 + r.duration_90k as i64);
+            Ok(())
+        })?;
+        Ok(StreamState {
+            latest_id: next_id - 1,
+            growing_end_90k,
+        })
+    }
+
+    fn satisfies(&self, p: &Params) -> bool {
+        self.latest_id > p.after_id ||
+        p.growing_end_90k.map(|e| self.growing_end_90k.map(|g| g > e).unwrap_or(false))
+                         .unwrap_or(false)
+    }
+}
+
+struct Waiter {
+    params: Params,
+    deadline: Instant,
+    tx: mpsc::UnboundedSender<Chunk>,
+}
+
+struct State {
+    waiters: Vec<Waiter>,
+    thread_running: bool,
+
+    /// True if recordings have been committed since the thread last checked.
+    notified: bool,
+}
+
+/// All requests waiting for new recordings.
+pub struct Hub {
+    db: Arc<db::Database>,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Hub {
+    pub fn new(db: Arc<db::Database>) -> Arc<Self> {
+        Arc::new(Hub {
+            db,
+            state: Mutex::new(State {
+                waiters: Vec::new(),
+                thread_running: false,
+                notified: false,
+            }),
+            cond: Condvar::new(),
+        })
+    }
+
+    /// Returns a `application/json` body which is sent once `p`'s condition is met or it times
+    /// out. Fails immediately if the stream doesn't exist.
+    pub fn wait(hub: &Arc<Self>, params: Params) -> Result<BodyStream, Error> {
+        let (tx, rx) = mpsc::unbounded();
+        let body: BodyStream = Box::new(rx.map_err(|()| -> BoxedError { unreachable!() }));
+        let ready = {
+            let l = hub.db.lock();
+            if StreamState::get(&l, params.stream_id)?.satisfies(&params) {
+                Some(respond(&l, &params)?)
+            } else {
+                None
+            }
+        };
+        if let Some(r) = ready {
+            let _ = tx.unbounded_send(r);
+            return Ok(body);
+        }
+        let mut s = hub.state.lock();
+        s.waiters.push(Waiter {
+            deadline: Instant::now() + params.timeout,
+            params,
+            tx,
+        });
+        if !s.thread_running {
+            s.thread_running = true;
+            let hub = hub.clone();
+            thread::Builder::new()
+                .name("updates".to_owned())
+                .spawn(move || hub.run())
+                .expect("can't create updates thread");
+        }
+        Ok(body)
+    }
+
+    /// Wakes the thread to check waiters, as when a flush commits recordings. Called with the
+    /// database lock held, so this never acquires it.
+    pub fn notify(&self) {
+        let mut s = self.state.lock();
+        if s.thread_running {
+            s.notified = true;
+            self.cond.notify_one();
+        }
+    }
+
+    fn run(&self) {
+        loop {
+            // Take the waiters so the database lock is never acquired with the state lock held.
+            let waiters = {
+                let mut s = self.state.lock();
+                if s.waiters.is_empty() {
+                    s.thread_running = false;
+                    return;
+                }
+                if !s.notified {
+                    self.cond.wait_for(&mut s, Duration::from_millis(POLL_INTERVAL_MS));
+                }
+                s.notified = false;
+                ::std::mem::replace(&mut s.waiters, Vec::new())
+            };
+            let now = Instant::now();
+            let mut remaining = Vec::with_capacity(waiters.len());
+            {
+                let l = self.db.lock();
+                for w in waiters {
+                    let done = match StreamState::get(&l, w.params.stream_id) {
+                        Ok(s) => s.satisfies(&w.params) || now >= w.deadline,
+                        Err(e) => {
+                            // The stream was deleted; end the response without a body.
+                            warn!("updates: {}", e);
+                            continue;
+                        },
+                    };
+                    if !done {
+                        remaining.push(w);
+                        continue;
+                    }
+                    match respond(&l, &w.params) {
+                        Ok(r) => { let _ = w.tx.unbounded_send(r); },
+                        Err(e) => warn!("updates: unable to list recordings: {}", e),
+                    }
+                }
+            }
+            self.state.lock().waiters.extend(remaining);
+        }
+    }
+}
+
+/// Returns the response body: the stream's state and its recordings as in `/recordings`.
+fn respond(db: &db::LockedDatabase, p: &Params) -> Result<Chunk, Error> {
+    let state = StreamState::get(db, p.stream_id)?;
+    let mut out = json::RecordingUpdates {
+        latest_id: state.latest_id,
+        growing_end_90k: state.growing_end_90k,
+        recordings: Vec::new(),
+    };
+    db.list_aggregated_recordings(p.stream_id, p.time.clone(), p.split, &mut |row| {
+        out.recordings.push(json::Recording::wrap(row, db));
+        Ok(())
+    })?;
+    Ok(Chunk::from(serde_json::to_vec(&out)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Params, StreamState};
+    use db::recording;
+    use std::time::Duration;
+
+    #[test]
+    fn satisfies() {
+        let p = |after_id, growing_end_90k| Params {
+            stream_id: 1,
+            after_id,
+            growing_end_90k,
+            time: recording::Time(0) .. recording::Time(1),
+            split: Default::default(),
+            timeout: Duration::from_secs(1),
+        };
+        let s = StreamState { latest_id: 5, growing_end_90k: Some(90000) };
+        assert!(s.satisfies(&p(4, None)));
+        assert!(!s.satisfies(&p(5, None)));
+        assert!(!s.satisfies(&p(5, Some(90000))));
+        assert!(s.satisfies(&p(5, Some(89999))));
+        let s = StreamState { latest_id: 5, growing_end_90k: None };
+        assert!(!s.satisfies(&p(5, Some(0))));
+    }
+}
@@ -0,0 +1,758 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Ingestion of cameras' own analytics events (motion, line crossing, intrusion, tamper) and
+//! doorbell presses, as configured by `db::Camera::event_source` or carried in a stream's RTSP
+//! metadata track (see `MetadataEvents`). The camera has already done the analysis, so this costs
+//! no server CPU beyond parsing. Events are stored via `db::LockedDatabase::add_event`.
+//!
+//! Each camera with an event source has a thread which holds a subscription open, reconnecting
+//! on error. Sources report events in one of three ways, represented by `State`: an explicit
+//! start and end, a repeated "active" notification without an explicit end, or a single
+//! instantaneous notification.
+
+use analytics;
+use clock::Clocks;
+use db::{self, recording};
+use failure::Error;
+use maintenance::Maintenance;
+use onvif;
+use regex::Regex;
+use reqwest;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The event type for an object crossing a configured line.
+pub const LINE_CROSSING_EVENT_TYPE: &'static str = "line_crossing";
+
+/// The event type for an object within a configured region.
+pub const INTRUSION_EVENT_TYPE: &'static str = "intrusion";
+
+/// The event type for the camera being covered, moved, or defocused.
+pub const TAMPER_EVENT_TYPE: &'static str = "tamper";
+
+/// The event type for a doorbell camera's button being pressed. These are given a snapshot (see
+/// `snapshot`) and urgent notifications (see `push`).
+pub const DOORBELL_EVENT_TYPE: &'static str = "doorbell";
+
+/// The event type for an object tracked by the camera's analytics, as reported in a stream's
+/// metadata track. Each has a detection (see `db::Detection`) from its most confident sighting.
+pub const OBJECT_EVENT_TYPE: &'static str = "object";
+
+/// An object in a metadata track is considered gone this long after it was last reported.
+const OBJECT_HOLD: recording::Duration = recording::Duration(2 * recording::TIME_UNITS_PER_SEC);
+
+/// How long to wait after an error before reconnecting.
+const RETRY_SEC: u64 = 10;
+
+/// Hikvision repeats `active` notifications about once a second while an event is ongoing and
+/// doesn't reliably send an end; an event ends this long after its last notification.
+const HIKVISION_HOLD: recording::Duration = recording::Duration(3 * recording::TIME_UNITS_PER_SEC);
+
+/// For sources with explicit ends, an event which has lasted this long is assumed to have had its
+/// end lost.
+const MAX_DURATION: recording::Duration =
+    recording::Duration(10 * 60 * recording::TIME_UNITS_PER_SEC);
+
+/// Records above this size are discarded, so a misbehaving camera can't exhaust memory.
+const MAX_RECORD_BYTES: usize = 1 << 16;
+
+lazy_static! {
+    static ref HIKVISION_TYPE_RE: Regex = Regex::new(r"<eventType>([^<]*)</").unwrap();
+    static ref HIKVISION_STATE_RE: Regex = Regex::new(r"<eventState>([^<]*)</").unwrap();
+    static ref HIKVISION_CHANNEL_RE: Regex =
+        Regex::new(r"<(?:dynChannelID|channelID)>([^<]*)</").unwrap();
+    static ref DAHUA_RE: Regex = Regex::new(r"Code=(\w+);action=(\w+);index=(\d+)").unwrap();
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    /// The event has started or is ongoing.
+    Active,
+
+    /// The event has ended.
+    Inactive,
+
+    /// An instantaneous event, such as a line crossing.
+    Pulse,
+}
+
+/// A notification from a camera, translated to Moonfire NVR's event types.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Notification {
+    pub type_: &'static str,
+
+    /// Distinguishes concurrent events of the same type, such as those from different rules or
+    /// channels. Used as the event's description.
+    pub key: String,
+
+    pub state: State,
+}
+
+/// Translates a notification from an ONVIF `PullPoint`.
+pub fn from_onvif(n: &onvif::Notification) -> Option<Notification> {
+    let topic = n.topic.splitn(2, ':').last().unwrap();
+    let type_ = if topic.ends_with("CellMotionDetector/Motion") ||
+                   topic.ends_with("VideoSource/MotionAlarm") {
+        analytics::MOTION_EVENT_TYPE
+    } else if topic.ends_with("LineDetector/Crossed") {
+        LINE_CROSSING_EVENT_TYPE
+    } else if topic.ends_with("FieldDetector/ObjectsInside") {
+        INTRUSION_EVENT_TYPE
+    } else if topic.ends_with("TamperDetector/Tamper") ||
+              topic.ends_with("VideoSource/GlobalSceneChange/ImagingService") {
+        TAMPER_EVENT_TYPE
+    } else if topic.ends_with("Device/Trigger/DigitalInput") {
+        // Doorbells expose their button as a digital input. A press is instantaneous; report it
+        // on the input becoming active and ignore its release.
+        return match n.data.iter().find(|&&(ref k, _)| k == "LogicalState") {
+            Some(&(_, ref v)) if v == "true" => Some(Notification {
+                type_: DOORBELL_EVENT_TYPE,
+                key: String::new(),
+                state: State::Pulse,
+            }),
+            _ => None,
+        };
+    } else {
+        return None;
+    };
+
+    // Stateful topics have a single boolean data item (IsMotion, State, IsInside, IsTamper).
+    let state = match n.data.iter().map(|&(_, ref v)| v.as_str()).find(|&v| v == "true" ||
+                                                                              v == "false") {
+        Some("true") => State::Active,
+        Some(_) => State::Inactive,
+        None => State::Pulse,
+    };
+    let key = n.source.iter().map(|&(ref k, ref v)| format!("{}={}", k, v))
+                             .collect::<Vec<_>>()
+                             .join(", ");
+    Some(Notification { type_, key, state })
+}
+
+/// Translates an `EventNotificationAlert` from a Hikvision `alertStream`.
+fn from_hikvision(alert: &str) -> Option<Notification> {
+    let type_ = match HIKVISION_TYPE_RE.captures(alert)?.get(1).unwrap().as_str() {
+        "VMD" => analytics::MOTION_EVENT_TYPE,
+        "linedetection" => LINE_CROSSING_EVENT_TYPE,
+        "fielddetection" => INTRUSION_EVENT_TYPE,
+        "tamperdetection" | "shelteralarm" => TAMPER_EVENT_TYPE,
+        _ => return None,
+    };
+    let state = match HIKVISION_STATE_RE.captures(alert)?.get(1).unwrap().as_str() {
+        "active" => State::Active,
+        _ => State::Inactive,
+    };
+    let key = HIKVISION_CHANNEL_RE.captures(alert)
+                                  .map(|c| format!("channel {}", &c[1]))
+                                  .unwrap_or_else(String::new);
+    Some(Notification { type_, key, state })
+}
+
+/// Translates a line from a Dahua `eventManager.cgi` attachment.
+fn from_dahua(line: &str) -> Option<Notification> {
+    let c = DAHUA_RE.captures(line)?;
+    let type_ = match &c[1] {
+        "VideoMotion" => analytics::MOTION_EVENT_TYPE,
+        "CrossLineDetection" => LINE_CROSSING_EVENT_TYPE,
+        "CrossRegionDetection" => INTRUSION_EVENT_TYPE,
+        "VideoBlind" | "SceneChange" => TAMPER_EVENT_TYPE,
+
+        // Video intercoms (VTOs) report a press of the call button as a call.
+        "CallNoAnswered" | "PhoneCallDetect" => {
+            if &c[2] != "Start" {
+                return None;
+            }
+            return Some(Notification {
+                type_: DOORBELL_EVENT_TYPE,
+                key: String::new(),
+                state: State::Pulse,
+            });
+        },
+        _ => return None,
+    };
+    let state = match &c[2] {
+        "Start" => State::Active,
+        "Stop" => State::Inactive,
+        "Pulse" => State::Pulse,
+        _ => return None,
+    };
+    Some(Notification { type_, key: format!("channel {}", &c[3]), state })
+}
+
+/// Turns a sequence of `Notification`s into events.
+struct Tracker {
+    camera_id: i32,
+
+    /// An active event ends this long after its last notification.
+    hold: recording::Duration,
+
+    /// The `start .. last notification` of each active event.
+    active: HashMap<(&'static str, String), (recording::Time, recording::Time)>,
+}
+
+impl Tracker {
+    fn new(camera_id: i32, hold: recording::Duration) -> Self {
+        Tracker {
+            camera_id,
+            hold,
+            active: HashMap::new(),
+        }
+    }
+
+    fn event(&self, type_: &'static str, key: String, time: ::std::ops::Range<recording::Time>)
+             -> db::EventToInsert {
+        db::EventToInsert {
+            camera_id: self.camera_id,
+            type_: type_.to_owned(),
+            time,
+            description: if key.is_empty() { None } else { Some(key) },
+            score: None,
+        }
+    }
+
+    /// Processes a notification received at `now`, appending any finished event to `out`.
+    fn process(&mut self, now: recording::Time, n: Notification,
+               out: &mut Vec<db::EventToInsert>) {
+        let k = (n.type_, n.key);
+        match n.state {
+            State::Active => {
+                let e = self.active.entry(k).or_insert((now, now));
+                e.1 = now;
+            },
+            State::Inactive => {
+                let removed = self.active.remove(&k);
+                if let Some((start, _)) = removed {
+                    out.push(self.event(k.0, k.1, start .. now));
+                }
+            },
+            State::Pulse => out.push(self.event(k.0, k.1, now .. now)),
+        }
+    }
+
+    /// Ends events which haven't been renewed within the hold time, appending them to `out`.
+    fn expire(&mut self, now: recording::Time, out: &mut Vec<db::EventToInsert>) {
+        let hold = self.hold;
+        let expired: Vec<_> = self.active.iter()
+                                         .filter(|&(_, &(_, last))| now - last > hold)
+                                         .map(|(k, _)| k.clone())
+                                         .collect();
+        for k in expired {
+            let (start, last) = self.active.remove(&k).unwrap();
+            out.push(self.event(k.0, k.1, start .. last));
+        }
+    }
+
+    /// Ends all active events, as when the connection is lost.
+    fn finish(&mut self, out: &mut Vec<db::EventToInsert>) {
+        let active: Vec<_> = self.active.drain().collect();
+        for (k, (start, last)) in active {
+            out.push(self.event(k.0, k.1, start .. last));
+        }
+    }
+}
+
+/// An object seen in a metadata track, as tracked by `MetadataEvents`.
+struct TrackedObject {
+    start: recording::Time,
+    last: recording::Time,
+    label: Option<String>,
+    score: Option<f64>,
+    detection: Option<db::Detection>,
+}
+
+/// Turns the ONVIF analytics in a stream's metadata track into events, for streams with
+/// `db::Stream::metadata_events` set. Rule notifications in the track's `Event` sections are
+/// handled as those from a `PullPoint`. Each object in its `VideoAnalytics` sections becomes an
+/// `OBJECT_EVENT_TYPE` event lasting while it's reported.
+pub struct MetadataEvents {
+    camera_id: i32,
+    tracker: Tracker,
+    objects: HashMap<String, TrackedObject>,
+
+    /// The current `MetadataStream` document, which may span several packets.
+    pending: Vec<u8>,
+}
+
+impl MetadataEvents {
+    pub fn new(camera_id: i32) -> Self {
+        MetadataEvents {
+            camera_id,
+            tracker: Tracker::new(camera_id, MAX_DURATION),
+            objects: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Processes a metadata packet received at `now`, appending finished events (and the
+    /// detections to attach to them) to `out`.
+    pub fn process(&mut self, now: recording::Time, pkt: &[u8],
+                   out: &mut Vec<(db::EventToInsert, Option<db::Detection>)>) {
+        self.pending.extend_from_slice(pkt);
+        if self.pending.len() > MAX_RECORD_BYTES {
+            debug!("discarding {}-byte metadata document", self.pending.len());
+            self.pending.clear();
+        }
+        let complete = match ::std::str::from_utf8(&self.pending) {
+            Ok(d) => onvif::METADATA_STREAM_END_RE.is_match(d),
+            Err(_) => false,  // perhaps a character is split across packets.
+        };
+        if !complete {
+            self.expire(now, out);
+            return;
+        }
+        let doc = String::from_utf8(::std::mem::replace(&mut self.pending, Vec::new()))
+            .expect("checked above");
+        let mut events = Vec::new();
+        for n in onvif::parse_notifications(&doc).iter().filter_map(from_onvif) {
+            self.tracker.process(now, n, &mut events);
+        }
+        out.extend(events.drain(..).map(|e| (e, None)));
+        for o in onvif::parse_metadata_objects(&doc) {
+            let (label, score) = match o.class {
+                Some((l, s)) => (Some(l), s),
+                None => (None, None),
+            };
+            let detection = o.bounding_box.and_then(|b| to_detection(&b, &label, score));
+            let t = self.objects.entry(o.id).or_insert(TrackedObject {
+                start: now,
+                last: now,
+                label: None,
+                score: None,
+                detection: None,
+            });
+            t.last = now;
+            if label.is_some() && score.unwrap_or(0.) >= t.score.unwrap_or(0.) {
+                t.label = label;
+                t.score = score;
+                if detection.is_some() {
+                    t.detection = detection;
+                }
+            } else if t.detection.is_none() {
+                t.detection = detection;
+            }
+        }
+        self.expire(now, out);
+    }
+
+    fn event(&self, o: TrackedObject, end: recording::Time)
+             -> (db::EventToInsert, Option<db::Detection>) {
+        (db::EventToInsert {
+            camera_id: self.camera_id,
+            type_: OBJECT_EVENT_TYPE.to_owned(),
+            time: o.start .. end,
+            description: o.label,
+            score: o.score,
+        }, o.detection)
+    }
+
+    fn expire(&mut self, now: recording::Time,
+              out: &mut Vec<(db::EventToInsert, Option<db::Detection>)>) {
+        let mut events = Vec::new();
+        self.tracker.expire(now, &mut events);
+        out.extend(events.drain(..).map(|e| (e, None)));
+        let expired: Vec<String> = self.objects.iter()
+                                               .filter(|&(_, o)| now - o.last > OBJECT_HOLD)
+                                               .map(|(k, _)| k.clone())
+                                               .collect();
+        for k in expired {
+            let o = self.objects.remove(&k).unwrap();
+            let last = o.last;
+            out.push(self.event(o, last));
+        }
+    }
+
+    /// Ends all active events, as when the stream is closed.
+    pub fn finish(&mut self, out: &mut Vec<(db::EventToInsert, Option<db::Detection>)>) {
+        let mut events = Vec::new();
+        self.tracker.finish(&mut events);
+        out.extend(events.drain(..).map(|e| (e, None)));
+        let objects: Vec<_> = self.objects.drain().collect();
+        for (_, o) in objects {
+            let last = o.last;
+            out.push(self.event(o, last));
+        }
+    }
+}
+
+/// Converts an ONVIF bounding box (see `onvif::MetadataObject`) to a detection.
+fn to_detection(b: &[f64; 4], label: &Option<String>, score: Option<f64>)
+                -> Option<db::Detection> {
+    let (left, right) = (b[0].min(b[2]), b[0].max(b[2]));
+    let (top, bottom) = (b[1].max(b[3]), b[1].min(b[3]));
+    let d = db::Detection {
+        label: label.clone().unwrap_or_else(|| OBJECT_EVENT_TYPE.to_owned()),
+        score: score.map(|s| s.max(0.).min(1.)),
+        x: (left + 1.) / 2.,
+        y: (1. - top) / 2.,
+        width: (right - left) / 2.,
+        height: (top - bottom) / 2.,
+    };
+    if d.x >= 0. && d.y >= 0. && d.width > 0. && d.height > 0. && d.x + d.width <= 1. &&
+       d.y + d.height <= 1. {
+        Some(d)
+    } else {
+        None
+    }
+}
+
+/// Calls `f` with each `terminator`-ended record of `r` until EOF or error.
+fn for_each_record<R: Read, F>(mut r: R, terminator: &[u8], mut f: F) -> Result<(), Error>
+where F: FnMut(&str) -> Result<(), Error> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = r.read(&mut chunk)?;
+        if n == 0 {
+            bail!("event stream ended");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        loop {
+            let end = match buf.windows(terminator.len()).position(|w| w == terminator) {
+                None => break,
+                Some(p) => p + terminator.len(),
+            };
+            f(&String::from_utf8_lossy(&buf[..end]))?;
+            buf.drain(..end);
+        }
+        if buf.len() > MAX_RECORD_BYTES {
+            warn!("discarding {}-byte partial event record", buf.len());
+            buf.clear();
+        }
+    }
+}
+
+struct Subscriber {
+    db: Arc<db::Database>,
+    maintenance: Arc<Maintenance>,
+    camera_id: i32,
+    short_name: String,
+    source: db::EventSource,
+    host: String,
+    username: String,
+    password: String,
+}
+
+impl Subscriber {
+    fn run(&self) {
+        let hold = match self.source {
+            db::EventSource::Hikvision => HIKVISION_HOLD,
+            _ => MAX_DURATION,
+        };
+        let mut tracker = Tracker::new(self.camera_id, hold);
+        loop {
+            let e = match self.source {
+                db::EventSource::Onvif => self.run_onvif(&mut tracker),
+                db::EventSource::Hikvision => {
+                    self.run_http("/ISAPI/Event/notification/alertStream",
+                                  b"</EventNotificationAlert>", from_hikvision, &mut tracker)
+                },
+                db::EventSource::Dahua => {
+                    self.run_http("/cgi-bin/eventManager.cgi?action=attach&codes=[All]", b"\n",
+                                  from_dahua, &mut tracker)
+                },
+            }.unwrap_err();
+            if !self.db.lock().cameras_by_id().contains_key(&self.camera_id) {
+                info!("{}: camera was deleted; unsubscribing from events", self.short_name);
+                return;
+            }
+            let mut events = Vec::new();
+            tracker.finish(&mut events);
+            let _ = self.add_events(&events);
+            warn!("{}: {} events failed; reconnecting in {} sec: {}",
+                  self.short_name, self.source.as_str(), RETRY_SEC, e);
+            thread::sleep(Duration::from_secs(RETRY_SEC));
+        }
+    }
+
+    fn now(&self) -> recording::Time { recording::Time::new(self.db.clocks().realtime()) }
+
+    /// Adds the given events, failing if the camera has been deleted so that the subscription
+    /// ends. Events are dropped while maintenance mode is active.
+    fn add_events(&self, events: &[db::EventToInsert]) -> Result<(), Error> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let mut l = self.db.lock();
+        if !l.cameras_by_id().contains_key(&self.camera_id) {
+            bail!("camera was deleted");
+        }
+        if self.maintenance.is_active() {
+            debug!("{}: maintenance mode; dropping {} events", self.short_name, events.len());
+            return Ok(());
+        }
+        for e in events {
+            if let Err(err) = l.add_event(e) {
+                warn!("{}: unable to add event {:?}: {}", self.short_name, e, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pulls from an ONVIF `PullPoint` until error.
+    fn run_onvif(&self, tracker: &mut Tracker) -> Result<(), Error> {
+        let mut p = onvif::PullPoint::create(&self.host, &self.username, &self.password)?;
+        info!("{}: subscribed to ONVIF events", self.short_name);
+        let mut events = Vec::new();
+        loop {
+            let notifications = p.pull()?;
+            let now = self.now();
+            for n in notifications.iter().filter_map(from_onvif) {
+                tracker.process(now, n, &mut events);
+            }
+            tracker.expire(now, &mut events);
+            self.add_events(&events)?;
+            events.clear();
+        }
+    }
+
+    /// Reads a long-lived HTTP response of `terminator`-ended records until error.
+    fn run_http(&self, path: &str, terminator: &[u8], parse: fn(&str) -> Option<Notification>,
+                tracker: &mut Tracker) -> Result<(), Error> {
+        // Both vendors send heartbeats well within this interval.
+        let client = reqwest::Client::builder().timeout(Duration::from_secs(60)).build()?;
+        let host = match self.host.rfind(':') {
+            Some(i) if !self.host.contains(']') && self.host.matches(':').count() == 1 => {
+                &self.host[..i]
+            },
+            _ => &self.host[..],
+        };
+        let resp = client.get(&format!("http://{}{}", host, path))
+                         .basic_auth(&self.username, Some(&self.password))
+                         .send()?;
+        if !resp.status().is_success() {
+            bail!("status {}", resp.status());
+        }
+        info!("{}: subscribed to {} events", self.short_name, self.source.as_str());
+        let mut events = Vec::new();
+        for_each_record(resp, terminator, |record| {
+            let now = self.now();
+            if let Some(n) = parse(record) {
+                tracker.process(now, n, &mut events);
+            }
+            tracker.expire(now, &mut events);
+            self.add_events(&events)?;
+            events.clear();
+            Ok(())
+        })
+    }
+}
+
+/// Starts a subscriber thread for each camera with an event source.
+pub fn start(db: &Arc<db::Database>, maintenance: &Arc<Maintenance>) -> Result<(), Error> {
+    let l = db.lock();
+    for c in l.cameras_by_id().values() {
+        let source = match c.event_source {
+            None => continue,
+            Some(s) => s,
+        };
+        let s = Subscriber {
+            db: db.clone(),
+            maintenance: maintenance.clone(),
+            camera_id: c.id,
+            short_name: c.short_name.clone(),
+            source,
+            host: c.host.clone(),
+            username: c.username.clone(),
+            password: c.password.clone(),
+        };
+        thread::Builder::new()
+            .name(format!("ev-{}", c.short_name))
+            .spawn(move || s.run())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use analytics::MOTION_EVENT_TYPE;
+    use db;
+    use db::recording::{self, TIME_UNITS_PER_SEC};
+    use onvif;
+    use super::*;
+
+    fn n(type_: &'static str, key: &str, state: State) -> Notification {
+        Notification { type_, key: key.to_owned(), state }
+    }
+
+    #[test]
+    fn test_from_onvif() {
+        let motion = |v: &str| onvif::Notification {
+            topic: "tns1:RuleEngine/CellMotionDetector/Motion".to_owned(),
+            source: vec![("Rule".to_owned(), "MyRule".to_owned())],
+            data: vec![("IsMotion".to_owned(), v.to_owned())],
+        };
+        assert_eq!(from_onvif(&motion("true")),
+                   Some(n(MOTION_EVENT_TYPE, "Rule=MyRule", State::Active)));
+        assert_eq!(from_onvif(&motion("false")),
+                   Some(n(MOTION_EVENT_TYPE, "Rule=MyRule", State::Inactive)));
+        assert_eq!(from_onvif(&onvif::Notification {
+            topic: "tns1:RuleEngine/LineDetector/Crossed".to_owned(),
+            source: vec![],
+            data: vec![("ObjectId".to_owned(), "3".to_owned())],
+        }), Some(n(LINE_CROSSING_EVENT_TYPE, "", State::Pulse)));
+        let input = |v: &str| onvif::Notification {
+            topic: "tns1:Device/Trigger/DigitalInput".to_owned(),
+            source: vec![("InputToken".to_owned(), "DIGIT_IN_0".to_owned())],
+            data: vec![("LogicalState".to_owned(), v.to_owned())],
+        };
+        assert_eq!(from_onvif(&input("true")), Some(n(DOORBELL_EVENT_TYPE, "", State::Pulse)));
+        assert_eq!(from_onvif(&input("false")), None);
+        assert_eq!(from_onvif(&onvif::Notification {
+            topic: "tns1:Device/HardwareFailure/StorageFailure".to_owned(),
+            ..Default::default()
+        }), None);
+    }
+
+    #[test]
+    fn test_from_hikvision() {
+        let alert = "<EventNotificationAlert version=\"2.0\">\
+                     <ipAddress>192.168.1.64</ipAddress><channelID>1</channelID>\
+                     <dateTime>2018-03-01T00:00:09-08:00</dateTime><activePostCount>1\
+                     </activePostCount><eventType>VMD</eventType><eventState>active</eventState>\
+                     <eventDescription>Motion alarm</eventDescription></EventNotificationAlert>";
+        assert_eq!(from_hikvision(alert), Some(n(MOTION_EVENT_TYPE, "channel 1", State::Active)));
+        let heartbeat = "<EventNotificationAlert><eventType>videoloss</eventType>\
+                         <eventState>inactive</eventState></EventNotificationAlert>";
+        assert_eq!(from_hikvision(heartbeat), None);
+    }
+
+    #[test]
+    fn test_from_dahua() {
+        assert_eq!(from_dahua("Code=VideoMotion;action=Start;index=0\r\n"),
+                   Some(n(MOTION_EVENT_TYPE, "channel 0", State::Active)));
+        assert_eq!(from_dahua("Code=CrossLineDetection;action=Pulse;index=1;data={}\r\n"),
+                   Some(n(LINE_CROSSING_EVENT_TYPE, "channel 1", State::Pulse)));
+        assert_eq!(from_dahua("Code=CallNoAnswered;action=Start;index=0\r\n"),
+                   Some(n(DOORBELL_EVENT_TYPE, "", State::Pulse)));
+        assert_eq!(from_dahua("Code=CallNoAnswered;action=Stop;index=0\r\n"), None);
+        assert_eq!(from_dahua("--myboundary\r\n"), None);
+    }
+
+    #[test]
+    fn test_for_each_record() {
+        let input = &b"Code=VideoMotion;action=Start;index=0\r\nCode=VideoMotion;action=Stop;\
+                       index=0\r\npartial"[..];
+        let mut records = Vec::new();
+        let e = for_each_record(input, b"\n", |r| { records.push(r.to_owned()); Ok(()) })
+            .unwrap_err();
+        assert_eq!(e.to_string(), "event stream ended");
+        assert_eq!(records, vec!["Code=VideoMotion;action=Start;index=0\r\n",
+                                 "Code=VideoMotion;action=Stop;index=0\r\n"]);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let sec = |s: i64| recording::Duration(s * TIME_UNITS_PER_SEC);
+        let t0 = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let mut t = Tracker::new(1, super::HIKVISION_HOLD);
+        let mut out = Vec::new();
+
+        // Repeated active notifications make a single event which expires after the hold.
+        for i in 0..5 {
+            t.process(t0 + sec(i), n(MOTION_EVENT_TYPE, "channel 1", State::Active), &mut out);
+            t.expire(t0 + sec(i), &mut out);
+        }
+        t.process(t0 + sec(5), n(LINE_CROSSING_EVENT_TYPE, "", State::Pulse), &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].type_, LINE_CROSSING_EVENT_TYPE);
+        assert_eq!(out[0].time, t0 + sec(5) .. t0 + sec(5));
+        assert_eq!(out[0].description, None);
+        t.expire(t0 + sec(8), &mut out);
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[1].time, t0 .. t0 + sec(4));
+        assert_eq!(out[1].description.as_ref().map(String::as_str), Some("channel 1"));
+
+        // An explicit end.
+        t.process(t0 + sec(10), n(TAMPER_EVENT_TYPE, "", State::Active), &mut out);
+        t.process(t0 + sec(11), n(TAMPER_EVENT_TYPE, "", State::Inactive), &mut out);
+        assert_eq!(out[2].time, t0 + sec(10) .. t0 + sec(11));
+
+        // Ending the connection ends all events.
+        t.process(t0 + sec(12), n(INTRUSION_EVENT_TYPE, "", State::Active), &mut out);
+        t.finish(&mut out);
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[3].time, t0 + sec(12) .. t0 + sec(12));
+    }
+
+    #[test]
+    fn test_metadata_events() {
+        let sec = |s: i64| recording::Duration(s * TIME_UNITS_PER_SEC);
+        let t0 = recording::Time(1430006400 * TIME_UNITS_PER_SEC);
+        let frame = |id: &str| format!(r#"<tt:MetadataStream><tt:VideoAnalytics><tt:Frame>
+<tt:Object ObjectId="{}"><tt:Appearance><tt:Shape>
+<tt:BoundingBox left="-0.5" top="0.5" right="0.0" bottom="-0.5"/></tt:Shape>
+<tt:Class><tt:Type Likelihood="0.8">Human</tt:Type></tt:Class></tt:Appearance></tt:Object>
+</tt:Frame></tt:VideoAnalytics></tt:MetadataStream>"#, id);
+        let mut m = MetadataEvents::new(1);
+        let mut out = Vec::new();
+
+        // A document split across packets.
+        let f = frame("1");
+        let (a, b) = f.as_bytes().split_at(20);
+        m.process(t0, a, &mut out);
+        m.process(t0, b, &mut out);
+        m.process(t0 + sec(1), frame("1").as_bytes(), &mut out);
+        assert!(out.is_empty());
+
+        // A motion notification, then the object going away.
+        m.process(t0 + sec(2), br#"<tt:MetadataStream><tt:Event><wsnt:NotificationMessage>
+<wsnt:Topic>tns1:RuleEngine/LineDetector/Crossed</wsnt:Topic>
+<wsnt:Message><tt:Message><tt:Data><tt:SimpleItem Name="ObjectId" Value="1"/></tt:Data>
+</tt:Message></wsnt:Message></wsnt:NotificationMessage></tt:Event></tt:MetadataStream>"#,
+                  &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].0.type_, LINE_CROSSING_EVENT_TYPE);
+        m.process(t0 + sec(4), b"<tt:MetadataStream></tt:MetadataStream>", &mut out);
+        assert_eq!(out.len(), 2);
+        let (ref e, ref d) = out[1];
+        assert_eq!(e.type_, OBJECT_EVENT_TYPE);
+        assert_eq!(e.time, t0 .. t0 + sec(1));
+        assert_eq!(e.description.as_ref().map(String::as_str), Some("Human"));
+        assert_eq!(e.score, Some(0.8));
+        assert_eq!(d.as_ref().unwrap(), &db::Detection {
+            label: "Human".to_owned(),
+            score: Some(0.8),
+            x: 0.25,
+            y: 0.25,
+            width: 0.25,
+            height: 0.5,
+        });
+
+        // Closing the stream ends active objects.
+        m.process(t0 + sec(5), frame("2").as_bytes(), &mut out);
+        m.finish(&mut out);
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[2].0.time, t0 + sec(5) .. t0 + sec(5));
+    }
+}
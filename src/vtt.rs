@@ -0,0 +1,212 @@
+// This file is part of Moonfire NVR, a security camera digital video recorder.
+// Copyright (C) 2018 Scott Lamb <slamb@slamb.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// In addition, as a special exception, the copyright holders give
+// permission to link the code of portions of this program with the
+// OpenSSL library under certain conditions as described in each
+// individual source file, and distribute linked combinations including
+// the two.
+//
+// You must obey the GNU General Public License in all respects for all
+// of the code used other than OpenSSL. If you modify file(s) with this
+// exception, you may extend this exception to your version of the
+// file(s), but you are not obligated to do so. If you do not wish to do
+// so, delete this exception statement from your version. If you delete
+// this exception statement from all source files in the program, then
+// also delete it here.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! [WebVTT](https://www.w3.org/TR/webvtt1/) tracks of timestamps and events, as served by
+//! `/api/cameras/<uuid>/<type>/view.vtt`. Cue times are relative to the start of the
+//! corresponding `view.mp4`, so a web player can show them as a `<track>` without the `.mp4`'s
+//! own subtitle track (and toggle them without fetching the video again).
+
+use db::{self, recording};
+use db::recording::TIME_UNITS_PER_SEC;
+use failure::Error;
+use std::cmp;
+use std::fmt::Write;
+use std::ops::Range;
+use time;
+
+struct Cue {
+    /// The times within the `.mp4`, in 90 kHz units.
+    range_90k: Range<i64>,
+
+    /// Cue settings, such as `line:0` to show the cue at the top of the video.
+    settings: &'static str,
+    text: String,
+}
+
+/// Builds a track from the segments of a `view.mp4`, in the same order they're appended to its
+/// `mp4::FileBuilder`.
+pub struct TrackBuilder {
+    timestamps: bool,
+    events: bool,
+
+    /// The `strftime` format of timestamps, normally `mp4::SUBTITLE_TEMPLATE` or a translation.
+    timestamp_format: String,
+
+    /// The position of the next segment within the `.mp4`, in 90 kHz units.
+    pos_90k: i64,
+    cues: Vec<Cue>,
+}
+
+impl TrackBuilder {
+    /// Creates a builder for a track including per-second timestamps and/or events.
+    pub fn new(timestamps: bool, events: bool, timestamp_format: &str) -> Self {
+        TrackBuilder {
+            timestamps,
+            events,
+            timestamp_format: timestamp_format.to_owned(),
+            pos_90k: 0,
+            cues: Vec::new(),
+        }
+    }
+
+    /// Appends a segment of the given camera covering the given wall-clock time: that is, the
+    /// `rel_range_90k` passed to `mp4::FileBuilder::append`, offset by the recording's start.
+    pub fn append(&mut self, db: &db::LockedDatabase, camera_id: i32,
+                  time: Range<recording::Time>) -> Result<(), Error> {
+        let pos_90k = self.pos_90k;
+        let to_pos = |t: recording::Time| pos_90k + (t - time.start).0;
+        if self.timestamps {
+            // Like the `.mp4` subtitle track: one cue per second, the first and last possibly
+            // partial.
+            let mut start = time.start;
+            while start < time.end {
+                let sec = start.unix_seconds();
+                let next = cmp::min(time.end, recording::Time((sec + 1) * TIME_UNITS_PER_SEC));
+                let tm = time::at(time::Timespec { sec, nsec: 0 });
+                self.cues.push(Cue {
+                    range_90k: to_pos(start) .. to_pos(next),
+                    settings: "",
+                    text: tm.strftime(&self.timestamp_format)?.to_string(),
+                });
+                start = next;
+            }
+        }
+        if self.events {
+            let cues = &mut self.cues;
+            db.list_events(camera_id, time.clone(), &mut |e| {
+                let start = cmp::max(e.time.start, time.start);
+                let end = cmp::min(e.time.end, time.end);
+                cues.push(Cue {
+                    range_90k: to_pos(start) .. to_pos(cmp::max(start, end)),
+                    settings: " line:0",
+                    text: escape(&e.description.unwrap_or(e.type_)),
+                });
+                Ok(())
+            })?;
+        }
+        self.pos_90k += (time.end - time.start).0;
+        Ok(())
+    }
+
+    /// Returns the track as a WebVTT file.
+    pub fn build(mut self) -> String {
+        self.cues.sort_by_key(|c| c.range_90k.start);  // stable, so timestamps come first.
+        let mut out = String::from("WEBVTT\n");
+        for c in &self.cues {
+            write!(out, "\n{} --> {}{}\n{}\n", format_time(c.range_90k.start),
+                   format_time(c.range_90k.end), c.settings, c.text).unwrap();
+        }
+        out
+    }
+}
+
+/// Formats a time within the track as `hh:mm:ss.ttt`.
+fn format_time(t_90k: i64) -> String {
+    let ms = t_90k / (TIME_UNITS_PER_SEC / 1000);
+    format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60,
+            ms % 1000)
+}
+
+/// Escapes cue text, which may otherwise be interpreted as markup or (with a blank line or
+/// `-->`) end the cue early.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' | '\n' => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use base::clock::RealClocks;
+    use db::{self, recording};
+    use db::recording::TIME_UNITS_PER_SEC;
+    use db::testutil::{self, TestDb, TEST_CAMERA_ID};
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "00:00:00.000");
+        assert_eq!(format_time(90 * 1234), "00:00:01.234");
+        assert_eq!(format_time(TIME_UNITS_PER_SEC * (3600 + 61)), "01:01:01.000");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a <b> & c\n-->"), "a &lt;b&gt; &amp; c --&gt;");
+    }
+
+    #[test]
+    fn test_track() {
+        testutil::init();
+        let db = TestDb::new(RealClocks {});
+        let start = recording::Time(1430006400 * TIME_UNITS_PER_SEC);  // 2015-04-26 00:00:00 UTC
+        let sec = |s: i64| recording::Duration(s * TIME_UNITS_PER_SEC);
+        db.db.lock().add_event(&db::EventToInsert {
+            camera_id: TEST_CAMERA_ID,
+            type_: "motion".to_owned(),
+            time: start + sec(1) .. start + sec(5),
+            description: Some("person <1>".to_owned()),
+            score: None,
+        }).unwrap();
+        let mut b = TrackBuilder::new(true, true, ::mp4::SUBTITLE_TEMPLATE);
+        {
+            let l = db.db.lock();
+
+            // Two segments with a gap between them, the first starting mid-second.
+            let half = recording::Duration(TIME_UNITS_PER_SEC / 2);
+            b.append(&l, TEST_CAMERA_ID, start + half .. start + sec(2)).unwrap();
+            b.append(&l, TEST_CAMERA_ID, start + sec(4) .. start + sec(5)).unwrap();
+        }
+        assert_eq!(b.build(), "WEBVTT\n\
+                               \n\
+                               00:00:00.000 --> 00:00:00.500\n\
+                               2015-04-25 17:00:00 -0700\n\
+                               \n\
+                               00:00:00.500 --> 00:00:01.500\n\
+                               2015-04-25 17:00:01 -0700\n\
+                               \n\
+                               00:00:00.500 --> 00:00:01.500 line:0\n\
+                               person &lt;1&gt;\n\
+                               \n\
+                               00:00:01.500 --> 00:00:02.500\n\
+                               2015-04-25 17:00:04 -0700\n\
+                               \n\
+                               00:00:01.500 --> 00:00:02.500 line:0\n\
+                               person &lt;1&gt;\n");
+    }
+}
@@ -30,150 +30,117 @@
 
 extern crate hyper;
 
-use base::strutil;
-use body::{Body, BoxedError, wrap_error};
+use annotate;
+use bandwidth;
+use base::{sched, strutil};
+use body::{Body, BodyStream, BoxedError, Chunk, wrap_error};
+use clips;
 use core::borrow::Borrow;
 use core::str::FromStr;
+use crash;
 use db::{self, recording};
 use db::dir::SampleFileDir;
 use failure::Error;
 use fnv::FnvHashMap;
-use futures::future;
+use futures::{future, Future, Stream};
 use futures_cpupool;
 use json;
+use maintenance::{self, Maintenance};
+use memory;
 use http::{self, Request, Response, status::StatusCode};
 use http_serve;
 use http::header::{self, HeaderValue};
+use email;
+use embed;
+use export;
+use jobs;
+use l10n;
+use log;
+use logs;
+use mosaic;
 use mp4;
-use regex::Regex;
+use onvif;
+use parking_lot::{Condvar, Mutex};
+use relocate;
+use request::{self, Path, Segments};
+use serde::Serialize;
 use serde_json;
-use std::collections::HashMap;
+use snapshot;
+use sse;
+use tail;
+use teardown;
+use updates;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::cmp;
 use std::fs;
+use std::io::{self, Read, Seek, Write};
 use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use stream;
+use time;
 use url::form_urlencoded;
 use uuid::Uuid;
+use vtt;
 
-lazy_static! {
-    /// Regex used to parse the `s` query parameter to `view.mp4`.
-    /// As described in `design/api.md`, this is of the form
-    /// `START_ID[-END_ID][@OPEN_ID][.[REL_START_TIME]-[REL_END_TIME]]`.
-    static ref SEGMENTS_RE: Regex =
-        Regex::new(r"^(\d+)(-\d+)?(@\d+)?(?:\.(\d+)?-(\d+)?)?$").unwrap();
-}
+/// The maximum number of recordings returned by a single request to `/index`.
+const MAX_INDEX_RECORDINGS: i32 = 1000;
 
-enum Path {
-    TopLevel,                                    // "/api/"
-    InitSegment([u8; 20]),                       // "/api/init/<sha1>.mp4"
-    Camera(Uuid),                                // "/api/cameras/<uuid>/"
-    StreamRecordings(Uuid, db::StreamType),      // "/api/cameras/<uuid>/<type>/recordings"
-    StreamViewMp4(Uuid, db::StreamType),         // "/api/cameras/<uuid>/<type>/view.mp4"
-    StreamViewMp4Segment(Uuid, db::StreamType),  // "/api/cameras/<uuid>/<type>/view.m4s"
-    Static,                                      // "<other path>"
-    NotFound,
-}
+/// The maximum number of requests in a single `/api/batch`.
+const MAX_BATCH_REQUESTS: usize = 100;
 
-fn decode_path(path: &str) -> Path {
-    if !path.starts_with("/api/") {
-        return Path::Static;
-    }
-    let path = &path["/api".len()..];
-    if path == "/" {
-        return Path::TopLevel;
-    }
-    if path.starts_with("/init/") {
-        if path.len() != 50 || !path.ends_with(".mp4") {
-            return Path::NotFound;
-        }
-        if let Ok(sha1) = strutil::dehex(&path.as_bytes()[6..46]) {
-            return Path::InitSegment(sha1);
-        }
-        return Path::NotFound;
-    }
-    if !path.starts_with("/cameras/") {
-        return Path::NotFound;
-    }
-    let path = &path["/cameras/".len()..];
-    let slash = match path.find('/') {
-        None => { return Path::NotFound; },
-        Some(s) => s,
-    };
-    let uuid = &path[0 .. slash];
-    let path = &path[slash+1 .. ];
+/// The maximum number of packets returned by a single request to `/metadata`.
+const MAX_METADATA_PACKETS: usize = 10000;
 
-    // TODO(slamb): require uuid to be in canonical format.
-    let uuid = match Uuid::parse_str(uuid) {
-        Ok(u) => u,
-        Err(_) => { return Path::NotFound },
-    };
+/// The number of bytes returned from the end of the log file by `/api/admin/logs?file=true`.
+const LOG_FILE_TAIL_BYTES: u64 = 64 << 10;
 
-    if path.is_empty() {
-        return Path::Camera(uuid);
-    }
+/// The number of built `.mp4` files to keep in `ServiceInner::mp4_cache`, and for how long.
+const MP4_CACHE_ENTRIES: usize = 16;
+const MP4_CACHE_TTL_SEC: u64 = 60;
 
-    let slash = match path.find('/') {
-        None => { return Path::NotFound; },
-        Some(s) => s,
-    };
-    let (type_, path) = path.split_at(slash);
+/// The number of live snapshots to keep in `ServiceInner::snapshot_cache`, and for how long.
+/// This keeps many viewers of the same stream from each causing a request to the camera.
+const SNAPSHOT_CACHE_ENTRIES: usize = 32;
+const SNAPSHOT_CACHE_TTL_SEC: u64 = 2;
 
-    let type_ = match db::StreamType::parse(type_) {
-        None => { return Path::NotFound; },
-        Some(t) => t,
-    };
-    match path {
-        "/recordings" => Path::StreamRecordings(uuid, type_),
-        "/view.mp4" => Path::StreamViewMp4(uuid, type_),
-        "/view.m4s" => Path::StreamViewMp4Segment(uuid, type_),
-        _ => Path::NotFound,
-    }
-}
+/// The number of threads serving requests which block; see `request::Path::blocks`.
+const BLOCKING_THREADS: usize = 4;
 
-#[derive(Debug, Eq, PartialEq)]
-struct Segments {
-    ids: Range<i32>,
-    open_id: Option<u32>,
-    start_time: i64,
-    end_time: Option<i64>,
+/// A small cache of recently used values which expire after a fixed time.
+///
+/// This is used for built `.mp4` files, so that players which issue many range requests against
+/// the same URL (such as AVPlayer and ExoPlayer while seeking) don't cause the file's layout to be
+/// rebuilt for each one.
+struct ExpiringCache<T: Clone> {
+    capacity: usize,
+    ttl: Duration,
+    entries: VecDeque<(String, Instant, T)>,
 }
 
-impl Segments {
-    pub fn parse(input: &str) -> Result<Segments, ()> {
-        let caps = SEGMENTS_RE.captures(input).ok_or(())?;
-        let ids_start = i32::from_str(caps.get(1).unwrap().as_str()).map_err(|_| ())?;
-        let ids_end = match caps.get(2) {
-            Some(m) => i32::from_str(&m.as_str()[1..]).map_err(|_| ())?,
-            None => ids_start,
-        } + 1;
-        let open_id = match caps.get(3) {
-            Some(m) => Some(u32::from_str(&m.as_str()[1..]).map_err(|_| ())?),
-            None => None,
-        };
-        if ids_start < 0 || ids_end <= ids_start {
-            return Err(());
+impl<T: Clone> ExpiringCache<T> {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        ExpiringCache {
+            capacity,
+            ttl,
+            entries: VecDeque::with_capacity(capacity),
         }
-        let start_time = caps.get(4).map_or(Ok(0), |m| i64::from_str(m.as_str())).map_err(|_| ())?;
-        if start_time < 0 {
-            return Err(());
+    }
+
+    fn get(&mut self, key: &str, now: Instant) -> Option<T> {
+        let ttl = self.ttl;
+        self.entries.retain(|e| now.duration_since(e.1) < ttl);
+        self.entries.iter().find(|e| e.0 == key).map(|e| e.2.clone())
+    }
+
+    fn insert(&mut self, key: String, now: Instant, value: T) {
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
         }
-        let end_time = match caps.get(5) {
-            Some(v) => {
-                let e = i64::from_str(v.as_str()).map_err(|_| ())?;
-                if e <= start_time {
-                    return Err(());
-                }
-                Some(e)
-            },
-            None => None
-        };
-        Ok(Segments {
-            ids: ids_start .. ids_end,
-            open_id,
-            start_time,
-            end_time,
-        })
+        self.entries.push_back((key, now, value));
     }
 }
 
@@ -188,112 +155,2335 @@ struct UiFile {
 
 struct ServiceInner {
     db: Arc<db::Database>,
-    dirs_by_stream_id: Arc<FnvHashMap<i32, Arc<SampleFileDir>>>,
+    dirs: Arc<StreamDirs>,
     ui_files: HashMap<String, UiFile>,
     allow_origin: Option<HeaderValue>,
     pool: futures_cpupool::CpuPool,
+
+    /// Serves requests which block; see `request::Path::blocks`.
+    blocking_pool: futures_cpupool::CpuPool,
     time_zone_name: String,
+    allow_camera_reboot: bool,
+    allow_probe: bool,
+    sse: Arc<sse::Hub>,
+    push_public_key: Option<String>,
+    mosaic_ffmpeg: Option<PathBuf>,
+    snapshot_ffmpeg: Option<PathBuf>,
+    embed_signer: Option<embed::Signer>,
+    jobs: Option<Arc<jobs::Queue>>,
+    exporter: Option<Arc<export::Exporter>>,
+    watermark_exports: bool,
+    event_clips: Option<Arc<clips::EventClips>>,
+    maintenance: Arc<Maintenance>,
+    log_file: Option<PathBuf>,
+    bandwidth: Option<Arc<bandwidth::Accountant>>,
+    user_header: Option<header::HeaderName>,
+    ready: Arc<AtomicBool>,
+    catalog: Arc<l10n::Catalog>,
+
+    /// The shared tails of recordings being viewed live, for `view.m4s?tail=true`.
+    tails: Arc<tail::Hub>,
+    updates: Arc<updates::Hub>,
+
+    /// Recently built `.mp4` files, keyed by path and query. Only files whose contents can't
+    /// change (those without uncommitted recordings or event chapters) are cached.
+    mp4_cache: Mutex<ExpiringCache<mp4::File>>,
+
+    /// Recently taken live snapshots, keyed by path.
+    snapshot_cache: Mutex<ExpiringCache<Arc<Vec<u8>>>>,
+
+    /// Live snapshots being taken, keyed as in `snapshot_cache`. When locking both, lock this
+    /// one first.
+    pending_snapshots: Mutex<HashMap<String, Arc<PendingSnapshot>>>,
+}
+
+/// A live snapshot being taken, which other requests for the same stream wait on rather than
+/// each making their own request of the camera.
+#[derive(Default)]
+struct PendingSnapshot {
+    /// The outcome, once known: the JPEG, or `None` if it couldn't be taken.
+    result: Mutex<Option<Option<Arc<Vec<u8>>>>>,
+    cond: Condvar,
+}
+
+/// Returns an `application/json` response with the given status and body. If the request has a
+/// `fields` parameter, only the selected fields are sent; see `json::Fields`.
+fn serve_json<T: Serialize>(req: &Request<::hyper::Body>, status: StatusCode, body: &T)
+                            -> Result<Response<Body>, Error> {
+    let fields = match req.uri().query() {
+        None => None,
+        Some(q) => request::parse_fields(q)?,
+    };
+    let (mut resp, writer) = http_serve::streaming_body(req).build();
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if let Some(mut w) = writer {
+        match fields {
+            None => serde_json::to_writer(&mut w, body)?,
+            Some(f) => {
+                let mut v = serde_json::to_value(body)?;
+                f.retain(&mut v);
+                serde_json::to_writer(&mut w, &v)?;
+            },
+        }
+    }
+    Ok(resp)
+}
+
+/// The English message of a `plain_response`, kept so `localize` can translate it.
+struct Message(&'static str);
+
+/// Returns a `text/plain` response with the given status and message. Only static messages
+/// can be localized.
+fn plain_response<M: Into<Cow<'static, str>>>(status: StatusCode, msg: M) -> Response<Body> {
+    let (body, msg): (Body, _) = match msg.into() {
+        Cow::Borrowed(m) => (m.as_bytes().into(), Some(Message(m))),
+        Cow::Owned(m) => (m.into_bytes().into(), None),
+    };
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    if let Some(m) = msg {
+        resp.extensions_mut().insert(m);
+    }
+    resp
+}
+
+/// Returns the locale for the given request, as negotiated from its `Accept-Language` header.
+fn request_locale<'a>(catalog: &'a l10n::Catalog, req: &Request<::hyper::Body>)
+                      -> l10n::Locale<'a> {
+    catalog.negotiate(req.headers().get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()))
+}
+
+/// Translates the body of a `plain_response` into the request's locale, if possible.
+fn localize(catalog: &l10n::Catalog, req: &Request<::hyper::Body>, resp: &mut Response<Body>) {
+    let msg = match resp.extensions().get::<Message>() {
+        None => return,
+        Some(m) => m.0,
+    };
+    let locale = request_locale(catalog, req);
+    let translated = locale.get(msg);
+    if translated == msg {
+        return;
+    }
+    *resp.body_mut() = translated.as_bytes().to_vec().into();
+    if let Ok(v) = HeaderValue::from_str(locale.tag()) {
+        resp.headers_mut().insert(header::CONTENT_LANGUAGE, v);
+    }
 }
 
 impl ServiceInner {
+    /// Serves a request, adding headers and bandwidth accounting to `route`'s response.
+    fn serve(&self, path: Path, req: &Request<::hyper::Body>)
+             -> Result<Response<Body>, BoxedError> {
+        let camera_uuid = path.camera_uuid();
+        let mut res = self.route(path, req);
+        let over_budget = match res {
+            Err(ref e) => e.downcast_ref::<memory::OverBudget>().map(|e| e.to_string()),
+            Ok(_) => None,
+        };
+        if let Some(e) = over_budget {
+            warn!("{}: {}", req.uri(), e);
+            let mut resp = plain_response(StatusCode::SERVICE_UNAVAILABLE,
+                                          "server is busy; try again later");
+            resp.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from_static("10"));
+            res = Ok(resp);
+        }
+        if let Ok(ref mut resp) = res {
+            localize(&self.catalog, req, resp);
+            if let Some(ref o) = self.allow_origin {
+                resp.headers_mut().insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, o.clone());
+            }
+        }
+        if let Some(ref a) = self.bandwidth {
+            let user_name = self.user_name(req).unwrap_or("").to_owned();
+            res = res.map(|r| r.map(|b| {
+                bandwidth::Accountant::wrap(a, b, user_name, camera_uuid)
+            }));
+        }
+        res.map_err(wrap_error)
+    }
+
+    /// Serves a request to the given (already decoded) path.
+    fn route(&self, path: Path, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        if let Some(resp) = self.check_tenant(&path, req)? {
+            return Ok(resp);
+        }
+        match path {
+            Path::InitSegment(sha1) => self.init_segment(sha1, req),
+            Path::Healthz => self.healthz(),
+            Path::Readyz => self.readyz(),
+            Path::TopLevel => self.top_level(req),
+            Path::Probe => self.probe(req),
+            Path::Camera(uuid) => self.camera(req, uuid),
+            Path::CameraEvents(uuid) => self.camera_events(req, uuid),
+            Path::CameraReboot(uuid) => self.camera_reboot(req, uuid),
+            Path::CameraCredentials(uuid) => self.camera_credentials(req, uuid),
+            Path::CameraSnapshots(uuid) => self.camera_snapshots(req, uuid),
+            Path::CameraSnapshot(uuid) => self.camera_snapshot(req, uuid),
+            Path::EventStream => self.event_stream(),
+            Path::EventClip(id) => self.event_clip(req, id),
+            Path::EventSnapshot(id) => self.event_snapshot(req, id),
+            Path::Metrics => self.metrics(req),
+            Path::Bandwidth => self.bandwidth(req),
+            Path::Viewers => self.viewers(req),
+            Path::Maintenance => self.maintenance(req),
+            Path::Logs => self.logs(req),
+            Path::Push => self.push(req),
+            Path::Embeds => self.embeds(req),
+            Path::EmbedPage(token) => self.embed_page(&token),
+            Path::EmbedMp4(token) => self.embed_mp4(req, &token),
+            Path::UserPreferences(id) => self.user_preferences(req, id),
+            Path::StreamMove(id) => self.stream_move(req, id),
+            Path::Mosaic => self.mosaic(req),
+            Path::Exports => self.exports(req),
+            Path::ExportFile(id, c) => self.export_file(req, id, c),
+            Path::Jobs => self.jobs(req),
+            Path::Job(id) => self.job(req, id),
+            Path::Holds => self.holds(req),
+            Path::Hold(id) => self.hold(req, id),
+            Path::Layouts => self.layouts(req),
+            Path::Layout(id) => self.layout(req, id),
+            Path::Incidents => self.incidents(req),
+            Path::Incident(id) => self.incident(req, id),
+            Path::Batch => self.batch(req),
+            Path::Coverage => self.coverage(req),
+            Path::StreamRecordings(uuid, type_) => self.stream_recordings(req, uuid, type_),
+            Path::StreamRecordingUpdates(uuid, type_) => {
+                self.stream_recording_updates(req, uuid, type_)
+            },
+            Path::StreamRecordingArchive(uuid, type_) => {
+                self.stream_recording_archive(req, uuid, type_)
+            },
+            Path::StreamIndex(uuid, type_) => self.stream_index(req, uuid, type_),
+            Path::StreamNotes(uuid, type_) => self.stream_notes(req, uuid, type_),
+            Path::StreamViewMp4(uuid, type_) => {
+                self.stream_view_mp4(req, uuid, type_, mp4::Type::Normal)
+            },
+            Path::StreamViewMp4Segment(uuid, type_) => {
+                self.stream_view_mp4(req, uuid, type_, mp4::Type::MediaSegment)
+            },
+            Path::StreamViewVtt(uuid, type_) => self.stream_view_vtt(req, uuid, type_),
+            Path::StreamSnapshot(uuid, type_) => self.stream_snapshot(uuid, type_),
+            Path::StreamThumbnails(uuid, type_) => self.stream_thumbnails(req, uuid, type_),
+            Path::StreamThumbnail(uuid, type_) => self.stream_thumbnail(req, uuid, type_),
+            Path::StreamMetadata(uuid, type_) => self.stream_metadata(req, uuid, type_),
+            Path::StreamExportEmail(uuid, type_) => {
+                self.stream_export_email(req, uuid, type_)
+            },
+            Path::StreamEnable(uuid, type_) => self.stream_set_paused(req, uuid, type_, false),
+            Path::StreamDisable(uuid, type_) => self.stream_set_paused(req, uuid, type_, true),
+            Path::StreamEncoder(uuid, type_) => self.stream_encoder(req, uuid, type_),
+            Path::StreamUsage(uuid, type_) => self.stream_usage(req, uuid, type_),
+            Path::NotFound => self.not_found(),
+            Path::Static => self.static_file(req),
+        }
+    }
+
+    /// Returns the name of the requesting user, as given by `--user-header`, if known.
+    fn user_name<'r>(&self, req: &'r Request<::hyper::Body>) -> Option<&'r str> {
+        self.user_header.as_ref()
+                        .and_then(|h| req.headers().get(h))
+                        .and_then(|v| v.to_str().ok())
+    }
+
+    /// Returns the requesting user, as named by `--user-header`, if known.
+    fn user(&self, req: &Request<::hyper::Body>) -> Result<Option<db::User>, Error> {
+        match self.user_name(req) {
+            None => Ok(None),
+            Some(u) => self.db.lock().get_user(u),
+        }
+    }
+
+    /// Returns an error response if the requesting user belongs to a tenant and `path` isn't
+    /// limited to that tenant's cameras. Other cameras are reported as not found, so their
+    /// existence isn't revealed. Requests which span cameras (other than `/api/`, which is
+    /// filtered) are forbidden. Exports are limited by their `camera` parameter and export jobs
+    /// (and their output) by the exported stream's camera.
+    fn check_tenant(&self, path: &Path, req: &Request<::hyper::Body>)
+                    -> Result<Option<Response<Body>>, Error> {
+        let (user_id, tenant_id) = match self.user(req)? {
+            Some(db::User { id, tenant_id: Some(t), .. }) => (id, t),
+            _ => return Ok(None),
+        };
+        let db = self.db.lock();
+        let camera_id = match *path {
+            Path::TopLevel | Path::Batch | Path::InitSegment(_) | Path::Healthz | Path::Readyz |
+            Path::Static | Path::NotFound | Path::EmbedPage(_) | Path::EmbedMp4(_) => {
+                return Ok(None);
+            },
+            Path::UserPreferences(id) if id == user_id => return Ok(None),
+            Path::EventClip(id) | Path::EventSnapshot(id) => db.get_event(id)?.map(|e| e.camera_id),
+            Path::Exports => {
+                let mut camera = None;
+                if let Some(q) = req.uri().query() {
+                    for (key, value) in request::parse_query(q, &["redact"])? {
+                        if key == "camera" {
+                            camera = Some(request::parse_uuid(&value)?);
+                        }
+                    }
+                }
+                match camera {
+                    None => return Ok(None),  // rejected by exports.
+                    Some(uuid) => db.get_camera(uuid).map(|c| c.id),
+                }
+            },
+            Path::Job(id) | Path::ExportFile(id, _) => {
+                db.list_jobs()?
+                  .into_iter()
+                  .find(|j| j.uuid == id && j.type_ == "export")
+                  .and_then(|j| serde_json::from_str::<export::Params>(&j.params).ok())
+                  .and_then(|p| db.streams_by_id().get(&p.stream_id).map(|s| s.camera_id))
+            },
+            ref p => match p.camera_uuid() {
+                Some(uuid) => db.get_camera(uuid).map(|c| c.id),
+                None => return Ok(Some(plain_response(StatusCode::FORBIDDEN,
+                                                      "not allowed for tenant users"))),
+            },
+        };
+        match camera_id.and_then(|id| db.cameras_by_id().get(&id)) {
+            Some(c) if c.tenant_id == Some(tenant_id) => Ok(None),
+            _ => Ok(Some(plain_response(StatusCode::NOT_FOUND, "not found"))),
+        }
+    }
+
+    /// Serves `/api/batch`, running several `GET` requests of JSON API endpoints and returning
+    /// their responses in one body.
+    fn batch(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let mut paths = Vec::new();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &["r"])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "r" => paths.push(value.to_owned()),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        if paths.len() > MAX_BATCH_REQUESTS {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "too many requests in batch"));
+        }
+        let mut responses = Vec::with_capacity(paths.len());
+        for path in paths {
+            let (status, body) = match self.batch_one(req, &path) {
+                Ok(r) => r,
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR,
+                           serde_json::Value::String(e.to_string())),
+            };
+            responses.push(json::BatchResponse {
+                path,
+                status: status.as_u16(),
+                body,
+            });
+        }
+        serve_json(req, StatusCode::OK, &json::Batch { responses })
+    }
+
+    /// Runs a single request of a batch, returning its status and body. JSON bodies are
+    /// included as-is; others (such as plain-text errors) as a string. The request is made as
+    /// the user making `outer`.
+    fn batch_one(&self, outer: &Request<::hyper::Body>, path: &str)
+                 -> Result<(StatusCode, serde_json::Value), Error> {
+        let mut req = Request::get(path).body(::hyper::Body::empty())?;
+        if let Some(ref h) = self.user_header {
+            if let Some(v) = outer.headers().get(h) {
+                req.headers_mut().insert(h.clone(), v.clone());
+            }
+        }
+        let resp = match decode_path(req.uri().path(), &self.db) {
+            Path::Static | Path::NotFound | Path::Healthz | Path::Readyz => self.not_found()?,
+            Path::Batch | Path::EventStream | Path::EventClip(_) | Path::EventSnapshot(_) |
+            Path::Mosaic | Path::Metrics | Path::InitSegment(_) | Path::ExportFile(..) |
+            Path::StreamRecordingUpdates(..) |
+            Path::StreamViewMp4(..) | Path::StreamViewMp4Segment(..) |
+            Path::StreamViewVtt(..) | Path::StreamSnapshot(..) | Path::StreamThumbnail(..) |
+            Path::CameraSnapshot(_) | Path::EmbedPage(_) | Path::EmbedMp4(_) => {
+                plain_response(StatusCode::BAD_REQUEST, "not allowed in a batch")
+            },
+            ref p if p.blocks() => plain_response(StatusCode::BAD_REQUEST,
+                                                  "not allowed in a batch"),
+            p => self.route(p, &req)?,
+        };
+        let status = resp.status();
+        let is_json = resp.headers().get(header::CONTENT_TYPE)
+                          .map(|t| t == "application/json").unwrap_or(false);
+        let data = resp.into_body()
+                       .into_stream()
+                       .fold(Vec::new(), |mut v, c| {
+                           v.extend_from_slice(::bytes::Buf::bytes(&c));
+                           Ok::<_, BoxedError>(v)
+                       })
+                       .wait()
+                       .map_err(|e| format_err!("unable to read response: {}", e))?;
+        let body = if is_json {
+            serde_json::from_slice(&data)?
+        } else {
+            serde_json::Value::String(String::from_utf8_lossy(&data).into_owned())
+        };
+        Ok((status, body))
+    }
+
     fn not_found(&self) -> Result<Response<Body>, Error> {
-        let body: Body = (&b"not found"[..]).into();
-        Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .header(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"))
-            .body(body)?)
+        Ok(plain_response(StatusCode::NOT_FOUND, "not found"))
+    }
+
+    /// Serves `/healthz`, a liveness check for container orchestration. This succeeds as long as
+    /// the database lock can be taken, so a wedged server is restarted.
+    fn healthz(&self) -> Result<Response<Body>, Error> {
+        drop(self.db.lock());
+        Ok(plain_response(StatusCode::OK, "ok"))
+    }
+
+    /// Serves `/readyz`, a readiness check for container orchestration. This fails before startup
+    /// is complete and once shutdown has begun, so traffic is routed elsewhere.
+    fn readyz(&self) -> Result<Response<Body>, Error> {
+        if !self.ready.load(Ordering::SeqCst) {
+            return Ok(plain_response(StatusCode::SERVICE_UNAVAILABLE, "not ready"));
+        }
+        Ok(plain_response(StatusCode::OK, "ok"))
+    }
+
+    fn top_level(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let mut days = false;
+        let mut filter = json::CameraFilter::default();
+        let mut tenant = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &["label"])? {
+                let (key, value) : (_, &str) = (key.borrow(), value.borrow());
+                match key {
+                    "days" => days = value == "true",
+                    "label" => filter.labels.push(json::LabelFilter::parse(value)),
+                    "tenant" => match request::parse_uuid(value) {
+                        Ok(u) => tenant = Some(u),
+                        Err(_) => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                                           "bad tenant uuid")),
+                    },
+                    _ => {},
+                };
+            }
+        }
+        if let Some(uuid) = tenant {
+            match self.db.lock().get_tenant(uuid) {
+                None => return self.not_found(),
+                Some(t) => filter.tenant_id = Some(t.id),
+            }
+        }
+        let user_tenant_id = self.user(req)?.and_then(|u| u.tenant_id);
+        if let Some(id) = user_tenant_id {
+            if filter.tenant_id.map(|t| t != id).unwrap_or(false) {
+                return self.not_found();
+            }
+            filter.tenant_id = Some(id);
+        }
+
+        let db = self.db.lock();
+        let tenants = db.tenants_by_id()
+                        .values()
+                        .filter(|t| filter.tenant_id.map(|id| id == t.id).unwrap_or(true))
+                        .map(|t| json::Tenant::wrap(t, &db))
+                        .collect();
+        serve_json(req, StatusCode::OK, &json::TopLevel {
+                time_zone_name: &self.time_zone_name,
+                cameras: (&db, days, user_tenant_id.is_none(), &filter),
+                tenants,
+                export_presets: db.export_presets_by_id().values()
+                                  .map(json::ExportPreset::wrap).collect(),
+                last_crash: crash::last(),
+                startup: json::StartupReport::new(&db),
+        })
+    }
+
+    fn probe(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        if !self.allow_probe {
+            return Ok(plain_response(StatusCode::FORBIDDEN,
+                                     "probing is not enabled on this server"));
+        }
+        let mut url = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                if key == "url" {
+                    url = Some(value.into_owned());
+                }
+            }
+        }
+        let url = match url {
+            Some(ref u) if u.starts_with("rtsp://") => u,
+            _ => return Ok(plain_response(StatusCode::BAD_REQUEST, "rtsp:// url expected")),
+        };
+        let p = stream::probe(url, &[])?;
+        serve_json(req, StatusCode::OK, &json::Probe {
+            video_codec: p.video_codec,
+            width: p.width,
+            height: p.height,
+            frames_per_sec: p.frames_per_sec,
+            bits_per_sec: p.bits_per_sec,
+            audio_codec: p.audio_codec,
+            unsupported_reason: p.unsupported_reason.as_ref().map(String::as_str),
+        })
+    }
+
+    fn event_stream(&self) -> Result<Response<Body>, Error> {
+        let mut resp = Response::new(self.sse.subscribe().into());
+        resp.headers_mut().insert(header::CONTENT_TYPE,
+                                  HeaderValue::from_static("text/event-stream"));
+        resp.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        Ok(resp)
+    }
+
+    fn mosaic(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let ffmpeg = match self.mosaic_ffmpeg {
+            None => return Ok(plain_response(StatusCode::NOT_FOUND,
+                                             "mosaics are not enabled on this server")),
+            Some(ref f) => f,
+        };
+        let mut uuids = Vec::new();
+        let mut stream_type = db::StreamType::SUB;
+        let mut tile = mosaic::TileSize::default();
+        let mut fps = 5;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &["camera"])? {
+                let (key, value) : (_, &str) = (key.borrow(), value.borrow());
+                match key {
+                    "camera" => uuids.push(request::parse_uuid(value)?),
+                    "stream" => stream_type = db::StreamType::parse(value).ok_or_else(
+                        || format_err!("invalid stream {:?}", value))?,
+                    "width" => tile.width = u32::from_str(value)?,
+                    "height" => tile.height = u32::from_str(value)?,
+                    "fps" => fps = u32::from_str(value)?,
+                    _ => bail!("parameter {} not understood", key),
+                }
+            }
+        }
+        if tile.width == 0 || tile.width > 1920 || tile.height == 0 || tile.height > 1080 ||
+           fps == 0 || fps > 30 {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "invalid tile size or fps"));
+        }
+        let urls = {
+            let db = self.db.lock();
+            let cameras: Vec<&db::Camera> = if uuids.is_empty() {
+                // Mosaics pass RTSP-specific options to ffmpeg, so skip HTTP sources.
+                db.cameras_by_id().values()
+                  .filter(|c| c.streams[stream_type.index()].map(|id| {
+                      db.streams_by_id()[&id].source == db::StreamSource::Rtsp
+                  }).unwrap_or(false))
+                  .take(mosaic::MAX_INPUTS)
+                  .collect()
+            } else {
+                let mut cameras = Vec::with_capacity(uuids.len());
+                for u in &uuids {
+                    match db.get_camera(*u) {
+                        None => return self.not_found(),
+                        Some(c) => cameras.push(c),
+                    }
+                }
+                cameras
+            };
+            let mut urls = Vec::with_capacity(cameras.len());
+            for c in cameras {
+                let s = match c.streams[stream_type.index()] {
+                    None => bail!("camera {} has no {} stream", c.uuid, stream_type),
+                    Some(id) => &db.streams_by_id()[&id],
+                };
+                if s.source != db::StreamSource::Rtsp {
+                    bail!("camera {}'s {} stream isn't RTSP", c.uuid, stream_type);
+                }
+                urls.push(stream::rtsp_url(&c.username, &c.password, &c.host, &s.rtsp_path));
+            }
+            urls
+        };
+        if urls.is_empty() || urls.len() > mosaic::MAX_INPUTS {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "too few or too many cameras"));
+        }
+        let body = match mosaic::start(ffmpeg, &urls, tile, fps)? {
+            None => return Ok(plain_response(StatusCode::SERVICE_UNAVAILABLE,
+                                             "too many mosaics are running; try again later")),
+            Some(b) => b,
+        };
+        let mut resp = Response::new(body.into());
+        resp.headers_mut().insert(header::CONTENT_TYPE,
+                                  HeaderValue::from_static(mosaic::CONTENT_TYPE));
+        resp.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        Ok(resp)
+    }
+
+    /// Serves `/api/users/<id>/preferences`. A `POST` sets a single preference, optionally
+    /// conditional on its current version, and returns it.
+    fn user_preferences(&self, req: &Request<::hyper::Body>, user_id: i32)
+                        -> Result<Response<Body>, Error> {
+        let body = if *req.method() == http::Method::POST {
+            let mut key = None;
+            let mut value = None;
+            let mut version = None;
+            if let Some(q) = req.uri().query() {
+                for (k, v) in request::parse_query(q, &[])? {
+                    let (k, v) = (k.borrow(), v.borrow());
+                    match k {
+                        "key" => key = Some(v.to_owned()),
+                        "value" => value = Some(v.to_owned()),
+                        "version" => version = Some(request::parse_decimal(v)?),
+                        _ => bail!("parameter {} not understood", k),
+                    }
+                };
+            }
+            let (key, value) = match (key, value) {
+                (Some(k), Some(v)) => (k, v),
+                _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                              "key and value are required")),
+            };
+            let parsed: serde_json::Value = match serde_json::from_str(&value) {
+                Ok(v) => v,
+                Err(_) => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                                   "value must be JSON")),
+            };
+            let mut db = self.db.lock();
+            if db.list_user_preferences(user_id)?.is_none() {
+                return self.not_found();
+            }
+            let version = match db.set_user_preference(user_id, &key, &value, version)? {
+                None => return Ok(plain_response(StatusCode::CONFLICT,
+                                                 "preference has been changed since version")),
+                Some(v) => v,
+            };
+            serde_json::to_value(json::UserPreference { value: parsed, version })?
+        } else {
+            let prefs = match self.db.lock().list_user_preferences(user_id)? {
+                None => return self.not_found(),
+                Some(p) => p,
+            };
+            serde_json::to_value(json::UserPreferences::wrap(prefs)?)?
+        };
+        serve_json(req, StatusCode::OK, &body)
+    }
+
+    fn push(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let public_key = match self.push_public_key {
+            None => return Ok(plain_response(StatusCode::NOT_FOUND,
+                                             "push notifications are not enabled on this server")),
+            Some(ref k) => k,
+        };
+        if *req.method() == http::Method::GET || *req.method() == http::Method::HEAD {
+            return serve_json(req, StatusCode::OK,
+                              &json::Push { application_server_key: public_key });
+        }
+        let mut sub = db::PushSubscription {
+            endpoint: String::new(),
+            p256dh: String::new(),
+            auth: String::new(),
+        };
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                match key.borrow() {
+                    "endpoint" => sub.endpoint = value.into_owned(),
+                    "p256dh" => sub.p256dh = value.into_owned(),
+                    "auth" => sub.auth = value.into_owned(),
+                    _ => {},
+                };
+            }
+        }
+        if !sub.endpoint.starts_with("https://") {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "https:// endpoint expected"));
+        }
+        match *req.method() {
+            http::Method::POST => {
+                self.db.lock().add_push_subscription(&sub)?;
+                Ok(plain_response(StatusCode::NO_CONTENT, ""))
+            },
+            http::Method::DELETE => {
+                if !self.db.lock().delete_push_subscription(&sub.endpoint)? {
+                    return self.not_found();
+                }
+                Ok(plain_response(StatusCode::NO_CONTENT, ""))
+            },
+            _ => Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED,
+                                   "GET, POST, or DELETE expected")),
+        }
+    }
+
+    fn camera(&self, req: &Request<::hyper::Body>, uuid: Uuid) -> Result<Response<Body>, Error> {
+        if *req.method() == http::Method::DELETE {
+            return self.camera_delete(req, uuid);
+        }
+        let include_dirs = self.user(req)?.map(|u| u.tenant_id.is_none()).unwrap_or(true);
+        let db = self.db.lock();
+        let camera = db.get_camera(uuid)
+                       .ok_or_else(|| format_err!("no such camera {}", uuid))?;
+        serve_json(req, StatusCode::OK, &json::Camera::wrap(camera, &db, true, include_dirs)?)
+    }
+
+    /// Serves `DELETE /api/cameras/<uuid>/`, which starts a `deleteCamera` job removing the
+    /// camera and everything recorded from it. As a guard, `confirm` must be the camera's short
+    /// name.
+    fn camera_delete(&self, req: &Request<::hyper::Body>, uuid: Uuid)
+                     -> Result<Response<Body>, Error> {
+        let jobs = match self.jobs {
+            Some(ref j) if j.has_handler("deleteCamera") => j,
+            _ => return Ok(plain_response(StatusCode::NOT_FOUND,
+                                          "camera deletion is not enabled on this server")),
+        };
+        let mut confirm = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "confirm" => confirm = Some(value.to_owned()),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let short_name = match self.db.lock().get_camera(uuid) {
+            None => return self.not_found(),
+            Some(c) => c.short_name.clone(),
+        };
+        if confirm.as_ref() != Some(&short_name) {
+            return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                     "confirm must be the camera's short name"));
+        }
+        let in_progress = jobs.list()?.iter().any(|j| {
+            j.type_ == "deleteCamera" && !j.state.is_finished() &&
+            serde_json::from_str::<teardown::Params>(&j.params).ok()
+                .map(|p| p.camera == uuid).unwrap_or(false)
+        });
+        if in_progress {
+            return Ok(plain_response(StatusCode::CONFLICT, "camera is already being deleted"));
+        }
+        let user = self.user_name(req).unwrap_or("unknown user").to_owned();
+        info!(target: "audit", "{} requested deletion of camera {} ({})", user, short_name, uuid);
+        let job = jobs.create("deleteCamera", &teardown::Params { camera: uuid, user })?;
+        self.job_response(req, StatusCode::ACCEPTED, &job)
+    }
+
+    /// Serves `/api/streams/<id>/move`. A `POST` starts a `moveStream` job moving the stream's
+    /// recordings to the sample file directory with the path given as `dir`.
+    fn stream_move(&self, req: &Request<::hyper::Body>, stream_id: i32)
+                   -> Result<Response<Body>, Error> {
+        let jobs = match self.jobs {
+            Some(ref j) if j.has_handler("moveStream") => j,
+            _ => return Ok(plain_response(StatusCode::NOT_FOUND,
+                                          "moving streams is not enabled on this server")),
+        };
+        if *req.method() != http::Method::POST {
+            return Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected"));
+        }
+        let mut dir = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "dir" => dir = Some(value.to_owned()),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let dir = match dir {
+            None => return Ok(plain_response(StatusCode::BAD_REQUEST, "dir is required")),
+            Some(d) => d,
+        };
+        let (name, dir_id) = {
+            let l = self.db.lock();
+            let s = match l.streams_by_id().get(&stream_id) {
+                None => return self.not_found(),
+                Some(s) => s,
+            };
+            let dir_id = match l.sample_file_dirs_by_id().values().find(|d| d.path == dir) {
+                None => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                                 "no sample file dir has the given path")),
+                Some(d) => d.id,
+            };
+            if s.sample_file_dir_id == Some(dir_id) {
+                return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                         "stream is already in the given dir"));
+            }
+            if s.sample_file_dir_id.is_none() || s.mirror_sample_file_dir_id == Some(dir_id) {
+                return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                         "stream can't be moved to the given dir"));
+            }
+            let c = &l.cameras_by_id()[&s.camera_id];
+            (format!("{}/{}", c.short_name, s.type_.as_str()), dir_id)
+        };
+        let in_progress = jobs.list()?.iter().any(|j| {
+            j.type_ == "moveStream" && !j.state.is_finished() &&
+            serde_json::from_str::<relocate::Params>(&j.params).ok()
+                .map(|p| p.stream == stream_id).unwrap_or(false)
+        });
+        if in_progress {
+            return Ok(plain_response(StatusCode::CONFLICT, "stream is already being moved"));
+        }
+        let user = self.user_name(req).unwrap_or("unknown user").to_owned();
+        info!(target: "audit", "{} requested move of stream {} to {}", user, name, dir);
+        let job = jobs.create("moveStream", &relocate::Params { stream: stream_id, dir: dir_id,
+                                                                user })?;
+        self.job_response(req, StatusCode::ACCEPTED, &job)
+    }
+
+    fn camera_events(&self, req: &Request<::hyper::Body>, uuid: Uuid)
+                     -> Result<Response<Body>, Error> {
+        let mut time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => time.start = recording::Time::parse(value)?,
+                    "endTime90k" => time.end = recording::Time::parse(value)?,
+                    _ => {},
+                }
+            };
+        }
+        let mut out = json::ListEvents{events: Vec::new()};
+        {
+            let db = self.db.lock();
+            let camera = db.get_camera(uuid)
+                           .ok_or_else(|| format_err!("no such camera {}", uuid))?;
+            db.list_events(camera.id, time, &mut |row| {
+                out.events.push(json::Event {
+                    id: row.id,
+                    type_: row.type_,
+                    start_time_90k: row.time.start.0,
+                    end_time_90k: row.time.end.0,
+                    description: row.description,
+                    score: row.score,
+                });
+                Ok(())
+            })?;
+        }
+        serve_json(req, StatusCode::OK, &out)
+    }
+
+    fn camera_reboot(&self, req: &Request<::hyper::Body>, uuid: Uuid)
+                     -> Result<Response<Body>, Error> {
+        if *req.method() != http::Method::POST {
+            return Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected"));
+        }
+        if !self.allow_camera_reboot {
+            warn!(target: "audit", "refused reboot of camera {}: not enabled", uuid);
+            return Ok(plain_response(StatusCode::FORBIDDEN,
+                                     "camera reboot is not enabled on this server"));
+        }
+        let (short_name, host, username, password) = {
+            let db = self.db.lock();
+            let c = match db.get_camera(uuid) {
+                None => return self.not_found(),
+                Some(c) => c,
+            };
+            (c.short_name.clone(), c.host.clone(), c.username.clone(), c.password.clone())
+        };
+        info!(target: "audit", "rebooting camera {} ({}) at {}", short_name, uuid, host);
+        let message = match onvif::reboot(&host, &username, &password) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!(target: "audit", "reboot of camera {} failed: {}", short_name, e);
+                return Err(e);
+            },
+        };
+        info!(target: "audit", "camera {} accepted reboot: {:?}", short_name, message);
+        serve_json(req, StatusCode::OK, &json::CameraReboot { message: &message })
+    }
+
+    /// Serves `/api/cameras/<uuid>/credentials`. A `POST` rotates the username and password used
+    /// to access the camera, keeping the old ones; with `rollback=true`, it restores them instead.
+    /// Streamers pick up the change at their next key frame.
+    fn camera_credentials(&self, req: &Request<::hyper::Body>, uuid: Uuid)
+                          -> Result<Response<Body>, Error> {
+        if *req.method() != http::Method::POST {
+            return Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected"));
+        }
+        let mut username = None;
+        let mut password = None;
+        let mut rollback = false;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "username" => username = Some(value.to_owned()),
+                    "password" => password = Some(value.to_owned()),
+                    "rollback" => rollback = value == "true",
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let user = self.user_name(req).unwrap_or("unknown user");
+        let mut db = self.db.lock();
+        let (id, short_name, current_username) = match db.get_camera(uuid) {
+            None => return self.not_found(),
+            Some(c) => (c.id, c.short_name.clone(), c.username.clone()),
+        };
+        match (rollback, password) {
+            (true, None) if username.is_none() => {
+                if db.cameras_by_id()[&id].previous_credentials.is_none() {
+                    return Ok(plain_response(StatusCode::CONFLICT, "no previous credentials"));
+                }
+                db.rollback_camera_credentials(id)?;
+                info!(target: "audit", "{} rolled back credentials of camera {}", user, short_name);
+            },
+            (false, Some(p)) => {
+                let username = username.unwrap_or(current_username);
+                db.rotate_camera_credentials(id, username.clone(), p)?;
+                info!(target: "audit", "{} rotated credentials of camera {} to user {:?}",
+                      user, short_name, username);
+            },
+            _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                          "expected password or rollback=true")),
+        }
+        Ok(plain_response(StatusCode::NO_CONTENT, ""))
+    }
+
+    /// Serves `/api/cameras/<uuid>/snapshots`, the camera's scheduled snapshots within the given
+    /// time range.
+    fn camera_snapshots(&self, req: &Request<::hyper::Body>, uuid: Uuid)
+                        -> Result<Response<Body>, Error> {
+        let mut time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => time.start = recording::Time::parse(value)?,
+                    "endTime90k" => time.end = recording::Time::parse(value)?,
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let out = {
+            let db = self.db.lock();
+            let camera_id = match db.get_camera(uuid) {
+                None => return self.not_found(),
+                Some(c) => c.id,
+            };
+            json::ListScheduledSnapshots {
+                snapshots: db.list_scheduled_snapshots(camera_id, time)?
+                             .iter().map(json::Thumbnail::wrap).collect(),
+            }
+        };
+        serve_json(req, StatusCode::OK, &out)
+    }
+
+    /// Serves `/api/cameras/<uuid>/snapshot.jpg`, the latest scheduled snapshot at or before the
+    /// given time.
+    fn camera_snapshot(&self, req: &Request<::hyper::Body>, uuid: Uuid)
+                       -> Result<Response<Body>, Error> {
+        let mut time = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "time90k" => time = Some(recording::Time::parse(value)?),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let time = match time {
+            None => return Ok(plain_response(StatusCode::BAD_REQUEST, "time90k is required")),
+            Some(t) => t,
+        };
+        let (t, jpeg) = {
+            let db = self.db.lock();
+            let camera_id = match db.get_camera(uuid) {
+                None => return self.not_found(),
+                Some(c) => c.id,
+            };
+            match db.get_scheduled_snapshot(camera_id, time)? {
+                None => return self.not_found(),
+                Some(t) => t,
+            }
+        };
+        let mut resp = Response::new(jpeg.into());
+        {
+            let h = resp.headers_mut();
+            h.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+            h.insert("x-snapshot-time-90k", HeaderValue::from_str(&t.0.to_string())?);
+        }
+        Ok(resp)
+    }
+
+    /// Serves `/api/coverage`, the oldest and newest recorded times of each stream. These come
+    /// from in-memory aggregates, so this is much cheaper than querying `/recordings`.
+    fn coverage(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let mut uuids = Vec::new();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "cameras" => {
+                        for u in value.split(',').filter(|u| !u.is_empty()) {
+                            uuids.push(request::parse_uuid(u)?);
+                        }
+                    },
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let db = self.db.lock();
+        let mut out = json::Coverage { cameras: Vec::new() };
+        if uuids.is_empty() {
+            for c in db.cameras_by_id().values() {
+                out.cameras.push(json::CameraCoverage::wrap(c, &db));
+            }
+        } else {
+            for u in &uuids {
+                match db.get_camera(*u) {
+                    None => return self.not_found(),
+                    Some(c) => out.cameras.push(json::CameraCoverage::wrap(c, &db)),
+                }
+            }
+        }
+        serve_json(req, StatusCode::OK, &out)
+    }
+
+    fn stream_recordings(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
+                         -> Result<Response<Body>, Error> {
+        let (r, split) = {
+            let mut time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+            let mut split = db::AggregationSplit::default();
+            if let Some(q) = req.uri().query() {
+                for (key, value) in request::parse_query(q, &[])? {
+                    let (key, value) = (key.borrow(), value.borrow());
+                    match key {
+                        "startTime90k" => time.start = recording::Time::parse(value)?,
+                        "endTime90k" => time.end = recording::Time::parse(value)?,
+                        _ => { parse_split(key, value, &mut split)?; },
+                    }
+                };
+            }
+            (time, split)
+        };
+        let ndjson = req.headers().get(header::ACCEPT)
+                        .and_then(|a| a.to_str().ok())
+                        .map(|a| a.contains("application/x-ndjson"))
+                        .unwrap_or(false);
+        if ndjson {
+            return self.stream_recordings_ndjson(req, uuid, type_, r, split);
+        }
+        let mut out = json::ListRecordings{recordings: Vec::new(), notes: Vec::new()};
+        {
+            let db = self.db.lock();
+            let camera = db.get_camera(uuid)
+                           .ok_or_else(|| format_err!("no such camera {}", uuid))?;
+            let stream_id = camera.streams[type_.index()]
+                                  .ok_or_else(|| format_err!("no such stream {}/{}", uuid, type_))?;
+            db.list_notes(stream_id, r.clone(), None, &mut |n| {
+                out.notes.push(json::Note::wrap(n));
+                Ok(())
+            })?;
+            db.list_aggregated_recordings(stream_id, r, split, &mut |row| {
+                out.recordings.push(json::Recording::wrap(row, &db));
+                Ok(())
+            })?;
+        }
+        serve_json(req, StatusCode::OK, &out)
+    }
+
+    /// Serves `/recordings/updates`, which waits for new recordings before responding.
+    fn stream_recording_updates(&self, req: &Request<::hyper::Body>, uuid: Uuid,
+                                type_: db::StreamType) -> Result<Response<Body>, Error> {
+        let mut time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+        let mut split = db::AggregationSplit::default();
+        let mut after_id = None;
+        let mut growing_end_90k = None;
+        let mut timeout_sec = 30;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => time.start = recording::Time::parse(value)?,
+                    "endTime90k" => time.end = recording::Time::parse(value)?,
+                    "afterId" => after_id = Some(i32::from_str(value)?),
+                    "growingEnd90k" => growing_end_90k = Some(i64::from_str(value)?),
+                    "timeoutSec" => timeout_sec = u64::from_str(value)?,
+                    _ => if !parse_split(key, value, &mut split)? {
+                        bail!("parameter {} not understood", key);
+                    },
+                }
+            };
+        }
+        let after_id = match after_id {
+            None => return Ok(plain_response(StatusCode::BAD_REQUEST, "afterId is required")),
+            Some(id) => id,
+        };
+        if timeout_sec > updates::MAX_TIMEOUT_SEC {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "timeoutSec is too large"));
+        }
+        let stream_id = {
+            let db = self.db.lock();
+            match db.get_camera(uuid).and_then(|c| c.streams[type_.index()]) {
+                None => return self.not_found(),
+                Some(id) => id,
+            }
+        };
+        let body = updates::Hub::wait(&self.updates, updates::Params {
+            stream_id,
+            after_id,
+            growing_end_90k,
+            time,
+            split,
+            timeout: Duration::from_secs(timeout_sec),
+        })?;
+        let mut resp = Response::new(body.into());
+        resp.headers_mut().insert(header::CONTENT_TYPE,
+                                  HeaderValue::from_static("application/json"));
+        resp.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        Ok(resp)
+    }
+
+    /// Serves `/recordings` as newline-delimited JSON, one recording per line. The rows are
+    /// gathered under the database lock, but each line is serialized only as the client is ready
+    /// for it, after the lock is released, so a slow client doesn't stall other requests.
+    fn stream_recordings_ndjson(&self, req: &Request<::hyper::Body>, uuid: Uuid,
+                                type_: db::StreamType, r: Range<recording::Time>,
+                                split: db::AggregationSplit) -> Result<Response<Body>, Error> {
+        let mut rows = Vec::new();
+        if *req.method() != http::Method::HEAD {
+            let db = self.db.lock();
+            let camera = db.get_camera(uuid)
+                           .ok_or_else(|| format_err!("no such camera {}", uuid))?;
+            let stream_id = camera.streams[type_.index()]
+                                  .ok_or_else(|| format_err!("no such stream {}/{}", uuid, type_))?;
+            db.list_aggregated_recordings(stream_id, r, split, &mut |row| {
+                rows.push(json::Recording::wrap(row, &db));
+                Ok(())
+            })?;
+        }
+        let body: BodyStream = Box::new(::futures::stream::iter_ok(rows).and_then(|r| {
+            let mut line = serde_json::to_vec(&r).map_err(|e| wrap_error(e.into()))?;
+            line.push(b'\n');
+            Ok(Chunk::from(line))
+        }));
+        let mut resp = Response::new(body.into());
+        resp.headers_mut().insert(header::CONTENT_TYPE,
+                                  HeaderValue::from_static("application/x-ndjson"));
+        Ok(resp)
+    }
+
+    /// Serves `/metadata`: the packets of the stream's metadata track within the given time range.
+    fn stream_metadata(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
+                       -> Result<Response<Body>, Error> {
+        let mut time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => time.start = recording::Time::parse(value)?,
+                    "endTime90k" => time.end = recording::Time::parse(value)?,
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let mut out = json::ListMetadata { packets: Vec::new() };
+        {
+            let db = self.db.lock();
+            let stream_id = match db.get_camera(uuid).and_then(|c| c.streams[type_.index()]) {
+                None => return self.not_found(),
+                Some(id) => id,
+            };
+            let mut rows = Vec::new();
+            db.list_recordings_by_time(stream_id, time.clone(), &mut |r| {
+                rows.push((r.id, r.start));
+                Ok(())
+            })?;
+            for (id, start) in rows {
+                db.with_recording_metadata(id, &mut |m| {
+                    for s in recording::parse_metadata(m)? {
+                        let t = start + recording::Duration(s.rel_90k as i64);
+                        if t < time.start || t >= time.end {
+                            continue;
+                        }
+                        if out.packets.len() == MAX_METADATA_PACKETS {
+                            bail!("more than {} metadata packets in range; narrow it",
+                                  MAX_METADATA_PACKETS);
+                        }
+                        out.packets.push(json::MetadataPacket {
+                            recording_id: id.recording(),
+                            time_90k: t.0,
+                            data: String::from_utf8_lossy(s.data).into_owned(),
+                        });
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        serve_json(req, StatusCode::OK, &out)
+    }
+
+    /// Serves `/api/cameras/<uuid>/<type>/enable` and `/disable`, which resume or pause recording
+    /// of a stream without changing the rest of its configuration.
+    fn stream_set_paused(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType,
+                         paused: bool) -> Result<Response<Body>, Error> {
+        if *req.method() != http::Method::POST {
+            return Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected"));
+        }
+        let user = self.user_name(req).unwrap_or("unknown user");
+        let mut db = self.db.lock();
+        let (stream_id, short_name) = match db.get_camera(uuid) {
+            None => return self.not_found(),
+            Some(c) => match c.streams[type_.index()] {
+                None => return self.not_found(),
+                Some(id) => (id, c.short_name.clone()),
+            },
+        };
+        if db.set_stream_paused(stream_id, paused)? {
+            info!(target: "audit", "{} {} recording of {}/{}",
+                  user, if paused { "paused" } else { "resumed" }, short_name, type_.as_str());
+        }
+        Ok(plain_response(StatusCode::NO_CONTENT, ""))
+    }
+
+    /// Serves `/api/cameras/<uuid>/<type>/recordings/archive`, marking recordings whose sample
+    /// files have been copied elsewhere as archived and removing the local copies.
+    fn stream_recording_archive(&self, req: &Request<::hyper::Body>, uuid: Uuid,
+                                 type_: db::StreamType) -> Result<Response<Body>, Error> {
+        if *req.method() != http::Method::POST {
+            return Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected"));
+        }
+        let mut start_id = None;
+        let mut end_id = None;
+        let mut location = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startId" => start_id = Some(i32::from_str(value)?),
+                    "endId" => end_id = Some(i32::from_str(value)?),
+                    "location" => location = Some(value.to_owned()),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let (start_id, end_id, location) = match (start_id, end_id, location) {
+            (Some(s), Some(e), Some(ref l)) if s <= e && !l.is_empty() => (s, e, l.clone()),
+            _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                          "startId, endId, and location are required")),
+        };
+        let user = self.user_name(req).unwrap_or("unknown user");
+        let (short_name, ids, dir) = {
+            let mut db = self.db.lock();
+            let (stream_id, short_name) = match db.get_camera(uuid) {
+                None => return self.not_found(),
+                Some(c) => match c.streams[type_.index()] {
+                    None => return self.not_found(),
+                    Some(id) => (id, c.short_name.clone()),
+                },
+            };
+            let dir_id = match db.streams_by_id()[&stream_id].sample_file_dir_id {
+                None => return self.not_found(),
+                Some(d) => d,
+            };
+            if db.recordings_held(stream_id, start_id .. end_id + 1)? {
+                return Ok(plain_response(StatusCode::CONFLICT,
+                                         "recordings in range are covered by a hold"));
+            }
+            let ids = db.archive_recordings(stream_id, start_id .. end_id + 1, &location)?;
+            (short_name, ids, db.sample_file_dirs_by_id()[&dir_id].get()?)
+        };
+        info!(target: "audit", "{} archived {} recordings of {}/{} ({}-{}) to {}",
+              user, ids.len(), short_name, type_.as_str(), start_id, end_id, location);
+        dir.unlink_unreferenced(&ids)?;
+        Ok(plain_response(StatusCode::NO_CONTENT, ""))
+    }
+
+    /// Serves `/api/cameras/<uuid>/<type>/encoder`. `GET` reads the camera's video encoder
+    /// configuration for the stream via ONVIF; `POST` pushes the stream's configured encoder
+    /// settings to the camera immediately rather than waiting for the streamer to do so.
+    fn stream_encoder(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
+                      -> Result<Response<Body>, Error> {
+        let push = match *req.method() {
+            http::Method::GET | http::Method::HEAD => false,
+            http::Method::POST => true,
+            _ => return Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED,
+                                          "GET, HEAD, or POST expected")),
+        };
+        let (short_name, host, username, password, rtsp_path, settings) = {
+            let db = self.db.lock();
+            let c = match db.get_camera(uuid) {
+                None => return self.not_found(),
+                Some(c) => c,
+            };
+            let s = match c.streams[type_.index()] {
+                None => return self.not_found(),
+                Some(id) => &db.streams_by_id()[&id],
+            };
+            (c.short_name.clone(), c.host.clone(), c.username.clone(), c.password.clone(),
+             s.rtsp_path.clone(), s.encoder)
+        };
+        let config = if push {
+            if settings.is_empty() {
+                return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                         "stream has no encoder settings configured"));
+            }
+            let user = self.user_name(req).unwrap_or("unknown user");
+            info!(target: "audit", "{} pushing encoder settings {:?} to {}/{}",
+                  user, settings, short_name, type_.as_str());
+            match onvif::set_encoder(&host, &username, &password, &rtsp_path, &settings) {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!(target: "audit", "pushing encoder settings to {}/{} failed: {}",
+                          short_name, type_.as_str(), e);
+                    return Err(e);
+                },
+            }
+        } else {
+            onvif::get_encoder(&host, &username, &password, &rtsp_path)?
+        };
+        serve_json(req, StatusCode::OK, &json::StreamEncoder::wrap(&config, &settings))
+    }
+
+    fn stream_notes(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
+                    -> Result<Response<Body>, Error> {
+        let mut time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+        let mut query = None;
+        let mut text = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => time.start = recording::Time::parse(value)?,
+                    "endTime90k" => time.end = recording::Time::parse(value)?,
+                    "q" => query = Some(value.to_owned()),
+                    "text" => text = Some(value.to_owned()),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let mut db = self.db.lock();
+        let stream_id = match db.get_camera(uuid).and_then(|c| c.streams[type_.index()]) {
+            None => return self.not_found(),
+            Some(id) => id,
+        };
+        let (status, body) = if *req.method() == http::Method::POST {
+            let text = match text {
+                Some(ref t) if !t.is_empty() && time.start <= time.end &&
+                               time.start.0 != i64::min_value() &&
+                               time.end.0 != i64::max_value() => t,
+                _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                              "startTime90k, endTime90k, and text are required")),
+            };
+            let now_sec = time::get_time().sec;
+            let id = db.add_note(stream_id, time.clone(), text, now_sec)?;
+            let note = json::Note {
+                id,
+                start_time_90k: time.start.0,
+                end_time_90k: time.end.0,
+                text: text.clone(),
+                created_sec: now_sec,
+            };
+            (StatusCode::CREATED, serde_json::to_value(note)?)
+        } else {
+            let mut out = json::ListNotes { notes: Vec::new() };
+            db.list_notes(stream_id, time, query.as_ref().map(|q| q.as_str()), &mut |n| {
+                out.notes.push(json::Note::wrap(n));
+                Ok(())
+            })?;
+            (StatusCode::OK, serde_json::to_value(out)?)
+        };
+        drop(db);
+        serve_json(req, status, &body)
+    }
+
+    /// Serves `/api/cameras/<uuid>/<type>/usage`, a breakdown of the stream's disk usage.
+    fn stream_usage(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
+                    -> Result<Response<Body>, Error> {
+        let out = {
+            let db = self.db.lock();
+            match db.get_camera(uuid).and_then(|c| c.streams[type_.index()]) {
+                None => return self.not_found(),
+                Some(id) => json::StreamUsage::new(&db, &db.streams_by_id()[&id]),
+            }
+        };
+        serve_json(req, StatusCode::OK, &out)
+    }
+
+    /// Serves `/api/cameras/<uuid>/<type>/thumbnails`, the times of stored thumbnails.
+    fn stream_thumbnails(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
+                         -> Result<Response<Body>, Error> {
+        let mut time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => time.start = recording::Time::parse(value)?,
+                    "endTime90k" => time.end = recording::Time::parse(value)?,
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let out = {
+            let db = self.db.lock();
+            let stream_id = match db.get_camera(uuid).and_then(|c| c.streams[type_.index()]) {
+                None => return self.not_found(),
+                Some(id) => id,
+            };
+            json::ListThumbnails {
+                thumbnails: db.list_thumbnails(stream_id, time)?
+                              .iter().map(json::Thumbnail::wrap).collect(),
+            }
+        };
+        serve_json(req, StatusCode::OK, &out)
+    }
+
+    /// Serves `/api/cameras/<uuid>/<type>/thumbnail.jpg`, the latest stored thumbnail at or
+    /// before the given time.
+    fn stream_thumbnail(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
+                        -> Result<Response<Body>, Error> {
+        let mut time = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "time90k" => time = Some(recording::Time::parse(value)?),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let time = match time {
+            None => return Ok(plain_response(StatusCode::BAD_REQUEST, "time90k is required")),
+            Some(t) => t,
+        };
+        let (t, jpeg) = {
+            let db = self.db.lock();
+            let stream_id = match db.get_camera(uuid).and_then(|c| c.streams[type_.index()]) {
+                None => return self.not_found(),
+                Some(id) => id,
+            };
+            match db.get_thumbnail(stream_id, time)? {
+                None => return self.not_found(),
+                Some(t) => t,
+            }
+        };
+        let mut resp = Response::new(jpeg.into());
+        {
+            let h = resp.headers_mut();
+            h.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+            h.insert("x-thumbnail-time-90k", HeaderValue::from_str(&t.0.to_string())?);
+        }
+        Ok(resp)
+    }
+
+    /// Serves `/api/cameras/<uuid>/<type>/export/email`, creating an `email` job to send a clip
+    /// of the given time range to the configured recipients. Jobs run on the job queue's bounded
+    /// worker threads, so many requests queue rather than each running `sendmail` at once.
+    fn stream_export_email(&self, req: &Request<::hyper::Body>, uuid: Uuid,
+                           type_: db::StreamType) -> Result<Response<Body>, Error> {
+        let jobs = match self.jobs {
+            Some(ref j) if j.has_handler("email") => j,
+            _ => return Ok(plain_response(StatusCode::NOT_FOUND,
+                                          "email is not enabled on this server")),
+        };
+        if *req.method() != http::Method::POST {
+            return Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected"));
+        }
+        let mut start = None;
+        let mut end = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startTime90k" => start = Some(recording::Time::parse(value)?),
+                    "endTime90k" => end = Some(recording::Time::parse(value)?),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let (start, end) = match (start, end) {
+            (Some(s), Some(e)) if s < e => (s, e),
+            _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                          "startTime90k and endTime90k are required")),
+        };
+        let params = {
+            let db = self.db.lock();
+            let camera = match db.get_camera(uuid) {
+                None => return self.not_found(),
+                Some(c) => c,
+            };
+            match camera.streams[type_.index()] {
+                None => return self.not_found(),
+                Some(stream_id) => email::Params {
+                    stream_id,
+                    start_time_90k: start.0,
+                    end_time_90k: end.0,
+                    subject: self.catalog.default_locale().format(
+                        "Clip from {camera}", &[("camera", camera.short_name.as_str())]),
+                },
+            }
+        };
+        let job = jobs.create("email", &params)?;
+        self.job_response(req, StatusCode::ACCEPTED, &job)
+    }
+
+    fn exports(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let jobs = match self.jobs {
+            Some(ref j) if j.has_handler("export") => j,
+            _ => return Ok(plain_response(StatusCode::NOT_FOUND,
+                                          "exports are not enabled on this server")),
+        };
+        if *req.method() != http::Method::POST {
+            return Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected"));
+        }
+        let mut camera = None;
+        let mut type_ = db::StreamType::MAIN;
+        let mut start = None;
+        let mut end = None;
+        let mut watermark = false;
+        let mut precise = false;
+        let mut redactions = Vec::new();
+        let mut redaction_style = None;
+        let mut preset_name = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &["redact"])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "camera" => camera = Some(request::parse_uuid(value)?),
+                    "stream" => type_ = db::StreamType::parse(value).ok_or_else(
+                        || format_err!("invalid stream {:?}", value))?,
+                    "startTime90k" => start = Some(recording::Time::parse(value)?),
+                    "endTime90k" => end = Some(recording::Time::parse(value)?),
+                    "watermark" => watermark = value == "true",
+                    "precise" => precise = value == "true",
+                    "redact" => redactions.push(export::Redaction::parse(value)?),
+                    "redactStyle" => redaction_style = Some(
+                        export::RedactionStyle::parse(value).ok_or_else(
+                            || format_err!("invalid redactStyle {:?}", value))?),
+                    "preset" => preset_name = Some(value.to_owned()),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let (camera, start, end) = match (camera, start, end) {
+            (Some(c), Some(s), Some(e)) if s < e => (c, s, e),
+            _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                          "camera, startTime90k, and endTime90k are required")),
+        };
+        // The watermark names the user as authenticated by the proxy in front of the server, not
+        // as claimed by the client.
+        let user = self.user_name(req).map(|u| u.to_owned());
+        let tenant_id = self.user(req)?.and_then(|u| u.tenant_id);
+
+        // A named preset must exist; otherwise the "default" preset applies, if there is one.
+        let preset = {
+            let db = self.db.lock();
+            watermark |= self.watermark_exports ||
+                         tenant_id.and_then(|id| db.tenants_by_id().get(&id))
+                                  .map(|t| t.watermark_exports)
+                                  .unwrap_or(false);
+            match preset_name {
+                Some(ref n) => match db.get_export_preset(n) {
+                    None => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                                     "no such export preset")),
+                    Some(p) => Some(p.clone()),
+                },
+                None => db.get_export_preset("default").cloned(),
+            }
+        };
+        let reencoding = self.exporter.as_ref().map(|e| e.supports_reencoding()).unwrap_or(false);
+        if let Some(ref p) = preset {
+            if (p.max_height.is_some() || p.container != db::ExportContainer::Mp4) && !reencoding {
+                return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                         "the export preset is not supported on this server"));
+            }
+            watermark |= p.watermark;
+        }
+        if precise && !reencoding {
+            return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                     "precise exports are not enabled on this server"));
+        }
+        if !redactions.is_empty() && !reencoding {
+            return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                     "redaction is not enabled on this server"));
+        }
+        if redactions.len() > export::MAX_REDACTIONS {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "too many redactions"));
+        }
+        let watermark = match user {
+            _ if !watermark => None,
+            None => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                             "watermarking requires an authenticated user")),
+            Some(ref u) if u.is_empty() || u.chars().any(char::is_control) || u.len() > 64 => {
+                return Ok(plain_response(StatusCode::BAD_REQUEST, "invalid user"));
+            },
+            Some(u) => Some(format!("Exported by {} at {}", u,
+                                    time::now().strftime("%Y-%m-%d %H:%M:%S %z")?)),
+        };
+        let stream_id = {
+            let db = self.db.lock();
+            match db.get_camera(camera).and_then(|c| c.streams[type_.index()]) {
+                None => return self.not_found(),
+                Some(id) => id,
+            }
+        };
+        let job = jobs.create("export", &export::Params {
+            stream_id,
+            start_time_90k: start.0,
+            end_time_90k: end.0,
+            watermark,
+            precise,
+            redactions,
+            redaction_style,
+            timestamps: preset.as_ref().map(|p| p.timestamps).unwrap_or(false),
+            max_height: preset.as_ref().and_then(|p| p.max_height),
+            container: preset.as_ref().and_then(|p| match p.container {
+                db::ExportContainer::Mp4 => None,
+                c => Some(c.as_str().to_owned()),
+            }),
+            preset: preset.map(|p| p.name),
+        })?;
+        self.job_response(req, StatusCode::ACCEPTED, &job)
+    }
+
+    fn event_clip(&self, req: &Request<::hyper::Body>, id: i64) -> Result<Response<Body>, Error> {
+        let clips = match self.event_clips {
+            None => return self.not_found(),
+            Some(ref c) => c,
+        };
+        match clips.get(id)? {
+            None => self.not_found(),
+            Some(mp4) => Ok(http_serve::serve(mp4, req)),
+        }
+    }
+
+    fn event_snapshot(&self, req: &Request<::hyper::Body>, id: i64)
+                      -> Result<Response<Body>, Error> {
+        let mut annotate = false;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "annotate" => annotate = bool::from_str(value)?,
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let (jpeg, detections) = {
+            let db = self.db.lock();
+            let jpeg = match db.get_event_snapshot(id)? {
+                None => return self.not_found(),
+                Some(j) => j,
+            };
+            (jpeg, if annotate { db.list_event_detections(id)? } else { Vec::new() })
+        };
+        let jpeg = if detections.is_empty() {
+            jpeg
+        } else {
+            let ffmpeg = match self.snapshot_ffmpeg {
+                None => return Ok(plain_response(StatusCode::NOT_FOUND,
+                                                 "annotation is not enabled on this server")),
+                Some(ref f) => f,
+            };
+            annotate::annotate_jpeg(ffmpeg, jpeg, &detections)?
+        };
+        let mut resp = Response::new(jpeg.into());
+        resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+        Ok(resp)
+    }
+
+    /// Serves `/api/embed`, which signs a token for `/embed/<token>`.
+    fn embeds(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let signer = match self.embed_signer {
+            None => return Ok(plain_response(StatusCode::NOT_FOUND,
+                                             "embedding is not enabled on this server")),
+            Some(ref s) => s,
+        };
+        if *req.method() != http::Method::POST {
+            return Ok(plain_response(StatusCode::METHOD_NOT_ALLOWED, "POST expected"));
+        }
+        let mut camera = None;
+        let mut type_ = db::StreamType::MAIN;
+        let mut start = None;
+        let mut end = None;
+        let mut expires_sec = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "camera" => camera = Some(request::parse_uuid(value)?),
+                    "stream" => type_ = db::StreamType::parse(value).ok_or_else(
+                        || format_err!("invalid stream {:?}", value))?,
+                    "startTime90k" => start = Some(recording::Time::parse(value)?),
+                    "endTime90k" => end = Some(recording::Time::parse(value)?),
+                    "expiresSec" => expires_sec = Some(request::parse_decimal(value)?),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let (camera, start, end) = match (camera, start, end) {
+            (Some(c), Some(s), Some(e)) if 0 <= s.0 && s < e => (c, s, e),
+            _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                          "camera, startTime90k, and endTime90k are required")),
+        };
+        let now_sec = time::get_time().sec;
+        let expires_sec = expires_sec.unwrap_or(now_sec + embed::DEFAULT_EXPIRATION_SEC);
+        if expires_sec <= now_sec {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "expiresSec is in the past"));
+        }
+        if self.db.lock().get_camera(camera).and_then(|c| c.streams[type_.index()]).is_none() {
+            return self.not_found();
+        }
+        let token = signer.sign(&embed::Grant {
+            camera_uuid: camera,
+            stream_type: type_,
+            time: start .. end,
+            expires_sec,
+        })?;
+        info!(target: "audit", "signed embed token for camera {} stream {} {}-{}, expiring {}",
+              camera, type_, start, end, expires_sec);
+        serve_json(req, StatusCode::OK, &json::EmbedToken {
+            path: format!("/embed/{}", token),
+            token,
+        })
+    }
+
+    /// Verifies an `/embed/<token>` token, returning its grant and stream id. Returns `None`
+    /// for any failure, so that the public response doesn't reveal which check failed.
+    fn embed_grant(&self, token: &str) -> Option<(embed::Grant, i32)> {
+        let signer = self.embed_signer.as_ref()?;
+        let grant = match signer.verify(token, time::get_time().sec) {
+            Ok(g) => g,
+            Err(e) => {
+                debug!("rejecting embed token {:?}: {}", token, e);
+                return None;
+            },
+        };
+        let stream_id = self.db.lock().get_camera(grant.camera_uuid)
+                                      .and_then(|c| c.streams[grant.stream_type.index()])?;
+        Some((grant, stream_id))
+    }
+
+    fn embed_page(&self, token: &str) -> Result<Response<Body>, Error> {
+        let (grant, _) = match self.embed_grant(token) {
+            None => return self.not_found(),
+            Some(g) => g,
+        };
+        let title = match self.db.lock().get_camera(grant.camera_uuid) {
+            None => return self.not_found(),
+            Some(c) => format!("{} {}", c.short_name, grant.time.start),
+        };
+        let mut resp = Response::new(embed::page(&title, token).into_bytes().into());
+        {
+            let h = resp.headers_mut();
+            h.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+
+            // This page is meant to be framed by other sites.
+            h.insert(header::CONTENT_SECURITY_POLICY,
+                     HeaderValue::from_static("frame-ancestors *"));
+            h.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        }
+        Ok(resp)
+    }
+
+    fn embed_mp4(&self, req: &Request<::hyper::Body>, token: &str)
+                 -> Result<Response<Body>, Error> {
+        let (grant, stream_id) = match self.embed_grant(token) {
+            None => return self.not_found(),
+            Some(g) => g,
+        };
+        let dirs = self.dirs.get()?;
+        match export::build(&self.db, &dirs, stream_id, grant.time, None) {
+            Ok((_, mp4)) => Ok(http_serve::serve(mp4, req)),
+            Err(e) => {
+                // Most likely, the recordings have since been deleted.
+                debug!("unable to build embed {:?}: {}", token, e);
+                self.not_found()
+            },
+        }
+    }
+
+    fn maintenance(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        if *req.method() == http::Method::POST {
+            let mut reason = None;
+            let mut paused = Vec::new();
+            if let Some(q) = req.uri().query() {
+                for (key, value) in request::parse_query(q, &["pause"])? {
+                    let (key, value) = (key.borrow(), value.borrow());
+                    match key {
+                        "reason" => reason = Some(value.to_owned()),
+                        "pause" => {
+                            let slash = value.find('/').ok_or_else(
+                                || format_err!("pause should be <camera uuid>/<stream type>"))?;
+                            let uuid = request::parse_uuid(&value[.. slash])?;
+                            let type_ = db::StreamType::parse(&value[slash+1 ..])
+                                .ok_or_else(|| format_err!("no such stream type {}",
+                                                           &value[slash+1 ..]))?;
+                            paused.push((uuid, type_));
+                        },
+                        _ => bail!("parameter {} not understood", key),
+                    }
+                };
+            }
+            let reason = match reason {
+                Some(r) if !r.is_empty() => r,
+                _ => return Ok(plain_response(StatusCode::BAD_REQUEST, "reason is required")),
+            };
+            let paused_streams = {
+                let db = self.db.lock();
+                let mut ids = ::std::collections::BTreeSet::new();
+                for &(uuid, type_) in &paused {
+                    match db.get_camera(uuid).and_then(|c| c.streams[type_.index()]) {
+                        None => bail!("no such stream {}/{}", uuid, type_),
+                        Some(id) => ids.insert(id),
+                    };
+                }
+                ids
+            };
+            info!(target: "audit", "entering maintenance mode ({}); pausing streams {:?}",
+                  reason, paused);
+            let generation = self.maintenance.enter(reason, paused_streams,
+                                                    time::get_time().sec);
+            maintenance::quiesce(self.maintenance.clone(), self.db.clone(), generation)?;
+        } else if *req.method() == http::Method::DELETE {
+            if self.maintenance.exit() {
+                info!(target: "audit", "exited maintenance mode");
+            }
+        }
+        let state = self.maintenance.state();
+        let body = {
+            let db = self.db.lock();
+            json::Maintenance::wrap(&state, &db)
+        };
+        serve_json(req, StatusCode::OK, &body)
+    }
+
+    /// Serves `/api/admin/logs`: recent log records from memory, or the tail of the log file.
+    fn logs(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let mut level = log::Level::Trace;
+        let mut since = 0;
+        let mut file = false;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "level" => level = log::Level::from_str(value)
+                        .map_err(|_| format_err!("invalid level {}", value))?,
+                    "since" => since = u64::from_str(value)?,
+                    "file" => file = value == "true",
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        if file {
+            let p = match self.log_file {
+                None => return Ok(plain_response(StatusCode::NOT_FOUND, "no log file configured")),
+                Some(ref p) => p,
+            };
+            let mut f = ::std::fs::File::open(p)?;
+            let len = f.metadata()?.len();
+            let start = len.saturating_sub(LOG_FILE_TAIL_BYTES);
+            f.seek(io::SeekFrom::Start(start))?;
+            let mut buf = Vec::with_capacity((len - start) as usize);
+            f.take(LOG_FILE_TAIL_BYTES).read_to_end(&mut buf)?;
+            if start > 0 {
+                // Skip the partial first line.
+                let nl = buf.iter().position(|&b| b == b'\n').map(|i| i + 1).unwrap_or(0);
+                buf.drain(..nl);
+            }
+            let mut resp = Response::new(buf.into());
+            resp.headers_mut().insert(header::CONTENT_TYPE,
+                                      HeaderValue::from_static("text/plain; charset=utf-8"));
+            return Ok(resp);
+        }
+        let records = logs::list(level, since);
+        let out = json::Logs {
+            records: records.iter().map(json::LogRecord::wrap).collect(),
+        };
+        serve_json(req, StatusCode::OK, &out)
+    }
+
+    /// Serves `/api/stats/bandwidth`, hourly bytes served by user and camera. Defaults to the last
+    /// day; `startSec`, `endSec`, `camera`, and `user` narrow it.
+    fn bandwidth(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let acct = match self.bandwidth {
+            None => return Ok(plain_response(StatusCode::NOT_FOUND,
+                                             "bandwidth accounting is not enabled")),
+            Some(ref a) => a,
+        };
+        let now_sec = time::get_time().sec;
+        let mut start_sec = now_sec - 24 * 60 * 60;
+        let mut end_sec = now_sec + 1;
+        let mut camera = None;
+        let mut user = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startSec" => start_sec = request::parse_decimal(value)?,
+                    "endSec" => end_sec = request::parse_decimal(value)?,
+                    "camera" => camera = Some(request::parse_uuid(value)?),
+                    "user" => user = Some(value.to_owned()),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+
+        // Hours are stored by their start; include the one containing start_sec.
+        acct.flush()?;
+        let usage = self.db.lock().list_bandwidth_usage(start_sec - start_sec % 3600 .. end_sec)?;
+        let usage = usage.into_iter()
+                         .filter(|u| camera.map(|c| u.camera_uuid == Some(c)).unwrap_or(true))
+                         .filter(|u| user.as_ref().map(|n| &u.user_name == n).unwrap_or(true))
+                         .map(json::BandwidthUsage::wrap)
+                         .collect();
+        serve_json(req, StatusCode::OK, &json::Bandwidth { usage })
+    }
+
+    /// Serves `/api/viewers`, the number of clients watching each stream live.
+    fn viewers(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let viewers = self.tails.viewers();
+        let streams = {
+            let db = self.db.lock();
+            let mut streams: Vec<json::StreamViewers> = Vec::new();
+            for v in &viewers {
+                let s = match db.streams_by_id().get(&v.stream_id) {
+                    None => continue,
+                    Some(s) => s,
+                };
+                let camera_uuid = db.cameras_by_id()[&s.camera_id].uuid;
+                match streams.iter_mut().find(|j| j.camera_uuid == camera_uuid &&
+                                                  j.stream == s.type_.as_str()) {
+                    Some(j) => j.viewers += v.viewers,
+                    None => streams.push(json::StreamViewers {
+                        camera_uuid,
+                        stream: s.type_.as_str(),
+                        viewers: v.viewers,
+                    }),
+                }
+            }
+            streams
+        };
+        serve_json(req, StatusCode::OK, &json::Viewers { streams })
+    }
+
+    /// Serves metrics in the Prometheus text exposition format.
+    fn metrics(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let mut metrics: Vec<(&'static str, &'static str, &'static str, u64)> = Vec::new();
+        if let Some(ref c) = self.event_clips {
+            let m = c.metrics();
+            metrics.extend_from_slice(&[
+                ("moonfire_event_clip_cache_entries", "gauge",
+                 "Number of event clips in the cache.", m.entries as u64),
+                ("moonfire_event_clip_cache_max_entries", "gauge",
+                 "Maximum number of event clips in the cache.", m.max_entries as u64),
+                ("moonfire_event_clip_cache_bytes", "gauge",
+                 "Total length of the cached event clips.", m.bytes),
+                ("moonfire_event_clip_cache_hits_total", "counter",
+                 "Event clip requests served from the cache.", m.hits),
+                ("moonfire_event_clip_cache_misses_total", "counter",
+                 "Event clip requests not served from the cache.", m.misses),
+                ("moonfire_event_clip_builds_total", "counter",
+                 "Event clips built, in advance or on request.", m.builds),
+                ("moonfire_event_clip_build_failures_total", "counter",
+                 "Event clips which failed to build.", m.failures),
+            ]);
+        }
+        let viewers = self.tails.viewers();
+        let memory = memory::BUDGET.metrics();
+        metrics.extend_from_slice(&[
+            ("moonfire_memory_budget_bytes", "gauge",
+             "Limit on memory held by in-flight responses, or 0 if unlimited.",
+             memory.limit as u64),
+            ("moonfire_memory_budget_used_bytes", "gauge",
+             "Memory currently held by in-flight responses.", memory.used as u64),
+            ("moonfire_memory_budget_rejections_total", "counter",
+             "Requests rejected or jobs failed for lack of memory budget.", memory.rejected),
+            ("moonfire_live_viewers", "gauge",
+             "Clients currently watching live (tail) streams.",
+             viewers.iter().map(|v| v.viewers as u64).sum()),
+            ("moonfire_live_recordings", "gauge",
+             "Recordings currently being tailed, each shared by all of its viewers.",
+             viewers.len() as u64),
+        ]);
+        let (mut resp, writer) = http_serve::streaming_body(&req).build();
+        resp.headers_mut().insert(header::CONTENT_TYPE,
+                                  HeaderValue::from_static("text/plain; version=0.0.4"));
+        if let Some(mut w) = writer {
+            for &(name, type_, help, value) in &metrics {
+                write!(w, "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                       name, help, name, type_, name, value)?;
+            }
+        }
+        Ok(resp)
+    }
+
+    fn export_file(&self, req: &Request<::hyper::Body>, id: Uuid, container: db::ExportContainer)
+                   -> Result<Response<Body>, Error> {
+        let (jobs, exporter) = match (self.jobs.as_ref(), self.exporter.as_ref()) {
+            (Some(j), Some(e)) => (j, e),
+            _ => return self.not_found(),
+        };
+        match jobs.get(id)? {
+            Some(ref j) if j.type_ == "export" && j.state == db::JobState::Done => {
+                let p: export::Params = serde_json::from_str(&j.params)?;
+                if p.container()? != container {
+                    return self.not_found();
+                }
+            },
+            _ => return self.not_found(),
+        }
+        let f = fs::File::open(&exporter.path(id, container))?;
+        let mut hdrs = http::HeaderMap::new();
+        hdrs.insert(header::CONTENT_TYPE, HeaderValue::from_static(match container {
+            db::ExportContainer::Mp4 => "video/mp4",
+            db::ExportContainer::Matroska => "video/x-matroska",
+        }));
+        let e = http_serve::ChunkedReadFile::new(f, Some(self.pool.clone()), hdrs)?;
+        Ok(http_serve::serve(e, &req))
+    }
+
+    fn job_response(&self, req: &Request<::hyper::Body>, status: StatusCode, job: &db::Job)
+                    -> Result<Response<Body>, Error> {
+        serve_json(req, status, &json::Job::wrap(job))
+    }
+
+    fn jobs(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let jobs = match self.jobs {
+            None => return self.not_found(),
+            Some(ref j) => j,
+        };
+        let list = jobs.list()?;
+        serve_json(req, StatusCode::OK, &json::Jobs {
+            jobs: list.iter().map(json::Job::wrap).collect(),
+        })
+    }
+
+    fn job(&self, req: &Request<::hyper::Body>, id: Uuid) -> Result<Response<Body>, Error> {
+        let jobs = match self.jobs {
+            None => return self.not_found(),
+            Some(ref j) => j,
+        };
+        if *req.method() == http::Method::DELETE {
+            if !jobs.cancel_or_delete(id)? {
+                return self.not_found();
+            }
+            return Ok(plain_response(StatusCode::NO_CONTENT, ""));
+        }
+        match jobs.get(id)? {
+            None => self.not_found(),
+            Some(j) => self.job_response(req, StatusCode::OK, &j),
+        }
+    }
+
+    fn holds(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        if *req.method() == http::Method::POST {
+            return self.add_hold(req);
+        }
+        let db = self.db.lock();
+        serve_json(req, StatusCode::OK, &json::Holds {
+            holds: db.holds_by_id().values().map(|h| json::Hold::wrap(h, &db)).collect(),
+        })
+    }
+
+    fn add_hold(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let mut camera = None;
+        let mut start = None;
+        let mut end = None;
+        let mut reason = None;
+        let mut expires_sec = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "camera" => camera = Some(request::parse_uuid(value)?),
+                    "startTime90k" => start = Some(recording::Time::parse(value)?),
+                    "endTime90k" => end = Some(recording::Time::parse(value)?),
+                    "reason" => reason = Some(value.to_owned()),
+                    "expiresSec" => expires_sec = Some(i64::from_str(value)?),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let (camera, start, end, reason) = match (camera, start, end, reason) {
+            (Some(c), Some(s), Some(e), Some(r)) if s < e => (c, s, e, r),
+            _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                          "camera, startTime90k, endTime90k, and reason are \
+                                           required")),
+        };
+        let now_sec = time::get_time().sec;
+        if expires_sec.map(|e| e <= now_sec).unwrap_or(false) {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "expiresSec must be in the future"));
+        }
+        let mut db = self.db.lock();
+        let camera_id = match db.get_camera(camera) {
+            None => return self.not_found(),
+            Some(c) => c.id,
+        };
+        let id = db.add_hold(camera_id, start .. end, reason, now_sec, expires_sec)?;
+        let hold = json::Hold::wrap(db.holds_by_id().get(&id).unwrap(), &db);
+        drop(db);
+        serve_json(req, StatusCode::CREATED, &hold)
+    }
+
+    fn hold(&self, req: &Request<::hyper::Body>, uuid: Uuid) -> Result<Response<Body>, Error> {
+        let mut db = self.db.lock();
+        let id = match db.holds_by_id().values().find(|h| h.uuid == uuid) {
+            None => return self.not_found(),
+            Some(h) => h.id,
+        };
+        if *req.method() == http::Method::DELETE {
+            db.release_hold(id)?;
+            return Ok(plain_response(StatusCode::NO_CONTENT, ""));
+        }
+        if *req.method() == http::Method::POST {
+            let mut expires_sec = None;
+            if let Some(q) = req.uri().query() {
+                for (key, value) in request::parse_query(q, &[])? {
+                    let (key, value) = (key.borrow(), value.borrow());
+                    match key {
+                        "expiresSec" => expires_sec = Some(match value {
+                            "never" => None,
+                            v => Some(i64::from_str(v)?),
+                        }),
+                        _ => bail!("parameter {} not understood", key),
+                    }
+                };
+            }
+            let expires_sec = match expires_sec {
+                None => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                                 "expiresSec is required")),
+                Some(e) => e,
+            };
+            if expires_sec.map(|e| e <= time::get_time().sec).unwrap_or(false) {
+                return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                         "expiresSec must be in the future"));
+            }
+            db.update_hold_expiry(id, expires_sec)?;
+        }
+        let hold = json::Hold::wrap(db.holds_by_id().get(&id).unwrap(), &db);
+        drop(db);
+        serve_json(req, StatusCode::OK, &hold)
+    }
+
+    fn layouts(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let user = match self.user(req)? {
+            Some(u) => u.id,
+            None => return Ok(plain_response(StatusCode::UNAUTHORIZED,
+                                             "layouts require an authenticated user")),
+        };
+        let mut name = None;
+        let mut rows = None;
+        let mut columns = None;
+        let mut shared = false;
+        let mut cells = Vec::new();
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &["cell"])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "name" => name = Some(value.to_owned()),
+                    "rows" => rows = Some(request::parse_decimal(value)?),
+                    "columns" => columns = Some(request::parse_decimal(value)?),
+                    "shared" => shared = bool::from_str(value)?,
+                    "cell" => cells.push(request::parse_layout_cell(value)?),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let mut db = self.db.lock();
+        let (status, body) = if *req.method() == http::Method::POST {
+            let l = match (name, rows, columns) {
+                (Some(name), Some(rows), Some(columns)) => db::Layout {
+                    id: 0,
+                    uuid: Uuid::nil(),
+                    name,
+                    owner_user_id: user,
+                    shared,
+                    rows,
+                    columns,
+                    created_sec: time::get_time().sec,
+                    cells,
+                },
+                _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                              "name, rows, and columns are required")),
+            };
+            let l = match db.add_layout(l) {
+                Ok(l) => l,
+                Err(e) => return Ok(plain_response(StatusCode::BAD_REQUEST, e.to_string())),
+            };
+            (StatusCode::CREATED, serde_json::to_value(json::Layout::wrap(l))?)
+        } else {
+            let layouts = db.list_layouts(Some(user))?;
+            (StatusCode::OK, serde_json::to_value(json::Layouts {
+                layouts: layouts.into_iter().map(json::Layout::wrap).collect(),
+            })?)
+        };
+        drop(db);
+        serve_json(req, status, &body)
     }
 
-    fn top_level(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
-        let mut days = false;
+    fn layout(&self, req: &Request<::hyper::Body>, uuid: Uuid) -> Result<Response<Body>, Error> {
+        let user = match self.user(req)? {
+            Some(u) => u.id,
+            None => return Ok(plain_response(StatusCode::UNAUTHORIZED,
+                                             "layouts require an authenticated user")),
+        };
+        let mut shared = None;
         if let Some(q) = req.uri().query() {
-            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
-                let (key, value) : (_, &str) = (key.borrow(), value.borrow());
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
                 match key {
-                    "days" => days = value == "true",
-                    _ => {},
-                };
-            }
+                    "shared" => shared = Some(bool::from_str(value)?),
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
         }
-
-        let (mut resp, writer) = http_serve::streaming_body(&req).build();
-        resp.headers_mut().insert(header::CONTENT_TYPE,
-                                  HeaderValue::from_static("application/json"));
-        if let Some(mut w) = writer {
-            let db = self.db.lock();
-            serde_json::to_writer(&mut w, &json::TopLevel {
-                    time_zone_name: &self.time_zone_name,
-                    cameras: (&db, days),
-            })?;
+        let mut db = self.db.lock();
+        let mut l = match db.get_layout(uuid)? {
+            Some(ref l) if !l.shared && l.owner_user_id != user => return self.not_found(),
+            None => return self.not_found(),
+            Some(l) => l,
+        };
+        if *req.method() == http::Method::POST || *req.method() == http::Method::DELETE {
+            if l.owner_user_id != user {
+                return Ok(plain_response(StatusCode::FORBIDDEN,
+                                         "only the layout's owner can change it"));
+            }
+            if *req.method() == http::Method::DELETE {
+                db.delete_layout(l.id)?;
+                return Ok(plain_response(StatusCode::NO_CONTENT, ""));
+            }
+            if let Some(s) = shared {
+                db.set_layout_shared(l.id, s)?;
+                l.shared = s;
+            }
         }
-        Ok(resp)
+        drop(db);
+        serve_json(req, StatusCode::OK, &json::Layout::wrap(l))
     }
 
-    fn camera(&self, req: &Request<::hyper::Body>, uuid: Uuid) -> Result<Response<Body>, Error> {
-        let (mut resp, writer) = http_serve::streaming_body(&req).build();
-        resp.headers_mut().insert(header::CONTENT_TYPE,
-                                  HeaderValue::from_static("application/json"));
-        if let Some(mut w) = writer {
-            let db = self.db.lock();
-            let camera = db.get_camera(uuid)
-                           .ok_or_else(|| format_err!("no such camera {}", uuid))?;
-            serde_json::to_writer(&mut w, &json::Camera::wrap(camera, &db, true)?)?
+    fn incidents(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
+        let mut db = self.db.lock();
+        let (status, body) = if *req.method() == http::Method::POST {
+            let mut title = None;
+            let mut description = String::new();
+            if let Some(q) = req.uri().query() {
+                for (key, value) in request::parse_query(q, &[])? {
+                    let (key, value) = (key.borrow(), value.borrow());
+                    match key {
+                        "title" => title = Some(value.to_owned()),
+                        "description" => description = value.to_owned(),
+                        _ => bail!("parameter {} not understood", key),
+                    }
+                };
+            }
+            let title = match title {
+                Some(t) if !t.is_empty() => t,
+                _ => return Ok(plain_response(StatusCode::BAD_REQUEST, "title is required")),
+            };
+            let i = db.add_incident(title, description, time::get_time().sec)?;
+            let i = json::Incident::wrap(i, Some(&[][..]), &db);
+            (StatusCode::CREATED, serde_json::to_value(i)?)
+        } else {
+            let incidents = db.list_incidents()?;
+            let out = json::Incidents {
+                incidents: incidents.into_iter().map(|i| json::Incident::wrap(i, None, &db))
+                                    .collect(),
+            };
+            (StatusCode::OK, serde_json::to_value(out)?)
         };
-        Ok(resp)
+        drop(db);
+        serve_json(req, status, &body)
     }
 
-    fn stream_recordings(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
-                         -> Result<Response<Body>, Error> {
-        let (r, split) = {
-            let mut time = recording::Time(i64::min_value()) .. recording::Time(i64::max_value());
-            let mut split = recording::Duration(i64::max_value());
+    fn incident(&self, req: &Request<::hyper::Body>, uuid: Uuid) -> Result<Response<Body>, Error> {
+        let mut db = self.db.lock();
+        let incident = match db.list_incidents()?.into_iter().find(|i| i.uuid == uuid) {
+            None => return self.not_found(),
+            Some(i) => i,
+        };
+        if *req.method() == http::Method::DELETE {
+            db.delete_incident(incident.id)?;
+            return Ok(plain_response(StatusCode::NO_CONTENT, ""));
+        }
+        if *req.method() == http::Method::POST {
+            let mut item = None;
+            let mut camera = None;
+            let mut start = None;
+            let mut end = None;
             if let Some(q) = req.uri().query() {
-                for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+                for (key, value) in request::parse_query(q, &[])? {
                     let (key, value) = (key.borrow(), value.borrow());
                     match key {
-                        "startTime90k" => time.start = recording::Time::parse(value)?,
-                        "endTime90k" => time.end = recording::Time::parse(value)?,
-                        "split90k" => split = recording::Duration(i64::from_str(value)?),
-                        _ => {},
+                        "event" => item = Some(db::IncidentItem::Event(i64::from_str(value)?)),
+                        "note" => item = Some(db::IncidentItem::Note(i64::from_str(value)?)),
+                        "export" => item = Some(db::IncidentItem::Export(request::parse_uuid(value)?)),
+                        "camera" => camera = Some(request::parse_uuid(value)?),
+                        "startTime90k" => start = Some(recording::Time::parse(value)?),
+                        "endTime90k" => end = Some(recording::Time::parse(value)?),
+                        _ => bail!("parameter {} not understood", key),
                     }
                 };
             }
-            (time, split)
-        };
-        let mut out = json::ListRecordings{recordings: Vec::new()};
+            let item = match (item, camera, start, end) {
+                (Some(i), None, None, None) => i,
+                (None, Some(c), Some(s), Some(e)) if s <= e => {
+                    let camera_id = match db.get_camera(c) {
+                        None => return self.not_found(),
+                        Some(c) => c.id,
+                    };
+                    db::IncidentItem::Range { camera_id, time: s .. e }
+                },
+                _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                              "expected one of event, note, export, or camera \
+                                               with startTime90k and endTime90k")),
+            };
+            db.add_incident_item(incident.id, &item)?;
+        }
+        let items = db.list_incident_items(incident.id)?;
+        let body = json::Incident::wrap(incident, Some(&items), &db);
+        drop(db);
+        serve_json(req, StatusCode::OK, &body)
+    }
+
+    /// Returns the full index of committed recordings, for mirroring by a central server.
+    fn stream_index(&self, req: &Request<::hyper::Body>, uuid: Uuid, type_: db::StreamType)
+                    -> Result<Response<Body>, Error> {
+        let mut start_id = None;
+        let mut end_id = None;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "startId" => start_id = Some(i32::from_str(value)?),
+                    "endId" => end_id = Some(i32::from_str(value)?),
+                    _ => {},
+                }
+            };
+        }
+        let mut out = json::StreamIndex{recordings: Vec::new(), next_start_id: None};
         {
             let db = self.db.lock();
             let camera = db.get_camera(uuid)
                            .ok_or_else(|| format_err!("no such camera {}", uuid))?;
             let stream_id = camera.streams[type_.index()]
                                   .ok_or_else(|| format_err!("no such stream {}/{}", uuid, type_))?;
-            db.list_aggregated_recordings(stream_id, r, split, &mut |row| {
-                let end = row.ids.end - 1;  // in api, ids are inclusive.
+
+            // Without a startId, start from the oldest recording rather than id 0, which may be
+            // long deleted. Return at most MAX_INDEX_RECORDINGS ids per page.
+            let committed = db.committed_recording_ids(stream_id)?;
+            let mut ids = start_id.unwrap_or(committed.start) ..
+                          cmp::min(end_id.map(|e| e.saturating_add(1))
+                                         .unwrap_or(i32::max_value()),
+                                   committed.end);
+            let page_end = ids.start.saturating_add(MAX_INDEX_RECORDINGS);
+            if page_end < ids.end {
+                ids.end = page_end;
+                out.next_start_id = Some(page_end);
+            }
+            db.list_recordings_by_id(stream_id, ids, &mut |row| {
+                if (row.flags & db::RecordingFlags::Uncommitted as i32) != 0 {
+                    return Ok(());
+                }
                 let vse = db.video_sample_entries_by_id().get(&row.video_sample_entry_id).unwrap();
-                out.recordings.push(json::Recording {
-                    start_id: row.ids.start,
-                    end_id: if end == row.ids.start { None } else { Some(end) },
-                    start_time_90k: row.time.start.0,
-                    end_time_90k: row.time.end.0,
+                let video_index = db.with_recording_playback(row.id, &mut |p| {
+                    Ok(strutil::hex(p.video_index))
+                })?;
+                out.recordings.push(json::IndexRecording {
+                    id: row.id.recording(),
+                    start_time_90k: row.start.0,
+                    duration_90k: row.duration_90k,
+                    video_samples: row.video_samples,
+                    video_sync_samples: row.video_sync_samples,
                     sample_file_bytes: row.sample_file_bytes,
+                    run_offset: row.run_offset,
                     open_id: row.open_id,
-                    first_uncommitted: row.first_uncommitted,
-                    video_samples: row.video_samples,
-                    video_sample_entry_width: vse.width,
-                    video_sample_entry_height: vse.height,
+                    flags: row.flags,
                     video_sample_entry_sha1: strutil::hex(&vse.sha1),
-                    growing: row.growing,
+                    video_index,
                 });
                 Ok(())
             })?;
         }
-        let (mut resp, writer) = http_serve::streaming_body(&req).build();
-        resp.headers_mut().insert(header::CONTENT_TYPE,
-                                  HeaderValue::from_static("application/json"));
-        if let Some(mut w) = writer {
-            serde_json::to_writer(&mut w, &out)?
-        };
-        Ok(resp)
+        serve_json(req, StatusCode::OK, &out)
     }
 
     fn init_segment(&self, sha1: [u8; 20], req: &Request<::hyper::Body>)
@@ -303,7 +2493,7 @@ impl ServiceInner {
         for ent in db.video_sample_entries_by_id().values() {
             if ent.sha1 == sha1 {
                 builder.append_video_sample_entry(ent.clone());
-                let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())?;
+                let mp4 = builder.build(self.db.clone(), self.dirs.get()?)?;
                 return Ok(http_serve::serve(mp4, req));
             }
         }
@@ -320,9 +2510,25 @@ impl ServiceInner {
             camera.streams[stream_type_.index()]
                   .ok_or_else(|| format_err!("no such stream {}/{}", uuid, stream_type_))?
         };
+        if let Some(q) = req.uri().query() {
+            if form_urlencoded::parse(q.as_bytes()).any(|(k, v)| k == "tail" && v == "true") {
+                return self.stream_view_tail(req, stream_id, mp4_type_);
+            }
+        }
+        let key = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("").to_owned();
+        let now = Instant::now();
+        if let Some(mp4) = self.mp4_cache.lock().get(&key, now) {
+            return Ok(http_serve::serve(mp4, req));
+        }
         let mut builder = mp4::FileBuilder::new(mp4_type_);
+        let mut include_event_chapters = false;
+        let mut cacheable = true;
+        let mut archived = None;
         if let Some(q) = req.uri().query() {
-            for (key, value) in form_urlencoded::parse(q.as_bytes()) {
+            // kf applies to all segments, so it must be known before any are appended.
+            builder.key_frames_only(form_urlencoded::parse(q.as_bytes())
+                                    .any(|(key, value)| key == "kf" && value == "true"));
+            for (key, value) in request::parse_query(q, &["s"])? {
                 let (key, value) = (key.borrow(), value.borrow());
                 match key {
                     "s" => {
@@ -336,129 +2542,611 @@ impl ServiceInner {
                             // there are no gaps or overlap, possibly another for misalignment of
                             // the requested timespan with the rotate offset and another because
                             // rotation only happens at key frames.
-                            let ceil_durations = (end - s.start_time +
-                                                  recording::DESIRED_RECORDING_DURATION - 1) /
-                                                 recording::DESIRED_RECORDING_DURATION;
+                            let desired = self.db.lock()
+                                              .streams_by_id()
+                                              .get(&stream_id)
+                                              .map(|s| s.recording_duration_sec *
+                                                       recording::TIME_UNITS_PER_SEC)
+                                              .unwrap_or(recording::DESIRED_RECORDING_DURATION);
+                            let ceil_durations = (end - s.start_time + desired - 1) / desired;
                             est_segments = cmp::min(est_segments, (ceil_durations + 2) as usize);
                         }
                         builder.reserve(est_segments);
-                        let db = self.db.lock();
-                        let mut prev = None;
-                        let mut cur_off = 0;
-                        db.list_recordings_by_id(stream_id, s.ids.clone(), &mut |r| {
-                            let recording_id = r.id.recording();
-
-                            if let Some(o) = s.open_id {
-                                if r.open_id != o {
-                                    bail!("recording {} has open id {}, requested {}",
-                                          r.id, r.open_id, o);
-                                }
+                        let all_committed = for_each_segment(&self.db.lock(), stream_id, &s,
+                                                             &mut |db, r, rel_range_90k| {
+                            if (r.flags & db::RecordingFlags::Archived as i32) != 0 {
+                                archived = archived.or(Some(r.id));
+                                return Ok(());
                             }
-
-                            // Check for missing recordings.
-                            match prev {
-                                None if recording_id == s.ids.start => {},
-                                None => bail!("no such recording {}/{}", stream_id, s.ids.start),
-                                Some(id) if r.id.recording() != id + 1 => {
-                                    bail!("no such recording {}/{}", stream_id, id + 1);
-                                },
-                                _ => {},
-                            };
-                            prev = Some(recording_id);
-
-                            // Add a segment for the relevant part of the recording, if any.
-                            let end_time = s.end_time.unwrap_or(i64::max_value());
-                            let d = r.duration_90k as i64;
-                            if s.start_time <= cur_off + d && cur_off < end_time {
-                                let start = cmp::max(0, s.start_time - cur_off);
-                                let end = cmp::min(d, end_time - cur_off);
-                                let times = start as i32 .. end as i32;
-                                debug!("...appending recording {} with times {:?} \
-                                       (out of dur {})", r.id, times, d);
-                                builder.append(&db, r, start as i32 .. end as i32)?;
-                            } else {
-                                debug!("...skipping recording {} dur {}", r.id, d);
-                            }
-                            cur_off += d;
-                            Ok(())
+                            builder.append(db, r, rel_range_90k)
                         })?;
-
-                        // Check for missing recordings.
-                        match prev {
-                            Some(id) if s.ids.end != id + 1 => {
-                                bail!("no such recording {}/{}", stream_id, s.ids.end - 1);
-                            },
-                            None => {
-                                bail!("no such recording {}/{}", stream_id, s.ids.start);
-                            },
-                            _ => {},
-                        };
-                        if let Some(end) = s.end_time {
-                            if end > cur_off {
-                                bail!("end time {} is beyond specified recordings", end);
-                            }
-                        }
+                        cacheable &= all_committed;
                     },
                     "ts" => builder.include_timestamp_subtitle_track(value == "true"),
+                    "kf" => {},  // handled above.
+                    "tail" | "latency" => {},  // handled above.
+                    "ev" => include_event_chapters = value == "true",
                     _ => bail!("parameter {} not understood", key),
                 }
             };
         }
-        let mp4 = builder.build(self.db.clone(), self.dirs_by_stream_id.clone())?;
+        if let Some(id) = archived {
+            let location = self.db.lock().get_archive_location(id)?.unwrap_or_default();
+            let msg = format!("recording {} is archived; its video is at {}\n", id, location);
+            let mut resp = Response::new(msg.into_bytes().into());
+            *resp.status_mut() = StatusCode::NOT_FOUND;
+            resp.headers_mut().insert(header::CONTENT_TYPE,
+                                      HeaderValue::from_static("text/plain"));
+            return Ok(resp);
+        }
+        if include_event_chapters {
+            builder.append_event_chapters(&self.db.lock())?;
+            cacheable = false;
+        }
+        if mp4_type_ == mp4::Type::Normal {
+            builder.append_origin(&self.db.lock())?;
+        }
+        let mp4 = builder.build(self.db.clone(), self.dirs.get()?)?;
+        if cacheable {
+            self.mp4_cache.lock().insert(key, now, mp4.clone());
+        }
         Ok(http_serve::serve(mp4, req))
     }
 
+    /// Serves `snapshot.jpg`, a JPEG of the stream's current frame.
+    fn stream_snapshot(&self, uuid: Uuid, type_: db::StreamType)
+                       -> Result<Response<Body>, Error> {
+        let source = {
+            let db = self.db.lock();
+            let c = match db.get_camera(uuid) {
+                None => return self.not_found(),
+                Some(c) => c,
+            };
+            let s = match c.streams[type_.index()] {
+                None => return self.not_found(),
+                Some(id) => &db.streams_by_id()[&id],
+            };
+            snapshot::Source::new(c, s)
+        };
+        match source {
+            snapshot::Source::Stream(_) if self.snapshot_ffmpeg.is_none() => {
+                return Ok(plain_response(StatusCode::NOT_FOUND,
+                                         "snapshots are not enabled for this stream"));
+            },
+            snapshot::Source::Exclusive(_) => {
+                return Ok(plain_response(StatusCode::NOT_FOUND,
+                                         "snapshots of this source require a snapshot_url"));
+            },
+            _ => {},
+        }
+        let jpeg = match self.take_snapshot(format!("{}/{}", uuid, type_), &source) {
+            None => return Ok(plain_response(StatusCode::BAD_GATEWAY, "unable to take snapshot")),
+            Some(j) => j,
+        };
+        let mut resp = Response::new((*jpeg).clone().into());
+        {
+            let h = resp.headers_mut();
+            h.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+            h.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        }
+        Ok(resp)
+    }
+
+    /// Returns a live snapshot from `source`: a recently taken one, the one another request is
+    /// taking, or a new one. Failures are logged and return `None`.
+    fn take_snapshot(&self, key: String, source: &snapshot::Source) -> Option<Arc<Vec<u8>>> {
+        let (pending, taker) = {
+            let mut l = self.pending_snapshots.lock();
+            if let Some(j) = self.snapshot_cache.lock().get(&key, Instant::now()) {
+                return Some(j);
+            }
+            match l.get(&key) {
+                Some(p) => (p.clone(), false),
+                None => {
+                    let p = Arc::new(PendingSnapshot::default());
+                    l.insert(key.clone(), p.clone());
+                    (p, true)
+                },
+            }
+        };
+        if !taker {
+            let mut r = pending.result.lock();
+            while r.is_none() {
+                pending.cond.wait(&mut r);
+            }
+            return r.as_ref().unwrap().clone();
+        }
+        let jpeg = match source.take(self.snapshot_ffmpeg.as_ref()) {
+            Ok(j) => Some(Arc::new(j)),
+            Err(e) => {
+                warn!("{}: unable to take snapshot: {}", key, e);
+                None
+            },
+        };
+        {
+            let mut l = self.pending_snapshots.lock();
+            if let Some(ref j) = jpeg {
+                self.snapshot_cache.lock().insert(key.clone(), Instant::now(), j.clone());
+            }
+            l.remove(&key);
+        }
+        *pending.result.lock() = Some(jpeg.clone());
+        pending.cond.notify_all();
+        jpeg
+    }
+
+    /// Serves `view.vtt`, a WebVTT track of timestamps and events aligned to `view.mp4`.
+    fn stream_view_vtt(&self, req: &Request<::hyper::Body>, uuid: Uuid,
+                       stream_type_: db::StreamType) -> Result<Response<Body>, Error> {
+        let (camera_id, stream_id) = {
+            let db = self.db.lock();
+            let camera = match db.get_camera(uuid) {
+                None => return self.not_found(),
+                Some(c) => c,
+            };
+            match camera.streams[stream_type_.index()] {
+                None => return self.not_found(),
+                Some(s) => (camera.id, s),
+            }
+        };
+        let mut segments = Vec::new();
+        let mut timestamps = true;
+        let mut events = true;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &["s"])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "s" => segments.push(Segments::parse(value).map_err(
+                        |_| format_err!("invalid s parameter: {}", value))?),
+                    "ts" => timestamps = bool::from_str(value)?,
+                    "ev" => events = bool::from_str(value)?,
+                    _ => bail!("parameter {} not understood", key),
+                }
+            };
+        }
+        let timestamp_format = request_locale(&self.catalog, req).get(mp4::SUBTITLE_TEMPLATE);
+        let mut track = vtt::TrackBuilder::new(timestamps, events, timestamp_format);
+        {
+            let db = self.db.lock();
+            for s in &segments {
+                for_each_segment(&db, stream_id, s, &mut |db, r, rel_range_90k| {
+                    let start = r.start + recording::Duration(rel_range_90k.start as i64);
+                    let end = r.start + recording::Duration(rel_range_90k.end as i64);
+                    track.append(db, camera_id, start .. end)
+                })?;
+            }
+        }
+        let mut resp = Response::new(track.build().into_bytes().into());
+        resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/vtt"));
+        resp.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        Ok(resp)
+    }
+
+    /// Serves `view.m4s?tail=true`, which streams media segments as a recording grows.
+    fn stream_view_tail(&self, req: &Request<::hyper::Body>, stream_id: i32,
+                        mp4_type_: mp4::Type) -> Result<Response<Body>, Error> {
+        if mp4_type_ != mp4::Type::MediaSegment {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "tail requires view.m4s"));
+        }
+        let mut s = None;
+        let mut latency = tail::Latency::Smooth;
+        if let Some(q) = req.uri().query() {
+            for (key, value) in request::parse_query(q, &[])? {
+                let (key, value) = (key.borrow(), value.borrow());
+                match key {
+                    "s" if s.is_none() => s = Some(Segments::parse(value).map_err(
+                        |_| format_err!("invalid s parameter: {}", value))?),
+                    "tail" => {},
+                    "latency" => latency = tail::Latency::parse(value).ok_or_else(
+                        || format_err!("invalid latency parameter: {}", value))?,
+                    _ => bail!("parameter {} not understood", key),
+                }
+            }
+        }
+        let s = match s {
+            Some(ref s) if s.ids.end == s.ids.start + 1 && s.end_time.is_none() => s,
+            _ => return Ok(plain_response(StatusCode::BAD_REQUEST,
+                                          "tail requires a single recording with no end time")),
+        };
+        if s.start_time > i32::max_value() as i64 {
+            return Ok(plain_response(StatusCode::BAD_REQUEST, "start time out of range"));
+        }
+        let body = tail::Hub::subscribe(&self.tails, tail::Params {
+            stream_id,
+            recording_id: s.ids.start,
+            open_id: s.open_id,
+            start_90k: s.start_time as i32,
+            latency,
+        })?;
+        let mut resp = Response::new(body.into());
+        resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("video/mp4"));
+        resp.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        Ok(resp)
+    }
+
+    /// Serves a file from `--ui-dir`. Paths which don't match a file but look like routes of the
+    /// UI's client-side router get `index.html`, so that deep links work.
     fn static_file(&self, req: &Request<::hyper::Body>) -> Result<Response<Body>, Error> {
-        let s = match self.ui_files.get(req.uri().path()) {
+        let path = req.uri().path();
+        let (s, fallback) = match self.ui_files.get(path) {
+            Some(s) => (s, false),
+            None if is_client_route(path) => match self.ui_files.get("/") {
+                None => { return self.not_found() },
+                Some(s) => (s, true),
+            },
             None => { return self.not_found() },
-            Some(s) => s,
         };
         let f = fs::File::open(&s.path)?;
         let mut hdrs = http::HeaderMap::new();
         hdrs.insert(header::CONTENT_TYPE, s.mime.clone());
+        if fallback {
+            // The same contents are served for every route; don't let caches key on the path
+            // and keep stale copies of index.html around.
+            hdrs.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        }
         let e = http_serve::ChunkedReadFile::new(f, Some(self.pool.clone()), hdrs)?;
         Ok(http_serve::serve(e, &req))
     }
 }
 
+/// Calls `f` with each recording of `stream_id` selected by `s` (a `view.mp4`-style `s`
+/// parameter) and the relative time range of the recording to include, verifying there are no
+/// missing recordings. Returns true iff all of the recordings are committed and complete.
+fn for_each_segment(db: &db::LockedDatabase, stream_id: i32, s: &Segments,
+                    f: &mut FnMut(&db::LockedDatabase, db::ListRecordingsRow, Range<i32>)
+                                  -> Result<(), Error>) -> Result<bool, Error> {
+    let mut prev = None;
+    let mut cur_off = 0;
+    let mut all_committed = true;
+    db.list_recordings_by_id(stream_id, s.ids.clone(), &mut |r| {
+        let recording_id = r.id.recording();
+
+        if let Some(o) = s.open_id {
+            if r.open_id != o {
+                bail!("recording {} has open id {}, requested {}", r.id, r.open_id, o);
+            }
+        }
+
+        // Check for missing recordings.
+        match prev {
+            None if recording_id == s.ids.start => {},
+            None => bail!("no such recording {}/{}", stream_id, s.ids.start),
+            Some(id) if r.id.recording() != id + 1 => {
+                bail!("no such recording {}/{}", stream_id, id + 1);
+            },
+            _ => {},
+        };
+        prev = Some(recording_id);
+        if (r.flags & (db::RecordingFlags::Uncommitted as i32 |
+                       db::RecordingFlags::Growing as i32)) != 0 {
+            all_committed = false;
+        }
+
+        // Add a segment for the relevant part of the recording, if any.
+        let end_time = s.end_time.unwrap_or(i64::max_value());
+        let d = r.duration_90k as i64;
+        if s.start_time <= cur_off + d && cur_off < end_time {
+            let start = cmp::max(0, s.start_time - cur_off);
+            let end = cmp::min(d, end_time - cur_off);
+            let times = start as i32 .. end as i32;
+            debug!("...appending recording {} with times {:?} (out of dur {})", r.id, times, d);
+            f(db, r, times)?;
+        } else {
+            debug!("...skipping recording {} dur {}", r.id, d);
+        }
+        cur_off += d;
+        Ok(())
+    })?;
+
+    // Check for missing recordings.
+    match prev {
+        Some(id) if s.ids.end != id + 1 => {
+            bail!("no such recording {}/{}", stream_id, s.ids.end - 1);
+        },
+        None => {
+            bail!("no such recording {}/{}", stream_id, s.ids.start);
+        },
+        _ => {},
+    };
+    if let Some(end) = s.end_time {
+        if end > cur_off {
+            bail!("end time {} is beyond specified recordings", end);
+        }
+    }
+    Ok(all_committed)
+}
+
+/// Returns true if `path` (which isn't under `/api/`) may be a route of the UI's client-side
+/// router. Paths whose last component has an extension are assumed to be missing files instead,
+/// so that (for example) a stale reference to a `.js` file fails plainly rather than with HTML.
+fn is_client_route(path: &str) -> bool {
+    if !path.starts_with('/') || path == "/api" || path.starts_with("/api/") {
+        return false;
+    }
+    let last = &path[path.rfind('/').unwrap() + 1 ..];
+    !last.contains('.')
+}
+
+/// Configuration for `Service::new`.
+pub struct Config<'a> {
+    pub db: Arc<db::Database>,
+
+    /// The sample file directory of each stream, shared with the rest of the server.
+    pub dirs: Arc<StreamDirs>,
+
+    pub ui_dir: Option<&'a str>,
+    pub allow_origin: Option<String>,
+
+    /// The name of the server's time zone, as in `/api/`'s `timeZoneName`.
+    pub zone: String,
+
+    /// Allows `/api/cameras/<uuid>/reboot`.
+    pub allow_camera_reboot: bool,
+
+    /// Allows `/api/probe`.
+    pub allow_probe: bool,
+
+    /// The VAPID public key to offer via `/api/push`, or `None` if push notifications are
+    /// disabled.
+    pub push_public_key: Option<String>,
+
+    /// The `ffmpeg` binary used to composite `/api/mosaic.mjpeg`, or `None` if mosaics are
+    /// disabled.
+    pub mosaic_ffmpeg: Option<PathBuf>,
+
+    /// The `ffmpeg` binary used to decode and encode snapshots for `/api/events/<id>.jpg`'s
+    /// `annotate` parameter, or `None` if annotation is disabled.
+    pub snapshot_ffmpeg: Option<PathBuf>,
+
+    /// The key used to sign and verify `/embed/<token>` tokens, or `None` if embedding is
+    /// disabled.
+    pub embed_signer: Option<embed::Signer>,
+
+    /// The background job queue, or `None` if the database is read-only. Exports and emailed
+    /// clips are enabled if it has the respective handlers.
+    pub jobs: Option<Arc<jobs::Queue>>,
+
+    /// The handler of `export` jobs, for serving their output.
+    pub exporter: Option<Arc<export::Exporter>>,
+
+    /// Watermarks every export with the requesting user (see `user_header`) and the time. Such
+    /// exports are refused when the user isn't known.
+    pub watermark_exports: bool,
+
+    /// The cache of event clips for `/api/events/<id>.mp4`, or `None` if they're disabled.
+    pub event_clips: Option<Arc<clips::EventClips>>,
+
+    /// Maintenance mode, as controlled by `/api/admin/maintenance`.
+    pub maintenance: Arc<Maintenance>,
+
+    /// The file to which the log is written, if any, for `/api/admin/logs?file=true`.
+    pub log_file: Option<PathBuf>,
+
+    /// Counts bytes served for `/api/stats/bandwidth`, or `None` if accounting is disabled.
+    pub bandwidth: Option<Arc<bandwidth::Accountant>>,
+
+    /// The request header naming the user, as set by an authenticating reverse proxy. The user
+    /// is credited with bandwidth, named in audit logs and export watermarks, and (if they belong
+    /// to a tenant) limited to that tenant's cameras.
+    pub user_header: Option<String>,
+
+    /// True once startup is complete and until shutdown begins, for `/readyz`.
+    pub ready: Arc<AtomicBool>,
+
+    /// Translations of error bodies and `view.vtt` timestamps, and the locale of emailed clips.
+    pub catalog: Arc<l10n::Catalog>,
+}
+
+/// The sample file directory of each stream which has one.
+///
+/// This is recomputed (opening any newly referenced directories) when streams are added, removed,
+/// or reassigned to another directory, so that new cameras' recordings can be served without a
+/// restart. It's shared by the web service, exporter, mailer, event clip cache, and the
+/// startup of streamers.
+pub struct StreamDirs {
+    db: Arc<db::Database>,
+
+    /// The `streams_generation` the map was computed at, and the map itself.
+    current: Mutex<(u64, Arc<FnvHashMap<i32, Arc<SampleFileDir>>>)>,
+}
+
+impl StreamDirs {
+    /// Creates a new `StreamDirs`, opening the directory of every stream.
+    pub fn new(db: Arc<db::Database>) -> Result<Arc<Self>, Error> {
+        let current = {
+            let mut l = db.lock();
+            (l.streams_generation(), dirs_by_stream_id(&mut l)?)
+        };
+        Ok(Arc::new(StreamDirs {
+            db,
+            current: Mutex::new(current),
+        }))
+    }
+
+    /// Returns the current mapping, recomputing it first if streams have changed.
+    pub fn get(&self) -> Result<Arc<FnvHashMap<i32, Arc<SampleFileDir>>>, Error> {
+        let mut c = self.current.lock();
+        let mut l = self.db.lock();
+        let generation = l.streams_generation();
+        if generation != c.0 {
+            let d = dirs_by_stream_id(&mut l)?;
+            info!("Stream changes; now serving {} streams' sample file dirs", d.len());
+            *c = (generation, d);
+        }
+        Ok(c.1.clone())
+    }
+}
+
+/// Opens the sample file directory of each stream which has one and returns a mapping to them.
+fn dirs_by_stream_id(l: &mut db::LockedDatabase)
+                     -> Result<Arc<FnvHashMap<i32, Arc<SampleFileDir>>>, Error> {
+    let dirs_to_open: Vec<_> =
+        l.streams_by_id()
+         .values()
+         .flat_map(|s| s.sample_file_dir_id.into_iter().chain(s.mirror_sample_file_dir_id))
+         .collect();
+    l.open_sample_file_dirs(&dirs_to_open)?;
+    let mut d = FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
+    for (&id, s) in l.streams_by_id().iter() {
+        let dir_id = match s.sample_file_dir_id {
+            Some(d) => d,
+            None => continue,
+        };
+        d.insert(id, l.sample_file_dirs_by_id()
+                      .get(&dir_id)
+                      .unwrap()
+                      .get()?);
+    }
+    Ok(Arc::new(d))
+}
+
+/// Parses the `/recordings` parameters which control splitting of aggregated rows into `split`,
+/// returning false if `key` isn't one of them.
+fn parse_split(key: &str, value: &str, split: &mut db::AggregationSplit) -> Result<bool, Error> {
+    match key {
+        "split90k" => split.duration = recording::Duration(i64::from_str(value)?),
+        "splitBytes" => split.bytes = i64::from_str(value)?,
+        "splitRecordings" => split.recordings = i32::from_str(value)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Publishes a database change to server-sent event subscribers.
+/// Called with the database lock held.
+fn publish_change(sse: &sse::Hub, db: &db::LockedDatabase, c: &db::Change) {
+    match *c {
+        db::Change::RecordingsAdded { stream_id, count } => {
+            let s = &db.streams_by_id()[&stream_id];
+            sse.publish("recordings", &json::RecordingsAddedMessage {
+                camera_uuid: db.cameras_by_id()[&s.camera_id].uuid,
+                stream: s.type_.as_str(),
+                count,
+            });
+        },
+        db::Change::StreamHealth { stream_id } => {
+            let s = &db.streams_by_id()[&stream_id];
+            let c = &db.cameras_by_id()[&s.camera_id];
+            sse.publish("streamHealth", &json::StreamHealthMessage {
+                camera_uuid: c.uuid,
+                stream: s.type_.as_str(),
+                health: json::StreamHealth::wrap(&s.health, &c.reachability),
+            });
+        },
+        db::Change::CameraReachability { camera_id } => {
+            let c = &db.cameras_by_id()[&camera_id];
+            sse.publish("cameraReachability", &json::CameraReachabilityMessage {
+                camera_uuid: c.uuid,
+                reachable: c.reachability.reachable.unwrap_or(true),
+                error: c.reachability.last_error.as_ref().map(String::as_str),
+            });
+        },
+        db::Change::EventAdded { id, ref event } |
+        db::Change::EventUpdated { id, ref event } => {
+            let name = if let db::Change::EventAdded { .. } = *c { "event" } else { "eventUpdated" };
+            sse.publish(name, &json::EventAddedMessage {
+                camera_uuid: db.cameras_by_id()[&event.camera_id].uuid,
+                event: json::Event {
+                    id,
+                    type_: event.type_.clone(),
+                    start_time_90k: event.time.start.0,
+                    end_time_90k: event.time.end.0,
+                    description: event.description.clone(),
+                    score: event.score,
+                },
+            });
+        },
+        db::Change::ChainAnchored { stream_id, ref anchor } => {
+            let s = &db.streams_by_id()[&stream_id];
+            sse.publish("chainAnchor", &json::ChainAnchorMessage {
+                camera_uuid: db.cameras_by_id()[&s.camera_id].uuid,
+                stream: s.type_.as_str(),
+                recording_id: anchor.id.recording(),
+                chain_sha1: strutil::hex(&anchor.chain_sha1),
+                time_sec: anchor.time_sec,
+            });
+        },
+        db::Change::RetentionExceeded { stream_id } => {
+            let s = &db.streams_by_id()[&stream_id];
+            sse.publish("retentionExceeded", &json::RetentionExceededMessage {
+                camera_uuid: db.cameras_by_id()[&s.camera_id].uuid,
+                stream: s.type_.as_str(),
+                usage_bytes: s.retention_usage(),
+                retain_bytes: s.retain_bytes,
+                delete_after_90k: s.over_limit_since.map(|t| (t + db.retention_grace()).0),
+            });
+        },
+    }
+}
+
 #[derive(Clone)]
 pub struct Service(Arc<ServiceInner>);
 
 impl Service {
-    pub fn new(db: Arc<db::Database>, ui_dir: Option<&str>, allow_origin: Option<String>,
-               zone: String) -> Result<Self, Error> {
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let db = config.db;
         let mut ui_files = HashMap::new();
-        if let Some(d) = ui_dir {
+        if let Some(d) = config.ui_dir {
             Service::fill_ui_files(d, &mut ui_files);
         }
         debug!("UI files: {:#?}", ui_files);
-        let dirs_by_stream_id = {
-            let l = db.lock();
-            let mut d =
-                FnvHashMap::with_capacity_and_hasher(l.streams_by_id().len(), Default::default());
-            for (&id, s) in l.streams_by_id().iter() {
-                let dir_id = match s.sample_file_dir_id {
-                    Some(d) => d,
-                    None => continue,
-                };
-                d.insert(id, l.sample_file_dirs_by_id()
-                              .get(&dir_id)
-                              .unwrap()
-                              .get()?);
-            }
-            Arc::new(d)
-        };
-        let allow_origin = match allow_origin {
+        let allow_origin = match config.allow_origin {
             None => None,
             Some(o) => Some(HeaderValue::from_str(&o)?),
         };
+        let user_header = match config.user_header {
+            None => None,
+            Some(h) => Some(header::HeaderName::from_bytes(h.as_bytes())?),
+        };
+        let sse = Arc::new(sse::Hub::new());
+        let tails = tail::Hub::new(db.clone(), config.dirs.clone());
+        let updates = updates::Hub::new(db.clone());
+        db.lock().watch({
+            let sse = sse.clone();
+            let updates = updates.clone();
+            Box::new(move |db, c| {
+                if let db::Change::RecordingsAdded { .. } = *c {
+                    updates.notify();
+                }
+                publish_change(&sse, db, c)
+            })
+        });
         Ok(Service(Arc::new(ServiceInner {
             db,
-            dirs_by_stream_id,
+            dirs: config.dirs,
             ui_files,
             allow_origin,
             pool: futures_cpupool::Builder::new().pool_size(1).name_prefix("static").create(),
-            time_zone_name: zone,
+            blocking_pool: futures_cpupool::Builder::new()
+                .pool_size(BLOCKING_THREADS)
+                .name_prefix("blocking")
+                .after_start(|| sched::enter(sched::Class::Web))
+                .create(),
+            time_zone_name: config.zone,
+            allow_camera_reboot: config.allow_camera_reboot,
+            allow_probe: config.allow_probe,
+            sse,
+            push_public_key: config.push_public_key,
+            mosaic_ffmpeg: config.mosaic_ffmpeg,
+            snapshot_ffmpeg: config.snapshot_ffmpeg,
+            embed_signer: config.embed_signer,
+            jobs: config.jobs,
+            exporter: config.exporter,
+            watermark_exports: config.watermark_exports,
+            event_clips: config.event_clips,
+            maintenance: config.maintenance,
+            log_file: config.log_file,
+            bandwidth: config.bandwidth,
+            user_header,
+            ready: config.ready,
+            catalog: config.catalog,
+            tails,
+            updates,
+            mp4_cache: Mutex::new(ExpiringCache::new(MP4_CACHE_ENTRIES,
+                                                     Duration::from_secs(MP4_CACHE_TTL_SEC))),
+            snapshot_cache: Mutex::new(ExpiringCache::new(
+                SNAPSHOT_CACHE_ENTRIES, Duration::from_secs(SNAPSHOT_CACHE_TTL_SEC))),
+            pending_snapshots: Mutex::new(HashMap::new()),
         })))
     }
 
@@ -508,61 +3196,146 @@ impl ::hyper::service::Service for Service {
     type ReqBody = ::hyper::Body;
     type ResBody = Body;
     type Error = BoxedError;
-    type Future = future::FutureResult<Response<Self::ResBody>, Self::Error>;
+    type Future = Box<Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
 
     fn call(&mut self, req: Request<::hyper::Body>) -> Self::Future {
         debug!("request on: {}", req.uri());
-        let mut res = match decode_path(req.uri().path()) {
-            Path::InitSegment(sha1) => self.0.init_segment(sha1, &req),
-            Path::TopLevel => self.0.top_level(&req),
-            Path::Camera(uuid) => self.0.camera(&req, uuid),
-            Path::StreamRecordings(uuid, type_) => self.0.stream_recordings(&req, uuid, type_),
-            Path::StreamViewMp4(uuid, type_) => {
-                self.0.stream_view_mp4(&req, uuid, type_, mp4::Type::Normal)
-            },
-            Path::StreamViewMp4Segment(uuid, type_) => {
-                self.0.stream_view_mp4(&req, uuid, type_, mp4::Type::MediaSegment)
-            },
-            Path::NotFound => self.0.not_found(),
-            Path::Static => self.0.static_file(&req),
-        };
-        if let Ok(ref mut resp) = res {
-            if let Some(ref o) = self.0.allow_origin {
-                resp.headers_mut().insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, o.clone());
-            }
+        crash::note_request(req.method(), req.uri().path());
+        let path = decode_path(req.uri().path(), &self.0.db);
+        if path.blocks() {
+            let inner = self.0.clone();
+            return Box::new(self.0.blocking_pool.spawn_fn(move || inner.serve(path, &req)));
         }
-        future::result(res.map_err(|e| wrap_error(e)))
+        Box::new(future::result(self.0.serve(path, &req)))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use db::testutil;
-    use super::Segments;
+    use base::clock::RealClocks;
+    use db::{self, testutil::{self, TestDb}};
+    use export;
+    use failure::Error;
+    use http::{Request, status::StatusCode};
+    use jobs;
+    use maintenance::Maintenance;
+    use request::Path;
+    use serde_json;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Duration, Instant};
+    use super::{Config, ExpiringCache, Service, StreamDirs, is_client_route};
+
+    #[test]
+    fn test_is_client_route() {
+        for p in &["/cameras", "/cameras/driveway/live", "/layouts/", "/v1.2/events"] {
+            assert!(is_client_route(p), "{}", p);
+        }
+        for p in &["/app.js", "/static/app.3f2a.js.map", "/favicon.ico", "/api", "/api/x",
+                   "/.well-known/x.json"] {
+            assert!(!is_client_route(p), "{}", p);
+        }
+    }
+
+    #[test]
+    fn test_expiring_cache() {
+        let mut c = ExpiringCache::new(2, Duration::from_secs(60));
+        let t0 = Instant::now();
+        c.insert("a".to_owned(), t0, 1);
+        c.insert("b".to_owned(), t0, 2);
+        assert_eq!(c.get("a", t0), Some(1));
+        c.insert("c".to_owned(), t0, 3);  // evicts the oldest entry, a.
+        assert_eq!(c.get("a", t0), None);
+        assert_eq!(c.get("b", t0), Some(2));
+        assert_eq!(c.get("c", t0 + Duration::from_secs(60)), None);  // expired.
+    }
+
+    struct NopHandler;
+
+    impl jobs::Handler for NopHandler {
+        fn run(&self, _job: &db::Job, _cancel: &AtomicBool) -> Result<String, Error> {
+            Ok("{}".to_owned())
+        }
+    }
 
     #[test]
-    fn test_segments() {
+    fn test_tenant_export() {
         testutil::init();
-        assert_eq!(Segments{ids: 1..2, open_id: None, start_time: 0, end_time: None},
-                   Segments::parse("1").unwrap());
-        assert_eq!(Segments{ids: 1..2, open_id: Some(42), start_time: 0, end_time: None},
-                   Segments::parse("1@42").unwrap());
-        assert_eq!(Segments{ids: 1..2, open_id: None, start_time: 26, end_time: None},
-                   Segments::parse("1.26-").unwrap());
-        assert_eq!(Segments{ids: 1..2, open_id: Some(42), start_time: 26, end_time: None},
-                   Segments::parse("1@42.26-").unwrap());
-        assert_eq!(Segments{ids: 1..2, open_id: None, start_time: 0, end_time: Some(42)},
-                   Segments::parse("1.-42").unwrap());
-        assert_eq!(Segments{ids: 1..2, open_id: None, start_time: 26, end_time: Some(42)},
-                   Segments::parse("1.26-42").unwrap());
-        assert_eq!(Segments{ids: 1..6, open_id: None, start_time: 0, end_time: None},
-                   Segments::parse("1-5").unwrap());
-        assert_eq!(Segments{ids: 1..6, open_id: None, start_time: 26, end_time: None},
-                   Segments::parse("1-5.26-").unwrap());
-        assert_eq!(Segments{ids: 1..6, open_id: None, start_time: 0, end_time: Some(42)},
-                   Segments::parse("1-5.-42").unwrap());
-        assert_eq!(Segments{ids: 1..6, open_id: None, start_time: 26, end_time: Some(42)},
-                   Segments::parse("1-5.26-42").unwrap());
+        let tdb = TestDb::new(RealClocks {});
+        let tenant_camera_uuid = {
+            let mut l = tdb.db.lock();
+            let tenant_id = l.add_tenant("apt1".to_owned(), None, true).unwrap();
+            l.set_user_tenant(testutil::TEST_USER, Some(tenant_id)).unwrap();
+            let dir_id = *l.sample_file_dirs_by_id().keys().next().unwrap();
+            let camera_id = l.add_camera(db::CameraChange {
+                short_name: "apt1 door".to_owned(),
+                description: "".to_owned(),
+                host: "apt1-door".to_owned(),
+                username: "".to_owned(),
+                password: "".to_owned(),
+                streams: [
+                    db::StreamChange {
+                        sample_file_dir_id: Some(dir_id),
+                        rtsp_path: "/main".to_owned(),
+                        ..Default::default()
+                    },
+                    Default::default(),
+                ],
+                labels: Default::default(),
+                tenant_id: Some(tenant_id),
+                event_source: None,
+                snapshot_schedule: None,
+            }).unwrap();
+            l.cameras_by_id().get(&camera_id).unwrap().uuid
+        };
+        let mut handlers: HashMap<&'static str, Arc<jobs::Handler>> = HashMap::new();
+        handlers.insert("export", Arc::new(NopHandler));
+        let maintenance = Maintenance::new();
+        let jobs = jobs::Queue::start(tdb.db.clone(), handlers, 1, maintenance.clone()).unwrap();
+        let service = Service::new(Config {
+            db: tdb.db.clone(),
+            dirs: StreamDirs::new(tdb.db.clone()).unwrap(),
+            ui_dir: None,
+            allow_origin: None,
+            zone: "".to_owned(),
+            allow_camera_reboot: false,
+            allow_probe: false,
+            push_public_key: None,
+            mosaic_ffmpeg: None,
+            snapshot_ffmpeg: None,
+            embed_signer: None,
+            jobs: Some(jobs),
+            exporter: None,
+            watermark_exports: false,
+            event_clips: None,
+            maintenance,
+            log_file: None,
+            bandwidth: None,
+            user_header: Some("X-User".to_owned()),
+            ready: Arc::new(AtomicBool::new(true)),
+            catalog: Arc::new(::l10n::Catalog::english()),
+        }).unwrap();
+        let export = |camera_uuid| {
+            let uri = format!("/api/export?camera={}&startTime90k=0&endTime90k=90000", camera_uuid);
+            let req = Request::post(&uri[..])
+                .header("X-User", testutil::TEST_USER)
+                .body(::hyper::Body::empty())
+                .unwrap();
+            service.0.route(Path::Exports, &req).unwrap().status()
+        };
+
+        // The tenant user can't export another tenant's camera...
+        assert_eq!(export(tdb.test_camera_uuid), StatusCode::NOT_FOUND);
+        assert!(tdb.db.lock().list_jobs().unwrap().is_empty());
+
+        // ...but can export its own, which its tenant requires to be watermarked.
+        assert_eq!(export(tenant_camera_uuid), StatusCode::ACCEPTED);
+        let jobs = tdb.db.lock().list_jobs().unwrap();
+        assert_eq!(jobs.len(), 1);
+        let p: export::Params = serde_json::from_str(&jobs[0].params).unwrap();
+        let watermark = p.watermark.unwrap();
+        assert!(watermark.starts_with("Exported by test user at "), "{}", watermark);
     }
 }
 
@@ -591,8 +3364,29 @@ mod bench {
             let (tx, rx) = ::std::sync::mpsc::channel();
             ::std::thread::spawn(move || {
                 let addr = "127.0.0.1:0".parse().unwrap();
-                let service = super::Service::new(db.db.clone(), None, None,
-                                                  "".to_owned()).unwrap();
+                let service = super::Service::new(super::Config {
+                    db: db.db.clone(),
+                    dirs: super::StreamDirs::new(db.db.clone()).unwrap(),
+                    ui_dir: None,
+                    allow_origin: None,
+                    zone: "".to_owned(),
+                    allow_camera_reboot: false,
+                    allow_probe: false,
+                    push_public_key: None,
+                    mosaic_ffmpeg: None,
+                    snapshot_ffmpeg: None,
+                    embed_signer: None,
+                    jobs: None,
+                    exporter: None,
+                    watermark_exports: false,
+                    event_clips: None,
+                    maintenance: ::maintenance::Maintenance::new(),
+                    log_file: None,
+                    bandwidth: None,
+                    user_header: None,
+                    ready: Arc::new(AtomicBool::new(true)),
+                    catalog: Arc::new(::l10n::Catalog::english()),
+                }).unwrap();
                 let server = hyper::server::Server::bind(&addr)
                     .tcp_nodelay(true)
                     .serve(move || Ok::<_, Box<StdError + Send + Sync>>(service.clone()));